@@ -0,0 +1,239 @@
+use std::fmt;
+
+/// Sources of motion commands, highest priority first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MotionSource {
+    /// Requests from clients, including the runs they start
+    User,
+    /// Scheduled moves
+    Scheduler,
+    /// The attract loop and the demo motion
+    Attract,
+}
+
+impl MotionSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            MotionSource::User => "user",
+            MotionSource::Scheduler => "scheduler",
+            MotionSource::Attract => "attract",
+        }
+    }
+}
+
+impl fmt::Display for MotionSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a source starting motion does about one already holding the arm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// Start; the holder stops before its next command
+    Preempt,
+    /// Start alongside; the commands of both queue at the serial port
+    Share,
+    /// Wait for the holder to finish
+    Queue,
+    /// Wait for the higher priority holder to finish
+    Yield,
+}
+
+/// The arbitration matrix: how `incoming` treats a running `holder`
+///
+/// Higher priority preempts lower, and lower waits for higher. Scheduled
+/// moves queue behind each other, while user commands share the arm as
+/// before, one command at a time, so a run started by one client doesn't
+/// lock out the others.
+pub fn rule(incoming: MotionSource, holder: MotionSource) -> Rule {
+    use MotionSource::*;
+    match (incoming, holder) {
+        (User, User) => Rule::Share,
+        (User, Scheduler | Attract) => Rule::Preempt,
+        (Scheduler, User) => Rule::Yield,
+        (Scheduler, Scheduler) => Rule::Queue,
+        (Scheduler, Attract) => Rule::Preempt,
+        (Attract, User | Scheduler) => Rule::Yield,
+        (Attract, Attract) => Rule::Queue,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use crate::testing::TestServer;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use MotionSource::*;
+
+    const ALL: [MotionSource; 3] = [User, Scheduler, Attract];
+
+    #[test]
+    fn matrix_covers_every_pair() {
+        let expected = [
+            // holder:  User, Scheduler, Attract
+            (User, [Rule::Share, Rule::Preempt, Rule::Preempt]),
+            (Scheduler, [Rule::Yield, Rule::Queue, Rule::Preempt]),
+            (Attract, [Rule::Yield, Rule::Yield, Rule::Queue]),
+        ];
+        for (incoming, rules) in expected {
+            for (holder, expected) in ALL.into_iter().zip(rules) {
+                assert_eq!(
+                    rule(incoming, holder),
+                    expected,
+                    "{} over {}",
+                    incoming,
+                    holder
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matrix_follows_the_priority_order() {
+        for incoming in ALL {
+            for holder in ALL {
+                let rule = rule(incoming, holder);
+                let expected = match incoming.cmp(&holder) {
+                    std::cmp::Ordering::Less => rule == Rule::Preempt,
+                    std::cmp::Ordering::Greater => rule == Rule::Yield,
+                    std::cmp::Ordering::Equal => matches!(rule, Rule::Share | Rule::Queue),
+                };
+                assert!(expected, "{} over {}: {:?}", incoming, holder, rule);
+            }
+        }
+        assert_eq!(
+            ALL.map(MotionSource::name),
+            ["user", "scheduler", "attract"]
+        );
+    }
+
+    /// Motion commands sent since the last call
+    fn moves(server: &TestServer) -> Vec<String> {
+        let commands = server.mock.take_commands();
+        commands
+            .into_iter()
+            .filter(|c| c.starts_with("MOVE") || c.starts_with("POSE"))
+            .collect()
+    }
+
+    async fn schedule(server: &TestServer, delay_ms: u64) {
+        let body = json!({ "duration_ms": 100, "angles": [170], "delay_ms": delay_ms });
+        let reply = server.post("/api/move/schedule", body).await;
+        assert_eq!(reply.status, 202);
+    }
+
+    /// Wait for the scheduled move to finish, returning its status
+    async fn finished(server: &TestServer) -> serde_json::Value {
+        loop {
+            let reply = server.get("/api/schedule").await;
+            let status = &reply.body["finished"][0]["status"];
+            if !status.is_null() {
+                return status.clone();
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Wait until `source` waits to start motion
+    async fn waiting(server: &TestServer, source: MotionSource) {
+        let state = server.server.state();
+        while !state.motion.waiting().contains(&source) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn moved(commands: &[String]) -> bool {
+        commands.iter().any(|c| c.starts_with("MOVE"))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scheduled_move_waits_for_a_user_run() {
+        let clock = Arc::new(MockClock::new());
+        let server = TestServer::on_clock(clock.clone(), |_| {}).await;
+        let start = clock.now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let to = |ms| clock.advance(at(ms) - clock.now());
+        let steps = json!({ "steps": [
+            { "duration_ms": 150, "angles": [10] },
+            { "duration_ms": 150, "angles": [20] },
+            { "duration_ms": 150, "angles": [30] },
+        ] });
+        assert_eq!(server.put("/api/sequences/run", steps).await.status, 200);
+
+        // Due during the run, so it waits for the rest of it
+        schedule(&server, 200).await;
+        clock.sleeping(|end| end == at(200)).await;
+        let run = server.post("/api/sequences/run/execute", json!({}));
+        let steps = async {
+            server.mock.recorded(moved).await;
+            assert_eq!(moves(&server), ["MOVE 150 10"]);
+            to(200);
+            waiting(&server, Scheduler).await;
+            for (at, step) in [(350, "MOVE 150 20"), (500, "MOVE 150 30")] {
+                server.mock.recorded(moved).await;
+                assert_eq!(moves(&server), [step]);
+                to(at);
+            }
+        };
+        let (reply, ()) = tokio::join!(run, steps);
+        assert_eq!(reply.status, 200);
+
+        // Not preempted, as the run was there first
+        server.mock.recorded(moved).await;
+        assert_eq!(moves(&server), ["MOVE 100 170"]);
+        to(600);
+        assert_eq!(finished(&server).await, "completed");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scheduled_move_preempts_the_attract_loop() {
+        let clock = Arc::new(MockClock::new());
+        let server = TestServer::on_clock(clock.clone(), |config| {
+            config.attract.enabled = true;
+            config.attract.sequence = Some("attract".to_string());
+            config.attract.idle_timeout_ms = 400;
+        })
+        .await;
+        let start = clock.now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let to = |ms| clock.advance(at(ms) - clock.now());
+        let sequence = json!({ "steps": [
+            { "duration_ms": 200, "angles": [10] },
+            { "duration_ms": 200, "angles": [90] },
+        ] });
+        let reply = server.put("/api/sequences/attract", sequence).await;
+        assert_eq!(reply.status, 200);
+        // The loop polls every 250ms and starts once idle for 400ms
+        clock.sleeping(|end| end == at(250)).await;
+        to(250);
+        clock.sleeping(|end| end == at(500)).await;
+        to(500);
+        server.mock.recorded(moved).await;
+        assert_eq!(moves(&server), ["MOVE 200 10"]);
+
+        // Sent after the segment in progress, and the loop only comes
+        // back after the idle timeout
+        schedule(&server, 0).await;
+        waiting(&server, Scheduler).await;
+        to(700);
+        server.mock.recorded(moved).await;
+        assert_eq!(moves(&server), ["MOVE 100 170"]);
+        clock.sleeping(|end| end == at(950)).await;
+        to(800);
+        assert_eq!(finished(&server).await, "completed");
+        to(950);
+        clock.sleeping(|end| end == at(1200)).await;
+        assert!(moves(&server).is_empty());
+        to(1200);
+        server.mock.recorded(moved).await;
+        assert_eq!(moves(&server), ["MOVE 200 10"]);
+
+        // Let the segment finish with the link gone, so the loop stops
+        server.disconnect().await;
+        to(1400);
+    }
+}
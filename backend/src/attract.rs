@@ -0,0 +1,367 @@
+use axum::http::HeaderMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::arbitration::MotionSource;
+use crate::controller::ServoController;
+use crate::handlers::{self, AppState};
+use crate::library::Sequence;
+use crate::models::ExecuteRequest;
+use crate::serial::CommandOptions;
+
+/// Longest MOVE sent at once; a user command waits for at most one
+const SEGMENT_MS: u16 = 500;
+
+/// How often an idle attract loop checks whether it may start
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The attract sequence being played, one segment at a time
+struct Playback {
+    sequence: Sequence,
+    step: usize,
+    segment: u32,
+    segments: u32,
+    /// Angles at the start of the current step
+    from: Vec<u16>,
+    /// Whether a segment has been sent
+    running: bool,
+}
+
+impl Playback {
+    fn new(state: &AppState, sequence: Sequence) -> Self {
+        let mut playback = Self {
+            sequence,
+            step: 0,
+            segment: 0,
+            segments: 0,
+            from: Vec::new(),
+            running: false,
+        };
+        let known = *state.positions.lock().unwrap();
+        playback.start_step(known.to_vec());
+        playback
+    }
+
+    /// Begin the current step from `from`; a step starting at an unknown
+    /// position can't be interpolated and is sent whole
+    fn start_step(&mut self, from: Vec<Option<u16>>) {
+        let step = &self.sequence.steps[self.step];
+        let known: Option<Vec<u16>> = from.into_iter().take(step.angles.len()).collect();
+        match known.filter(|from| from.len() == step.angles.len()) {
+            Some(from) => {
+                self.segments = step.duration_ms.div_ceil(SEGMENT_MS).max(1) as u32;
+                self.from = from;
+            }
+            None => {
+                self.segments = 1;
+                self.from = step.angles.clone();
+            }
+        }
+        self.segment = 0;
+    }
+
+    /// Duration and angles of the next segment, moving on to the next step
+    /// (and back to the first one at the end) as needed
+    fn next_segment(&mut self) -> (u16, Vec<u16>) {
+        if self.segment == self.segments {
+            let done = self.sequence.steps[self.step].angles.clone();
+            self.step = (self.step + 1) % self.sequence.steps.len();
+            self.start_step(done.into_iter().map(Some).collect());
+        }
+        self.segment += 1;
+
+        let step = &self.sequence.steps[self.step];
+        let (segment, segments) = (self.segment, self.segments);
+        let elapsed = step.duration_ms as u32 * (segment - 1) / segments;
+        let until = step.duration_ms as u32 * segment / segments;
+        let angles = self
+            .from
+            .iter()
+            .zip(&step.angles)
+            .map(|(&from, &to)| {
+                let offset = (to as i32 - from as i32) * segment as i32 / segments as i32;
+                (from as i32 + offset) as u16
+            })
+            .collect();
+        ((until - elapsed) as u16, angles)
+    }
+}
+
+/// Minute of the local day, for the quiet hours
+fn local_minute(utc_offset_minutes: i16) -> u16 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let minute = (secs / 60 + utc_offset_minutes as i64).rem_euclid(24 * 60);
+    minute as u16
+}
+
+/// Loop the configured attract sequence while nobody is using the arm
+///
+/// The loop runs with the lowest priority: each segment is sent through
+/// [`MotionActivity::demo_step`], so a user command or scheduled move
+/// waits for at most the segment in progress and the loop stops as soon
+/// as one arrives. It starts over once no motion command has been made for
+/// the idle timeout, which also applies after startup.
+///
+/// [`MotionActivity::demo_step`]: crate::demo::MotionActivity::demo_step
+pub async fn run(state: Arc<AppState>) {
+    // Start of the current wait for the idle timeout
+    let mut waiting = state.clock.now();
+    let mut entered: Option<Arc<dyn ServoController>> = None;
+    let mut playback: Option<Playback> = None;
+
+    loop {
+        if playback.is_none() {
+            state.clock.sleep(POLL_INTERVAL).await;
+        }
+        let config = state.config();
+        let attract = &config.attract;
+        let idle = Duration::from_millis(attract.idle_timeout_ms);
+        if !attract.enabled
+            || state.clock.now().duration_since(waiting) < idle
+            || attract.is_quiet(local_minute(attract.utc_offset_minutes))
+        {
+            stop(&mut playback, "disabled or in quiet hours");
+            continue;
+        }
+        let serial = match state.serial.lock().unwrap().clone() {
+            Some(serial) => serial,
+            None => {
+                stop(&mut playback, "disconnected");
+                continue;
+            }
+        };
+        if state.link_loss.latched() {
+            stop(&mut playback, "latched after a link loss");
+            continue;
+        }
+
+        if playback.is_none() {
+            match prepare(
+                &state,
+                &*serial,
+                attract.sequence.as_deref().unwrap_or_default(),
+            ) {
+                Some(sequence) => {
+                    if !matches!(&entered, Some(current) if Arc::ptr_eq(current, &serial)) {
+                        if let Err(e) = serial.start_serial_mode() {
+                            warn!("Attract loop could not enter serial mode: {}", e);
+                        }
+                        entered = Some(serial.clone());
+                    }
+                    playback = Some(Playback::new(&state, sequence));
+                }
+                None => {
+                    // Try again after another idle timeout, not every poll
+                    waiting = state.clock.now();
+                    continue;
+                }
+            }
+        }
+
+        let (duration_ms, angles) = playback.as_mut().unwrap().next_segment();
+        let step_state = state.clone();
+        let ran = tokio::task::spawn_blocking(move || {
+            step_state.motion.demo_step(idle, || {
+                let result = handlers::run_move(
+                    &step_state,
+                    &*serial,
+                    duration_ms,
+                    &angles,
+                    CommandOptions::default(),
+                );
+                if let Err((_, e)) = result {
+                    warn!("Attract segment failed: {}", e.error);
+                }
+            })
+        })
+        .await
+        .unwrap_or(false);
+
+        let playing = playback.as_mut().unwrap();
+        if !ran {
+            if playing.running {
+                let by = state.motion.yielded_to().map_or("user", MotionSource::name);
+                info!(by, "Attract loop yielded to {} commands", by);
+            }
+            // demo_step holds off until the client has been idle long enough
+            playback = None;
+        } else if !playing.running {
+            info!("Attract loop running");
+            playing.running = true;
+        }
+    }
+}
+
+fn stop(playback: &mut Option<Playback>, reason: &str) {
+    if playback.take().is_some_and(|playback| playback.running) {
+        info!("Attract loop stopped: {}", reason);
+    }
+}
+
+/// The attract sequence, if it exists, is valid and may start from the
+/// current position
+fn prepare(state: &AppState, serial: &dyn ServoController, name: &str) -> Option<Sequence> {
+    let sequence = match handlers::saved_sequence(state, name) {
+        Ok(sequence) => sequence,
+        Err((_, e)) => {
+            warn!("Attract loop can't start: {}", e.error);
+            return None;
+        }
+    };
+    if let Err(e) = sequence.validate(&state.config()) {
+        warn!("Attract sequence {} is invalid: {:#}", name, e);
+        return None;
+    }
+    let checked = handlers::check_preconditions(
+        state,
+        serial,
+        &sequence.preconditions,
+        false,
+        &ExecuteRequest::default(),
+        &HeaderMap::new(),
+    );
+    if let Err((_, e)) = checked {
+        warn!("Attract sequence {} can't start here: {}", name, e.error);
+        return None;
+    }
+    Some(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use crate::library::SequenceStep;
+    use crate::testing::TestServer;
+    use serde_json::json;
+
+    fn playback(steps: &[(u16, &[u16])]) -> Playback {
+        Playback {
+            sequence: Sequence {
+                steps: steps
+                    .iter()
+                    .map(|(duration_ms, angles)| SequenceStep {
+                        duration_ms: *duration_ms,
+                        angles: angles.to_vec(),
+                    })
+                    .collect(),
+                preconditions: Default::default(),
+            },
+            step: 0,
+            segment: 0,
+            segments: 0,
+            from: Vec::new(),
+            running: false,
+        }
+    }
+
+    /// Motion commands sent since the last call
+    fn moves(server: &TestServer) -> Vec<String> {
+        let commands = server.mock.take_commands();
+        commands
+            .into_iter()
+            .filter(|c| c.starts_with("MOVE") || c.starts_with("POSE"))
+            .collect()
+    }
+
+    #[test]
+    fn steps_are_split_into_segments_and_looped() {
+        let mut playback = playback(&[(1000, &[10, 170]), (300, &[90, 90])]);
+        playback.start_step(vec![Some(90), Some(90)]);
+        let segments: Vec<_> = (0..4).map(|_| playback.next_segment()).collect();
+        assert_eq!(
+            segments,
+            [
+                (500, vec![50, 130]),
+                (500, vec![10, 170]),
+                (300, vec![90, 90]),
+                // From the end of the last step back to the first
+                (500, vec![50, 130]),
+            ]
+        );
+    }
+
+    #[test]
+    fn step_from_an_unknown_position_is_sent_whole() {
+        let mut playback = playback(&[(1200, &[10, 170])]);
+        playback.start_step(vec![None, Some(90)]);
+        assert_eq!(playback.next_segment(), (1200, vec![10, 170]));
+        // Known from then on
+        assert_eq!(playback.next_segment(), (400, vec![10, 170]));
+    }
+
+    #[test]
+    fn local_minute_follows_the_offset() {
+        let utc = local_minute(0);
+        let ahead = local_minute(90);
+        // 91 if the minute turned in between
+        assert!(matches!((ahead + 24 * 60 - utc) % (24 * 60), 90 | 91));
+        assert!(local_minute(-600) < 24 * 60);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn user_command_preempts_within_a_segment_and_attract_resumes_after_idle() {
+        let clock = Arc::new(MockClock::new());
+        let server = TestServer::on_clock(clock.clone(), |config| {
+            config.attract.enabled = true;
+            config.attract.sequence = Some("attract".to_string());
+            config.attract.idle_timeout_ms = 400;
+        })
+        .await;
+        let sequence = json!({ "steps": [
+            { "duration_ms": 200, "angles": [10] },
+            { "duration_ms": 200, "angles": [170] },
+        ] });
+        assert_eq!(
+            server.put("/api/sequences/attract", sequence).await.status,
+            200
+        );
+        let at = |ms| clock.now() + Duration::from_millis(ms);
+
+        // Started at the first poll after the idle timeout
+        let polled = at(250);
+        clock.sleeping(|end| end == polled).await;
+        clock.advance(POLL_INTERVAL);
+        let polled = at(250);
+        clock.sleeping(|end| end == polled).await;
+        assert!(moves(&server).is_empty());
+        clock.advance(POLL_INTERVAL);
+        let started = |commands: &[String]| commands.iter().any(|c| c.starts_with("MOVE"));
+        server.mock.recorded(started).await;
+        assert_eq!(moves(&server), ["MOVE 200 10"]);
+
+        // The user waits for the segment in progress, and the loop for
+        // the user
+        let state = server.server.state().clone();
+        let user = server.post("/api/pose", json!({ "angles": [45] }));
+        let segment = async {
+            while state.motion.waiting().is_empty() {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_millis(200));
+        };
+        let (reply, ()) = tokio::join!(user, segment);
+        assert_eq!(reply.status, 200);
+        assert_eq!(moves(&server), ["POSE 45"]);
+
+        // Held off for the idle timeout after the user's command, then
+        // back at the next poll
+        let polled = at(250);
+        clock.sleeping(|end| end == polled).await;
+        clock.advance(POLL_INTERVAL);
+        let polled = at(250);
+        clock.sleeping(|end| end == polled).await;
+        assert!(moves(&server).is_empty());
+        clock.advance(POLL_INTERVAL);
+        server.mock.recorded(started).await;
+        assert_eq!(moves(&server), ["MOVE 200 10"]);
+
+        // Let the segment finish with the link gone, so the loop stops
+        server.disconnect().await;
+        clock.advance(Duration::from_millis(200));
+    }
+}
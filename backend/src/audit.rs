@@ -0,0 +1,547 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One configuration mutation made through the API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub timestamp_ms: u64,
+    pub actor: String,
+    /// Method and route, e.g. "PUT /api/poses/:name"
+    pub endpoint: String,
+    /// Kind of object changed, e.g. "pose", "sequence", "config"
+    pub kind: String,
+    /// Name of the changed object, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub changes: Vec<Change>,
+}
+
+/// A changed value at a JSON path; `None` means absent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub path: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Filter for [`AuditLog::query`]
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub since_ms: Option<u64>,
+    pub actor: Option<String>,
+    pub kind: Option<String>,
+}
+
+/// Append-only audit trail, kept in memory and optionally in a JSONL file
+///
+/// At most `max_entries` are retained; the file is compacted to that size
+/// once it has grown to twice as many lines.
+pub struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+    next_id: u64,
+    max_entries: usize,
+    path: Option<PathBuf>,
+    file_lines: usize,
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Compute the leaf-level differences between two JSON documents
+pub fn diff(before: &Value, after: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    diff_into("", Some(before), Some(after), &mut changes);
+    changes
+}
+
+fn diff_into(path: &str, before: Option<&Value>, after: Option<&Value>, out: &mut Vec<Change>) {
+    match (before, after) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            for (key, value) in a {
+                diff_into(&join(path, key), Some(value), b.get(key), out);
+            }
+            for (key, value) in b {
+                if !a.contains_key(key) {
+                    diff_into(&join(path, key), None, Some(value), out);
+                }
+            }
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) if a.len() == b.len() => {
+            for (i, (x, y)) in a.iter().zip(b).enumerate() {
+                diff_into(&format!("{}[{}]", path, i), Some(x), Some(y), out);
+            }
+        }
+        (a, b) if a != b => out.push(Change {
+            path: if path.is_empty() { "." } else { path }.to_string(),
+            before: a.cloned(),
+            after: b.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+/// Append an object key to a path; keys that aren't plain identifiers are
+/// quoted (`poses["a.b"]`) so the path can be parsed back
+fn join(path: &str, key: &str) -> String {
+    let plain = !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    match (plain, path.is_empty()) {
+        (true, true) => key.to_string(),
+        (true, false) => format!("{}.{}", path, key),
+        (false, _) => format!("{}[{}]", path, Value::String(key.to_string())),
+    }
+}
+
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// Split a path produced by [`diff`] into its segments
+fn parse_path(path: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = path;
+    if rest == "." {
+        return Some(segments);
+    }
+    while !rest.is_empty() {
+        if let Some(inner) = rest.strip_prefix('[') {
+            if inner.starts_with('"') {
+                let mut stream = serde_json::Deserializer::from_str(inner).into_iter::<String>();
+                let key = stream.next()?.ok()?;
+                rest = inner[stream.byte_offset()..].strip_prefix(']')?;
+                segments.push(Segment::Key(key));
+            } else {
+                let end = inner.find(']')?;
+                segments.push(Segment::Index(inner[..end].parse().ok()?));
+                rest = &inner[end + 1..];
+            }
+        } else {
+            let rest_key = rest.strip_prefix('.').unwrap_or(rest);
+            let end = rest_key.find(['.', '[']).unwrap_or(rest_key.len());
+            segments.push(Segment::Key(rest_key[..end].to_string()));
+            rest = &rest_key[end..];
+        }
+    }
+    Some(segments)
+}
+
+/// Apply changes recorded by [`diff`] to a document
+///
+/// Returns a description of every change whose `before` value didn't match
+/// the document (the change is applied regardless).
+pub fn apply(doc: &mut Value, changes: &[Change]) -> Vec<String> {
+    let mut divergences = Vec::new();
+    for change in changes {
+        let segments = match parse_path(&change.path) {
+            Some(segments) => segments,
+            None => {
+                divergences.push(format!("{}: unparseable path", change.path));
+                continue;
+            }
+        };
+        let current = lookup(doc, &segments);
+        if current != change.before.as_ref() {
+            divergences.push(format!(
+                "{}: expected {}, found {}",
+                change.path,
+                change.before.as_ref().map_or("nothing".to_string(), Value::to_string),
+                current.map_or("nothing".to_string(), Value::to_string)
+            ));
+        }
+        if !set(doc, &segments, change.after.clone()) {
+            divergences.push(format!("{}: parent missing, not applied", change.path));
+        }
+    }
+    divergences
+}
+
+fn lookup<'a>(doc: &'a Value, segments: &[Segment]) -> Option<&'a Value> {
+    segments.iter().try_fold(doc, |value, segment| match segment {
+        Segment::Key(key) => value.get(key),
+        Segment::Index(index) => value.get(index),
+    })
+}
+
+/// Set (or with `None` remove) the value at a path, creating missing
+/// objects along the way; returns false if the path can't be reached
+fn set(doc: &mut Value, segments: &[Segment], value: Option<Value>) -> bool {
+    let (last, parents) = match segments.split_last() {
+        Some(split) => split,
+        None => {
+            *doc = value.unwrap_or(Value::Null);
+            return true;
+        }
+    };
+
+    let mut node = doc;
+    for segment in parents {
+        node = match segment {
+            Segment::Key(key) => {
+                if node.is_null() {
+                    *node = Value::Object(Default::default());
+                }
+                match node.as_object_mut() {
+                    Some(map) => map.entry(key.clone()).or_insert(Value::Null),
+                    None => return false,
+                }
+            }
+            Segment::Index(index) => match node.get_mut(*index) {
+                Some(child) => child,
+                None => return false,
+            },
+        };
+    }
+
+    match (last, value) {
+        (Segment::Key(key), value) => {
+            if node.is_null() {
+                *node = Value::Object(Default::default());
+            }
+            let map = match node.as_object_mut() {
+                Some(map) => map,
+                None => return false,
+            };
+            match value {
+                Some(value) => {
+                    map.insert(key.clone(), value);
+                }
+                None => {
+                    map.remove(key);
+                }
+            }
+            true
+        }
+        (Segment::Index(index), Some(value)) => match node.get_mut(*index) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        },
+        (Segment::Index(_), None) => false,
+    }
+}
+
+impl AuditLog {
+    /// Open the audit log, loading the most recent entries from `path`
+    pub fn load(path: Option<&Path>, max_entries: usize) -> Result<Self> {
+        let mut log = Self {
+            entries: VecDeque::new(),
+            next_id: 1,
+            max_entries: max_entries.max(1),
+            path: path.map(Path::to_path_buf),
+            file_lines: 0,
+        };
+
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(log),
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(log),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read audit log {}", path.display()))
+            }
+        };
+
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            log.file_lines += 1;
+            // A torn final line from a crash is skipped rather than fatal
+            if let Ok(entry) = serde_json::from_str::<AuditEntry>(line) {
+                log.next_id = log.next_id.max(entry.id + 1);
+                log.push(entry);
+            }
+        }
+        Ok(log)
+    }
+
+    fn push(&mut self, entry: AuditEntry) {
+        self.entries.push_back(entry);
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Record a mutation, returning the new entry; does nothing if there
+    /// are no changes
+    pub fn record(
+        &mut self,
+        actor: &str,
+        endpoint: &str,
+        kind: &str,
+        target: Option<&str>,
+        changes: Vec<Change>,
+    ) -> Result<Option<AuditEntry>> {
+        if changes.is_empty() {
+            return Ok(None);
+        }
+
+        let entry = AuditEntry {
+            id: self.next_id,
+            timestamp_ms: now_ms(),
+            actor: actor.to_string(),
+            endpoint: endpoint.to_string(),
+            kind: kind.to_string(),
+            target: target.map(str::to_string),
+            changes,
+        };
+        self.next_id += 1;
+
+        if let Some(path) = &self.path {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)
+                .context("Failed to write audit log")?;
+            self.file_lines += 1;
+        }
+        self.push(entry.clone());
+
+        if self.file_lines >= self.max_entries * 2 {
+            self.compact()?;
+        }
+        Ok(Some(entry))
+    }
+
+    /// Rewrite the file with only the retained entries
+    fn compact(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("jsonl.tmp");
+        let mut text = String::new();
+        for entry in &self.entries {
+            text.push_str(&serde_json::to_string(entry)?);
+            text.push('\n');
+        }
+        std::fs::write(&tmp, text)
+            .with_context(|| format!("Failed to write audit log {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to replace audit log {}", path.display()))?;
+        self.file_lines = self.entries.len();
+        Ok(())
+    }
+
+    /// Entries matching the filter, oldest first
+    pub fn query(&self, filter: &AuditFilter) -> Vec<&AuditEntry> {
+        self.entries
+            .iter()
+            .filter(|e| !matches!(filter.since_ms, Some(since) if e.timestamp_ms < since))
+            .filter(|e| !matches!(&filter.actor, Some(actor) if &e.actor != actor))
+            .filter(|e| !matches!(&filter.kind, Some(kind) if &e.kind != kind))
+            .collect()
+    }
+
+    /// The retained entry with this id
+    pub fn get(&self, id: u64) -> Option<&AuditEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    /// The `count` most recent entries, oldest first
+    pub fn recent(&self, count: usize) -> Vec<AuditEntry> {
+        let skip = self.entries.len().saturating_sub(count);
+        self.entries.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::config::{Config, ServoConfig};
+    use crate::library::{Library, Pose};
+    use crate::testing::TempDir;
+
+    fn change(path: &str, before: Option<Value>, after: Option<Value>) -> Change {
+        Change {
+            path: path.to_string(),
+            before,
+            after,
+        }
+    }
+
+    #[test]
+    fn changed_limit_is_diffed_to_its_leaf() {
+        let mut config = Config::default();
+        config.servos.push(ServoConfig {
+            channel: 2,
+            ..ServoConfig::default()
+        });
+        let before = serde_json::to_value(&config).unwrap();
+        config.servos[0].max = 150;
+        let after = serde_json::to_value(&config).unwrap();
+
+        assert_eq!(
+            diff(&before, &after),
+            [change("servos[0].max", Some(json!(180)), Some(json!(150)))]
+        );
+    }
+
+    #[test]
+    fn saved_pose_is_diffed_by_name() {
+        let mut library = Library::default();
+        let pose = |angles: Vec<u16>| Pose {
+            angles,
+            preconditions: Default::default(),
+        };
+        library.poses.insert("home".to_string(), pose(vec![90, 90]));
+        let before = serde_json::to_value(&library).unwrap();
+        library.poses.insert("home".to_string(), pose(vec![90, 45]));
+        library.poses.insert("tuck.low".to_string(), pose(vec![0]));
+        let after = serde_json::to_value(&library).unwrap();
+
+        assert_eq!(
+            diff(&before, &after),
+            [
+                change("poses.home.angles[1]", Some(json!(90)), Some(json!(45))),
+                change("poses[\"tuck.low\"]", None, Some(json!({ "angles": [0] }))),
+            ]
+        );
+    }
+
+    #[test]
+    fn resized_arrays_and_removed_keys_are_whole_changes() {
+        let before = json!({ "home": [90, 90], "name": "arm" });
+        let after = json!({ "home": [90, 90, 90] });
+        assert_eq!(
+            diff(&before, &after),
+            [
+                change("home", Some(json!([90, 90])), Some(json!([90, 90, 90]))),
+                change("name", Some(json!("arm")), None),
+            ]
+        );
+        assert_eq!(
+            diff(&json!(1), &json!(2)),
+            [change(".", Some(json!(1)), Some(json!(2)))]
+        );
+        assert!(diff(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn diff_applies_back_onto_the_document() {
+        let before = json!({ "poses": { "a.b": { "angles": [1, 2] } }, "trim": 0 });
+        let after = json!({ "poses": { "a.b": { "angles": [1, 3] }, "c": {} }, "trim": 4 });
+        let changes = diff(&before, &after);
+
+        let mut doc = before.clone();
+        assert!(apply(&mut doc, &changes).is_empty());
+        assert_eq!(doc, after);
+    }
+
+    #[test]
+    fn diverging_documents_are_reported_but_applied() {
+        let mut doc = json!({ "trim": 1, "home": [] });
+        let divergences = apply(
+            &mut doc,
+            &[
+                change("trim", Some(json!(0)), Some(json!(4))),
+                change("home[3]", Some(json!(90)), Some(json!(45))),
+                change("poses[\"x", None, Some(json!(1))),
+            ],
+        );
+        assert_eq!(
+            divergences,
+            [
+                "trim: expected 0, found 1",
+                "home[3]: expected 90, found nothing",
+                "home[3]: parent missing, not applied",
+                "poses[\"x: unparseable path",
+            ]
+        );
+        assert_eq!(doc["trim"], 4);
+    }
+
+    #[test]
+    fn entries_are_recorded_and_queried() {
+        let mut log = AuditLog::load(None, 10).unwrap();
+        let changes = || vec![change("trim", Some(json!(0)), Some(json!(1)))];
+        assert!(log
+            .record("ann", "PUT /api/x", "config", None, Vec::new())
+            .unwrap()
+            .is_none());
+        let first = log
+            .record("ann", "PUT /api/config", "config", None, changes())
+            .unwrap()
+            .unwrap();
+        log.record(
+            "bob",
+            "PUT /api/poses/:name",
+            "pose",
+            Some("home"),
+            changes(),
+        )
+        .unwrap();
+
+        assert_eq!(first.id, 1);
+        let by_bob = log.query(&AuditFilter {
+            actor: Some("bob".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(by_bob.len(), 1);
+        assert_eq!(by_bob[0].target.as_deref(), Some("home"));
+        let poses = log.query(&AuditFilter {
+            kind: Some("pose".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(poses[0].id, 2);
+        let later = log.query(&AuditFilter {
+            since_ms: Some(first.timestamp_ms + 60_000),
+            ..Default::default()
+        });
+        assert!(later.is_empty());
+        assert_eq!(log.get(2).unwrap().actor, "bob");
+    }
+
+    #[test]
+    fn log_is_bounded_in_memory_and_on_disk() {
+        let dir = TempDir::new("audit");
+        let path = dir.join("audit.jsonl");
+        let mut log = AuditLog::load(Some(&path), 3).unwrap();
+        for i in 0..7 {
+            let changes = vec![change("trim", Some(json!(i)), Some(json!(i + 1)))];
+            log.record("ann", "PUT /api/config", "config", None, changes)
+                .unwrap();
+        }
+
+        let ids = |log: &AuditLog| log.recent(10).iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(&log), [5, 6, 7]);
+        // Compacted to 3 lines at the 6th, then one more appended
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 4);
+
+        // A torn last line is skipped, and ids carry on after the others
+        std::fs::write(&path, text + "{\"id\": 8, \"times").unwrap();
+        let mut reloaded = AuditLog::load(Some(&path), 3).unwrap();
+        assert_eq!(ids(&reloaded), [5, 6, 7]);
+        let entry = reloaded
+            .record(
+                "ann",
+                "PUT /api/config",
+                "config",
+                None,
+                vec![change("x", None, Some(json!(1)))],
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.id, 8);
+    }
+}
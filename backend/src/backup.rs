@@ -0,0 +1,424 @@
+use anyhow::{Context, Result};
+use reqwest::redirect::Policy;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::config::{redact_url, BackupConfig};
+use crate::handlers::{self, AppState};
+use crate::models::Snapshot;
+
+/// Time given changes to settle after a mutation, so a burst of them is
+/// backed up once
+const SETTLE: Duration = Duration::from_secs(5);
+
+/// Time allowed for the whole PUT
+const PUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Name of the files written to `backup.dir`, `<PREFIX><ms>.json`
+const FILE_PREFIX: &str = "snapshot-";
+
+/// Backups of the snapshot document to the `[backup]` sink
+///
+/// The content hash covers the configuration, library and compensation
+/// models, so a backup is only made when one of them changed; known
+/// positions and the audit tail are pushed along but don't count as
+/// changes.
+#[derive(Default)]
+pub struct Backups {
+    status: Mutex<BackupStatus>,
+    /// Signalled after a mutation
+    changed: Notify,
+    /// Held while a backup is made, so a forced one doesn't race the loop
+    running: tokio::sync::Mutex<()>,
+}
+
+/// Outcome of the backups so far, for `GET /api/backup/status`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackupStatus {
+    /// Times in ms since the epoch
+    pub last_attempt_ms: Option<u64>,
+    pub last_success_ms: Option<u64>,
+    /// Error of the last attempt, cleared by a success
+    pub last_error: Option<String>,
+    /// Content hash of the last backup made
+    pub last_hash: Option<String>,
+    /// File written or URL put to by the last backup
+    pub last_target: Option<String>,
+    pub backups: u64,
+    /// Checks that found nothing changed since the last backup
+    pub unchanged: u64,
+}
+
+/// What a backup did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Written,
+    Unchanged,
+}
+
+impl Backups {
+    /// Note a mutation of the configuration, library or compensation
+    pub fn changed(&self) {
+        self.changed.notify_one();
+    }
+
+    pub fn status(&self) -> BackupStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Back up once at startup, then every `interval_ms` and shortly after
+/// mutations, while a sink is configured
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        if state.config().backup.enabled() {
+            if let Err(e) = backup(&state, false).await {
+                warn!("Backup failed: {}", e);
+            }
+        }
+        let interval = Duration::from_millis(state.config().backup.interval_ms);
+        tokio::select! {
+            _ = state.clock.sleep(interval) => {}
+            _ = state.backups.changed.notified() => state.clock.sleep(SETTLE).await,
+        }
+    }
+}
+
+/// Back up the snapshot if it changed since the last backup, or
+/// regardless if `force`d
+pub async fn backup(state: &Arc<AppState>, force: bool) -> Result<Outcome, String> {
+    let _running = state.backups.running.lock().await;
+    let config = state.config().backup.clone();
+    let snapshot = handlers::snapshot(state);
+    let hash = content_hash(&json!({
+        "config": snapshot.config,
+        "library": snapshot.library,
+        "compensation": snapshot.compensation,
+    }));
+
+    let mut previous = state.backups.status().last_hash;
+    if previous.is_none() {
+        // Continue from the files of the last run rather than adding a copy
+        // of the newest on every start
+        if let Some(dir) = config.dir.clone() {
+            previous = tokio::task::spawn_blocking(move || newest_hash(&dir))
+                .await
+                .ok()
+                .flatten();
+        }
+    }
+    if !force && previous.as_deref() == Some(hash.as_str()) {
+        let mut status = state.backups.status.lock().unwrap();
+        status.unchanged += 1;
+        status.last_hash = previous;
+        debug!("Backup skipped, nothing changed");
+        return Ok(Outcome::Unchanged);
+    }
+
+    let attempt_ms = state.clock.now_ms();
+    state.backups.status.lock().unwrap().last_attempt_ms = Some(attempt_ms);
+    let result = write(&config, &snapshot).await;
+
+    let mut status = state.backups.status.lock().unwrap();
+    match result {
+        Ok(target) => {
+            info!("Backed up state to {}", target);
+            status.last_success_ms = Some(attempt_ms);
+            status.last_error = None;
+            status.last_hash = Some(hash);
+            status.last_target = Some(target);
+            status.backups += 1;
+            Ok(Outcome::Written)
+        }
+        Err(e) => {
+            let message = format!("{:#}", e);
+            status.last_error = Some(message.clone());
+            Err(message)
+        }
+    }
+}
+
+/// Write the snapshot to the configured sink; returns where it went
+async fn write(config: &BackupConfig, snapshot: &Snapshot) -> Result<String> {
+    let body = serde_json::to_vec_pretty(snapshot)?;
+    if let Some(url) = &config.url {
+        put(url, config.token.as_deref(), body).await?;
+        Ok(redact_url(url))
+    } else if let Some(dir) = config.dir.clone() {
+        let (keep, timestamp_ms) = (config.keep, snapshot.timestamp_ms);
+        let path = tokio::task::spawn_blocking(move || write_file(&dir, keep, timestamp_ms, &body))
+            .await??;
+        Ok(path.display().to_string())
+    } else {
+        anyhow::bail!("No backup.url or backup.dir configured")
+    }
+}
+
+async fn put(url: &str, token: Option<&str>, body: Vec<u8>) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(PUT_TIMEOUT)
+        .redirect(Policy::none())
+        .build()?;
+    let mut request = client
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("PUT {} failed: {}", redact_url(url), e))?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("PUT {} answered {}", redact_url(url), status);
+    }
+    Ok(())
+}
+
+/// Write `<dir>/snapshot-<ms>.json` and remove all but the newest `keep`
+fn write_file(dir: &Path, keep: usize, timestamp_ms: u64, body: &[u8]) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create backup directory {}", dir.display()))?;
+    // Zero-padded so the names sort by time
+    let path = dir.join(format!("{}{:013}.json", FILE_PREFIX, timestamp_ms));
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, body).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    let files = backup_files(dir)?;
+    for old in &files[..files.len().saturating_sub(keep)] {
+        match std::fs::remove_file(old) {
+            Ok(()) => debug!("Removed old backup {}", old.display()),
+            Err(e) => warn!("Failed to remove old backup {}: {}", old.display(), e),
+        }
+    }
+    Ok(path)
+}
+
+/// Backup files in `dir`, oldest first
+fn backup_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to list backup directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(FILE_PREFIX) && name.ends_with(".json"))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Content hash of the newest backup file in `dir`, if any can be read
+fn newest_hash(dir: &Path) -> Option<String> {
+    let newest = backup_files(dir).ok()?.pop()?;
+    let text = std::fs::read_to_string(newest).ok()?;
+    let snapshot: Value = serde_json::from_str(&text).ok()?;
+    Some(content_hash(&json!({
+        "config": snapshot["config"],
+        "library": snapshot["library"],
+        "compensation": snapshot["compensation"],
+    })))
+}
+
+/// Hex SHA-256 of a document's JSON
+fn content_hash(document: &Value) -> String {
+    let digest = Sha256::digest(document.to_string().as_bytes());
+    let mut hash = String::with_capacity(64);
+    for byte in digest {
+        let _ = write!(hash, "{:02x}", byte);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, TestServer};
+    use axum::http::{HeaderMap, StatusCode};
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    /// Wait for the backup the loop makes at startup
+    async fn started(server: &TestServer) -> BackupStatus {
+        for _ in 0..200 {
+            let status = server.server.state().backups.status();
+            let checked = status.last_attempt_ms.is_some() || status.unchanged > 0;
+            if checked && server.server.state().backups.running.try_lock().is_ok() {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no backup at startup");
+    }
+
+    fn names(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir.join(""))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn only_the_newest_files_are_kept() {
+        let dir = TempDir::new("backup-keep");
+        std::fs::write(dir.join("notes.txt"), "mine").unwrap();
+        for timestamp_ms in [5000, 1000, 3000, 4000, 2000] {
+            let body = format!("{{\"at\": {}}}", timestamp_ms);
+            write_file(&dir.join("backups"), 3, timestamp_ms, body.as_bytes()).unwrap();
+        }
+        let path = write_file(&dir.join("backups"), 3, 1_700_000_000_000, b"{}").unwrap();
+        assert_eq!(path.file_name().unwrap(), "snapshot-1700000000000.json");
+        let files: Vec<String> = backup_files(&dir.join("backups"))
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            files,
+            [
+                "snapshot-0000000004000.json",
+                "snapshot-0000000005000.json",
+                "snapshot-1700000000000.json"
+            ]
+        );
+        // Nothing else is touched
+        assert_eq!(names(&dir), ["backups", "notes.txt"]);
+    }
+
+    #[test]
+    fn newest_file_gives_the_hash_to_continue_from() {
+        let dir = TempDir::new("backup-hash");
+        assert_eq!(newest_hash(&dir.join("")), None);
+        let old = json!({ "config": { "a": 1 }, "library": {}, "compensation": {} });
+        let new = json!({
+            "config": { "a": 2 },
+            "library": {},
+            "compensation": {},
+            "positions": [90],
+            "timestamp_ms": 2,
+        });
+        write_file(&dir.join(""), 5, 1, old.to_string().as_bytes()).unwrap();
+        write_file(&dir.join(""), 5, 2, new.to_string().as_bytes()).unwrap();
+        let expected = content_hash(&json!({
+            "config": { "a": 2 },
+            "library": {},
+            "compensation": {},
+        }));
+        assert_eq!(newest_hash(&dir.join("")), Some(expected.clone()));
+        assert_eq!(expected.len(), 64);
+        assert_ne!(content_hash(&old), content_hash(&new));
+
+        write_file(&dir.join(""), 5, 3, b"not json").unwrap();
+        assert_eq!(newest_hash(&dir.join("")), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backups_are_made_only_when_something_changed() {
+        let dir = TempDir::new("backup-changes");
+        let backups = dir.join("backups");
+        let serve = || {
+            let configured = backups.clone();
+            TestServer::with_config(|config| config.backup.dir = Some(configured))
+        };
+        let server = serve().await;
+        let status = started(&server).await;
+        assert_eq!((status.backups, status.unchanged), (1, 0));
+        assert!(status.last_error.is_none());
+        server.server.shutdown().await.unwrap();
+
+        // A restart continues from the files
+        let server = serve().await;
+        let state = server.server.state();
+        let status = started(&server).await;
+        assert_eq!((status.backups, status.unchanged), (0, 1));
+        assert_eq!(backup_files(&backups).unwrap().len(), 1);
+
+        assert_eq!(backup(state, false).await, Ok(Outcome::Unchanged));
+        // Positions aren't part of the hash
+        server.post("/api/pose", json!({ "angles": [10] })).await;
+        assert_eq!(backup(state, false).await, Ok(Outcome::Unchanged));
+
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        server
+            .put("/api/poses/rest", json!({ "angles": [10] }))
+            .await;
+        assert_eq!(backup(state, false).await, Ok(Outcome::Written));
+        let status = state.backups.status();
+        assert_eq!((status.backups, status.unchanged), (1, 3));
+        let newest = backup_files(&backups).unwrap().pop().unwrap();
+        assert_eq!(status.last_target, Some(newest.display().to_string()));
+        let snapshot: Value = serde_json::from_slice(&std::fs::read(&newest).unwrap()).unwrap();
+        assert_eq!(snapshot["library"]["poses"]["rest"]["angles"], json!([10]));
+        assert_eq!(newest_hash(&backups), status.last_hash);
+
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        assert_eq!(backup(state, true).await, Ok(Outcome::Written));
+        assert_eq!(backup_files(&backups).unwrap().len(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn snapshots_are_put_with_the_token() {
+        type Received = Arc<Mutex<Vec<(Option<String>, Value)>>>;
+        let received = Received::default();
+        let answer = Arc::new(AtomicU16::new(500));
+        let (recorder, status) = (received.clone(), answer.clone());
+        let app = axum::Router::new().route(
+            "/backup",
+            axum::routing::put(move |headers: HeaderMap, body: String| {
+                let authorization = headers
+                    .get("authorization")
+                    .map(|value| value.to_str().unwrap().to_string());
+                let body = serde_json::from_str(&body).unwrap();
+                recorder.lock().unwrap().push((authorization, body));
+                let status = StatusCode::from_u16(status.load(Ordering::Relaxed)).unwrap();
+                async move { status }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/backup", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let configured = url.clone();
+        let server = TestServer::with_config(|config| {
+            config.backup.url = Some(configured);
+            config.backup.token = Some("backup-secret".to_string());
+        })
+        .await;
+        let state = server.server.state();
+        // The endpoint failed the backup at startup
+        let status = started(&server).await;
+        let error = format!("PUT {} answered 500 Internal Server Error", url);
+        assert_eq!(status.last_error.as_deref(), Some(error.as_str()));
+        assert_eq!(status.last_success_ms, None);
+        assert_eq!(backup(state, false).await, Err(error));
+
+        answer.store(204, Ordering::Relaxed);
+        assert_eq!(backup(state, false).await, Ok(Outcome::Written));
+        let status = state.backups.status();
+        assert!(status.last_error.is_none());
+        assert_eq!(status.last_target, Some(url));
+        assert_eq!(backup(state, false).await, Ok(Outcome::Unchanged));
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        let (authorization, snapshot) = received.last().unwrap();
+        assert_eq!(authorization.as_deref(), Some("Bearer backup-secret"));
+        assert!(snapshot["library"].is_object());
+        assert!(snapshot["config"].is_object());
+    }
+}
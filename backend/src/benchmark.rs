@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::command_queue;
+use crate::controller::ServoController;
+use crate::handlers::{self, ApiError, AppState};
+use crate::protocol::{Angle, Channel};
+use crate::serial::{self, CommandOptions, SerialMode, NUM_SERVOS};
+
+/// Shortest and longest run
+pub const MIN_DURATION_MS: u64 = 100;
+pub const MAX_DURATION_MS: u64 = 60_000;
+
+/// Distinct failure messages kept in the report
+const MAX_ERRORS: usize = 5;
+
+/// Command sent over and over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BenchmarkCommand {
+    /// `GET` of each channel's angle in turn; nothing moves
+    Get,
+    /// `ANGLE` of each channel in turn, to the angle it is already at
+    Angle,
+    /// `POSE` of every channel, to where the arm already is
+    Pose,
+}
+
+impl BenchmarkCommand {
+    fn moves(self) -> bool {
+        self != BenchmarkCommand::Get
+    }
+}
+
+/// Why a run ended before its duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stopped {
+    /// A link loss latched during the run
+    LinkLossLatched,
+    /// An operator cleared the command queue
+    Cancelled,
+    /// The port failed; the connection was dropped for reconnection
+    Disconnected,
+}
+
+/// Round trips of the commands that succeeded
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Latency {
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Result of a run
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchmarkReport {
+    pub command: BenchmarkCommand,
+    pub duration_ms: u64,
+    /// Time actually spent sending, shorter than `duration_ms` if stopped
+    pub elapsed_ms: u64,
+    pub sent: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    /// Commands sent per second
+    pub rate_per_s: f64,
+    /// Commands that succeeded per second, the rate the link sustains
+    pub reliable_rate_per_s: f64,
+    pub latency: Option<Latency>,
+    /// The first few distinct failures
+    pub errors: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<Stopped>,
+}
+
+/// Send `command` back to back for `duration` and count how many went
+/// through
+///
+/// The motion commands re-send the positions the arm is at, so nothing
+/// moves, and go straight to the serial port: they aren't counted as
+/// wear or kept in the angle history. Afterwards the arm is sent to those
+/// positions again with a POSE, in case a failed command left a servo
+/// elsewhere, and the firmware goes back to button mode if it was in it.
+pub fn run(
+    state: &AppState,
+    serial: &dyn ServoController,
+    command: BenchmarkCommand,
+    duration: Duration,
+) -> Result<BenchmarkReport, ApiError> {
+    let _motion = state.begin_motion()?;
+    let mode = serial.mode();
+    let channels: Vec<Channel> = Channel::all(NUM_SERVOS).collect();
+    let mut positions = Vec::new();
+    if command.moves() {
+        for &channel in &channels {
+            positions.push(handlers::current_position(state, serial, channel)?);
+        }
+    }
+    let angles = handlers::to_compensated_angles(state, &state.config(), &positions)?;
+    if command.moves() && mode != Some(SerialMode::Serial) {
+        serial
+            .start_serial_mode()
+            .map_err(|e| handlers::handle_serial_error(state, &e))?;
+    }
+
+    info!("Benchmarking {:?} for {} ms", command, duration.as_millis());
+    let mut tally = Tally::default();
+    let started = Instant::now();
+    for &channel in channels.iter().cycle() {
+        if started.elapsed() >= duration {
+            break;
+        }
+        if state.link_loss.latched() {
+            tally.stopped = Some(Stopped::LinkLossLatched);
+            break;
+        }
+        let sent = Instant::now();
+        let result = send(serial, command, channel, &angles);
+        let latency = sent.elapsed();
+        match result {
+            Ok(()) => tally.succeeded(latency),
+            Err(e) if command_queue::is_cancelled(&e) => {
+                tally.stopped = Some(Stopped::Cancelled);
+                break;
+            }
+            Err(e) if serial::is_io_failure(&e) => {
+                tally.failed(&e);
+                // Drops the connection for reconnection; the report says why
+                // the run ended instead of the error
+                let _ = handlers::handle_serial_error(state, &e);
+                tally.stopped = Some(Stopped::Disconnected);
+                break;
+            }
+            Err(e) => tally.failed(&e),
+        }
+    }
+    let report = tally.report(command, duration, started.elapsed());
+    info!(
+        "Benchmark of {:?}: {} of {} succeeded, {:.1}/s",
+        command, report.succeeded, report.sent, report.reliable_rate_per_s
+    );
+
+    if report.stopped.is_none() || report.stopped == Some(Stopped::Cancelled) {
+        restore(state, serial, command, mode, &positions);
+    }
+    Ok(report)
+}
+
+fn send(
+    serial: &dyn ServoController,
+    command: BenchmarkCommand,
+    channel: Channel,
+    angles: &[Angle],
+) -> anyhow::Result<()> {
+    let opts = CommandOptions::default();
+    match command {
+        BenchmarkCommand::Get => serial.get_servo_angle(channel).map(drop),
+        BenchmarkCommand::Angle => serial.set_servo_angle(channel, angles[channel.index()], opts),
+        BenchmarkCommand::Pose => serial.execute_pose(angles, opts),
+    }
+}
+
+/// Put the arm and the firmware mode back as they were before the run
+fn restore(
+    state: &AppState,
+    serial: &dyn ServoController,
+    command: BenchmarkCommand,
+    mode: Option<SerialMode>,
+    positions: &[u16],
+) {
+    if !command.moves() {
+        return;
+    }
+    if let Err((_, e)) = handlers::run_pose(state, serial, positions, CommandOptions::default()) {
+        warn!("Failed to restore the pose after a benchmark: {}", e.error);
+    }
+    if mode == Some(SerialMode::Button) {
+        if let Err(e) = serial.stop_serial_mode() {
+            warn!("Failed to return to button mode after a benchmark: {:#}", e);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    succeeded: u64,
+    failed: u64,
+    total: Duration,
+    min: Option<Duration>,
+    max: Duration,
+    errors: Vec<String>,
+    stopped: Option<Stopped>,
+}
+
+impl Tally {
+    fn succeeded(&mut self, latency: Duration) {
+        self.succeeded += 1;
+        self.total += latency;
+        self.min = Some(self.min.map_or(latency, |min| min.min(latency)));
+        self.max = self.max.max(latency);
+    }
+
+    fn failed(&mut self, error: &anyhow::Error) {
+        self.failed += 1;
+        let error = error.to_string();
+        if self.errors.len() < MAX_ERRORS && !self.errors.contains(&error) {
+            self.errors.push(error);
+        }
+    }
+
+    fn report(
+        self,
+        command: BenchmarkCommand,
+        duration: Duration,
+        elapsed: Duration,
+    ) -> BenchmarkReport {
+        let sent = self.succeeded + self.failed;
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        BenchmarkReport {
+            command,
+            duration_ms: duration.as_millis() as u64,
+            elapsed_ms: elapsed.as_millis() as u64,
+            sent,
+            succeeded: self.succeeded,
+            failed: self.failed,
+            rate_per_s: sent as f64 / seconds,
+            reliable_rate_per_s: self.succeeded as f64 / seconds,
+            latency: self.min.map(|min| Latency {
+                min_ms: ms(min),
+                mean_ms: ms(self.total) / self.succeeded as f64,
+                max_ms: ms(self.max),
+            }),
+            errors: self.errors,
+            stopped: self.stopped,
+        }
+    }
+}
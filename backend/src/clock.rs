@@ -0,0 +1,191 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of time for the timing features: idle timeouts, motion
+/// estimates and the reconnect interval
+///
+/// Those read the time through [`AppState::clock`] instead of calling
+/// `Instant::now()` or `tokio::time` directly, so a test can swap in a
+/// [`MockClock`] and advance time deterministically.
+///
+/// [`AppState::clock`]: crate::handlers::AppState::clock
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for intervals and deadlines
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time in ms since the epoch, for timestamps in responses
+    fn now_ms(&self) -> u64;
+
+    /// Wait for `duration` to pass on this clock
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+    /// Block the thread until `duration` has passed on this clock, for
+    /// the serial link's blocking I/O
+    fn sleep_blocking(&self, duration: Duration);
+}
+
+/// The real clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn sleep_blocking(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when told to, for tests
+///
+/// It starts at the real time of its creation. Sleeps finish once
+/// [`advance`](MockClock::advance) has moved the clock past their end;
+/// a blocking sleep advances the clock itself, as nothing else runs on
+/// the blocked thread to do it.
+#[cfg(test)]
+pub struct MockClock {
+    start: Instant,
+    start_ms: u64,
+    time: std::sync::Arc<MockTime>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockTime {
+    elapsed: std::sync::Mutex<Duration>,
+    /// Starts and ends of the sleeps in progress, by id
+    sleeps: std::sync::Mutex<Vec<(u64, Duration, Duration)>>,
+    next_id: std::sync::atomic::AtomicU64,
+    /// Signalled when the clock moves, for sleeps
+    advanced: tokio::sync::Notify,
+    /// The same for [`MockClock::wait_until`]
+    advanced_blocking: std::sync::Condvar,
+    /// Signalled when a sleep starts
+    slept: tokio::sync::Notify,
+}
+
+/// A sleep in progress on a [`MockClock`], until dropped
+#[cfg(test)]
+struct Sleeping {
+    time: std::sync::Arc<MockTime>,
+    id: u64,
+}
+
+#[cfg(test)]
+impl Drop for Sleeping {
+    fn drop(&mut self) {
+        let mut sleeps = self.time.sleeps.lock().unwrap();
+        sleeps.retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            start_ms: SystemClock.now_ms(),
+            time: Default::default(),
+        }
+    }
+
+    /// Move the clock forward, waking the sleeps that are now over
+    pub fn advance(&self, duration: Duration) {
+        *self.time.elapsed.lock().unwrap() += duration;
+        self.time.advanced.notify_waiters();
+        self.time.advanced_blocking.notify_all();
+    }
+
+    /// Block the thread until the clock has been advanced to `until`, as
+    /// a sleep waits; for mocks of a device that takes time
+    pub fn wait_until(&self, until: Instant) {
+        let until = until.saturating_duration_since(self.start);
+        let elapsed = self.time.elapsed.lock().unwrap();
+        let _elapsed = self
+            .time
+            .advanced_blocking
+            .wait_while(elapsed, |elapsed| *elapsed < until)
+            .unwrap();
+    }
+
+    /// Wait until something goes to sleep now until a time `end`
+    /// accepts, checked again whenever a sleep starts
+    pub async fn sleeping(&self, end: impl Fn(Instant) -> bool) {
+        loop {
+            let started = self.time.slept.notified();
+            tokio::pin!(started);
+            started.as_mut().enable();
+            let now = self.elapsed();
+            let ends: Vec<_> = {
+                let sleeps = self.time.sleeps.lock().unwrap();
+                sleeps
+                    .iter()
+                    .filter(|(_, from, _)| *from == now)
+                    .map(|(_, _, until)| self.start + *until)
+                    .collect()
+            };
+            if ends.into_iter().any(&end) {
+                return;
+            }
+            started.await;
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.time.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.start_ms + self.elapsed().as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> {
+        let from = self.elapsed();
+        let until = from + duration;
+        let time = self.time.clone();
+        let id = time
+            .next_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        time.sleeps.lock().unwrap().push((id, from, until));
+        time.slept.notify_waiters();
+        let sleeping = Sleeping { time, id };
+        Box::pin(async move {
+            let time = &sleeping.time;
+            loop {
+                // Registered before checking, so an advance in between
+                // isn't missed
+                let notified = time.advanced.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if *time.elapsed.lock().unwrap() >= until {
+                    return;
+                }
+                notified.await;
+            }
+        })
+    }
+
+    fn sleep_blocking(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
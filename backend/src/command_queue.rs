@@ -0,0 +1,349 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+use crate::protocol;
+
+/// Longest summary of a command kept for `GET /api/queue`
+const MAX_SUMMARY: usize = 80;
+
+/// Start of the error of a command an operator took off the queue
+pub const CANCELLED: &str = "Cancelled by an operator";
+
+tokio::task_local! {
+    /// Who a request's commands are queued for, set around every request
+    pub static REQUESTER: String;
+}
+
+/// Who the current task's commands are for, `background` outside a request
+pub fn requester() -> String {
+    REQUESTER
+        .try_with(|requester| requester.clone())
+        .unwrap_or_else(|_| "background".to_string())
+}
+
+/// Whether an error means the command was taken off the queue
+pub fn is_cancelled(error: &anyhow::Error) -> bool {
+    error.to_string().starts_with(CANCELLED)
+}
+
+/// A command waiting for the serial port, or being sent
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueEntry {
+    pub id: u64,
+    /// Name of the command in `GET /api/protocol`, `raw` if unknown
+    pub command: &'static str,
+    /// The command line, shortened
+    pub summary: String,
+    pub requester: String,
+    pub age_ms: u64,
+    /// A later command of the same kind makes this one redundant
+    pub coalescible: bool,
+    /// Moves the arm or switches the mode; kept by a flush
+    pub critical: bool,
+    /// Handed to the serial port, so it can't be cancelled any more
+    pub in_flight: bool,
+}
+
+struct Entry {
+    info: QueueEntry,
+    enqueued: Instant,
+    cancelled: bool,
+}
+
+impl Entry {
+    fn snapshot(&self, now: Instant) -> QueueEntry {
+        QueueEntry {
+            age_ms: now.saturating_duration_since(self.enqueued).as_millis() as u64,
+            ..self.info.clone()
+        }
+    }
+}
+
+/// Why an entry couldn't be cancelled
+pub enum CancelError {
+    NotFound,
+    InFlight(QueueEntry),
+}
+
+/// Commands waiting for the serial port, served in order
+///
+/// Every command to the firmware takes its turn here before locking the
+/// port, so operators can see what's stuck behind a slow one and take
+/// pending commands off again.
+#[derive(Default)]
+pub struct CommandQueue {
+    inner: Mutex<Inner>,
+    turn: Condvar,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    /// The front one is in flight once its turn came
+    entries: VecDeque<Entry>,
+}
+
+/// The command's turn at the port, until dropped
+pub struct Turn<'a> {
+    queue: &'a CommandQueue,
+    id: u64,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.queue.inner.lock().unwrap();
+        inner.entries.retain(|entry| entry.info.id != self.id);
+        self.queue.turn.notify_all();
+    }
+}
+
+impl CommandQueue {
+    /// Queue the (unframed) command `cmd` and wait for its turn; fails if
+    /// an operator cancelled it meanwhile
+    pub fn enter(&self, cmd: &str) -> anyhow::Result<Turn<'_>> {
+        let command = protocol::command_spec(cmd).map_or("raw", |spec| spec.name);
+        let mut summary = cmd.trim().to_string();
+        if summary.len() > MAX_SUMMARY {
+            let mut end = MAX_SUMMARY;
+            while !summary.is_char_boundary(end) {
+                end -= 1;
+            }
+            summary.truncate(end);
+            summary.push_str("...");
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.entries.push_back(Entry {
+            info: QueueEntry {
+                id,
+                command,
+                summary,
+                requester: requester(),
+                age_ms: 0,
+                coalescible: coalescible(command),
+                critical: critical(command),
+                in_flight: false,
+            },
+            enqueued: Instant::now(),
+            cancelled: false,
+        });
+        loop {
+            let position = inner
+                .entries
+                .iter()
+                .position(|entry| entry.info.id == id)
+                .expect("only the waiter removes its entry");
+            if inner.entries[position].cancelled {
+                inner.entries.remove(position);
+                self.turn.notify_all();
+                anyhow::bail!("{} while queued for the serial port", CANCELLED);
+            }
+            if position == 0 {
+                inner.entries[0].info.in_flight = true;
+                return Ok(Turn { queue: self, id });
+            }
+            inner = self.turn.wait(inner).unwrap();
+        }
+    }
+
+    /// Entries in order, the one in flight first
+    pub fn list(&self) -> Vec<QueueEntry> {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner
+            .entries
+            .iter()
+            .filter(|entry| !entry.cancelled)
+            .map(|entry| entry.snapshot(now))
+            .collect()
+    }
+
+    /// Take a pending entry off the queue; its caller fails with
+    /// [`CANCELLED`]
+    pub fn cancel(&self, id: u64) -> Result<QueueEntry, CancelError> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let entry = inner
+            .entries
+            .iter_mut()
+            .find(|entry| entry.info.id == id && !entry.cancelled)
+            .ok_or(CancelError::NotFound)?;
+        if entry.info.in_flight {
+            return Err(CancelError::InFlight(entry.snapshot(now)));
+        }
+        entry.cancelled = true;
+        let cancelled = entry.snapshot(now);
+        self.turn.notify_all();
+        Ok(cancelled)
+    }
+
+    /// Cancel every pending entry that isn't critical; returns them
+    pub fn flush(&self) -> Vec<QueueEntry> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let mut cancelled = Vec::new();
+        for entry in inner.entries.iter_mut() {
+            if !entry.cancelled && !entry.info.in_flight && !entry.info.critical {
+                entry.cancelled = true;
+                cancelled.push(entry.snapshot(now));
+            }
+        }
+        self.turn.notify_all();
+        cancelled
+    }
+}
+
+/// Reads and absolute targets, which a newer one of the same kind
+/// supersedes
+fn coalescible(command: &str) -> bool {
+    matches!(
+        command,
+        "get"
+            | "busy"
+            | "volt"
+            | "faults"
+            | "speed"
+            | "set_angle"
+            | "set_angle_extended"
+            | "set_pwm"
+            | "pose"
+    )
+}
+
+/// Commands whose loss would leave the arm or the mode other than the
+/// caller expects; unknown ones count as such
+fn critical(command: &str) -> bool {
+    !matches!(command, "get" | "busy" | "volt" | "faults" | "clrfaults" | "speed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    /// Queue `cmd` from another thread as `requester`, holding the turn
+    /// briefly once it comes
+    fn queued(
+        queue: &Arc<CommandQueue>,
+        cmd: &'static str,
+        requester: &str,
+    ) -> JoinHandle<anyhow::Result<()>> {
+        let expected = queue.list().len() + 1;
+        let waiter = queue.clone();
+        let requester = requester.to_string();
+        let handle = thread::spawn(move || {
+            REQUESTER.sync_scope(requester, || {
+                let _turn = waiter.enter(cmd)?;
+                Ok(())
+            })
+        });
+        wait_for(queue, expected);
+        handle
+    }
+
+    fn wait_for(queue: &CommandQueue, len: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while queue.list().len() != len {
+            assert!(Instant::now() < deadline, "{:?}", queue.list());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn entries_are_listed_in_order() {
+        let queue = Arc::new(CommandQueue::default());
+        let turn = queue.enter("MOVE 500 90,90").unwrap();
+        let get = queued(&queue, "GET 1", "alice");
+        let pose = queued(&queue, "POSE 10,20", "bob");
+        let raw = queued(&queue, "FROB", "bob");
+
+        let entries = queue.list();
+        let ids: Vec<u64> = entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+        let commands: Vec<&str> = entries.iter().map(|entry| entry.command).collect();
+        assert_eq!(commands, ["move", "get", "pose", "raw"]);
+        assert_eq!(entries[0].requester, "background");
+        assert_eq!(entries[1].requester, "alice");
+        assert_eq!(entries[1].summary, "GET 1");
+        let flags = |entry: &QueueEntry| (entry.in_flight, entry.coalescible, entry.critical);
+        assert_eq!(flags(&entries[0]), (true, false, true));
+        assert_eq!(flags(&entries[1]), (false, true, false));
+        assert_eq!(flags(&entries[2]), (false, true, true));
+        assert_eq!(flags(&entries[3]), (false, false, true));
+
+        // Each takes its turn after the one before
+        drop(turn);
+        for handle in [get, pose, raw] {
+            handle.join().unwrap().unwrap();
+        }
+        assert!(queue.list().is_empty());
+        assert_eq!(queue.enter("GET 1").unwrap().id, 5);
+    }
+
+    #[test]
+    fn long_summaries_are_shortened() {
+        let queue = CommandQueue::default();
+        let cmd = format!("RAW {}", "é".repeat(60));
+        let _turn = queue.enter(&cmd).unwrap();
+        let summary = &queue.list()[0].summary;
+        assert!(summary.ends_with("..."));
+        assert!(summary.len() <= MAX_SUMMARY + 3);
+        assert!(cmd.starts_with(summary.trim_end_matches("...")));
+    }
+
+    #[test]
+    fn pending_entries_are_cancelled() {
+        let queue = Arc::new(CommandQueue::default());
+        let turn = queue.enter("POSE 10,20").unwrap();
+        let get = queued(&queue, "GET 1", "alice");
+        let volt = queued(&queue, "VOLT", "alice");
+
+        let cancelled = queue.cancel(2).ok().unwrap();
+        assert_eq!((cancelled.id, cancelled.command), (2, "get"));
+        let error = get.join().unwrap().unwrap_err();
+        assert!(is_cancelled(&error));
+        assert_eq!(
+            error.to_string(),
+            "Cancelled by an operator while queued for the serial port"
+        );
+        let ids: Vec<u64> = queue.list().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert!(matches!(queue.cancel(2), Err(CancelError::NotFound)));
+        assert!(matches!(queue.cancel(99), Err(CancelError::NotFound)));
+
+        // The one in flight stays
+        match queue.cancel(1) {
+            Err(CancelError::InFlight(entry)) => assert!(entry.in_flight),
+            _ => panic!("the entry in flight was cancelled"),
+        }
+        drop(turn);
+        volt.join().unwrap().unwrap();
+        assert!(!is_cancelled(&anyhow::anyhow!("Serial timeout")));
+    }
+
+    #[test]
+    fn flush_keeps_critical_and_in_flight_entries() {
+        let queue = Arc::new(CommandQueue::default());
+        let turn = queue.enter("GET 1").unwrap();
+        let get = queued(&queue, "GET 2", "alice");
+        let pose = queued(&queue, "POSE 10,20", "bob");
+        let faults = queued(&queue, "FAULTS", "alice");
+
+        let flushed: Vec<u64> = queue.flush().iter().map(|entry| entry.id).collect();
+        assert_eq!(flushed, [2, 4]);
+        assert!(is_cancelled(&get.join().unwrap().unwrap_err()));
+        assert!(is_cancelled(&faults.join().unwrap().unwrap_err()));
+        let ids: Vec<u64> = queue.list().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert!(queue.flush().is_empty());
+
+        drop(turn);
+        pose.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,536 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::persist::{self, Persister};
+use crate::serial::NUM_SERVOS;
+
+/// Highest power of an input angle in a model
+pub const MAX_DEGREE: u8 = 3;
+
+/// Most input channels of a model
+pub const MAX_INPUTS: usize = 4;
+
+/// Largest correction applied, in degrees; a model asking for more is
+/// capped
+pub const MAX_CORRECTION: f64 = 20.0;
+
+/// Correction of a channel's angle for sag under load, as a polynomial of
+/// other channels' angles
+///
+/// Each input angle enters as `x = (angle - 90) / 90`. The correction in
+/// degrees is `coefficients[0]` plus, for each input in order, the terms
+/// `x`, `x²`, ... up to `degree`. It is added to the commanded angle and
+/// taken off the angle read back.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Model {
+    pub inputs: Vec<u8>,
+    pub degree: u8,
+    pub coefficients: Vec<f64>,
+}
+
+/// An angle measured on the arm, for [`fit`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Sample {
+    /// Angle commanded without compensation
+    pub commanded: u16,
+    /// Angle the joint actually reached
+    pub measured: f64,
+    /// Angles of the other channels at the time, by channel
+    pub context: BTreeMap<u8, u16>,
+}
+
+/// A model fitted to samples and how well it matches them
+#[derive(Debug, Clone, Serialize)]
+pub struct Fit {
+    pub model: Model,
+    /// Root mean square and largest deviation of the fitted corrections
+    /// from the measured sag, in degrees
+    pub rms_error: f64,
+    pub max_error: f64,
+}
+
+fn normalized(angle: u16) -> f64 {
+    (angle as f64 - 90.0) / 90.0
+}
+
+/// Terms of the polynomial for input values `xs`, constant first
+fn basis(xs: &[f64], degree: u8) -> Vec<f64> {
+    let mut terms = vec![1.0];
+    for &x in xs {
+        let mut power = 1.0;
+        for _ in 0..degree {
+            power *= x;
+            terms.push(power);
+        }
+    }
+    terms
+}
+
+impl Model {
+    /// Check the model for `channel`
+    pub fn validate(&self, channel: u8) -> Result<(), String> {
+        if self.degree == 0 || self.degree > MAX_DEGREE {
+            return Err(format!("degree must be 1-{}", MAX_DEGREE));
+        }
+        if self.inputs.is_empty() || self.inputs.len() > MAX_INPUTS {
+            return Err(format!("inputs must list 1-{} channels", MAX_INPUTS));
+        }
+        let mut seen = BTreeSet::new();
+        for &input in &self.inputs {
+            if input >= NUM_SERVOS {
+                return Err(format!("Input channel {} is out of range", input));
+            }
+            if input == channel {
+                return Err(format!(
+                    "Channel {} can't be an input of its own model",
+                    channel
+                ));
+            }
+            if !seen.insert(input) {
+                return Err(format!("Input channel {} is listed twice", input));
+            }
+        }
+        let expected = 1 + self.inputs.len() * self.degree as usize;
+        if self.coefficients.len() != expected {
+            return Err(format!(
+                "Expected {} coefficients for {} inputs of degree {}, got {}",
+                expected,
+                self.inputs.len(),
+                self.degree,
+                self.coefficients.len()
+            ));
+        }
+        if self.coefficients.iter().any(|c| !c.is_finite()) {
+            return Err("Coefficients must be finite".to_string());
+        }
+        Ok(())
+    }
+
+    /// Correction in degrees with the channels at `angles` (positional);
+    /// `None` if an input's angle is unknown
+    pub fn correction(&self, angles: &[Option<u16>]) -> Option<f64> {
+        let xs = self
+            .inputs
+            .iter()
+            .map(|&input| {
+                angles
+                    .get(input as usize)
+                    .copied()
+                    .flatten()
+                    .map(normalized)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let value: f64 = basis(&xs, self.degree)
+            .iter()
+            .zip(&self.coefficients)
+            .map(|(term, c)| term * c)
+            .sum();
+        Some(value.clamp(-MAX_CORRECTION, MAX_CORRECTION))
+    }
+}
+
+/// Fit a model of the given inputs and degree to measured samples by least
+/// squares
+///
+/// The correction fitted for each sample is the sag it showed,
+/// `commanded - measured`.
+pub fn fit(inputs: &[u8], degree: u8, samples: &[Sample]) -> Result<Fit, String> {
+    let terms = 1 + inputs.len() * degree as usize;
+    if samples.len() < terms {
+        return Err(format!(
+            "{} coefficients need at least {} samples, got {}",
+            terms,
+            terms,
+            samples.len()
+        ));
+    }
+
+    let mut rows = Vec::with_capacity(samples.len());
+    for (i, sample) in samples.iter().enumerate() {
+        let xs = inputs
+            .iter()
+            .map(|input| sample.context.get(input).copied().map(normalized))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("Sample {} lacks an angle for every input", i))?;
+        let sag = sample.commanded as f64 - sample.measured;
+        rows.push((basis(&xs, degree), sag));
+    }
+
+    // Normal equations AᵀA c = Aᵀy
+    let mut ata = vec![vec![0.0; terms]; terms];
+    let mut aty = vec![0.0; terms];
+    for (row, y) in &rows {
+        for i in 0..terms {
+            aty[i] += row[i] * y;
+            for j in 0..terms {
+                ata[i][j] += row[i] * row[j];
+            }
+        }
+    }
+    let coefficients = solve(ata, aty)
+        .ok_or_else(|| "The samples don't vary the inputs enough to fit this degree".to_string())?;
+
+    let model = Model {
+        inputs: inputs.to_vec(),
+        degree,
+        coefficients,
+    };
+    let errors: Vec<f64> = rows
+        .iter()
+        .map(|(row, y)| {
+            row.iter()
+                .zip(&model.coefficients)
+                .map(|(t, c)| t * c)
+                .sum::<f64>()
+                - y
+        })
+        .collect();
+    let rms_error = (errors.iter().map(|e| e * e).sum::<f64>() / errors.len() as f64).sqrt();
+    let max_error = errors.iter().fold(0.0, |max: f64, e| max.max(e.abs()));
+    Ok(Fit {
+        model,
+        rms_error,
+        max_error,
+    })
+}
+
+/// Solve `a x = b` by Gaussian elimination with partial pivoting; `None`
+/// if `a` is (nearly) singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    let scale = a.iter().flatten().fold(0.0, |max: f64, v| max.max(v.abs()));
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() <= scale * 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..n {
+            let (upper, lower) = a.split_at_mut(row);
+            let factor = lower[0][col] / upper[col][col];
+            for (value, pivot) in lower[0][col..].iter_mut().zip(&upper[col][col..]) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Sag compensation models by channel, kept in `compensation_file` (if
+/// set) across restarts
+#[derive(Default)]
+pub struct Compensation {
+    models: Mutex<BTreeMap<u8, Model>>,
+    path: Option<PathBuf>,
+    persister: Persister,
+}
+
+impl Compensation {
+    /// Load the models from `path`, or its backup if the file is corrupt; a
+    /// missing file has none
+    pub fn load(path: Option<&Path>, persister: &Persister) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let models: BTreeMap<u8, Model> = persist::load(path, persister, |text| {
+            serde_json::from_str(text)
+                .with_context(|| format!("Failed to parse compensation {}", path.display()))
+        })?
+        .unwrap_or_default();
+        for (&channel, model) in &models {
+            model
+                .validate(channel)
+                .map_err(|e| anyhow::anyhow!("Invalid model for channel {}: {}", channel, e))?;
+        }
+        Ok(Self {
+            models: Mutex::new(models),
+            path: Some(path.to_path_buf()),
+            persister: persister.clone(),
+        })
+    }
+
+    pub fn all(&self) -> BTreeMap<u8, Model> {
+        self.models.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, channel: u8) -> Option<Model> {
+        self.models.lock().unwrap().get(&channel).cloned()
+    }
+
+    /// Correction for `channel` with the channels at `angles`, 0 without a
+    /// model or with an input's angle unknown
+    pub fn correction(&self, channel: u8, angles: &[Option<u16>]) -> f64 {
+        let models = self.models.lock().unwrap();
+        models
+            .get(&channel)
+            .and_then(|model| model.correction(angles))
+            .unwrap_or_default()
+    }
+
+    /// Set or remove the model of a channel, saving the models; returns
+    /// the previous one
+    pub fn set(&self, channel: u8, model: Option<Model>) -> Result<Option<Model>> {
+        let mut models = self.models.lock().unwrap();
+        let mut updated = models.clone();
+        let previous = match model {
+            Some(model) => updated.insert(channel, model),
+            None => updated.remove(&channel),
+        };
+        self.save(&updated)?;
+        *models = updated;
+        Ok(previous)
+    }
+
+    /// Queue the models to be written to disk
+    fn save(&self, models: &BTreeMap<u8, Model>) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        self.persister.submit(path, serde_json::to_vec_pretty(models)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, TestServer};
+    use serde_json::json;
+
+    /// Elbow (channel 2) sagging with the shoulder (channel 1) and the
+    /// base (channel 0)
+    fn sag_model() -> Model {
+        Model {
+            inputs: vec![1, 0],
+            degree: 2,
+            coefficients: vec![-3.0, 1.5, -2.0, 0.25, 0.5],
+        }
+    }
+
+    fn angles(context: &BTreeMap<u8, u16>) -> Vec<Option<u16>> {
+        (0..NUM_SERVOS)
+            .map(|channel| context.get(&channel).copied())
+            .collect()
+    }
+
+    /// Samples the arm would show under `model`, plus `noise` of each
+    fn samples(model: &Model, noise: impl Fn(usize) -> f64) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for shoulder in (30..=150).step_by(30) {
+            for base in (0..=180).step_by(45) {
+                let context = BTreeMap::from([(0, base), (1, shoulder)]);
+                let sag = model.correction(&angles(&context)).unwrap();
+                samples.push(Sample {
+                    commanded: 100,
+                    measured: 100.0 - sag + noise(samples.len()),
+                    context,
+                });
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn known_polynomial_is_recovered() {
+        let model = sag_model();
+        let fit = fit(&model.inputs, model.degree, &samples(&model, |_| 0.0)).unwrap();
+        assert_eq!(
+            (fit.model.inputs.clone(), fit.model.degree),
+            (vec![1, 0], 2)
+        );
+        for (fitted, known) in fit.model.coefficients.iter().zip(&model.coefficients) {
+            assert!(
+                (fitted - known).abs() < 1e-9,
+                "{:?}",
+                fit.model.coefficients
+            );
+        }
+        assert!(fit.rms_error < 1e-9 && fit.max_error < 1e-9);
+        assert!(fit.model.validate(2).is_ok());
+    }
+
+    #[test]
+    fn noise_is_averaged_out() {
+        let model = sag_model();
+        let noise = |i: usize| if i.is_multiple_of(2) { 0.2 } else { -0.2 };
+        let fit = fit(&model.inputs, model.degree, &samples(&model, noise)).unwrap();
+        for (fitted, known) in fit.model.coefficients.iter().zip(&model.coefficients) {
+            assert!((fitted - known).abs() < 0.2, "{:?}", fit.model.coefficients);
+        }
+        assert!(
+            fit.rms_error > 0.1 && fit.rms_error < 0.2,
+            "{}",
+            fit.rms_error
+        );
+        assert!(fit.max_error <= 0.25, "{}", fit.max_error);
+    }
+
+    #[test]
+    fn too_few_samples_are_refused() {
+        let samples = samples(&sag_model(), |_| 0.0);
+        assert_eq!(
+            fit(&[1, 0], 2, &samples[..4]).unwrap_err(),
+            "5 coefficients need at least 5 samples, got 4"
+        );
+        // A degree beyond what the samples can determine
+        assert_eq!(
+            fit(&[1], 3, &samples[..3]).unwrap_err(),
+            "4 coefficients need at least 4 samples, got 3"
+        );
+        assert_eq!(
+            fit(&[1], 1, &[]).unwrap_err(),
+            "2 coefficients need at least 2 samples, got 0"
+        );
+    }
+
+    #[test]
+    fn singular_samples_are_refused() {
+        let at = |shoulder: u16, measured: f64| Sample {
+            commanded: 90,
+            measured,
+            context: BTreeMap::from([(1, shoulder)]),
+        };
+        let error = "The samples don't vary the inputs enough to fit this degree";
+        // The same context throughout
+        let same: Vec<Sample> = (0..6).map(|i| at(120, 87.0 + i as f64)).collect();
+        assert_eq!(fit(&[1], 1, &same).unwrap_err(), error);
+        // Two shoulder angles can't fix a quadratic
+        let two: Vec<Sample> = (0..6).map(|i| at(60 + 60 * (i % 2), 88.0)).collect();
+        assert_eq!(fit(&[1], 2, &two).unwrap_err(), error);
+        assert!(fit(&[1], 1, &two).is_ok());
+
+        let mut missing = two;
+        missing[1].context.clear();
+        assert_eq!(
+            fit(&[1], 1, &missing).unwrap_err(),
+            "Sample 1 lacks an angle for every input"
+        );
+    }
+
+    #[test]
+    fn correction_needs_every_input_and_is_capped() {
+        let model = Model {
+            inputs: vec![1],
+            degree: 1,
+            coefficients: vec![3.0, 2.0],
+        };
+        let mut angles = [None; NUM_SERVOS as usize];
+        assert_eq!(model.correction(&angles), None);
+        angles[1] = Some(90);
+        assert_eq!(model.correction(&angles), Some(3.0));
+        angles[1] = Some(180);
+        assert_eq!(model.correction(&angles), Some(5.0));
+        angles[1] = Some(0);
+        assert_eq!(model.correction(&angles), Some(1.0));
+
+        let steep = Model {
+            coefficients: vec![0.0, 100.0],
+            ..model
+        };
+        assert_eq!(steep.correction(&angles), Some(-MAX_CORRECTION));
+        angles[1] = Some(180);
+        assert_eq!(steep.correction(&angles), Some(MAX_CORRECTION));
+    }
+
+    #[test]
+    fn models_are_validated() {
+        let model = |inputs: Vec<u8>, degree, coefficients: Vec<f64>| Model {
+            inputs,
+            degree,
+            coefficients,
+        };
+        let cases = [
+            (model(vec![1], 0, vec![0.0]), "degree must be 1-3"),
+            (model(vec![1], 4, vec![0.0; 5]), "degree must be 1-3"),
+            (model(vec![], 1, vec![0.0]), "inputs must list 1-4 channels"),
+            (
+                model(vec![6], 1, vec![0.0; 2]),
+                "Input channel 6 is out of range",
+            ),
+            (
+                model(vec![2], 1, vec![0.0; 2]),
+                "Channel 2 can't be an input of its own model",
+            ),
+            (
+                model(vec![1, 1], 1, vec![0.0; 3]),
+                "Input channel 1 is listed twice",
+            ),
+            (
+                model(vec![0, 1], 2, vec![0.0; 3]),
+                "Expected 5 coefficients for 2 inputs of degree 2, got 3",
+            ),
+            (
+                model(vec![1], 1, vec![f64::NAN, 0.0]),
+                "Coefficients must be finite",
+            ),
+        ];
+        for (model, error) in cases {
+            assert_eq!(model.validate(2).unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn models_are_kept_in_their_file() {
+        let dir = TempDir::new("compensation");
+        let path = dir.join("compensation.json");
+        let persister = Persister::default();
+        let models = Compensation::load(Some(&path), &persister).unwrap();
+        assert!(models.all().is_empty());
+        assert_eq!(models.set(2, Some(sag_model())).unwrap(), None);
+        assert!(persister.write_pending());
+
+        let loaded = Compensation::load(Some(&path), &persister).unwrap();
+        assert_eq!(loaded.get(2), Some(sag_model()));
+        let mut angles = [Some(90); NUM_SERVOS as usize];
+        assert_eq!(loaded.correction(2, &angles), -3.0);
+        assert_eq!(loaded.correction(3, &angles), 0.0);
+        angles[0] = None;
+        assert_eq!(loaded.correction(2, &angles), 0.0);
+        assert_eq!(loaded.set(2, None).unwrap(), Some(sag_model()));
+
+        // A model that doesn't validate isn't loaded
+        std::fs::write(
+            &path,
+            json!({ "2": { "inputs": [2], "degree": 1, "coefficients": [0.0, 0.0] } }).to_string(),
+        )
+        .unwrap();
+        let error = Compensation::load(Some(&path), &persister).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Invalid model for channel 2: Channel 2 can't be an input of its own model"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commands_are_corrected_and_readings_uncorrected() {
+        let server = TestServer::start().await;
+        // 3° of sag at a level shoulder, 1° more per 45° raised
+        let model = json!({ "inputs": [1], "degree": 1, "coefficients": [3.0, 2.0] });
+        assert_eq!(server.put("/api/compensation/2", model).await.status, 200);
+
+        server
+            .post("/api/pose", json!({ "angles": [90, 135, 100] }))
+            .await;
+        assert_eq!(server.mock.take_commands(), ["POSE 90,135,104"]);
+        // The arm reports the corrected angle; clients see the logical one
+        let reply = server.get("/api/servo/2").await;
+        assert_eq!(reply.body["angle"], 100);
+        assert_eq!(server.mock.take_commands(), ["GET 2"]);
+
+        server
+            .post("/api/pose", json!({ "angles": [90, 45, 100] }))
+            .await;
+        assert_eq!(server.mock.take_commands(), ["POSE 90,45,102"]);
+        assert_eq!(server.get("/api/servo/2").await.body["angle"], 100);
+    }
+}
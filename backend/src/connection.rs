@@ -0,0 +1,118 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::audit;
+
+/// Transitions kept for `GET /api/connection/history`
+const MAX_TRANSITIONS: usize = 200;
+
+/// Where the serial connection is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Opening the port for the first time
+    Connecting,
+    /// Port open, probing the firmware
+    Handshaking,
+    /// The firmware answered; in button mode or a mode not known yet
+    Connected,
+    /// The firmware took `START`
+    SerialMode,
+    /// The link dropped, or the first connection failed
+    Disconnected,
+    /// Trying to open the port again
+    Reconnecting,
+}
+
+impl ConnectionState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Handshaking => "handshaking",
+            ConnectionState::Connected => "connected",
+            ConnectionState::SerialMode => "serial_mode",
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Reconnecting => "reconnecting",
+        }
+    }
+}
+
+/// One change of the connection state
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    /// `None` for the first transition
+    pub from: Option<ConnectionState>,
+    pub to: ConnectionState,
+    pub at_ms: u64,
+    /// Why, for transitions caused by an error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Every transition of the serial connection, recorded in one place
+///
+/// Recording the state it is already in does nothing, so a reconnect
+/// that keeps failing the same way doesn't fill the history. Transitions
+/// are appended to the JSONL file, if configured, as they happen.
+pub struct ConnectionLog {
+    inner: Mutex<Inner>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Option<ConnectionState>,
+    transitions: VecDeque<Transition>,
+}
+
+impl ConnectionLog {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            inner: Mutex::default(),
+            path,
+        }
+    }
+
+    /// Record that the connection is now in `to`
+    pub fn record(&self, to: ConnectionState, detail: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == Some(to) {
+            return;
+        }
+        let transition = Transition {
+            from: inner.state.replace(to),
+            to,
+            at_ms: audit::now_ms(),
+            detail,
+        };
+        debug!(
+            from = transition.from.map_or("none", ConnectionState::name),
+            to = to.name(),
+            "Connection state changed"
+        );
+        if let Some(path) = &self.path {
+            let written = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&transition)?));
+            if let Err(e) = written {
+                warn!("Failed to write connection log {}: {}", path.display(), e);
+            }
+        }
+        if inner.transitions.len() >= MAX_TRANSITIONS {
+            inner.transitions.pop_front();
+        }
+        inner.transitions.push_back(transition);
+    }
+
+    /// Current state and the transitions, oldest first
+    pub fn snapshot(&self) -> (Option<ConnectionState>, Vec<Transition>) {
+        let inner = self.inner.lock().unwrap();
+        (inner.state, inner.transitions.iter().cloned().collect())
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::script::{self, Positions};
+
+/// A `pose_constraints` entry a pose fails
+#[derive(Debug, Clone, Serialize)]
+pub struct Broken {
+    /// Name of the constraint, its expression if unnamed
+    pub constraint: String,
+    pub expr: String,
+    /// Why the constraint couldn't be evaluated, e.g. an angle it reads
+    /// is unknown; the pose then counts as failing it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Broken {
+    pub fn message(&self) -> String {
+        match &self.reason {
+            Some(reason) => format!(
+                "Pose constraint {} can't be checked: {}",
+                self.constraint, reason
+            ),
+            None if self.constraint == self.expr => {
+                format!("Pose breaks constraint {}", self.constraint)
+            }
+            None => format!(
+                "Pose breaks constraint {} ({})",
+                self.constraint, self.expr
+            ),
+        }
+    }
+}
+
+/// Angles of a pose by channel, `None` where unknown
+pub struct Angles<'a> {
+    pub config: &'a Config,
+    pub angles: &'a [Option<u16>],
+}
+
+impl Positions for Angles<'_> {
+    fn position(&self, channel: u8) -> Option<u16> {
+        self.angles.get(channel as usize).copied().flatten()
+    }
+
+    fn channel_by_name(&self, name: &str) -> Option<u8> {
+        self.config.channel_by_name(name)
+    }
+}
+
+/// Check `pose` against the `pose_constraints` of `config`, in order; the
+/// first one it fails
+pub fn check(config: &Config, pose: &dyn Positions) -> Result<(), Broken> {
+    for constraint in &config.pose_constraints {
+        let broken = |reason| Broken {
+            constraint: constraint.label().to_string(),
+            expr: constraint.expr.clone(),
+            reason,
+        };
+        // Parsed again each time, so a reloaded config applies at once
+        let holds = script::parse_condition(&constraint.expr)
+            .and_then(|condition| condition.holds(pose));
+        match holds {
+            Ok(true) => {}
+            Ok(false) => return Err(broken(None)),
+            Err(e) => return Err(broken(Some(e.message))),
+        }
+    }
+    Ok(())
+}
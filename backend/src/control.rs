@@ -0,0 +1,139 @@
+//! Commanding the arm from a program embedding the backend, see
+//! [`RobotArmServer`](crate::RobotArmServer)
+//!
+//! Each command runs the handler of its REST endpoint, so it waits in the
+//! same command queue, goes through the same checks and is accounted the
+//! same way as a request, under the actor [`ACTOR`].
+
+use axum::extract::{Path, Query, State};
+use axum::Json;
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::command_queue;
+use crate::handlers::{self, ApiError, AppState};
+use crate::models::{CommandQuery, MoveRequest, PoseAngles, PoseRequest, SetAngleRequest};
+use crate::usage;
+
+/// What commands sent here are accounted and queued as, like a request's
+/// `X-Actor`
+pub const ACTOR: &str = "embedded";
+
+/// Run a handler as a request from [`ACTOR`] to `endpoint` would
+async fn submit<T>(
+    endpoint: &str,
+    handler: impl Future<Output = Result<T, ApiError>>,
+) -> Result<(), ApiError> {
+    let requester = format!("{} ({})", endpoint, ACTOR);
+    let handler = usage::PRINCIPAL.scope(ACTOR.to_string(), handler);
+    command_queue::REQUESTER.scope(requester, handler).await?;
+    Ok(())
+}
+
+/// Send a POSE, as `POST /api/pose` with an angle list
+pub async fn pose(state: &Arc<AppState>, angles: Vec<u16>) -> Result<(), ApiError> {
+    let request = PoseRequest {
+        angles: PoseAngles::List(angles),
+    };
+    let handler = handlers::execute_pose(
+        State(state.clone()),
+        Query(CommandQuery::default()),
+        Json(request),
+    );
+    submit("POST /api/pose", handler).await
+}
+
+/// Send a MOVE and wait for it, as `POST /api/move` with an angle list
+pub async fn move_to(
+    state: &Arc<AppState>,
+    duration_ms: u32,
+    angles: Vec<u16>,
+) -> Result<(), ApiError> {
+    let request = MoveRequest {
+        duration_ms,
+        angles: PoseAngles::List(angles),
+        track: false,
+    };
+    let handler = handlers::execute_move(
+        State(state.clone()),
+        Query(CommandQuery::default()),
+        Json(request),
+    );
+    submit("POST /api/move", handler).await
+}
+
+/// Set one servo's angle, as `POST /api/servo/:id/angle`
+pub async fn set_angle(state: &Arc<AppState>, channel: u8, angle: u16) -> Result<(), ApiError> {
+    let handler = handlers::set_servo_angle(
+        State(state.clone()),
+        Path(channel),
+        Query(CommandQuery::default()),
+        Json(SetAngleRequest { angle }),
+    );
+    submit("POST /api/servo/:id/angle", handler).await
+}
+
+/// Move to the home pose, as `POST /api/home`
+pub async fn home(state: &Arc<AppState>) -> Result<(), ApiError> {
+    let handler = handlers::go_home(State(state.clone()), Query(CommandQuery::default()));
+    submit("POST /api/home", handler).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServoConfig;
+    use crate::testing::TestServer;
+
+    fn embedded_usage(server: &TestServer) -> crate::usage::Counters {
+        let state = server.server.state();
+        state.usage.report(ACTOR, state.clock.now_ms()).today
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commands_are_sent_as_their_requests_would_be() {
+        let server = TestServer::with_config(|c| c.home = Some(vec![90, 45])).await;
+        let state = server.server.state();
+        pose(state, vec![30, 60]).await.unwrap();
+        move_to(state, 100, vec![40, 50]).await.unwrap();
+        set_angle(state, 1, 70).await.unwrap();
+        home(state).await.unwrap();
+        assert_eq!(
+            server.mock.take_commands(),
+            ["POSE 30,60", "MOVE 100 40,50", "S1:70", "POSE 90,45"]
+        );
+        assert_eq!(state.confirmed_positions()[..2], [Some(90), Some(45)]);
+
+        let usage = embedded_usage(&server);
+        assert_eq!(usage.motion_commands, 4);
+        assert_eq!(usage.move_ms, 100);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commands_are_checked_as_their_requests_would_be() {
+        let server = TestServer::with_config(|c| {
+            c.servos.push(ServoConfig {
+                channel: 1,
+                min: 30,
+                max: 150,
+                ..ServoConfig::default()
+            });
+        })
+        .await;
+        let state = server.server.state();
+        let (status, error) = pose(state, vec![90, 10]).await.unwrap_err();
+        assert_eq!(status, 422);
+        assert_eq!(error.code.as_deref(), Some("SOFT_LIMIT"));
+        let (status, _) = set_angle(state, 1, 200).await.unwrap_err();
+        assert_eq!(status, 422);
+        let (status, _) = set_angle(state, 99, 90).await.unwrap_err();
+        assert_eq!(status, 400);
+        let (status, _) = move_to(state, 700_000, vec![90]).await.unwrap_err();
+        assert_eq!(status, 422);
+        assert!(server.mock.take_commands().is_empty());
+
+        server.disconnect().await;
+        let (status, _) = pose(state, vec![90]).await.unwrap_err();
+        assert_eq!(status, 503);
+    }
+}
@@ -0,0 +1,154 @@
+//! What the API needs of the link to the firmware
+//!
+//! [`SerialManager`](crate::serial::SerialManager) implements it over the
+//! serial port, the simulator or a custom transport. Handlers and the
+//! background tasks only see the trait, so they can be run against
+//! another implementation, e.g. a mock recording the commands in tests.
+
+use anyhow::Result;
+use tracing::error;
+
+use crate::command_queue::CommandQueue;
+use crate::config::{Config, ProtocolConfig, TimeoutConfig};
+use crate::feedback::FeedbackCalibration;
+use crate::protocol::{Angle, Channel};
+use crate::serial::{is_io_failure, CommandOptions, SerialMode, ServoReadings, NUM_SERVOS};
+use crate::violations::Violations;
+
+/// Commands and queries of the firmware, each one a full exchange
+///
+/// Every call blocks until the firmware answered or timed out, so async
+/// code makes them from a blocking thread.
+pub trait ServoController: Send + Sync {
+    /// Baud rate in use (may differ from the configured one if detected)
+    fn baud_rate(&self) -> u32;
+
+    /// Whether this is connected to the simulator rather than hardware
+    fn is_simulated(&self) -> bool;
+
+    /// Commands waiting for the port
+    fn queue(&self) -> &CommandQueue;
+
+    /// Apply new timeouts without reopening the port
+    fn set_timeouts(&self, timeouts: &TimeoutConfig) -> Result<()>;
+
+    /// Apply new protocol settings without reopening the port
+    fn set_protocol(&self, protocol: &ProtocolConfig);
+
+    /// Protocol violations of this connection
+    fn violations(&self) -> &Violations;
+
+    /// Largest angle the firmware can represent
+    fn max_angle(&self) -> u16;
+
+    /// Wait for the chained MOVE in progress, if any, and check its reply
+    fn end_chain(&self) -> Result<()>;
+
+    /// Enter serial mode
+    fn start_serial_mode(&self) -> Result<()>;
+
+    /// Exit serial mode
+    fn stop_serial_mode(&self) -> Result<()>;
+
+    /// Exit serial mode for being idle; the next motion command enters it
+    /// again before it is sent
+    fn stop_for_idle(&self) -> Result<()>;
+
+    /// Whether button mode was entered for being idle
+    fn idle_stopped(&self) -> bool;
+
+    /// Mode the firmware was last switched to, if known
+    fn mode(&self) -> Option<SerialMode>;
+
+    /// Whether the firmware answered but refused the last `START`
+    fn mode_refused(&self) -> bool;
+
+    /// Set servo angle (0 to the firmware's maximum)
+    fn set_servo_angle(&self, channel: Channel, angle: Angle, opts: CommandOptions) -> Result<()>;
+
+    /// Set servo PWM pulse width (0-20000 microseconds)
+    fn set_servo_pwm(&self, channel: Channel, pulse_us: u16, opts: CommandOptions) -> Result<()>;
+
+    /// Execute POSE command (set multiple servos instantly)
+    fn execute_pose(&self, angles: &[Angle], opts: CommandOptions) -> Result<()>;
+
+    /// Execute MOVE command (smooth interpolated movement)
+    fn execute_move(&self, duration_ms: u16, angles: &[Angle], opts: CommandOptions) -> Result<()>;
+
+    /// Get servo angle; `None` if the firmware answered with one of
+    /// `protocol.angle_sentinels`
+    fn get_servo_angle(&self, channel: Channel) -> Result<Option<Angle>>;
+
+    /// Read the raw counts of a servo's feedback potentiometer; `None` if
+    /// the firmware isn't configured to support the query
+    fn get_servo_adc(&self, channel: Channel) -> Result<Option<u32>>;
+
+    /// Read a servo's angle, with `GET` or, for firmware without it, from
+    /// the feedback ADC mapped by the servo's calibration
+    fn get_measured_angle(
+        &self,
+        channel: Channel,
+        feedback: Option<&FeedbackCalibration>,
+    ) -> Result<Option<Angle>>;
+
+    /// Ask the firmware whether a servo is still moving; `None` if the
+    /// firmware isn't configured to support the query
+    ///
+    /// The firmware doesn't read commands during a MOVE, so this waits for
+    /// a MOVE in progress to finish.
+    fn get_servo_busy(&self, channel: Channel) -> Result<Option<bool>>;
+
+    /// Ask the firmware for its supply voltage in millivolts; `None` if
+    /// the firmware isn't configured to support the query
+    fn get_supply_voltage(&self) -> Result<Option<u32>>;
+
+    /// Read the firmware's latched fault register; `None` if the firmware
+    /// isn't configured to have one
+    fn get_faults(&self) -> Result<Option<u32>>;
+
+    /// Clear the firmware's fault register; `false` if the firmware isn't
+    /// configured to have one
+    fn clear_faults(&self) -> Result<bool>;
+
+    /// Read the firmware's slew-rate limit in degrees per second, 0 if it
+    /// has none; `None` if the firmware isn't configured to support one
+    fn get_speed_limit(&self) -> Result<Option<u16>>;
+
+    /// Set the firmware's slew-rate limit, 0 for none; `false` if the
+    /// firmware isn't configured to support one
+    fn set_speed_limit(&self, limit: u16) -> Result<bool>;
+
+    /// Get all servo angles, as
+    /// [`get_measured_angle`](Self::get_measured_angle) reads them with
+    /// the calibrations of `config`
+    ///
+    /// A servo that can't be read is skipped, but an I/O failure ends the
+    /// reads: the remaining ones would only fail the same way, each after
+    /// the full timeout.
+    fn get_all_servos(&self, config: &Config) -> ServoReadings {
+        let mut servos = Vec::new();
+
+        for channel in Channel::all(NUM_SERVOS) {
+            let feedback = config.servo(channel.get()).feedback;
+            match self.get_measured_angle(channel, feedback.as_ref()) {
+                Ok(angle) => servos.push((channel, angle)),
+                Err(e) if is_io_failure(&e) => {
+                    error!(channel = channel.get(), error = %e, "Aborting servo reads");
+                    return ServoReadings {
+                        servos,
+                        failure: Some(e),
+                    };
+                }
+                Err(e) => {
+                    error!(channel = channel.get(), error = %e, "Failed to get servo angle");
+                    // Continue with other servos
+                }
+            }
+        }
+
+        ServoReadings {
+            servos,
+            failure: None,
+        }
+    }
+}
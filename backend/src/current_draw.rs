@@ -0,0 +1,75 @@
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::serial::NUM_SERVOS;
+
+/// Estimated draw of one servo for a pose
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelDraw {
+    pub channel: u8,
+    pub target: u16,
+    /// Known position the move starts from, `None` if unknown
+    pub from: Option<u16>,
+    /// Degrees to go; from an unknown position, the farthest the servo's
+    /// limits allow
+    pub delta: u16,
+    /// Draw at the start of the move
+    pub peak_ma: u32,
+    /// Draw holding the target once there
+    pub holding_ma: u32,
+}
+
+/// Estimated draw of every servo moving to a pose at once
+pub struct Estimate {
+    pub channels: Vec<ChannelDraw>,
+    pub peak_ma: u32,
+    pub holding_ma: u32,
+}
+
+/// Estimate the draw of moving from `positions` to `targets`
+///
+/// Servos targeted or at a known position draw their holding current; a
+/// servo that has to move adds its per-degree current for the distance,
+/// up to its stall current. All servos start moving together, as POSE
+/// and MOVE drive them, so the peak is their sum.
+pub fn estimate(
+    config: &Config,
+    targets: &[Option<u16>; NUM_SERVOS as usize],
+    positions: &[Option<u16>; NUM_SERVOS as usize],
+) -> Estimate {
+    let mut channels = Vec::new();
+    for channel in 0..NUM_SERVOS {
+        let servo = config.servo(channel);
+        if !servo.kind.is_servo() {
+            continue;
+        }
+        let from = positions[channel as usize];
+        let Some(target) = targets[channel as usize].or(from) else {
+            // Never commanded nor read, so not known to be powered
+            continue;
+        };
+        let delta = match from {
+            Some(from) => target.abs_diff(from),
+            None => target.abs_diff(servo.min).max(target.abs_diff(servo.max)),
+        };
+        let moving = servo.moving_ma_per_degree * delta as f64;
+        let peak_ma = if delta == 0 {
+            servo.holding_ma
+        } else {
+            (servo.holding_ma as f64 + moving).min(servo.stall_ma as f64).round() as u32
+        };
+        channels.push(ChannelDraw {
+            channel,
+            target,
+            from,
+            delta,
+            peak_ma,
+            holding_ma: servo.holding_ma,
+        });
+    }
+    Estimate {
+        peak_ma: channels.iter().map(|c| c.peak_ma).sum(),
+        holding_ma: channels.iter().map(|c| c.holding_ma).sum(),
+        channels,
+    }
+}
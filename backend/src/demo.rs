@@ -0,0 +1,428 @@
+use std::f64::consts::PI;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::arbitration::{self, MotionSource, Rule};
+use crate::clock::Clock;
+use crate::command_queue;
+use crate::config::Config;
+use crate::controller::ServoController;
+use crate::handlers::{self, AppState};
+use crate::serial::{CommandOptions};
+
+/// Duration of the MOVE that glides back into the demo motion on resume
+const RESUME_MOVE_MS: u16 = 1000;
+
+/// Tracks motion commands so lower priority sources yield to them
+///
+/// Motion handlers hold a [`MotionGuard`] while they command the arm,
+/// taken for their [`MotionSource`] and arbitrated by
+/// [`arbitration::rule`]. The demo and the attract loop only move the arm
+/// through [`MotionActivity::demo_step`], which runs while holding the
+/// same lock, so no other command interleaves with a demo step, and
+/// which doesn't start while a command waits for that lock.
+pub struct MotionActivity {
+    state: Mutex<Activity>,
+    /// Signalled whenever a guard is dropped
+    released: Condvar,
+    /// Sources waiting in [`begin`](Self::begin), which a demo step
+    /// doesn't go ahead of
+    waiting: Mutex<Vec<MotionSource>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct Activity {
+    next_id: u64,
+    /// Motion commands in progress
+    leases: Vec<Lease>,
+    /// When the last motion command finished, and its source
+    last: Option<(Instant, MotionSource)>,
+}
+
+struct Lease {
+    id: u64,
+    holder: Holder,
+    preempted_by: Option<Holder>,
+}
+
+/// A source of motion and who it is for
+#[derive(Debug, Clone)]
+pub struct Holder {
+    pub source: MotionSource,
+    pub requester: String,
+}
+
+/// Marks a motion command as in progress until dropped
+pub struct MotionGuard<'a> {
+    activity: &'a MotionActivity,
+    id: u64,
+}
+
+impl MotionActivity {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            state: Mutex::default(),
+            released: Condvar::new(),
+            waiting: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Mark the start of a motion command from `source`, waiting for any
+    /// demo step in progress to finish, and for the holders `source`
+    /// queues or yields to
+    ///
+    /// Holders `source` preempts are marked as such, see
+    /// [`MotionGuard::preempted_by`]. User commands never wait for a holder.
+    pub fn begin(&self, source: MotionSource) -> MotionGuard<'_> {
+        let holder = Holder {
+            source,
+            requester: command_queue::requester(),
+        };
+        self.waiting.lock().unwrap().push(source);
+        let mut state = self.state.lock().unwrap();
+        while state.leases.iter().any(|lease| {
+            matches!(
+                arbitration::rule(source, lease.holder.source),
+                Rule::Queue | Rule::Yield
+            )
+        }) {
+            state = self.released.wait(state).unwrap();
+        }
+        for lease in &mut state.leases {
+            if arbitration::rule(source, lease.holder.source) == Rule::Preempt
+                && lease.preempted_by.is_none()
+            {
+                warn!(
+                    preempted = lease.holder.source.name(),
+                    preempted_requester = lease.holder.requester,
+                    by = source.name(),
+                    by_requester = holder.requester,
+                    "Motion preempted"
+                );
+                lease.preempted_by = Some(holder.clone());
+            }
+        }
+        state.next_id += 1;
+        let id = state.next_id;
+        state.leases.push(Lease {
+            id,
+            holder,
+            preempted_by: None,
+        });
+        let mut waiting = self.waiting.lock().unwrap();
+        let position = waiting.iter().position(|waiting| *waiting == source);
+        waiting.remove(position.expect("waiting since the start"));
+        drop(waiting);
+        MotionGuard { activity: self, id }
+    }
+
+    /// Run `step` unless other motion is in progress or waiting to start,
+    /// or ended less than `idle` ago; returns whether it ran
+    pub fn demo_step(&self, idle: Duration, step: impl FnOnce()) -> bool {
+        let state = self.state.lock().unwrap();
+        let waiting = self.waiting.lock().unwrap().clone();
+        let sources = state.leases.iter().map(|lease| lease.holder.source);
+        let blocked = sources.chain(waiting).any(|source| {
+            arbitration::rule(MotionSource::Attract, source) != Rule::Share
+        });
+        if blocked {
+            return false;
+        }
+        let now = self.clock.now();
+        if matches!(state.last, Some((last, _)) if now.duration_since(last) < idle) {
+            return false;
+        }
+        step();
+        true
+    }
+
+    /// The source a demo step last yielded to: the one in progress or
+    /// waiting to start, else the last to finish
+    pub fn yielded_to(&self) -> Option<MotionSource> {
+        let state = self.state.lock().unwrap();
+        let waiting = self.waiting.lock().unwrap().clone();
+        let sources = state.leases.iter().map(|lease| lease.holder.source);
+        let holding = sources.chain(waiting).min();
+        holding.or(state.last.map(|(_, source)| source))
+    }
+
+    /// Sources waiting in [`begin`](Self::begin)
+    #[cfg(test)]
+    pub fn waiting(&self) -> Vec<MotionSource> {
+        self.waiting.lock().unwrap().clone()
+    }
+}
+
+impl MotionGuard<'_> {
+    /// Who took the arm over from this command, if anyone; it should stop
+    /// before its next command
+    pub fn preempted_by(&self) -> Option<Holder> {
+        let state = self.activity.state.lock().unwrap();
+        let lease = state.leases.iter().find(|lease| lease.id == self.id)?;
+        lease.preempted_by.clone()
+    }
+}
+
+impl Drop for MotionGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.activity.state.lock().unwrap();
+        let position = state.leases.iter().position(|lease| lease.id == self.id);
+        if let Some(lease) = position.map(|position| state.leases.remove(position)) {
+            state.last = Some((self.activity.clock.now(), lease.holder.source));
+        }
+        self.activity.released.notify_all();
+    }
+}
+
+/// Angles of the demo motion `elapsed` into the loop
+///
+/// Every channel swings sinusoidally around its home angle, phase-shifted
+/// from its neighbour, with the amplitude reduced to stay within limits.
+pub fn demo_angles(config: &Config, elapsed: Duration) -> Vec<u16> {
+    let demo = &config.demo;
+    let home = config.home_pose();
+    let period = demo.period_ms.max(1) as f64;
+    let phase = 2.0 * PI * (elapsed.as_millis() as f64 % period) / period;
+
+    // Only the servos POSE can reach without crossing a non-servo channel
+    (0..config.servo_prefix() as u8)
+        .map(|channel| {
+            let servo = config.servo(channel);
+            let center = home
+                .get(channel as usize)
+                .copied()
+                .unwrap_or(90)
+                .clamp(servo.min, servo.max);
+            let amplitude = demo
+                .amplitude
+                .min(center - servo.min)
+                .min(servo.max - center) as f64;
+            let offset = channel as f64 * PI / 3.0;
+            let angle = center as f64 + amplitude * (phase + offset).sin();
+            angle.round().clamp(servo.min as f64, servo.max as f64) as u16
+        })
+        .collect()
+}
+
+/// Loop the demo motion on the connected arm, pausing while a client is
+/// commanding motion and resuming once it has been idle long enough
+pub async fn run(state: Arc<AppState>) {
+    let start = state.clock.now();
+    let mut entered: Option<Arc<dyn ServoController>> = None;
+    let mut paused = true;
+
+    loop {
+        let config = state.config();
+        state.clock.sleep(Duration::from_millis(config.demo.tick_ms.max(1))).await;
+
+        let serial = match state.serial.lock().unwrap().clone() {
+            Some(serial) => serial,
+            None => continue,
+        };
+        if !matches!(&entered, Some(current) if Arc::ptr_eq(current, &serial)) {
+            // Enter serial mode once per connection; clients may still
+            // leave it, in which case demo steps fail until they re-enter
+            if let Err(e) = serial.start_serial_mode() {
+                warn!("Demo could not enter serial mode: {}", e);
+            }
+            entered = Some(serial.clone());
+        }
+
+        if state.link_loss.latched() {
+            continue;
+        }
+        let idle = Duration::from_millis(config.demo.idle_resume_ms);
+        let angles = demo_angles(&config, state.clock.now().duration_since(start));
+        if angles.is_empty() {
+            // Channel 0 has no servo
+            continue;
+        }
+        let resuming = paused;
+        let ran = state.motion.demo_step(idle, || {
+            let result = if resuming {
+                handlers::run_move(
+                    &state,
+                    &*serial,
+                    RESUME_MOVE_MS,
+                    &angles,
+                    CommandOptions::default(),
+                )
+            } else {
+                handlers::run_pose(&state, &*serial, &angles, CommandOptions::default())
+            };
+            if let Err((_, e)) = result {
+                warn!("Demo step failed: {}", e.error);
+            }
+        });
+
+        if ran && paused {
+            info!("Demo motion running");
+        } else if !ran && !paused {
+            let by = state.motion.yielded_to().map_or("user", MotionSource::name);
+            info!(by, "Demo motion paused for {} commands", by);
+        }
+        paused = !ran;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::{ChannelKind, ServoConfig};
+
+    const IDLE: Duration = Duration::from_secs(10);
+
+    fn activity() -> (Arc<MockClock>, MotionActivity) {
+        let clock = Arc::new(MockClock::new());
+        (clock.clone(), MotionActivity::new(clock))
+    }
+
+    /// Wait until a thread waits in [`MotionActivity::begin`]
+    fn until_waiting(activity: &MotionActivity) {
+        while activity.waiting().is_empty() {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn demo_swings_each_channel_around_home() {
+        let config = Config::default();
+        let at = |ms| demo_angles(&config, Duration::from_millis(ms));
+
+        assert_eq!(at(0), [90, 116, 116, 90, 64, 64]);
+        // A quarter period later channel 0 is at the top of its swing
+        assert_eq!(at(2000)[0], 120);
+        assert_eq!(at(6000)[0], 60);
+        assert_eq!(at(8000 + 1234), at(1234));
+    }
+
+    #[test]
+    fn demo_swing_is_reduced_to_the_limits() {
+        let mut config = Config::default();
+        config.servos.push(ServoConfig {
+            channel: 1,
+            min: 80,
+            max: 95,
+            ..ServoConfig::default()
+        });
+        for ms in (0..8000).step_by(250) {
+            let angle = demo_angles(&config, Duration::from_millis(ms))[1];
+            assert!((85..=95).contains(&angle), "{} at {}ms", angle, ms);
+        }
+    }
+
+    #[test]
+    fn demo_stops_at_the_first_non_servo_channel() {
+        let mut config = Config::default();
+        config.servos.push(ServoConfig {
+            channel: 2,
+            kind: ChannelKind::PwmOutput,
+            ..ServoConfig::default()
+        });
+        assert_eq!(demo_angles(&config, Duration::ZERO).len(), 2);
+    }
+
+    #[test]
+    fn demo_yields_to_user_motion_until_idle() {
+        let (clock, activity) = activity();
+        assert!(activity.demo_step(IDLE, || {}));
+
+        let guard = activity.begin(MotionSource::User);
+        assert!(!activity.demo_step(IDLE, || panic!("stepped during a user command")));
+        assert_eq!(activity.yielded_to(), Some(MotionSource::User));
+        drop(guard);
+
+        // Handed back only once the user has been idle long enough
+        clock.advance(IDLE - Duration::from_millis(1));
+        assert!(!activity.demo_step(IDLE, || {}));
+        assert_eq!(activity.yielded_to(), Some(MotionSource::User));
+        clock.advance(Duration::from_millis(1));
+        assert!(activity.demo_step(IDLE, || {}));
+    }
+
+    #[test]
+    fn demo_step_doesnt_go_ahead_of_a_waiting_command() {
+        let (_, activity) = activity();
+        activity
+            .waiting
+            .lock()
+            .unwrap()
+            .push(MotionSource::Scheduler);
+        assert!(!activity.demo_step(IDLE, || panic!("stepped ahead of a scheduled move")));
+        assert_eq!(activity.yielded_to(), Some(MotionSource::Scheduler));
+
+        activity.waiting.lock().unwrap().clear();
+        assert!(activity.demo_step(IDLE, || {}));
+    }
+
+    #[test]
+    fn demo_step_holds_off_user_commands_until_done() {
+        let (clock, activity) = activity();
+        let activity = Arc::new(activity);
+        let (started, start) = std::sync::mpsc::channel();
+        let (finish, finished) = std::sync::mpsc::channel::<()>();
+
+        let demo = {
+            let activity = activity.clone();
+            std::thread::spawn(move || {
+                activity.demo_step(IDLE, || {
+                    started.send(()).unwrap();
+                    finished.recv().unwrap();
+                })
+            })
+        };
+        start.recv().unwrap();
+        let user = {
+            let activity = activity.clone();
+            std::thread::spawn(move || drop(activity.begin(MotionSource::User)))
+        };
+        // However long the step takes
+        until_waiting(&activity);
+        clock.advance(IDLE);
+        assert!(!user.is_finished());
+
+        finish.send(()).unwrap();
+        assert!(demo.join().unwrap());
+        user.join().unwrap();
+    }
+
+    #[test]
+    fn leases_follow_the_arbitration_matrix() {
+        let (clock, activity) = activity();
+        let activity = Arc::new(activity);
+        let scheduled = activity.begin(MotionSource::Scheduler);
+        assert!(scheduled.preempted_by().is_none());
+
+        // Another scheduled move queues behind it
+        let queued = {
+            let activity = activity.clone();
+            std::thread::spawn(move || drop(activity.begin(MotionSource::Scheduler)))
+        };
+        until_waiting(&activity);
+        clock.advance(IDLE);
+        assert!(!queued.is_finished());
+
+        // A user command takes over at once
+        let user = crate::command_queue::REQUESTER
+            .sync_scope("POST /api/pose (alice)".to_string(), || {
+                activity.begin(MotionSource::User)
+            });
+        let by = scheduled.preempted_by().expect("preempted");
+        assert_eq!(by.source, MotionSource::User);
+        assert_eq!(by.requester, "POST /api/pose (alice)");
+        assert!(user.preempted_by().is_none());
+
+        // The queued one yields to the user too
+        drop(scheduled);
+        clock.advance(IDLE);
+        assert_eq!(activity.waiting(), [MotionSource::Scheduler]);
+        assert!(!queued.is_finished());
+        drop(user);
+        queued.join().unwrap();
+        assert_eq!(activity.yielded_to(), Some(MotionSource::Scheduler));
+    }
+}
@@ -0,0 +1,355 @@
+use crate::config::{Config, HomeOnConnect, LinkLossPolicy};
+use crate::tokens;
+
+/// An optional feature, reported by name in `GET /api/capabilities`
+///
+/// Names are snake_case and stable: a released name is never renamed or
+/// reused, so clients can test for it instead of probing endpoints. Every
+/// new optional endpoint or subsystem gets an entry in [`FEATURES`].
+pub struct Feature {
+    pub name: &'static str,
+    /// Whether the feature is available with the given configuration
+    enabled: fn(&Config) -> bool,
+}
+
+const fn always(_: &Config) -> bool {
+    true
+}
+
+/// Every optional feature, in the order they were added
+pub const FEATURES: &[Feature] = &[
+    // Endpoints present in every build
+    Feature {
+        name: "trajectory",
+        enabled: always,
+    },
+    Feature {
+        name: "pose_stream",
+        enabled: always,
+    },
+    Feature {
+        name: "support_bundle",
+        enabled: always,
+    },
+    Feature {
+        name: "output_channels",
+        enabled: always,
+    },
+    Feature {
+        name: "tracked_moves",
+        enabled: always,
+    },
+    Feature {
+        name: "scripts",
+        enabled: always,
+    },
+    Feature {
+        name: "tracking_error",
+        enabled: always,
+    },
+    Feature {
+        name: "busy_estimates",
+        enabled: always,
+    },
+    Feature {
+        name: "motion_scale",
+        enabled: always,
+    },
+    Feature {
+        name: "sequence_import",
+        enabled: always,
+    },
+    Feature {
+        name: "named_pose",
+        enabled: always,
+    },
+    Feature {
+        name: "jointstate_export",
+        enabled: always,
+    },
+    Feature {
+        name: "channel_lockout",
+        enabled: always,
+    },
+    Feature {
+        name: "move_progress",
+        enabled: always,
+    },
+    Feature {
+        name: "state_at",
+        enabled: always,
+    },
+    Feature {
+        name: "protocol_description",
+        enabled: always,
+    },
+    Feature {
+        name: "route_inventory",
+        enabled: always,
+    },
+    Feature {
+        name: "command_stats",
+        enabled: always,
+    },
+    Feature {
+        name: "wear_stats",
+        enabled: always,
+    },
+    Feature {
+        name: "url_import",
+        enabled: always,
+    },
+    Feature {
+        name: "sag_compensation",
+        enabled: always,
+    },
+    Feature {
+        name: "move_schedule",
+        enabled: always,
+    },
+    // Firmware capabilities
+    Feature {
+        name: "firmware_busy_query",
+        enabled: |config| config.protocol.busy_query,
+    },
+    Feature {
+        name: "firmware_voltage_query",
+        enabled: |config| config.protocol.voltage_query,
+    },
+    Feature {
+        name: "firmware_adc_feedback",
+        enabled: |config| config.protocol.adc_query,
+    },
+    Feature {
+        name: "firmware_fault_register",
+        enabled: |config| config.protocol.fault_register,
+    },
+    Feature {
+        name: "firmware_speed_limit",
+        enabled: |config| config.protocol.speed_limit,
+    },
+    Feature {
+        name: "extended_angles",
+        enabled: |config| config.protocol.extended_angles,
+    },
+    Feature {
+        name: "command_framing",
+        enabled: |config| {
+            !config.protocol.command_prefix.is_empty() || !config.protocol.command_suffix.is_empty()
+        },
+    },
+    Feature {
+        name: "move_lookahead",
+        enabled: |config| config.protocol.move_lookahead,
+    },
+    // Configured subsystems
+    Feature {
+        name: "pose_guard",
+        enabled: |config| config.pose_guard.max_jump.is_some(),
+    },
+    Feature {
+        name: "simulation",
+        enabled: |config| config.simulate,
+    },
+    Feature {
+        name: "demo",
+        enabled: |config| config.demo.enabled,
+    },
+    Feature {
+        name: "attract",
+        enabled: |config| config.attract.enabled,
+    },
+    Feature {
+        name: "admin_override",
+        enabled: tokens::admin_configured,
+    },
+    Feature {
+        name: "persistent_library",
+        enabled: |config| config.library_file.is_some(),
+    },
+    Feature {
+        name: "persistent_audit",
+        enabled: |config| config.audit_file.is_some(),
+    },
+    Feature {
+        name: "baud_autodetect",
+        enabled: |config| !config.serial.baud_autodetect.is_empty(),
+    },
+    Feature {
+        name: "replication",
+        enabled: |config| config.replication.peer_url.is_some(),
+    },
+    Feature {
+        name: "response_signing",
+        enabled: |config| config.response_signing_key.is_some(),
+    },
+    Feature {
+        name: "home_on_connect",
+        enabled: |config| config.home_on_connect != HomeOnConnect::Never,
+    },
+    Feature {
+        name: "link_loss_recovery",
+        enabled: |config| config.link_loss.policy != LinkLossPolicy::Ignore,
+    },
+    Feature {
+        name: "backup",
+        enabled: |config| config.backup.enabled(),
+    },
+    Feature {
+        name: "position_poll",
+        enabled: |config| config.position_poll.enabled,
+    },
+    Feature {
+        name: "library_watch",
+        enabled: |config| config.library_watch.dir.is_some(),
+    },
+    Feature {
+        name: "profiles",
+        enabled: |config| !config.profiles.is_empty(),
+    },
+];
+
+/// Names of the features available with the given configuration
+pub fn enabled(config: &Config) -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|feature| (feature.enabled)(config))
+        .map(|feature| feature.name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Profile;
+    use crate::testing::TestServer;
+
+    /// Features present in every build, each with a route it adds
+    const SERVED: &[(&str, &str)] = &[
+        ("trajectory", "/api/trajectory/plan"),
+        ("pose_stream", "/api/ws/stream"),
+        ("support_bundle", "/api/support-bundle"),
+        ("output_channels", "/api/output/:id"),
+        ("tracked_moves", "/api/move/:id/cancel"),
+        ("scripts", "/api/script"),
+        ("tracking_error", "/api/servos/error"),
+        ("busy_estimates", "/api/busy"),
+        ("motion_scale", "/api/motion-scale"),
+        ("sequence_import", "/api/sequences/:name/import"),
+        ("named_pose", "/api/pose/named"),
+        ("jointstate_export", "/api/export/jointstates"),
+        ("channel_lockout", "/api/servo/:id/enable"),
+        ("move_progress", "/api/move/:id/progress"),
+        ("state_at", "/api/state-at"),
+        ("protocol_description", "/api/protocol"),
+        ("route_inventory", "/api/routes"),
+        ("command_stats", "/api/servos/stats"),
+        ("wear_stats", "/api/wear"),
+        ("url_import", "/api/poses/import-url"),
+        ("sag_compensation", "/api/compensation"),
+        ("move_schedule", "/api/move/schedule"),
+    ];
+
+    type Configure = fn(&mut Config);
+
+    /// Features that depend on the config, each with a change enabling it
+    fn configured() -> Vec<(&'static str, Configure)> {
+        vec![
+            ("firmware_busy_query", |c| c.protocol.busy_query = true),
+            ("firmware_voltage_query", |c| {
+                c.protocol.voltage_query = true
+            }),
+            ("firmware_adc_feedback", |c| c.protocol.adc_query = true),
+            ("firmware_fault_register", |c| {
+                c.protocol.fault_register = true
+            }),
+            ("firmware_speed_limit", |c| c.protocol.speed_limit = true),
+            ("extended_angles", |c| c.protocol.extended_angles = true),
+            ("command_framing", |c| {
+                c.protocol.command_suffix = "!".into()
+            }),
+            ("move_lookahead", |c| c.protocol.move_lookahead = true),
+            ("pose_guard", |c| c.pose_guard.max_jump = Some(30)),
+            ("simulation", |c| c.simulate = true),
+            ("demo", |c| c.demo.enabled = true),
+            ("attract", |c| c.attract.enabled = true),
+            ("admin_override", |c| c.admin_token = Some("secret".into())),
+            ("persistent_library", |c| {
+                c.library_file = Some("lib.json".into())
+            }),
+            ("persistent_audit", |c| {
+                c.audit_file = Some("audit.json".into())
+            }),
+            ("baud_autodetect", |c| c.serial.baud_autodetect = vec![9600]),
+            ("replication", |c| {
+                c.replication.peer_url = Some("http://peer".into())
+            }),
+            ("response_signing", |c| {
+                c.response_signing_key = Some("key".into())
+            }),
+            ("home_on_connect", |c| {
+                c.home_on_connect = HomeOnConnect::First
+            }),
+            ("link_loss_recovery", |c| {
+                c.link_loss.policy = LinkLossPolicy::Reconcile
+            }),
+            ("backup", |c| c.backup.dir = Some("backups".into())),
+            ("position_poll", |c| c.position_poll.enabled = true),
+            ("library_watch", |c| {
+                c.library_watch.dir = Some("poses".into())
+            }),
+            ("profiles", |c| {
+                c.profiles.insert("night".into(), Profile::default());
+            }),
+        ]
+    }
+
+    #[test]
+    fn every_feature_is_registered_once() {
+        let configured = configured();
+        let mut names: Vec<&str> = SERVED.iter().map(|(name, _)| *name).collect();
+        names.extend(configured.iter().map(|(name, _)| *name));
+        let registry: Vec<&str> = FEATURES.iter().map(|f| f.name).collect();
+        assert_eq!(names, registry);
+
+        let mut unique = registry.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), registry.len());
+        for name in registry {
+            assert!(
+                name.chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'),
+                "{} is not snake_case",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn configured_features_follow_the_config() {
+        let always: Vec<&str> = SERVED.iter().map(|(name, _)| *name).collect();
+        assert_eq!(enabled(&Config::default()), always);
+        for (name, configure) in configured() {
+            let mut config = Config::default();
+            configure(&mut config);
+            let mut expected = always.clone();
+            expected.push(name);
+            assert_eq!(enabled(&config), expected, "{}", name);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn served_features_have_their_routes() {
+        let server = TestServer::start().await;
+        let routes = server.server.state().routes.get().unwrap();
+        for (name, path) in SERVED {
+            assert!(
+                routes.iter().any(|route| route.path == *path),
+                "{} has no route {}",
+                name,
+                path
+            );
+        }
+    }
+}
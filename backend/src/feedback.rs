@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+
+/// Two-point mapping of a feedback ADC's raw counts to the servo angle
+///
+/// Measured at two angles by `POST /api/servo/:id/feedback/calibrate`;
+/// counts between the points are interpolated and those beyond them
+/// extrapolated along the same line.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FeedbackCalibration {
+    pub raw_a: u32,
+    pub angle_a: u16,
+    pub raw_b: u32,
+    pub angle_b: u16,
+}
+
+impl FeedbackCalibration {
+    /// Two points the line goes through, `None` if the counts are equal
+    pub fn from_points(a: (u32, u16), b: (u32, u16)) -> Option<Self> {
+        if a.0 == b.0 {
+            return None;
+        }
+        Some(Self {
+            raw_a: a.0,
+            angle_a: a.1,
+            raw_b: b.0,
+            angle_b: b.1,
+        })
+    }
+
+    /// The angle `raw` counts stand for, saturated to `0..=max_angle`
+    pub fn angle(&self, raw: u32, max_angle: u16) -> u16 {
+        let slope =
+            (self.angle_b as f64 - self.angle_a as f64) / (self.raw_b as f64 - self.raw_a as f64);
+        let angle = self.angle_a as f64 + (raw as f64 - self.raw_a as f64) * slope;
+        angle.round().clamp(0.0, max_angle as f64) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServoConfig;
+    use crate::testing::TestServer;
+    use serde_json::json;
+
+    /// 1000 counts at 0 degrees, 3000 at 180
+    const RISING: FeedbackCalibration = FeedbackCalibration {
+        raw_a: 1000,
+        angle_a: 0,
+        raw_b: 3000,
+        angle_b: 180,
+    };
+
+    #[test]
+    fn counts_map_along_the_line_through_the_points() {
+        assert_eq!(RISING.angle(1000, 180), 0);
+        assert_eq!(RISING.angle(3000, 180), 180);
+        assert_eq!(RISING.angle(2000, 180), 90);
+        assert_eq!(RISING.angle(1500, 180), 45);
+        // Rounded to the nearest degree
+        assert_eq!(RISING.angle(1006, 180), 1);
+        assert_eq!(RISING.angle(1005, 180), 0);
+
+        // A potentiometer wired the other way round
+        let falling = FeedbackCalibration::from_points((3500, 20), (500, 170)).unwrap();
+        assert_eq!(falling.angle(3500, 180), 20);
+        assert_eq!(falling.angle(2000, 180), 95);
+        assert_eq!(falling.angle(500, 180), 170);
+        // Extrapolated beyond the points
+        assert_eq!(falling.angle(300, 180), 180);
+        assert_eq!(falling.angle(3700, 180), 10);
+    }
+
+    #[test]
+    fn counts_beyond_the_range_saturate() {
+        assert_eq!(RISING.angle(0, 180), 0);
+        assert_eq!(RISING.angle(900, 180), 0);
+        assert_eq!(RISING.angle(3100, 180), 180);
+        assert_eq!(RISING.angle(u32::MAX, 180), 180);
+        // To the firmware's range, which may be wider
+        assert_eq!(RISING.angle(3100, 270), 189);
+        assert_eq!(RISING.angle(5000, 270), 270);
+    }
+
+    #[test]
+    fn equal_counts_make_no_line() {
+        assert_eq!(FeedbackCalibration::from_points((700, 0), (700, 180)), None);
+        assert_eq!(
+            FeedbackCalibration::from_points((1000, 0), (3000, 180)),
+            Some(RISING)
+        );
+    }
+
+    /// Firmware without GET, whose ADC reads 2500 counts, with channel 0
+    /// calibrated as [`RISING`]
+    async fn without_get() -> TestServer {
+        let server = TestServer::with_config(|config| {
+            config.protocol.angle_query = false;
+            config.protocol.adc_query = true;
+            config.servos = vec![ServoConfig {
+                channel: 0,
+                feedback: Some(RISING),
+                ..ServoConfig::default()
+            }];
+        })
+        .await;
+        server.mock.script().adc = Some(2500);
+        server
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn feedback_reports_the_counts_and_their_angle() {
+        let server = without_get().await;
+        let reply = server.get("/api/servo/0/feedback").await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body["available"], true);
+        assert_eq!(reply.body["raw"], 2500);
+        assert_eq!(reply.body["angle"], 135);
+        assert_eq!(
+            reply.body["calibration"],
+            json!({ "raw_a": 1000, "angle_a": 0, "raw_b": 3000, "angle_b": 180 })
+        );
+        // Counts only, without a calibration
+        let reply = server.get("/api/servo/1/feedback").await;
+        assert_eq!(reply.body["raw"], 2500);
+        assert!(reply.body.get("angle").is_none());
+        assert_eq!(server.mock.take_commands(), ["ADC 0", "ADC 1"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn positions_are_measured_through_the_adc_without_get() {
+        let server = without_get().await;
+        let reply = server.get("/api/servo/0").await;
+        assert_eq!(reply.status, 200, "{:?}", reply.body);
+        assert_eq!(reply.body["angle"], 135);
+        assert_eq!(server.mock.take_commands(), ["ADC 0"]);
+        assert_eq!(server.server.state().confirmed_positions()[0], Some(135));
+
+        server.mock.script().adc = Some(4000);
+        assert_eq!(server.get("/api/servo/0").await.body["angle"], 180);
+        // No calibration to read channel 1 by
+        assert_ne!(server.get("/api/servo/1").await.status, 200);
+        assert!(server
+            .mock
+            .take_commands()
+            .iter()
+            .all(|line| line == "ADC 0"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_is_used_where_the_firmware_has_it() {
+        let server = TestServer::with_config(|config| {
+            config.protocol.adc_query = true;
+            config.servos = vec![ServoConfig {
+                channel: 0,
+                feedback: Some(RISING),
+                ..ServoConfig::default()
+            }];
+        })
+        .await;
+        server.mock.script().adc = Some(2500);
+        assert_eq!(server.get("/api/servo/0").await.body["angle"], 90);
+        assert_eq!(server.mock.take_commands(), ["GET 0"]);
+    }
+}
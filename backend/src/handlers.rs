@@ -1,303 +1,4548 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::Body,
+    extract::{MatchedPath, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
     Json,
 };
-use std::sync::{Arc, Mutex};
-use tracing::{error, warn};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{error, info, warn, Instrument};
 
+use crate::arbitration::MotionSource;
+use crate::audit::{self, AuditEntry, AuditLog};
+use crate::backup::Backups;
+use crate::benchmark::{self, BenchmarkReport};
+use crate::clock::Clock;
+use crate::command_queue::{self, QueueEntry};
+use crate::compensation::{self, Compensation, Model};
+use crate::config::{
+    ChannelKind, Config, HomeOnConnect, LinkLossPolicy, PoseViolation, Role, ServoConfig,
+};
+use crate::connection::ConnectionState;
+use crate::constraints;
+use crate::controller::ServoController;
+use crate::current_draw;
+use crate::demo::{MotionActivity, MotionGuard};
+use crate::features;
+use crate::feedback::FeedbackCalibration;
+use crate::history::{self, AngleHistory};
+use crate::imports::{ImportJobs, ImportRecord};
+use crate::jobs::{JobRecord, ScriptJobs};
+use crate::library::{Library, Pose, Preconditions, Sequence};
+use crate::library_watch::LibraryWatch;
+use crate::link_loss::{LinkLoss, OffTarget, Recovery};
+use crate::lockout::ChannelLockout;
 use crate::models::*;
-use crate::serial::SerialManager;
+use crate::moves::{self, MoveOutcome, MoveTracker};
+use crate::overrides::{self, CreateError, Override, Overrides};
+use crate::pattern::{self, Pattern, PatternRun, Patterns};
+use crate::persist::Persister;
+use crate::planner::{self, Frame};
+use crate::poller::Poller;
+use crate::protocol::{self, Angle, Channel};
+use crate::replication::Replication;
+use crate::routes::RouteInfo;
+use crate::schedule::{self, CancelError, Schedule, ScheduledMove};
+use crate::schema::{self, Param};
+use crate::script::{self, Positions};
+use crate::serial::{self, CommandOptions, Observers, SerialMode, NUM_SERVOS};
+use crate::signing::{self, Signer};
+use crate::simulator;
+use crate::stats::{self, CommandStats};
+use crate::tokens::{self, Caller, Scope};
+use crate::url_import::{self, PackKind, UrlImportRecord, UrlImports};
+use crate::usage::{self, QuotaExceeded, RequestClass, Usage, UsageReport};
+use crate::wear::{self, Wear};
+
+mod maintenance;
+mod replication;
+mod snapshots;
+mod streams;
+mod validation;
+
+pub use maintenance::{get_audit, rezero, undo_rezero};
+pub use replication::{get_replication, promote, receive_replication};
+pub use snapshots::{backup_now, get_backup_status, get_snapshot, get_support_bundle, snapshot};
+pub use streams::{position_feed, serial_monitor, stream_poses, stream_sequence};
+pub use validation::{validate_library, validate_poses};
+
+/// Upper bound on the frames of a planned trajectory
+const MAX_TRAJECTORY_FRAMES: u32 = 10_000;
+
+/// Upper bound on the points of a joint state export
+const MAX_EXPORT_POINTS: u64 = 100_000;
+
+/// Largest accepted script source
+const MAX_SCRIPT_BYTES: usize = 16 * 1024;
+
+/// Reads of the positions after connecting before they are left unknown
+const SEED_ATTEMPTS: u32 = 3;
+
+/// Pause between those reads
+const SEED_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Error returned by handlers: status code plus JSON error body
+pub type ApiError = (StatusCode, Json<ErrorResponse>);
 
 /// Shared application state
 pub struct AppState {
-    pub serial: Arc<Mutex<Option<Arc<SerialManager>>>>,
-    pub serial_port_name: String,
-    pub serial_baud_rate: u32,
+    pub serial: Arc<Mutex<Option<Arc<dyn ServoController>>>>,
+    pub config: Mutex<Arc<Config>>,
+    pub config_path: Option<PathBuf>,
+    /// Last known angle per channel (commanded or read back)
+    pub positions: Mutex<[Option<u16>; NUM_SERVOS as usize]>,
+    /// Channels whose known position was restored from `last_pose_file`
+    /// and not confirmed by a command or read since
+    pub restored: Mutex<[bool; NUM_SERVOS as usize]>,
+    /// Reading the positions after connecting failed, and no read of
+    /// every channel succeeded since
+    pub positions_unknown: AtomicBool,
+    /// Last commanded angle per channel
+    pub commanded: Mutex<[Option<u16>; NUM_SERVOS as usize]>,
+    /// Estimated end of the motion in progress per channel
+    pub moving_until: Mutex<[Option<Instant>; NUM_SERVOS as usize]>,
+    /// When the arm was last commanded, for `[idle_exit]`
+    pub last_command: Mutex<Instant>,
+    pub library: Mutex<Library>,
+    pub audit: Mutex<AuditLog>,
+    /// User motion commands, which the demo yields to
+    pub motion: MotionActivity,
+    /// Signalled by the reconnect task when a connection is established
+    pub connected: Notify,
+    pub replication: Replication,
+    /// Tracked MOVEs that can still be cancelled
+    pub moves: MoveTracker,
+    /// MOVEs waiting for a future time
+    pub schedule: Schedule,
+    /// The pattern being traced
+    pub patterns: Patterns,
+    /// Set once the first connection to the arm was established
+    pub has_connected: AtomicBool,
+    pub scripts: ScriptJobs,
+    pub imports: ImportJobs,
+    pub url_imports: UrlImports,
+    pub backups: Backups,
+    /// Files loaded from the `[library_watch]` directory
+    pub library_watch: LibraryWatch,
+    /// Background reads of the positions
+    pub poller: Poller,
+    /// Last supply voltage read from the firmware, in millivolts
+    pub supply_mv: Mutex<Option<u32>>,
+    /// Last fault register read from the firmware
+    pub faults: Mutex<Option<u32>>,
+    /// Firmware slew-rate limit last read or set, 0 for none
+    pub firmware_speed_limit: Mutex<Option<u16>>,
+    /// Changes of the known positions, for `GET /api/export/jointstates`
+    pub history: AngleHistory,
+    /// Channels locked out until re-enabled
+    pub lockout: ChannelLockout,
+    /// Relaxed limits for supervised maintenance
+    pub overrides: Overrides,
+    pub compensation: Compensation,
+    pub link_loss: LinkLoss,
+    /// Lifecycle transitions and unsolicited lines of the serial
+    /// connections
+    pub observers: Observers,
+    pub command_stats: CommandStats,
+    /// Use of the API by each client, for `GET /api/usage`
+    pub usage: Usage,
+    /// Range use and travel of each servo, for `GET /api/wear`
+    pub wear: Wear,
+    /// Writer of the library, lockout, compensation, last pose and wear
+    /// files
+    pub persister: Persister,
+    /// Time source of the idle, motion estimate and reconnect timing
+    pub clock: Arc<dyn Clock>,
+    /// Route inventory, recorded once the router is built
+    pub routes: OnceLock<Vec<RouteInfo>>,
 }
 
 impl AppState {
-    fn get_serial(&self) -> Option<Arc<SerialManager>> {
+    /// Signer for a response to a request with these headers, if signing
+    /// is configured and not declined with `X-No-Signature: 1`
+    pub fn signer(&self, headers: &HeaderMap) -> Option<Signer> {
+        let declined = headers
+            .get(signing::OPT_OUT_HEADER)
+            .is_some_and(|v| v.as_bytes() == b"1");
+        if declined {
+            return None;
+        }
+        self.config().response_signing_key.as_deref().map(Signer::new)
+    }
+
+    fn get_serial(&self) -> Option<Arc<dyn ServoController>> {
         self.serial.lock().unwrap().clone()
     }
-}
 
-/// Handle serial errors and detect disconnections
-fn handle_serial_error(
-    state: &AppState,
-    error: &anyhow::Error,
-) -> (StatusCode, Json<ErrorResponse>) {
-    // If error indicates I/O failure, drop the serial manager
-    let error_msg = error.to_string();
-    if error_msg.contains("Failed to clear input buffer")
-        || error_msg.contains("Failed to write")
-        || error_msg.contains("Failed to read")
-    {
-        warn!(
-            "Serial I/O error detected, dropping connection for reconnection: {}",
-            error
-        );
-        let mut serial = state.serial.lock().unwrap();
-        *serial = None;
-        (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(ErrorResponse {
-                error: "Serial device disconnected, reconnecting...".to_string(),
-            }),
-        )
-    } else {
-        (
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: error.to_string(),
-            }),
-        )
+    pub fn require_serial(&self) -> Result<Arc<dyn ServoController>, ApiError> {
+        self.get_serial().ok_or_else(|| {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(ErrorResponse::new("Serial device not connected")),
+            )
+        })
     }
-}
 
-/// Health check endpoint
-pub async fn health_check(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
-    let serial_status = match state.get_serial() {
-        Some(_) => "connected".to_string(),
-        None => "not_connected".to_string(),
-    };
+    /// The serial connection; with `wait`, a disconnected device is given
+    /// up to `timeouts.connect_wait_ms` to be reconnected
+    async fn wait_for_serial(&self, wait: bool) -> Result<Arc<dyn ServoController>, ApiError> {
+        if !wait {
+            return self.require_serial();
+        }
 
-    let overall_status = if serial_status == "connected" {
-        "ok".to_string()
-    } else {
-        "degraded".to_string()
-    };
+        // Register before checking so a reconnect in between isn't missed
+        let notified = self.connected.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if let Some(serial) = self.get_serial() {
+            return Ok(serial);
+        }
 
-    Json(HealthResponse {
-        status: overall_status,
-        serial: serial_status,
-    })
-}
+        let timeout = Duration::from_millis(self.config().timeouts.connect_wait_ms);
+        info!("Serial device not connected, waiting up to {:?}", timeout);
+        let _ = tokio::time::timeout(timeout, notified).await;
+        self.require_serial()
+    }
 
-/// Enter serial mode
-pub async fn start_serial_mode(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
-            ));
+    /// Snapshot of the active configuration, relaxed by the override the
+    /// request named with `X-Override` while that is still active
+    pub fn config(&self) -> Arc<Config> {
+        let config = self.base_config();
+        let Some(id) = overrides::requested() else {
+            return config;
+        };
+        match self.overrides.active(self.clock.now_ms()) {
+            Some(active) if active.id == id => Arc::new(active.relaxed.apply(&config)),
+            _ => config,
         }
-    };
+    }
 
-    match serial.start_serial_mode() {
-        Ok(_) => Ok(Json(SuccessResponse {
-            status: "serial_mode".to_string(),
-        })),
-        Err(e) => {
-            error!("Failed to start serial mode: {}", e);
-            Err(handle_serial_error(&state, &e))
+    /// Snapshot of the configuration without any override, the one to
+    /// change
+    pub fn base_config(&self) -> Arc<Config> {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Velocity limit of a channel in degrees per second: the stricter of
+    /// its `max_velocity` and the firmware's slew-rate limit, if known
+    pub fn max_velocity(&self, channel: u8) -> u16 {
+        let software = self.config().max_velocity(channel);
+        match *self.firmware_speed_limit.lock().unwrap() {
+            Some(firmware) if firmware > 0 => software.min(firmware),
+            _ => software,
         }
     }
-}
 
-/// Exit serial mode
-pub async fn stop_serial_mode(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
-            ));
+    /// Account `duration_ms` of MOVE to the client of the current request,
+    /// if there is one
+    pub fn account_move(&self, duration_ms: u32) {
+        if let Some(principal) = usage::principal() {
+            self.usage.move_ms(&principal, duration_ms.into(), self.clock.now_ms());
         }
-    };
+    }
 
-    match serial.stop_serial_mode() {
-        Ok(_) => Ok(Json(SuccessResponse {
-            status: "button_mode".to_string(),
-        })),
-        Err(e) => {
-            error!("Failed to stop serial mode: {}", e);
-            Err(handle_serial_error(&state, &e))
+    /// Note that the arm was commanded just now
+    pub fn note_command(&self) {
+        *self.last_command.lock().unwrap() = self.clock.now();
+    }
+
+    /// Time since the arm was last commanded
+    pub fn idle_for(&self) -> Duration {
+        self.clock.now().saturating_duration_since(*self.last_command.lock().unwrap())
+    }
+
+    /// Remember the commanded angles of the first `angles.len()` channels,
+    /// sent as a MOVE over `duration` or else as a POSE
+    fn record_positions(&self, angles: &[u16], duration: Option<Duration>) {
+        self.note_command();
+        let mut positions = self.positions.lock().unwrap();
+        let mut commanded = self.commanded.lock().unwrap();
+        let mut restored = self.restored.lock().unwrap();
+        for (channel, &angle) in angles.iter().enumerate().take(NUM_SERVOS as usize) {
+            let from = commanded[channel].or(positions[channel].filter(|_| !restored[channel]));
+            self.record_wear(channel as u8, from, angle, duration);
+            positions[channel] = Some(angle);
+            commanded[channel] = Some(angle);
+            restored[channel] = false;
         }
+        self.history.record(&positions);
+        let commands = angles.iter().enumerate().map(|(channel, &angle)| (channel as u8, angle));
+        self.command_stats.record(commands, self.clock.now(), self.clock.now_ms());
     }
-}
 
-/// Set servo angle
-pub async fn set_servo_angle(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<u8>,
-    Json(req): Json<SetAngleRequest>,
-) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
-            ));
+    fn record_command(&self, channel: u8, angle: u16) {
+        self.note_command();
+        let commanded = self.commanded.lock().unwrap()[channel as usize];
+        let from = commanded.or(self.confirmed_positions()[channel as usize]);
+        self.record_wear(channel, from, angle, None);
+        self.record_position(channel, angle);
+        self.command_stats.record([(channel, angle)], self.clock.now(), self.clock.now_ms());
+        if let Some(slot) = self.commanded.lock().unwrap().get_mut(channel as usize) {
+            *slot = Some(angle);
         }
-    };
+    }
 
-    match serial.set_servo_angle(id, req.angle) {
-        Ok(_) => Ok(Json(SuccessResponse {
-            status: "ok".to_string(),
-        })),
-        Err(e) => {
-            error!("Failed to set servo {} angle: {}", id, e);
-            Err(handle_serial_error(&state, &e))
+    /// Count a command moving `channel` from `from` (if known) to `to`
+    /// towards its wear; without a MOVE `duration` the servo is taken to
+    /// move at its velocity limit
+    fn record_wear(&self, channel: u8, from: Option<u16>, to: u16, duration: Option<Duration>) {
+        let moving_ms = match (from, duration) {
+            (Some(from), _) if from == to => 0,
+            (_, Some(duration)) => duration.as_millis() as u64,
+            (Some(from), None) => {
+                let velocity = self.max_velocity(channel) as u64;
+                (from.abs_diff(to) as u64 * 1000).div_ceil(velocity)
+            }
+            (None, None) => 0,
+        };
+        self.wear.record(channel, from, to, moving_ms);
+    }
+
+    /// Record an angle read back from the firmware, returning it unless
+    /// it is within the channel's dead zone of the known position, which
+    /// is kept then
+    fn record_reading(&self, channel: u8, angle: u16) -> u16 {
+        let dead_zone = self.config().servo(channel).dead_zone;
+        let known = self.confirmed_positions()[channel as usize];
+        let angle = match known {
+            Some(known) if known.abs_diff(angle) <= dead_zone => known,
+            _ => angle,
+        };
+        self.record_position(channel, angle);
+        angle
+    }
+
+    fn record_position(&self, channel: u8, angle: u16) {
+        let mut positions = self.positions.lock().unwrap();
+        if let Some(slot) = positions.get_mut(channel as usize) {
+            *slot = Some(angle);
+            self.restored.lock().unwrap()[channel as usize] = false;
+            self.history.record(&positions);
         }
     }
-}
 
-/// Set servo PWM pulse width
-pub async fn set_servo_pwm(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<u8>,
-    Json(req): Json<SetPwmRequest>,
-) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
-            ));
+    /// The known positions, without those only restored from
+    /// `last_pose_file`
+    pub fn confirmed_positions(&self) -> [Option<u16>; NUM_SERVOS as usize] {
+        let mut positions = *self.positions.lock().unwrap();
+        let restored = *self.restored.lock().unwrap();
+        for (position, restored) in positions.iter_mut().zip(restored) {
+            if restored {
+                *position = None;
+            }
         }
-    };
+        positions
+    }
 
-    match serial.set_servo_pwm(id, req.pulse_us) {
-        Ok(_) => Ok(Json(SuccessResponse {
-            status: "ok".to_string(),
-        })),
-        Err(e) => {
-            error!("Failed to set servo {} PWM: {}", id, e);
-            Err(handle_serial_error(&state, &e))
+    /// Take positions saved by an earlier run as known, each until a
+    /// command or read confirms it
+    pub fn restore_positions(&self, saved: &[Option<u16>; NUM_SERVOS as usize]) {
+        let mut positions = self.positions.lock().unwrap();
+        let mut restored = self.restored.lock().unwrap();
+        for channel in 0..NUM_SERVOS as usize {
+            if positions[channel].is_none() && saved[channel].is_some() {
+                positions[channel] = saved[channel];
+                restored[channel] = true;
+            }
         }
     }
-}
 
-/// Get servo position
-pub async fn get_servo_position(
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<u8>,
-) -> Result<Json<ServoPosition>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
-            ));
+    /// Note motion of the first `channels` servos lasting `duration` from
+    /// now
+    ///
+    /// A later estimate already in place is kept, so the segments of a
+    /// planned motion (a sequence, trajectory or tracked move) don't
+    /// shorten the plan; [`end_motion`](Self::end_motion) drops it.
+    pub fn record_motion(&self, channels: usize, duration: Duration) {
+        let until = self.clock.now() + duration;
+        for slot in self.moving_until.lock().unwrap().iter_mut().take(channels) {
+            *slot = Some(slot.map_or(until, |current| current.max(until)));
         }
-    };
+    }
 
-    match serial.get_servo_angle(id) {
-        Ok(angle) => Ok(Json(ServoPosition {
-            channel: id,
-            angle,
-        })),
-        Err(e) => {
-            error!("Failed to get servo {} position: {}", id, e);
-            Err(handle_serial_error(&state, &e))
+    /// Note a planned motion of the first `channels` servos lasting
+    /// `duration`; the estimate is dropped when the plan is, whether the
+    /// motion finished, failed or was cancelled
+    pub fn plan_motion(&self, channels: usize, duration: Duration) -> MotionPlan<'_> {
+        self.record_motion(channels, duration);
+        MotionPlan {
+            state: self,
+            channels,
+        }
+    }
+
+    /// Wall-clock time (ms since the epoch) a servo is estimated to be
+    /// busy until, `None` if idle
+    fn busy_until_ms(&self, channel: u8) -> Option<u64> {
+        self.motion_remaining(channel)
+            .map(|remaining| self.clock.now_ms() + remaining.as_millis() as u64)
+    }
+
+    /// Estimated time until a servo finishes its MOVE, `None` if idle
+    pub fn motion_remaining(&self, channel: u8) -> Option<Duration> {
+        let until = self.moving_until.lock().unwrap()[channel as usize]?;
+        Some(until.saturating_duration_since(self.clock.now())).filter(|d| !d.is_zero())
+    }
+
+    /// Forget all known positions (e.g. after the firmware was reset)
+    pub fn clear_positions(&self) {
+        *self.positions.lock().unwrap() = [None; NUM_SERVOS as usize];
+        *self.commanded.lock().unwrap() = [None; NUM_SERVOS as usize];
+        *self.restored.lock().unwrap() = [false; NUM_SERVOS as usize];
+        self.history.gap();
+    }
+
+    /// Persist the library if a library file is configured
+    fn save_library(&self, library: &Library) -> Result<(), ApiError> {
+        if let Some(path) = &self.config().library_file {
+            if let Err(e) = library.save(path, &self.persister) {
+                error!("Failed to save library: {:#}", e);
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorResponse::new(format!("{:#}", e))),
+                ));
+            }
         }
+        Ok(())
     }
 }
 
-/// Get all servo positions
-pub async fn get_all_servos(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<ServoPositions>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
+/// The first pose or sequence of the library that fails validation
+fn library_error(library: &Library, config: &Config) -> Option<String> {
+    library
+        .poses
+        .iter()
+        .map(|(name, pose)| (name, pose.validate(config)))
+        .chain(library.sequences.iter().map(|(name, seq)| (name, seq.validate(config))))
+        .find_map(|(name, result)| result.err().map(|e| format!("{}: {:#}", name, e)))
+}
+
+/// Who made a request, as reported by the `X-Actor` header
+fn actor(headers: &HeaderMap) -> String {
+    headers
+        .get("x-actor")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .unwrap_or("anonymous")
+        .to_string()
+}
+
+/// JSON subtree `{section: {name: value}}` used to diff one library entry
+fn library_subtree<T: serde::Serialize>(
+    section: &str,
+    name: &str,
+    value: Option<&T>,
+) -> serde_json::Value {
+    let mut entries = serde_json::Map::new();
+    if let Some(value) = value {
+        entries.insert(name.to_string(), serde_json::to_value(value).unwrap_or_default());
+    }
+    serde_json::json!({ section: entries })
+}
+
+impl AppState {
+    /// Append to the audit trail, returning the entry unless there were
+    /// no changes; failures are logged, not returned, since the change has
+    /// already been applied
+    fn audit(
+        &self,
+        headers: &HeaderMap,
+        endpoint: &str,
+        kind: &str,
+        target: Option<&str>,
+        before: &serde_json::Value,
+        after: &serde_json::Value,
+    ) -> Option<AuditEntry> {
+        let changes = audit::diff(before, after);
+        let result = self
+            .audit
+            .lock()
+            .unwrap()
+            .record(&actor(headers), endpoint, kind, target, changes);
+        match result {
+            Ok(Some(entry)) => {
+                self.replication.local_write(&entry);
+                self.backups.changed();
+                Some(entry)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to record audit entry: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Mark the start of a user motion command; refused on a standby
+    pub fn begin_motion(&self) -> Result<MotionGuard<'_>, ApiError> {
+        self.begin_motion_as(MotionSource::User)
+    }
+
+    /// Mark the start of a motion command from `source`, waiting for the
+    /// commands it yields to; refused on a standby
+    pub fn begin_motion_as(&self, source: MotionSource) -> Result<MotionGuard<'_>, ApiError> {
+        if self.replication.role() == Role::Standby {
             return Err((
                 StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
+                Json(ErrorResponse::with_code(
+                    "STANDBY",
+                    "This instance is a standby; promote it to accept motion commands",
+                )),
             ));
         }
-    };
-
-    match serial.get_all_servos() {
-        Ok(servos) => {
-            let positions = servos
-                .into_iter()
-                .map(|(channel, angle)| ServoPosition { channel, angle })
-                .collect();
-            Ok(Json(ServoPositions { servos: positions }))
+        if self.link_loss.latched() {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(ErrorResponse::with_code(
+                    "LINK_LOSS_LATCHED",
+                    "The link dropped during a MOVE; acknowledge with POST /api/link-loss/ack",
+                )),
+            ));
         }
-        Err(e) => {
-            error!("Failed to get all servos: {}", e);
-            Err(handle_serial_error(&state, &e))
+        if let Some(principal) = usage::principal() {
+            let config = self.config();
+            let now_ms = self.clock.now_ms();
+            self.usage
+                .motion(&principal, config.quota(&principal), now_ms)
+                .map_err(|exceeded| quota_exceeded(&principal, exceeded))?;
         }
+        let motion = self.motion.begin(source);
+        self.poller.activity();
+        self.note_command();
+        Ok(motion)
     }
 }
 
-/// Execute POSE command
-pub async fn execute_pose(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<PoseRequest>,
-) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
-            ));
+/// A planned motion in progress, see [`AppState::plan_motion`]
+pub struct MotionPlan<'a> {
+    state: &'a AppState,
+    channels: usize,
+}
+
+impl Drop for MotionPlan<'_> {
+    fn drop(&mut self) {
+        let mut moving_until = self.state.moving_until.lock().unwrap();
+        for slot in moving_until.iter_mut().take(self.channels) {
+            *slot = None;
         }
-    };
+    }
+}
 
-    match serial.execute_pose(&req.angles) {
-        Ok(_) => Ok(Json(SuccessResponse {
-            status: "ok".to_string(),
-        })),
-        Err(e) => {
-            error!("Failed to execute POSE: {}", e);
-            Err(handle_serial_error(&state, &e))
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Who sent the request, if its bearer token is configured
+fn caller(state: &AppState, headers: &HeaderMap) -> Option<Caller> {
+    bearer_token(headers).and_then(|token| tokens::caller(&state.config(), token))
+}
+
+/// Check the request's bearer token against the configured admin token,
+/// or for a named token with the `admin` scope
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    if !tokens::admin_configured(&state.config()) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(ErrorResponse::with_code(
+                "ADMIN_DISABLED",
+                "No admin token configured",
+            )),
+        ));
+    }
+
+    if caller(state, headers).is_some_and(|caller| caller.grants(Scope::Admin)) {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            Json(ErrorResponse::with_code(
+                "FORBIDDEN",
+                "Valid admin token required",
+            )),
+        ))
+    }
+}
+
+fn bad_request(error: String) -> ApiError {
+    (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(error)))
+}
+
+fn unprocessable(code: &str, error: String) -> ApiError {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse::with_code(code, error)),
+    )
+}
+
+/// Check a request value against the [`schema`], answering 422
+fn check(config: &Config, param: Param, channel: Option<u8>, value: u32) -> Result<(), ApiError> {
+    param
+        .check(config, channel, value)
+        .map_err(|e| unprocessable(e.code, e.message))
+}
+
+/// Refuse commands to a disabled channel
+fn check_enabled(config: &Config, channel: u8) -> Result<ChannelKind, ApiError> {
+    match config.kind(channel) {
+        ChannelKind::Disabled => Err(unprocessable(
+            "CHANNEL_DISABLED",
+            format!("Channel {} is disabled", channel),
+        )),
+        kind => Ok(kind),
+    }
+}
+
+/// Refuse direct commands to a locked-out channel
+fn check_unlocked(state: &AppState, channel: u8) -> Result<(), ApiError> {
+    if state.lockout.is_disabled(channel) {
+        return Err(channel_locked(channel));
+    }
+    Ok(())
+}
+
+fn channel_locked(channel: u8) -> ApiError {
+    (
+        StatusCode::LOCKED,
+        Json(ErrorResponse::with_code(
+            "CHANNEL_DISABLED",
+            format!(
+                "Channel {} is locked out; re-enable it with POST /api/servo/{}/enable",
+                channel, channel
+            ),
+        )),
+    )
+}
+
+/// Hold locked-out channels of a POSE/MOVE at their current position
+///
+/// POSE and MOVE can't leave a channel out, so a locked-out channel is
+/// sent its current position (read back if unknown) instead of its
+/// target. Returns the angles to send and the channels held. With
+/// `strict`, a target that would move a locked-out channel is refused.
+fn hold_locked(
+    state: &AppState,
+    serial: &dyn ServoController,
+    angles: &[u16],
+    strict: bool,
+) -> Result<(Vec<u16>, Vec<u8>), ApiError> {
+    let mut held = angles.to_vec();
+    let mut skipped = Vec::new();
+    for channel in state.lockout.disabled() {
+        let Some(target) = held.get_mut(channel as usize) else {
+            continue;
+        };
+        let current = current_position(state, serial, servo_channel(channel)?)?;
+        if *target != current {
+            if strict {
+                return Err(channel_locked(channel));
+            }
+            *target = current;
+            skipped.push(channel);
         }
     }
+    Ok((held, skipped))
 }
 
-/// Execute MOVE command
-pub async fn execute_move(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<MoveRequest>,
-) -> Result<Json<SuccessResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let serial = match state.get_serial() {
-        Some(s) => s,
-        None => {
-            return Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    error: "Serial device not connected".to_string(),
-                }),
-            ));
+/// The channel numbered `id`, if the arm has it
+fn servo_channel(id: u8) -> Result<Channel, ApiError> {
+    Channel::new(id, NUM_SERVOS).map_err(bad_request)
+}
+
+/// Refuse angle commands to a channel without a servo
+fn check_servo(config: &Config, channel: u8) -> Result<(), ApiError> {
+    match check_enabled(config, channel)? {
+        ChannelKind::PwmOutput => Err(unprocessable(
+            "NOT_A_SERVO",
+            format!(
+                "Channel {} is a PWM output, use POST /api/output/{}",
+                channel, channel
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check an angle against the firmware's range and the channel's soft
+/// limits, then apply its trim and the sag `correction` in degrees
+///
+/// Errors are `FIRMWARE_RANGE` if the firmware can't represent the angle
+/// (before or after trim and correction) and `SOFT_LIMIT` if it is outside
+/// the configured limits. Channels without a servo are refused.
+fn to_servo_angle(
+    config: &Config,
+    channel: u8,
+    angle: u16,
+    correction: f64,
+) -> Result<Angle, ApiError> {
+    check_servo(config, channel)?;
+    check(config, Param::Angle, Some(channel), angle as u32)?;
+
+    // Scaled towards the center, so the result stays within the limits
+    let center = config.center(channel) as f64;
+    let angle = (center + (angle as f64 - center) * config.motion_scale).round() as u16;
+
+    let max_angle = config.protocol.max_angle;
+    let trimmed =
+        angle as i32 + config.servo(channel).trim as i32 + correction.round() as i32;
+    u16::try_from(trimmed)
+        .ok()
+        .and_then(|trimmed| Angle::new(trimmed, max_angle).ok())
+        .ok_or_else(|| {
+            unprocessable(
+                "FIRMWARE_RANGE",
+                format!(
+                    "Angle {} for servo {} is {} after trim and compensation, beyond the \
+                     firmware range 0-{}",
+                    angle, channel, trimmed, max_angle
+                ),
+            )
+        })
+}
+
+/// Remove the channel's sag `correction`, trim and the motion scale from
+/// an angle read back from the firmware
+///
+/// With a motion scale of 0 every servo sits at its center, and the angle
+/// is reported as it is.
+fn from_servo_angle(config: &Config, channel: Channel, angle: Angle, correction: f64) -> u16 {
+    let channel = channel.get();
+    let servo = config.servo(channel);
+    let max_angle = config.protocol.max_angle as i32;
+    let angle = (angle.get() as i32 - servo.trim as i32 - correction.round() as i32)
+        .clamp(0, max_angle);
+    if config.motion_scale == 0.0 {
+        return angle as u16;
+    }
+    let center = config.center(channel) as f64;
+    let unscaled = center + (angle as f64 - center) / config.motion_scale;
+    (unscaled.round() as i32).clamp(0, max_angle) as u16
+}
+
+/// Apply limits and trims to a positional list of angles
+fn to_servo_angles(
+    config: &Config,
+    angles: &[u16],
+) -> Result<Vec<Angle>, ApiError> {
+    angles
+        .iter()
+        .enumerate()
+        .map(|(channel, &angle)| to_servo_angle(config, channel as u8, angle, 0.0))
+        .collect()
+}
+
+/// [`to_servo_angles`] with the sag compensation for the arm at `angles`,
+/// the channels past them at their known positions
+pub fn to_compensated_angles(
+    state: &AppState,
+    config: &Config,
+    angles: &[u16],
+) -> Result<Vec<Angle>, ApiError> {
+    let mut context = state.confirmed_positions();
+    for (known, &angle) in context.iter_mut().zip(angles) {
+        *known = Some(angle);
+    }
+    angles
+        .iter()
+        .enumerate()
+        .map(|(channel, &angle)| {
+            let correction = state.compensation.correction(channel as u8, &context);
+            to_servo_angle(config, channel as u8, angle, correction)
+        })
+        .collect()
+}
+
+/// Sag correction of `channel` with the other channels at their known
+/// positions
+fn known_correction(state: &AppState, channel: u8) -> f64 {
+    let context = state.confirmed_positions();
+    state.compensation.correction(channel, &context)
+}
+
+impl CommandQuery {
+    fn options(&self) -> CommandOptions {
+        CommandOptions {
+            clear_input: self.clear_input,
+            chain: false,
         }
+    }
+}
+
+/// Resolve POSE/MOVE angles into a positional list
+///
+/// For the map form, keys are channel names or indices and channels below
+/// the highest one given are filled in with their current position. POSE
+/// and MOVE can't skip a channel, so filling in a channel without a servo
+/// is refused.
+pub fn resolve_angles(
+    state: &AppState,
+    serial: &dyn ServoController,
+    angles: &PoseAngles,
+) -> Result<Vec<u16>, ApiError> {
+    let map = match angles {
+        PoseAngles::List(list) => return Ok(list.clone()),
+        PoseAngles::Map(map) => map,
     };
+    let targets = map_targets(&state.config(), map)?;
+    fill_pose(state, serial, &targets, 0)
+}
 
-    match serial.execute_move(req.duration_ms, &req.angles) {
-        Ok(_) => Ok(Json(SuccessResponse {
-            status: "ok".to_string(),
-        })),
-        Err(e) => {
-            error!("Failed to execute MOVE: {}", e);
-            Err(handle_serial_error(&state, &e))
-        }
+/// The channels a map of channel name or index to angle sets
+fn map_targets(
+    config: &Config,
+    map: &BTreeMap<String, u16>,
+) -> Result<[Option<u16>; NUM_SERVOS as usize], ApiError> {
+    let mut targets: [Option<u16>; NUM_SERVOS as usize] = [None; NUM_SERVOS as usize];
+    for (key, &angle) in map {
+        set_target(config, &mut targets, channel_key(config, key)?, angle)?;
+    }
+    Ok(targets)
+}
+
+/// The channel a map key names, by index or servo name
+fn channel_key(config: &Config, key: &str) -> Result<u8, ApiError> {
+    match key.parse::<u8>() {
+        Ok(channel) if channel < NUM_SERVOS => Ok(channel),
+        Ok(channel) => Err(bad_request(format!("Invalid servo channel: {}", channel))),
+        Err(_) => config
+            .channel_by_name(key)
+            .ok_or_else(|| bad_request(format!("Unknown servo name: {}", key))),
     }
 }
+
+/// Record one target of a partial pose, refusing a channel given twice
+fn set_target(
+    config: &Config,
+    targets: &mut [Option<u16>; NUM_SERVOS as usize],
+    channel: u8,
+    angle: u16,
+) -> Result<(), ApiError> {
+    check_servo(config, channel)?;
+    if targets[channel as usize].is_some() {
+        return Err(bad_request(format!("Servo {} specified more than once", channel)));
+    }
+    targets[channel as usize] = Some(angle);
+    Ok(())
+}
+
+/// Complete a partial pose with current positions, up to the highest
+/// channel given and at least the first `min_count` channels
+fn fill_pose(
+    state: &AppState,
+    serial: &dyn ServoController,
+    targets: &[Option<u16>; NUM_SERVOS as usize],
+    min_count: usize,
+) -> Result<Vec<u16>, ApiError> {
+    let config = state.config();
+    let count = match targets.iter().rposition(|t| t.is_some()) {
+        Some(last) => (last + 1).max(min_count),
+        None => return Err(bad_request("No servo angles given".to_string())),
+    };
+
+    let mut resolved = Vec::with_capacity(count);
+    for (channel, target) in Channel::all(NUM_SERVOS).zip(&targets[..count]) {
+        let angle = match target {
+            Some(angle) => *angle,
+            None if !config.kind(channel.get()).is_servo() => {
+                return Err(unprocessable(
+                    "NOT_A_SERVO",
+                    format!(
+                        "Channel {} has no servo; POSE and MOVE can't reach servo {} without \
+                         commanding it",
+                        channel.get(),
+                        count - 1
+                    ),
+                ));
+            }
+            None => read_position(state, serial, channel)
+                .map_err(|(_, Json(e))| positions_unknown(channel.get(), &e.error))?,
+        };
+        resolved.push(angle);
+    }
+
+    Ok(resolved)
+}
+
+/// Refusal of a command needing a channel's position that isn't known
+/// and couldn't be read
+fn positions_unknown(channel: u8, reason: &str) -> ApiError {
+    (
+        StatusCode::CONFLICT,
+        Json(ErrorResponse::with_code(
+            "POSITIONS_UNKNOWN",
+            format!("Position of servo {} is unknown and couldn't be read: {}", channel, reason),
+        )),
+    )
+}
+
+/// A servo's known position, read from the firmware if unknown or only
+/// restored from `last_pose_file`
+pub fn current_position(
+    state: &AppState,
+    serial: &dyn ServoController,
+    channel: Channel,
+) -> Result<u16, ApiError> {
+    match state.confirmed_positions()[channel.index()] {
+        Some(angle) => Ok(angle),
+        None => read_position(state, serial, channel)
+            .map_err(|(_, Json(e))| positions_unknown(channel.get(), &e.error)),
+    }
+}
+
+/// Read one servo's angle from the firmware, updating the position cache
+pub fn read_position(
+    state: &AppState,
+    serial: &dyn ServoController,
+    channel: Channel,
+) -> Result<u16, ApiError> {
+    let config = state.config();
+    match serial.get_measured_angle(channel, config.servo(channel.get()).feedback.as_ref()) {
+        Ok(None) => Err(positions_unknown(channel.get(), "the firmware has no angle for it")),
+        Ok(Some(angle)) => {
+            let correction = known_correction(state, channel.get());
+            let angle = from_servo_angle(&config, channel, angle, correction);
+            let angle = state.record_reading(channel.get(), angle);
+            Ok(angle)
+        }
+        Err(e) => {
+            error!(channel = channel.get(), error = %e, "Failed to read servo");
+            Err(handle_serial_error(state, &e))
+        }
+    }
+}
+
+/// Handle serial errors and detect disconnections
+pub fn handle_serial_error(
+    state: &AppState,
+    error: &anyhow::Error,
+) -> ApiError {
+    if command_queue::is_cancelled(error) {
+        return (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code("CANCELLED_BY_OPERATOR", error.to_string())),
+        );
+    }
+    // If error indicates I/O failure, drop the serial manager
+    if serial::is_io_failure(error) {
+        warn!(
+            "Serial I/O error detected, dropping connection for reconnection: {}",
+            error
+        );
+        let mut serial = state.serial.lock().unwrap();
+        *serial = None;
+        state
+            .observers
+            .connection
+            .record(ConnectionState::Disconnected, Some(error.to_string()));
+        state.history.gap();
+        if (0..NUM_SERVOS).any(|channel| state.motion_remaining(channel).is_some()) {
+            warn!("Serial link dropped during a MOVE");
+            state.link_loss.lost(state.clock.now_ms());
+        }
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse::new("Serial device disconnected, reconnecting...")),
+        )
+    } else if serial::is_mode_refused(error) {
+        (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code("SERIAL_MODE_REFUSED", error.to_string())),
+        )
+    } else if serial::is_ignored_in_button_mode(error) {
+        (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "FIRMWARE_IN_BUTTON_MODE",
+                "The firmware ignored the command but answers reads, so it is likely in \
+                 button mode; enter serial mode with POST /api/serial/start first, or set \
+                 protocol.auto_start",
+            )),
+        )
+    } else if serial::is_command_too_long(error) {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse::with_code(
+                "COMMAND_TOO_LONG",
+                format!(
+                    "{}; send fewer channels per MOVE, or a POSE, which is split into angle \
+                     commands",
+                    error
+                ),
+            )),
+        )
+    } else if serial::is_protocol_violation(error) {
+        (
+            StatusCode::BAD_GATEWAY,
+            Json(ErrorResponse::with_code("PROTOCOL_VIOLATION", error.to_string())),
+        )
+    } else if serial::is_button_mode(error) {
+        (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "NOT_IN_SERIAL_MODE",
+                "Firmware is in button mode, enter serial mode first",
+            )),
+        )
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse::new(error.to_string())),
+        )
+    }
+}
+
+/// Run a request with its method, path and actor as the requester of the
+/// commands it queues, inside a `request` span carrying its id
+///
+/// The id is the client's `X-Request-Id` if given (up to 64 printable
+/// characters), else a generated one, and is returned in the response.
+pub async fn tag_requester(request: Request, next: Next) -> Response {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:08x}", NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+    let requester = format!(
+        "{} {} ({})",
+        request.method(),
+        request.uri().path(),
+        actor(request.headers())
+    );
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let mut response = command_queue::REQUESTER
+        .scope(requester, next.run(request))
+        .instrument(span)
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
+/// Account a request to its `X-Actor`, refusing it once the client used
+/// up its hourly request quota
+pub async fn account_usage(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let principal = actor(request.headers());
+    let class = match *request.method() {
+        Method::GET | Method::HEAD => RequestClass::Read,
+        _ => RequestClass::Write,
+    };
+    let config = state.config();
+    let now_ms = state.clock.now_ms();
+    if let Err(exceeded) = state.usage.request(&principal, class, config.quota(&principal), now_ms)
+    {
+        return quota_exceeded(&principal, exceeded).into_response();
+    }
+    usage::PRINCIPAL.scope(principal, next.run(request)).await
+}
+
+fn quota_exceeded(principal: &str, exceeded: QuotaExceeded) -> ApiError {
+    let mut error = ErrorResponse::with_code(
+        "QUOTA_EXCEEDED",
+        format!(
+            "{} used up its {} of {}, until {}",
+            principal, exceeded.quota, exceeded.limit, exceeded.reset_ms
+        ),
+    );
+    error.details = Some(serde_json::json!(exceeded));
+    (StatusCode::TOO_MANY_REQUESTS, Json(error))
+}
+
+/// Refuse a request without a token granting its route's scope, once
+/// `tokens` are configured
+///
+/// Routes outside the inventory, e.g. ones added when embedding, and the
+/// replication route, which checks the peer's token, are left alone.
+pub async fn check_scope(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.config().tokens.is_empty() {
+        return next.run(request).await;
+    }
+    let method = match *request.method() {
+        Method::HEAD => "GET",
+        ref method => method.as_str(),
+    };
+    let required = request.extensions().get::<MatchedPath>().and_then(|path| {
+        let routes = state.routes.get()?;
+        let route = routes
+            .iter()
+            .find(|route| route.method == method && route.path == path.as_str())?;
+        route.auth.scope()
+    });
+    let Some(required) = required else {
+        return next.run(request).await;
+    };
+
+    match caller(&state, request.headers()) {
+        Some(caller) if caller.grants(required) => next.run(request).await,
+        Some(caller) => {
+            let mut error = ErrorResponse::with_code(
+                "INSUFFICIENT_SCOPE",
+                format!("Token {} lacks the {} scope", caller.name, required),
+            );
+            error.details = Some(serde_json::json!({
+                "required": required,
+                "scopes": caller.scopes,
+            }));
+            (StatusCode::FORBIDDEN, Json(error)).into_response()
+        }
+        None => (
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse::with_code(
+                "UNAUTHORIZED",
+                format!("A bearer token with the {} scope is required", required),
+            )),
+        )
+            .into_response(),
+    }
+}
+
+/// Answer 504 once a request runs longer than its `timeouts.request_ms`,
+/// or the entry of its route in `timeouts.endpoint_ms`
+///
+/// The handler runs as a task of its own, so a serial command it started
+/// still completes and the port isn't left mid-exchange; only its response
+/// is dropped.
+pub async fn request_timeout(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = state.config();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| format!("{} {}", request.method(), path.as_str()));
+    let limit_ms = route
+        .as_ref()
+        .and_then(|route| config.timeouts.endpoint_ms.get(route))
+        .copied()
+        .unwrap_or(config.timeouts.request_ms);
+    if limit_ms == 0 {
+        return next.run(request).await;
+    }
+
+    let route = route.unwrap_or_else(|| format!("{} {}", request.method(), request.uri().path()));
+    let mut task = tokio::spawn(next.run(request));
+    tokio::select! {
+        joined = &mut task => match joined {
+            Ok(response) => response,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        },
+        _ = state.clock.sleep(Duration::from_millis(limit_ms)) => {
+            warn!(route, limit_ms, "Request timed out, leaving it to finish in the background");
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                Json(ErrorResponse::with_code(
+                    "REQUEST_TIMEOUT",
+                    format!(
+                        "No response within {}ms; a command already sent may still complete",
+                        limit_ms
+                    ),
+                )),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Return preference of a request (`Prefer: return=minimal` or
+/// `return=representation`), if given
+fn return_preference(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|p| p.trim().strip_prefix("return="))
+        .next_back()
+}
+
+/// Send successful write commands as 204 No Content when the client prefers
+/// `return=minimal` or `minimal_responses` is configured; a client can opt
+/// back into the body with `Prefer: return=representation`
+pub async fn minimal_response(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let preference = return_preference(request.headers()).map(str::to_string);
+    let query = request.method() == Method::GET || request.method() == Method::HEAD;
+    let response = next.run(request).await;
+    if query || response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let minimal = match preference.as_deref() {
+        Some("minimal") => true,
+        Some("representation") => false,
+        _ => state.config().minimal_responses,
+    };
+    if !minimal {
+        return response;
+    }
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    if preference.is_some() {
+        response.headers_mut().insert(
+            "preference-applied",
+            HeaderValue::from_static("return=minimal"),
+        );
+    }
+    response
+}
+
+/// Sign JSON responses to GET requests when `response_signing_key` is
+/// set, unless the client asks for an unsigned response
+///
+/// The body is replaced by its canonical form, which the signature covers;
+/// see [`Signer`].
+pub async fn sign_response(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let signer = match state.signer(request.headers()) {
+        Some(signer) if request.method() == Method::GET => signer,
+        _ => return next.run(request).await,
+    };
+    let target = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let response = next.run(request).await;
+    let json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|t| t.as_bytes().starts_with(b"application/json"));
+    if !json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Failed to read response for signing: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let value: serde_json::Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    let body = signing::canonical(&value);
+    let signature = signer.sign("GET", &target, &body);
+    if let Ok(value) = HeaderValue::from_str(&signature.value) {
+        parts.headers.insert(signing::SIGNATURE_HEADER, value);
+    }
+    parts
+        .headers
+        .insert(signing::TIMESTAMP_HEADER, signature.timestamp_ms.into());
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Health check endpoint
+pub async fn health_check(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
+    Json(health(&state))
+}
+
+fn health(state: &AppState) -> HealthResponse {
+    let serial = state.get_serial();
+    let simulated = matches!(&serial, Some(serial) if serial.is_simulated());
+    let mode = serial.as_ref().and_then(|serial| serial.mode());
+    let idle_stopped = serial.as_ref().is_some_and(|serial| serial.idle_stopped());
+    let config = state.config();
+    let low_voltage = match (*state.supply_mv.lock().unwrap(), config.low_voltage_mv) {
+        (Some(millivolts), Some(threshold)) => millivolts < threshold,
+        _ => false,
+    };
+    // Report a simulated arm distinctly so monitoring doesn't take it for
+    // real hardware
+    let faults = protocol::fault_names(state.faults.lock().unwrap().unwrap_or_default());
+    let positions_unknown = state.positions_unknown.load(Ordering::Relaxed);
+    let restored = (0..NUM_SERVOS)
+        .filter(|&channel| state.restored.lock().unwrap()[channel as usize])
+        .collect();
+    let (overall_status, serial_status) = match serial {
+        Some(_) if simulated => (config.simulated_health.clone(), "simulated"),
+        // Reachable, but not taking commands
+        Some(serial) if serial.mode_refused() => ("degraded".to_string(), "refused"),
+        Some(_) if !faults.is_empty() => ("degraded".to_string(), "connected"),
+        Some(_) if positions_unknown => ("degraded".to_string(), "connected"),
+        Some(_) => ("ok".to_string(), "connected"),
+        None => ("degraded".to_string(), "not_connected"),
+    };
+    let persistence_failing: Vec<String> = state
+        .persister
+        .failing()
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    // Changes are being lost if the process stops
+    let overall_status = if persistence_failing.is_empty() || overall_status != "ok" {
+        overall_status
+    } else {
+        "degraded".to_string()
+    };
+
+    HealthResponse {
+        status: overall_status,
+        serial: serial_status.to_string(),
+        simulated,
+        mode,
+        idle_stopped,
+        low_voltage,
+        faults,
+        position_poll: config.position_poll.enabled.then(|| state.poller.status()),
+        profile: config.profile.clone(),
+        positions_unknown,
+        restored,
+        persistence_failing,
+    }
+}
+
+/// Enter serial mode
+pub async fn start_serial_mode(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let serial = state.require_serial()?;
+
+    match serial.start_serial_mode() {
+        Ok(_) => Ok(Json(SuccessResponse {
+            status: "serial_mode".to_string(),
+        })),
+        Err(e) => {
+            error!(error = %e, "Failed to start serial mode");
+            Err(handle_serial_error(&state, &e))
+        }
+    }
+}
+
+/// Exit serial mode
+pub async fn stop_serial_mode(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let serial = state.require_serial()?;
+
+    match serial.stop_serial_mode() {
+        Ok(_) => Ok(Json(SuccessResponse {
+            status: "button_mode".to_string(),
+        })),
+        Err(e) => {
+            error!(error = %e, "Failed to stop serial mode");
+            Err(handle_serial_error(&state, &e))
+        }
+    }
+}
+
+/// Ranges and units of every command parameter, with the per-servo limits
+/// currently in effect
+pub async fn get_schema(State(state): State<Arc<AppState>>) -> Json<SchemaResponse> {
+    Json(SchemaResponse {
+        parameters: schema::describe(&state.config()),
+    })
+}
+
+/// The firmware command set as data, for generating client SDKs
+pub async fn get_protocol(State(state): State<Arc<AppState>>) -> Json<ProtocolResponse> {
+    let config = state.config();
+    let protocol = &config.protocol;
+    Json(ProtocolResponse {
+        prefix: protocol.command_prefix.clone(),
+        suffix: protocol.command_suffix.clone(),
+        channels: NUM_SERVOS,
+        max_angle: protocol.max_angle,
+        commands: protocol::COMMANDS
+            .iter()
+            .map(|spec| CommandInfo {
+                spec,
+                enabled: spec.enabled(protocol),
+            })
+            .collect(),
+    })
+}
+
+/// Bytes from the firmware no expected response explained, on the
+/// current connection
+pub async fn get_protocol_violations(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ProtocolViolations>, ApiError> {
+    let serial = state.require_serial()?;
+    let (counts, recent) = serial.violations().snapshot();
+    Ok(Json(ProtocolViolations {
+        strict: state.config().protocol.strict,
+        counts,
+        recent,
+    }))
+}
+
+/// What was found and done after the last link loss during a MOVE
+pub async fn get_link_loss(State(state): State<Arc<AppState>>) -> Json<LinkLossResponse> {
+    Json(LinkLossResponse {
+        latched: state.link_loss.latched(),
+        last: state.link_loss.last(),
+    })
+}
+
+/// Lifecycle transitions of the serial connection since startup
+pub async fn get_connection_history(
+    State(state): State<Arc<AppState>>,
+) -> Json<ConnectionHistory> {
+    let (current, transitions) = state.observers.connection.snapshot();
+    Json(ConnectionHistory {
+        state: current,
+        transitions,
+    })
+}
+
+/// Acknowledge a link loss, lifting the `latch` policy's motion latch
+pub async fn ack_link_loss(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Json<SuccessResponse> {
+    if state.link_loss.acknowledge() {
+        info!("Link loss acknowledged by {}, motion allowed", actor(&headers));
+    }
+    Json(SuccessResponse {
+        status: "ok".to_string(),
+    })
+}
+
+/// Every route of the API with the credentials it requires
+pub async fn get_routes(State(state): State<Arc<AppState>>) -> Json<RoutesResponse> {
+    Json(RoutesResponse {
+        routes: state.routes.get().cloned().unwrap_or_default(),
+    })
+}
+
+/// Report the features enabled on this instance
+pub async fn get_capabilities(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Json<Capabilities> {
+    Json(Capabilities {
+        caller: caller(&state, &headers),
+        ..capabilities(&state)
+    })
+}
+
+fn capabilities(state: &AppState) -> Capabilities {
+    let config = state.config();
+    let firmware = state.get_serial().map(|serial| FirmwareInfo {
+        channels: NUM_SERVOS,
+        max_angle: serial.max_angle(),
+        baud_rate: serial.baud_rate(),
+        simulated: serial.is_simulated(),
+        commands: protocol::COMMANDS
+            .iter()
+            .filter(|spec| spec.enabled(&config.protocol))
+            .map(|spec| spec.keyword.to_string())
+            .collect(),
+    });
+
+    Capabilities {
+        simulation: config.simulate,
+        demo: config.demo.enabled,
+        admin: tokens::admin_configured(&config),
+        config_reload: state.config_path.is_some(),
+        persistent_library: config.library_file.is_some(),
+        persistent_audit: config.audit_file.is_some(),
+        baud_autodetect: !config.serial.baud_autodetect.is_empty(),
+        role: state.replication.role(),
+        replication: config.replication.peer_url.is_some(),
+        named_channels: config
+            .servos
+            .iter()
+            .filter(|s| s.kind.is_servo())
+            .filter_map(|s| s.name.clone())
+            .collect(),
+        features: features::enabled(&config),
+        firmware,
+        caller: None,
+    }
+}
+
+/// Set servo angle
+pub async fn set_servo_angle(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<SetAngleRequest>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let channel = servo_channel(id)?;
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    check_unlocked(&state, id)?;
+    let mut targets = [None; NUM_SERVOS as usize];
+    targets[id as usize] = Some(req.angle);
+    check_constraints(&state, &*serial, &targets)?;
+    let angle = to_servo_angle(&state.config(), id, req.angle, known_correction(&state, id))?;
+
+    match serial.set_servo_angle(channel, angle, query.options()) {
+        Ok(_) => {
+            state.record_command(id, req.angle);
+            Ok(Json(SuccessResponse {
+                status: "ok".to_string(),
+            }))
+        }
+        Err(e) => {
+            error!(channel = id, error = %e, "Failed to set servo angle");
+            Err(handle_serial_error(&state, &e))
+        }
+    }
+}
+
+/// Set several servo angles, one command per channel in channel order
+///
+/// A channel that can't be set doesn't stop the others; each one's
+/// outcome is listed, with 207 Multi-Status unless every channel
+/// succeeded. Once the port itself fails, the remaining channels are
+/// skipped rather than each waiting for the same failure.
+pub async fn set_servos(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<BatchAngleRequest>,
+) -> Result<Response, ApiError> {
+    let config = state.config();
+    let mut targets = BTreeMap::new();
+    for (key, &angle) in &req.angles {
+        let channel = channel_key(&config, key)?;
+        if targets.insert(channel, angle).is_some() {
+            return Err(bad_request(format!("Servo {} specified more than once", channel)));
+        }
+    }
+    if targets.is_empty() {
+        return Err(bad_request("No angles given".to_string()));
+    }
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    let mut failure: Option<ErrorResponse> = None;
+    let mut results = Vec::with_capacity(targets.len());
+    for (channel, angle) in targets {
+        let (status, error) = if let Some(failure) = &failure {
+            (ChannelStatus::Skipped, Some(failure.clone()))
+        } else {
+            match set_batch_angle(&state, &*serial, &config, channel, angle, query.options()) {
+                Ok(()) => (ChannelStatus::Ok, None),
+                Err((io_failure, (_, Json(error)))) => {
+                    if io_failure {
+                        failure = Some(error.clone());
+                    }
+                    (ChannelStatus::Failed, Some(error))
+                }
+            }
+        };
+        results.push(ChannelResult {
+            channel,
+            name: config.servo(channel).name,
+            angle,
+            status,
+            error,
+        });
+    }
+
+    let succeeded = results.iter().filter(|r| r.status == ChannelStatus::Ok).count();
+    let result = BatchResult {
+        succeeded,
+        failed: results.len() - succeeded,
+        results,
+    };
+    let status = if result.failed == 0 {
+        StatusCode::OK
+    } else {
+        warn!(failed = result.failed, succeeded, "Batch of servo angles partly failed");
+        StatusCode::MULTI_STATUS
+    };
+    Ok((status, Json(result)).into_response())
+}
+
+/// Set one channel of a batch; on failure, whether the port itself failed
+fn set_batch_angle(
+    state: &AppState,
+    serial: &dyn ServoController,
+    config: &Config,
+    channel: u8,
+    angle: u16,
+    opts: CommandOptions,
+) -> Result<(), (bool, ApiError)> {
+    check_unlocked(state, channel).map_err(|e| (false, e))?;
+    let mut targets = [None; NUM_SERVOS as usize];
+    targets[channel as usize] = Some(angle);
+    check_constraints(state, serial, &targets).map_err(|e| (false, e))?;
+    let servo_angle = to_servo_angle(config, channel, angle, known_correction(state, channel))
+        .map_err(|e| (false, e))?;
+    let target = Channel::new(channel, NUM_SERVOS).unwrap();
+    match serial.set_servo_angle(target, servo_angle, opts) {
+        Ok(_) => {
+            state.record_command(channel, angle);
+            Ok(())
+        }
+        Err(e) => {
+            error!(channel, error = %e, "Failed to set servo angle");
+            Err((serial::is_io_failure(&e), handle_serial_error(state, &e)))
+        }
+    }
+}
+
+/// Set servo PWM pulse width
+pub async fn set_servo_pwm(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<SetPwmRequest>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let channel = servo_channel(id)?;
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    let config = state.config();
+    check_enabled(&config, id)?;
+    check_unlocked(&state, id)?;
+    check(&config, Param::PulseWidth, Some(id), req.pulse_us as u32)?;
+    match serial.set_servo_pwm(channel, req.pulse_us, query.options()) {
+        Ok(_) => Ok(Json(SuccessResponse {
+            status: "ok".to_string(),
+        })),
+        Err(e) => {
+            error!(channel = id, error = %e, "Failed to set servo PWM");
+            Err(handle_serial_error(&state, &e))
+        }
+    }
+}
+
+/// Drive a PWM output channel by pulse width or duty cycle
+pub async fn set_output(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<OutputRequest>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let channel = servo_channel(id)?;
+    let config = state.config();
+    if check_enabled(&config, id)? != ChannelKind::PwmOutput {
+        return Err(unprocessable(
+            "NOT_AN_OUTPUT",
+            format!(
+                "Channel {} is a servo, use /api/servo/{}/pwm for raw pulses",
+                id, id
+            ),
+        ));
+    }
+
+    let pulse_us = match (req.pulse_us, req.percent) {
+        (Some(pulse_us), None) => pulse_us,
+        (None, Some(percent)) if (0.0..=100.0).contains(&percent) => {
+            (percent / 100.0 * schema::MAX_PULSE_US as f64).round() as u16
+        }
+        (None, Some(percent)) => {
+            return Err(unprocessable(
+                "OUT_OF_RANGE",
+                format!("Duty cycle {}% is outside 0-100", percent),
+            ));
+        }
+        _ => return Err(bad_request("Give either pulse_us or percent".to_string())),
+    };
+    check(&config, Param::PulseWidth, Some(id), pulse_us as u32)?;
+    check_unlocked(&state, id)?;
+
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+    match serial.set_servo_pwm(channel, pulse_us, query.options()) {
+        Ok(_) => Ok(Json(SuccessResponse {
+            status: "ok".to_string(),
+        })),
+        Err(e) => {
+            error!(channel = id, error = %e, "Failed to set output");
+            Err(handle_serial_error(&state, &e))
+        }
+    }
+}
+
+/// Get servo position
+pub async fn get_servo_position(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+) -> Result<Json<ServoPosition>, ApiError> {
+    let channel = servo_channel(id)?;
+    check_servo(&state.config(), id)?;
+    let serial = state.require_serial()?;
+
+    let config = state.config();
+    match serial.get_measured_angle(channel, config.servo(id).feedback.as_ref()) {
+        Ok(angle) => {
+            let angle = angle.map(|angle| {
+                let angle =
+                    from_servo_angle(&config, channel, angle, known_correction(&state, id));
+                state.record_reading(id, angle)
+            });
+            Ok(Json(ServoPosition {
+                channel,
+                angle,
+                name: config.servo(id).name,
+                kind: ChannelKind::Servo,
+                busy_until_ms: state.busy_until_ms(id),
+            }))
+        }
+        Err(e) => {
+            error!(channel = id, error = %e, "Failed to get servo position");
+            Err(handle_serial_error(&state, &e))
+        }
+    }
+}
+
+/// Whether a servo is still moving
+///
+/// Firmware with the `BUSY` query (`[protocol] busy_query`) is asked
+/// directly; otherwise, or if the query fails, the state is estimated from
+/// the durations of the motion sent to the servo.
+pub async fn get_servo_busy(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+) -> Result<Json<ServoBusy>, ApiError> {
+    let channel = servo_channel(id)?;
+    check_servo(&state.config(), id)?;
+    let serial = state.require_serial()?;
+
+    match serial.get_servo_busy(channel) {
+        Ok(Some(busy)) => {
+            return Ok(Json(ServoBusy {
+                channel,
+                busy,
+                method: BusyMethod::Firmware,
+                remaining_ms: None,
+            }));
+        }
+        Ok(None) => {}
+        Err(e) => warn!(channel = id, error = %e, "Busy query failed, estimating"),
+    }
+
+    let remaining = state.motion_remaining(id);
+    Ok(Json(ServoBusy {
+        channel,
+        busy: remaining.is_some(),
+        method: BusyMethod::Estimate,
+        remaining_ms: remaining.map(|d| d.as_millis() as u64),
+    }))
+}
+
+/// Everything known about one servo, gathered from the reads the firmware
+/// supports
+///
+/// The angle is read as `GET /api/servo/:id` reads it, updating the known
+/// position; a failed read leaves `measured` null, and after an I/O
+/// failure nothing more is asked of the firmware. Disconnected, only what
+/// the backend knows is returned.
+pub async fn get_servo_state(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+) -> Result<Json<ServoState>, ApiError> {
+    let channel = servo_channel(id)?;
+    let config = state.config();
+    check_servo(&config, id)?;
+    let servo = config.servo(id);
+
+    let mut serial = state.get_serial();
+    let connected = serial.is_some();
+    let mut measured = None;
+    if let Some(connection) = serial.as_deref() {
+        match read_position(&state, connection, channel) {
+            Ok(angle) => measured = Some(angle),
+            Err(_) if state.get_serial().is_none() => serial = None,
+            Err(_) => {}
+        }
+    }
+    let feedback_raw = serial.as_ref().and_then(|serial| {
+        serial
+            .get_servo_adc(channel)
+            .inspect_err(|e| warn!(channel = id, error = %e, "Failed to read feedback ADC"))
+            .ok()
+            .flatten()
+    });
+    let firmware_busy = serial.as_ref().and_then(|serial| {
+        serial
+            .get_servo_busy(channel)
+            .inspect_err(|e| warn!(channel = id, error = %e, "Busy query failed, estimating"))
+            .ok()
+            .flatten()
+    });
+    let (busy, busy_method) = match firmware_busy {
+        Some(busy) => (busy, BusyMethod::Firmware),
+        None => (state.motion_remaining(id).is_some(), BusyMethod::Estimate),
+    };
+    let commanded = state.commanded.lock().unwrap()[channel.index()];
+    // No firmware reports these, so only the simulated arm makes them up
+    let synthetic = serial.as_ref().is_some_and(|serial| serial.is_simulated())
+        && config.simulation.telemetry;
+    let load_ma = synthetic.then(|| simulator::servo_load_ma(&servo, busy));
+    let temperature_c =
+        load_ma.map(|load_ma| simulator::servo_temperature_c(&config.simulation, load_ma));
+
+    Ok(Json(ServoState {
+        channel,
+        name: servo.name,
+        enabled: !state.lockout.is_disabled(id),
+        connected,
+        measured,
+        commanded,
+        error: measured.zip(commanded).map(|(m, c)| m as i32 - c as i32),
+        known: state.positions.lock().unwrap()[channel.index()],
+        pulse_us: None,
+        load_ma,
+        temperature_c,
+        feedback_raw,
+        busy,
+        busy_method,
+        busy_until_ms: state.busy_until_ms(id),
+        min: servo.min,
+        max: servo.max,
+    }))
+}
+
+/// Supply voltage reported by the firmware
+///
+/// Firmware without the `VOLT` query (`[protocol] voltage_query`) is
+/// answered with `available: false` rather than an error. A reading below
+/// `low_voltage_mv` is logged and flagged in `/api/health` until the next
+/// reading.
+pub async fn get_power(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PowerResponse>, ApiError> {
+    let serial = state.require_serial()?;
+    let threshold = state.config().low_voltage_mv;
+
+    let millivolts = match serial.get_supply_voltage() {
+        Ok(Some(millivolts)) => millivolts,
+        Ok(None) => {
+            return Ok(Json(PowerResponse {
+                available: false,
+                reason: Some("The firmware has no voltage query ([protocol] voltage_query)"),
+                millivolts: None,
+                volts: None,
+                low_voltage_mv: threshold,
+                low: false,
+            }));
+        }
+        Err(e) => {
+            error!("Failed to read supply voltage: {}", e);
+            return Err(handle_serial_error(&state, &e));
+        }
+    };
+
+    *state.supply_mv.lock().unwrap() = Some(millivolts);
+    let low = threshold.is_some_and(|threshold| millivolts < threshold);
+    if low {
+        warn!(
+            "Supply voltage low: {}mV (threshold {}mV)",
+            millivolts,
+            threshold.unwrap_or_default()
+        );
+    }
+    Ok(Json(PowerResponse {
+        available: true,
+        reason: None,
+        millivolts: Some(millivolts),
+        volts: Some(millivolts as f64 / 1000.0),
+        low_voltage_mv: threshold,
+        low,
+    }))
+}
+
+const NO_ADC_QUERY: &str = "The firmware has no ADC query ([protocol] adc_query)";
+
+/// Raw counts of a servo's feedback potentiometer and the angle they map to
+///
+/// Firmware without the `ADC` query (`[protocol] adc_query`) is answered
+/// with `available: false` rather than an error; `angle` is left out until
+/// the servo has a `feedback` calibration.
+pub async fn get_servo_feedback(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+) -> Result<Json<ServoFeedback>, ApiError> {
+    let channel = servo_channel(id)?;
+    let config = state.config();
+    check_servo(&config, id)?;
+    let serial = state.require_serial()?;
+
+    let raw = match serial.get_servo_adc(channel) {
+        Ok(Some(raw)) => raw,
+        Ok(None) => {
+            return Ok(Json(ServoFeedback {
+                channel: id,
+                available: false,
+                reason: Some(NO_ADC_QUERY),
+                raw: None,
+                angle: None,
+                calibration: None,
+            }));
+        }
+        Err(e) => {
+            error!(channel = id, error = %e, "Failed to read feedback ADC");
+            return Err(handle_serial_error(&state, &e));
+        }
+    };
+    let calibration = config.servo(id).feedback;
+    let angle = calibration.map(|calibration| {
+        let max_angle = config.protocol.max_angle;
+        let angle = Angle::new(calibration.angle(raw, max_angle), max_angle)
+            .expect("mapped angles are saturated");
+        from_servo_angle(&config, channel, angle, known_correction(&state, id))
+    });
+    Ok(Json(ServoFeedback {
+        channel: id,
+        available: true,
+        reason: None,
+        raw: Some(raw),
+        angle,
+        calibration,
+    }))
+}
+
+/// Calibrate a servo's feedback ADC at two angles
+///
+/// The servo is moved to each angle and its counts read once it settled;
+/// the calibration through the two points applies until the next restart
+/// or config reload, and is returned to be put in the config file.
+pub async fn calibrate_feedback(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    headers: HeaderMap,
+    Json(req): Json<FeedbackCalibrateRequest>,
+) -> Result<Json<FeedbackCalibrateResponse>, ApiError> {
+    let channel = servo_channel(id)?;
+    check_servo(&state.config(), id)?;
+    let serial = state.require_serial()?;
+    if !state.config().protocol.adc_query {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code("NO_ADC_QUERY", NO_ADC_QUERY)),
+        ));
+    }
+    let servo = state.config().servo(id);
+    let angles = [req.low.unwrap_or(servo.min), req.high.unwrap_or(servo.max)];
+    if angles[0] == angles[1] {
+        return Err(bad_request("low and high must differ".to_string()));
+    }
+    let _motion = state.begin_motion()?;
+    check_unlocked(&state, id)?;
+
+    let mut points = Vec::new();
+    let mut measured = Vec::new();
+    for angle in angles {
+        let servo_angle =
+            to_servo_angle(&state.config(), id, angle, known_correction(&state, id))?;
+        if let Err(e) = serial.set_servo_angle(channel, servo_angle, CommandOptions::default()) {
+            error!(channel = id, error = %e, "Failed to move servo for calibration");
+            return Err(handle_serial_error(&state, &e));
+        }
+        state.record_command(id, angle);
+        state.clock.sleep(Duration::from_millis(req.settle_ms)).await;
+        let raw = match serial.get_servo_adc(channel) {
+            Ok(raw) => raw.unwrap_or_default(),
+            Err(e) => {
+                error!(channel = id, error = %e, "Failed to read feedback ADC");
+                return Err(handle_serial_error(&state, &e));
+            }
+        };
+        points.push(FeedbackPoint { angle, raw });
+        measured.push((raw, servo_angle.get()));
+    }
+    let Some(calibration) = FeedbackCalibration::from_points(measured[0], measured[1]) else {
+        return Err(unprocessable(
+            "CALIBRATION_FAILED",
+            format!("The ADC read {} counts at both angles", measured[0].0),
+        ));
+    };
+
+    let current = state.base_config();
+    let mut updated = (*current).clone();
+    if !updated.servos.iter().any(|servo| servo.channel == id) {
+        updated.servos.push(ServoConfig {
+            channel: id,
+            ..ServoConfig::default()
+        });
+    }
+    let servo = updated
+        .servos
+        .iter_mut()
+        .find(|servo| servo.channel == id)
+        .expect("just added");
+    servo.feedback = Some(calibration);
+    if let Err(e) = updated.validate() {
+        return Err(bad_request(format!("{:#}", e)));
+    }
+    let before = serde_json::to_value(&*current).unwrap_or_default();
+    let after = serde_json::to_value(&updated).unwrap_or_default();
+    *state.config.lock().unwrap() = Arc::new(updated);
+    let target = id.to_string();
+    let endpoint = "POST /api/servo/:id/feedback/calibrate";
+    state.audit(&headers, endpoint, "config", Some(&target), &before, &after);
+    info!(
+        "Calibrated feedback of channel {}: {} counts at {}°, {} at {}°",
+        id, calibration.raw_a, calibration.angle_a, calibration.raw_b, calibration.angle_b
+    );
+
+    Ok(Json(FeedbackCalibrateResponse {
+        channel: id,
+        points,
+        calibration,
+    }))
+}
+
+const NO_FAULT_REGISTER: &str = "The firmware has no fault register ([protocol] fault_register)";
+
+/// Faults latched in the firmware's fault register
+///
+/// Firmware without the register (`[protocol] fault_register`) is
+/// answered with `available: false` rather than an error. The faults read
+/// are reported by `/api/health` until the next read or clear.
+pub async fn get_faults(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<FaultsResponse>, ApiError> {
+    let serial = state.require_serial()?;
+
+    let register = match serial.get_faults() {
+        Ok(Some(register)) => register,
+        Ok(None) => {
+            return Ok(Json(FaultsResponse {
+                available: false,
+                reason: Some(NO_FAULT_REGISTER),
+                register: None,
+                faults: Vec::new(),
+            }));
+        }
+        Err(e) => {
+            error!("Failed to read fault register: {}", e);
+            return Err(handle_serial_error(&state, &e));
+        }
+    };
+
+    *state.faults.lock().unwrap() = Some(register);
+    let faults = protocol::fault_names(register);
+    if !faults.is_empty() {
+        warn!("Firmware faults latched: {}", faults.join(", "));
+    }
+    Ok(Json(FaultsResponse {
+        available: true,
+        reason: None,
+        register: Some(register),
+        faults,
+    }))
+}
+
+/// Clear the firmware's fault register
+///
+/// Refused with 409 `NO_FAULT_REGISTER` for firmware without one.
+pub async fn clear_faults(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<FaultsResponse>, ApiError> {
+    let serial = state.require_serial()?;
+
+    match serial.clear_faults() {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(ErrorResponse::with_code("NO_FAULT_REGISTER", NO_FAULT_REGISTER)),
+            ));
+        }
+        Err(e) => {
+            error!("Failed to clear fault register: {}", e);
+            return Err(handle_serial_error(&state, &e));
+        }
+    }
+
+    let previous = state.faults.lock().unwrap().replace(0);
+    info!(
+        "Firmware faults cleared by {} (were: {})",
+        actor(&headers),
+        match previous {
+            Some(register) => protocol::fault_names(register).join(", "),
+            None => "not read".to_string(),
+        }
+    );
+    Ok(Json(FaultsResponse {
+        available: true,
+        reason: None,
+        register: Some(0),
+        faults: Vec::new(),
+    }))
+}
+
+const NO_SPEED_LIMIT: &str = "The firmware has no speed limit ([protocol] speed_limit)";
+
+fn speed_limit_response(state: &AppState, firmware: Option<u16>) -> SpeedLimitResponse {
+    *state.firmware_speed_limit.lock().unwrap() = firmware;
+    let config = state.config();
+    let servos = (0..NUM_SERVOS)
+        .map(|channel| ChannelSpeedLimit {
+            channel,
+            name: config.servo(channel).name,
+            software_dps: config.max_velocity(channel),
+            effective_dps: state.max_velocity(channel),
+        })
+        .collect();
+    SpeedLimitResponse {
+        available: firmware.is_some(),
+        reason: firmware.is_none().then_some(NO_SPEED_LIMIT),
+        firmware_dps: firmware.filter(|&limit| limit > 0),
+        servos,
+    }
+}
+
+/// The firmware's slew-rate limit next to the backend's velocity limits
+///
+/// Firmware without one (`[protocol] speed_limit`) is answered with
+/// `available: false` rather than an error.
+pub async fn get_speed_limit(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SpeedLimitResponse>, ApiError> {
+    let serial = state.require_serial()?;
+
+    match serial.get_speed_limit() {
+        Ok(firmware) => Ok(Json(speed_limit_response(&state, firmware))),
+        Err(e) => {
+            error!("Failed to read speed limit: {}", e);
+            Err(handle_serial_error(&state, &e))
+        }
+    }
+}
+
+/// Set the firmware's slew-rate limit (admin only)
+///
+/// Refused with 409 `NO_SPEED_LIMIT` for firmware without one.
+pub async fn set_speed_limit(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<SpeedLimitRequest>,
+) -> Result<Json<SpeedLimitResponse>, ApiError> {
+    require_admin(&state, &headers)?;
+    let serial = state.require_serial()?;
+
+    match serial.set_speed_limit(req.limit_dps) {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(ErrorResponse::with_code("NO_SPEED_LIMIT", NO_SPEED_LIMIT)),
+            ));
+        }
+        Err(e) => {
+            error!("Failed to set speed limit: {}", e);
+            return Err(handle_serial_error(&state, &e));
+        }
+    }
+
+    let previous = *state.firmware_speed_limit.lock().unwrap();
+    let before = serde_json::json!({ "speed_limit_dps": previous });
+    let after = serde_json::json!({ "speed_limit_dps": req.limit_dps });
+    let endpoint = "POST /api/firmware/speed_limit";
+    state.audit(&headers, endpoint, "firmware", None, &before, &after);
+    info!(
+        "Firmware speed limit set to {} degrees/s by {}",
+        req.limit_dps,
+        actor(&headers)
+    );
+    Ok(Json(speed_limit_response(&state, Some(req.limit_dps))))
+}
+
+/// Commands waiting for the serial port, the one being sent first
+pub async fn get_queue(State(state): State<Arc<AppState>>) -> Json<QueueList> {
+    let serial = state.serial.lock().unwrap().clone();
+    Json(QueueList {
+        connected: serial.is_some(),
+        entries: serial.map(|serial| serial.queue().list()).unwrap_or_default(),
+    })
+}
+
+/// Take a pending command off the queue (admin only); its caller gets 409
+/// `CANCELLED_BY_OPERATOR`
+pub async fn cancel_queue_entry(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<Json<QueueEntry>, ApiError> {
+    require_admin(&state, &headers)?;
+    let unknown = || not_found(format!("Unknown queue entry {}", id));
+    let serial = state.serial.lock().unwrap().clone().ok_or_else(unknown)?;
+    match serial.queue().cancel(id) {
+        Ok(entry) => {
+            warn!(
+                "Queued {} {} of {} cancelled by {}",
+                entry.command,
+                id,
+                entry.requester,
+                actor(&headers)
+            );
+            Ok(Json(entry))
+        }
+        Err(command_queue::CancelError::NotFound) => Err(unknown()),
+        Err(command_queue::CancelError::InFlight(entry)) => Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse {
+                details: Some(serde_json::json!({ "entry": entry })),
+                ..ErrorResponse::with_code(
+                    "IN_FLIGHT",
+                    format!("Queue entry {} was already sent to the serial port", id),
+                )
+            }),
+        )),
+    }
+}
+
+/// Cancel every pending command that doesn't move the arm or switch the
+/// mode (admin only)
+pub async fn flush_queue(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<QueueFlush>, ApiError> {
+    require_admin(&state, &headers)?;
+    let serial = state.serial.lock().unwrap().clone();
+    let cancelled = serial
+        .as_ref()
+        .map(|serial| serial.queue().flush())
+        .unwrap_or_default();
+    let kept = serial.map_or(0, |serial| serial.queue().list().len());
+    if !cancelled.is_empty() {
+        warn!(
+            "{} queued commands flushed by {}",
+            cancelled.len(),
+            actor(&headers)
+        );
+    }
+    Ok(Json(QueueFlush { cancelled, kept }))
+}
+
+/// Reconstruct the arm's state at a past time from the angle history and
+/// the script jobs still kept
+///
+/// Channels without data at that time, e.g. before the history starts or
+/// after the positions were lost, are reported with `known: false`.
+pub async fn get_state_at(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StateAtQuery>,
+) -> Result<Json<StateAt>, ApiError> {
+    if query.t > audit::now_ms() {
+        return Err(bad_request("t is in the future".to_string()));
+    }
+    let entry = state.history.at(query.t);
+    let (angles, positions_lost) = match entry {
+        Some(history::Entry::Sample { angles, .. }) => (angles, false),
+        Some(history::Entry::Gap { .. }) => ([None; NUM_SERVOS as usize], true),
+        None => ([None; NUM_SERVOS as usize], false),
+    };
+    let channels = Channel::all(NUM_SERVOS)
+        .zip(angles)
+        .map(|(channel, angle)| ChannelStateAt {
+            channel,
+            angle,
+            known: angle.is_some(),
+        })
+        .collect();
+    let job = state.scripts.running_at(query.t).map(|(record, step)| JobAt {
+        id: record.id,
+        started_ms: record.started_ms,
+        line: step.as_ref().map(|step| step.line),
+        source: step.map(|step| step.source),
+    });
+
+    Ok(Json(StateAt {
+        t: query.t,
+        history_from_ms: state.history.first_ms(),
+        positions_lost,
+        since_ms: entry.map(|entry| entry.at_ms()),
+        channels,
+        job,
+    }))
+}
+
+/// Joint trajectory resampled from the angle history, as JSON or CSV
+///
+/// Each servo channel is a joint, named as in the config (`channel_<n>`
+/// otherwise). Positions are in radians from the channel's center (its
+/// home angle, or the middle of its limits), negated for a `reversed`
+/// servo.
+pub async fn export_joint_states(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<JointStatesQuery>,
+) -> Result<Response, ApiError> {
+    let config = state.config();
+    check(&config, Param::SampleRate, None, query.rate_hz as u32)?;
+    let now = audit::now_ms();
+    let to = query.to.unwrap_or(now).min(now);
+    let from = match query.from.or_else(|| state.history.first_ms()) {
+        Some(from) => from,
+        None => return Err(not_found("No angle history yet".to_string())),
+    };
+    if from > to {
+        return Err(bad_request("from is after to".to_string()));
+    }
+    let step_ms = 1000 / query.rate_hz as u64;
+    if (to - from) / step_ms >= MAX_EXPORT_POINTS {
+        return Err(bad_request(format!(
+            "Export is longer than {} points; narrow from/to or lower rate_hz",
+            MAX_EXPORT_POINTS
+        )));
+    }
+
+    let channels: Vec<u8> = (0..NUM_SERVOS)
+        .filter(|&channel| config.kind(channel).is_servo())
+        .collect();
+    let name: Vec<String> = channels
+        .iter()
+        .map(|&channel| {
+            config
+                .servo(channel)
+                .name
+                .unwrap_or_else(|| format!("channel_{}", channel))
+        })
+        .collect();
+    let grid = history::resample(&state.history.window(from, to), from, to, step_ms);
+    let position: Vec<Vec<Option<f64>>> = grid
+        .iter()
+        .map(|angles| {
+            channels
+                .iter()
+                .map(|&channel| {
+                    let servo = config.servo(channel);
+                    let sign = if servo.reversed { -1.0 } else { 1.0 };
+                    let center = config.center(channel) as f64;
+                    angles[channel as usize].map(|angle| sign * (angle - center).to_radians())
+                })
+                .collect()
+        })
+        .collect();
+    let timestamps: Vec<f64> = (0..grid.len() as u64)
+        .map(|i| (from + i * step_ms) as f64 / 1000.0)
+        .collect();
+
+    if query.format == ExportFormat::Csv {
+        let mut csv = format!("time,{}\n", name.join(","));
+        for (time, row) in timestamps.iter().zip(&position) {
+            csv.push_str(&time.to_string());
+            for value in row {
+                csv.push(',');
+                if let Some(value) = value {
+                    csv.push_str(&value.to_string());
+                }
+            }
+            csv.push('\n');
+        }
+        return Ok(([(header::CONTENT_TYPE, "text/csv")], csv).into_response());
+    }
+    Ok(Json(JointStates {
+        header: JointStatesHeader {
+            stamp: RosTime {
+                secs: from / 1000,
+                nsecs: (from % 1000) as u32 * 1_000_000,
+            },
+            frame_id: String::new(),
+            rate_hz: query.rate_hz,
+        },
+        name,
+        position,
+        timestamps,
+    })
+    .into_response())
+}
+
+/// When the arm and each servo are estimated to be free again
+///
+/// Estimates cover single MOVEs, tracked moves, sequences and trajectory
+/// playback; only busy servos are listed.
+pub async fn get_busy(State(state): State<Arc<AppState>>) -> Json<BusyResponse> {
+    let channels: Vec<ChannelBusy> = (0..NUM_SERVOS)
+        .filter_map(|channel| {
+            state.busy_until_ms(channel).map(|busy_until_ms| ChannelBusy {
+                channel,
+                busy_until_ms,
+            })
+        })
+        .collect();
+    let busy_until_ms = channels.iter().map(|c| c.busy_until_ms).max();
+    Json(BusyResponse {
+        busy: busy_until_ms.is_some(),
+        busy_until_ms,
+        channels,
+    })
+}
+
+/// Get all servo positions; other channels are listed with `?all=true`
+pub async fn get_all_servos(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ServosQuery>,
+) -> Result<Json<ServoPositions>, ApiError> {
+    let serial = state.require_serial()?;
+
+    let (positions, unavailable, failure) = read_servos(&state, &*serial);
+    let error = match failure {
+        Some(e) if positions.iter().all(Option::is_none) && unavailable.is_empty() => {
+            return Err(e)
+        }
+        failure => failure.map(|(_, Json(e))| e.error),
+    };
+    let config = state.config();
+    let servos = Channel::all(NUM_SERVOS)
+        .zip(positions)
+        .filter_map(|(channel, angle)| {
+            let servo = config.servo(channel.get());
+            if !servo.kind.is_servo() && !query.all {
+                return None;
+            }
+            if angle.is_none() && !unavailable.contains(&channel) {
+                return None;
+            }
+            Some(ServoPosition {
+                channel,
+                angle,
+                name: servo.name,
+                kind: servo.kind,
+                busy_until_ms: state.busy_until_ms(channel.get()),
+            })
+        })
+        .collect();
+
+    Ok(Json(ServoPositions {
+        servos,
+        incomplete: error.is_some(),
+        error,
+    }))
+}
+
+/// Compare the commanded angles with a fresh read of every servo
+///
+/// Channels off by more than `tracking_error_threshold` are flagged; a
+/// jammed, overloaded or miscalibrated servo shows up here.
+pub async fn get_tracking_error(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<TrackingErrors>, ApiError> {
+    let serial = state.require_serial()?;
+
+    let commanded = *state.commanded.lock().unwrap();
+    let measured = read_all_positions(&state, &*serial)?;
+    let config = state.config();
+    let threshold = config.tracking_error_threshold;
+    let channels: Vec<TrackingError> = (0..NUM_SERVOS)
+        .filter(|&channel| config.kind(channel).is_servo())
+        .filter_map(|channel| {
+            let measured = measured[channel as usize]?;
+            let commanded = commanded[channel as usize];
+            let error = commanded.map(|commanded| measured as i32 - commanded as i32);
+            Some(TrackingError {
+                channel,
+                name: config.servo(channel).name,
+                commanded,
+                measured,
+                error,
+                exceeded: matches!(error, Some(e) if e.unsigned_abs() > threshold as u32),
+            })
+        })
+        .collect();
+
+    let exceeded: Vec<u8> = channels.iter().filter(|c| c.exceeded).map(|c| c.channel).collect();
+    if !exceeded.is_empty() {
+        warn!("Tracking error above {} degrees on servos {:?}", threshold, exceeded);
+    }
+    Ok(Json(TrackingErrors {
+        threshold,
+        exceeded,
+        channels,
+    }))
+}
+
+/// How often each channel has been commanded
+pub async fn get_servo_stats(State(state): State<Arc<AppState>>) -> Json<ServoStats> {
+    let config = state.config();
+    let servos = state
+        .command_stats
+        .snapshot(state.clock.now())
+        .into_iter()
+        .map(|stats| ChannelCommandStats {
+            channel: stats.channel,
+            name: config.servo(stats.channel).name,
+            count: stats.count,
+            rate: stats.rate,
+            last_angle: stats.last.map(|(angle, _)| angle),
+            last_ms: stats.last.map(|(_, at_ms)| at_ms),
+        })
+        .collect();
+    Json(ServoStats {
+        window_ms: stats::RATE_WINDOW.as_millis() as u64,
+        servos,
+    })
+}
+
+/// Which parts of each servo's range are used, and how far and long it
+/// moved
+pub async fn get_wear(State(state): State<Arc<AppState>>) -> Json<WearResponse> {
+    let config = state.config();
+    let snapshot = state.wear.snapshot(state.clock.now_ms());
+    let servos = snapshot
+        .channels
+        .into_iter()
+        .map(|(channel, wear)| ChannelWearInfo {
+            channel,
+            name: config.servo(channel).name,
+            commands: wear.buckets.iter().sum(),
+            travel_deg: wear.travel_deg,
+            moving_ms: wear.moving_ms,
+            duty: if snapshot.tracked_ms == 0 {
+                0.0
+            } else {
+                (wear.moving_ms as f64 / snapshot.tracked_ms as f64).min(1.0)
+            },
+            histogram: wear.buckets,
+        })
+        .collect();
+    Json(WearResponse {
+        since_ms: snapshot.since_ms,
+        tracked_ms: snapshot.tracked_ms,
+        bucket_degrees: wear::BUCKET_DEGREES,
+        servos,
+    })
+}
+
+/// Use of the API by the requesting client, as named by `X-Actor`
+pub async fn get_usage(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Json<UsageReport> {
+    Json(state.usage.report(&actor(&headers), state.clock.now_ms()))
+}
+
+/// Use of the API by every client seen since startup (admin only)
+pub async fn get_usage_all(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<UsageReport>>, ApiError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.usage.all(state.clock.now_ms())))
+}
+
+/// Read every servo from the firmware, updating the position cache
+fn read_all_positions(
+    state: &AppState,
+    serial: &dyn ServoController,
+) -> Result<[Option<u16>; NUM_SERVOS as usize], ApiError> {
+    match read_available_positions(state, serial) {
+        (positions, None) => Ok(positions),
+        (_, Some(e)) => Err(e),
+    }
+}
+
+/// Read every servo the firmware answers for, updating the position cache
+///
+/// The reads stop at the first I/O failure, which drops the connection;
+/// the angles read until then are returned along with the error.
+fn read_available_positions(
+    state: &AppState,
+    serial: &dyn ServoController,
+) -> ([Option<u16>; NUM_SERVOS as usize], Option<ApiError>) {
+    let (positions, _, failure) = read_servos(state, serial);
+    (positions, failure)
+}
+
+/// [`read_available_positions`], with the servos the firmware answered
+/// with an angle sentinel for
+fn read_servos(
+    state: &AppState,
+    serial: &dyn ServoController,
+) -> ([Option<u16>; NUM_SERVOS as usize], Vec<Channel>, Option<ApiError>) {
+    let config = state.config();
+    let readings = serial.get_all_servos(&config);
+    // The corrections depend on the other channels, taken as read without
+    // their corrections where the known positions are lost
+    let mut context = state.confirmed_positions();
+    let mut unavailable = Vec::new();
+    let mut read = Vec::new();
+    for (channel, angle) in readings.servos {
+        match angle {
+            Some(angle) => read.push((channel, angle)),
+            None => unavailable.push(channel),
+        }
+    }
+    for &(channel, angle) in &read {
+        context[channel.index()]
+            .get_or_insert_with(|| from_servo_angle(&config, channel, angle, 0.0));
+    }
+    let mut positions = [None; NUM_SERVOS as usize];
+    for (channel, angle) in read {
+        let correction = state.compensation.correction(channel.get(), &context);
+        let angle = from_servo_angle(&config, channel, angle, correction);
+        let angle = state.record_reading(channel.get(), angle);
+        positions[channel.index()] = Some(angle);
+    }
+    let failure = readings.failure.map(|e| {
+        error!(error = %e, "Failed to get all servos");
+        handle_serial_error(state, &e)
+    });
+    let servos = (0..NUM_SERVOS).filter(|&channel| config.kind(channel).is_servo());
+    if failure.is_none() && servos.clone().all(|channel| positions[channel as usize].is_some()) {
+        state.positions_unknown.store(false, Ordering::Relaxed);
+    }
+    (positions, unavailable, failure)
+}
+
+/// Read the positions for the background poller; whether any changed, or
+/// `None` if skipped because the arm isn't connected, isn't in serial mode
+/// or is moving
+pub fn poll_positions(state: &AppState) -> Option<bool> {
+    let serial = state.get_serial()?;
+    let moving = (0..NUM_SERVOS).any(|channel| state.motion_remaining(channel).is_some());
+    if serial.mode() != Some(SerialMode::Serial) || moving {
+        return None;
+    }
+    let before = *state.positions.lock().unwrap();
+    let (after, _) = read_available_positions(state, &*serial);
+    Some(
+        after
+            .iter()
+            .zip(&before)
+            .any(|(after, before)| after.is_some() && after != before),
+    )
+}
+
+/// Move to the home pose after a connection was established, as far as
+/// `home_on_connect` asks for it on this connection
+pub fn home_on_connect(state: &AppState, serial: &dyn ServoController) {
+    let first = !state.has_connected.swap(true, Ordering::SeqCst);
+    let config = state.config();
+    let wanted = match config.home_on_connect {
+        HomeOnConnect::Never => false,
+        HomeOnConnect::First => first,
+        HomeOnConnect::Always => true,
+    };
+    let home = config.home_pose();
+    if !wanted || home.is_empty() {
+        return;
+    }
+    let _motion = match state.begin_motion() {
+        Ok(motion) => motion,
+        Err((_, e)) => {
+            info!("Not homing on connect: {}", e.error);
+            return;
+        }
+    };
+
+    info!("Homing on connect");
+    // A freshly connected board starts in button mode
+    if let Err(e) = serial.start_serial_mode() {
+        warn!("Could not enter serial mode to home: {}", e);
+        return;
+    }
+    // Locked-out channels stay where they are, even in strict mode
+    let result = hold_locked(state, serial, &home, false)
+        .and_then(|(home, _)| run_pose(state, serial, &home, CommandOptions::default()));
+    if let Err((_, e)) = result {
+        warn!("Homing on connect failed: {}", e.error);
+    }
+}
+
+/// Read the positions the cache doesn't know yet after a connection was
+/// established, before it is used
+///
+/// Entering serial mode first, as the firmware only answers reads there,
+/// the read is tried [`SEED_ATTEMPTS`] times. If it keeps failing, the
+/// positions are left unknown and `/api/health` reports it, so commands
+/// needing them answer `POSITIONS_UNKNOWN` until they can be read.
+pub fn seed_positions(state: &AppState, serial: &dyn ServoController) {
+    let config = state.config();
+    let servos: Vec<u8> = (0..NUM_SERVOS).filter(|&c| config.kind(c).is_servo()).collect();
+    let confirmed = state.confirmed_positions();
+    if servos.iter().all(|&channel| confirmed[channel as usize].is_some()) {
+        return;
+    }
+    for attempt in 1..=SEED_ATTEMPTS {
+        let error = match serial.start_serial_mode() {
+            Ok(()) => match read_available_positions(state, serial) {
+                (_, Some((_, Json(e)))) => e.error,
+                (positions, None) => {
+                    let missing: Vec<u8> = servos
+                        .iter()
+                        .copied()
+                        .filter(|&channel| positions[channel as usize].is_none())
+                        .collect();
+                    if missing.is_empty() {
+                        info!("Read the positions after connecting");
+                        return;
+                    }
+                    format!("No reading of servos {:?}", missing)
+                }
+            },
+            Err(e) => format!("Could not enter serial mode: {}", e),
+        };
+        warn!(attempt, error = %error, "Failed to read the positions after connecting");
+        if attempt < SEED_ATTEMPTS {
+            std::thread::sleep(SEED_RETRY_DELAY);
+        }
+    }
+    warn!("Positions unknown; commands needing them fail with POSITIONS_UNKNOWN");
+    state.positions_unknown.store(true, Ordering::Relaxed);
+}
+
+/// Check where the arm ended up after reconnecting, if the link dropped
+/// during a MOVE, and act on it as `[link_loss]` asks
+pub fn recover_link_loss(state: &AppState, serial: &dyn ServoController) {
+    let Some((lost_ms, target)) = state.link_loss.take_interrupted() else {
+        return;
+    };
+    let config = state.config();
+    let policy = config.link_loss.policy;
+    if policy == LinkLossPolicy::Ignore {
+        return;
+    }
+
+    let mut events = Vec::new();
+    let mut event = |text: String| {
+        warn!("Link loss: {}", text);
+        events.push(text);
+    };
+    event(format!(
+        "Link dropped during a MOVE to {:?}, {}ms ago",
+        target,
+        state.clock.now_ms().saturating_sub(lost_ms)
+    ));
+
+    // A board reset by reconnecting starts in button mode
+    let positions = match serial.start_serial_mode() {
+        Ok(()) => {
+            let (positions, failure) = read_available_positions(state, serial);
+            if let Some((_, e)) = failure {
+                event(format!("Reading positions failed: {}", e.error));
+            }
+            positions
+        }
+        Err(e) => {
+            event(format!("Could not enter serial mode to read positions: {}", e));
+            [None; NUM_SERVOS as usize]
+        }
+    };
+    let read = positions.iter().flatten().count();
+    event(format!("Reconciled the cache with {} measured positions", read));
+
+    let off_target: Vec<OffTarget> = Channel::all(NUM_SERVOS)
+        .zip(&target)
+        .filter(|&(channel, _)| config.servo(channel.get()).kind.is_servo())
+        .filter_map(|(channel, &target)| {
+            let actual = positions[channel.index()];
+            let arrived = actual.is_some_and(|a| a.abs_diff(target) <= config.link_loss.tolerance);
+            (!arrived).then_some(OffTarget {
+                channel: channel.get(),
+                target,
+                actual,
+            })
+        })
+        .collect();
+    if off_target.is_empty() {
+        event("Every joint reached the target".to_string());
+    } else {
+        event(format!("{} joints away from the target", off_target.len()));
+    }
+
+    match policy {
+        LinkLossPolicy::Reassert if !off_target.is_empty() => {
+            if off_target.iter().any(|off| off.actual.is_none()) {
+                event("Not re-asserting the target with positions unknown".to_string());
+            } else {
+                let duration_ms = off_target
+                    .iter()
+                    .map(|off| {
+                        let channel = Channel::new(off.channel, NUM_SERVOS).unwrap();
+                        travel_ms(state, channel, off.target.abs_diff(off.actual.unwrap()))
+                    })
+                    .max()
+                    .unwrap_or_default()
+                    .min(u16::MAX as u32) as u16;
+                let result = state
+                    .begin_motion()
+                    .and_then(|_motion| {
+                        run_move(state, serial, duration_ms, &target, CommandOptions::default())
+                    });
+                match result {
+                    Ok(()) => {
+                        event(format!("Re-asserted the target with a {}ms MOVE", duration_ms))
+                    }
+                    Err((_, e)) => event(format!("Re-asserting the target failed: {}", e.error)),
+                }
+            }
+        }
+        LinkLossPolicy::Latch => {
+            event("Motion latched until POST /api/link-loss/ack".to_string());
+        }
+        _ => {}
+    }
+
+    let recovery = Recovery {
+        lost_ms,
+        recovered_ms: state.clock.now_ms(),
+        policy,
+        target,
+        off_target,
+        events,
+    };
+    state.link_loss.recovered(recovery, policy == LinkLossPolicy::Latch);
+}
+
+/// Send a POSE with limits and trims applied, updating the position cache
+pub fn run_pose(
+    state: &AppState,
+    serial: &dyn ServoController,
+    angles: &[u16],
+    opts: CommandOptions,
+) -> Result<(), ApiError> {
+    let config = state.config();
+    let (angles, _) = hold_locked(state, serial, angles, config.lockout_strict)?;
+    let targets: Vec<_> = angles.iter().copied().map(Some).collect();
+    check_constraints(state, serial, &targets)?;
+    let servo_angles = to_compensated_angles(state, &config, &angles)?;
+
+    match serial.execute_pose(&servo_angles, opts) {
+        Ok(_) => {
+            state.record_positions(&angles, None);
+            Ok(())
+        }
+        Err(e) => {
+            error!(command = "pose", error = %e, "Failed to execute POSE");
+            Err(handle_serial_error(state, &e))
+        }
+    }
+}
+
+/// Send a client's POSE, checked against `[pose_guard]`
+///
+/// A POSE moves at full servo speed, so one that would make a joint jump
+/// further than `max_jump` from its position is refused with
+/// `POSE_TOO_FAR`, or sent as a MOVE at the joints' velocity limits.
+pub fn send_pose(
+    state: &AppState,
+    serial: &dyn ServoController,
+    angles: &[u16],
+    opts: CommandOptions,
+) -> Result<(), ApiError> {
+    match guard_pose(state, serial, angles)? {
+        Some(duration_ms) => run_move(state, serial, duration_ms, angles, opts),
+        None => run_pose(state, serial, angles, opts),
+    }
+}
+
+/// Time a joint takes to travel `distance` degrees at its velocity limit
+fn travel_ms(state: &AppState, channel: Channel, distance: u16) -> u32 {
+    let velocity = state.max_velocity(channel.get()) as u32;
+    (distance as u32 * 1000).div_ceil(velocity)
+}
+
+/// The MOVE duration to send a POSE jumping too far as, if it may be
+fn guard_pose(
+    state: &AppState,
+    serial: &dyn ServoController,
+    angles: &[u16],
+) -> Result<Option<u16>, ApiError> {
+    let config = state.config();
+    let Some(max_jump) = config.pose_guard.max_jump else {
+        return Ok(None);
+    };
+
+    let mut too_far = None;
+    let mut duration_ms = 0;
+    for (channel, &target) in Channel::all(NUM_SERVOS).zip(angles) {
+        let current = current_position(state, serial, channel)?;
+        let distance = target.abs_diff(current);
+        if distance > max_jump && too_far.is_none() {
+            too_far = Some((channel, distance));
+        }
+        duration_ms = duration_ms.max(travel_ms(state, channel, distance));
+    }
+
+    let Some((channel, distance)) = too_far else {
+        return Ok(None);
+    };
+    match config.pose_guard.on_violation {
+        PoseViolation::Reject => Err(pose_too_far(channel.get(), distance, max_jump)),
+        PoseViolation::Move => {
+            let duration_ms = duration_ms.min(u16::MAX as u32) as u16;
+            info!(
+                channel = channel.get(),
+                distance,
+                duration_ms,
+                "POSE jump too large, sending a MOVE instead"
+            );
+            Ok(Some(duration_ms))
+        }
+    }
+}
+
+fn pose_too_far(channel: u8, distance: u16, max_jump: u16) -> ApiError {
+    unprocessable(
+        "POSE_TOO_FAR",
+        format!(
+            "Servo {} would jump {} degrees, more than pose_guard.max_jump ({}); use a MOVE",
+            channel, distance, max_jump
+        ),
+    )
+}
+
+/// Refuse a motion that would end in a pose failing one of the
+/// `pose_constraints`
+///
+/// Channels without a target keep their position, read from the firmware
+/// if a constraint needs one the cache lacks.
+fn check_constraints(
+    state: &AppState,
+    serial: &dyn ServoController,
+    targets: &[Option<u16>],
+) -> Result<(), ApiError> {
+    let config = state.config();
+    if config.pose_constraints.is_empty() {
+        return Ok(());
+    }
+    let outcome = Outcome {
+        state,
+        serial,
+        config: &config,
+        targets,
+    };
+    constraints::check(&config, &outcome).map_err(constraint_broken)
+}
+
+/// The pose a motion would end in, see [`check_constraints`]
+struct Outcome<'a> {
+    state: &'a AppState,
+    serial: &'a dyn ServoController,
+    config: &'a Config,
+    targets: &'a [Option<u16>],
+}
+
+impl Positions for Outcome<'_> {
+    fn position(&self, channel: u8) -> Option<u16> {
+        if let Some(angle) = self.targets.get(channel as usize).copied().flatten() {
+            return Some(angle);
+        }
+        let channel = Channel::new(channel, NUM_SERVOS).ok()?;
+        current_position(self.state, self.serial, channel).ok()
+    }
+
+    fn channel_by_name(&self, name: &str) -> Option<u8> {
+        self.config.channel_by_name(name)
+    }
+}
+
+fn constraint_broken(broken: constraints::Broken) -> ApiError {
+    let mut error = ErrorResponse::with_code("POSE_CONSTRAINT", broken.message());
+    error.details = Some(serde_json::json!(broken));
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(error))
+}
+
+/// Send a MOVE with limits and trims applied, updating the position cache
+pub fn run_move(
+    state: &AppState,
+    serial: &dyn ServoController,
+    duration_ms: u16,
+    angles: &[u16],
+    opts: CommandOptions,
+) -> Result<(), ApiError> {
+    let config = state.config();
+    let (angles, _) = hold_locked(state, serial, angles, config.lockout_strict)?;
+    let targets: Vec<_> = angles.iter().copied().map(Some).collect();
+    check_constraints(state, serial, &targets)?;
+    let servo_angles = to_compensated_angles(state, &config, &angles)?;
+
+    state.record_motion(angles.len(), Duration::from_millis(duration_ms as u64));
+    state.link_loss.sending(&angles);
+    match serial.execute_move(duration_ms, &servo_angles, opts) {
+        Ok(_) => {
+            let duration = Duration::from_millis(duration_ms as u64);
+            state.record_positions(&angles, Some(duration));
+            state.account_move(duration_ms.into());
+            Ok(())
+        }
+        Err(e) => {
+            error!(command = "move", error = %e, "Failed to execute MOVE");
+            Err(handle_serial_error(state, &e))
+        }
+    }
+}
+
+/// Wait for the last segment of a chain of MOVEs, see
+/// [`CommandOptions::chain`]
+pub fn end_chain(state: &AppState, serial: &dyn ServoController) -> Result<(), ApiError> {
+    serial.end_chain().map_err(|e| {
+        error!(command = "move", error = %e, "Failed to execute MOVE");
+        handle_serial_error(state, &e)
+    })
+}
+
+/// Execute POSE command
+pub async fn execute_pose(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<PoseRequest>,
+) -> Result<Json<MotionResponse>, ApiError> {
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    let angles = resolve_angles(&state, &*serial, &req.angles)?;
+    let strict = state.config().lockout_strict;
+    let (angles, skipped) = hold_locked(&state, &*serial, &angles, strict)?;
+    send_pose(&state, &*serial, &angles, query.options())?;
+
+    Ok(Json(MotionResponse {
+        status: "ok".to_string(),
+        skipped,
+    }))
+}
+
+/// Execute POSE from joint name/angle pairs in any order
+///
+/// Names are resolved through the servo config and every other servo
+/// POSE can reach holds its current position, so the firmware always gets
+/// a complete pose.
+pub async fn execute_named_pose(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<Vec<NamedAngle>>,
+) -> Result<Json<MotionResponse>, ApiError> {
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    let config = state.config();
+    let mut targets: [Option<u16>; NUM_SERVOS as usize] = [None; NUM_SERVOS as usize];
+    for joint in &req {
+        let channel = config
+            .channel_by_name(&joint.name)
+            .ok_or_else(|| bad_request(format!("Unknown servo name: {}", joint.name)))?;
+        set_target(&config, &mut targets, channel, joint.angle)?;
+    }
+    let angles = fill_pose(&state, &*serial, &targets, config.servo_prefix())?;
+    let (angles, skipped) = hold_locked(&state, &*serial, &angles, config.lockout_strict)?;
+    send_pose(&state, &*serial, &angles, query.options())?;
+
+    Ok(Json(MotionResponse {
+        status: "ok".to_string(),
+        skipped,
+    }))
+}
+
+/// Estimate the current a pose would draw, without moving
+///
+/// Moves start from the confirmed positions; unlisted channels hold them.
+/// A rough model for planning against the supply, see
+/// [`current_draw::estimate`].
+pub async fn estimate_pose_load(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<PoseRequest>,
+) -> Result<Json<LoadEstimate>, ApiError> {
+    let config = state.config();
+    let targets = match &req.angles {
+        PoseAngles::List(list) => {
+            if list.len() > NUM_SERVOS as usize {
+                return Err(bad_request(format!(
+                    "Too many servos: {} (max {})",
+                    list.len(),
+                    NUM_SERVOS
+                )));
+            }
+            let mut targets = [None; NUM_SERVOS as usize];
+            for (channel, &angle) in (0..).zip(list) {
+                set_target(&config, &mut targets, channel, angle)?;
+            }
+            targets
+        }
+        PoseAngles::Map(map) => map_targets(&config, map)?,
+    };
+    for (channel, angle) in (0..).zip(targets) {
+        if let Some(angle) = angle {
+            check(&config, Param::Angle, Some(channel), angle as u32)?;
+        }
+    }
+
+    let estimate = current_draw::estimate(&config, &targets, &state.confirmed_positions());
+    Ok(Json(LoadEstimate {
+        over_limit: config.supply_limit_ma.is_some_and(|limit| estimate.peak_ma > limit),
+        supply_limit_ma: config.supply_limit_ma,
+        peak_ma: estimate.peak_ma,
+        holding_ma: estimate.holding_ma,
+        channels: estimate.channels,
+    }))
+}
+
+/// Execute MOVE command
+///
+/// With `track`, the move runs in the background and 202 Accepted returns
+/// its id for `POST /api/move/:id/cancel`.
+pub async fn execute_move(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<MoveRequest>,
+) -> Result<Response, ApiError> {
+    let serial = state.wait_for_serial(query.wait).await?;
+    let motion = state.begin_motion()?;
+
+    let config = state.config();
+    check(&config, Param::MoveDuration, None, req.duration_ms)?;
+    let duration_ms = req.duration_ms.max(config.min_move_duration_ms.into());
+    if duration_ms != req.duration_ms {
+        info!(
+            requested_ms = req.duration_ms,
+            duration_ms, "MOVE duration raised to min_move_duration_ms"
+        );
+    }
+    let angles = resolve_angles(&state, &*serial, &req.angles)?;
+    let (angles, skipped) = hold_locked(&state, &*serial, &angles, config.lockout_strict)?;
+    if req.track {
+        to_servo_angles(&config, &angles)?;
+        drop(motion);
+        let id = state.moves.start(
+            state.clone(),
+            serial,
+            duration_ms,
+            angles,
+            query.options(),
+        );
+        let handle = MoveHandle {
+            id,
+            status: "running".to_string(),
+            duration_ms,
+            skipped,
+        };
+        let completion = state.clock.now_ms() + duration_ms as u64;
+        let headers = [("estimated-completion", completion.to_string())];
+        return Ok((StatusCode::ACCEPTED, headers, Json(handle)).into_response());
+    }
+    if duration_ms > moves::max_part_ms(&config) {
+        moves::run_split(&state, &*serial, duration_ms, &angles, query.options())?;
+    } else {
+        run_move(&state, &*serial, duration_ms as u16, &angles, query.options())?;
+    }
+
+    Ok(Json(MotionResponse {
+        status: "ok".to_string(),
+        skipped,
+    })
+    .into_response())
+}
+
+/// Progress of a tracked move, estimated from the time elapsed since it
+/// started as the firmware doesn't report it
+pub async fn get_move_progress(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<MoveProgress>, ApiError> {
+    let Some((elapsed, duration)) = state.moves.timing(id, state.clock.now()) else {
+        return Err(not_found(format!("No running move {}", id)));
+    };
+    // Clamped once the estimate has passed, while the last segment's
+    // reply is still outstanding
+    let elapsed = elapsed.min(duration);
+    let progress = if duration.is_zero() {
+        1.0
+    } else {
+        elapsed.as_secs_f64() / duration.as_secs_f64()
+    };
+    Ok(Json(MoveProgress {
+        id,
+        progress,
+        elapsed_ms: elapsed.as_millis() as u64,
+        duration_ms: duration.as_millis() as u64,
+    }))
+}
+
+/// Schedule a MOVE for a future time
+///
+/// The duration and a positional target are checked now; the move is
+/// checked again like `POST /api/move` when it is due.
+pub async fn schedule_move(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ScheduleMoveRequest>,
+) -> Result<(StatusCode, Json<ScheduledMove>), ApiError> {
+    let config = state.config();
+    check(&config, Param::MoveDuration, None, req.duration_ms as u32)?;
+    if let PoseAngles::List(angles) = &req.angles {
+        to_servo_angles(&config, angles)?;
+    }
+
+    let now_ms = state.clock.now_ms();
+    let at_ms = match (req.delay_ms, req.at_ms) {
+        (Some(delay_ms), None) => now_ms.saturating_add(delay_ms),
+        (None, Some(at_ms)) => at_ms,
+        _ => return Err(bad_request("Give exactly one of delay_ms and at_ms".to_string())),
+    };
+    if at_ms < now_ms {
+        return Err(unprocessable(
+            "IN_THE_PAST",
+            format!("at_ms {} is {}ms in the past", at_ms, now_ms - at_ms),
+        ));
+    }
+    if at_ms - now_ms > schedule::MAX_DELAY.as_millis() as u64 {
+        return Err(unprocessable(
+            "TOO_FAR_AHEAD",
+            format!(
+                "Moves can be scheduled at most {}s ahead",
+                schedule::MAX_DELAY.as_secs()
+            ),
+        ));
+    }
+
+    match state.schedule.add(state.clone(), at_ms, req.duration_ms, req.angles) {
+        Some(record) => Ok((StatusCode::ACCEPTED, Json(record))),
+        None => Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(ErrorResponse::with_code(
+                "SCHEDULE_FULL",
+                format!("{} moves are already scheduled", schedule::MAX_PENDING),
+            )),
+        )),
+    }
+}
+
+/// Run a scheduled move that is due, checked as if just requested;
+/// returns the locked-out channels held
+///
+/// It waits for user commands in progress and earlier scheduled moves,
+/// and fails with 409 `PREEMPTED` if a user command starts before its MOVE
+/// is sent.
+pub fn run_scheduled_move(
+    state: &AppState,
+    duration_ms: u16,
+    angles: &PoseAngles,
+) -> Result<Vec<u8>, ApiError> {
+    let serial = state.require_serial()?;
+    let motion = state.begin_motion_as(MotionSource::Scheduler)?;
+    let config = state.config();
+    check(&config, Param::MoveDuration, None, duration_ms as u32)?;
+    let angles = resolve_angles(state, &*serial, angles)?;
+    let (angles, skipped) = hold_locked(state, &*serial, &angles, config.lockout_strict)?;
+    if let Some(by) = motion.preempted_by() {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "PREEMPTED",
+                format!("Preempted by a {} command from {}", by.source, by.requester),
+            )),
+        ));
+    }
+    run_move(state, &*serial, duration_ms, &angles, CommandOptions::default())?;
+    Ok(skipped)
+}
+
+/// Scheduled moves, pending and recently finished
+pub async fn get_schedule(State(state): State<Arc<AppState>>) -> Json<ScheduleList> {
+    let (pending, finished) = state.schedule.list();
+    Json(ScheduleList { pending, finished })
+}
+
+/// Cancel a scheduled move before it is due
+pub async fn cancel_scheduled_move(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<ScheduledMove>, ApiError> {
+    match state.schedule.cancel(id, state.clock.now_ms()) {
+        Ok(record) => Ok(Json(record)),
+        Err(CancelError::NotFound) => Err(not_found(format!("No scheduled move {}", id))),
+        Err(CancelError::NotPending(status)) => Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "NOT_PENDING",
+                format!("Scheduled move {} is already {}", id, status.name()),
+            )),
+        )),
+    }
+}
+
+/// Trace a circle, square or Lissajous figure with two channels in the
+/// background
+///
+/// Both channels must stay within their limits at center plus or minus
+/// the amplitude, and below their velocity limits at the pattern's peak
+/// speed. 202 Accepted returns the run; `DELETE /api/pattern` stops it.
+pub async fn start_pattern(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<PatternRequest>,
+) -> Result<(StatusCode, Json<PatternRun>), ApiError> {
+    let serial = state.require_serial()?;
+    // Refused on a standby or a link loss latch before anything is read
+    drop(state.begin_motion()?);
+    let config = state.config();
+
+    if req.amplitude == 0 {
+        return Err(bad_request("amplitude must be at least 1".to_string()));
+    }
+    if !(pattern::MIN_PERIOD_MS..=pattern::MAX_PERIOD_MS).contains(&req.period_ms) {
+        return Err(bad_request(format!(
+            "period_ms must be {}-{}",
+            pattern::MIN_PERIOD_MS,
+            pattern::MAX_PERIOD_MS
+        )));
+    }
+    if !(1..=pattern::MAX_CYCLES).contains(&req.cycles) {
+        return Err(bad_request(format!("cycles must be 1-{}", pattern::MAX_CYCLES)));
+    }
+    if req.channels[0] == req.channels[1] {
+        return Err(bad_request("The two channels must differ".to_string()));
+    }
+    if req.ratio.contains(&0) {
+        return Err(bad_request("ratio must be at least 1 on each axis".to_string()));
+    }
+
+    let mut center = [0; 2];
+    for (axis, &channel) in req.channels.iter().enumerate() {
+        let servo = servo_channel(channel)?;
+        check_servo(&config, channel)?;
+        center[axis] = match req.center {
+            Some(center) => center[axis],
+            None => current_position(&state, &*serial, servo)?,
+        };
+        let Some(low) = center[axis].checked_sub(req.amplitude) else {
+            return Err(unprocessable(
+                "OUT_OF_RANGE",
+                format!(
+                    "Servo {} would go below 0 with center {} and amplitude {}",
+                    channel, center[axis], req.amplitude
+                ),
+            ));
+        };
+        to_servo_angle(&config, channel, low, 0.0)?;
+        to_servo_angle(&config, channel, center[axis] + req.amplitude, 0.0)?;
+
+        let speed = req.pattern.peak_speed(req.ratio)[axis] * req.amplitude as f64 * 1000.0
+            / req.period_ms as f64;
+        let limit = config.max_velocity(channel);
+        if speed > limit as f64 {
+            return Err(unprocessable(
+                "TOO_FAST",
+                format!(
+                    "Servo {} would reach {:.0} degrees/s, more than its limit of {}",
+                    channel, speed, limit
+                ),
+            ));
+        }
+    }
+
+    let pattern = Pattern {
+        pattern: req.pattern,
+        amplitude: req.amplitude,
+        period_ms: req.period_ms,
+        cycles: req.cycles,
+        channels: req.channels,
+        center,
+        ratio: req.ratio,
+    };
+    match state.patterns.start(state.clone(), serial, pattern) {
+        Some(record) => Ok((StatusCode::ACCEPTED, Json(record))),
+        None => Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "PATTERN_RUNNING",
+                "A pattern is running; stop it with DELETE /api/pattern",
+            )),
+        )),
+    }
+}
+
+/// The running pattern, else the last one
+pub async fn get_pattern(State(state): State<Arc<AppState>>) -> Result<Json<PatternRun>, ApiError> {
+    state
+        .patterns
+        .current()
+        .map(Json)
+        .ok_or_else(|| not_found("No pattern has run".to_string()))
+}
+
+/// Stop the running pattern, holding the arm at its measured position
+pub async fn cancel_pattern(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PatternRun>, ApiError> {
+    state
+        .patterns
+        .cancel()
+        .await
+        .map(Json)
+        .ok_or_else(|| not_found("No pattern is running".to_string()))
+}
+
+/// Send one command back to back for a while and report how many went
+/// through (admin only)
+///
+/// Holds the motion gate for the whole run, so it is refused on a standby
+/// or with a link loss latched and stops if one latches meanwhile.
+pub async fn run_benchmark(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<BenchmarkRequest>,
+) -> Result<Json<BenchmarkReport>, ApiError> {
+    require_admin(&state, &headers)?;
+    if !(benchmark::MIN_DURATION_MS..=benchmark::MAX_DURATION_MS).contains(&req.duration_ms) {
+        return Err(bad_request(format!(
+            "duration_ms must be {}-{}",
+            benchmark::MIN_DURATION_MS,
+            benchmark::MAX_DURATION_MS
+        )));
+    }
+    let serial = state.require_serial()?;
+    let duration = Duration::from_millis(req.duration_ms);
+    let task = tokio::task::spawn_blocking(move || {
+        benchmark::run(&state, &*serial, req.command, duration)
+    });
+    let report = task.await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse::new(format!("Benchmark failed: {}", e))),
+        )
+    })??;
+    Ok(Json(report))
+}
+
+/// Cancel a tracked move, holding the arm at its measured position
+pub async fn cancel_move(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<MoveCancelResponse>, ApiError> {
+    let outcome = match state.moves.cancel(id).await {
+        Some(outcome) => outcome?,
+        None => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse::new(format!("No running move {}", id))),
+            ))
+        }
+    };
+
+    Ok(Json(match outcome {
+        MoveOutcome::Completed => MoveCancelResponse {
+            id,
+            status: "completed".to_string(),
+            angles: None,
+        },
+        MoveOutcome::Cancelled { angles } => {
+            info!("Cancelled tracked move {} at {:?}", id, angles);
+            MoveCancelResponse {
+                id,
+                status: "cancelled".to_string(),
+                angles: Some(angles),
+            }
+        }
+    }))
+}
+
+/// Move all servos to the configured home pose
+pub async fn go_home(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CommandQuery>,
+) -> Result<Json<MotionResponse>, ApiError> {
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    let home = state.config().home_pose();
+    if home.is_empty() {
+        return Err(unprocessable(
+            "NOT_A_SERVO",
+            "Channel 0 has no servo, so POSE can't reach any servo".to_string(),
+        ));
+    }
+    // Locked-out channels stay where they are, even in strict mode
+    let (home, skipped) = hold_locked(&state, &*serial, &home, false)?;
+    run_pose(&state, &*serial, &home, query.options())?;
+
+    Ok(Json(MotionResponse {
+        status: "ok".to_string(),
+        skipped,
+    }))
+}
+
+/// Plan a smooth trajectory through waypoints, optionally playing it back
+///
+/// The frames are returned either way; with `execute` the arm first moves
+/// to the first waypoint, then follows the frames with one MOVE each.
+pub async fn plan_trajectory(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CommandQuery>,
+    Json(req): Json<TrajectoryRequest>,
+) -> Result<Json<TrajectoryPlan>, ApiError> {
+    if req.waypoints.len() < 2 {
+        return Err(bad_request("At least two waypoints are required".to_string()));
+    }
+    let config = state.config();
+    check(&config, Param::SampleRate, None, req.sample_rate_hz as u32)?;
+
+    let channels = req.waypoints[0].angles.len();
+    let mut waypoints = Vec::with_capacity(req.waypoints.len());
+    let mut time_ms = 0u32;
+    for (i, waypoint) in req.waypoints.iter().enumerate() {
+        if waypoint.angles.is_empty() || waypoint.angles.len() > NUM_SERVOS as usize {
+            return Err(bad_request(format!("Waypoint {}: invalid number of angles", i)));
+        }
+        if waypoint.angles.len() != channels {
+            return Err(bad_request(format!(
+                "Waypoint {}: expected {} angles like the first waypoint",
+                i, channels
+            )));
+        }
+        if i > 0 {
+            check(&config, Param::WaypointDuration, None, waypoint.duration_ms as u32)?;
+        }
+        to_servo_angles(&config, &waypoint.angles)?;
+        if i > 0 {
+            time_ms += waypoint.duration_ms as u32;
+        }
+        waypoints.push(Frame {
+            time_ms,
+            angles: waypoint.angles.clone(),
+        });
+    }
+
+    config
+        .sequence_limits
+        .check(waypoints.len(), "waypoints", Duration::from_millis(time_ms as u64))
+        .map_err(|e| bad_request(format!("Trajectory too long: {:#}", e)))?;
+    let sample_ms = 1000 / req.sample_rate_hz as u32;
+    if time_ms / sample_ms >= MAX_TRAJECTORY_FRAMES {
+        return Err(bad_request(format!(
+            "Trajectory would have more than {} frames",
+            MAX_TRAJECTORY_FRAMES
+        )));
+    }
+    // Overshoot between waypoints is clamped to the soft limits
+    let limits: Vec<_> = (0..channels as u8)
+        .map(|channel| {
+            let servo = config.servo(channel);
+            (servo.min, servo.max)
+        })
+        .collect();
+    let frames = planner::catmull_rom(&waypoints, sample_ms, &limits);
+
+    if req.execute {
+        let serial = state.wait_for_serial(query.wait).await?;
+        let _motion = state.begin_motion()?;
+
+        let start = &req.waypoints[0];
+        info!("Executing trajectory ({} frames)", frames.len());
+        let total = start.duration_ms as u64 + time_ms as u64;
+        let _plan = state.plan_motion(channels, Duration::from_millis(total));
+        if start.duration_ms > 0 {
+            run_move(&state, &*serial, start.duration_ms, &start.angles, query.options())?;
+        } else {
+            run_pose(&state, &*serial, &start.angles, query.options())?;
+        }
+        // MOVE replies once the move is done, which paces the playback;
+        // with lookahead, each frame is sent shortly before the last ends
+        let opts = CommandOptions {
+            chain: true,
+            ..query.options()
+        };
+        for pair in frames.windows(2) {
+            let duration_ms = (pair[1].time_ms - pair[0].time_ms) as u16;
+            run_move(&state, &*serial, duration_ms, &pair[1].angles, opts)?;
+        }
+        end_chain(&state, &*serial)?;
+    }
+
+    Ok(Json(TrajectoryPlan {
+        sample_ms,
+        duration_ms: time_ms,
+        frames,
+        executed: req.execute,
+    }))
+}
+
+/// Parse a motion script and run it as a background job
+///
+/// Syntax errors are answered right away with 400 `SCRIPT_SYNTAX`; the
+/// returned job can be followed with `GET /api/script/:id`.
+pub async fn run_script(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ScriptRequest>,
+) -> Result<(StatusCode, Json<JobRecord>), ApiError> {
+    if req.source.len() > MAX_SCRIPT_BYTES {
+        return Err(bad_request(format!(
+            "Script is longer than {} bytes",
+            MAX_SCRIPT_BYTES
+        )));
+    }
+    let script = script::parse(&req.source).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                details: Some(serde_json::json!({ "line": e.line })),
+                ..ErrorResponse::with_code("SCRIPT_SYNTAX", e.to_string())
+            }),
+        )
+    })?;
+
+    let serial = state.require_serial()?;
+    // Refuse on a standby before accepting the job
+    drop(state.begin_motion()?);
+
+    let record = state.scripts.start(state.clone(), serial, script);
+    Ok((StatusCode::ACCEPTED, Json(record)))
+}
+
+/// Status and trace of a script job
+pub async fn get_script(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobRecord>, ApiError> {
+    state
+        .scripts
+        .get(id)
+        .map(Json)
+        .ok_or_else(|| not_found(format!("Unknown script job {}", id)))
+}
+
+/// Cancel a script job, waiting for the statement in progress to finish
+pub async fn cancel_script(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<JobRecord>, ApiError> {
+    match state.scripts.cancel(id).await {
+        Some(record) => Ok(Json(record)),
+        None => Err(not_found(format!("Unknown script job {}", id))),
+    }
+}
+
+/// Re-read the config file and apply the hot-reloadable settings
+pub async fn reload_config(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<ConfigReloadResponse>, ApiError> {
+    let path = match &state.config_path {
+        Some(path) => path,
+        None => {
+            return Err(bad_request(
+                "No config file configured (set CONFIG_FILE)".to_string(),
+            ));
+        }
+    };
+
+    // Load and validate everything before touching the running state
+    let mut new_config = match Config::load(Some(path)) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Config reload failed: {:#}", e);
+            return Err(bad_request(format!("{:#}", e)));
+        }
+    };
+
+    let current = state.base_config();
+    // The active profile stays active if the file still has it
+    if let Some(applied) = current.profile.as_ref().and_then(|name| new_config.with_profile(name)) {
+        new_config = applied;
+    }
+    let (changed, requires_restart) = current.changes(&new_config);
+    for change in &requires_restart {
+        warn!("Config change requires a restart, not applied: {}", change);
+    }
+
+    // Settings that can't change while running keep their current values
+    new_config.bind_addr = current.bind_addr.clone();
+    new_config.bind_uds = current.bind_uds.clone();
+    new_config.readonly_bind_addr = current.readonly_bind_addr.clone();
+    new_config.serial = current.serial.clone();
+    new_config.role = current.role;
+    new_config.replication = current.replication.clone();
+
+    if let Some(serial) = state.get_serial() {
+        if new_config.timeouts != current.timeouts {
+            if let Err(e) = serial.set_timeouts(&new_config.timeouts) {
+                error!("Failed to apply new timeouts: {}", e);
+                return Err(handle_serial_error(&state, &e));
+            }
+        }
+        serial.set_protocol(&new_config.protocol);
+    }
+
+    let before = serde_json::to_value(&*current).unwrap_or_default();
+    let after = serde_json::to_value(&new_config).unwrap_or_default();
+    *state.config.lock().unwrap() = Arc::new(new_config);
+    state.audit(&headers, "POST /api/config/reload", "config", None, &before, &after);
+
+    for change in &changed {
+        info!("Config updated: {}", change);
+    }
+    info!("Configuration reloaded ({} changes applied)", changed.len());
+
+    Ok(Json(ConfigReloadResponse {
+        changed,
+        requires_restart,
+    }))
+}
+
+/// Configured profiles and the active one
+pub async fn list_profiles(State(state): State<Arc<AppState>>) -> Json<ProfileList> {
+    let config = state.config();
+    Json(ProfileList {
+        profiles: config.profiles.keys().cloned().collect(),
+        active: config.profile.clone(),
+    })
+}
+
+/// Make a profile's servo settings and home pose the active ones
+///
+/// The config is swapped as a whole, so every command sees either the
+/// previous settings or the profile's, never a mix.
+pub async fn activate_profile(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<ProfileActivated>, ApiError> {
+    let (before, after, changed) = {
+        let mut config = state.config.lock().unwrap();
+        let Some(updated) = config.with_profile(&name) else {
+            return Err(not_found(format!("No profile {}", name)));
+        };
+        let (changed, _) = config.changes(&updated);
+        let before = serde_json::to_value(&**config).unwrap_or_default();
+        let after = serde_json::to_value(&updated).unwrap_or_default();
+        *config = Arc::new(updated);
+        (before, after, changed)
+    };
+    let endpoint = "POST /api/profile/:name/activate";
+    state.audit(&headers, endpoint, "config", Some(&name), &before, &after);
+    info!("Activated profile {} ({} changes)", name, changed.len());
+
+    Ok(Json(ProfileActivated {
+        profile: name,
+        changed,
+    }))
+}
+
+/// Current global motion scale
+pub async fn get_motion_scale(State(state): State<Arc<AppState>>) -> Json<MotionScale> {
+    Json(MotionScale {
+        scale: state.config().motion_scale,
+    })
+}
+
+/// Change the global motion scale until the next restart or config reload
+pub async fn set_motion_scale(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<MotionScale>,
+) -> Result<Json<MotionScale>, ApiError> {
+    let current = state.base_config();
+    let mut updated = (*current).clone();
+    updated.motion_scale = req.scale;
+    if let Err(e) = updated.validate() {
+        return Err(bad_request(format!("{:#}", e)));
+    }
+
+    let before = serde_json::to_value(&*current).unwrap_or_default();
+    let after = serde_json::to_value(&updated).unwrap_or_default();
+    *state.config.lock().unwrap() = Arc::new(updated);
+    state.audit(&headers, "PUT /api/motion-scale", "config", None, &before, &after);
+    if req.scale < 1.0 {
+        warn!("Motion scaled to {} of the commanded range", req.scale);
+    } else {
+        info!("Motion scale reset to 1.0");
+    }
+
+    Ok(Json(req))
+}
+
+/// Sag compensation models by channel
+pub async fn get_compensation(State(state): State<Arc<AppState>>) -> Json<CompensationList> {
+    Json(CompensationList {
+        models: state.compensation.all(),
+    })
+}
+
+/// Set a channel's sag compensation model
+pub async fn set_compensation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    headers: HeaderMap,
+    Json(model): Json<Model>,
+) -> Result<Json<Model>, ApiError> {
+    servo_channel(id)?;
+    check_servo(&state.config(), id)?;
+    model.validate(id).map_err(bad_request)?;
+    store_compensation(&state, &headers, "PUT /api/compensation/:id", id, Some(model.clone()))?;
+    Ok(Json(model))
+}
+
+/// Remove a channel's sag compensation model
+pub async fn delete_compensation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    headers: HeaderMap,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    servo_channel(id)?;
+    if state.compensation.get(id).is_none() {
+        return Err(not_found(format!("No compensation model for channel {}", id)));
+    }
+    store_compensation(&state, &headers, "DELETE /api/compensation/:id", id, None)?;
+    Ok(Json(SuccessResponse {
+        status: "deleted".to_string(),
+    }))
+}
+
+/// Fit a channel's sag compensation model to measured samples and set it
+///
+/// The samples should be taken without a model on the channel, since the
+/// sag fitted is `commanded - measured`.
+pub async fn calibrate_compensation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    headers: HeaderMap,
+    Json(req): Json<CalibrateRequest>,
+) -> Result<Json<CalibrateResponse>, ApiError> {
+    servo_channel(id)?;
+    check_servo(&state.config(), id)?;
+    let inputs = match req.inputs {
+        Some(inputs) => inputs,
+        // Channels every sample has an angle for
+        None => {
+            let mut samples = req.samples.iter();
+            let mut inputs: Vec<u8> = samples
+                .next()
+                .map(|sample| sample.context.keys().copied().collect())
+                .unwrap_or_default();
+            for sample in samples {
+                inputs.retain(|input| sample.context.contains_key(input));
+            }
+            inputs.retain(|&input| input != id);
+            inputs
+        }
+    };
+    // Checked before fitting, so a bad request isn't reported as a bad fit
+    Model {
+        coefficients: vec![0.0; 1 + inputs.len() * req.degree as usize],
+        inputs: inputs.clone(),
+        degree: req.degree,
+    }
+    .validate(id)
+    .map_err(bad_request)?;
+    let fit = compensation::fit(&inputs, req.degree, &req.samples)
+        .map_err(|e| unprocessable("CALIBRATION_FAILED", e))?;
+
+    let endpoint = "POST /api/compensation/:id/calibrate";
+    store_compensation(&state, &headers, endpoint, id, Some(fit.model.clone()))?;
+    info!(
+        "Fitted sag compensation for channel {} to {} samples (rms error {:.2}°)",
+        id,
+        req.samples.len(),
+        fit.rms_error
+    );
+    Ok(Json(CalibrateResponse {
+        channel: id,
+        samples: req.samples.len(),
+        fit,
+    }))
+}
+
+fn store_compensation(
+    state: &AppState,
+    headers: &HeaderMap,
+    endpoint: &str,
+    channel: u8,
+    model: Option<Model>,
+) -> Result<(), ApiError> {
+    let after = serde_json::to_value(&model).unwrap_or_default();
+    let previous = state.compensation.set(channel, model).map_err(|e| {
+        error!("Failed to save compensation: {:#}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse::new(format!("Failed to save compensation: {:#}", e))),
+        )
+    })?;
+    let before = serde_json::to_value(&previous).unwrap_or_default();
+    let target = channel.to_string();
+    state.audit(headers, endpoint, "compensation", Some(&target), &before, &after);
+    Ok(())
+}
+
+/// Re-enable a locked-out channel (admin only)
+pub async fn enable_channel(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    headers: HeaderMap,
+) -> Result<Json<LockoutResponse>, ApiError> {
+    set_lockout(&state, id, &headers, false)
+}
+
+/// Lock a channel out of every command until re-enabled (admin only)
+pub async fn disable_channel(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u8>,
+    headers: HeaderMap,
+) -> Result<Json<LockoutResponse>, ApiError> {
+    set_lockout(&state, id, &headers, true)
+}
+
+fn set_lockout(
+    state: &AppState,
+    id: u8,
+    headers: &HeaderMap,
+    disabled: bool,
+) -> Result<Json<LockoutResponse>, ApiError> {
+    require_admin(state, headers)?;
+    servo_channel(id)?;
+
+    let before = serde_json::json!({ "disabled": state.lockout.disabled() });
+    let changed = state.lockout.set(id, disabled).map_err(|e| {
+        error!("Failed to save lockout: {:#}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse::new(format!("Failed to save lockout: {:#}", e))),
+        )
+    })?;
+    let after = serde_json::json!({ "disabled": state.lockout.disabled() });
+    if changed {
+        let action = if disabled { "disable" } else { "enable" };
+        let endpoint = format!("POST /api/servo/:id/{}", action);
+        let target = id.to_string();
+        state.audit(headers, &endpoint, "lockout", Some(&target), &before, &after);
+        if disabled {
+            warn!(channel = id, "Channel locked out");
+        } else {
+            info!(channel = id, "Channel re-enabled");
+        }
+    }
+
+    Ok(Json(LockoutResponse {
+        channel: id,
+        enabled: !disabled,
+        disabled: state.lockout.disabled(),
+    }))
+}
+
+/// Run a request naming an override with `X-Override` under it
+///
+/// The header carries the token `POST /api/overrides` returned. Refused
+/// with 409 `OVERRIDE_INACTIVE` if no active override has that token, and
+/// with 403 `FORBIDDEN` unless the request's bearer token is the one the
+/// override was opened with or grants `admin`. Should the override expire
+/// while the request runs, later commands of the request are checked
+/// against the normal rules again.
+pub async fn apply_override(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(value) = request.headers().get("x-override") else {
+        return next.run(request).await;
+    };
+    let token = value.to_str().unwrap_or_default().trim();
+    let Some(active) = state.overrides.by_token(token, state.clock.now_ms()) else {
+        return (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "OVERRIDE_INACTIVE",
+                "X-Override names no active override",
+            )),
+        )
+            .into_response();
+    };
+    let allowed = caller(&state, request.headers())
+        .is_some_and(|caller| caller.name == active.owner || caller.grants(Scope::Admin));
+    if !allowed {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ErrorResponse::with_code(
+                "FORBIDDEN",
+                "Only the override's creator or an admin may use it",
+            )),
+        )
+            .into_response();
+    }
+    warn!(override_id = active.id, "Request under override");
+    overrides::REQUESTED.scope(active.id, next.run(request)).await
+}
+
+/// The active override, if any
+pub async fn get_override(State(state): State<Arc<AppState>>) -> Json<OverrideStatus> {
+    Json(OverrideStatus {
+        active: state.overrides.active(state.clock.now_ms()),
+    })
+}
+
+/// Open a supervised override session relaxing limits for `ttl_ms`
+/// (admin only)
+///
+/// Motion requests naming it with `X-Override` are checked against the
+/// relaxed limits; at most one is active at a time.
+pub async fn create_override(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<CreateOverrideRequest>,
+) -> Result<(StatusCode, Json<CreatedOverride>), ApiError> {
+    require_admin(&state, &headers)?;
+    if req.ttl_ms == 0 || req.ttl_ms > overrides::MAX_TTL_MS {
+        return Err(bad_request(format!(
+            "ttl_ms must be between 1 and {}",
+            overrides::MAX_TTL_MS
+        )));
+    }
+    if let Err(e) = req.relaxed.validate(&state.base_config()) {
+        return Err(bad_request(format!("{:#}", e)));
+    }
+
+    let now_ms = state.clock.now_ms();
+    if let Some(expired) = state.overrides.expire(now_ms) {
+        audit_override_expiry(&state, &expired);
+    }
+    // Known to be a caller: only admins get here
+    let owner = caller(&state, &headers).map(|caller| caller.name).unwrap_or_default();
+    let created = state
+        .overrides
+        .create(&actor(&headers), &owner, req.relaxed, now_ms, req.ttl_ms)
+        .map_err(|e| match e {
+            CreateError::Active(active) => {
+                let mut error = ErrorResponse::with_code(
+                    "OVERRIDE_ACTIVE",
+                    format!("Override {} is active; revoke it first", active.id),
+                );
+                error.details = Some(serde_json::json!({ "active": active }));
+                (StatusCode::CONFLICT, Json(error))
+            }
+            CreateError::Token(e) => {
+                error!("Failed to open an override: {:#}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ErrorResponse::new(format!("{:#}", e))),
+                )
+            }
+        })?;
+
+    let target = created.id.to_string();
+    let after = serde_json::json!({ "override": created });
+    let before = serde_json::json!({ "override": null });
+    state.audit(&headers, "POST /api/overrides", "override", Some(&target), &before, &after);
+    warn!(
+        override_id = created.id,
+        ttl_ms = req.ttl_ms,
+        "Override opened: {:?}",
+        created.relaxed
+    );
+    tokio::spawn(expire_override(state.clone(), created.expires_ms));
+
+    let token = created.token.clone();
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedOverride {
+            session: created,
+            token,
+        }),
+    ))
+}
+
+/// End an override before it expires (admin only)
+pub async fn revoke_override(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<Json<Override>, ApiError> {
+    require_admin(&state, &headers)?;
+    let Some(revoked) = state.overrides.revoke(id, state.clock.now_ms()) else {
+        return Err(not_found(format!("Override {} is not active", id)));
+    };
+
+    let target = id.to_string();
+    let before = serde_json::json!({ "override": revoked });
+    let after = serde_json::json!({ "override": null });
+    let endpoint = "DELETE /api/overrides/:id";
+    state.audit(&headers, endpoint, "override", Some(&target), &before, &after);
+    info!(override_id = id, "Override revoked");
+
+    Ok(Json(revoked))
+}
+
+/// Drop the override once it is due to expire, if it is still active
+async fn expire_override(state: Arc<AppState>, expires_ms: u64) {
+    let remaining = expires_ms.saturating_sub(state.clock.now_ms());
+    state.clock.sleep(Duration::from_millis(remaining)).await;
+    if let Some(expired) = state.overrides.expire(state.clock.now_ms()) {
+        audit_override_expiry(&state, &expired);
+    }
+}
+
+fn audit_override_expiry(state: &AppState, expired: &Override) {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-actor", HeaderValue::from_static("override-expiry"));
+    let target = expired.id.to_string();
+    let before = serde_json::json!({ "override": expired });
+    let after = serde_json::json!({ "override": null });
+    state.audit(&headers, "expiry", "override", Some(&target), &before, &after);
+    info!(override_id = expired.id, "Override expired");
+}
+
+fn not_found(error: String) -> ApiError {
+    (StatusCode::NOT_FOUND, Json(ErrorResponse::new(error)))
+}
+
+/// A saved pose by name
+pub fn saved_pose(state: &AppState, name: &str) -> Result<Pose, ApiError> {
+    let pose = state.library.lock().unwrap().poses.get(name).cloned();
+    pose.ok_or_else(|| not_found(format!("Unknown pose: {}", name)))
+}
+
+/// Channels a sequence moves
+pub fn sequence_channels(sequence: &Sequence) -> usize {
+    sequence.steps.iter().map(|step| step.angles.len()).max().unwrap_or(0)
+}
+
+/// A saved sequence by name
+pub fn saved_sequence(state: &AppState, name: &str) -> Result<Sequence, ApiError> {
+    let sequence = state.library.lock().unwrap().sequences.get(name).cloned();
+    sequence.ok_or_else(|| not_found(format!("Unknown sequence: {}", name)))
+}
+
+/// Refuse to start unless the preconditions hold for the current positions
+///
+/// Positions come from the cache unless `fresh` is set. An override skips
+/// the check but requires the admin token.
+pub fn check_preconditions(
+    state: &AppState,
+    serial: &dyn ServoController,
+    preconditions: &Preconditions,
+    fresh: bool,
+    req: &ExecuteRequest,
+    headers: &HeaderMap,
+) -> Result<(), ApiError> {
+    if preconditions.is_empty() {
+        return Ok(());
+    }
+
+    if req.override_preconditions {
+        require_admin(state, headers)?;
+        warn!("Preconditions overridden by admin");
+        return Ok(());
+    }
+
+    let positions = if fresh {
+        state.poller.activity();
+        read_all_positions(state, serial)?
+    } else {
+        state.confirmed_positions()
+    };
+
+    let violations = preconditions.evaluate(&positions, &state.library.lock().unwrap());
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    warn!("Preconditions not met: {}", violations.join("; "));
+    Err((
+        StatusCode::CONFLICT,
+        Json(ErrorResponse {
+            details: Some(serde_json::json!({ "violations": violations })),
+            ..ErrorResponse::with_code("PRECONDITION_FAILED", "Preconditions not met")
+        }),
+    ))
+}
+
+/// List saved poses
+pub async fn list_poses(State(state): State<Arc<AppState>>) -> Json<BTreeMap<String, Pose>> {
+    Json(state.library.lock().unwrap().poses.clone())
+}
+
+/// Get a saved pose
+pub async fn get_pose(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<Pose>, ApiError> {
+    match state.library.lock().unwrap().poses.get(&name) {
+        Some(pose) => Ok(Json(pose.clone())),
+        None => Err(not_found(format!("Unknown pose: {}", name))),
+    }
+}
+
+/// Create or replace a saved pose
+pub async fn save_pose(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(pose): Json<Pose>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    if let Err(e) = pose.validate(&state.config()) {
+        return Err(bad_request(format!("{:#}", e)));
+    }
+    store_pose(&state, &headers, "PUT /api/poses/:name", &name, pose)?;
+
+    Ok(Json(SuccessResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+/// Save a validated pose to the library and the audit trail
+pub fn store_pose(
+    state: &AppState,
+    headers: &HeaderMap,
+    endpoint: &str,
+    name: &str,
+    pose: Pose,
+) -> Result<(), ApiError> {
+    let mut library = state.library.lock().unwrap();
+    let before = library_subtree("poses", name, library.poses.get(name));
+    let after = library_subtree("poses", name, Some(&pose));
+    library.poses.insert(name.to_string(), pose);
+    state.save_library(&library)?;
+    state.audit(headers, endpoint, "pose", Some(name), &before, &after);
+    info!("Saved pose {}", name);
+    Ok(())
+}
+
+/// Delete a saved pose
+pub async fn delete_pose(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    if !remove_pose(&state, &headers, "DELETE /api/poses/:name", &name)? {
+        return Err(not_found(format!("Unknown pose: {}", name)));
+    }
+
+    Ok(Json(SuccessResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+/// Remove a pose from the library, recording it in the audit trail;
+/// `false` if there is no such pose
+pub fn remove_pose(
+    state: &AppState,
+    headers: &HeaderMap,
+    endpoint: &str,
+    name: &str,
+) -> Result<bool, ApiError> {
+    let mut library = state.library.lock().unwrap();
+    let Some(removed) = library.poses.remove(name) else {
+        return Ok(false);
+    };
+    state.save_library(&library)?;
+    let before = library_subtree("poses", name, Some(&removed));
+    let after = library_subtree::<Pose>("poses", name, None);
+    state.audit(headers, endpoint, "pose", Some(name), &before, &after);
+    info!("Deleted pose {}", name);
+    Ok(true)
+}
+
+/// Move to a saved pose
+pub async fn execute_saved_pose(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<FreshQuery>,
+    headers: HeaderMap,
+    req: Option<Json<ExecuteRequest>>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    let pose = saved_pose(&state, &name)?;
+
+    let req = req.map(|Json(req)| req).unwrap_or_default();
+    check_preconditions(&state, &*serial, &pose.preconditions, query.fresh, &req, &headers)?;
+
+    info!("Executing pose {}", name);
+    send_pose(&state, &*serial, &pose.angles, CommandOptions::default())?;
+
+    Ok(Json(SuccessResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+/// Move to a saved pose with offsets added to some channels, leaving the
+/// saved pose as it is
+///
+/// Each adjusted angle is clamped to its servo's soft limits. The saved
+/// pose's preconditions apply as when it is executed.
+pub async fn execute_adjusted_pose(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<FreshQuery>,
+    headers: HeaderMap,
+    Json(req): Json<AdjustedPoseRequest>,
+) -> Result<Json<AdjustedPoseResponse>, ApiError> {
+    let pose = saved_pose(&state, &name)?;
+    let config = state.config();
+    let mut angles = pose.angles.clone();
+    let mut seen = [false; NUM_SERVOS as usize];
+    let mut clamped = Vec::new();
+    for (key, &offset) in &req.offsets {
+        let channel = channel_key(&config, key)?;
+        check_servo(&config, channel)?;
+        if std::mem::replace(&mut seen[channel as usize], true) {
+            return Err(bad_request(format!("Servo {} specified more than once", channel)));
+        }
+        let Some(angle) = angles.get_mut(channel as usize) else {
+            return Err(bad_request(format!(
+                "Pose {} has no angle for servo {}",
+                name, channel
+            )));
+        };
+        let servo = config.servo(channel);
+        let adjusted = (*angle as i32).saturating_add(offset);
+        *angle = adjusted.clamp(servo.min as i32, servo.max as i32) as u16;
+        if *angle as i32 != adjusted {
+            clamped.push(channel);
+        }
+    }
+
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+    let execute = ExecuteRequest {
+        override_preconditions: req.override_preconditions,
+    };
+    check_preconditions(&state, &*serial, &pose.preconditions, query.fresh, &execute, &headers)?;
+
+    info!(?angles, "Executing pose {} adjusted", name);
+    send_pose(&state, &*serial, &angles, CommandOptions::default())?;
+
+    Ok(Json(AdjustedPoseResponse {
+        status: "ok".to_string(),
+        base: pose.angles,
+        angles,
+        clamped,
+    }))
+}
+
+/// List saved sequences
+pub async fn list_sequences(
+    State(state): State<Arc<AppState>>,
+) -> Json<BTreeMap<String, Sequence>> {
+    Json(state.library.lock().unwrap().sequences.clone())
+}
+
+/// Get a saved sequence
+pub async fn get_sequence(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Json<Sequence>, ApiError> {
+    match state.library.lock().unwrap().sequences.get(&name) {
+        Some(sequence) => Ok(Json(sequence.clone())),
+        None => Err(not_found(format!("Unknown sequence: {}", name))),
+    }
+}
+
+/// Create or replace a saved sequence
+pub async fn save_sequence(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(sequence): Json<Sequence>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    if let Err(e) = sequence.validate(&state.config()) {
+        return Err(bad_request(format!("{:#}", e)));
+    }
+
+    store_sequence(&state, &headers, "PUT /api/sequences/:name", &name, sequence)?;
+
+    Ok(Json(SuccessResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+/// Save a validated sequence, recording the change in the audit trail
+pub fn store_sequence(
+    state: &AppState,
+    headers: &HeaderMap,
+    endpoint: &str,
+    name: &str,
+    sequence: Sequence,
+) -> Result<(), ApiError> {
+    let mut library = state.library.lock().unwrap();
+    let before = library_subtree("sequences", name, library.sequences.get(name));
+    let after = library_subtree("sequences", name, Some(&sequence));
+    library.sequences.insert(name.to_string(), sequence);
+    state.save_library(&library)?;
+    state.audit(headers, endpoint, "sequence", Some(name), &before, &after);
+    info!("Saved sequence {}", name);
+    Ok(())
+}
+
+/// Import a sequence from a request body of up to 64 MiB
+///
+/// Small bodies are imported right away. Larger ones are spooled to a
+/// temporary file and imported in the background; the returned 202 job
+/// can be followed with `GET /api/imports/:id`.
+pub async fn import_sequence(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<ImportRecord>), ApiError> {
+    let (status, record) = state.imports.import(state.clone(), name, headers, body).await?;
+    Ok((status, Json(record)))
+}
+
+/// Import the poses of a pack at a URL, see [`url_import::start`]
+pub async fn import_poses_url(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<UrlImportRequest>,
+) -> Result<(StatusCode, Json<UrlImportRecord>), ApiError> {
+    let (status, record) =
+        url_import::start(state.clone(), PackKind::Poses, &req.url, req.prefix, headers).await?;
+    Ok((status, Json(record)))
+}
+
+/// Import the sequences of a pack at a URL, see [`url_import::start`]
+pub async fn import_sequences_url(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<UrlImportRequest>,
+) -> Result<(StatusCode, Json<UrlImportRecord>), ApiError> {
+    let (status, record) =
+        url_import::start(state.clone(), PackKind::Sequences, &req.url, req.prefix, headers)
+            .await?;
+    Ok((status, Json(record)))
+}
+
+/// Status and per-item results of an import from a URL
+pub async fn get_url_import(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<UrlImportRecord>, ApiError> {
+    state
+        .url_imports
+        .get(id)
+        .map(Json)
+        .ok_or_else(|| not_found(format!("Unknown import {}", id)))
+}
+
+/// Files loaded from the watched directory and the ones that failed
+pub async fn get_library_watch(State(state): State<Arc<AppState>>) -> Json<LibraryWatchInfo> {
+    let config = state.config();
+    Json(LibraryWatchInfo {
+        dir: config
+            .library_watch
+            .dir
+            .as_ref()
+            .map(|dir| dir.display().to_string()),
+        running: state.library_watch.running(),
+        conflicts: config.library_watch.conflicts,
+        files: state.library_watch.files(),
+        problems: state.library_watch.problems(),
+    })
+}
+
+/// Files waiting to be written and problems keeping them
+pub async fn get_persistence(State(state): State<Arc<AppState>>) -> Json<PersistenceInfo> {
+    let paths = |paths: Vec<std::path::PathBuf>| {
+        paths.iter().map(|path| path.display().to_string()).collect()
+    };
+    Json(PersistenceInfo {
+        pending: paths(state.persister.pending()),
+        failing: paths(state.persister.failing()),
+        problems: state.persister.problems(),
+    })
+}
+
+/// Status and validation errors of a sequence import
+pub async fn get_import(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<ImportRecord>, ApiError> {
+    state
+        .imports
+        .get(id)
+        .map(Json)
+        .ok_or_else(|| not_found(format!("Unknown import {}", id)))
+}
+
+/// Delete a saved sequence
+pub async fn delete_sequence(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    if !remove_sequence(&state, &headers, "DELETE /api/sequences/:name", &name)? {
+        return Err(not_found(format!("Unknown sequence: {}", name)));
+    }
+
+    Ok(Json(SuccessResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+/// Remove a sequence from the library, recording it in the audit trail;
+/// `false` if there is no such sequence
+pub fn remove_sequence(
+    state: &AppState,
+    headers: &HeaderMap,
+    endpoint: &str,
+    name: &str,
+) -> Result<bool, ApiError> {
+    let mut library = state.library.lock().unwrap();
+    let Some(removed) = library.sequences.remove(name) else {
+        return Ok(false);
+    };
+    state.save_library(&library)?;
+    let before = library_subtree("sequences", name, Some(&removed));
+    let after = library_subtree::<Sequence>("sequences", name, None);
+    state.audit(headers, endpoint, "sequence", Some(name), &before, &after);
+    info!("Deleted sequence {}", name);
+    Ok(true)
+}
+
+/// Play back a saved sequence, waiting for each step to finish
+pub async fn execute_sequence(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<FreshQuery>,
+    headers: HeaderMap,
+    req: Option<Json<ExecuteRequest>>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+
+    let sequence = saved_sequence(&state, &name)?;
+
+    // Reject the whole sequence up front if any step is outside the limits
+    let config = state.config();
+    // Saved before the limits were lowered
+    sequence
+        .check_limits(&config)
+        .map_err(|e| bad_request(format!("{:#}", e)))?;
+    for step in &sequence.steps {
+        to_servo_angles(&config, &step.angles)?;
+    }
+
+    let req = req.map(|Json(req)| req).unwrap_or_default();
+    check_preconditions(&state, &*serial, &sequence.preconditions, query.fresh, &req, &headers)?;
+
+    info!("Executing sequence {} ({} steps)", name, sequence.steps.len());
+    let _plan = state.plan_motion(sequence_channels(&sequence), sequence.duration());
+    for step in &sequence.steps {
+        run_move(
+            &state,
+            &*serial,
+            step.duration_ms,
+            &step.angles,
+            CommandOptions::default(),
+        )?;
+        tokio::time::sleep(Duration::from_millis(step.duration_ms as u64)).await;
+    }
+
+    Ok(Json(SuccessResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests;
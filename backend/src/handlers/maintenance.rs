@@ -0,0 +1,195 @@
+//! Handlers for the audit trail and re-zeroing a channel
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use std::sync::Arc;
+use tracing::info;
+
+use super::{
+    bad_request, library_error, not_found, require_admin, servo_channel, unprocessable, ApiError,
+    AppState,
+};
+use crate::audit::{self, AuditFilter};
+use crate::config::Config;
+use crate::library::Library;
+use crate::models::{AuditPage, AuditQuery, ErrorResponse, RezeroRequest, RezeroResponse};
+use crate::rezero;
+
+/// Query the audit trail of configuration changes
+pub async fn get_audit(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+) -> Json<AuditPage> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let filter = AuditFilter {
+        since_ms: query.since,
+        actor: query.actor,
+        kind: query.kind,
+    };
+
+    let audit = state.audit.lock().unwrap();
+    let matching = audit.query(&filter);
+    let entries = matching
+        .iter()
+        .skip(query.offset)
+        .take(limit)
+        .map(|&entry| entry.clone())
+        .collect();
+
+    Json(AuditPage {
+        entries,
+        total: matching.len(),
+        offset: query.offset,
+        limit,
+    })
+}
+
+/// The stores a re-zero touches, as diffed into its audit entry
+fn rezero_doc(library: &Library, config: &Config) -> serde_json::Value {
+    serde_json::json!({
+        "library": library,
+        "config": { "servos": config.servos, "home": config.home },
+    })
+}
+
+/// Shift one channel's stored angles by an offset, as after a servo horn
+/// was refitted
+///
+/// Poses, sequences, the home pose and the soft limits are updated
+/// together or not at all. Angles the offset takes out of the firmware's
+/// range refuse the whole re-zero unless `clamp` is set.
+pub async fn rezero(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<RezeroRequest>,
+) -> Result<Json<RezeroResponse>, ApiError> {
+    require_admin(&state, &headers)?;
+    servo_channel(req.channel)?;
+    if req.apply_to.is_empty() {
+        return Err(bad_request("apply_to names no stores".to_string()));
+    }
+    if req.offset == 0 {
+        return Err(bad_request("Offset is 0".to_string()));
+    }
+
+    let mut library = state.library.lock().unwrap();
+    let current = state.base_config();
+    let rezeroed = rezero::rezero(&library, &current, req.channel, req.offset, &req.apply_to);
+    if !rezeroed.out_of_range.is_empty() && !req.clamp {
+        let mut error = ErrorResponse::with_code(
+            "OUT_OF_RANGE",
+            format!(
+                "{} angles would leave the firmware's range; set clamp to clamp them",
+                rezeroed.out_of_range.len()
+            ),
+        );
+        error.details = Some(serde_json::json!({ "out_of_range": rezeroed.out_of_range }));
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(error)));
+    }
+    if let Err(e) = rezeroed.config.validate() {
+        return Err(unprocessable("INVALID_CONFIG", format!("{:#}", e)));
+    }
+    if let Some(error) = library_error(&rezeroed.library, &rezeroed.config) {
+        return Err(unprocessable("INVALID_LIBRARY", error));
+    }
+
+    let before = rezero_doc(&library, &current);
+    let after = rezero_doc(&rezeroed.library, &rezeroed.config);
+    state.save_library(&rezeroed.library)?;
+    *library = rezeroed.library;
+    *state.config.lock().unwrap() = Arc::new(rezeroed.config);
+    drop(library);
+
+    let target = format!("channel {}", req.channel);
+    let entry = state.audit(
+        &headers,
+        "POST /api/maintenance/rezero",
+        "rezero",
+        Some(&target),
+        &before,
+        &after,
+    );
+    info!(
+        "Re-zeroed channel {} by {} ({} angles, {} clamped)",
+        req.channel,
+        req.offset,
+        rezeroed.shifted,
+        rezeroed.out_of_range.len()
+    );
+
+    Ok(Json(RezeroResponse {
+        audit_id: entry.map(|entry| entry.id),
+        shifted: rezeroed.shifted,
+        out_of_range: rezeroed.out_of_range,
+    }))
+}
+
+/// Revert a re-zero by applying its recorded changes in reverse
+///
+/// Clamped angles come back exactly as they were. Refused if any of the
+/// angles has been changed since, so later edits aren't overwritten.
+pub async fn undo_rezero(
+    State(state): State<Arc<AppState>>,
+    Path(audit_id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<Json<RezeroResponse>, ApiError> {
+    require_admin(&state, &headers)?;
+    let entry = state.audit.lock().unwrap().get(audit_id).cloned();
+    let Some(entry) = entry.filter(|entry| entry.kind == "rezero") else {
+        return Err(not_found(format!("No re-zero with audit id {}", audit_id)));
+    };
+    let inverse: Vec<audit::Change> = entry
+        .changes
+        .iter()
+        .rev()
+        .map(|change| audit::Change {
+            path: change.path.clone(),
+            before: change.after.clone(),
+            after: change.before.clone(),
+        })
+        .collect();
+
+    let mut library = state.library.lock().unwrap();
+    let current = state.base_config();
+    let before = rezero_doc(&library, &current);
+    let mut doc = before.clone();
+    let divergences = audit::apply(&mut doc, &inverse);
+    if !divergences.is_empty() {
+        let mut error = ErrorResponse::with_code(
+            "DIVERGED",
+            format!("{} values changed since the re-zero", divergences.len()),
+        );
+        error.details = Some(serde_json::json!({ "divergences": divergences }));
+        return Err((StatusCode::CONFLICT, Json(error)));
+    }
+
+    let invalid = |e: serde_json::Error| unprocessable("INVALID_CHANGE", e.to_string());
+    let updated: Library = serde_json::from_value(doc["library"].take()).map_err(invalid)?;
+    let mut config = (*current).clone();
+    config.servos = serde_json::from_value(doc["config"]["servos"].take()).map_err(invalid)?;
+    config.home = serde_json::from_value(doc["config"]["home"].take()).map_err(invalid)?;
+    if let Err(e) = config.validate() {
+        return Err(unprocessable("INVALID_CONFIG", format!("{:#}", e)));
+    }
+    if let Some(error) = library_error(&updated, &config) {
+        return Err(unprocessable("INVALID_LIBRARY", error));
+    }
+
+    let after = rezero_doc(&updated, &config);
+    state.save_library(&updated)?;
+    *library = updated;
+    *state.config.lock().unwrap() = Arc::new(config);
+    drop(library);
+
+    let target = entry.target.as_deref();
+    let endpoint = "POST /api/maintenance/rezero/undo/:audit_id";
+    let undo = state.audit(&headers, endpoint, "rezero", target, &before, &after);
+    info!("Undid re-zero {} ({} values)", audit_id, inverse.len());
+
+    Ok(Json(RezeroResponse {
+        audit_id: undo.map(|entry| entry.id),
+        shifted: inverse.len(),
+        out_of_range: Vec::new(),
+    }))
+}
@@ -0,0 +1,199 @@
+//! Handlers for replication between an active and a standby instance
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use super::{bad_request, bearer_token, library_error, require_admin, ApiError, AppState};
+use crate::audit::{self, AuditEntry};
+use crate::config::{redact_url, Config, Role};
+use crate::library::Library;
+use crate::models::{ErrorResponse, PromoteResponse, ReplicationInfo, SuccessResponse};
+use crate::replication::REPLICATED_KINDS;
+use crate::tokens;
+
+/// Replication status and role of this instance
+pub async fn get_replication(State(state): State<Arc<AppState>>) -> Json<ReplicationInfo> {
+    Json(replication_info(&state))
+}
+
+pub(super) fn replication_info(state: &AppState) -> ReplicationInfo {
+    ReplicationInfo {
+        role: state.replication.role(),
+        peer_url: state
+            .config()
+            .replication
+            .peer_url
+            .as_deref()
+            .map(redact_url),
+        status: state.replication.status(),
+    }
+}
+
+/// Apply a change pushed by the peer
+///
+/// Pose and sequence changes are applied to the library; of config
+/// changes only servo settings and the home pose are taken over, since the
+/// rest (serial port, bind address, ...) is specific to each instance.
+pub async fn receive_replication(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(entry): Json<AuditEntry>,
+) -> Result<Json<SuccessResponse>, ApiError> {
+    let config = state.config();
+    match &config.replication.token {
+        None => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(ErrorResponse::with_code(
+                    "REPLICATION_DISABLED",
+                    "No replication token configured",
+                )),
+            ));
+        }
+        Some(token) if !bearer_token(&headers).is_some_and(|b| tokens::same_token(b, token)) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(ErrorResponse::with_code(
+                    "FORBIDDEN",
+                    "Valid replication token required",
+                )),
+            ));
+        }
+        Some(_) => {}
+    }
+
+    if !REPLICATED_KINDS.contains(&entry.kind.as_str()) {
+        return Err(bad_request(format!(
+            "Kind {} is not replicated",
+            entry.kind
+        )));
+    }
+    if !state.replication.accept_remote(&entry) {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "STALE",
+                "A newer local change to the same object exists",
+            )),
+        ));
+    }
+
+    let divergences = if entry.kind == "config" {
+        apply_replicated_config(&state, &entry)?
+    } else {
+        apply_replicated_library(&state, &entry)?
+    };
+
+    let result = state.audit.lock().unwrap().record(
+        &format!("replica:{}", entry.actor),
+        &entry.endpoint,
+        &entry.kind,
+        entry.target.as_deref(),
+        entry.changes.clone(),
+    );
+    if let Err(e) = result {
+        error!("Failed to record audit entry: {:#}", e);
+    }
+    state.replication.applied(&entry, divergences);
+    info!(
+        "Applied replicated {} change from {}",
+        entry.kind, entry.actor
+    );
+
+    Ok(Json(SuccessResponse {
+        status: "ok".to_string(),
+    }))
+}
+
+fn replication_error(state: &AppState, entry: &AuditEntry, error: String) -> ApiError {
+    state.replication.report(entry.id, error.clone());
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse::with_code("INVALID_CHANGE", error)),
+    )
+}
+
+fn apply_replicated_library(state: &AppState, entry: &AuditEntry) -> Result<Vec<String>, ApiError> {
+    let mut library = state.library.lock().unwrap();
+    let mut doc = serde_json::to_value(&*library).unwrap_or_default();
+    let divergences = audit::apply(&mut doc, &entry.changes);
+
+    let updated: Library = match serde_json::from_value(doc) {
+        Ok(updated) => updated,
+        Err(e) => {
+            return Err(replication_error(
+                state,
+                entry,
+                format!("Invalid library: {}", e),
+            ))
+        }
+    };
+    if let Some(error) = library_error(&updated, &state.config()) {
+        return Err(replication_error(state, entry, error));
+    }
+
+    state.save_library(&updated)?;
+    *library = updated;
+    Ok(divergences)
+}
+
+fn apply_replicated_config(state: &AppState, entry: &AuditEntry) -> Result<Vec<String>, ApiError> {
+    let changes: Vec<_> = entry
+        .changes
+        .iter()
+        .filter(|c| c.path.starts_with("servos") || c.path.starts_with("home"))
+        .cloned()
+        .collect();
+
+    let current = state.base_config();
+    let mut doc = serde_json::to_value(&*current).unwrap_or_default();
+    let divergences = audit::apply(&mut doc, &changes);
+    let parsed: Config = match serde_json::from_value(doc) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Err(replication_error(
+                state,
+                entry,
+                format!("Invalid config: {}", e),
+            ))
+        }
+    };
+
+    let mut updated = (*current).clone();
+    updated.servos = parsed.servos;
+    updated.home = parsed.home;
+    if let Err(e) = updated.validate() {
+        return Err(replication_error(state, entry, format!("{:#}", e)));
+    }
+    *state.config.lock().unwrap() = Arc::new(updated);
+    Ok(divergences)
+}
+
+/// Make a standby instance active
+pub async fn promote(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<PromoteResponse>, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let previous = state.replication.promote();
+    if previous != Role::Active {
+        warn!("Promoted from {:?} to active", previous);
+        state.audit(
+            &headers,
+            "POST /api/admin/promote",
+            "role",
+            None,
+            &serde_json::json!({ "role": previous }),
+            &serde_json::json!({ "role": Role::Active }),
+        );
+    }
+
+    Ok(Json(PromoteResponse {
+        role: Role::Active,
+        previous,
+    }))
+}
@@ -0,0 +1,137 @@
+//! Handlers exporting the state: snapshots, backups and support bundles
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use std::sync::Arc;
+use tokio_util::io::{ReaderStream, SyncIoBridge};
+use tracing::warn;
+
+use super::replication::replication_info;
+use super::{capabilities, health, require_admin, ApiError, AppState};
+use crate::audit;
+use crate::backup;
+use crate::config::redact_url;
+use crate::models::{BackupInfo, ErrorResponse, Snapshot, VersionInfo};
+use crate::support::Bundle;
+
+/// Audit entries included in a support bundle
+const SUPPORT_AUDIT_ENTRIES: usize = 200;
+
+/// Export configuration, library, known positions and recent audit entries
+pub async fn get_snapshot(State(state): State<Arc<AppState>>) -> Json<Snapshot> {
+    Json(snapshot(&state))
+}
+
+/// The document of `GET /api/snapshot`, also what backups contain
+pub fn snapshot(state: &AppState) -> Snapshot {
+    let mut config = (*state.config()).clone();
+    config.replication.peer_url = config.replication.peer_url.as_deref().map(redact_url);
+    config.backup.url = config.backup.url.as_deref().map(redact_url);
+    Snapshot {
+        timestamp_ms: audit::now_ms(),
+        config,
+        library: state.library.lock().unwrap().clone(),
+        compensation: state.compensation.all(),
+        positions: state.positions.lock().unwrap().to_vec(),
+        recent_audit: state.audit.lock().unwrap().recent(10),
+    }
+}
+
+/// Outcome of the backups of the snapshot
+pub async fn get_backup_status(State(state): State<Arc<AppState>>) -> Json<BackupInfo> {
+    Json(backup_info(&state, None))
+}
+
+/// Back up the snapshot now, even if it didn't change
+pub async fn backup_now(State(state): State<Arc<AppState>>) -> Result<Json<BackupInfo>, ApiError> {
+    if !state.config().backup.enabled() {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse::with_code(
+                "BACKUP_DISABLED",
+                "No backup.url or backup.dir configured",
+            )),
+        ));
+    }
+    match backup::backup(&state, true).await {
+        Ok(outcome) => Ok(Json(backup_info(&state, Some(outcome)))),
+        Err(e) => Err((
+            StatusCode::BAD_GATEWAY,
+            Json(ErrorResponse {
+                details: serde_json::to_value(backup_info(&state, None)).ok(),
+                ..ErrorResponse::with_code("BACKUP_FAILED", e)
+            }),
+        )),
+    }
+}
+
+fn backup_info(state: &AppState, outcome: Option<backup::Outcome>) -> BackupInfo {
+    let config = state.config();
+    let sink = match (&config.backup.url, &config.backup.dir) {
+        (Some(url), _) => Some(redact_url(url)),
+        (None, Some(dir)) => Some(dir.display().to_string()),
+        (None, None) => None,
+    };
+    BackupInfo {
+        enabled: config.backup.enabled(),
+        sink,
+        interval_ms: config.backup.interval_ms,
+        outcome,
+        status: state.backups.status(),
+    }
+}
+
+/// Download a zip of everything useful for a bug report (admin only)
+///
+/// The archive is produced on a blocking thread and streamed out as it is
+/// compressed.
+pub async fn get_support_bundle(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let timestamp_ms = audit::now_ms();
+    let capabilities = capabilities(&state);
+    let mut bundle = Bundle::default();
+    bundle.add(
+        "version.json",
+        VersionInfo {
+            backend: env!("CARGO_PKG_VERSION").to_string(),
+            generated_ms: timestamp_ms,
+            firmware: capabilities.firmware.clone(),
+        },
+    );
+    bundle.add("config.json", state.config().redacted());
+    bundle.add("health.json", health(&state));
+    bundle.add("capabilities.json", capabilities);
+    bundle.add("positions.json", state.positions.lock().unwrap().to_vec());
+    bundle.add("library.json", state.library.lock().unwrap().clone());
+    bundle.add(
+        "audit.json",
+        state.audit.lock().unwrap().recent(SUPPORT_AUDIT_ENTRIES),
+    );
+    bundle.add("replication.json", replication_info(&state));
+
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = bundle.write_zip(SyncIoBridge::new(writer)) {
+            warn!("Support bundle incomplete: {:#}", e);
+        }
+    });
+
+    let disposition = format!(
+        "attachment; filename=\"robotarm-support-{}.zip\"",
+        timestamp_ms
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    ))
+}
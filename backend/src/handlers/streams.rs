@@ -0,0 +1,200 @@
+//! Handlers streaming motion in and positions and firmware lines out
+
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::Response;
+use axum::Json;
+use futures_util::StreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use super::{bad_request, run_move, to_servo_angles, ApiError, AppState};
+use crate::library::SequenceStep;
+use crate::models::{CommandQuery, ErrorResponse, StreamedSequence};
+use crate::streaming;
+
+/// Longest line of a streamed sequence
+const MAX_STREAM_LINE: usize = 64 * 1024;
+
+/// Stream target poses over a WebSocket through the safety clamp
+pub async fn stream_poses(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let signer = state.signer(&headers);
+    ws.on_upgrade(move |socket| streaming::session(state, socket, signer))
+}
+
+/// Send the known positions over a WebSocket, every frame in full or
+/// with `compact` as changes between keyframes
+pub async fn position_feed(
+    State(state): State<Arc<AppState>>,
+    Query(options): Query<streaming::FeedOptions>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    if !(1..=streaming::MAX_FEED_HZ).contains(&options.hz) {
+        return Err(bad_request(format!(
+            "hz must be 1-{}",
+            streaming::MAX_FEED_HZ
+        )));
+    }
+    if !(1..=streaming::MAX_KEYFRAME_EVERY).contains(&options.keyframe_every) {
+        return Err(bad_request(format!(
+            "keyframe_every must be 1-{}",
+            streaming::MAX_KEYFRAME_EVERY
+        )));
+    }
+    let signer = state.signer(&headers);
+    Ok(ws.on_upgrade(move |socket| streaming::feed_session(state, socket, options, signer)))
+}
+
+/// Send the unsolicited lines the firmware sends to a WebSocket as they
+/// are skipped
+pub async fn serial_monitor(State(state): State<Arc<AppState>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| monitor_session(state, socket))
+}
+
+async fn monitor_session(state: Arc<AppState>, mut socket: WebSocket) {
+    info!("Serial monitor connected");
+    let mut lines = state.observers.unsolicited.subscribe();
+    loop {
+        let line = tokio::select! {
+            line = lines.recv() => line,
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let line = match line {
+            Ok(line) => line,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Serial monitor fell behind, {} lines not sent", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let text = serde_json::to_string(&line).unwrap_or_default();
+        if socket.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+    info!("Serial monitor disconnected");
+}
+
+/// Play back a sequence streamed as newline-delimited JSON steps, each
+/// one as soon as its line has arrived
+///
+/// The body is read no faster than the steps play, so a sender producing
+/// steps faster than real time is held back by the connection. A step
+/// that fails to parse or validate ends the playback; the error names its
+/// line and how many steps were played before it.
+pub async fn stream_sequence(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CommandQuery>,
+    body: Body,
+) -> Result<Json<StreamedSequence>, ApiError> {
+    let serial = state.wait_for_serial(query.wait).await?;
+    let _motion = state.begin_motion()?;
+    let opts = query.options();
+
+    info!("Playing a streamed sequence");
+    let mut stream = body.into_data_stream();
+    let mut buffer = Vec::new();
+    let mut line = 0;
+    let mut steps = 0;
+    let mut duration = Duration::ZERO;
+    let mut ended = false;
+    while !ended {
+        match stream.next().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                let error = bad_request(format!("Failed to read body: {}", e));
+                return Err(at_line(error, line + 1, steps));
+            }
+            // The last line needn't end in a newline
+            None => {
+                ended = true;
+                buffer.push(b'\n');
+            }
+        }
+
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let text: Vec<u8> = buffer.drain(..=end).collect();
+            line += 1;
+            let Some(step) = streamed_step(&state, &text, steps, duration)
+                .map_err(|error| at_line(error, line, steps))?
+            else {
+                continue;
+            };
+            run_move(&state, &*serial, step.duration_ms, &step.angles, opts)
+                .map_err(|error| at_line(error, line, steps))?;
+            tokio::time::sleep(Duration::from_millis(step.duration_ms as u64)).await;
+            steps += 1;
+            duration += Duration::from_millis(2 * step.duration_ms as u64);
+        }
+        if buffer.len() > MAX_STREAM_LINE {
+            let error = (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(ErrorResponse::new(format!(
+                    "Line is longer than {} bytes",
+                    MAX_STREAM_LINE
+                ))),
+            );
+            return Err(at_line(error, line + 1, steps));
+        }
+    }
+
+    info!("Played a streamed sequence of {} steps", steps);
+    Ok(Json(StreamedSequence {
+        status: "ok".to_string(),
+        steps,
+    }))
+}
+
+/// Parse and check one line of a streamed sequence, `None` if blank;
+/// `steps` taking `duration` were played before it
+fn streamed_step(
+    state: &AppState,
+    text: &[u8],
+    steps: usize,
+    duration: Duration,
+) -> Result<Option<SequenceStep>, ApiError> {
+    let text = std::str::from_utf8(text)
+        .map_err(|_| bad_request("Line is not UTF-8".to_string()))?
+        .trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let step: SequenceStep =
+        serde_json::from_str(text).map_err(|e| bad_request(format!("Invalid step: {}", e)))?;
+
+    let config = state.config();
+    step.validate(&config)
+        .map_err(|e| bad_request(format!("{:#}", e)))?;
+    to_servo_angles(&config, &step.angles)?;
+    let duration = duration + Duration::from_millis(2 * step.duration_ms as u64);
+    config
+        .sequence_limits
+        .check(steps + 1, "steps", duration)
+        .map_err(|e| bad_request(format!("Sequence too long: {:#}", e)))?;
+    Ok(Some(step))
+}
+
+/// Point an error of a streamed sequence at its line
+fn at_line((status, Json(e)): ApiError, line: usize, steps: usize) -> ApiError {
+    let details = serde_json::json!({ "line": line, "steps_played": steps });
+    (
+        status,
+        Json(ErrorResponse {
+            error: format!("Line {}: {}", line, e.error),
+            details: Some(details),
+            ..e
+        }),
+    )
+}
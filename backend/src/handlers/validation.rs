@@ -0,0 +1,652 @@
+//! Handlers checking stored entries and candidate poses against the
+//! current config
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::{
+    bad_request, channel_locked, constraint_broken, map_targets, pose_too_far, to_servo_angle,
+    unprocessable, ApiError, AppState,
+};
+use crate::config::{Config, PoseViolation};
+use crate::constraints;
+use crate::library::{Pose, Sequence};
+use crate::models::{
+    CandidatePose, EntryReport, EntryViolation, ErrorResponse, LibraryFix, LibraryReport,
+    PoseAngles, PoseVerdict, ValidateLibraryQuery, ValidateRequest, ValidateResponse,
+};
+use crate::schedule::ScheduleStatus;
+use crate::serial::NUM_SERVOS;
+
+/// Check every stored pose, sequence, pending scheduled move and the home
+/// pose against the current config, as if executed now
+///
+/// Each angle goes through the checks of POSE and MOVE: the channel's
+/// kind, the firmware range and soft limits, and trim with the sag
+/// compensation for the arm at that pose. With `lockout_strict`, targets
+/// moving a locked-out channel fail as well. Nothing is changed; with
+/// `fix=clamp` each failing entry comes with a proposed fixed version.
+pub async fn validate_library(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ValidateLibraryQuery>,
+) -> Json<LibraryReport> {
+    let config = state.config();
+    let checker = EntryChecker {
+        state: &state,
+        config: &config,
+        known: state.confirmed_positions(),
+        locked: if config.lockout_strict {
+            state.lockout.disabled()
+        } else {
+            Vec::new()
+        },
+        fix: query.fix,
+    };
+    let mut checked = 0;
+    let mut failing = Vec::new();
+    let mut report = |kind, name: Option<String>, (violations, fixed): Checked| {
+        checked += 1;
+        if !violations.is_empty() {
+            failing.push(EntryReport {
+                kind,
+                name,
+                violations,
+                fixed,
+            });
+        }
+    };
+
+    {
+        let library = state.library.lock().unwrap();
+        for (name, pose) in &library.poses {
+            report("pose", Some(name.clone()), checker.pose(pose));
+        }
+        for (name, sequence) in &library.sequences {
+            report("sequence", Some(name.clone()), checker.sequence(sequence));
+        }
+    }
+    let (pending, _) = state.schedule.list();
+    for scheduled in pending.iter().filter(|s| s.status == ScheduleStatus::Pending) {
+        report("schedule", Some(scheduled.id.to_string()), checker.scheduled(&scheduled.angles));
+    }
+    if let Some(home) = &config.home {
+        report("home", None, checker.home(home));
+    }
+
+    Json(LibraryReport {
+        ok: failing.is_empty(),
+        checked,
+        failing,
+    })
+}
+
+/// Check a batch of candidate poses without touching the arm
+///
+/// Each pose is checked as `POST /api/pose` would check it while the arm
+/// is at its known positions: the channels' kinds, the firmware range and
+/// soft limits, trim with the sag compensation, strict lockouts and a
+/// rejecting `[pose_guard]`. Channels without a known position aren't
+/// checked against the guard. The stores are read once for the batch.
+pub async fn validate_poses(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ValidateRequest>,
+) -> Result<Json<ValidateResponse>, ApiError> {
+    let config = state.config();
+    if req.poses.len() > config.validate_max_poses {
+        let mut error = ErrorResponse::with_code(
+            "BATCH_TOO_LARGE",
+            format!(
+                "{} poses given, at most {} can be validated at once",
+                req.poses.len(),
+                config.validate_max_poses
+            ),
+        );
+        error.details = Some(serde_json::json!({ "max": config.validate_max_poses }));
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, Json(error)));
+    }
+
+    let checker = EntryChecker {
+        state: &state,
+        config: &config,
+        known: state.confirmed_positions(),
+        locked: if config.lockout_strict {
+            state.lockout.disabled()
+        } else {
+            Vec::new()
+        },
+        fix: None,
+    };
+    let results: Vec<_> = (0..)
+        .zip(req.poses)
+        .map(|(index, pose)| {
+            let (id, angles) = match pose {
+                CandidatePose::Angles(angles) => (None, angles),
+                CandidatePose::Identified { id, angles } => (id, angles),
+            };
+            let violations = checker.candidate(&angles);
+            PoseVerdict {
+                index,
+                id,
+                ok: violations.is_empty(),
+                violations,
+            }
+        })
+        .collect();
+
+    Ok(Json(ValidateResponse {
+        ok: results.iter().all(|result| result.ok),
+        checked: results.len(),
+        results,
+    }))
+}
+
+/// Violations of one entry, and its proposed fix
+type Checked = (Vec<EntryViolation>, Option<serde_json::Value>);
+
+/// Runs the command checks over stored entries, see [`validate_library`]
+struct EntryChecker<'a> {
+    state: &'a AppState,
+    config: &'a Config,
+    known: [Option<u16>; NUM_SERVOS as usize],
+    /// Locked-out channels, if targets moving them are refused
+    locked: Vec<u8>,
+    fix: Option<LibraryFix>,
+}
+
+fn violation(
+    step: Option<usize>,
+    channel: Option<u8>,
+    angle: Option<u16>,
+    e: ApiError,
+) -> EntryViolation {
+    let (_, Json(error)) = e;
+    EntryViolation {
+        step,
+        channel,
+        angle,
+        code: error.code.unwrap_or_default(),
+        error: error.error,
+    }
+}
+
+impl EntryChecker<'_> {
+    fn pose(&self, pose: &Pose) -> Checked {
+        let mut violations = Vec::new();
+        if let Err(e) = pose.preconditions.validate(self.config) {
+            violations.push(violation(None, None, None, bad_request(format!("{:#}", e))));
+        }
+        let targets: Vec<_> = pose.angles.iter().copied().map(Some).collect();
+        let fixed = self.angles(&targets, None, &mut violations).map(|angles| {
+            let fixed = Pose {
+                angles: angles.into_iter().flatten().collect(),
+                ..pose.clone()
+            };
+            serde_json::to_value(fixed).unwrap_or_default()
+        });
+        self.finish(violations, fixed)
+    }
+
+    fn sequence(&self, sequence: &Sequence) -> Checked {
+        let mut violations = Vec::new();
+        if sequence.steps.is_empty() {
+            let e = bad_request("Sequence has no steps".to_string());
+            violations.push(violation(None, None, None, e));
+        }
+        if let Err(e) = sequence.check_limits(self.config) {
+            let e = unprocessable("SEQUENCE_TOO_LONG", format!("{:#}", e));
+            violations.push(violation(None, None, None, e));
+        }
+        if let Err(e) = sequence.preconditions.validate(self.config) {
+            violations.push(violation(None, None, None, bad_request(format!("{:#}", e))));
+        }
+        let mut fixed = Some(sequence.clone());
+        for (i, step) in sequence.steps.iter().enumerate() {
+            let targets: Vec<_> = step.angles.iter().copied().map(Some).collect();
+            let angles = self.angles(&targets, Some(i), &mut violations);
+            match (fixed.as_mut(), angles) {
+                (Some(fixed), Some(angles)) => {
+                    fixed.steps[i].angles = angles.into_iter().flatten().collect();
+                }
+                _ => fixed = None,
+            }
+        }
+        let fixed = fixed.map(|fixed| serde_json::to_value(fixed).unwrap_or_default());
+        self.finish(violations, fixed)
+    }
+
+    fn scheduled(&self, angles: &PoseAngles) -> Checked {
+        let mut violations = Vec::new();
+        let fixed = match angles {
+            PoseAngles::List(list) => {
+                let targets: Vec<_> = list.iter().copied().map(Some).collect();
+                self.angles(&targets, None, &mut violations).map(|angles| {
+                    let list: Vec<u16> = angles.into_iter().flatten().collect();
+                    serde_json::json!({ "angles": list })
+                })
+            }
+            PoseAngles::Map(map) => match map_targets(self.config, map) {
+                Ok(targets) => self.angles(&targets, None, &mut violations).map(|angles| {
+                    let map: BTreeMap<String, u16> = (0..)
+                        .zip(angles)
+                        .filter_map(|(channel, angle): (u8, _)| Some((channel.to_string(), angle?)))
+                        .collect();
+                    serde_json::json!({ "angles": map })
+                }),
+                Err(e) => {
+                    violations.push(violation(None, None, None, e));
+                    None
+                }
+            },
+        };
+        self.finish(violations, fixed)
+    }
+
+    fn home(&self, home: &[u16]) -> Checked {
+        let mut violations = Vec::new();
+        let targets: Vec<_> = home.iter().copied().map(Some).collect();
+        let fixed = self.angles(&targets, None, &mut violations).map(|angles| {
+            let home: Vec<u16> = angles.into_iter().flatten().collect();
+            serde_json::json!(home)
+        });
+        self.finish(violations, fixed)
+    }
+
+    /// Check the targets of one pose, channels without one holding their
+    /// position; the fixed targets if every failing one has a fix
+    fn angles(
+        &self,
+        targets: &[Option<u16>],
+        step: Option<usize>,
+        violations: &mut Vec<EntryViolation>,
+    ) -> Option<Vec<Option<u16>>> {
+        let mut context = self.known;
+        for (known, target) in context.iter_mut().zip(targets) {
+            if target.is_some() {
+                *known = *target;
+            }
+        }
+
+        let mut fixed = targets.to_vec();
+        let mut fixable = true;
+        for (channel, target) in (0..).zip(targets) {
+            let Some(angle) = *target else {
+                continue;
+            };
+            if self.locked.contains(&channel) && self.known[channel as usize] != Some(angle) {
+                let e = channel_locked(channel);
+                violations.push(violation(step, Some(channel), Some(angle), e));
+                match self.known[channel as usize] {
+                    Some(current) => fixed[channel as usize] = Some(current),
+                    None => fixable = false,
+                }
+                continue;
+            }
+            let correction = self.state.compensation.correction(channel, &context);
+            let Err(e) = to_servo_angle(self.config, channel, angle, correction) else {
+                continue;
+            };
+            violations.push(violation(step, Some(channel), Some(angle), e));
+            // Searched for only if asked for, as it tries every angle
+            match self.fix.and_then(|_| self.sendable_angle(channel, angle, correction)) {
+                Some(angle) => fixed[channel as usize] = Some(angle),
+                None => fixable = false,
+            }
+        }
+        let pose = constraints::Angles {
+            config: self.config,
+            angles: &context,
+        };
+        if let Err(broken) = constraints::check(self.config, &pose) {
+            violations.push(violation(step, None, None, constraint_broken(broken)));
+            // Which joint to change is the author's call
+            fixable = false;
+        }
+        fixable.then_some(fixed)
+    }
+
+    /// Check a planner's candidate pose as `POST /api/pose` would, with
+    /// the pose guard against the known positions
+    fn candidate(&self, angles: &[u16]) -> Vec<EntryViolation> {
+        let mut violations = Vec::new();
+        if angles.len() > NUM_SERVOS as usize {
+            let e = unprocessable(
+                "TOO_MANY_ANGLES",
+                format!("Pose has {} angles, the arm has {} channels", angles.len(), NUM_SERVOS),
+            );
+            violations.push(violation(None, None, None, e));
+            return violations;
+        }
+        let targets: Vec<_> = angles.iter().copied().map(Some).collect();
+        self.angles(&targets, None, &mut violations);
+        let guard = &self.config.pose_guard;
+        if let (Some(max_jump), PoseViolation::Reject) = (guard.max_jump, &guard.on_violation) {
+            for ((channel, &angle), known) in (0..).zip(angles).zip(self.known) {
+                let Some(distance) = known.map(|known| angle.abs_diff(known)) else {
+                    continue;
+                };
+                if distance > max_jump {
+                    let e = pose_too_far(channel, distance, max_jump);
+                    violations.push(violation(None, Some(channel), Some(angle), e));
+                }
+            }
+        }
+        violations
+    }
+
+    /// The angle nearest `angle` that [`to_servo_angle`] accepts, if any
+    fn sendable_angle(&self, channel: u8, angle: u16, correction: f64) -> Option<u16> {
+        (0..=self.config.protocol.max_angle)
+            .filter(|&candidate| {
+                to_servo_angle(self.config, channel, candidate, correction).is_ok()
+            })
+            .min_by_key(|&candidate| candidate.abs_diff(angle))
+    }
+
+    /// Drop the fix unless asked for, or if there is nothing to fix
+    fn finish(&self, violations: Vec<EntryViolation>, fixed: Option<serde_json::Value>) -> Checked {
+        let wanted = self.fix == Some(LibraryFix::Clamp) && !violations.is_empty();
+        (violations, fixed.filter(|_| wanted))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ChannelKind, ServoConfig};
+    use crate::testing::TestServer;
+    use reqwest::Method;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A library, a scheduled move and a home pose all valid under the
+    /// default config, and then the config tightened under them: servo 1
+    /// limited to 30-150, servo 2 trimmed by +10, channel 4 a PWM output
+    /// and the home pose outside servo 1's limits
+    async fn tightened() -> TestServer {
+        let server = TestServer::start().await;
+        for (name, angles) in [
+            ("low", json!([90, 10])),
+            ("ok", json!([90, 90, 90])),
+            ("output", json!([90, 90, 90, 90, 90])),
+        ] {
+            let reply = server
+                .put(&format!("/api/poses/{}", name), json!({ "angles": angles }))
+                .await;
+            assert_eq!(reply.status, 200, "{:?}", reply.body);
+        }
+        for (name, steps) in [
+            (
+                "steady",
+                json!([{ "duration_ms": 500, "angles": [90, 90, 90] }]),
+            ),
+            (
+                "wave",
+                json!([
+                    { "duration_ms": 500, "angles": [90, 90, 90] },
+                    { "duration_ms": 500, "angles": [90, 60, 175] }
+                ]),
+            ),
+        ] {
+            let reply = server
+                .put(
+                    &format!("/api/sequences/{}", name),
+                    json!({ "steps": steps }),
+                )
+                .await;
+            assert_eq!(reply.status, 200, "{:?}", reply.body);
+        }
+        let scheduled = json!({ "duration_ms": 500, "angles": [90, 160], "delay_ms": 60_000 });
+        let reply = server.post("/api/move/schedule", scheduled).await;
+        assert_eq!(reply.status, 202, "{:?}", reply.body);
+
+        let state = server.server.state();
+        let mut config = (*state.config()).clone();
+        config.home = Some(vec![90, 20]);
+        config.servos = vec![
+            ServoConfig {
+                channel: 1,
+                min: 30,
+                max: 150,
+                ..ServoConfig::default()
+            },
+            ServoConfig {
+                channel: 2,
+                trim: 10,
+                ..ServoConfig::default()
+            },
+            ServoConfig {
+                channel: 4,
+                kind: ChannelKind::PwmOutput,
+                ..ServoConfig::default()
+            },
+        ];
+        *state.config.lock().unwrap() = Arc::new(config);
+        server
+    }
+
+    /// The violations of a report entry as `(step, channel, angle, code)`
+    fn violations(entry: &Value) -> Vec<(Value, Value, Value, String)> {
+        entry["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| {
+                let code = v["code"].as_str().unwrap().to_string();
+                (
+                    v["step"].clone(),
+                    v["channel"].clone(),
+                    v["angle"].clone(),
+                    code,
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn valid_entries_pass() {
+        let server = TestServer::start().await;
+        server
+            .put("/api/poses/ok", json!({ "angles": [90, 90] }))
+            .await;
+        let reply = server.get("/api/validate-library").await;
+        assert_eq!(
+            reply.body,
+            json!({ "ok": true, "checked": 1, "failing": [] })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn violating_entries_are_grouped_by_entry() {
+        let server = tightened().await;
+        let report = server.get("/api/validate-library").await.body;
+        assert_eq!(report["ok"], false);
+        // 3 poses, 2 sequences, the scheduled move and the home pose
+        assert_eq!(report["checked"], 7);
+        let failing = report["failing"].as_array().unwrap();
+        let entries: Vec<_> = failing
+            .iter()
+            .map(|entry| (entry["kind"].as_str().unwrap(), entry["name"].as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("pose", Some("low")),
+                ("pose", Some("output")),
+                ("sequence", Some("wave")),
+                ("schedule", Some("1")),
+                ("home", None)
+            ]
+        );
+
+        let null = Value::Null;
+        assert_eq!(
+            violations(&failing[0]),
+            [(null.clone(), json!(1), json!(10), "SOFT_LIMIT".to_string())]
+        );
+        assert_eq!(
+            violations(&failing[1]),
+            [(null.clone(), json!(4), json!(90), "NOT_A_SERVO".to_string())]
+        );
+        assert_eq!(
+            violations(&failing[2]),
+            [(json!(1), json!(2), json!(175), "FIRMWARE_RANGE".to_string())]
+        );
+        assert_eq!(
+            violations(&failing[3]),
+            [(null.clone(), json!(1), json!(160), "SOFT_LIMIT".to_string())]
+        );
+        assert_eq!(
+            violations(&failing[4]),
+            [(null, json!(1), json!(20), "SOFT_LIMIT".to_string())]
+        );
+        // Fixes only when asked for
+        assert!(failing.iter().all(|entry| entry.get("fixed").is_none()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clamp_proposes_fixes_without_applying_them() {
+        let server = tightened().await;
+        let report = server.get("/api/validate-library?fix=clamp").await.body;
+        let fixed: Vec<_> = report["failing"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.get("fixed").cloned())
+            .collect();
+        assert_eq!(
+            fixed,
+            [
+                Some(json!({ "angles": [90, 30] })),
+                // A PWM output has no angle to clamp to
+                None,
+                Some(json!({
+                    "steps": [
+                        { "duration_ms": 500, "angles": [90, 90, 90] },
+                        { "duration_ms": 500, "angles": [90, 60, 170] }
+                    ]
+                })),
+                Some(json!({ "angles": [90, 150] })),
+                Some(json!([90, 30])),
+            ]
+        );
+        assert_eq!(
+            server.get("/api/poses/low").await.body["angles"],
+            json!([90, 10])
+        );
+        assert_eq!(server.server.state().config().home, Some(vec![90, 20]));
+        assert!(server.mock.take_commands().is_empty());
+    }
+
+    /// Served with a populated library, a sag compensation model, a
+    /// strict lockout of channel 5 and servo 1 limited to 150 degrees
+    async fn populated() -> TestServer {
+        let server = TestServer::with_config(|config| {
+            config.lockout_strict = true;
+            config.servos = vec![ServoConfig {
+                channel: 1,
+                max: 150,
+                ..ServoConfig::default()
+            }];
+        })
+        .await;
+        let model = json!({ "inputs": [1], "degree": 1, "coefficients": [1, 0.01] });
+        let reply = server.put("/api/compensation/2", model).await;
+        assert_eq!(reply.status, 200, "{:?}", reply.body);
+        let reply = server
+            .admin(Method::POST, "/api/servo/5/disable", None)
+            .await;
+        assert_eq!(reply.status, 200, "{:?}", reply.body);
+        let library = serde_json::from_value(json!({
+            "poses": (0..500)
+                .map(|i| (format!("pose-{}", i), json!({ "angles": [i % 150, 90, 90] })))
+                .collect::<serde_json::Map<_, _>>(),
+            "sequences": (0..50)
+                .map(|i| {
+                    let steps = vec![json!({ "duration_ms": 500, "angles": [90, i, 90] }); 20];
+                    (format!("sequence-{}", i), json!({ "steps": steps }))
+                })
+                .collect::<serde_json::Map<_, _>>(),
+        }))
+        .unwrap();
+        *server.server.state().library.lock().unwrap() = library;
+        server.mock.take_commands();
+        server
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_full_batch_is_validated_in_order_and_quickly() {
+        let server = populated().await;
+        let max = server.server.state().config().validate_max_poses;
+        assert_eq!(max, 1000);
+        // Every other pose with an id; servo 1 beyond its limit in some,
+        // the locked-out channel moved in others
+        let poses: Vec<Value> = (0..max as u16)
+            .map(|i| {
+                let mut angles = vec![90, i % 181, 90];
+                if i % 7 == 0 {
+                    angles.extend([90, 90, 45]);
+                }
+                match i % 2 {
+                    0 => json!({ "id": format!("candidate-{}", i), "angles": angles }),
+                    _ => json!(angles),
+                }
+            })
+            .collect();
+
+        let started = std::time::Instant::now();
+        let reply = server
+            .post("/api/validate", json!({ "poses": poses }))
+            .await;
+        let elapsed = started.elapsed();
+        assert_eq!(reply.status, 200);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+
+        assert_eq!(reply.body["ok"], false);
+        assert_eq!(reply.body["checked"], max);
+        let results = reply.body["results"].as_array().unwrap();
+        assert_eq!(results.len(), max);
+        for (i, result) in (0..).zip(results) {
+            assert_eq!(result["index"], i);
+            match i % 2 {
+                0 => assert_eq!(result["id"], format!("candidate-{}", i)),
+                _ => assert!(result.get("id").is_none(), "{}", i),
+            }
+            let mut expected = Vec::new();
+            if i % 181 > 150 {
+                expected.push("SOFT_LIMIT");
+            }
+            if i % 7 == 0 {
+                expected.push("CHANNEL_DISABLED");
+            }
+            let codes: Vec<_> = result["violations"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v["code"].as_str().unwrap())
+                .collect();
+            assert_eq!(codes, expected, "{}", i);
+            assert_eq!(result["ok"], expected.is_empty());
+        }
+        assert!(server.mock.take_commands().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batches_beyond_the_maximum_are_refused() {
+        let server = TestServer::start().await;
+        let poses = vec![json!([90]); 1001];
+        let reply = server
+            .post("/api/validate", json!({ "poses": poses }))
+            .await;
+        assert_eq!(reply.status, 413);
+        assert_eq!(reply.code(), "BATCH_TOO_LARGE");
+        assert_eq!(reply.body["details"], json!({ "max": 1000 }));
+        assert_eq!(
+            reply.body["error"],
+            "1001 poses given, at most 1000 can be validated at once"
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::audit;
+use crate::serial::NUM_SERVOS;
+
+/// Entries kept; the oldest are dropped beyond this (about half an hour
+/// of poses streamed at 50 Hz)
+const MAX_ENTRIES: usize = 100_000;
+
+/// Known angles of every channel, `None` where unknown
+pub type Angles = [Option<u16>; NUM_SERVOS as usize];
+
+/// One point of the angle history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Entry {
+    /// The known angles after a command or read-back
+    Sample { at_ms: u64, angles: Angles },
+    /// The link went down or the positions were lost; nothing is known
+    /// until the next sample
+    Gap { at_ms: u64 },
+}
+
+impl Entry {
+    pub fn at_ms(&self) -> u64 {
+        match *self {
+            Entry::Sample { at_ms, .. } | Entry::Gap { at_ms } => at_ms,
+        }
+    }
+}
+
+/// Recent changes of the known positions, for exports
+#[derive(Default)]
+pub struct AngleHistory {
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl AngleHistory {
+    /// Record the known angles as of now
+    pub fn record(&self, angles: &Angles) {
+        self.push(Entry::Sample {
+            at_ms: audit::now_ms(),
+            angles: *angles,
+        });
+    }
+
+    /// Record that the positions are unknown from now on
+    pub fn gap(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        // Repeated failures while disconnected
+        if !matches!(entries.back(), Some(Entry::Gap { .. })) {
+            entries.push_back(Entry::Gap {
+                at_ms: audit::now_ms(),
+            });
+        }
+    }
+
+    fn push(&self, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The entries relevant to `from_ms..=to_ms`: those in the range plus
+    /// the last one before it
+    pub fn window(&self, from_ms: u64, to_ms: u64) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap();
+        let start = entries
+            .partition_point(|entry| entry.at_ms() <= from_ms)
+            .saturating_sub(1);
+        entries
+            .range(start..)
+            .take_while(|entry| entry.at_ms() <= to_ms)
+            .copied()
+            .collect()
+    }
+
+    /// The last entry at or before `at_ms`
+    pub fn at(&self, at_ms: u64) -> Option<Entry> {
+        let entries = self.entries.lock().unwrap();
+        let end = entries.partition_point(|entry| entry.at_ms() <= at_ms);
+        end.checked_sub(1).map(|i| entries[i])
+    }
+
+    /// Time of the oldest entry kept
+    pub fn first_ms(&self) -> Option<u64> {
+        self.entries.lock().unwrap().front().map(Entry::at_ms)
+    }
+}
+
+/// Angles on the uniform grid `from_ms, from_ms + step_ms, ..` up to
+/// `to_ms`, from entries in time order
+///
+/// Between two samples each channel is interpolated linearly; after the
+/// last sample before a gap (or the end of the history) it holds its
+/// angle. Grid points before the first sample, within a gap or where a
+/// channel's angle is unknown are `None`.
+pub fn resample(
+    entries: &[Entry],
+    from_ms: u64,
+    to_ms: u64,
+    step_ms: u64,
+) -> Vec<[Option<f64>; NUM_SERVOS as usize]> {
+    let mut grid = Vec::new();
+    let mut next = 0;
+    let mut t = from_ms;
+    while t <= to_ms {
+        while next < entries.len() && entries[next].at_ms() <= t {
+            next += 1;
+        }
+        let angles = match (next.checked_sub(1).map(|i| entries[i]), entries.get(next)) {
+            (
+                Some(Entry::Sample { at_ms, angles }),
+                Some(&Entry::Sample {
+                    at_ms: until_ms,
+                    angles: until,
+                }),
+            ) => interpolate(
+                &angles,
+                &until,
+                (t - at_ms) as f64 / (until_ms - at_ms) as f64,
+            ),
+            (Some(Entry::Sample { angles, .. }), _) => angles.map(|angle| angle.map(f64::from)),
+            (Some(Entry::Gap { .. }) | None, _) => [None; NUM_SERVOS as usize],
+        };
+        grid.push(angles);
+        t += step_ms;
+    }
+    grid
+}
+
+/// Angles `fraction` of the way from `from` to `to`; a channel known only
+/// at the start holds its angle
+fn interpolate(from: &Angles, to: &Angles, fraction: f64) -> [Option<f64>; NUM_SERVOS as usize] {
+    let mut angles = from.map(|angle| angle.map(f64::from));
+    for (angle, &target) in angles.iter_mut().zip(to) {
+        if let (Some(start), Some(end)) = (*angle, target) {
+            *angle = Some(start + (end as f64 - start) * fraction);
+        }
+    }
+    angles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(at_ms: u64, known: &[u16]) -> Entry {
+        let mut angles = [None; NUM_SERVOS as usize];
+        for (angle, &known) in angles.iter_mut().zip(known) {
+            *angle = Some(known);
+        }
+        Entry::Sample { at_ms, angles }
+    }
+
+    /// Channel 0 of each grid point
+    fn first(grid: &[[Option<f64>; NUM_SERVOS as usize]]) -> Vec<Option<f64>> {
+        grid.iter().map(|angles| angles[0]).collect()
+    }
+
+    #[test]
+    fn samples_are_interpolated_on_the_grid() {
+        let entries = [sample(1000, &[0, 90]), sample(2000, &[100, 90])];
+        let grid = resample(&entries, 1000, 2000, 250);
+        assert_eq!(
+            first(&grid),
+            [Some(0.0), Some(25.0), Some(50.0), Some(75.0), Some(100.0)]
+        );
+        assert!(grid.iter().all(|angles| angles[1] == Some(90.0)));
+        assert!(grid.iter().all(|angles| angles[2].is_none()));
+    }
+
+    #[test]
+    fn nothing_is_known_before_the_first_sample() {
+        let entries = [sample(1000, &[40])];
+        let grid = resample(&entries, 500, 1500, 250);
+        assert_eq!(
+            first(&grid),
+            [None, None, Some(40.0), Some(40.0), Some(40.0)]
+        );
+    }
+
+    #[test]
+    fn gap_is_not_interpolated_across() {
+        let entries = [
+            sample(1000, &[0]),
+            sample(2000, &[100]),
+            Entry::Gap { at_ms: 2500 },
+            sample(4000, &[180]),
+            sample(5000, &[80]),
+        ];
+        let grid = resample(&entries, 1000, 5000, 500);
+        assert_eq!(
+            first(&grid),
+            [
+                Some(0.0),
+                Some(50.0),
+                // Held after the last sample before the gap
+                Some(100.0),
+                // Within the gap, not between 100 and 180
+                None,
+                None,
+                None,
+                Some(180.0),
+                Some(130.0),
+                Some(80.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn gap_right_after_a_sample_leaves_nothing_to_hold() {
+        let entries = [
+            sample(1000, &[10]),
+            Entry::Gap { at_ms: 1000 },
+            sample(2000, &[20]),
+        ];
+        let grid = resample(&entries, 1000, 2000, 500);
+        assert_eq!(first(&grid), [None, None, Some(20.0)]);
+    }
+
+    #[test]
+    fn channel_unknown_at_one_end_holds_or_stays_unknown() {
+        let mut before = [None; NUM_SERVOS as usize];
+        before[0] = Some(50);
+        let entries = [
+            Entry::Sample {
+                at_ms: 0,
+                angles: before,
+            },
+            sample(1000, &[150, 30]),
+        ];
+        let grid = resample(&entries, 0, 1000, 500);
+        assert_eq!(first(&grid), [Some(50.0), Some(100.0), Some(150.0)]);
+        // Channel 1 is only known at the end
+        let second: Vec<_> = grid.iter().map(|angles| angles[1]).collect();
+        assert_eq!(second, [None, None, Some(30.0)]);
+    }
+
+    #[test]
+    fn window_starts_with_the_entry_before_it() {
+        let history = AngleHistory::default();
+        for entry in [
+            sample(1000, &[1]),
+            sample(2000, &[2]),
+            Entry::Gap { at_ms: 3000 },
+            sample(4000, &[4]),
+        ] {
+            history.push(entry);
+        }
+        let at: Vec<u64> = history
+            .window(2500, 3500)
+            .iter()
+            .map(Entry::at_ms)
+            .collect();
+        assert_eq!(at, [2000, 3000]);
+        assert_eq!(history.at(3999), Some(Entry::Gap { at_ms: 3000 }));
+        assert_eq!(history.at(999), None);
+        assert_eq!(history.first_ms(), Some(1000));
+    }
+
+    #[test]
+    fn repeated_gaps_are_recorded_once() {
+        let history = AngleHistory::default();
+        history.record(&[Some(90); NUM_SERVOS as usize]);
+        history.gap();
+        history.gap();
+        let entries = history.window(0, u64::MAX);
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(entries[1], Entry::Gap { .. }));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn state_at_replays_a_synthetic_history() {
+        let server = crate::testing::TestServer::start().await;
+        let history = &server.server.state().history;
+        let now = audit::now_ms();
+        history.entries.lock().unwrap().clear();
+        for entry in [
+            sample(now - 5000, &[10, 20]),
+            sample(now - 4000, &[30, 20]),
+            Entry::Gap { at_ms: now - 3000 },
+            Entry::Sample {
+                at_ms: now - 2000,
+                angles: [Some(40), None, None, None, None, None],
+            },
+        ] {
+            history.push(entry);
+        }
+        let state_at = |t: u64| {
+            let server = &server;
+            async move { server.get(&format!("/api/state-at?t={}", t)).await.body }
+        };
+        let known = |body: &serde_json::Value| -> Vec<Option<u64>> {
+            let channels = body["channels"].as_array().unwrap();
+            assert_eq!(channels.len(), NUM_SERVOS as usize);
+            channels
+                .iter()
+                .map(|channel| {
+                    assert_eq!(channel["known"], channel.get("angle").is_some());
+                    channel.get("angle").and_then(|angle| angle.as_u64())
+                })
+                .collect()
+        };
+
+        // Before the history starts
+        let body = state_at(now - 6000).await;
+        assert_eq!(known(&body), [None; NUM_SERVOS as usize]);
+        assert_eq!(body["history_from_ms"], now - 5000);
+        assert_eq!(body["positions_lost"], false);
+        assert!(body.get("since_ms").is_none());
+
+        // On a sample and between two
+        let body = state_at(now - 5000).await;
+        assert_eq!(known(&body)[..3], [Some(10), Some(20), None]);
+        assert_eq!(body["since_ms"], now - 5000);
+        let body = state_at(now - 3500).await;
+        assert_eq!(known(&body)[..3], [Some(30), Some(20), None]);
+        assert_eq!(body["since_ms"], now - 4000);
+
+        // During the gap
+        let body = state_at(now - 2500).await;
+        assert_eq!(known(&body), [None; NUM_SERVOS as usize]);
+        assert_eq!(body["positions_lost"], true);
+        assert_eq!(body["since_ms"], now - 3000);
+
+        // Known again only as far as read back
+        let body = state_at(now).await;
+        assert_eq!(known(&body)[..2], [Some(40), None]);
+        assert_eq!(body["positions_lost"], false);
+        assert!(body.get("job").is_none());
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::handlers::AppState;
+use crate::serial::{SerialMode, NUM_SERVOS};
+
+/// How often the idle time is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Return the firmware to button mode once nothing commanded the arm for
+/// `idle_exit.timeout_ms`
+///
+/// Only serial mode is left, and never during an estimated motion. The
+/// next motion command enters serial mode again before it is sent, see
+/// [`SerialManager::stop_for_idle`].
+///
+/// [`SerialManager::stop_for_idle`]: crate::serial::SerialManager::stop_for_idle
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        state.clock.sleep(CHECK_INTERVAL).await;
+        let config = state.config();
+        let timeout = Duration::from_millis(config.idle_exit.timeout_ms);
+        if !config.idle_exit.enabled || state.idle_for() < timeout {
+            continue;
+        }
+        let Some(serial) = state.serial.lock().unwrap().clone() else {
+            continue;
+        };
+        let moving = (0..NUM_SERVOS).any(|channel| state.motion_remaining(channel).is_some());
+        if serial.mode() != Some(SerialMode::Serial) || moving {
+            continue;
+        }
+        let result = tokio::task::spawn_blocking(move || serial.stop_for_idle()).await;
+        if let Ok(Err(e)) = result {
+            warn!("Failed to exit serial mode after idling: {:#}", e);
+            // Not again before another idle timeout
+            state.note_command();
+        }
+    }
+}
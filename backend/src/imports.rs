@@ -0,0 +1,495 @@
+use axum::body::Body;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::audit;
+use crate::config::Config;
+use crate::handlers::{self, ApiError, AppState};
+use crate::library::{Sequence, StepError};
+use crate::models::ErrorResponse;
+
+/// Bodies up to this size are imported while the request waits
+const SYNC_IMPORT_BYTES: usize = 256 * 1024;
+
+/// Largest accepted import body
+const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Invalid steps listed per import; the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 50;
+
+/// Finished imports kept for `GET /api/imports/:id`
+const MAX_FINISHED_IMPORTS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// State and outcome of a sequence import
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportRecord {
+    pub id: u64,
+    /// Name the sequence is saved under
+    pub name: String,
+    pub status: ImportStatus,
+    pub bytes: usize,
+    pub started_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_ms: Option<u64>,
+    /// Steps in the imported sequence, once parsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The first invalid steps
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub step_errors: Vec<StepError>,
+    /// Number of invalid steps, including those not listed
+    #[serde(skip_serializing_if = "is_zero")]
+    pub step_error_count: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// Sequence imports, running and recently finished
+#[derive(Default)]
+pub struct ImportJobs {
+    inner: Mutex<Imports>,
+}
+
+#[derive(Default)]
+struct Imports {
+    next_id: u64,
+    records: BTreeMap<u64, Arc<Mutex<ImportRecord>>>,
+}
+
+/// A request body, in memory or spooled to disk
+enum Received {
+    Memory(Vec<u8>),
+    File(TempFile, usize),
+}
+
+/// Temporary file removed when dropped
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl ImportJobs {
+    /// Import a sequence body, synchronously (200) if it is small and as a
+    /// background job (202) otherwise
+    pub async fn import(
+        &self,
+        state: Arc<AppState>,
+        name: String,
+        headers: HeaderMap,
+        body: Body,
+    ) -> Result<(StatusCode, ImportRecord), ApiError> {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_id += 1;
+            inner.next_id
+        };
+
+        match receive(body, id).await? {
+            Received::Memory(bytes) => {
+                let mut record = new_record(id, name, bytes.len());
+                import(&state, &headers, &mut record, bytes.as_slice());
+                self.register(id, Arc::new(Mutex::new(record.clone())));
+                match record.status {
+                    ImportStatus::Failed => Err(invalid(record)),
+                    _ => Ok((StatusCode::OK, record)),
+                }
+            }
+            Received::File(file, bytes) => {
+                let record = Arc::new(Mutex::new(new_record(id, name, bytes)));
+                self.register(id, record.clone());
+                info!(
+                    "Importing {} bytes in the background (import {})",
+                    bytes, id
+                );
+
+                let snapshot = record.lock().unwrap().clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut result = record.lock().unwrap().clone();
+                    match std::fs::File::open(&file.0) {
+                        Ok(reader) => import(
+                            &state,
+                            &headers,
+                            &mut result,
+                            std::io::BufReader::new(reader),
+                        ),
+                        Err(e) => fail(&mut result, format!("Failed to read import: {}", e)),
+                    }
+                    drop(file);
+                    *record.lock().unwrap() = result;
+                });
+                Ok((StatusCode::ACCEPTED, snapshot))
+            }
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<ImportRecord> {
+        let record = self.inner.lock().unwrap().records.get(&id)?.clone();
+        let record = record.lock().unwrap().clone();
+        Some(record)
+    }
+
+    /// Keep an import's record, dropping the oldest finished ones
+    fn register(&self, id: u64, record: Arc<Mutex<ImportRecord>>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.records.insert(id, record);
+        let finished: Vec<u64> = inner
+            .records
+            .iter()
+            .filter(|(_, record)| record.lock().unwrap().status != ImportStatus::Running)
+            .map(|(&id, _)| id)
+            .collect();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_IMPORTS);
+        for id in &finished[..excess] {
+            inner.records.remove(id);
+        }
+    }
+}
+
+fn new_record(id: u64, name: String, bytes: usize) -> ImportRecord {
+    ImportRecord {
+        id,
+        name,
+        status: ImportStatus::Running,
+        bytes,
+        started_ms: audit::now_ms(),
+        finished_ms: None,
+        steps: None,
+        error: None,
+        step_errors: Vec::new(),
+        step_error_count: 0,
+    }
+}
+
+/// Read the body, keeping it in memory up to [`SYNC_IMPORT_BYTES`] and
+/// streaming it to a temporary file beyond that
+async fn receive(body: Body, id: u64) -> Result<Received, ApiError> {
+    let mut stream = body.into_data_stream();
+    let mut buffer = Vec::new();
+    let mut spool: Option<(TempFile, tokio::fs::File)> = None;
+    let mut bytes = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| bad_request(format!("Failed to read body: {}", e)))?;
+        bytes += chunk.len();
+        if bytes > MAX_IMPORT_BYTES {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(ErrorResponse::new(format!(
+                    "Import is larger than {} bytes",
+                    MAX_IMPORT_BYTES
+                ))),
+            ));
+        }
+
+        if spool.is_none() && buffer.len() + chunk.len() > SYNC_IMPORT_BYTES {
+            let path = std::env::temp_dir().join(format!(
+                "robotarm-import-{}-{}.json",
+                std::process::id(),
+                id
+            ));
+            let file = tokio::fs::File::create(&path)
+                .await
+                .map_err(|e| spool_error(&e))?;
+            spool = Some((TempFile(path), file));
+        }
+        match &mut spool {
+            Some((_, file)) => {
+                if !buffer.is_empty() {
+                    file.write_all(&buffer).await.map_err(|e| spool_error(&e))?;
+                    buffer = Vec::new();
+                }
+                file.write_all(&chunk).await.map_err(|e| spool_error(&e))?;
+            }
+            None => buffer.extend_from_slice(&chunk),
+        }
+    }
+
+    match spool {
+        Some((path, mut file)) => {
+            file.flush().await.map_err(|e| spool_error(&e))?;
+            Ok(Received::File(path, bytes))
+        }
+        None => Ok(Received::Memory(buffer)),
+    }
+}
+
+/// Why an imported sequence is invalid
+pub struct SequenceErrors {
+    pub error: String,
+    /// The first invalid steps
+    pub step_errors: Vec<StepError>,
+    /// Number of invalid steps, including those not listed
+    pub step_error_count: usize,
+}
+
+/// Validate an imported sequence, reporting every invalid step rather
+/// than only the first
+pub fn check_sequence(config: &Config, sequence: &Sequence) -> Result<(), SequenceErrors> {
+    // Before looking at every step of a huge sequence
+    if let Err(e) = sequence.check_limits(config) {
+        return Err(SequenceErrors {
+            error: format!("{:#}", e),
+            step_errors: Vec::new(),
+            step_error_count: 0,
+        });
+    }
+    let mut errors = sequence.step_errors(config);
+    if !errors.is_empty() {
+        let count = errors.len();
+        errors.truncate(MAX_REPORTED_ERRORS);
+        return Err(SequenceErrors {
+            error: format!("{} invalid steps", count),
+            step_errors: errors,
+            step_error_count: count,
+        });
+    }
+    // Checks beyond the steps, such as an empty sequence or bad
+    // preconditions
+    sequence.validate(config).map_err(|e| SequenceErrors {
+        error: format!("{:#}", e),
+        step_errors: Vec::new(),
+        step_error_count: 0,
+    })
+}
+
+/// Parse, validate and save a sequence, filling in the record
+fn import(state: &AppState, headers: &HeaderMap, record: &mut ImportRecord, body: impl Read) {
+    let sequence: Sequence = match serde_json::from_reader(body) {
+        Ok(sequence) => sequence,
+        Err(e) => return fail(record, format!("Invalid sequence: {}", e)),
+    };
+    record.steps = Some(sequence.steps.len());
+
+    if let Err(errors) = check_sequence(&state.config(), &sequence) {
+        record.step_errors = errors.step_errors;
+        record.step_error_count = errors.step_error_count;
+        return fail(record, errors.error);
+    }
+
+    let endpoint = "POST /api/sequences/:name/import";
+    if let Err((_, Json(e))) =
+        handlers::store_sequence(state, headers, endpoint, &record.name, sequence)
+    {
+        return fail(record, e.error);
+    }
+    info!("Import {} completed", record.id);
+    record.status = ImportStatus::Completed;
+    record.finished_ms = Some(audit::now_ms());
+}
+
+fn fail(record: &mut ImportRecord, error: String) {
+    warn!("Import {} failed: {}", record.id, error);
+    record.status = ImportStatus::Failed;
+    record.error = Some(error);
+    record.finished_ms = Some(audit::now_ms());
+}
+
+/// 400 `INVALID_SEQUENCE` for a failed synchronous import, with the step
+/// errors as details
+fn invalid(record: ImportRecord) -> ApiError {
+    let error = record.error.unwrap_or_default();
+    (
+        StatusCode::BAD_REQUEST,
+        Json(ErrorResponse {
+            details: Some(serde_json::json!({
+                "step_errors": record.step_errors,
+                "step_error_count": record.step_error_count,
+            })),
+            ..ErrorResponse::with_code("INVALID_SEQUENCE", error)
+        }),
+    )
+}
+
+fn bad_request(error: String) -> ApiError {
+    (StatusCode::BAD_REQUEST, Json(ErrorResponse::new(error)))
+}
+
+fn spool_error(e: &std::io::Error) -> ApiError {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ErrorResponse::new(format!("Failed to spool import: {}", e))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SequenceLimits;
+    use crate::library::SequenceStep;
+    use crate::testing::TestServer;
+    use std::time::Duration;
+
+    /// A sequence of `steps` keyframes, those at the `invalid` indices out
+    /// of range
+    fn sequence(steps: usize, invalid: impl Fn(usize) -> bool) -> Sequence {
+        Sequence {
+            steps: (0..steps)
+                .map(|i| SequenceStep {
+                    duration_ms: 20,
+                    angles: vec![(i % 180) as u16, if invalid(i) { 999 } else { 90 }],
+                })
+                .collect(),
+            preconditions: Default::default(),
+        }
+    }
+
+    fn chunked(chunks: Vec<Vec<u8>>) -> Body {
+        Body::from_stream(futures_util::stream::iter(
+            chunks.into_iter().map(Ok::<_, std::io::Error>),
+        ))
+    }
+
+    async fn finished(server: &TestServer, id: u64) -> serde_json::Value {
+        for _ in 0..100 {
+            let reply = server.get(&format!("/api/imports/{}", id)).await;
+            if reply.body["status"] != "running" {
+                return reply.body;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("import {} is still running", id);
+    }
+
+    #[test]
+    fn scattered_invalid_steps_are_all_counted() {
+        let config = Config::default();
+        assert!(check_sequence(&config, &sequence(500, |_| false)).is_ok());
+
+        let errors = check_sequence(&config, &sequence(2000, |i| i % 13 == 5)).unwrap_err();
+        assert_eq!(errors.step_error_count, 154);
+        assert_eq!(errors.error, "154 invalid steps");
+        assert_eq!(errors.step_errors.len(), MAX_REPORTED_ERRORS);
+        let steps: Vec<usize> = errors.step_errors.iter().map(|e| e.step).collect();
+        let expected: Vec<usize> = (0..MAX_REPORTED_ERRORS).map(|n| 5 + 13 * n).collect();
+        assert_eq!(steps, expected);
+    }
+
+    #[test]
+    fn limits_are_checked_before_the_steps() {
+        let config = Config {
+            sequence_limits: SequenceLimits {
+                max_steps: Some(100),
+                max_duration_ms: None,
+            },
+            ..Config::default()
+        };
+        let errors = check_sequence(&config, &sequence(101, |_| true)).unwrap_err();
+        assert!(errors.error.starts_with("Sequence too long"));
+        assert!(errors.step_errors.is_empty());
+        assert_eq!(errors.step_error_count, 0);
+
+        let errors = check_sequence(&config, &sequence(0, |_| false)).unwrap_err();
+        assert_eq!(errors.error, "Sequence has no steps");
+    }
+
+    #[tokio::test]
+    async fn small_body_stays_in_memory() {
+        let chunks = vec![b"{\"steps\"".to_vec(), b": []}".to_vec()];
+        match receive(chunked(chunks), 1).await {
+            Ok(Received::Memory(bytes)) => assert_eq!(bytes, b"{\"steps\": []}"),
+            _ => panic!("not received in memory"),
+        }
+    }
+
+    #[tokio::test]
+    async fn large_body_is_spooled_across_chunks() {
+        let chunks: Vec<Vec<u8>> = (0..40u8).map(|i| vec![b'a' + i % 26; 10_000]).collect();
+        let expected = chunks.concat();
+        let path = match receive(chunked(chunks), u64::MAX).await {
+            Ok(Received::File(file, bytes)) => {
+                assert_eq!(bytes, 400_000);
+                assert_eq!(std::fs::read(&file.0).unwrap(), expected);
+                file.0.clone()
+            }
+            _ => panic!("not spooled"),
+        };
+        assert!(!path.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_import_runs_in_the_background() {
+        let server = TestServer::start().await;
+        let body = serde_json::to_string(&sequence(20_000, |_| false)).unwrap();
+        assert!(body.len() > SYNC_IMPORT_BYTES);
+        let reply = server.post_text("/api/sequences/long/import", &body).await;
+        assert_eq!(reply.status, 202);
+        assert_eq!(reply.body["status"], "running");
+        assert_eq!(reply.body["bytes"], body.len());
+
+        let record = finished(&server, reply.body["id"].as_u64().unwrap()).await;
+        assert_eq!(record["status"], "completed");
+        assert_eq!(record["steps"], 20_000);
+        let saved = server.get("/api/sequences/long").await;
+        assert_eq!(saved.body["steps"].as_array().unwrap().len(), 20_000);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_import_reports_the_first_invalid_steps() {
+        let server = TestServer::start().await;
+        let body = serde_json::to_string(&sequence(20_000, |i| i % 97 == 96)).unwrap();
+        let reply = server.post_text("/api/sequences/long/import", &body).await;
+        assert_eq!(reply.status, 202);
+
+        let record = finished(&server, reply.body["id"].as_u64().unwrap()).await;
+        assert_eq!(record["status"], "failed");
+        assert_eq!(record["error"], "206 invalid steps");
+        assert_eq!(record["step_error_count"], 206);
+        let step_errors = record["step_errors"].as_array().unwrap();
+        assert_eq!(step_errors.len(), MAX_REPORTED_ERRORS);
+        assert_eq!(step_errors[0]["step"], 96);
+        assert_eq!(step_errors[49]["step"], 96 + 97 * 49);
+        assert_eq!(server.get("/api/sequences/long").await.status, 404);
+
+        // Not JSON at all
+        let body = "x".repeat(SYNC_IMPORT_BYTES + 1);
+        let reply = server.post_text("/api/sequences/long/import", &body).await;
+        let record = finished(&server, reply.body["id"].as_u64().unwrap()).await;
+        assert_eq!(record["status"], "failed");
+        assert!(record["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid sequence"));
+    }
+
+    #[test]
+    fn only_the_latest_finished_imports_are_kept() {
+        let jobs = ImportJobs::default();
+        let running = Arc::new(Mutex::new(new_record(1, "running".into(), 0)));
+        jobs.register(1, running);
+        for id in 2..=MAX_FINISHED_IMPORTS as u64 + 5 {
+            let mut record = new_record(id, "done".into(), 0);
+            record.status = ImportStatus::Completed;
+            jobs.register(id, Arc::new(Mutex::new(record)));
+        }
+        assert!(jobs.get(1).is_some());
+        assert!(jobs.get(5).is_none());
+        assert!(jobs.get(6).is_some());
+        assert_eq!(
+            jobs.inner.lock().unwrap().records.len(),
+            MAX_FINISHED_IMPORTS + 1
+        );
+    }
+}
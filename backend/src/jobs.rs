@@ -0,0 +1,438 @@
+use axum::http::HeaderMap;
+use axum::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::audit;
+use crate::controller::ServoController;
+use crate::handlers::{self, ApiError, AppState};
+use crate::library::Preconditions;
+use crate::models::ExecuteRequest;
+use crate::script::{self, Halt, Host, Limits, Positions, Script, ScriptError, TraceEntry};
+use crate::serial::{CommandOptions};
+
+/// Finished jobs kept for `GET /api/script/:id`
+const MAX_FINISHED_JOBS: usize = 20;
+
+/// Trace entries kept per job; older ones are dropped first
+const MAX_TRACE: usize = 1000;
+
+/// How often a sleeping script checks for a cancel
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// State and trace of a script job
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRecord {
+    pub id: u64,
+    pub status: JobStatus,
+    pub started_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_ms: Option<u64>,
+    /// Executed statements, most recent last
+    pub trace: Vec<TraceEntry>,
+    /// Trace entries dropped to stay within the limit
+    #[serde(skip_serializing_if = "is_zero")]
+    pub trace_dropped: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Line the script failed or was cancelled on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+struct Job {
+    token: CancellationToken,
+    record: Mutex<JobRecord>,
+    /// Set to true once the job has finished
+    done: watch::Sender<bool>,
+}
+
+/// Script jobs, running and recently finished
+#[derive(Default)]
+pub struct ScriptJobs {
+    inner: Mutex<Jobs>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, Arc<Job>>,
+}
+
+impl ScriptJobs {
+    /// Run a parsed script in the background
+    pub fn start(
+        &self,
+        state: Arc<AppState>,
+        serial: Arc<dyn ServoController>,
+        script: Script,
+    ) -> JobRecord {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let job = Arc::new(Job {
+            token: CancellationToken::new(),
+            record: Mutex::new(JobRecord {
+                id,
+                status: JobStatus::Running,
+                started_ms: audit::now_ms(),
+                finished_ms: None,
+                trace: Vec::new(),
+                trace_dropped: 0,
+                error: None,
+                line: None,
+            }),
+            done: watch::channel(false).0,
+        });
+        inner.jobs.insert(id, job.clone());
+
+        let finished: Vec<u64> = inner
+            .jobs
+            .iter()
+            .filter(|(_, job)| *job.done.borrow())
+            .map(|(&id, _)| id)
+            .collect();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_JOBS);
+        for id in &finished[..excess] {
+            inner.jobs.remove(id);
+        }
+        drop(inner);
+
+        info!("Started script job {}", id);
+        let record = job.record.lock().unwrap().clone();
+        tokio::task::spawn_blocking(move || {
+            let mut host = ArmHost {
+                state: &state,
+                serial: &*serial,
+                job: &job,
+            };
+            // The demo stays paused for the whole script, sleeps included
+            let result = state
+                .begin_motion()
+                .map_err(|e| {
+                    Halt::Failed(ScriptError {
+                        line: 1,
+                        message: message(e),
+                    })
+                })
+                .and_then(|_motion| script::run(&script, &mut host, Limits::default()));
+
+            let mut record = job.record.lock().unwrap();
+            record.finished_ms = Some(audit::now_ms());
+            match result {
+                Ok(()) => {
+                    info!("Script job {} completed", id);
+                    record.status = JobStatus::Completed;
+                }
+                Err(Halt::Cancelled { line }) => {
+                    info!("Script job {} cancelled on line {}", id, line);
+                    record.status = JobStatus::Cancelled;
+                    record.line = Some(line);
+                }
+                Err(Halt::Failed(e)) => {
+                    warn!("Script job {} failed: {}", id, e);
+                    record.status = JobStatus::Failed;
+                    record.error = Some(e.message);
+                    record.line = Some(e.line);
+                }
+            }
+            drop(record);
+            job.done.send_replace(true);
+        });
+        record
+    }
+
+    pub fn get(&self, id: u64) -> Option<JobRecord> {
+        let job = self.inner.lock().unwrap().jobs.get(&id)?.clone();
+        let record = job.record.lock().unwrap().clone();
+        Some(record)
+    }
+
+    /// The job that was running at `at_ms`, among those still kept, with
+    /// the trace entry of the statement it was on (if still traced)
+    pub fn running_at(&self, at_ms: u64) -> Option<(JobRecord, Option<TraceEntry>)> {
+        let jobs: Vec<Arc<Job>> = self.inner.lock().unwrap().jobs.values().cloned().collect();
+        jobs.iter().rev().find_map(|job| {
+            let record = job.record.lock().unwrap();
+            let running = record.started_ms <= at_ms
+                && record.finished_ms.is_none_or(|finished| finished > at_ms);
+            if !running {
+                return None;
+            }
+            let step = record
+                .trace
+                .iter()
+                .rev()
+                .find(|entry| record.started_ms + entry.elapsed_ms <= at_ms)
+                .cloned();
+            Some((record.clone(), step))
+        })
+    }
+
+    /// Cancel a job and wait for it to stop; `None` if there is no such job
+    pub async fn cancel(&self, id: u64) -> Option<JobRecord> {
+        let job = self.inner.lock().unwrap().jobs.get(&id)?.clone();
+        job.token.cancel();
+        let mut done = job.done.subscribe();
+        let _ = done.wait_for(|done| *done).await;
+        let record = job.record.lock().unwrap().clone();
+        Some(record)
+    }
+}
+
+/// Runs script statements on the connected arm
+struct ArmHost<'a> {
+    state: &'a AppState,
+    serial: &'a dyn ServoController,
+    job: &'a Job,
+}
+
+/// Error message of a failed command, with its details if any
+fn message((_, Json(error)): ApiError) -> String {
+    match error.details {
+        Some(details) => format!("{} ({})", error.error, details),
+        None => error.error,
+    }
+}
+
+impl ArmHost<'_> {
+    /// Check preconditions as an API call without a request body would,
+    /// on cached positions
+    fn check_preconditions(&self, preconditions: &Preconditions) -> Result<(), String> {
+        handlers::check_preconditions(
+            self.state,
+            self.serial,
+            preconditions,
+            false,
+            &ExecuteRequest::default(),
+            &HeaderMap::new(),
+        )
+        .map_err(message)
+    }
+}
+
+impl Positions for ArmHost<'_> {
+    fn position(&self, channel: u8) -> Option<u16> {
+        let positions = self.state.positions.lock().unwrap();
+        positions.get(channel as usize).copied().flatten()
+    }
+
+    fn channel_by_name(&self, name: &str) -> Option<u8> {
+        self.state.config().channel_by_name(name)
+    }
+}
+
+impl Host for ArmHost<'_> {
+    fn pose(&mut self, angles: &[u16]) -> Result<(), String> {
+        handlers::send_pose(self.state, self.serial, angles, CommandOptions::default())
+            .map_err(message)
+    }
+
+    fn move_to(&mut self, duration_ms: u16, angles: &[u16]) -> Result<(), String> {
+        handlers::run_move(
+            self.state,
+            self.serial,
+            duration_ms,
+            angles,
+            CommandOptions::default(),
+        )
+        .map_err(message)
+    }
+
+    fn saved_pose(&mut self, name: &str) -> Result<(), String> {
+        let pose = handlers::saved_pose(self.state, name).map_err(message)?;
+        self.check_preconditions(&pose.preconditions)?;
+        handlers::send_pose(
+            self.state,
+            self.serial,
+            &pose.angles,
+            CommandOptions::default(),
+        )
+        .map_err(message)
+    }
+
+    fn sequence(&mut self, name: &str) -> Result<(), String> {
+        let sequence = handlers::saved_sequence(self.state, name).map_err(message)?;
+        sequence
+            .check_limits(&self.state.config())
+            .map_err(|e| format!("{:#}", e))?;
+        self.check_preconditions(&sequence.preconditions)?;
+        let _plan = self
+            .state
+            .plan_motion(handlers::sequence_channels(&sequence), sequence.duration());
+        for step in &sequence.steps {
+            handlers::run_move(
+                self.state,
+                self.serial,
+                step.duration_ms,
+                &step.angles,
+                CommandOptions::default(),
+            )
+            .map_err(message)?;
+            if !self.sleep(Duration::from_millis(step.duration_ms as u64)) {
+                return Err(format!("Cancelled during sequence {}", name));
+            }
+        }
+        Ok(())
+    }
+
+    fn home(&mut self) -> Result<(), String> {
+        let home = self.state.config().home_pose();
+        if home.is_empty() {
+            return Err("Channel 0 has no servo, so POSE can't reach any servo".to_string());
+        }
+        handlers::run_pose(self.state, self.serial, &home, CommandOptions::default())
+            .map_err(message)
+    }
+
+    fn sleep(&mut self, duration: Duration) -> bool {
+        let until = Instant::now() + duration;
+        loop {
+            if self.cancelled() {
+                return false;
+            }
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            std::thread::sleep(left.min(SLEEP_SLICE));
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.job.token.is_cancelled()
+    }
+
+    fn trace(&mut self, entry: TraceEntry) {
+        let mut record = self.job.record.lock().unwrap();
+        if record.trace.len() >= MAX_TRACE {
+            record.trace.remove(0);
+            record.trace_dropped += 1;
+        }
+        record.trace.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+    use serde_json::json;
+
+    /// Add a job with statements on `lines` at the given times since its
+    /// start
+    fn insert(
+        jobs: &ScriptJobs,
+        started_ms: u64,
+        finished_ms: Option<u64>,
+        trace: &[(usize, u64)],
+    ) -> u64 {
+        let mut inner = jobs.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let record = JobRecord {
+            id,
+            status: match finished_ms {
+                Some(_) => JobStatus::Completed,
+                None => JobStatus::Running,
+            },
+            started_ms,
+            finished_ms,
+            trace: trace
+                .iter()
+                .map(|&(line, elapsed_ms)| TraceEntry {
+                    line,
+                    source: format!("line {}", line),
+                    elapsed_ms,
+                    note: None,
+                })
+                .collect(),
+            trace_dropped: 0,
+            error: None,
+            line: None,
+        };
+        let job = Job {
+            token: CancellationToken::new(),
+            record: Mutex::new(record),
+            done: watch::channel(finished_ms.is_some()).0,
+        };
+        inner.jobs.insert(id, Arc::new(job));
+        id
+    }
+
+    fn running_at(jobs: &ScriptJobs, at_ms: u64) -> Option<(u64, Option<usize>)> {
+        jobs.running_at(at_ms)
+            .map(|(record, step)| (record.id, step.map(|step| step.line)))
+    }
+
+    #[test]
+    fn job_running_at_a_time_is_found_with_its_statement() {
+        let jobs = ScriptJobs::default();
+        let first = insert(&jobs, 1000, Some(2000), &[(1, 0), (2, 400), (4, 900)]);
+        let second = insert(&jobs, 5000, None, &[(1, 100)]);
+
+        assert_eq!(running_at(&jobs, 999), None);
+        assert_eq!(running_at(&jobs, 1000), Some((first, Some(1))));
+        assert_eq!(running_at(&jobs, 1399), Some((first, Some(1))));
+        assert_eq!(running_at(&jobs, 1400), Some((first, Some(2))));
+        assert_eq!(running_at(&jobs, 1999), Some((first, Some(4))));
+        // Finished by then
+        assert_eq!(running_at(&jobs, 2000), None);
+        // Started, but not on its first statement yet
+        assert_eq!(running_at(&jobs, 5050), Some((second, None)));
+        assert_eq!(running_at(&jobs, u64::MAX), Some((second, Some(1))));
+    }
+
+    #[test]
+    fn latest_of_overlapping_jobs_is_reported() {
+        let jobs = ScriptJobs::default();
+        insert(&jobs, 1000, Some(3000), &[]);
+        let later = insert(&jobs, 2000, Some(2500), &[]);
+        assert_eq!(running_at(&jobs, 2200), Some((later, None)));
+        assert_eq!(running_at(&jobs, 2600).unwrap().0, later - 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn state_at_reports_the_script_line() {
+        let server = TestServer::start().await;
+        let source = "pose [10]\nsleep 300\npose [20]";
+        let reply = server
+            .post("/api/script", json!({ "source": source }))
+            .await;
+        assert_eq!(reply.status, 202);
+        let id = reply.body["id"].clone();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let path = format!("/api/state-at?t={}", audit::now_ms());
+        let body = server.get(&path).await.body;
+        assert_eq!(body["job"]["id"], id);
+        assert_eq!(body["job"]["line"], 2);
+        assert_eq!(body["job"]["source"], "sleep 300");
+        assert_eq!(body["channels"][0]["angle"], 10);
+
+        let started = body["job"]["started_ms"].as_u64().unwrap();
+        let body = server
+            .get(&format!("/api/state-at?t={}", started - 1))
+            .await
+            .body;
+        assert!(body.get("job").is_none());
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::audit;
+use crate::handlers::AppState;
+use crate::history::Angles;
+use crate::persist::{self, Persister};
+
+/// How often the known positions are written, if they changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Contents of the last pose file
+#[derive(Debug, Serialize, Deserialize)]
+struct LastPoseFile {
+    saved_ms: u64,
+    positions: Angles,
+}
+
+/// The positions saved in `path`, or its backup if the file is corrupt;
+/// `None` if there is no file yet
+pub fn load(path: &Path, persister: &Persister) -> Result<Option<Angles>> {
+    let file: Option<LastPoseFile> = persist::load(path, persister, |text| {
+        serde_json::from_str(text)
+            .with_context(|| format!("Failed to parse last pose {}", path.display()))
+    })?;
+    Ok(file.map(|file| file.positions))
+}
+
+/// Queue the positions to be written to `path`
+fn save(path: &Path, positions: &Angles, persister: &Persister) -> Result<()> {
+    let file = LastPoseFile {
+        saved_ms: audit::now_ms(),
+        positions: *positions,
+    };
+    persister.submit(path, serde_json::to_vec_pretty(&file)?);
+    Ok(())
+}
+
+/// Keep `last_pose_file` up to date with the known positions
+///
+/// Positions forgotten on a reconnect aren't written, so the file keeps
+/// the last ones known until they are known again.
+pub async fn run(state: Arc<AppState>) {
+    let mut saved = None;
+    loop {
+        state.clock.sleep(SAVE_INTERVAL).await;
+        let Some(path) = state.config().last_pose_file.clone() else {
+            continue;
+        };
+        let positions = *state.positions.lock().unwrap();
+        if saved == Some(positions) || positions.iter().all(Option::is_none) {
+            continue;
+        }
+        match save(&path, &positions, &state.persister) {
+            Ok(()) => saved = Some(positions),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::RobotArmServer;
+    use crate::testing::{test_config, MockController, TempDir, TestServer};
+    use serde_json::json;
+
+    /// Served with `last_pose_file` in `dir` holding `saved` if any, the
+    /// firmware answering `angles` and the pose guard reading positions
+    async fn restarted(
+        dir: &TempDir,
+        saved: Option<Angles>,
+        angles: [Option<u16>; 6],
+    ) -> TestServer {
+        let path = dir.join("last_pose.json");
+        if let Some(saved) = saved {
+            let persister = Persister::default();
+            save(&path, &saved, &persister).unwrap();
+            assert!(persister.write_pending());
+        }
+        let mut config = test_config();
+        config.last_pose_file = Some(path);
+        config.pose_guard.max_jump = Some(170);
+        let mock = Arc::new(MockController::default());
+        mock.script().angles = angles;
+        TestServer::serve(RobotArmServer::builder().config(config), mock).await
+    }
+
+    #[test]
+    fn saved_positions_are_loaded() {
+        let dir = TempDir::new("last-pose-round-trip");
+        let path = dir.join("last_pose.json");
+        let persister = Persister::default();
+        assert_eq!(load(&path, &persister).unwrap(), None);
+
+        let positions = [Some(10), Some(20), None, Some(40), Some(50), Some(60)];
+        save(&path, &positions, &persister).unwrap();
+        assert_eq!(persister.pending(), std::slice::from_ref(&path));
+        assert!(persister.write_pending());
+        assert_eq!(load(&path, &persister).unwrap(), Some(positions));
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(load(&path, &persister).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connecting_seeds_the_positions() {
+        let dir = TempDir::new("last-pose-seeded");
+        let server = restarted(&dir, None, [Some(90); 6]).await;
+        let state = server.server.state();
+        assert_eq!(state.confirmed_positions(), [Some(90); 6]);
+
+        let health = server.get("/api/health").await.body;
+        assert_eq!(health["status"], "ok");
+        assert!(health.get("positions_unknown").is_none());
+        assert!(health.get("restored").is_none());
+
+        // Known without reading again
+        let reply = server.post("/api/pose", json!({ "angles": [100] })).await;
+        assert_eq!(reply.status, 200, "{:?}", reply.body);
+        assert_eq!(server.mock.take_commands(), ["POSE 100"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unreadable_positions_are_unknown_until_read() {
+        let dir = TempDir::new("last-pose-unknown");
+        let server = restarted(&dir, None, [None; 6]).await;
+        let state = server.server.state();
+        assert_eq!(state.confirmed_positions(), [None; 6]);
+
+        let health = server.get("/api/health").await.body;
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["serial"], "connected");
+        assert_eq!(health["positions_unknown"], true);
+
+        let reply = server.post("/api/pose", json!({ "angles": [100] })).await;
+        assert_eq!(reply.status, 409);
+        assert_eq!(reply.code(), "POSITIONS_UNKNOWN");
+        assert!(!server
+            .mock
+            .take_commands()
+            .iter()
+            .any(|line| line.starts_with("POSE")));
+
+        // The firmware answers again
+        server.mock.script().angles = [Some(90); 6];
+        assert_eq!(server.get("/api/servos").await.status, 200);
+        let health = server.get("/api/health").await.body;
+        assert_eq!(health["status"], "ok");
+        assert!(health.get("positions_unknown").is_none());
+        let reply = server.post("/api/pose", json!({ "angles": [100] })).await;
+        assert_eq!(reply.status, 200, "{:?}", reply.body);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restored_positions_are_trusted_once_confirmed() {
+        let dir = TempDir::new("last-pose-restored");
+        let saved = [Some(10), Some(20), Some(30), Some(40), Some(50), Some(60)];
+        let server = restarted(&dir, Some(saved), [None; 6]).await;
+        let state = server.server.state();
+        assert_eq!(*state.positions.lock().unwrap(), saved);
+        assert_eq!(state.confirmed_positions(), [None; 6]);
+
+        let health = server.get("/api/health").await.body;
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["positions_unknown"], true);
+        assert_eq!(health["restored"], json!([0, 1, 2, 3, 4, 5]));
+
+        // A restored position isn't a guess commands are checked against
+        let reply = server.post("/api/pose", json!({ "angles": [100] })).await;
+        assert_eq!(reply.code(), "POSITIONS_UNKNOWN");
+
+        // Each read confirms its channel
+        server.mock.script().angles = [Some(12), Some(22), Some(32), Some(42), Some(52), Some(62)];
+        assert_eq!(server.get("/api/servo/0").await.status, 200);
+        let health = server.get("/api/health").await.body;
+        assert_eq!(health["restored"], json!([1, 2, 3, 4, 5]));
+        assert_eq!(state.confirmed_positions()[..2], [Some(12), None]);
+
+        assert_eq!(server.get("/api/servos").await.status, 200);
+        let health = server.get("/api/health").await.body;
+        assert_eq!(health["status"], "ok");
+        assert!(health.get("positions_unknown").is_none());
+        assert!(health.get("restored").is_none());
+        assert_eq!(
+            state.confirmed_positions(),
+            [Some(12), Some(22), Some(32), Some(42), Some(52), Some(62)]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn readable_firmware_overrides_restored_positions() {
+        let dir = TempDir::new("last-pose-confirmed");
+        let saved = [Some(10), Some(20), Some(30), Some(40), Some(50), Some(60)];
+        let server = restarted(&dir, Some(saved), [Some(90); 6]).await;
+        assert_eq!(server.server.state().confirmed_positions(), [Some(90); 6]);
+        let health = server.get("/api/health").await.body;
+        assert_eq!(health["status"], "ok");
+        assert!(health.get("restored").is_none());
+    }
+}
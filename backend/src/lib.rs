@@ -0,0 +1,71 @@
+//! Backend of the robot arm: drives the firmware over the serial port
+//! and serves the REST API
+//!
+//! The `robotarm-backend` binary runs it on its own. [`RobotArmServer`]
+//! runs it inside another program, which can also command the arm
+//! directly through [`control`].
+
+mod arbitration;
+mod attract;
+mod audit;
+mod backup;
+mod benchmark;
+mod clock;
+mod command_queue;
+mod compensation;
+mod config;
+mod connection;
+mod constraints;
+pub mod control;
+mod controller;
+mod current_draw;
+mod demo;
+mod features;
+mod feedback;
+mod handlers;
+mod history;
+mod idle_exit;
+mod imports;
+mod jobs;
+mod last_pose;
+mod library;
+mod library_watch;
+mod link_loss;
+mod lockout;
+mod logging;
+mod models;
+mod moves;
+mod overrides;
+mod pattern;
+mod persist;
+mod planner;
+mod poller;
+mod protocol;
+mod replication;
+mod rezero;
+mod routes;
+mod schedule;
+mod schema;
+mod script;
+mod serial;
+mod server;
+mod signing;
+mod simulator;
+mod stats;
+mod streaming;
+mod support;
+mod tokens;
+#[cfg(test)]
+mod testing;
+mod uds;
+mod url_import;
+mod usage;
+mod violations;
+mod wear;
+
+pub use config::Config;
+pub use handlers::{ApiError, AppState};
+pub use logging::{log_layer, LogFormat};
+pub use models::ErrorResponse;
+pub use protocol::{Angle, Channel};
+pub use server::{RobotArmServer, ServerBuilder, ServerHandle, Transport};
@@ -0,0 +1,341 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::persist::{self, Persister};
+use crate::schema::Param;
+use crate::serial::NUM_SERVOS;
+
+/// Named poses and sequences, persisted as a JSON document
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Library {
+    #[serde(default)]
+    pub poses: BTreeMap<String, Pose>,
+    #[serde(default)]
+    pub sequences: BTreeMap<String, Sequence>,
+}
+
+/// A saved set of angles, in channel order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pose {
+    pub angles: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Preconditions::is_empty")]
+    pub preconditions: Preconditions,
+}
+
+/// A list of MOVE steps played back in order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sequence {
+    pub steps: Vec<SequenceStep>,
+    #[serde(default, skip_serializing_if = "Preconditions::is_empty")]
+    pub preconditions: Preconditions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequenceStep {
+    pub duration_ms: u16,
+    pub angles: Vec<u16>,
+}
+
+/// Conditions on the arm's current position that must hold before a pose
+/// or sequence may start
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Preconditions {
+    /// Allowed starting range per channel
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ranges: BTreeMap<u8, AngleRange>,
+    /// Saved pose the arm must be near
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pose: Option<PoseCondition>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AngleRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoseCondition {
+    pub name: String,
+    /// Maximum difference in degrees on every channel of the pose
+    #[serde(default)]
+    pub tolerance: u16,
+}
+
+impl Preconditions {
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.pose.is_none()
+    }
+
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        let firmware = Param::Angle.range(config);
+        for (&channel, range) in &self.ranges {
+            if channel >= NUM_SERVOS {
+                anyhow::bail!("Invalid servo channel in precondition: {}", channel);
+            }
+            if range.min > range.max || !firmware.contains(range.max as u32) {
+                anyhow::bail!(
+                    "Invalid precondition range for servo {}: {}-{}",
+                    channel,
+                    range.min,
+                    range.max
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the conditions against known positions, returning every
+    /// violated condition. Channels with unknown positions fail.
+    pub fn evaluate(&self, positions: &[Option<u16>], library: &Library) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        for (&channel, range) in &self.ranges {
+            match positions.get(channel as usize).copied().flatten() {
+                None => violations.push(format!("Servo {}: position unknown", channel)),
+                Some(angle) if angle < range.min || angle > range.max => {
+                    violations.push(format!(
+                        "Servo {}: at {}, must start within {}-{}",
+                        channel, angle, range.min, range.max
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+
+        if let Some(condition) = &self.pose {
+            let pose = match library.poses.get(&condition.name) {
+                Some(pose) => pose,
+                None => {
+                    violations.push(format!("Required pose '{}' does not exist", condition.name));
+                    return violations;
+                }
+            };
+            for (channel, &target) in pose.angles.iter().enumerate() {
+                match positions.get(channel).copied().flatten() {
+                    None => violations.push(format!("Servo {}: position unknown", channel)),
+                    Some(angle) if angle.abs_diff(target) > condition.tolerance => {
+                        violations.push(format!(
+                            "Servo {}: at {}, must be within {} of pose '{}' ({})",
+                            channel, angle, condition.tolerance, condition.name, target
+                        ));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+fn validate_angles(angles: &[u16], config: &Config) -> Result<()> {
+    if angles.is_empty() {
+        anyhow::bail!("No servo angles given");
+    }
+    if angles.len() > NUM_SERVOS as usize {
+        anyhow::bail!("Too many servos: {} (max {})", angles.len(), NUM_SERVOS);
+    }
+    for &angle in angles {
+        if let Err(e) = Param::Angle.check(config, None, angle as u32) {
+            anyhow::bail!(e.message);
+        }
+    }
+    Ok(())
+}
+
+impl Pose {
+    /// Check the pose against the firmware's angle range
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        validate_angles(&self.angles, config)?;
+        self.preconditions.validate(config)
+    }
+}
+
+impl SequenceStep {
+    /// Check the step against the firmware's angle range
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        validate_angles(&self.angles, config)
+    }
+}
+
+/// A sequence step that failed validation
+#[derive(Debug, Clone, Serialize)]
+pub struct StepError {
+    pub step: usize,
+    pub error: String,
+}
+
+impl Sequence {
+    /// Check every step, collecting the errors of all invalid steps rather
+    /// than stopping at the first
+    pub fn step_errors(&self, config: &Config) -> Vec<StepError> {
+        self.steps
+            .iter()
+            .enumerate()
+            .filter_map(|(step, s)| {
+                let error = validate_angles(&s.angles, config).err()?;
+                Some(StepError {
+                    step,
+                    error: format!("{:#}", error),
+                })
+            })
+            .collect()
+    }
+
+    /// Estimated time to play; each step's MOVE is followed by a wait of
+    /// the same length
+    pub fn duration(&self) -> Duration {
+        let total: u64 = self.steps.iter().map(|step| 2 * step.duration_ms as u64).sum();
+        Duration::from_millis(total)
+    }
+
+    /// Check the length and duration against `[sequence_limits]`
+    pub fn check_limits(&self, config: &Config) -> Result<()> {
+        config
+            .sequence_limits
+            .check(self.steps.len(), "steps", self.duration())
+            .context("Sequence too long")
+    }
+
+    /// Check every step against the firmware's angle range and the
+    /// sequence against `[sequence_limits]`
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        if self.steps.is_empty() {
+            anyhow::bail!("Sequence has no steps");
+        }
+        self.check_limits(config)?;
+        for (i, step) in self.steps.iter().enumerate() {
+            validate_angles(&step.angles, config).with_context(|| format!("Step {}", i))?;
+        }
+        self.preconditions.validate(config)
+    }
+}
+
+impl Library {
+    /// Load the library from disk, or its backup if the file is corrupt; a
+    /// missing file yields an empty library
+    pub fn load(path: &Path, persister: &Persister) -> Result<Self> {
+        let library = persist::load(path, persister, |text| {
+            serde_json::from_str(text)
+                .with_context(|| format!("Failed to parse library {}", path.display()))
+        })?;
+        Ok(library.unwrap_or_default())
+    }
+
+    /// Queue the library to be written to disk
+    pub fn save(&self, path: &Path, persister: &Persister) -> Result<()> {
+        persister.submit(path, serde_json::to_vec_pretty(self)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn preconditions(value: serde_json::Value) -> Preconditions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn library() -> Library {
+        let mut library = Library::default();
+        library.poses.insert(
+            "folded".to_string(),
+            Pose {
+                angles: vec![10, 170],
+                preconditions: Preconditions::default(),
+            },
+        );
+        library
+    }
+
+    #[test]
+    fn preconditions_are_read_and_left_out_when_empty() {
+        let conditions = preconditions(json!({
+            "ranges": { "0": { "min": 0, "max": 45 } },
+            "pose": { "name": "folded" },
+        }));
+        assert_eq!(conditions.ranges[&0].max, 45);
+        assert_eq!(conditions.pose.as_ref().unwrap().tolerance, 0);
+
+        let pose: Pose = serde_json::from_value(json!({ "angles": [90] })).unwrap();
+        assert!(pose.preconditions.is_empty());
+        assert_eq!(
+            serde_json::to_value(&pose).unwrap(),
+            json!({ "angles": [90] })
+        );
+    }
+
+    #[test]
+    fn invalid_ranges_are_refused() {
+        let config = Config::default();
+        let valid = preconditions(json!({ "ranges": { "5": { "min": 0, "max": 180 } } }));
+        assert!(valid.validate(&config).is_ok());
+
+        for ranges in [
+            json!({ "6": { "min": 0, "max": 45 } }),
+            json!({ "0": { "min": 50, "max": 45 } }),
+            json!({ "0": { "min": 0, "max": 181 } }),
+        ] {
+            let invalid = preconditions(json!({ "ranges": ranges }));
+            assert!(invalid.validate(&config).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn ranges_are_checked_against_known_positions() {
+        let conditions = preconditions(json!({
+            "ranges": { "0": { "min": 0, "max": 45 }, "1": { "min": 90, "max": 180 } },
+        }));
+        assert!(conditions
+            .evaluate(&[Some(45), Some(90)], &Library::default())
+            .is_empty());
+        assert_eq!(
+            conditions.evaluate(&[Some(46), Some(180)], &Library::default()),
+            ["Servo 0: at 46, must start within 0-45"]
+        );
+    }
+
+    #[test]
+    fn unknown_positions_fail_closed() {
+        let conditions = preconditions(json!({
+            "ranges": { "0": { "min": 0, "max": 180 }, "3": { "min": 0, "max": 180 } },
+        }));
+        // Channel 0 unread, channel 3 beyond the positions known at all
+        assert_eq!(
+            conditions.evaluate(&[None, Some(90)], &Library::default()),
+            ["Servo 0: position unknown", "Servo 3: position unknown"]
+        );
+    }
+
+    #[test]
+    fn required_pose_is_checked_within_its_tolerance() {
+        let conditions = preconditions(json!({ "pose": { "name": "folded", "tolerance": 5 } }));
+        let library = library();
+        assert!(conditions
+            .evaluate(&[Some(15), Some(165)], &library)
+            .is_empty());
+        assert_eq!(
+            conditions.evaluate(&[Some(16), None], &library),
+            [
+                "Servo 0: at 16, must be within 5 of pose 'folded' (10)",
+                "Servo 1: position unknown",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_required_pose_fails() {
+        let conditions = preconditions(json!({ "pose": { "name": "gone" } }));
+        assert_eq!(
+            conditions.evaluate(&[Some(90)], &library()),
+            ["Required pose 'gone' does not exist"]
+        );
+    }
+}
@@ -0,0 +1,577 @@
+use axum::http::{HeaderMap, HeaderValue};
+use notify::{RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::config::WatchConflicts;
+use crate::handlers::{self, AppState};
+use crate::imports;
+use crate::library::{Pose, Sequence, StepError};
+
+/// Problems kept for `GET /api/library/watch`
+const MAX_PROBLEMS: usize = 50;
+
+/// Actor and endpoint of the watcher's changes in the audit trail
+const ACTOR: &str = "library-watch";
+const ENDPOINT: &str = "library watch";
+
+/// Suffix of the name a file is loaded as under [`WatchConflicts::Suffix`]
+const CONFLICT_SUFFIX: &str = "_file";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Pose,
+    Sequence,
+}
+
+impl FileKind {
+    /// Kind and entry name of a watched file, `None` for other files
+    ///
+    /// Dotfiles are skipped, as those are usually editor swap and lock files.
+    fn of(path: &Path) -> Option<(FileKind, String)> {
+        let file_name = path.file_name()?.to_str()?;
+        if file_name.starts_with('.') {
+            return None;
+        }
+        let (kind, name) = if let Some(name) = file_name.strip_suffix(".pose.json") {
+            (FileKind::Pose, name)
+        } else {
+            (FileKind::Sequence, file_name.strip_suffix(".seq.json")?)
+        };
+        (!name.is_empty()).then(|| (kind, name.to_string()))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FileKind::Pose => "pose",
+            FileKind::Sequence => "sequence",
+        }
+    }
+}
+
+/// A file loaded into the library
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchedFile {
+    pub kind: FileKind,
+    /// Library entry it was loaded as
+    pub name: String,
+    pub loaded_ms: u64,
+}
+
+/// A file that couldn't be loaded or removed
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchProblem {
+    pub path: String,
+    pub error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub step_errors: Vec<StepError>,
+    pub at_ms: u64,
+}
+
+/// Why a file wasn't loaded
+struct Failure {
+    error: String,
+    step_errors: Vec<StepError>,
+}
+
+impl From<String> for Failure {
+    fn from(error: String) -> Self {
+        Self {
+            error,
+            step_errors: Vec::new(),
+        }
+    }
+}
+
+/// Pose and sequence files of the `[library_watch]` directory loaded into
+/// the library
+///
+/// A file is validated like an import and loaded once it stayed unchanged
+/// for `debounce_ms`. A file that stops validating leaves the entry it
+/// loaded last in place. Deleting a file removes its entry, unless
+/// another file took the name over since.
+#[derive(Default)]
+pub struct LibraryWatch {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: bool,
+    files: BTreeMap<PathBuf, WatchedFile>,
+    problems: VecDeque<WatchProblem>,
+}
+
+impl LibraryWatch {
+    pub fn running(&self) -> bool {
+        self.inner.lock().unwrap().running
+    }
+
+    /// Loaded files by path
+    pub fn files(&self) -> BTreeMap<String, WatchedFile> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .files
+            .iter()
+            .map(|(path, file)| (path.display().to_string(), file.clone()))
+            .collect()
+    }
+
+    /// Recent problems, most recent first
+    pub fn problems(&self) -> Vec<WatchProblem> {
+        let inner = self.inner.lock().unwrap();
+        inner.problems.iter().rev().cloned().collect()
+    }
+
+    fn problem(&self, path: &Path, failure: Failure, at_ms: u64) {
+        warn!("Library watch: {}: {}", path.display(), failure.error);
+        let mut inner = self.inner.lock().unwrap();
+        if inner.problems.len() >= MAX_PROBLEMS {
+            inner.problems.pop_front();
+        }
+        inner.problems.push_back(WatchProblem {
+            path: path.display().to_string(),
+            error: failure.error,
+            step_errors: failure.step_errors,
+            at_ms,
+        });
+    }
+
+    /// Whether `path` loaded the entry `name` of `kind`
+    fn owns(&self, path: &Path, kind: FileKind, name: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .files
+            .get(path)
+            .is_some_and(|file| file.kind == kind && file.name == name)
+    }
+
+    /// Record `path` as the source of its entry, taking it from any other
+    /// file; returns the entry it loaded before, if different
+    fn loaded(&self, path: &Path, file: WatchedFile) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .files
+            .retain(|other, f| other == path || f.kind != file.kind || f.name != file.name);
+        let previous = inner.files.insert(path.to_path_buf(), file.clone())?;
+        (previous.name != file.name).then_some(previous.name)
+    }
+}
+
+/// Watch the configured directory until the backend stops
+pub async fn run(state: Arc<AppState>) {
+    let Some(dir) = state.config().library_watch.dir.clone() else {
+        return;
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Err(e) => warn!("Library watch error: {}", e),
+        });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Failed to start the library watch: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+        warn!("Failed to watch {}: {}", dir.display(), e);
+        return;
+    }
+    state.library_watch.inner.lock().unwrap().running = true;
+    info!("Watching {} for pose and sequence files", dir.display());
+
+    // Files already there; events from now on are queued meanwhile
+    for path in scan(&dir) {
+        sync(&state, &path).await;
+    }
+
+    // Files waiting for their edits to settle, by when they're loaded
+    let mut due: BTreeMap<PathBuf, Instant> = BTreeMap::new();
+    loop {
+        let next = due.values().min().copied();
+        let settled = async {
+            match next {
+                Some(at) => {
+                    let wait = at.saturating_duration_since(state.clock.now());
+                    state.clock.sleep(wait).await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            path = rx.recv() => {
+                let Some(path) = path else { break };
+                if FileKind::of(&path).is_some() {
+                    let debounce = state.config().library_watch.debounce_ms;
+                    due.insert(path, state.clock.now() + Duration::from_millis(debounce));
+                }
+            }
+            _ = settled => {
+                let now = state.clock.now();
+                let ready: Vec<PathBuf> = due
+                    .iter()
+                    .filter(|(_, &at)| at <= now)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                for path in ready {
+                    due.remove(&path);
+                    sync(&state, &path).await;
+                }
+            }
+        }
+    }
+    drop(watcher);
+}
+
+/// Watched files in `dir`, by name
+fn scan(dir: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to list {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| FileKind::of(path).is_some())
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Load a file that was created or changed, or remove the entry of one
+/// that's gone
+async fn sync(state: &AppState, path: &Path) {
+    let Some((kind, name)) = FileKind::of(path) else {
+        return;
+    };
+    let result = match tokio::fs::read(path).await {
+        Ok(body) => load(state, path, kind, &name, &body),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => unload(state, path),
+        Err(e) => Err(format!("Failed to read: {}", e).into()),
+    };
+    if let Err(failure) = result {
+        state
+            .library_watch
+            .problem(path, failure, state.clock.now_ms());
+    }
+}
+
+fn audit_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-actor", HeaderValue::from_static(ACTOR));
+    headers
+}
+
+fn load(
+    state: &AppState,
+    path: &Path,
+    kind: FileKind,
+    name: &str,
+    body: &[u8],
+) -> Result<(), Failure> {
+    let config = state.config();
+    enum Entry {
+        Pose(Pose),
+        Sequence(Sequence),
+    }
+    // Checked the same way as the import endpoints
+    let entry = match kind {
+        FileKind::Pose => {
+            let pose: Pose =
+                serde_json::from_slice(body).map_err(|e| format!("Invalid pose: {}", e))?;
+            pose.validate(&config).map_err(|e| format!("{:#}", e))?;
+            Entry::Pose(pose)
+        }
+        FileKind::Sequence => {
+            let sequence: Sequence =
+                serde_json::from_slice(body).map_err(|e| format!("Invalid sequence: {}", e))?;
+            if let Err(errors) = imports::check_sequence(&config, &sequence) {
+                return Err(Failure {
+                    error: errors.error,
+                    step_errors: errors.step_errors,
+                });
+            }
+            Entry::Sequence(sequence)
+        }
+    };
+
+    let watch = &state.library_watch;
+    let taken = |candidate: &str| {
+        let library = state.library.lock().unwrap();
+        let exists = match kind {
+            FileKind::Pose => library.poses.contains_key(candidate),
+            FileKind::Sequence => library.sequences.contains_key(candidate),
+        };
+        exists && !watch.owns(path, kind, candidate)
+    };
+    let target = if !taken(name) {
+        name.to_string()
+    } else {
+        match config.library_watch.conflicts {
+            WatchConflicts::FileWins => name.to_string(),
+            WatchConflicts::ApiWins => {
+                return Err(format!(
+                    "A {} named {} exists that this file didn't load",
+                    kind.name(),
+                    name
+                )
+                .into())
+            }
+            WatchConflicts::Suffix => {
+                let suffixed = format!("{}{}", name, CONFLICT_SUFFIX);
+                if taken(&suffixed) {
+                    return Err(format!(
+                        "A {} named {} exists, and one named {} as well",
+                        kind.name(),
+                        name,
+                        suffixed
+                    )
+                    .into());
+                }
+                suffixed
+            }
+        }
+    };
+
+    let headers = audit_headers();
+    match entry {
+        Entry::Pose(pose) => handlers::store_pose(state, &headers, ENDPOINT, &target, pose),
+        Entry::Sequence(sequence) => {
+            handlers::store_sequence(state, &headers, ENDPOINT, &target, sequence)
+        }
+    }
+    .map_err(|(_, e)| e.0.error)?;
+    info!("Loaded {} {} from {}", kind.name(), target, path.display());
+
+    let file = WatchedFile {
+        kind,
+        name: target,
+        loaded_ms: state.clock.now_ms(),
+    };
+    // Loaded under another name before, e.g. until the policy changed
+    if let Some(previous) = watch.loaded(path, file) {
+        remove(state, kind, &previous)?;
+    }
+    Ok(())
+}
+
+fn unload(state: &AppState, path: &Path) -> Result<(), Failure> {
+    let file = state.library_watch.inner.lock().unwrap().files.remove(path);
+    match file {
+        Some(file) => remove(state, file.kind, &file.name),
+        None => Ok(()),
+    }
+}
+
+fn remove(state: &AppState, kind: FileKind, name: &str) -> Result<(), Failure> {
+    let headers = audit_headers();
+    match kind {
+        FileKind::Pose => handlers::remove_pose(state, &headers, ENDPOINT, name),
+        FileKind::Sequence => handlers::remove_sequence(state, &headers, ENDPOINT, name),
+    }
+    .map_err(|(_, e)| Failure::from(e.0.error))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, TestServer};
+    use serde_json::json;
+
+    fn set_conflicts(state: &AppState, conflicts: WatchConflicts) {
+        let mut config = (*state.base_config()).clone();
+        config.library_watch.conflicts = conflicts;
+        *state.config.lock().unwrap() = Arc::new(config);
+    }
+
+    fn pose_angles(state: &AppState, name: &str) -> Option<Vec<u16>> {
+        let library = state.library.lock().unwrap();
+        library.poses.get(name).map(|pose| pose.angles.clone())
+    }
+
+    fn write(path: &Path, body: serde_json::Value) {
+        std::fs::write(path, body.to_string()).unwrap();
+    }
+
+    #[test]
+    fn only_pose_and_sequence_files_are_watched() {
+        let cases = [
+            ("wave.pose.json", Some((FileKind::Pose, "wave"))),
+            ("dir/wave.seq.json", Some((FileKind::Sequence, "wave"))),
+            ("wave.json", None),
+            (".wave.pose.json", None),
+            (".pose.json", None),
+            ("wave.seq.json.swp", None),
+        ];
+        for (path, expected) in cases {
+            let kind = FileKind::of(Path::new(path));
+            let expected = expected.map(|(kind, name)| (kind, name.to_string()));
+            assert_eq!(kind, expected, "{}", path);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn files_are_loaded_and_removed() {
+        let server = TestServer::start().await;
+        let state = server.server.state();
+        let dir = TempDir::new("watch-load");
+        let path = dir.join("wave.pose.json");
+        write(&path, json!({ "angles": [10, 20, 30] }));
+        sync(state, &path).await;
+        assert_eq!(pose_angles(state, "wave"), Some(vec![10, 20, 30]));
+        let files = state.library_watch.files();
+        assert_eq!(files[&path.display().to_string()].name, "wave");
+
+        std::fs::remove_file(&path).unwrap();
+        sync(state, &path).await;
+        assert_eq!(pose_angles(state, "wave"), None);
+        assert!(state.library_watch.files().is_empty());
+        assert!(state.library_watch.problems().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn malformed_files_are_problems() {
+        let server = TestServer::start().await;
+        let state = server.server.state();
+        let dir = TempDir::new("watch-malformed");
+        let path = dir.join("wave.pose.json");
+        write(&path, json!({ "angles": [10, 20, 30] }));
+        sync(state, &path).await;
+
+        // The entry loaded last stays while the file doesn't validate
+        std::fs::write(&path, "{ \"angles\": [10,").unwrap();
+        sync(state, &path).await;
+        assert_eq!(pose_angles(state, "wave"), Some(vec![10, 20, 30]));
+        let problems = state.library_watch.problems();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, path.display().to_string());
+        assert!(
+            problems[0].error.starts_with("Invalid pose: "),
+            "{}",
+            problems[0].error
+        );
+
+        let path = dir.join("reach.seq.json");
+        let steps = json!([
+            { "duration_ms": 100, "angles": [10, 20] },
+            { "duration_ms": 100, "angles": [10, 200] },
+        ]);
+        write(&path, json!({ "steps": steps }));
+        sync(state, &path).await;
+        let library = state.library.lock().unwrap();
+        assert!(!library.sequences.contains_key("reach"));
+        drop(library);
+        let problems = state.library_watch.problems();
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].path, path.display().to_string());
+        assert_eq!(problems[0].step_errors.len(), 1);
+        assert_eq!(problems[0].step_errors[0].step, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn conflicts_follow_the_policy() {
+        let server = TestServer::start().await;
+        let state = server.server.state();
+        let reply = server
+            .put("/api/poses/wave", json!({ "angles": [90, 90] }))
+            .await;
+        assert_eq!(reply.status, 200);
+        let dir = TempDir::new("watch-conflicts");
+        let path = dir.join("wave.pose.json");
+        write(&path, json!({ "angles": [10, 20] }));
+
+        set_conflicts(state, WatchConflicts::ApiWins);
+        sync(state, &path).await;
+        assert_eq!(pose_angles(state, "wave"), Some(vec![90, 90]));
+        assert_eq!(
+            state.library_watch.problems()[0].error,
+            "A pose named wave exists that this file didn't load"
+        );
+
+        set_conflicts(state, WatchConflicts::Suffix);
+        sync(state, &path).await;
+        assert_eq!(pose_angles(state, "wave"), Some(vec![90, 90]));
+        assert_eq!(pose_angles(state, "wave_file"), Some(vec![10, 20]));
+        // Its own entry isn't a conflict
+        write(&path, json!({ "angles": [10, 30] }));
+        sync(state, &path).await;
+        assert_eq!(pose_angles(state, "wave_file"), Some(vec![10, 30]));
+
+        // Moving to the plain name drops the suffixed entry
+        set_conflicts(state, WatchConflicts::FileWins);
+        sync(state, &path).await;
+        assert_eq!(pose_angles(state, "wave"), Some(vec![10, 30]));
+        assert_eq!(pose_angles(state, "wave_file"), None);
+        assert_eq!(state.library_watch.problems().len(), 1);
+
+        let other = dir.join("reach.pose.json");
+        write(&other, json!({ "angles": [40] }));
+        for name in ["reach", "reach_file"] {
+            let reply = server
+                .put(&format!("/api/poses/{}", name), json!({ "angles": [90] }))
+                .await;
+            assert_eq!(reply.status, 200);
+        }
+        set_conflicts(state, WatchConflicts::Suffix);
+        sync(state, &other).await;
+        assert_eq!(
+            state.library_watch.problems()[0].error,
+            "A pose named reach exists, and one named reach_file as well"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn edits_are_loaded_once_they_settle() {
+        let dir = TempDir::new("watch-debounce");
+        let server = TestServer::with_config(|config| {
+            config.library_watch.dir = Some(dir.join(""));
+            config.library_watch.debounce_ms = 400;
+        })
+        .await;
+        let state = server.server.state();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !state.library_watch.running() {
+            assert!(Instant::now() < deadline, "the watch didn't start");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Half-written along the way, as an editor may save it
+        let path = dir.join("wave.pose.json");
+        std::fs::write(&path, "{ \"angles\": ").unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        write(&path, json!({ "angles": [10, 20] }));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(pose_angles(state, "wave"), None);
+
+        while pose_angles(state, "wave").is_none() {
+            assert!(Instant::now() < deadline, "the file wasn't loaded");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(pose_angles(state, "wave"), Some(vec![10, 20]));
+        assert!(state.library_watch.problems().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+        while pose_angles(state, "wave").is_some() {
+            assert!(Instant::now() < deadline, "the entry wasn't removed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(state.library_watch.files().is_empty());
+    }
+}
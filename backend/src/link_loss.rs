@@ -0,0 +1,265 @@
+use serde::Serialize;
+use std::sync::Mutex;
+
+use crate::config::LinkLossPolicy;
+
+/// A channel found away from the target of the interrupted MOVE
+#[derive(Debug, Clone, Serialize)]
+pub struct OffTarget {
+    pub channel: u8,
+    pub target: u16,
+    /// Measured angle, `None` if it couldn't be read
+    pub actual: Option<u16>,
+}
+
+/// What was found and done after reconnecting, see [`LinkLoss`]
+#[derive(Debug, Clone, Serialize)]
+pub struct Recovery {
+    /// Time the link dropped, in ms since the epoch
+    pub lost_ms: u64,
+    pub recovered_ms: u64,
+    pub policy: LinkLossPolicy,
+    pub target: Vec<u16>,
+    pub off_target: Vec<OffTarget>,
+    /// What was found and done, in order
+    pub events: Vec<String>,
+}
+
+/// MOVEs the serial link dropped during
+///
+/// The target of every MOVE is noted as it is sent. When the link drops
+/// while one is estimated to be in progress, the MOVE is kept until the
+/// reconnect, which checks where the arm ended up as `[link_loss]` asks.
+#[derive(Default)]
+pub struct LinkLoss {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Target of the last MOVE sent
+    target: Option<Vec<u16>>,
+    /// Time and target of the MOVE the link dropped during
+    interrupted: Option<(u64, Vec<u16>)>,
+    last: Option<Recovery>,
+    /// Motion is refused until the loss is acknowledged
+    latched: bool,
+}
+
+impl LinkLoss {
+    /// Note the target of a MOVE being sent
+    pub fn sending(&self, target: &[u16]) {
+        self.inner.lock().unwrap().target = Some(target.to_vec());
+    }
+
+    /// Note that the link dropped at `at_ms` during the last MOVE sent
+    pub fn lost(&self, at_ms: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(target) = inner.target.take() {
+            inner.interrupted = Some((at_ms, target));
+        }
+    }
+
+    /// Time and target of the MOVE the link dropped during, once
+    pub fn take_interrupted(&self) -> Option<(u64, Vec<u16>)> {
+        self.inner.lock().unwrap().interrupted.take()
+    }
+
+    pub fn recovered(&self, recovery: Recovery, latch: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last = Some(recovery);
+        inner.latched |= latch;
+    }
+
+    pub fn last(&self) -> Option<Recovery> {
+        self.inner.lock().unwrap().last.clone()
+    }
+
+    pub fn latched(&self) -> bool {
+        self.inner.lock().unwrap().latched
+    }
+
+    /// Lift the latch; returns whether it was set
+    pub fn acknowledge(&self) -> bool {
+        std::mem::take(&mut self.inner.lock().unwrap().latched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers;
+    use crate::testing::TestServer;
+    use serde_json::json;
+
+    #[test]
+    fn only_a_move_in_progress_is_kept_for_the_reconnect() {
+        let loss = LinkLoss::default();
+        // Nothing sent
+        loss.lost(1000);
+        assert_eq!(loss.take_interrupted(), None);
+
+        loss.sending(&[10, 20]);
+        loss.lost(2000);
+        assert_eq!(loss.take_interrupted(), Some((2000, vec![10, 20])));
+        assert_eq!(loss.take_interrupted(), None);
+        // The target is given up with the loss
+        loss.lost(3000);
+        assert_eq!(loss.take_interrupted(), None);
+    }
+
+    #[test]
+    fn latch_holds_until_acknowledged() {
+        let loss = LinkLoss::default();
+        let recovery = Recovery {
+            lost_ms: 1,
+            recovered_ms: 2,
+            policy: LinkLossPolicy::Latch,
+            target: vec![10],
+            off_target: Vec::new(),
+            events: Vec::new(),
+        };
+        loss.recovered(recovery.clone(), true);
+        // A later recovery that doesn't latch leaves it set
+        loss.recovered(recovery, false);
+        assert!(loss.latched());
+        assert!(loss.acknowledge());
+        assert!(!loss.latched());
+        assert!(!loss.acknowledge());
+        assert_eq!(loss.last().unwrap().recovered_ms, 2);
+    }
+
+    /// A server whose link dropped during a MOVE to `[10, 20]`, reconnected
+    /// to firmware that reports channel 0 stopped at 50 degrees
+    async fn interrupted(policy: LinkLossPolicy) -> TestServer {
+        let server = TestServer::with_config(|config| config.link_loss.policy = policy).await;
+        let reply = server
+            .post(
+                "/api/move",
+                json!({ "duration_ms": 10, "angles": [10, 20] }),
+            )
+            .await;
+        assert_eq!(reply.status, 200);
+        let state = server.server.state();
+        state.link_loss.lost(state.clock.now_ms());
+        state.clear_positions();
+        server.mock.script().angles[0] = Some(50);
+        server.mock.take_commands();
+        handlers::recover_link_loss(state, &*server.mock);
+        server
+    }
+
+    fn moves(server: &TestServer) -> Vec<String> {
+        let commands = server.mock.take_commands();
+        commands
+            .into_iter()
+            .filter(|command| command.starts_with("MOVE") || command.starts_with("POSE"))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ignore_leaves_the_loss_unrecorded() {
+        let server = interrupted(LinkLossPolicy::Ignore).await;
+        assert!(server.mock.take_commands().is_empty());
+        assert!(server.server.state().link_loss.last().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reconcile_reads_the_actual_positions() {
+        let server = interrupted(LinkLossPolicy::Reconcile).await;
+        let state = server.server.state();
+        assert_eq!(state.confirmed_positions()[..2], [Some(50), Some(20)]);
+        assert!(moves(&server).is_empty());
+        assert!(!state.link_loss.latched());
+
+        let body = server.get("/api/link-loss").await.body;
+        assert_eq!(body["latched"], false);
+        assert_eq!(body["last"]["policy"], "reconcile");
+        assert_eq!(body["last"]["target"], json!([10, 20]));
+        assert_eq!(
+            body["last"]["off_target"],
+            json!([{ "channel": 0, "target": 10, "actual": 50 }])
+        );
+        let events = body["last"]["events"].as_array().unwrap();
+        assert!(events[0]
+            .as_str()
+            .unwrap()
+            .starts_with("Link dropped during a MOVE to [10, 20]"));
+        assert!(events.contains(&json!("1 joints away from the target")));
+        assert_eq!(
+            server
+                .post("/api/pose", json!({ "angles": [30] }))
+                .await
+                .status,
+            200
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reassert_moves_on_to_the_target() {
+        let server = interrupted(LinkLossPolicy::Reassert).await;
+        let sent = moves(&server);
+        assert_eq!(sent.len(), 1, "{:?}", sent);
+        assert!(
+            sent[0].starts_with("MOVE ") && sent[0].ends_with(" 10,20"),
+            "{:?}",
+            sent
+        );
+        let state = server.server.state();
+        assert_eq!(state.confirmed_positions()[..2], [Some(10), Some(20)]);
+        let events = state.link_loss.last().unwrap().events;
+        assert!(
+            events.last().unwrap().starts_with("Re-asserted the target"),
+            "{:?}",
+            events
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reassert_holds_back_with_positions_unknown() {
+        let server =
+            TestServer::with_config(|config| config.link_loss.policy = LinkLossPolicy::Reassert)
+                .await;
+        let state = server.server.state();
+        state.link_loss.sending(&[10, 20]);
+        state.link_loss.lost(state.clock.now_ms());
+        server.mock.script().angles[1] = None;
+        handlers::recover_link_loss(state, &*server.mock);
+        assert!(moves(&server).is_empty());
+        let recovery = state.link_loss.last().unwrap();
+        assert_eq!(recovery.off_target.len(), 2);
+        assert_eq!(
+            recovery.events.last().unwrap(),
+            "Not re-asserting the target with positions unknown"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn latch_refuses_motion_until_acknowledged() {
+        let server = interrupted(LinkLossPolicy::Latch).await;
+        assert!(moves(&server).is_empty());
+        let state = server.server.state();
+        assert_eq!(state.confirmed_positions()[0], Some(50));
+
+        let reply = server.post("/api/pose", json!({ "angles": [30] })).await;
+        assert_eq!(reply.status, 409);
+        assert_eq!(reply.code(), "LINK_LOSS_LATCHED");
+        assert!(moves(&server).is_empty());
+
+        let body = server.get("/api/link-loss").await.body;
+        assert_eq!(body["latched"], true);
+        assert_eq!(
+            body["last"]["events"].as_array().unwrap().last().unwrap(),
+            "Motion latched until POST /api/link-loss/ack"
+        );
+        server.post("/api/link-loss/ack", json!({})).await;
+        assert_eq!(
+            server
+                .post("/api/pose", json!({ "angles": [30] }))
+                .await
+                .status,
+            200
+        );
+        assert_eq!(moves(&server), ["POSE 30"]);
+    }
+}
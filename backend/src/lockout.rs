@@ -0,0 +1,243 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::persist::{self, Persister};
+
+/// Channels locked out until a human re-enables them, e.g. after
+/// maintenance on a joint
+///
+/// Unlike `kind = "disabled"` in the config, the lockout is switched at
+/// runtime and kept in `lockout_file` (if set) across restarts.
+#[derive(Default)]
+pub struct ChannelLockout {
+    disabled: Mutex<BTreeSet<u8>>,
+    path: Option<PathBuf>,
+    persister: Persister,
+}
+
+/// Contents of the lockout file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LockoutFile {
+    disabled: BTreeSet<u8>,
+}
+
+impl ChannelLockout {
+    /// Load the lockout from `path`, or its backup if the file is corrupt;
+    /// a missing file locks nothing
+    pub fn load(path: Option<&Path>, persister: &Persister) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let file: LockoutFile = persist::load(path, persister, |text| {
+            serde_json::from_str(text)
+                .with_context(|| format!("Failed to parse lockout {}", path.display()))
+        })?
+        .unwrap_or_default();
+        Ok(Self {
+            disabled: Mutex::new(file.disabled),
+            path: Some(path.to_path_buf()),
+            persister: persister.clone(),
+        })
+    }
+
+    pub fn is_disabled(&self, channel: u8) -> bool {
+        self.disabled.lock().unwrap().contains(&channel)
+    }
+
+    /// Locked-out channels in ascending order
+    pub fn disabled(&self) -> Vec<u8> {
+        self.disabled.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Lock a channel out or re-enable it, saving the lockout; returns
+    /// whether anything changed
+    pub fn set(&self, channel: u8, disabled: bool) -> Result<bool> {
+        let mut channels = self.disabled.lock().unwrap();
+        let changed = if disabled {
+            channels.insert(channel)
+        } else {
+            channels.remove(&channel)
+        };
+        if changed {
+            if let Err(e) = self.save(&channels) {
+                // Keep memory and file in agreement
+                if disabled {
+                    channels.remove(&channel);
+                } else {
+                    channels.insert(channel);
+                }
+                return Err(e);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Queue the lockout to be written to disk
+    fn save(&self, channels: &BTreeSet<u8>) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = LockoutFile {
+            disabled: channels.clone(),
+        };
+        self.persister.submit(path, serde_json::to_vec_pretty(&file)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, TestServer};
+    use reqwest::Method;
+    use serde_json::json;
+
+    async fn disable(server: &TestServer, channel: u8) {
+        let path = format!("/api/servo/{}/disable", channel);
+        assert_eq!(server.admin(Method::POST, &path, None).await.status, 200);
+        server.mock.take_commands();
+    }
+
+    #[test]
+    fn lockout_is_kept_in_its_file() {
+        let dir = TempDir::new("lockout");
+        let path = dir.join("lockout.json");
+        let persister = Persister::default();
+        let lockout = ChannelLockout::load(Some(&path), &persister).unwrap();
+        assert!(lockout.disabled().is_empty());
+
+        assert!(lockout.set(3, true).unwrap());
+        assert!(lockout.set(1, true).unwrap());
+        assert!(!lockout.set(1, true).unwrap());
+        assert!(!lockout.set(4, false).unwrap());
+        assert!(persister.write_pending());
+
+        let loaded = ChannelLockout::load(Some(&path), &persister).unwrap();
+        assert_eq!(loaded.disabled(), [1, 3]);
+        assert!(loaded.is_disabled(3) && !loaded.is_disabled(2));
+        assert!(loaded.set(3, false).unwrap());
+        assert!(persister.write_pending());
+        let loaded = ChannelLockout::load(Some(&path), &persister).unwrap();
+        assert_eq!(loaded.disabled(), [1]);
+    }
+
+    #[test]
+    fn lockout_without_a_file_stays_in_memory() {
+        let persister = Persister::default();
+        let lockout = ChannelLockout::load(None, &persister).unwrap();
+        assert!(lockout.set(2, true).unwrap());
+        assert!(persister.pending().is_empty());
+        assert_eq!(lockout.disabled(), [2]);
+    }
+
+    #[test]
+    fn malformed_file_is_refused() {
+        let dir = TempDir::new("lockout-malformed");
+        let path = dir.join("lockout.json");
+        std::fs::write(&path, "{\"disabled\": [\"two\"]}").unwrap();
+        assert!(ChannelLockout::load(Some(&path), &Persister::default()).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pose_holds_the_disabled_channel() {
+        let server = TestServer::start().await;
+        disable(&server, 1).await;
+        let reply = server
+            .post("/api/pose", json!({ "angles": [10, 20, 30] }))
+            .await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body["skipped"], json!([1]));
+        let commands = server.mock.take_commands();
+        assert_eq!(commands.last().unwrap(), "POSE 10,90,30");
+
+        // Already where it is held: nothing to skip
+        let reply = server
+            .post("/api/pose", json!({ "angles": [10, 90, 30] }))
+            .await;
+        assert!(reply.body.get("skipped").is_none());
+        // Beyond the pose
+        disable(&server, 5).await;
+        let reply = server.post("/api/pose", json!({ "angles": [10] })).await;
+        assert!(reply.body.get("skipped").is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sequence_steps_hold_the_disabled_channel() {
+        let server = TestServer::start().await;
+        let sequence = json!({ "steps": [
+            { "duration_ms": 10, "angles": [10, 20] },
+            { "duration_ms": 10, "angles": [30, 40] },
+        ] });
+        server.put("/api/sequences/wave", sequence).await;
+        disable(&server, 1).await;
+        let reply = server.post("/api/sequences/wave/execute", json!({})).await;
+        assert_eq!(reply.status, 200);
+        let moves: Vec<String> = server
+            .mock
+            .take_commands()
+            .into_iter()
+            .filter(|c| c.starts_with("MOVE"))
+            .collect();
+        assert_eq!(moves, ["MOVE 10 10,90", "MOVE 10 30,90"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn strict_mode_refuses_instead_of_skipping() {
+        let server = TestServer::with_config(|config| {
+            config.lockout_strict = true;
+            config.home = Some(vec![80, 70]);
+        })
+        .await;
+        server
+            .put(
+                "/api/sequences/wave",
+                json!({ "steps": [{ "duration_ms": 10, "angles": [10, 20] }] }),
+            )
+            .await;
+        disable(&server, 1).await;
+        for (path, body) in [
+            ("/api/pose", json!({ "angles": [10, 20] })),
+            (
+                "/api/move",
+                json!({ "duration_ms": 10, "angles": [10, 20] }),
+            ),
+            ("/api/sequences/wave/execute", json!({})),
+        ] {
+            let reply = server.post(path, body).await;
+            assert_eq!(reply.status, 423, "{}", path);
+            assert_eq!(reply.code(), "CHANNEL_DISABLED", "{}", path);
+        }
+        let sent = server.mock.take_commands();
+        assert!(sent.iter().all(|c| c.starts_with("GET")), "{:?}", sent);
+
+        // Home leaves the channel where it is even so
+        let reply = server.post("/api/home", json!({})).await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body["skipped"], json!([1]));
+        assert_eq!(server.mock.take_commands().last().unwrap(), "POSE 80,90");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lockout_survives_a_restart() {
+        let dir = TempDir::new("lockout-restart");
+        let path = dir.join("lockout.json");
+        let configured = path.clone();
+        let server = TestServer::with_config(|config| config.lockout_file = Some(configured)).await;
+        disable(&server, 2).await;
+        server.server.shutdown().await.unwrap();
+
+        let server = TestServer::with_config(|config| config.lockout_file = Some(path)).await;
+        let reply = server
+            .post("/api/servo/2/angle", json!({ "angle": 45 }))
+            .await;
+        assert_eq!(reply.status, 423);
+        assert_eq!(reply.code(), "CHANNEL_DISABLED");
+        let reply = server
+            .admin(Method::POST, "/api/servo/2/enable", None)
+            .await;
+        assert_eq!(reply.body["disabled"], json!([]));
+    }
+}
@@ -0,0 +1,207 @@
+use std::str::FromStr;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Format of the log lines, chosen with `LOG_FORMAT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Compact,
+    /// One JSON object per line, the event's fields at the top level and
+    /// the request's in `span`
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "Invalid LOG_FORMAT {:?}, expected json or compact",
+                other
+            )),
+        }
+    }
+}
+
+/// Layer writing the log lines in `format` to `writer`
+pub fn log_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Compact => layer.boxed(),
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::config::Config;
+    use crate::connection::ConnectionLog;
+    use crate::controller::ServoController;
+    use crate::protocol::Channel;
+    use crate::serial::{Observers, SerialManager, NUM_SERVOS};
+    use crate::simulator::SimulatedPort;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::broadcast;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Log output kept in memory
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'w> MakeWriter<'w> for Captured {
+        type Writer = Captured;
+
+        fn make_writer(&'w self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<String> {
+            let output = self.0.lock().unwrap();
+            String::from_utf8_lossy(&output)
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    /// Read channel 1 of the simulator within a request span, logging in
+    /// `format`
+    fn read_in_request(format: LogFormat) -> Vec<String> {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(log_layer(format, captured.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let config = Config::default();
+            let port = SimulatedPort::new(config.serial.baud, &config.protocol, &config.simulation);
+            let observers = Observers {
+                connection: Arc::new(ConnectionLog::new(None)),
+                unsolicited: broadcast::channel(16).0,
+                clock: Arc::new(SystemClock),
+            };
+            let serial = SerialManager::with_port(
+                Box::new(port),
+                &config.serial,
+                &config.timeouts,
+                &config.protocol,
+                observers,
+            )
+            .unwrap();
+            serial.start_serial_mode().unwrap();
+            let span = tracing::info_span!(
+                "request",
+                request_id = "req-42",
+                method = "GET",
+                path = "/api/servo/1",
+            );
+            let _entered = span.enter();
+            let channel = Channel::new(1, NUM_SERVOS).unwrap();
+            serial.get_servo_angle(channel).unwrap();
+        });
+        captured.lines()
+    }
+
+    #[test]
+    fn formats_are_parsed() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("compact".parse(), Ok(LogFormat::Compact));
+        assert_eq!(
+            "pretty".parse::<LogFormat>(),
+            Err("Invalid LOG_FORMAT \"pretty\", expected json or compact".to_string())
+        );
+    }
+
+    #[test]
+    fn command_lines_are_structured_json() {
+        let lines = read_in_request(LogFormat::Json);
+        let events: Vec<Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).expect("every line is JSON"))
+            .collect();
+        let answered = events
+            .iter()
+            .find(|event| event["message"] == "Command answered" && event["command"] == "get")
+            .expect("the read was logged");
+        let object = answered.as_object().unwrap();
+        let mut keys: Vec<&str> = object.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            [
+                "command",
+                "latency_ms",
+                "level",
+                "message",
+                "response",
+                "span",
+                "target",
+                "timestamp"
+            ]
+        );
+        assert_eq!(answered["level"], "DEBUG");
+        assert_eq!(answered["target"], "robotarm_backend::serial");
+        assert_eq!(answered["command"], "get");
+        assert!(answered["latency_ms"].is_u64());
+        assert!(answered["timestamp"].is_string());
+        assert_eq!(
+            answered["span"],
+            serde_json::json!({
+                "name": "request",
+                "request_id": "req-42",
+                "method": "GET",
+                "path": "/api/servo/1",
+            })
+        );
+
+        let sending = events
+            .iter()
+            .find(|event| event["message"] == "Sending command" && event["command"] == "get")
+            .unwrap();
+        assert_eq!(sending["command"], "get");
+        assert_eq!(sending["line"], "GET 1");
+    }
+
+    #[test]
+    fn compact_lines_are_plain_text() {
+        let lines = read_in_request(LogFormat::Compact);
+        let answered = lines
+            .iter()
+            .find(|line| line.contains("Command answered") && line.contains("\"get\""))
+            .unwrap();
+        assert!(!answered.starts_with('{'));
+        // The request's fields lead, in the colours of a terminal
+        assert!(answered.contains("request"), "{}", answered);
+        assert!(answered.contains("\"req-42\""), "{}", answered);
+        assert!(serde_json::from_str::<Value>(answered).is_err());
+    }
+}
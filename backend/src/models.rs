@@ -1,9 +1,86 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::audit::AuditEntry;
+use crate::backup::{BackupStatus, Outcome};
+use crate::benchmark::BenchmarkCommand;
+use crate::compensation::{Fit, Model, Sample};
+use crate::command_queue::QueueEntry;
+use crate::config::{ChannelKind, Config, Role, WatchConflicts};
+use crate::connection::{ConnectionState, Transition};
+use crate::current_draw::ChannelDraw;
+use crate::feedback::FeedbackCalibration;
+use crate::library::Library;
+use crate::library_watch::{WatchProblem, WatchedFile};
+use crate::link_loss::Recovery;
+use crate::overrides::{Override, Relaxed};
+use crate::persist::Problem;
+use crate::pattern::PatternKind;
+use crate::planner::Frame;
+use crate::poller::PollStatus;
+use crate::protocol::{Channel, CommandSpec};
+use crate::replication::ReplicationStatus;
+use crate::rezero::{OutOfRange, Store};
+use crate::routes::RouteInfo;
+use crate::schedule::ScheduledMove;
+use crate::schema::ParamSchema;
+use crate::serial::SerialMode;
+use crate::tokens::Caller;
+use crate::violations::Violation;
+
+/// Query parameters overriding protocol settings for one command
+#[derive(Debug, Default, Deserialize)]
+pub struct CommandQuery {
+    /// Clear the serial input buffer before sending (config default if unset)
+    pub clear_input: Option<bool>,
+    /// Wait for the reconnect task if the device is disconnected instead
+    /// of failing with 503
+    #[serde(default)]
+    pub wait: bool,
+}
 
 /// Request to set servo angle
 #[derive(Debug, Deserialize)]
 pub struct SetAngleRequest {
-    pub angle: u8,
+    pub angle: u16,
+}
+
+/// Request to set several servo angles one by one, by channel name or
+/// index
+#[derive(Debug, Deserialize)]
+pub struct BatchAngleRequest {
+    pub angles: BTreeMap<String, u16>,
+}
+
+/// Response of `POST /api/servos/batch`: the outcome of each channel, in
+/// the order they were sent
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub results: Vec<ChannelResult>,
+}
+
+/// Outcome of one channel of a batch
+#[derive(Debug, Serialize)]
+pub struct ChannelResult {
+    pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub angle: u16,
+    pub status: ChannelStatus,
+    /// Why the channel failed or was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelStatus {
+    Ok,
+    Failed,
+    /// Not sent, as the port failed on an earlier channel
+    Skipped,
 }
 
 /// Request to set servo PWM pulse width
@@ -12,30 +89,560 @@ pub struct SetPwmRequest {
     pub pulse_us: u16,
 }
 
+/// Request to drive a PWM output channel; exactly one field must be set
+#[derive(Debug, Deserialize)]
+pub struct OutputRequest {
+    pub pulse_us: Option<u16>,
+    /// Duty cycle of the 20ms PWM period
+    pub percent: Option<f64>,
+}
+
+/// Target angles for POSE/MOVE
+///
+/// Either a positional list (one slot per channel, starting at 0) or a map
+/// of channel name or index to angle, where unlisted channels hold their
+/// current position.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PoseAngles {
+    List(Vec<u16>),
+    Map(BTreeMap<String, u16>),
+}
+
 /// Request to execute POSE command
 #[derive(Debug, Deserialize)]
 pub struct PoseRequest {
-    pub angles: Vec<u8>,
+    pub angles: PoseAngles,
+}
+
+/// Query parameters of `GET /api/validate-library`
+#[derive(Debug, Deserialize)]
+pub struct ValidateLibraryQuery {
+    /// Propose a fixed version of each entry that fails
+    pub fix: Option<LibraryFix>,
+}
+
+/// How `GET /api/validate-library` proposes fixes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LibraryFix {
+    /// Each failing angle becomes the nearest one that can be sent
+    Clamp,
+}
+
+/// Response of `GET /api/validate-library`
+#[derive(Debug, Serialize)]
+pub struct LibraryReport {
+    /// No entry fails
+    pub ok: bool,
+    /// Entries checked
+    pub checked: usize,
+    /// The entries that fail, with their violations
+    pub failing: Vec<EntryReport>,
+}
+
+/// A stored entry that would fail if executed now
+#[derive(Debug, Serialize)]
+pub struct EntryReport {
+    /// `pose`, `sequence`, `schedule` or `home`
+    pub kind: &'static str,
+    /// Pose or sequence name, scheduled move id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub violations: Vec<EntryViolation>,
+    /// Proposed fixed entry, not applied; only with `fix`, and only if
+    /// every violation can be fixed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed: Option<serde_json::Value>,
+}
+
+/// Why a stored entry would be refused, with the code the command would
+/// fail with
+#[derive(Debug, Serialize)]
+pub struct EntryViolation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub angle: Option<u16>,
+    pub code: String,
+    pub error: String,
+}
+
+/// Body of `POST /api/validate`
+#[derive(Debug, Deserialize)]
+pub struct ValidateRequest {
+    pub poses: Vec<CandidatePose>,
+}
+
+/// A pose to validate, as angles by channel or with the planner's id
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum CandidatePose {
+    Angles(Vec<u16>),
+    Identified {
+        /// Any JSON value, given back as it is
+        #[serde(default)]
+        id: Option<serde_json::Value>,
+        angles: Vec<u16>,
+    },
+}
+
+/// Response of `POST /api/validate`
+#[derive(Debug, Serialize)]
+pub struct ValidateResponse {
+    /// Every pose passes
+    pub ok: bool,
+    pub checked: usize,
+    /// One verdict per pose, in request order
+    pub results: Vec<PoseVerdict>,
+}
+
+/// Verdict on one pose of `POST /api/validate`
+#[derive(Debug, Serialize)]
+pub struct PoseVerdict {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    pub ok: bool,
+    pub violations: Vec<EntryViolation>,
+}
+
+/// Response of `POST /api/pose/estimate_load`
+#[derive(Debug, Serialize)]
+pub struct LoadEstimate {
+    /// Total draw as the move starts, in milliamps
+    pub peak_ma: u32,
+    /// Total draw holding the pose once reached
+    pub holding_ma: u32,
+    pub supply_limit_ma: Option<u32>,
+    /// `peak_ma` is above `supply_limit_ma`
+    pub over_limit: bool,
+    pub channels: Vec<ChannelDraw>,
+}
+
+/// One joint of `POST /api/pose/named`
+#[derive(Debug, Deserialize)]
+pub struct NamedAngle {
+    /// Servo name from the config
+    pub name: String,
+    pub angle: u16,
 }
 
 /// Request to execute MOVE command
+///
+/// The duration is given either as `duration_ms` or as a `duration` with
+/// an explicit unit; both at once are refused rather than one picked.
 #[derive(Debug, Deserialize)]
+#[serde(try_from = "MoveRequestFields")]
 pub struct MoveRequest {
+    pub duration_ms: u32,
+    pub angles: PoseAngles,
+    /// Run in the background as a cancelable move
+    pub track: bool,
+}
+
+/// [`MoveRequest`] as sent
+#[derive(Deserialize)]
+struct MoveRequestFields {
+    duration_ms: Option<u32>,
+    duration: Option<MoveDuration>,
+    angles: PoseAngles,
+    #[serde(default)]
+    track: bool,
+}
+
+/// A duration with its unit, `{"secs": 2.5}` or `{"ms": 2500}`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MoveDuration {
+    secs: Option<f64>,
+    ms: Option<u32>,
+}
+
+impl TryFrom<MoveRequestFields> for MoveRequest {
+    type Error = String;
+
+    fn try_from(fields: MoveRequestFields) -> Result<Self, String> {
+        let duration_ms = match (fields.duration_ms, fields.duration) {
+            (Some(ms), None) => ms,
+            (None, Some(duration)) => duration.to_ms()?,
+            (Some(_), Some(_)) => return Err("Give either duration_ms or duration".to_string()),
+            (None, None) => return Err("missing field `duration_ms`".to_string()),
+        };
+        Ok(Self {
+            duration_ms,
+            angles: fields.angles,
+            track: fields.track,
+        })
+    }
+}
+
+impl MoveDuration {
+    fn to_ms(&self) -> Result<u32, String> {
+        match (self.secs, self.ms) {
+            (Some(secs), None) => {
+                let ms = (secs * 1000.0).round();
+                if !(0.0..=u32::MAX as f64).contains(&ms) {
+                    return Err(format!("Invalid duration: {} secs", secs));
+                }
+                Ok(ms as u32)
+            }
+            (None, Some(ms)) => Ok(ms),
+            _ => Err("duration takes exactly one of secs and ms".to_string()),
+        }
+    }
+}
+
+/// Request to schedule a MOVE; exactly one of `delay_ms` and `at_ms`
+#[derive(Debug, Deserialize)]
+pub struct ScheduleMoveRequest {
+    pub duration_ms: u16,
+    pub angles: PoseAngles,
+    /// Time from now until the move
+    #[serde(default)]
+    pub delay_ms: Option<u64>,
+    /// Time of the move in ms since the epoch
+    #[serde(default)]
+    pub at_ms: Option<u64>,
+}
+
+/// Response of `GET /api/schedule`
+#[derive(Debug, Serialize)]
+pub struct ScheduleList {
+    /// Waiting or running, by due time
+    pub pending: Vec<ScheduledMove>,
+    /// Most recent first
+    pub finished: Vec<ScheduledMove>,
+}
+
+/// Request to benchmark the serial link
+#[derive(Debug, Deserialize)]
+pub struct BenchmarkRequest {
+    pub duration_ms: u64,
+    pub command: BenchmarkCommand,
+}
+
+/// Request to trace a pattern with two channels
+#[derive(Debug, Deserialize)]
+pub struct PatternRequest {
+    pub pattern: PatternKind,
+    /// Degrees from the center to the edge
+    pub amplitude: u16,
+    /// Time of one cycle
+    pub period_ms: u32,
+    #[serde(default = "default_pattern_cycles")]
+    pub cycles: u32,
+    /// Channels along the x and y axis
+    #[serde(default = "default_pattern_channels")]
+    pub channels: [u8; 2],
+    /// Center angles of the channels, their positions by default
+    #[serde(default)]
+    pub center: Option<[u16; 2]>,
+    /// Frequencies of the axes of a Lissajous figure
+    #[serde(default = "default_lissajous_ratio")]
+    pub ratio: [u8; 2],
+}
+
+fn default_pattern_cycles() -> u32 {
+    1
+}
+
+fn default_pattern_channels() -> [u8; 2] {
+    [0, 1]
+}
+
+fn default_lissajous_ratio() -> [u8; 2] {
+    [3, 2]
+}
+
+/// Request to run a motion script
+#[derive(Debug, Deserialize)]
+pub struct ScriptRequest {
+    pub source: String,
+}
+
+/// Response for a pose or move
+#[derive(Debug, Serialize)]
+pub struct MotionResponse {
+    pub status: String,
+    /// Locked-out channels held at their position instead of moved
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<u8>,
+}
+
+/// Response for a tracked MOVE that was started
+#[derive(Debug, Serialize)]
+pub struct MoveHandle {
+    pub id: u64,
+    pub status: String,
+    pub duration_ms: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<u8>,
+}
+
+/// Response for cancelling a tracked MOVE
+#[derive(Debug, Serialize)]
+pub struct MoveCancelResponse {
+    pub id: u64,
+    /// "cancelled", or "completed" if it finished first
+    pub status: String,
+    /// Measured angles the arm was stopped at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub angles: Option<Vec<u16>>,
+}
+
+/// Estimated progress of a running tracked MOVE
+#[derive(Debug, Serialize)]
+pub struct MoveProgress {
+    pub id: u64,
+    /// Fraction of the duration elapsed, 0.0 to 1.0
+    pub progress: f64,
+    pub elapsed_ms: u64,
+    pub duration_ms: u64,
+}
+
+/// Request to plan (and optionally run) a trajectory through waypoints
+#[derive(Debug, Deserialize)]
+pub struct TrajectoryRequest {
+    pub waypoints: Vec<TrajectoryWaypoint>,
+    /// Frames per second of the sampled trajectory (1-50)
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate_hz: u16,
+    /// Play the frames back on the arm after planning
+    #[serde(default)]
+    pub execute: bool,
+}
+
+fn default_sample_rate() -> u16 {
+    20
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TrajectoryWaypoint {
+    /// Time to reach this waypoint from the previous one; for the first,
+    /// the time to move there from the current position when executing
+    #[serde(default)]
     pub duration_ms: u16,
-    pub angles: Vec<u8>,
+    pub angles: Vec<u16>,
+}
+
+/// A planned trajectory
+#[derive(Debug, Serialize)]
+pub struct TrajectoryPlan {
+    pub sample_ms: u32,
+    pub duration_ms: u32,
+    pub frames: Vec<Frame>,
+    pub executed: bool,
 }
 
 /// Response for servo position query
 #[derive(Debug, Serialize)]
 pub struct ServoPosition {
-    pub channel: u8,
-    pub angle: u8,
+    pub channel: Channel,
+    /// `null` if the firmware answered with an angle sentinel
+    pub angle: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "ChannelKind::is_servo")]
+    pub kind: ChannelKind,
+    /// Estimated end of the motion in progress (ms since the epoch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub busy_until_ms: Option<u64>,
+}
+
+/// Response of `GET /api/servo/:id/state`: everything known about one
+/// channel, `null` where the firmware can't tell
+#[derive(Debug, Serialize)]
+pub struct ServoState {
+    pub channel: Channel,
+    pub name: Option<String>,
+    /// Not locked out
+    pub enabled: bool,
+    /// The arm is connected
+    pub connected: bool,
+    /// Read from the firmware for this request
+    pub measured: Option<u16>,
+    /// Last commanded angle
+    pub commanded: Option<u16>,
+    /// `measured - commanded`
+    pub error: Option<i32>,
+    /// Last known angle, commanded or read back
+    pub known: Option<u16>,
+    /// The firmware doesn't report pulse widths
+    pub pulse_us: Option<u16>,
+    /// The firmware doesn't report load; made up for the simulated arm
+    /// with `simulation.telemetry`
+    pub load_ma: Option<u32>,
+    /// The firmware doesn't report temperatures; made up like `load_ma`
+    pub temperature_c: Option<f64>,
+    /// Feedback ADC counts, where the firmware has `ADC <n>`
+    pub feedback_raw: Option<u32>,
+    pub busy: bool,
+    pub busy_method: BusyMethod,
+    /// Estimated end of the motion in progress (ms since the epoch)
+    pub busy_until_ms: Option<u64>,
+    pub min: u16,
+    pub max: u16,
+}
+
+/// Response for the servo busy query
+#[derive(Debug, Serialize)]
+pub struct ServoBusy {
+    pub channel: Channel,
+    pub busy: bool,
+    pub method: BusyMethod,
+    /// Estimated time until the servo is done (estimate only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_ms: Option<u64>,
+}
+
+/// How the busy state was determined
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusyMethod {
+    /// Reported by the firmware
+    Firmware,
+    /// Estimated from the commanded MOVE durations
+    Estimate,
 }
 
 /// Response for all servos query
 #[derive(Debug, Serialize)]
 pub struct ServoPositions {
     pub servos: Vec<ServoPosition>,
+    /// The reads were cut short by a disconnect; `servos` holds the ones
+    /// read before it
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub incomplete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Response for config reload
+#[derive(Debug, Serialize)]
+pub struct ConfigReloadResponse {
+    pub changed: Vec<String>,
+    pub requires_restart: Vec<String>,
+}
+
+/// Configured profiles, for `GET /api/profiles`
+#[derive(Debug, Serialize)]
+pub struct ProfileList {
+    pub profiles: Vec<String>,
+    pub active: Option<String>,
+}
+
+/// Response for activating a profile
+#[derive(Debug, Serialize)]
+pub struct ProfileActivated {
+    pub profile: String,
+    /// Settings that differ from the previous ones
+    pub changed: Vec<String>,
+}
+
+/// Global motion scale, for `GET`/`PUT /api/motion-scale`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MotionScale {
+    /// Fraction of the commanded motion made around each servo's center
+    pub scale: f64,
+}
+
+/// Query parameters for the audit trail
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Only entries at or after this unix timestamp (ms)
+    pub since: Option<u64>,
+    pub actor: Option<String>,
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// One page of audit entries
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Body of `POST /api/overrides`
+#[derive(Debug, Deserialize)]
+pub struct CreateOverrideRequest {
+    /// How long the override lasts, at most `overrides::MAX_TTL_MS`
+    pub ttl_ms: u64,
+    pub relaxed: Relaxed,
+}
+
+/// Response of `GET /api/overrides`
+#[derive(Debug, Serialize)]
+pub struct OverrideStatus {
+    pub active: Option<Override>,
+}
+
+/// Response of `POST /api/overrides`, the only one carrying the token
+#[derive(Debug, Serialize)]
+pub struct CreatedOverride {
+    #[serde(flatten)]
+    pub session: Override,
+    pub token: String,
+}
+
+/// Body of `POST /api/maintenance/rezero`
+#[derive(Debug, Deserialize)]
+pub struct RezeroRequest {
+    pub channel: u8,
+    /// Degrees added to every angle of the channel
+    pub offset: i32,
+    pub apply_to: Vec<Store>,
+    /// Clamp angles that would leave the firmware's range instead of
+    /// refusing the whole re-zero
+    #[serde(default)]
+    pub clamp: bool,
+}
+
+/// Response of `POST /api/maintenance/rezero` and its undo
+#[derive(Debug, Serialize)]
+pub struct RezeroResponse {
+    /// Entry to pass to `POST /api/maintenance/rezero/undo/:audit_id`;
+    /// `None` if nothing changed
+    pub audit_id: Option<u64>,
+    /// Angles changed
+    pub shifted: usize,
+    /// Angles clamped to the firmware's range
+    pub out_of_range: Vec<OutOfRange>,
+}
+
+/// Export of the backend's state
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub timestamp_ms: u64,
+    pub config: Config,
+    pub library: Library,
+    /// Sag compensation models by channel
+    pub compensation: BTreeMap<u8, Model>,
+    pub positions: Vec<Option<u16>>,
+    pub recent_audit: Vec<AuditEntry>,
+}
+
+/// Response of `GET /api/queue`
+#[derive(Debug, Serialize)]
+pub struct QueueList {
+    /// Without a connection nothing is queued
+    pub connected: bool,
+    pub entries: Vec<QueueEntry>,
+}
+
+/// Response of `DELETE /api/queue`
+#[derive(Debug, Serialize)]
+pub struct QueueFlush {
+    pub cancelled: Vec<QueueEntry>,
+    /// Entries left: the one in flight and critical ones
+    pub kept: usize,
 }
 
 /// Generic success response
@@ -44,15 +651,757 @@ pub struct SuccessResponse {
     pub status: String,
 }
 
-/// Generic error response
+/// Response of `POST /api/sequence/stream`
+#[derive(Debug, Serialize)]
+pub struct StreamedSequence {
+    pub status: String,
+    /// Steps played
+    pub steps: usize,
+}
+
+/// Request body for executing a saved pose or sequence
+#[derive(Debug, Default, Deserialize)]
+pub struct ExecuteRequest {
+    /// Skip precondition checks (requires the admin token)
+    #[serde(default, rename = "override")]
+    pub override_preconditions: bool,
+}
+
+/// Request body for `POST /api/poses/:name/adjusted`
+#[derive(Debug, Deserialize)]
+pub struct AdjustedPoseRequest {
+    /// Degrees added to the saved angles, by channel name or index
+    pub offsets: BTreeMap<String, i32>,
+    /// Skip precondition checks (requires the admin token)
+    #[serde(default, rename = "override")]
+    pub override_preconditions: bool,
+}
+
+/// Response of `POST /api/poses/:name/adjusted`
+#[derive(Debug, Serialize)]
+pub struct AdjustedPoseResponse {
+    pub status: String,
+    /// Angles of the saved pose
+    pub base: Vec<u16>,
+    /// Angles moved to
+    pub angles: Vec<u16>,
+    /// Channels whose adjusted angle was clamped to the soft limits
+    pub clamped: Vec<u8>,
+}
+
+/// Response for `GET /api/busy`
+#[derive(Debug, Serialize)]
+pub struct BusyResponse {
+    pub busy: bool,
+    /// When the last busy servo is estimated to be free (ms since the epoch)
+    pub busy_until_ms: Option<u64>,
+    pub channels: Vec<ChannelBusy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelBusy {
+    pub channel: u8,
+    pub busy_until_ms: u64,
+}
+
+/// Commanded versus measured angle of one servo
+#[derive(Debug, Serialize)]
+pub struct TrackingError {
+    pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Last commanded angle, `None` if not commanded since connecting
+    pub commanded: Option<u16>,
+    pub measured: u16,
+    /// Measured minus commanded angle
+    pub error: Option<i32>,
+    pub exceeded: bool,
+}
+
+/// Response for `GET /api/servos/error`
 #[derive(Debug, Serialize)]
+pub struct TrackingErrors {
+    pub threshold: u16,
+    /// Channels whose error exceeds the threshold
+    pub exceeded: Vec<u8>,
+    pub channels: Vec<TrackingError>,
+}
+
+/// Command statistics of one channel
+#[derive(Debug, Serialize)]
+pub struct ChannelCommandStats {
+    pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Commands since startup
+    pub count: u64,
+    /// Commands per second over the last `window_ms`
+    pub rate: f64,
+    pub last_angle: Option<u16>,
+    /// Time of the last command in ms since the epoch
+    pub last_ms: Option<u64>,
+}
+
+/// Response for `GET /api/servos/stats`
+#[derive(Debug, Serialize)]
+pub struct ServoStats {
+    pub window_ms: u64,
+    /// Channels commanded since startup
+    pub servos: Vec<ChannelCommandStats>,
+}
+
+/// Lifetime use of one servo in `GET /api/wear`
+#[derive(Debug, Serialize)]
+pub struct ChannelWearInfo {
+    pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub commands: u64,
+    /// Commands ending in each `bucket_degrees` wide bucket, from 0
+    pub histogram: Vec<u64>,
+    /// Degrees moved between commanded angles
+    pub travel_deg: u64,
+    /// Estimated time spent moving
+    pub moving_ms: u64,
+    /// Share of the tracked time spent moving, the rest holding
+    pub duty: f64,
+}
+
+/// Response for `GET /api/wear`
+#[derive(Debug, Serialize)]
+pub struct WearResponse {
+    /// When counting started, in ms since the epoch
+    pub since_ms: u64,
+    /// Time the backend ran since then
+    pub tracked_ms: u64,
+    pub bucket_degrees: u16,
+    /// Channels commanded since counting started
+    pub servos: Vec<ChannelWearInfo>,
+}
+
+/// Query parameters of `GET /api/servos`
+#[derive(Debug, Default, Deserialize)]
+pub struct ServosQuery {
+    /// Include PWM output and disabled channels
+    #[serde(default)]
+    pub all: bool,
+}
+
+/// Query parameters selecting cached or freshly read positions
+#[derive(Debug, Deserialize)]
+pub struct FreshQuery {
+    #[serde(default)]
+    pub fresh: bool,
+    /// Wait for the reconnect task if the device is disconnected
+    #[serde(default)]
+    pub wait: bool,
+}
+
+/// Generic error response
+#[derive(Debug, Clone, Serialize)]
 pub struct ErrorResponse {
     pub error: String,
+    /// Machine-readable error code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ErrorResponse {
+    pub fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            code: None,
+            details: None,
+        }
+    }
+
+    pub fn with_code(code: &str, error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            code: Some(code.to_string()),
+            details: None,
+        }
+    }
+}
+
+/// Features enabled on this instance, for clients adapting their UI
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    /// The arm is simulated rather than real hardware
+    pub simulation: bool,
+    pub demo: bool,
+    /// An admin token is configured (precondition overrides possible)
+    pub admin: bool,
+    /// `POST /api/config/reload` is available
+    pub config_reload: bool,
+    /// Saved poses and sequences survive a restart
+    pub persistent_library: bool,
+    /// The audit trail survives a restart
+    pub persistent_audit: bool,
+    pub baud_autodetect: bool,
+    pub role: Role,
+    /// Changes are pushed to a warm-spare peer
+    pub replication: bool,
+    /// Servo names accepted in angle maps
+    pub named_channels: Vec<String>,
+    /// Names of the available optional features, see `features::FEATURES`
+    pub features: Vec<&'static str>,
+    /// Connected firmware, `None` while disconnected
+    pub firmware: Option<FirmwareInfo>,
+    /// Name and scopes of the request's bearer token, if it is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caller: Option<Caller>,
+}
+
+/// What is known about the connected firmware
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareInfo {
+    pub channels: u8,
+    /// Largest angle the firmware can represent
+    pub max_angle: u16,
+    pub baud_rate: u32,
+    pub simulated: bool,
+    /// Firmware commands the backend uses
+    pub commands: Vec<String>,
+}
+
+/// `version.json` of a support bundle
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub backend: String,
+    pub generated_ms: u64,
+    pub firmware: Option<FirmwareInfo>,
+}
+
+/// Response of `GET /api/schema`
+#[derive(Debug, Serialize)]
+pub struct SchemaResponse {
+    pub parameters: Vec<ParamSchema>,
+}
+
+/// Response of `GET /api/protocol`
+#[derive(Debug, Serialize)]
+pub struct ProtocolResponse {
+    /// Framing around every command line, before the newline
+    pub prefix: String,
+    pub suffix: String,
+    pub channels: u8,
+    pub max_angle: u16,
+    pub commands: Vec<CommandInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandInfo {
+    #[serde(flatten)]
+    pub spec: &'static CommandSpec,
+    /// Supported with the current `[protocol]` settings
+    pub enabled: bool,
+}
+
+/// Response of `GET /api/protocol/violations`
+#[derive(Debug, Serialize)]
+pub struct ProtocolViolations {
+    /// Violations fail their command
+    pub strict: bool,
+    /// Violations by kind since the connection was opened
+    pub counts: BTreeMap<&'static str, u64>,
+    /// Most recent first
+    pub recent: Vec<Violation>,
+}
+
+/// Response of `GET /api/link-loss`
+#[derive(Debug, Serialize)]
+pub struct LinkLossResponse {
+    /// Motion is refused until `POST /api/link-loss/ack`
+    pub latched: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last: Option<Recovery>,
+}
+
+/// Response of `GET /api/connection/history`
+#[derive(Debug, Serialize)]
+pub struct ConnectionHistory {
+    pub state: Option<ConnectionState>,
+    /// Oldest first
+    pub transitions: Vec<Transition>,
+}
+
+/// Body of `POST /api/poses/import-url` and `/api/sequences/import-url`
+#[derive(Debug, Deserialize)]
+pub struct UrlImportRequest {
+    pub url: String,
+    /// Added to the name of every imported item
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Response of `GET /api/routes`
+#[derive(Debug, Serialize)]
+pub struct RoutesResponse {
+    pub routes: Vec<RouteInfo>,
+}
+
+/// Response of `GET /api/backup/status` and `POST /api/backup/now`
+#[derive(Debug, Serialize)]
+pub struct BackupInfo {
+    /// A sink is configured
+    pub enabled: bool,
+    /// Directory or (redacted) URL backed up to
+    pub sink: Option<String>,
+    pub interval_ms: u64,
+    /// What the forced backup did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+    #[serde(flatten)]
+    pub status: BackupStatus,
+}
+
+/// Response of `GET /api/library/watch`
+#[derive(Debug, Serialize)]
+pub struct LibraryWatchInfo {
+    /// Directory watched, if configured
+    pub dir: Option<String>,
+    /// The directory is being watched
+    pub running: bool,
+    pub conflicts: WatchConflicts,
+    /// Loaded files by path
+    pub files: BTreeMap<String, WatchedFile>,
+    /// Recent files that failed to load, most recent first
+    pub problems: Vec<WatchProblem>,
+}
+
+/// Response of `GET /api/persistence`
+#[derive(Debug, Serialize)]
+pub struct PersistenceInfo {
+    /// Files with changes not written yet
+    pub pending: Vec<String>,
+    /// Files whose last write failed
+    pub failing: Vec<String>,
+    /// Recent recoveries and failed writes, most recent first
+    pub problems: Vec<Problem>,
+}
+
+/// Response of `GET /api/replication`
+#[derive(Debug, Serialize)]
+pub struct ReplicationInfo {
+    pub role: Role,
+    pub peer_url: Option<String>,
+    #[serde(flatten)]
+    pub status: ReplicationStatus,
+}
+
+/// Response of `POST /api/admin/promote`
+#[derive(Debug, Serialize)]
+pub struct PromoteResponse {
+    pub role: Role,
+    pub previous: Role,
 }
 
 /// Health check response
 #[derive(Debug, Serialize)]
 pub struct HealthResponse {
     pub status: String,
+    /// "connected", "simulated", "not_connected", or "refused" when the
+    /// firmware answers but refused serial mode
     pub serial: String,
+    pub simulated: bool,
+    /// Firmware mode last switched to, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<SerialMode>,
+    /// Button mode was entered for being idle, see `[idle_exit]`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub idle_stopped: bool,
+    /// The last supply voltage read was below `low_voltage_mv`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub low_voltage: bool,
+    /// Faults latched in the firmware as of the last read
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub faults: Vec<String>,
+    /// Rate of the background position polls, if enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_poll: Option<PollStatus>,
+    /// Active config profile, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// The positions couldn't be read after connecting
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub positions_unknown: bool,
+    /// Channels whose position is only restored from `last_pose_file`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub restored: Vec<u8>,
+    /// Files whose last write failed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub persistence_failing: Vec<String>,
+}
+
+/// Query for `GET /api/state-at`
+#[derive(Debug, Deserialize)]
+pub struct StateAtQuery {
+    /// Time in ms since the epoch
+    pub t: u64,
+}
+
+/// Arm state reconstructed for a past time
+#[derive(Debug, Serialize)]
+pub struct StateAt {
+    pub t: u64,
+    /// Oldest time the angle history still covers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_from_ms: Option<u64>,
+    /// The positions were lost (link failure or reconnect) and not known
+    /// again by `t`
+    pub positions_lost: bool,
+    /// Time of the last history entry at or before `t`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_ms: Option<u64>,
+    pub channels: Vec<ChannelStateAt>,
+    /// Script job running at `t`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job: Option<JobAt>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelStateAt {
+    pub channel: Channel,
+    /// Last commanded or measured angle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub angle: Option<u16>,
+    /// Whether anything was known about the channel at `t`
+    pub known: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobAt {
+    pub id: u64,
+    pub started_ms: u64,
+    /// Line and source of the statement being executed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// Query for `GET /api/export/jointstates`
+#[derive(Debug, Deserialize)]
+pub struct JointStatesQuery {
+    /// Start of the export in ms since the epoch (oldest history if unset)
+    pub from: Option<u64>,
+    /// End of the export in ms since the epoch (now if unset)
+    pub to: Option<u64>,
+    #[serde(default = "default_export_rate")]
+    pub rate_hz: u16,
+    /// `json` (default) or `csv`
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+fn default_export_rate() -> u16 {
+    10
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Resampled joint trajectory shaped like a series of ROS
+/// `sensor_msgs/JointState` messages
+#[derive(Debug, Serialize)]
+pub struct JointStates {
+    pub header: JointStatesHeader,
+    /// Joint names, in the order of each position row
+    pub name: Vec<String>,
+    /// Joint positions in radians per timestamp, `null` where unknown
+    pub position: Vec<Vec<Option<f64>>>,
+    /// Seconds since the epoch
+    pub timestamps: Vec<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JointStatesHeader {
+    pub stamp: RosTime,
+    pub frame_id: String,
+    pub rate_hz: u16,
+}
+
+/// ROS `time`
+#[derive(Debug, Serialize)]
+pub struct RosTime {
+    pub secs: u64,
+    pub nsecs: u32,
+}
+
+/// Channels locked out with `POST /api/servo/:id/disable`
+#[derive(Debug, Serialize)]
+pub struct LockoutResponse {
+    pub channel: u8,
+    pub enabled: bool,
+    /// Every locked-out channel
+    pub disabled: Vec<u8>,
+}
+
+/// Response of `GET /api/compensation`
+#[derive(Debug, Serialize)]
+pub struct CompensationList {
+    pub models: BTreeMap<u8, Model>,
+}
+
+/// Body of `POST /api/compensation/:id/calibrate`
+#[derive(Debug, Deserialize)]
+pub struct CalibrateRequest {
+    /// Channels the sag depends on; every channel in all samples' context
+    /// if unset
+    #[serde(default)]
+    pub inputs: Option<Vec<u8>>,
+    #[serde(default = "default_calibration_degree")]
+    pub degree: u8,
+    pub samples: Vec<Sample>,
+}
+
+fn default_calibration_degree() -> u8 {
+    2
+}
+
+/// Response of `POST /api/compensation/:id/calibrate`
+#[derive(Debug, Serialize)]
+pub struct CalibrateResponse {
+    pub channel: u8,
+    pub samples: usize,
+    #[serde(flatten)]
+    pub fit: Fit,
+}
+
+/// Response of `GET /api/faults` and `POST /api/faults/clear`
+#[derive(Debug, Serialize)]
+pub struct FaultsResponse {
+    /// The firmware has a fault register
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    /// Raw register value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub register: Option<u32>,
+    /// Names of the faults set, see `protocol::FAULT_BITS`
+    pub faults: Vec<String>,
+}
+
+/// Response of `GET` and `POST /api/firmware/speed_limit`
+#[derive(Debug, Serialize)]
+pub struct SpeedLimitResponse {
+    /// The firmware has a slew-rate limit
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    /// The firmware's limit for every servo in degrees per second; `None`
+    /// if it has none or isn't available
+    pub firmware_dps: Option<u16>,
+    pub servos: Vec<ChannelSpeedLimit>,
+}
+
+/// Speed limits of one servo in `GET /api/firmware/speed_limit`
+#[derive(Debug, Serialize)]
+pub struct ChannelSpeedLimit {
+    pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The backend's velocity limit, `max_velocity`
+    pub software_dps: u16,
+    /// The stricter of the software and the firmware limit
+    pub effective_dps: u16,
+}
+
+/// Request of `POST /api/firmware/speed_limit`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpeedLimitRequest {
+    /// Degrees per second for every servo, 0 to remove the limit
+    pub limit_dps: u16,
+}
+
+/// Response of `GET /api/servo/:id/feedback`
+#[derive(Debug, Serialize)]
+pub struct ServoFeedback {
+    pub channel: u8,
+    /// The firmware reports feedback ADC counts
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<u32>,
+    /// The counts mapped by the calibration, as positions are reported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub angle: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<FeedbackCalibration>,
+}
+
+/// Body of `POST /api/servo/:id/feedback/calibrate`
+#[derive(Debug, Deserialize)]
+pub struct FeedbackCalibrateRequest {
+    /// Angles the servo is moved to and read at; the servo's limits if
+    /// unset
+    #[serde(default)]
+    pub low: Option<u16>,
+    #[serde(default)]
+    pub high: Option<u16>,
+    /// Wait after each move before reading
+    #[serde(default = "default_settle_ms")]
+    pub settle_ms: u64,
+}
+
+fn default_settle_ms() -> u64 {
+    500
+}
+
+/// One point measured by `POST /api/servo/:id/feedback/calibrate`
+#[derive(Debug, Serialize)]
+pub struct FeedbackPoint {
+    pub angle: u16,
+    pub raw: u32,
+}
+
+/// Response of `POST /api/servo/:id/feedback/calibrate`
+#[derive(Debug, Serialize)]
+pub struct FeedbackCalibrateResponse {
+    pub channel: u8,
+    pub points: Vec<FeedbackPoint>,
+    /// In firmware angles, to put under the servo's `[[servos]]` entry
+    pub calibration: FeedbackCalibration,
+}
+
+/// Response for the supply voltage query
+#[derive(Debug, Serialize)]
+pub struct PowerResponse {
+    /// The firmware reports its supply voltage
+    pub available: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub millivolts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volts: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_voltage_mv: Option<u32>,
+    /// Below `low_voltage_mv`
+    pub low: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(body: serde_json::Value) -> Result<MoveRequest, String> {
+        serde_json::from_value(body).map_err(|e| e.to_string())
+    }
+
+    fn duration_ms(body: serde_json::Value) -> u32 {
+        parse(body).unwrap().duration_ms
+    }
+
+    #[test]
+    fn move_durations_are_taken_in_every_form() {
+        let request = parse(json!({ "duration_ms": 2500, "angles": [10] })).unwrap();
+        assert_eq!(request.duration_ms, 2500);
+        assert!(!request.track);
+        assert!(matches!(request.angles, PoseAngles::List(ref a) if a == &[10]));
+        // Beyond the firmware's 16 bits
+        assert_eq!(
+            duration_ms(json!({ "duration_ms": 65_536, "angles": [10] })),
+            65_536
+        );
+        assert_eq!(
+            duration_ms(json!({ "duration_ms": u32::MAX, "angles": [10] })),
+            u32::MAX
+        );
+        assert_eq!(
+            duration_ms(json!({ "duration": { "secs": 2.5 }, "angles": [10] })),
+            2500
+        );
+        assert_eq!(
+            duration_ms(json!({ "duration": { "secs": 65.5 }, "angles": [10] })),
+            65_500
+        );
+        assert_eq!(
+            duration_ms(json!({ "duration": { "secs": 0 }, "angles": [10] })),
+            0
+        );
+        // Rounded to the millisecond
+        assert_eq!(
+            duration_ms(json!({ "duration": { "secs": 0.0015 }, "angles": [10] })),
+            2
+        );
+        assert_eq!(
+            duration_ms(json!({ "duration": { "ms": 2500 }, "angles": [10] })),
+            2500
+        );
+        let request = parse(json!({
+            "duration": { "ms": 100 },
+            "angles": { "0": 10 },
+            "track": true,
+        }))
+        .unwrap();
+        assert!(request.track);
+        assert!(matches!(request.angles, PoseAngles::Map(_)));
+    }
+
+    #[test]
+    fn ambiguous_or_invalid_move_durations_are_refused() {
+        let refused = |body: serde_json::Value, error: &str| {
+            let message = parse(body.clone()).unwrap_err();
+            assert!(message.contains(error), "{}: {}", body, message);
+        };
+        refused(
+            json!({ "duration_ms": 2500, "duration": { "ms": 2500 }, "angles": [10] }),
+            "Give either duration_ms or duration",
+        );
+        refused(
+            json!({ "duration": { "secs": 2.5, "ms": 2500 }, "angles": [10] }),
+            "duration takes exactly one of secs and ms",
+        );
+        refused(
+            json!({ "duration": {}, "angles": [10] }),
+            "duration takes exactly one of secs and ms",
+        );
+        refused(
+            json!({ "duration": { "minutes": 1 }, "angles": [10] }),
+            "unknown field `minutes`",
+        );
+        refused(json!({ "duration": 2500, "angles": [10] }), "invalid type");
+        refused(
+            json!({ "duration": { "secs": -1 }, "angles": [10] }),
+            "Invalid duration: -1 secs",
+        );
+        refused(
+            json!({ "duration": { "secs": 5e6 }, "angles": [10] }),
+            "Invalid duration: 5000000 secs",
+        );
+        refused(
+            json!({ "duration": { "ms": -1 }, "angles": [10] }),
+            "invalid value",
+        );
+        refused(json!({ "angles": [10] }), "missing field `duration_ms`");
+        refused(
+            json!({ "duration_ms": -1, "angles": [10] }),
+            "invalid value",
+        );
+        refused(
+            json!({ "duration_ms": 4_294_967_296u64, "angles": [10] }),
+            "invalid value",
+        );
+        // Seconds given where milliseconds are meant
+        refused(
+            json!({ "duration_ms": 2.5, "angles": [10] }),
+            "invalid type",
+        );
+        refused(
+            json!({ "duration_ms": "2500", "angles": [10] }),
+            "invalid type",
+        );
+        refused(json!({ "duration_ms": 2500 }), "missing field `angles`");
+    }
 }
@@ -0,0 +1,354 @@
+use axum::http::StatusCode;
+use axum::Json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::controller::ServoController;
+use crate::handlers::{self, ApiError, AppState};
+use crate::models::ErrorResponse;
+use crate::protocol::Channel;
+use crate::serial::{CommandOptions, NUM_SERVOS};
+
+/// Length of one MOVE segment of a tracked move; a cancel takes effect at
+/// the end of the segment in progress. Each command also costs the
+/// response delay unless `move_lookahead` is on, so much shorter
+/// segments slow the move down.
+const SEGMENT_MS: u16 = 500;
+
+/// Tracked MOVEs running in the background
+///
+/// The firmware doesn't read commands while a MOVE is in progress, so a
+/// tracked move is sent as a series of short MOVEs along the straight line
+/// to the target, checking for a cancel between them.
+#[derive(Default)]
+pub struct MoveTracker {
+    inner: Mutex<Moves>,
+}
+
+#[derive(Default)]
+struct Moves {
+    next_id: u64,
+    active: HashMap<u64, TrackedMove>,
+}
+
+struct TrackedMove {
+    token: CancellationToken,
+    task: JoinHandle<Result<MoveOutcome, ApiError>>,
+    started: Instant,
+    duration: Duration,
+}
+
+/// How a tracked move ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoveOutcome {
+    Completed,
+    /// Stopped at the measured angles
+    Cancelled {
+        angles: Vec<u16>,
+    },
+}
+
+impl MoveTracker {
+    /// Start moving to `target` (already validated) over `duration_ms`,
+    /// returning the move's id
+    pub fn start(
+        &self,
+        state: Arc<AppState>,
+        serial: Arc<dyn ServoController>,
+        duration_ms: u32,
+        target: Vec<u16>,
+        opts: CommandOptions,
+    ) -> u64 {
+        // Busy from now on, not only once the task is running
+        let duration = Duration::from_millis(duration_ms as u64);
+        state.record_motion(target.len(), duration);
+        // The task moving the arm is outside the request
+        state.account_move(duration_ms);
+        let started = state.clock.now();
+        let token = CancellationToken::new();
+        // Hold the lock until the move is registered, so a move finishing
+        // right away can't try to unregister itself first
+        let mut moves = self.inner.lock().unwrap();
+        moves.next_id += 1;
+        let id = moves.next_id;
+
+        let cancelled = token.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let result = run(&state, &*serial, duration_ms, &target, opts, &cancelled);
+            state.moves.inner.lock().unwrap().active.remove(&id);
+            if let Err((_, e)) = &result {
+                warn!("Tracked move {} failed: {}", id, e.error);
+            }
+            result
+        });
+        moves.active.insert(
+            id,
+            TrackedMove {
+                token,
+                task,
+                started,
+                duration,
+            },
+        );
+        info!("Started tracked move {} ({}ms)", id, duration_ms);
+        id
+    }
+
+    /// Elapsed time and duration of a running move, `None` if no such move
+    /// is running
+    pub fn timing(&self, id: u64, now: Instant) -> Option<(Duration, Duration)> {
+        let moves = self.inner.lock().unwrap();
+        let tracked = moves.active.get(&id)?;
+        Some((now.saturating_duration_since(tracked.started), tracked.duration))
+    }
+
+    /// Cancel a move and wait for it to stop; `None` if no such move is
+    /// running
+    pub async fn cancel(&self, id: u64) -> Option<Result<MoveOutcome, ApiError>> {
+        let tracked = self.inner.lock().unwrap().active.remove(&id)?;
+        tracked.token.cancel();
+        Some(
+            tracked.task.await.unwrap_or_else(|e| {
+                Err(internal_error(format!("Tracked move {} failed: {}", id, e)))
+            }),
+        )
+    }
+}
+
+/// Drive the arm to `target` in segments until done or cancelled
+fn run(
+    state: &AppState,
+    serial: &dyn ServoController,
+    duration_ms: u32,
+    target: &[u16],
+    opts: CommandOptions,
+    cancelled: &CancellationToken,
+) -> Result<MoveOutcome, ApiError> {
+    let _motion = state.begin_motion()?;
+    // Dropped on cancel or failure too, so the estimate ends with the move
+    let _plan = state.plan_motion(target.len(), Duration::from_millis(duration_ms as u64));
+    let segments = duration_ms.div_ceil(SEGMENT_MS as u32);
+    run_segments(state, serial, duration_ms, segments, target, opts, Some(cancelled))
+}
+
+/// Longest MOVE sent as one command: within the firmware's 16-bit
+/// duration, and short enough for its reply, which comes once the move is
+/// done, to arrive well within `timeouts.command_ms`
+pub fn max_part_ms(config: &Config) -> u32 {
+    let within_timeout = (config.timeouts.command_ms / 2).min(u16::MAX as u64) as u32;
+    within_timeout.max(SEGMENT_MS as u32)
+}
+
+/// Move to `target` over a duration longer than [`max_part_ms`], in as
+/// few MOVEs as fit, within a motion the caller began
+pub fn run_split(
+    state: &AppState,
+    serial: &dyn ServoController,
+    duration_ms: u32,
+    target: &[u16],
+    opts: CommandOptions,
+) -> Result<(), ApiError> {
+    let segments = duration_ms.div_ceil(max_part_ms(&state.config()));
+    run_segments(state, serial, duration_ms, segments, target, opts, None).map(|_| ())
+}
+
+/// Send the move to `target` as `segments` MOVEs along the straight line
+/// from the current position, checking for a cancel before each one
+fn run_segments(
+    state: &AppState,
+    serial: &dyn ServoController,
+    duration_ms: u32,
+    segments: u32,
+    target: &[u16],
+    opts: CommandOptions,
+    cancelled: Option<&CancellationToken>,
+) -> Result<MoveOutcome, ApiError> {
+    let known = *state.positions.lock().unwrap();
+    let start = Channel::all(NUM_SERVOS)
+        .take(target.len())
+        .map(|channel| match known[channel.index()] {
+            Some(angle) => Ok(angle),
+            None => handlers::read_position(state, serial, channel),
+        })
+        .collect::<Result<Vec<u16>, ApiError>>()?;
+
+    let segments = segments.max(1);
+    let chained = CommandOptions { chain: true, ..opts };
+    for segment in 1..=segments {
+        if cancelled.is_some_and(CancellationToken::is_cancelled) {
+            return stop(state, serial, target.len(), opts);
+        }
+        let elapsed = (duration_ms as u64 * (segment as u64 - 1) / segments as u64) as u32;
+        let until = (duration_ms as u64 * segment as u64 / segments as u64) as u32;
+        let angles: Vec<u16> = start
+            .iter()
+            .zip(target)
+            .map(|(&from, &to)| {
+                let step = (to as i32 - from as i32) * segment as i32 / segments as i32;
+                (from as i32 + step) as u16
+            })
+            .collect();
+        handlers::run_move(state, serial, (until - elapsed) as u16, &angles, chained)?;
+    }
+    handlers::end_chain(state, serial)?;
+    Ok(MoveOutcome::Completed)
+}
+
+/// Hold the first `channels` servos at their measured angles
+fn stop(
+    state: &AppState,
+    serial: &dyn ServoController,
+    channels: usize,
+    opts: CommandOptions,
+) -> Result<MoveOutcome, ApiError> {
+    let angles = Channel::all(NUM_SERVOS)
+        .take(channels)
+        .map(|channel| handlers::read_position(state, serial, channel))
+        .collect::<Result<Vec<u16>, ApiError>>()?;
+    handlers::run_pose(state, serial, &angles, opts)?;
+    Ok(MoveOutcome::Cancelled { angles })
+}
+
+fn internal_error(error: String) -> ApiError {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ErrorResponse::new(error)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+    use serde_json::json;
+
+    fn start(server: &TestServer, duration_ms: u32, target: Vec<u16>) -> u64 {
+        let state = server.server.state();
+        state.moves.start(
+            state.clone(),
+            server.mock.clone(),
+            duration_ms,
+            target,
+            CommandOptions::default(),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn move_is_sent_in_segments_along_the_line() {
+        let server = TestServer::start().await;
+        let state = server.server.state();
+        let id = start(&server, 1200, vec![30, 150]);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(state.moves.timing(id, state.clock.now()).is_none());
+        assert_eq!(
+            server.mock.take_commands(),
+            ["MOVE 400 70,110", "MOVE 400 50,130", "MOVE 400 30,150"]
+        );
+        assert_eq!(state.motion_remaining(0), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_stops_at_the_end_of_the_segment() {
+        let server = TestServer::start().await;
+        let state = server.server.state();
+        let id = start(&server, 2000, vec![10]);
+        // Halfway through the second segment
+        tokio::time::sleep(Duration::from_millis(750)).await;
+        let (elapsed, duration) = state.moves.timing(id, state.clock.now()).unwrap();
+        assert!(elapsed >= Duration::from_millis(700));
+        assert_eq!(duration, Duration::from_millis(2000));
+        assert!(state.motion_remaining(0).unwrap() <= Duration::from_millis(1300));
+
+        let outcome = state.moves.cancel(id).await.unwrap().unwrap();
+        assert_eq!(outcome, MoveOutcome::Cancelled { angles: vec![50] });
+        assert_eq!(
+            server.mock.take_commands(),
+            ["MOVE 500 70", "MOVE 500 50", "GET 0", "POSE 50"]
+        );
+        // The estimate ends with the move, not when it would have
+        assert_eq!(state.motion_remaining(0), None);
+        assert!(state.moves.cancel(id).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn overlapping_motion_keeps_the_later_estimate() {
+        let server = TestServer::start().await;
+        let state = server.server.state();
+        let plan = state.plan_motion(2, Duration::from_secs(10));
+        // A segment of the plan, on fewer channels, doesn't shorten it
+        state.record_motion(1, Duration::from_secs(1));
+        assert!(state.motion_remaining(0).unwrap() > Duration::from_secs(9));
+        assert!(state.motion_remaining(1).unwrap() > Duration::from_secs(9));
+        // A longer motion on more channels extends it
+        state.record_motion(3, Duration::from_secs(20));
+        assert!(state.motion_remaining(0).unwrap() > Duration::from_secs(19));
+        assert!(state.motion_remaining(2).unwrap() > Duration::from_secs(19));
+        assert_eq!(state.motion_remaining(3), None);
+
+        drop(plan);
+        assert_eq!(state.motion_remaining(0), None);
+        assert_eq!(state.motion_remaining(1), None);
+        // Outside the plan's channels
+        assert!(state.motion_remaining(2).is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn overlapping_moves_each_hold_their_channels() {
+        let server = TestServer::start().await;
+        let state = server.server.state();
+        let first = start(&server, 1000, vec![70]);
+        let second = start(&server, 3000, vec![70, 70]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(state.motion_remaining(1).unwrap() > Duration::from_millis(2500));
+        assert_ne!(first, second);
+        state.moves.cancel(first).await.unwrap().unwrap();
+        state.moves.cancel(second).await.unwrap().unwrap();
+        assert_eq!(state.motion_remaining(0), None);
+        assert_eq!(state.motion_remaining(1), None);
+    }
+
+    #[test]
+    fn parts_fit_the_firmware_and_the_command_timeout() {
+        let mut config = Config::default();
+        assert_eq!(max_part_ms(&config), 6000);
+        config.timeouts.command_ms = 200_000;
+        assert_eq!(max_part_ms(&config), u16::MAX as u32);
+        config.timeouts.command_ms = 100;
+        assert_eq!(max_part_ms(&config), SEGMENT_MS as u32);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn long_move_is_split_into_parts() {
+        // Parts of at most 500ms
+        let server = TestServer::with_config(|c| c.timeouts.command_ms = 1000).await;
+        let reply = server
+            .post(
+                "/api/move",
+                json!({ "duration_ms": 500, "angles": [30, 150] }),
+            )
+            .await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(server.mock.take_commands(), ["MOVE 500 30,150"]);
+
+        let reply = server
+            .post(
+                "/api/move",
+                json!({ "duration": { "secs": 1.2 }, "angles": [90, 90] }),
+            )
+            .await;
+        assert_eq!(reply.body, json!({ "status": "ok" }));
+        assert_eq!(
+            server.mock.take_commands(),
+            ["MOVE 400 50,130", "MOVE 400 70,110", "MOVE 400 90,90"]
+        );
+        assert_eq!(
+            &server.server.state().positions.lock().unwrap()[..2],
+            [Some(90), Some(90)]
+        );
+    }
+}
@@ -0,0 +1,503 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::config::{Config, ServoConfig};
+use crate::serial::NUM_SERVOS;
+use crate::tokens;
+
+/// Longest an override may last
+pub const MAX_TTL_MS: u64 = 3_600_000;
+
+/// Largest velocity limit multiplier an override may set
+pub const MAX_VELOCITY_SCALE: f64 = 4.0;
+
+tokio::task_local! {
+    /// The override a request named with `X-Override`, set around it
+    pub static REQUESTED: u64;
+}
+
+/// The override the current task's request named, if any
+pub fn requested() -> Option<u64> {
+    REQUESTED.try_with(|id| *id).ok()
+}
+
+/// Soft limits of one channel while an override is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+/// Constraints an override relaxes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Relaxed {
+    /// Soft limits replacing the configured ones, by channel
+    #[serde(default)]
+    pub limits: BTreeMap<u8, LimitRange>,
+    /// Factor applied to every velocity limit
+    #[serde(default = "default_velocity_scale")]
+    pub velocity_scale: f64,
+}
+
+fn default_velocity_scale() -> f64 {
+    1.0
+}
+
+impl Relaxed {
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        if self.limits.is_empty() && self.velocity_scale == 1.0 {
+            anyhow::bail!("The override relaxes nothing");
+        }
+        let max_angle = config.protocol.max_angle;
+        for (&channel, range) in &self.limits {
+            if channel >= NUM_SERVOS || !config.kind(channel).is_servo() {
+                anyhow::bail!("limits.{}: no servo on channel {}", channel, channel);
+            }
+            if range.min > range.max || range.max > max_angle {
+                anyhow::bail!(
+                    "limits.{}: {}-{} must be a range within 0-{}",
+                    channel,
+                    range.min,
+                    range.max,
+                    max_angle
+                );
+            }
+        }
+        if !(1.0..=MAX_VELOCITY_SCALE).contains(&self.velocity_scale) {
+            anyhow::bail!(
+                "velocity_scale must be between 1 and {}",
+                MAX_VELOCITY_SCALE
+            );
+        }
+        Ok(())
+    }
+
+    /// `config` with these constraints in place of its own
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut relaxed = config.clone();
+        for channel in 0..NUM_SERVOS {
+            let velocity = config.max_velocity(channel) as f64 * self.velocity_scale;
+            let limits = self.limits.get(&channel);
+            if limits.is_none() && self.velocity_scale == 1.0 {
+                continue;
+            }
+            if !relaxed.servos.iter().any(|servo| servo.channel == channel) {
+                relaxed.servos.push(ServoConfig {
+                    channel,
+                    ..ServoConfig::default()
+                });
+            }
+            let servo = relaxed
+                .servos
+                .iter_mut()
+                .find(|servo| servo.channel == channel)
+                .expect("just added");
+            if let Some(limits) = limits {
+                servo.min = limits.min;
+                servo.max = limits.max;
+            }
+            servo.max_velocity = Some(velocity.round().min(u16::MAX as f64) as u16);
+        }
+        let streaming = config.streaming.max_velocity as f64 * self.velocity_scale;
+        relaxed.streaming.max_velocity = streaming.round().min(u16::MAX as f64) as u16;
+        relaxed
+    }
+}
+
+/// Random bytes of an override token
+const TOKEN_BYTES: usize = 32;
+
+/// A supervised override session
+#[derive(Debug, Clone, Serialize)]
+pub struct Override {
+    pub id: u64,
+    /// Who opened it, from `X-Actor`
+    pub actor: String,
+    /// Name of the bearer token it was opened with
+    pub owner: String,
+    pub created_ms: u64,
+    pub expires_ms: u64,
+    pub relaxed: Relaxed,
+    /// Secret requests send in `X-Override` to use it; only ever
+    /// returned to its creator
+    #[serde(skip_serializing)]
+    pub token: String,
+}
+
+/// An unguessable override token, hex-encoded
+fn new_token() -> Result<String> {
+    let mut bytes = [0; TOKEN_BYTES];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow::anyhow!("No randomness for an override token: {}", e))?;
+    let mut token = String::with_capacity(2 * TOKEN_BYTES);
+    for byte in bytes {
+        let _ = write!(token, "{:02x}", byte);
+    }
+    Ok(token)
+}
+
+/// Why [`Overrides::create`] didn't open an override
+#[derive(Debug)]
+pub enum CreateError {
+    /// This one is active
+    Active(Box<Override>),
+    /// No token could be made
+    Token(anyhow::Error),
+}
+
+/// The override session, at most one at a time
+#[derive(Default)]
+pub struct Overrides {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    active: Option<Override>,
+}
+
+impl Overrides {
+    /// Open an override lasting `ttl_ms` for `owner`, with a fresh token
+    pub fn create(
+        &self,
+        actor: &str,
+        owner: &str,
+        relaxed: Relaxed,
+        now_ms: u64,
+        ttl_ms: u64,
+    ) -> Result<Override, CreateError> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(active) = inner.active.as_ref().filter(|o| o.expires_ms > now_ms) {
+            return Err(CreateError::Active(Box::new(active.clone())));
+        }
+        let token = new_token().map_err(CreateError::Token)?;
+        inner.next_id += 1;
+        let created = Override {
+            id: inner.next_id,
+            actor: actor.to_string(),
+            owner: owner.to_string(),
+            created_ms: now_ms,
+            expires_ms: now_ms + ttl_ms,
+            relaxed,
+            token,
+        };
+        inner.active = Some(created.clone());
+        Ok(created)
+    }
+
+    /// The override in effect at `now_ms`
+    pub fn active(&self, now_ms: u64) -> Option<Override> {
+        let inner = self.inner.lock().unwrap();
+        inner.active.clone().filter(|o| o.expires_ms > now_ms)
+    }
+
+    /// The override in effect at `now_ms` if `token` is its token
+    pub fn by_token(&self, token: &str, now_ms: u64) -> Option<Override> {
+        self.active(now_ms)
+            .filter(|active| tokens::same_token(token, &active.token))
+    }
+
+    /// End the override `id` early
+    pub fn revoke(&self, id: u64, now_ms: u64) -> Option<Override> {
+        let mut inner = self.inner.lock().unwrap();
+        let active = inner.active.as_ref()?;
+        if active.id != id || active.expires_ms <= now_ms {
+            return None;
+        }
+        inner.active.take()
+    }
+
+    /// Drop the override once it has expired, returning it
+    pub fn expire(&self, now_ms: u64) -> Option<Override> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.active.as_ref()?.expires_ms > now_ms {
+            return None;
+        }
+        inner.active.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::server::RobotArmServer;
+    use crate::testing::{test_config, MockController, Reply, TestServer, ADMIN};
+    use reqwest::Method;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn relaxed(limits: &[(u8, u16, u16)], velocity_scale: f64) -> Relaxed {
+        Relaxed {
+            limits: limits
+                .iter()
+                .map(|&(channel, min, max)| (channel, LimitRange { min, max }))
+                .collect(),
+            velocity_scale,
+        }
+    }
+
+    /// Channels 0 and 1 limited to 100 degrees
+    fn config() -> Config {
+        let mut config = test_config();
+        config.servos = (0..2)
+            .map(|channel| ServoConfig {
+                channel,
+                max: 100,
+                ..ServoConfig::default()
+            })
+            .collect();
+        config
+    }
+
+    #[test]
+    fn relaxations_are_validated() {
+        let config = config();
+        assert!(relaxed(&[(0, 0, 150)], 1.0).validate(&config).is_ok());
+        assert!(relaxed(&[], 2.0).validate(&config).is_ok());
+        let error = |relaxed: Relaxed| relaxed.validate(&config).unwrap_err().to_string();
+        assert_eq!(error(relaxed(&[], 1.0)), "The override relaxes nothing");
+        assert_eq!(
+            error(relaxed(&[(6, 0, 150)], 1.0)),
+            "limits.6: no servo on channel 6"
+        );
+        assert_eq!(
+            error(relaxed(&[(0, 150, 10)], 1.0)),
+            "limits.0: 150-10 must be a range within 0-180"
+        );
+        assert_eq!(
+            error(relaxed(&[(0, 0, 181)], 1.0)),
+            "limits.0: 0-181 must be a range within 0-180"
+        );
+        for scale in [0.5, 4.5] {
+            assert_eq!(
+                error(relaxed(&[], scale)),
+                "velocity_scale must be between 1 and 4"
+            );
+        }
+    }
+
+    #[test]
+    fn relaxed_config_replaces_only_the_relaxed_constraints() {
+        let mut config = config();
+        config.servos[1].max_velocity = Some(60);
+        let applied = relaxed(&[(0, 10, 150)], 1.0).apply(&config);
+        assert_eq!((applied.servo(0).min, applied.servo(0).max), (10, 150));
+        assert_eq!(applied.servo(1).max, 100);
+        assert_eq!(applied.max_velocity(1), 60);
+        assert_eq!(
+            applied.streaming.max_velocity,
+            config.streaming.max_velocity
+        );
+
+        let applied = relaxed(&[(0, 10, 150)], 2.5).apply(&config);
+        assert_eq!(applied.max_velocity(1), 150);
+        assert_eq!(applied.servo(1).max, 100);
+        assert_eq!(
+            applied.streaming.max_velocity as f64,
+            (config.streaming.max_velocity as f64 * 2.5).round()
+        );
+        // Channels without a config of their own get one with the scale
+        assert_eq!(applied.max_velocity(4), applied.streaming.max_velocity);
+        // The config given is left alone
+        assert_eq!(config.servo(0).max, 100);
+    }
+
+    #[test]
+    fn one_override_is_active_until_its_ttl() {
+        let overrides = Overrides::default();
+        let created = overrides
+            .create("ann", "admin", relaxed(&[], 2.0), 1000, 500)
+            .unwrap();
+        assert_eq!((created.id, created.expires_ms), (1, 1500));
+        assert_eq!(created.token.len(), 2 * TOKEN_BYTES);
+        match overrides.create("bob", "admin", relaxed(&[], 2.0), 1499, 500) {
+            Err(CreateError::Active(active)) => assert_eq!(active.id, 1),
+            other => panic!("{:?}", other.map(|o| o.id)),
+        }
+
+        assert_eq!(overrides.active(1499).map(|o| o.id), Some(1));
+        assert!(overrides.active(1500).is_none());
+        assert!(overrides.by_token(&created.token, 1499).is_some());
+        assert!(overrides.by_token(&created.token, 1500).is_none());
+        assert!(overrides.by_token("guess", 1499).is_none());
+        assert!(overrides.expire(1499).is_none());
+        assert_eq!(overrides.expire(1500).map(|o| o.id), Some(1));
+        assert!(overrides.expire(1500).is_none());
+
+        // Expired without being dropped, it doesn't stop a new one
+        let next = overrides
+            .create("bob", "ops", relaxed(&[], 2.0), 1500, 500)
+            .unwrap();
+        assert_eq!(next.id, 2);
+        assert_ne!(next.token, created.token);
+        assert!(overrides.by_token(&created.token, 1600).is_none());
+        let later = overrides
+            .create("bob", "ops", relaxed(&[], 2.0), 2000, 500)
+            .unwrap();
+        assert_eq!(later.id, 3);
+    }
+
+    #[test]
+    fn only_the_active_override_is_revoked() {
+        let overrides = Overrides::default();
+        overrides
+            .create("ann", "admin", relaxed(&[], 2.0), 0, 100)
+            .unwrap();
+        assert!(overrides.revoke(2, 50).is_none());
+        assert!(overrides.revoke(1, 100).is_none());
+        let overrides = Overrides::default();
+        overrides
+            .create("ann", "admin", relaxed(&[], 2.0), 0, 100)
+            .unwrap();
+        assert_eq!(overrides.revoke(1, 50).map(|o| o.id), Some(1));
+        assert!(overrides.active(50).is_none());
+        assert!(overrides.revoke(1, 50).is_none());
+    }
+
+    /// Served with [`config`] on a mock clock, with an override of
+    /// channel 0's limits to 0-160 open for a second; its token
+    async fn overridden() -> (TestServer, Arc<MockClock>, String) {
+        let clock = Arc::new(MockClock::new());
+        let builder = RobotArmServer::builder()
+            .config(config())
+            .clock(clock.clone());
+        let server = TestServer::serve(builder, Arc::new(MockController::default())).await;
+        let relaxed = json!({ "limits": { "0": { "min": 0, "max": 160 } } });
+        let body = json!({ "ttl_ms": 1000, "relaxed": relaxed });
+        let reply = server
+            .send(
+                Method::POST,
+                "/api/overrides",
+                &[ADMIN, ("x-actor", "ann")],
+                Some(body),
+            )
+            .await;
+        assert_eq!(reply.status, 201, "{:?}", reply.body);
+        let token = reply.body["token"].as_str().unwrap().to_string();
+        (server, clock, token)
+    }
+
+    fn audited(server: &TestServer) -> Vec<(String, String)> {
+        let state = server.server.state();
+        let audit = state.audit.lock().unwrap();
+        let entries = audit.query(&Default::default());
+        entries
+            .iter()
+            .filter(|entry| entry.kind == "override")
+            .map(|entry| (entry.actor.clone(), entry.endpoint.clone()))
+            .collect()
+    }
+
+    async fn angle(
+        server: &TestServer,
+        headers: &[(&str, &str)],
+        channel: u8,
+        angle: u16,
+    ) -> Reply {
+        let path = format!("/api/servo/{}/angle", channel);
+        let body = json!({ "angle": angle });
+        server.send(Method::POST, &path, headers, Some(body)).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_are_checked_against_the_rules_they_name() {
+        let (server, _clock, token) = overridden().await;
+        let headers = [ADMIN, ("x-override", token.as_str())];
+
+        // Without the header the normal limits hold
+        assert_eq!(angle(&server, &[ADMIN], 0, 140).await.code(), "SOFT_LIMIT");
+        assert_eq!(angle(&server, &headers, 0, 140).await.status, 200);
+        // Beyond the relaxed range, and on a channel it doesn't relax
+        assert_eq!(angle(&server, &headers, 0, 170).await.code(), "SOFT_LIMIT");
+        assert_eq!(angle(&server, &headers, 1, 140).await.code(), "SOFT_LIMIT");
+        assert_eq!(server.mock.take_commands(), ["S0:140"]);
+
+        // The config to change and persist is never the relaxed one
+        assert_eq!(server.server.state().base_config().servo(0).max, 100);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expiry_is_audited_and_ends_the_relaxed_rules() {
+        let (server, clock, token) = overridden().await;
+        let headers = [ADMIN, ("x-override", token.as_str())];
+        let body = Some(json!({ "angle": 140 }));
+        clock.advance(Duration::from_millis(999));
+        let reply = server
+            .send(Method::POST, "/api/servo/0/angle", &headers, body.clone())
+            .await;
+        assert_eq!(reply.status, 200);
+
+        clock.advance(Duration::from_millis(1));
+        let reply = server
+            .send(Method::POST, "/api/servo/0/angle", &headers, body)
+            .await;
+        assert_eq!(reply.status, 409);
+        assert_eq!(reply.code(), "OVERRIDE_INACTIVE");
+        assert_eq!(
+            server.get("/api/overrides").await.body["active"],
+            Value::Null
+        );
+        // The expiry task wakes with the clock
+        for _ in 0..100 {
+            if audited(&server).len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            audited(&server),
+            [
+                ("ann".to_string(), "POST /api/overrides".to_string()),
+                ("override-expiry".to_string(), "expiry".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sequence_outliving_its_override_falls_back_to_the_normal_limits() {
+        let (server, clock, token) = overridden().await;
+        let steps = json!([
+            { "duration_ms": 200, "angles": [140] },
+            { "duration_ms": 200, "angles": [150] }
+        ]);
+        let reply = server
+            .put("/api/sequences/unfold", json!({ "steps": steps }))
+            .await;
+        assert_eq!(reply.status, 200, "{:?}", reply.body);
+        // Checked up front against the limits it names
+        let reply = server
+            .post("/api/sequences/unfold/execute", json!({}))
+            .await;
+        assert_eq!(reply.code(), "SOFT_LIMIT");
+        assert!(server.mock.take_commands().is_empty());
+
+        let headers = [ADMIN, ("x-override", token.as_str())];
+        let run = server.send(
+            Method::POST,
+            "/api/sequences/unfold/execute",
+            &headers,
+            Some(json!({})),
+        );
+        let expire = async {
+            let mut sent = Vec::new();
+            while sent.is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                sent.extend(server.mock.take_commands());
+            }
+            // Expires during the first step
+            clock.advance(Duration::from_millis(1000));
+            sent
+        };
+        let (reply, mut sent) = tokio::join!(run, expire);
+        assert_eq!(reply.status, 422);
+        assert_eq!(reply.code(), "SOFT_LIMIT");
+        sent.extend(server.mock.take_commands());
+        assert_eq!(sent, ["MOVE 200 140"]);
+    }
+}
@@ -0,0 +1,325 @@
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::TAU;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::controller::ServoController;
+use crate::handlers::{self, ApiError, AppState};
+use crate::protocol::Channel;
+use crate::serial::{CommandOptions, NUM_SERVOS};
+
+/// Shortest and longest cycle of a pattern
+pub const MIN_PERIOD_MS: u32 = 1000;
+pub const MAX_PERIOD_MS: u32 = 600_000;
+
+/// Most cycles of one run
+pub const MAX_CYCLES: u32 = 1000;
+
+/// Longest MOVE segment; a cancel takes effect at the end of the segment
+/// in progress
+const SEGMENT_MS: u32 = 200;
+
+/// Segments per cycle are a multiple of this, so the corners of a square
+/// are on a segment boundary
+const SEGMENT_STEP: u32 = 8;
+
+/// Shortest MOVE to the start of the pattern
+const LEAD_IN_MS: u32 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatternKind {
+    Circle,
+    Square,
+    Lissajous,
+}
+
+impl PatternKind {
+    /// Point at phase `t` (0 to 1) of a cycle, -1 to 1 on each axis; every
+    /// pattern starts at (1, 0)
+    fn point(self, t: f64, ratio: [u8; 2]) -> [f64; 2] {
+        match self {
+            PatternKind::Circle => [(TAU * t).cos(), (TAU * t).sin()],
+            PatternKind::Square => {
+                // The perimeter is 8 long: up the right edge, then around
+                let s = (8.0 * t).rem_euclid(8.0);
+                match s {
+                    s if s < 1.0 => [1.0, s],
+                    s if s < 3.0 => [2.0 - s, 1.0],
+                    s if s < 5.0 => [-1.0, 4.0 - s],
+                    s if s < 7.0 => [s - 6.0, -1.0],
+                    s => [1.0, s - 8.0],
+                }
+            }
+            PatternKind::Lissajous => [
+                (TAU * ratio[0] as f64 * t).cos(),
+                (TAU * ratio[1] as f64 * t).sin(),
+            ],
+        }
+    }
+
+    /// Fastest change on each axis, in units per cycle
+    pub fn peak_speed(self, ratio: [u8; 2]) -> [f64; 2] {
+        match self {
+            PatternKind::Circle => [TAU, TAU],
+            PatternKind::Square => [8.0, 8.0],
+            PatternKind::Lissajous => [TAU * ratio[0] as f64, TAU * ratio[1] as f64],
+        }
+    }
+}
+
+/// A pattern traced by two channels around their center angles
+#[derive(Debug, Clone, Serialize)]
+pub struct Pattern {
+    pub pattern: PatternKind,
+    /// Degrees from the center to the edge
+    pub amplitude: u16,
+    pub period_ms: u32,
+    pub cycles: u32,
+    /// Channels along the x and y axis
+    pub channels: [u8; 2],
+    pub center: [u16; 2],
+    /// Frequencies of the axes of a Lissajous figure
+    pub ratio: [u8; 2],
+}
+
+impl Pattern {
+    /// Angles of the two channels at phase `t` of a cycle
+    fn angles(&self, t: f64) -> [u16; 2] {
+        let point = self.pattern.point(t, self.ratio);
+        [0, 1].map(|axis| {
+            let angle = self.center[axis] as f64 + self.amplitude as f64 * point[axis];
+            angle.round().max(0.0) as u16
+        })
+    }
+
+    /// Segments per cycle
+    fn segments(&self) -> u32 {
+        self.period_ms
+            .div_ceil(SEGMENT_MS)
+            .next_multiple_of(SEGMENT_STEP)
+            .max(SEGMENT_STEP)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+/// A run of a pattern
+#[derive(Debug, Clone, Serialize)]
+pub struct PatternRun {
+    pub id: u64,
+    pub status: RunStatus,
+    #[serde(flatten)]
+    pub pattern: Pattern,
+    /// Cycles finished so far
+    pub cycles_done: u32,
+    pub started_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_ms: Option<u64>,
+    /// Measured angles the arm was held at on a cancel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub angles: Option<Vec<u16>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The pattern being traced, if any, and the last run
+///
+/// Like a tracked move, a pattern is sent as a chain of short MOVEs,
+/// checking for a cancel between them. One pattern runs at a time.
+#[derive(Default)]
+pub struct Patterns {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    last: Option<PatternRun>,
+    /// Set until the running pattern finished
+    running: Option<CancellationToken>,
+    task: Option<JoinHandle<()>>,
+}
+
+/// How a run ended
+enum Outcome {
+    Completed,
+    /// Stopped at the measured angles
+    Cancelled(Vec<u16>),
+}
+
+impl Patterns {
+    /// Start tracing `pattern` (already validated); `None` if one is
+    /// running already
+    pub fn start(
+        &self,
+        state: Arc<AppState>,
+        serial: Arc<dyn ServoController>,
+        pattern: Pattern,
+    ) -> Option<PatternRun> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.running.is_some() {
+            return None;
+        }
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let record = PatternRun {
+            id,
+            status: RunStatus::Running,
+            pattern: pattern.clone(),
+            cycles_done: 0,
+            started_ms: state.clock.now_ms(),
+            finished_ms: None,
+            angles: None,
+            error: None,
+        };
+        inner.last = Some(record.clone());
+
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let result = run(&state, &*serial, &pattern, &cancelled);
+            if let Err((_, Json(e))) = &result {
+                warn!("Pattern {} failed: {}", id, e.error);
+            }
+            state.patterns.finish(result, state.clock.now_ms());
+        });
+        // Registered under the lock, so a run failing right away finds it
+        inner.running = Some(token);
+        inner.task = Some(task);
+        info!("Started pattern {} ({:?})", id, record.pattern.pattern);
+        Some(record)
+    }
+
+    /// The running pattern, else the last one
+    pub fn current(&self) -> Option<PatternRun> {
+        self.inner.lock().unwrap().last.clone()
+    }
+
+    /// Cancel the running pattern and wait for it to stop; `None` if none
+    /// is running
+    pub async fn cancel(&self) -> Option<PatternRun> {
+        let task = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.running.as_ref()?.cancel();
+            inner.task.take()?
+        };
+        if let Err(e) = task.await {
+            warn!("Pattern task failed: {}", e);
+        }
+        self.current()
+    }
+
+    fn cycle_done(&self) {
+        if let Some(record) = &mut self.inner.lock().unwrap().last {
+            record.cycles_done += 1;
+        }
+    }
+
+    fn finish(&self, result: Result<Outcome, ApiError>, now_ms: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.running = None;
+        inner.task = None;
+        let Some(record) = &mut inner.last else {
+            return;
+        };
+        record.finished_ms = Some(now_ms);
+        match result {
+            Ok(Outcome::Completed) => record.status = RunStatus::Completed,
+            Ok(Outcome::Cancelled(angles)) => {
+                info!("Cancelled pattern {} at {:?}", record.id, angles);
+                record.status = RunStatus::Cancelled;
+                record.angles = Some(angles);
+            }
+            Err((_, Json(e))) => {
+                record.status = RunStatus::Failed;
+                record.error = Some(e.error);
+            }
+        }
+    }
+}
+
+/// Move to the start of the pattern, then trace it until done or
+/// cancelled; the channels before the highest pattern channel that aren't
+/// part of it hold their positions
+fn run(
+    state: &AppState,
+    serial: &dyn ServoController,
+    pattern: &Pattern,
+    cancelled: &CancellationToken,
+) -> Result<Outcome, ApiError> {
+    let _motion = state.begin_motion()?;
+    let config = state.config();
+    let channels = *pattern.channels.iter().max().unwrap_or(&0) as usize + 1;
+    let known = *state.positions.lock().unwrap();
+    let mut angles = Channel::all(NUM_SERVOS)
+        .take(channels)
+        .map(|channel| match known[channel.index()] {
+            Some(angle) => Ok(angle),
+            None => handlers::read_position(state, serial, channel),
+        })
+        .collect::<Result<Vec<u16>, ApiError>>()?;
+
+    let start = pattern.angles(0.0);
+    let lead_in_ms = pattern
+        .channels
+        .iter()
+        .zip(start)
+        .map(|(&channel, to)| {
+            let distance = to.abs_diff(angles[channel as usize]) as u32;
+            (distance * 1000).div_ceil(config.max_velocity(channel) as u32)
+        })
+        .fold(LEAD_IN_MS, u32::max);
+    let total_ms = lead_in_ms as u64 + pattern.period_ms as u64 * pattern.cycles as u64;
+    // Dropped on cancel or failure too, so the estimate ends with the run
+    let _plan = state.plan_motion(channels, Duration::from_millis(total_ms));
+
+    let chained = CommandOptions {
+        chain: true,
+        ..CommandOptions::default()
+    };
+    let mut send = |duration_ms: u32, target: [u16; 2]| {
+        for (&channel, angle) in pattern.channels.iter().zip(target) {
+            angles[channel as usize] = angle;
+        }
+        handlers::run_move(state, serial, duration_ms as u16, &angles, chained)
+    };
+    send(lead_in_ms, start)?;
+
+    let segments = pattern.segments();
+    for _ in 0..pattern.cycles {
+        for segment in 1..=segments {
+            if cancelled.is_cancelled() {
+                return stop(state, serial, channels);
+            }
+            let elapsed = pattern.period_ms * (segment - 1) / segments;
+            let until = pattern.period_ms * segment / segments;
+            let target = pattern.angles(segment as f64 / segments as f64);
+            send(until - elapsed, target)?;
+        }
+        state.patterns.cycle_done();
+    }
+    handlers::end_chain(state, serial)?;
+    Ok(Outcome::Completed)
+}
+
+/// Hold the first `channels` servos at their measured angles
+fn stop(state: &AppState, serial: &dyn ServoController, channels: usize) -> Result<Outcome, ApiError> {
+    let angles = Channel::all(NUM_SERVOS)
+        .take(channels)
+        .map(|channel| handlers::read_position(state, serial, channel))
+        .collect::<Result<Vec<u16>, ApiError>>()?;
+    handlers::run_pose(state, serial, &angles, CommandOptions::default())?;
+    Ok(Outcome::Cancelled(angles))
+}
@@ -0,0 +1,470 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::audit;
+use crate::handlers::AppState;
+
+/// Problems kept for `GET /api/persistence`
+const MAX_PROBLEMS: usize = 50;
+
+/// Pause before writing a file again after a write of it failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The file was missing or unreadable at startup and its backup was
+    /// loaded instead
+    Recovered,
+    /// A write failed; it is retried until one succeeds
+    WriteFailed,
+}
+
+/// Something that went wrong keeping a file
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub at_ms: u64,
+    pub path: PathBuf,
+    pub kind: ProblemKind,
+    pub detail: String,
+}
+
+/// The single writer of the files the stores persist to
+///
+/// Stores submit a snapshot of their contents whenever they change; the
+/// writer task ([`run`]) waits for the changes to settle and writes the
+/// latest snapshot of each file with [`write_atomic`], so writes of one
+/// file never interleave and a burst of changes is one write.
+#[derive(Clone, Default)]
+pub struct Persister {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Latest snapshot of each file not written yet
+    dirty: Mutex<BTreeMap<PathBuf, Vec<u8>>>,
+    wake: Notify,
+    /// Files whose last write failed
+    failing: Mutex<BTreeSet<PathBuf>>,
+    problems: Mutex<VecDeque<Problem>>,
+}
+
+impl Persister {
+    /// Queue `contents` to be written to `path`, replacing any snapshot of
+    /// it still queued
+    pub fn submit(&self, path: &Path, contents: Vec<u8>) {
+        self.inner
+            .dirty
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), contents);
+        self.inner.wake.notify_one();
+    }
+
+    /// Files with a snapshot waiting to be written
+    pub fn pending(&self) -> Vec<PathBuf> {
+        self.inner.dirty.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Files whose last write failed
+    pub fn failing(&self) -> Vec<PathBuf> {
+        self.inner.failing.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Recent problems, most recent first
+    pub fn problems(&self) -> Vec<Problem> {
+        self.inner
+            .problems
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    fn report(&self, path: &Path, kind: ProblemKind, detail: String) {
+        let mut problems = self.inner.problems.lock().unwrap();
+        if problems.len() >= MAX_PROBLEMS {
+            problems.pop_front();
+        }
+        problems.push_back(Problem {
+            at_ms: audit::now_ms(),
+            path: path.to_path_buf(),
+            kind,
+            detail,
+        });
+    }
+
+    /// Write every queued snapshot; whether all writes succeeded
+    pub fn write_pending(&self) -> bool {
+        let dirty = std::mem::take(&mut *self.inner.dirty.lock().unwrap());
+        let mut ok = true;
+        for (path, contents) in dirty {
+            match write_atomic(&path, &contents) {
+                Ok(()) => {
+                    debug!(path = %path.display(), bytes = contents.len(), "Persisted");
+                    self.inner.failing.lock().unwrap().remove(&path);
+                }
+                Err(e) => {
+                    warn!("{:#}", e);
+                    ok = false;
+                    self.report(&path, ProblemKind::WriteFailed, format!("{:#}", e));
+                    self.inner.failing.lock().unwrap().insert(path.clone());
+                    // Retried unless a newer snapshot came in meanwhile
+                    self.inner
+                        .dirty
+                        .lock()
+                        .unwrap()
+                        .entry(path)
+                        .or_insert(contents);
+                }
+            }
+        }
+        ok
+    }
+}
+
+/// Where the previous generation of `path` is kept
+pub fn backup_path(path: &Path) -> PathBuf {
+    suffixed(path, "bak")
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Replace `path` with `contents` so that a crash at any point leaves
+/// either the old or the new contents
+///
+/// The contents go to a temporary file that is synced before it is
+/// renamed over `path`; the file it replaces becomes the backup. If the
+/// power goes between the two renames only the backup is left, which
+/// [`load`] then falls back to.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = suffixed(path, "tmp");
+    let mut file = std::fs::File::create(&tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(contents)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    drop(file);
+
+    match std::fs::rename(path, backup_path(path)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to back up {}", path.display()));
+        }
+    }
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    // The renames are only durable once the directory is synced
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Err(e) = std::fs::File::open(dir).and_then(|dir| dir.sync_all()) {
+            warn!("Failed to sync {}: {}", dir.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Read `path` with `parse`, falling back to its backup if the file is
+/// missing while a backup exists, or doesn't parse, e.g. after a torn
+/// write; `None` if there is neither
+///
+/// A recovery keeps the unusable file as `<path>.corrupt`, puts the
+/// backup back in its place and is reported to `persister`. Without a
+/// usable backup the error is the primary file's.
+pub fn load<T>(
+    path: &Path,
+    persister: &Persister,
+    parse: impl Fn(&str) -> Result<T>,
+) -> Result<Option<T>> {
+    let primary = match std::fs::read_to_string(path) {
+        Ok(text) => match parse(&text) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => Err(e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(anyhow::Error::new(e).context(format!("Failed to read {}", path.display()))),
+    };
+
+    let backup = backup_path(path);
+    let text = match std::fs::read_to_string(&backup) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return primary.map(|()| None),
+        Err(e) => {
+            primary?;
+            return Err(e).with_context(|| format!("Failed to read {}", backup.display()));
+        }
+    };
+    let value = match parse(&text) {
+        Ok(value) => value,
+        Err(e) => {
+            primary?;
+            return Err(e).with_context(|| format!("Failed to recover {}", path.display()));
+        }
+    };
+
+    let detail = match primary {
+        Ok(()) => "missing, an interrupted write".to_string(),
+        Err(e) => format!("{:#}", e),
+    };
+    warn!(
+        "{} unusable ({}), recovered its backup",
+        path.display(),
+        detail
+    );
+    if path.exists() {
+        let corrupt = suffixed(path, "corrupt");
+        std::fs::rename(path, &corrupt)
+            .with_context(|| format!("Failed to set aside {}", path.display()))?;
+    }
+    std::fs::copy(&backup, path)
+        .with_context(|| format!("Failed to restore {}", path.display()))?;
+    persister.report(path, ProblemKind::Recovered, detail);
+    Ok(Some(value))
+}
+
+/// Write the snapshots the stores submit, once `persist_debounce_ms` passed
+/// without another one
+pub async fn run(state: Arc<AppState>) {
+    let persister = state.persister.clone();
+    loop {
+        persister.inner.wake.notified().await;
+        let debounce = Duration::from_millis(state.config().persist_debounce_ms);
+        // Every change during the pause restarts it
+        while tokio::time::timeout(debounce, persister.inner.wake.notified())
+            .await
+            .is_ok()
+        {}
+        let writer = persister.clone();
+        let written = tokio::task::spawn_blocking(move || writer.write_pending()).await;
+        if !matches!(written, Ok(true)) {
+            state.clock.sleep(RETRY_INTERVAL).await;
+            persister.inner.wake.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, TestServer};
+    use serde_json::{json, Value};
+
+    fn parse(text: &str) -> Result<Value> {
+        serde_json::from_str(text).context("Not JSON")
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    /// `path` written twice, as `{"generation": 1}` then `2`
+    fn two_generations(path: &Path) {
+        for generation in 1..=2 {
+            let contents = json!({ "generation": generation }).to_string();
+            write_atomic(path, contents.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn writes_keep_one_backup_generation() {
+        let dir = TempDir::new("persist-generations");
+        let path = dir.join("poses.json");
+        write_atomic(&path, b"1").unwrap();
+        assert!(!backup_path(&path).exists());
+        write_atomic(&path, b"2").unwrap();
+        write_atomic(&path, b"3").unwrap();
+        assert_eq!(read(&path), "3");
+        assert_eq!(read(&backup_path(&path)), "2");
+        assert!(!suffixed(&path, "tmp").exists());
+
+        let missing = dir.join("missing").join("poses.json");
+        let error = write_atomic(&missing, b"1").unwrap_err();
+        assert!(format!("{:#}", error).starts_with("Failed to create"));
+    }
+
+    #[test]
+    fn intact_files_load_without_a_problem() {
+        let dir = TempDir::new("persist-intact");
+        let path = dir.join("poses.json");
+        let persister = Persister::default();
+        assert_eq!(load(&path, &persister, parse).unwrap(), None);
+        two_generations(&path);
+        assert_eq!(
+            load(&path, &persister, parse).unwrap(),
+            Some(json!({ "generation": 2 }))
+        );
+        assert!(persister.problems().is_empty());
+    }
+
+    #[test]
+    fn truncated_file_falls_back_to_its_backup() {
+        let dir = TempDir::new("persist-truncated");
+        let path = dir.join("poses.json");
+        two_generations(&path);
+        // Cut short by a power loss part-way through the write
+        let torn = read(&path)[..7].to_string();
+        std::fs::write(&path, &torn).unwrap();
+
+        let persister = Persister::default();
+        assert_eq!(
+            load(&path, &persister, parse).unwrap(),
+            Some(json!({ "generation": 1 }))
+        );
+        // The backup is back in place and the torn file kept aside
+        assert_eq!(read(&path), read(&backup_path(&path)));
+        assert_eq!(read(&suffixed(&path, "corrupt")), torn);
+        let problems = persister.problems();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].kind, ProblemKind::Recovered);
+        assert_eq!(problems[0].path, path);
+        assert!(
+            problems[0].detail.starts_with("Not JSON"),
+            "{}",
+            problems[0].detail
+        );
+
+        // Loaded again, the restored file is fine
+        assert!(load(&path, &persister, parse).unwrap().is_some());
+        assert_eq!(persister.problems().len(), 1);
+    }
+
+    #[test]
+    fn missing_file_with_a_backup_is_an_interrupted_write() {
+        let dir = TempDir::new("persist-interrupted");
+        let path = dir.join("poses.json");
+        two_generations(&path);
+        // Power lost between the two renames
+        std::fs::rename(&path, backup_path(&path)).unwrap();
+
+        let persister = Persister::default();
+        assert_eq!(
+            load(&path, &persister, parse).unwrap(),
+            Some(json!({ "generation": 2 }))
+        );
+        assert!(path.exists());
+        assert!(!suffixed(&path, "corrupt").exists());
+        assert_eq!(
+            persister.problems()[0].detail,
+            "missing, an interrupted write"
+        );
+    }
+
+    #[test]
+    fn both_files_corrupt_is_the_primary_error() {
+        let dir = TempDir::new("persist-both-corrupt");
+        let path = dir.join("poses.json");
+        std::fs::write(&path, "{\"gen").unwrap();
+        std::fs::write(backup_path(&path), "{\"gen").unwrap();
+
+        let persister = Persister::default();
+        let error = load(&path, &persister, parse).unwrap_err();
+        assert_eq!(error.to_string(), "Not JSON");
+        // Nothing is moved, so both are there to be repaired by hand
+        assert_eq!(read(&path), "{\"gen");
+        assert!(!suffixed(&path, "corrupt").exists());
+        assert!(persister.problems().is_empty());
+
+        // As it is without a backup
+        std::fs::remove_file(backup_path(&path)).unwrap();
+        let error = load(&path, &persister, parse).unwrap_err();
+        assert_eq!(error.to_string(), "Not JSON");
+    }
+
+    #[test]
+    fn latest_snapshot_is_written_once() {
+        let dir = TempDir::new("persist-snapshots");
+        let path = dir.join("poses.json");
+        let persister = Persister::default();
+        assert!(persister.write_pending());
+        persister.submit(&path, b"1".to_vec());
+        persister.submit(&path, b"2".to_vec());
+        assert_eq!(persister.pending(), std::slice::from_ref(&path));
+        assert!(persister.write_pending());
+        assert_eq!(read(&path), "2");
+        // One write, so nothing to back up
+        assert!(!backup_path(&path).exists());
+        assert!(persister.pending().is_empty());
+    }
+
+    #[test]
+    fn failed_writes_are_retried_with_the_latest_snapshot() {
+        let dir = TempDir::new("persist-failing");
+        let path = dir.join("later").join("poses.json");
+        let persister = Persister::default();
+        persister.submit(&path, b"1".to_vec());
+        assert!(!persister.write_pending());
+        assert_eq!(persister.failing(), std::slice::from_ref(&path));
+        assert_eq!(persister.pending(), std::slice::from_ref(&path));
+        let problems = persister.problems();
+        assert_eq!(problems[0].kind, ProblemKind::WriteFailed);
+        assert!(problems[0].detail.starts_with("Failed to create"));
+
+        persister.submit(&path, b"2".to_vec());
+        std::fs::create_dir(dir.join("later")).unwrap();
+        assert!(persister.write_pending());
+        assert_eq!(read(&path), "2");
+        assert!(persister.failing().is_empty());
+        assert!(persister.pending().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn changes_are_written_once_they_settle() {
+        let dir = TempDir::new("persist-debounce");
+        let path = dir.join("library.json");
+        let server = TestServer::with_config(|config| {
+            config.library_file = Some(path.clone());
+            config.persist_debounce_ms = 300;
+        })
+        .await;
+        server.put("/api/poses/a", json!({ "angles": [10] })).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        // Restarts the pause
+        server.put("/api/poses/b", json!({ "angles": [20] })).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!path.exists());
+        let persistence = server.get("/api/persistence").await.body;
+        assert_eq!(persistence["pending"], json!([path.display().to_string()]));
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let written = parse(&read(&path)).unwrap();
+        assert_eq!(written["poses"]["a"]["angles"], json!([10]));
+        assert_eq!(written["poses"]["b"]["angles"], json!([20]));
+        // Both changes in one write
+        assert!(!backup_path(&path).exists());
+        let persistence = server.get("/api/persistence").await.body;
+        assert_eq!(persistence["pending"], json!([]));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recovery_at_startup_is_reported() {
+        let dir = TempDir::new("persist-startup");
+        let path = dir.join("library.json");
+        let library = |angle: u16| json!({ "poses": { "a": { "angles": [angle] } } }).to_string();
+        write_atomic(&path, library(10).as_bytes()).unwrap();
+        write_atomic(&path, library(20).as_bytes()).unwrap();
+        let torn = library(20)[..12].to_string();
+        std::fs::write(&path, torn).unwrap();
+
+        let server =
+            TestServer::with_config(|config| config.library_file = Some(path.clone())).await;
+        assert_eq!(server.get("/api/poses/a").await.body["angles"], json!([10]));
+        let problems = server.get("/api/persistence").await.body["problems"].clone();
+        assert_eq!(problems.as_array().unwrap().len(), 1);
+        assert_eq!(problems[0]["kind"], "recovered");
+        assert_eq!(problems[0]["path"], path.display().to_string());
+    }
+}
@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+
+/// Angles of all channels at a point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    /// Time since the start of the trajectory
+    pub time_ms: u32,
+    pub angles: Vec<u16>,
+}
+
+/// Sample a smooth trajectory through timed waypoints
+///
+/// Each channel follows a Catmull-Rom spline through the waypoint angles:
+/// the velocity at an inner waypoint points from its predecessor to its
+/// successor, and the trajectory starts and ends at rest. Segments are cubic
+/// Hermite curves in time, so unevenly spaced waypoints don't make the arm
+/// overshoot on the short ones.
+///
+/// Frames are `interval_ms` apart, plus a final frame at the last waypoint.
+/// A spline may swing past the waypoints between them; samples are clamped
+/// to `limits` (min, max per channel).
+///
+/// Waypoints must have strictly increasing times and the same number of
+/// angles as `limits`.
+pub fn catmull_rom(waypoints: &[Frame], interval_ms: u32, limits: &[(u16, u16)]) -> Vec<Frame> {
+    let (first, last) = match (waypoints.first(), waypoints.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Vec::new(),
+    };
+    let interval_ms = interval_ms.max(1);
+
+    let mut frames = Vec::new();
+    let mut time_ms = first.time_ms;
+    let mut segment = 0;
+    while time_ms < last.time_ms {
+        while waypoints[segment + 1].time_ms <= time_ms {
+            segment += 1;
+        }
+        frames.push(Frame {
+            time_ms: time_ms - first.time_ms,
+            angles: sample(waypoints, segment, time_ms, limits),
+        });
+        time_ms += interval_ms;
+    }
+    frames.push(Frame {
+        time_ms: last.time_ms - first.time_ms,
+        angles: last.angles.clone(),
+    });
+    frames
+}
+
+/// Angles at `time_ms` within the segment starting at waypoint `segment`
+fn sample(waypoints: &[Frame], segment: usize, time_ms: u32, limits: &[(u16, u16)]) -> Vec<u16> {
+    let start = &waypoints[segment];
+    let end = &waypoints[segment + 1];
+    let h = (end.time_ms - start.time_ms) as f64;
+    let s = (time_ms - start.time_ms) as f64 / h;
+
+    // Cubic Hermite basis functions
+    let h00 = 2.0 * s.powi(3) - 3.0 * s.powi(2) + 1.0;
+    let h10 = s.powi(3) - 2.0 * s.powi(2) + s;
+    let h01 = -2.0 * s.powi(3) + 3.0 * s.powi(2);
+    let h11 = s.powi(3) - s.powi(2);
+
+    limits
+        .iter()
+        .enumerate()
+        .map(|(channel, &(min, max))| {
+            let p0 = start.angles[channel] as f64;
+            let p1 = end.angles[channel] as f64;
+            let m0 = velocity(waypoints, segment, channel);
+            let m1 = velocity(waypoints, segment + 1, channel);
+            let angle = h00 * p0 + h10 * h * m0 + h01 * p1 + h11 * h * m1;
+            angle.round().clamp(min as f64, max as f64) as u16
+        })
+        .collect()
+}
+
+/// Velocity (degrees per ms) of a channel at a waypoint; zero at both ends
+fn velocity(waypoints: &[Frame], index: usize, channel: usize) -> f64 {
+    if index == 0 || index + 1 >= waypoints.len() {
+        return 0.0;
+    }
+    let before = &waypoints[index - 1];
+    let after = &waypoints[index + 1];
+    let rise = after.angles[channel] as f64 - before.angles[channel] as f64;
+    rise / (after.time_ms - before.time_ms) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: [(u16, u16); 2] = [(0, 180), (0, 180)];
+
+    fn waypoint(time_ms: u32, angles: &[u16]) -> Frame {
+        Frame {
+            time_ms,
+            angles: angles.to_vec(),
+        }
+    }
+
+    fn at(frames: &[Frame], time_ms: u32) -> &[u16] {
+        let frame = frames.iter().find(|f| f.time_ms == time_ms);
+        &frame.expect("frame at that time").angles
+    }
+
+    #[test]
+    fn path_passes_through_every_waypoint() {
+        let waypoints = [
+            waypoint(1000, &[90, 90]),
+            waypoint(1500, &[30, 120]),
+            waypoint(2300, &[150, 60]),
+            waypoint(3000, &[90, 90]),
+        ];
+        let frames = catmull_rom(&waypoints, 100, &LIMITS);
+        for waypoint in &waypoints {
+            assert_eq!(at(&frames, waypoint.time_ms - 1000), waypoint.angles);
+        }
+    }
+
+    #[test]
+    fn path_is_sampled_at_the_interval() {
+        let waypoints = [waypoint(0, &[0, 0]), waypoint(1050, &[180, 90])];
+        let frames = catmull_rom(&waypoints, 100, &LIMITS);
+        let times: Vec<u32> = frames.iter().map(|f| f.time_ms).collect();
+        let mut expected: Vec<u32> = (0..=1000).step_by(100).collect();
+        expected.push(1050);
+        assert_eq!(times, expected);
+
+        // Starting and ending at rest, in between the path only climbs
+        assert!(frames.windows(2).all(|w| w[0].angles[0] <= w[1].angles[0]));
+        assert!(at(&frames, 100)[0] < at(&frames, 600)[0] - at(&frames, 500)[0]);
+    }
+
+    #[test]
+    fn overshoot_is_clamped_to_the_limits() {
+        // Channel 0 arrives at 170 fast and swings past 175 on its way on
+        let waypoints = [
+            waypoint(0, &[0, 90]),
+            waypoint(100, &[170, 90]),
+            waypoint(1000, &[175, 90]),
+        ];
+        let unclamped = catmull_rom(&waypoints, 20, &[(0, 999), (0, 999)]);
+        assert!(unclamped.iter().any(|f| f.angles[0] > 175));
+        let frames = catmull_rom(&waypoints, 20, &[(0, 175), (80, 100)]);
+        assert!(frames
+            .iter()
+            .all(|f| f.angles[0] <= 175 && f.angles[1] == 90));
+    }
+
+    #[test]
+    fn single_waypoint_is_one_frame() {
+        let frames = catmull_rom(&[waypoint(500, &[10, 20])], 100, &LIMITS);
+        assert_eq!(frames, [waypoint(0, &[10, 20])]);
+        assert!(catmull_rom(&[], 100, &LIMITS).is_empty());
+    }
+
+    #[test]
+    fn repeated_waypoints_are_passed_through() {
+        // A hold between equal angles, then the same waypoint twice
+        let waypoints = [
+            waypoint(0, &[0, 0]),
+            waypoint(200, &[90, 90]),
+            waypoint(400, &[90, 90]),
+            waypoint(400, &[90, 90]),
+            waypoint(600, &[0, 0]),
+        ];
+        let frames = catmull_rom(&waypoints, 50, &LIMITS);
+        assert_eq!(at(&frames, 200), [90, 90]);
+        assert_eq!(at(&frames, 400), [90, 90]);
+        assert_eq!(at(&frames, 600), [0, 0]);
+        assert_eq!(frames.len(), 13);
+    }
+
+    #[test]
+    fn zero_interval_samples_every_millisecond() {
+        let waypoints = [waypoint(0, &[0, 0]), waypoint(10, &[10, 0])];
+        let frames = catmull_rom(&waypoints, 0, &LIMITS);
+        let times: Vec<u32> = frames.iter().map(|f| f.time_ms).collect();
+        assert_eq!(times, (0..=10).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,339 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::clock::Clock;
+use crate::config::PositionPollConfig;
+use crate::handlers::{self, AppState};
+
+/// Time between checks whether polling was enabled
+const DISABLED_CHECK: Duration = Duration::from_secs(1);
+
+/// Interval between polls: fast within the window after the last
+/// activity, then growing by `backoff` per poll up to the idle interval
+#[derive(Debug, Default)]
+pub struct PollRate {
+    last_activity: Option<Instant>,
+    /// Interval after the last poll, `None` before the first
+    interval: Option<Duration>,
+}
+
+impl PollRate {
+    /// Note a motion command, fresh read or change found at `now`
+    pub fn activity(&mut self, now: Instant) {
+        self.last_activity = Some(now);
+        self.interval = None;
+    }
+
+    /// Interval until the next poll, for a poll made at `now`
+    pub fn next(&mut self, now: Instant, config: &PositionPollConfig) -> Duration {
+        let fast = Duration::from_secs_f64(1.0 / config.max_hz);
+        let idle = Duration::from_millis(config.idle_interval_ms);
+        let window = Duration::from_millis(config.fast_window_ms);
+        let recent = self
+            .last_activity
+            .is_some_and(|at| now.saturating_duration_since(at) < window);
+        let interval = match self.interval {
+            Some(previous) if !recent => previous.mul_f64(config.backoff).clamp(fast, idle),
+            _ => fast,
+        };
+        self.interval = Some(interval);
+        interval
+    }
+}
+
+/// State of the background position polls, for `/api/health`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PollStatus {
+    /// Current interval between polls
+    pub interval_ms: u64,
+    pub rate_hz: f64,
+    /// Polls made, not counting ones skipped while the arm moved
+    pub polls: u64,
+    /// Time of the last poll in ms since the epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_poll_ms: Option<u64>,
+}
+
+/// Background reads of the positions at an adaptive rate
+///
+/// The startup counts as activity, so the positions are read quickly
+/// after a (re)start.
+pub struct Poller {
+    rate: Mutex<PollRate>,
+    status: Mutex<PollStatus>,
+    /// Signalled on activity, to cut an idle wait short
+    woken: Notify,
+    clock: Arc<dyn Clock>,
+}
+
+impl Poller {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let mut rate = PollRate::default();
+        rate.activity(clock.now());
+        Self {
+            rate: Mutex::new(rate),
+            status: Mutex::default(),
+            woken: Notify::new(),
+            clock,
+        }
+    }
+
+    /// Poll fast again
+    pub fn activity(&self) {
+        self.rate.lock().unwrap().activity(self.clock.now());
+        self.woken.notify_one();
+    }
+
+    pub fn status(&self) -> PollStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Poll the positions while `[position_poll]` is enabled
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        let config = state.config().position_poll.clone();
+        if !config.enabled {
+            state.clock.sleep(DISABLED_CHECK).await;
+            continue;
+        }
+
+        let now = state.clock.now();
+        let interval = state.poller.rate.lock().unwrap().next(now, &config);
+        {
+            let mut status = state.poller.status.lock().unwrap();
+            status.interval_ms = interval.as_millis() as u64;
+            status.rate_hz = 1.0 / interval.as_secs_f64();
+        }
+        tokio::select! {
+            _ = state.clock.sleep(interval) => {}
+            // Wait the fast interval instead, rather than reading right
+            // behind the command
+            _ = state.poller.woken.notified() => continue,
+        }
+
+        let polled = {
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || handlers::poll_positions(&state)).await
+        };
+        let changed = match polled {
+            Ok(Some(changed)) => {
+                let mut status = state.poller.status.lock().unwrap();
+                status.polls += 1;
+                status.last_poll_ms = Some(state.clock.now_ms());
+                changed
+            }
+            // Not connected, not in serial mode or moving
+            Ok(None) => false,
+            Err(e) => {
+                warn!("Position poll failed: {}", e);
+                false
+            }
+        };
+        if changed {
+            state
+                .poller
+                .rate
+                .lock()
+                .unwrap()
+                .activity(state.clock.now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::serial::NUM_SERVOS;
+    use crate::server::RobotArmServer;
+    use crate::testing::{self, MockController, TestServer};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn config() -> PositionPollConfig {
+        PositionPollConfig {
+            enabled: true,
+            max_hz: 10.0,
+            fast_window_ms: 1000,
+            backoff: 2.0,
+            idle_interval_ms: 1600,
+        }
+    }
+
+    /// Intervals of polls made back to back from `start`
+    fn intervals(rate: &mut PollRate, start: Instant, polls: usize) -> Vec<u64> {
+        let mut now = start;
+        (0..polls)
+            .map(|_| {
+                let interval = rate.next(now, &config());
+                now += interval;
+                interval.as_millis() as u64
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rate_decays_to_idle_after_the_window() {
+        let start = Instant::now();
+        let mut rate = PollRate::default();
+        rate.activity(start);
+        let expected = [100; 10].into_iter().chain([200, 400, 800, 1600, 1600]);
+        assert_eq!(
+            intervals(&mut rate, start, 15),
+            expected.collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn activity_resets_the_rate() {
+        let start = Instant::now();
+        let mut rate = PollRate::default();
+        // Never active: backs off from the first poll
+        assert_eq!(intervals(&mut rate, start, 4), [100, 200, 400, 800]);
+        let later = start + 10_000 * MS;
+        rate.activity(later);
+        assert_eq!(rate.next(later + 500 * MS, &config()), 100 * MS);
+        assert_eq!(rate.next(later + 999 * MS, &config()), 100 * MS);
+        assert_eq!(rate.next(later + 1000 * MS, &config()), 200 * MS);
+    }
+
+    struct Polled {
+        server: TestServer,
+        clock: Arc<MockClock>,
+        /// Channels read before the last poll
+        reads: AtomicUsize,
+    }
+
+    impl Polled {
+        async fn start() -> Self {
+            let clock = Arc::new(MockClock::new());
+            let mut config = testing::test_config();
+            config.position_poll = self::config();
+            let builder = RobotArmServer::builder()
+                .config(config)
+                .clock(clock.clone());
+            let server = TestServer::serve(builder, Arc::new(MockController::default())).await;
+            let reply = server.post("/api/serial/start", json!({})).await;
+            assert_eq!(reply.status, 200);
+            server.mock.take_commands();
+            Self {
+                server,
+                clock,
+                reads: AtomicUsize::new(0),
+            }
+        }
+
+        fn status(&self) -> PollStatus {
+            self.server.server.state().poller.status()
+        }
+
+        /// Wait for the poller to sleep `interval_ms` after `polls`
+        async fn settled(&self, polls: u64, interval_ms: u64) {
+            let interval = Duration::from_millis(interval_ms);
+            self.clock
+                .sleeping(|end| {
+                    let status = self.status();
+                    status.polls == polls
+                        && status.interval_ms == interval_ms
+                        && end == self.clock.now() + interval
+                })
+                .await;
+        }
+
+        /// Let the current interval pass; the interval after the poll
+        /// made then, which reads every channel
+        async fn poll(&self) -> u64 {
+            let status = self.status();
+            self.reads.fetch_add(self.gets(), Ordering::Relaxed);
+            self.clock
+                .advance(Duration::from_millis(status.interval_ms));
+            let read = |commands: &[String]| gets(commands) == NUM_SERVOS as usize;
+            self.server.mock.recorded(read).await;
+            // The next interval is set right before the poller sleeps on it
+            self.clock
+                .sleeping(|end| {
+                    let next = self.status();
+                    next.polls == status.polls + 1
+                        && end == self.clock.now() + Duration::from_millis(next.interval_ms)
+                })
+                .await;
+            self.status().interval_ms
+        }
+
+        /// Poll until back at the idle interval
+        async fn idle(&self) {
+            for _ in 0..20 {
+                if self.poll().await == 1600 {
+                    return;
+                }
+            }
+            panic!("the rate didn't decay: {:?}", self.status());
+        }
+
+        /// Channels read since the last call
+        fn reads(&self) -> usize {
+            let reads = self.reads.swap(0, Ordering::Relaxed);
+            reads + self.gets()
+        }
+
+        fn gets(&self) -> usize {
+            gets(&self.server.mock.take_commands())
+        }
+    }
+
+    fn gets(commands: &[String]) -> usize {
+        commands.iter().filter(|c| c.starts_with("GET")).count()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn polls_decay_and_reset_on_activity() {
+        let polled = Polled::start().await;
+        polled.settled(0, 100).await;
+        let mut intervals = Vec::new();
+        for _ in 0..14 {
+            intervals.push(polled.poll().await);
+        }
+        let expected: Vec<u64> = [100; 9]
+            .into_iter()
+            .chain([200, 400, 800, 1600, 1600])
+            .collect();
+        assert_eq!(intervals, expected);
+        assert_eq!(polled.status().polls, 14);
+        assert_eq!(polled.status().rate_hz, 1.0 / 1.6);
+        // Each poll reads every channel
+        assert_eq!(polled.reads(), 14 * NUM_SERVOS as usize);
+
+        // A motion command cuts the idle wait short
+        let reply = polled
+            .server
+            .post("/api/pose", json!({ "angles": [90, 90] }))
+            .await;
+        assert_eq!(reply.status, 200);
+        polled.settled(14, 100).await;
+        assert_eq!(polled.poll().await, 100);
+
+        // So does a change a poll found
+        polled.idle().await;
+        polled.server.mock.script().angles[3] = Some(45);
+        assert_eq!(polled.poll().await, 100);
+
+        // And a fresh read, even of preconditions that then fail
+        polled.idle().await;
+        let ranges = json!({ "ranges": { "0": { "min": 0, "max": 10 } } });
+        let pose = json!({ "angles": [90], "preconditions": ranges });
+        assert_eq!(polled.server.put("/api/poses/low", pose).await.status, 200);
+        let reply = polled
+            .server
+            .post("/api/poses/low/execute?fresh=true", json!({}))
+            .await;
+        assert_eq!(reply.status, 409, "{:?}", reply.body);
+        let polls = polled.status().polls;
+        polled.settled(polls, 100).await;
+    }
+}
@@ -0,0 +1,1238 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::config::ProtocolConfig;
+use crate::violations::MAX_DUMP;
+
+/// Maximum length of a single response line from the firmware
+pub const MAX_LINE_LEN: usize = 256;
+
+/// Channels the command set can address, one hex digit each
+pub const MAX_CHANNELS: u8 = 16;
+
+/// Largest angle the command set can carry (three digits)
+pub const MAX_ANGLE: u16 = 999;
+
+/// A servo channel number
+///
+/// Channels and angles are distinct types so they can't be swapped in a
+/// call. A `Channel` is always addressable; [`Channel::new`] also checks
+/// it against the number of channels fitted. Displays in the hex form the
+/// firmware uses.
+///
+/// ```
+/// use robotarm_backend::{Angle, Channel};
+///
+/// fn set(_channel: Channel, _angle: Angle) {}
+/// set(Channel::try_from(1).unwrap(), Angle::try_from(90).unwrap());
+/// ```
+///
+/// Passing them the other way round doesn't compile:
+///
+/// ```compile_fail
+/// use robotarm_backend::{Angle, Channel};
+///
+/// fn set(_channel: Channel, _angle: Angle) {}
+/// set(Angle::try_from(90).unwrap(), Channel::try_from(1).unwrap());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Channel(u8);
+
+impl Channel {
+    /// Channel `channel` of `count`
+    pub fn new(channel: u8, count: u8) -> Result<Self, String> {
+        if channel >= count.min(MAX_CHANNELS) {
+            return Err(format!("Invalid servo channel: {}", channel));
+        }
+        Ok(Self(channel))
+    }
+
+    /// The first `count` channels
+    pub fn all(count: u8) -> impl Iterator<Item = Channel> {
+        (0..count.min(MAX_CHANNELS)).map(Self)
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl TryFrom<u8> for Channel {
+    type Error = String;
+
+    fn try_from(channel: u8) -> Result<Self, String> {
+        Self::new(channel, MAX_CHANNELS)
+    }
+}
+
+impl From<Channel> for u8 {
+    fn from(channel: Channel) -> u8 {
+        channel.0
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", channel_to_hex(self.0))
+    }
+}
+
+/// An angle in the firmware's degrees, after trim and motion scale
+///
+/// [`Angle::new`] checks it against the firmware's configured range;
+/// displays in the decimal form of the basic command set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub struct Angle(u16);
+
+impl Angle {
+    /// `angle` if it is within `0..=max`
+    pub fn new(angle: u16, max: u16) -> Result<Self, String> {
+        if angle > max.min(MAX_ANGLE) {
+            return Err(format!("Invalid angle: {} (must be 0-{})", angle, max));
+        }
+        Ok(Self(angle))
+    }
+
+    pub fn get(self) -> u16 {
+        self.0
+    }
+}
+
+impl TryFrom<u16> for Angle {
+    type Error = String;
+
+    fn try_from(angle: u16) -> Result<Self, String> {
+        Self::new(angle, MAX_ANGLE)
+    }
+}
+
+impl From<Angle> for u16 {
+    fn from(angle: Angle) -> u16 {
+        angle.0
+    }
+}
+
+impl fmt::Display for Angle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Command sent to probe the firmware during the connection handshake
+/// (`GET` for channel 0); answered in both button mode and serial mode
+pub const HANDSHAKE_PROBE: &str = "GET 0\n";
+
+/// A parameter of a firmware command
+#[derive(Debug, Serialize)]
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub kind: ParamKind,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamKind {
+    /// One hex digit, 0-9 and A-F
+    Channel,
+    /// Decimal degrees; three digits with the extended command set
+    Angle,
+    /// Comma-separated angles for the channels from 0 up
+    AngleList,
+    /// Decimal milliseconds
+    DurationMs,
+    /// Decimal microseconds
+    PulseUs,
+    /// Decimal degrees per second, 0 for no limit
+    DegreesPerSecond,
+}
+
+/// `[protocol]` setting declaring that the firmware supports a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Requires {
+    ExtendedAngles,
+    AngleQuery,
+    BusyQuery,
+    VoltageQuery,
+    FaultRegister,
+    AdcQuery,
+    SpeedLimit,
+}
+
+/// A firmware command described as data
+///
+/// The encoders below build commands from these, and `GET /api/protocol`
+/// serves them as the reference for client SDKs. Every command is a line
+/// ended by a newline, wrapped in the configured framing; the firmware
+/// echoes it before replying, and answers `ERROR: <message>` on failure.
+#[derive(Debug, Serialize)]
+pub struct CommandSpec {
+    /// Stable identifier
+    pub name: &'static str,
+    /// Text the command starts with
+    pub keyword: &'static str,
+    /// The command line, with parameters in angle brackets
+    pub syntax: &'static str,
+    pub params: &'static [ParamSpec],
+    /// Reply on success
+    pub response: &'static str,
+    pub description: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requires: Option<Requires>,
+}
+
+impl CommandSpec {
+    /// Whether the firmware supports the command with these settings
+    pub fn enabled(&self, protocol: &ProtocolConfig) -> bool {
+        match self.requires {
+            None => true,
+            Some(Requires::ExtendedAngles) => protocol.extended_angles,
+            Some(Requires::AngleQuery) => protocol.angle_query,
+            Some(Requires::BusyQuery) => protocol.busy_query,
+            Some(Requires::VoltageQuery) => protocol.voltage_query,
+            Some(Requires::FaultRegister) => protocol.fault_register,
+            Some(Requires::AdcQuery) => protocol.adc_query,
+            Some(Requires::SpeedLimit) => protocol.speed_limit,
+        }
+    }
+}
+
+const CHANNEL: ParamSpec = ParamSpec {
+    name: "channel",
+    kind: ParamKind::Channel,
+};
+const ANGLE: ParamSpec = ParamSpec {
+    name: "angle",
+    kind: ParamKind::Angle,
+};
+const ANGLES: ParamSpec = ParamSpec {
+    name: "angles",
+    kind: ParamKind::AngleList,
+};
+
+pub const START: CommandSpec = CommandSpec {
+    name: "start",
+    keyword: "START",
+    syntax: "START",
+    params: &[],
+    response: "OK",
+    description: "Enter serial mode; other commands are ignored until then",
+    requires: None,
+};
+
+pub const STOP: CommandSpec = CommandSpec {
+    name: "stop",
+    keyword: "STOP",
+    syntax: "STOP",
+    params: &[],
+    response: "OK",
+    description: "Return to button mode",
+    requires: None,
+};
+
+pub const SET_ANGLE: CommandSpec = CommandSpec {
+    name: "set_angle",
+    keyword: "S",
+    syntax: "S<channel>:<angle>",
+    params: &[CHANNEL, ANGLE],
+    response: "OK",
+    description: "Set one servo's angle",
+    requires: None,
+};
+
+pub const SET_ANGLE_EXTENDED: CommandSpec = CommandSpec {
+    name: "set_angle_extended",
+    keyword: "A",
+    syntax: "A<channel>:<angle>",
+    params: &[CHANNEL, ANGLE],
+    response: "OK",
+    description: "Set one servo's angle, in three digits beyond 180",
+    requires: Some(Requires::ExtendedAngles),
+};
+
+pub const SET_PWM: CommandSpec = CommandSpec {
+    name: "set_pwm",
+    keyword: "P",
+    syntax: "P<channel>:<pulse_us>",
+    params: &[
+        CHANNEL,
+        ParamSpec {
+            name: "pulse_us",
+            kind: ParamKind::PulseUs,
+        },
+    ],
+    response: "OK",
+    description: "Set one channel's pulse width (0-20000us)",
+    requires: None,
+};
+
+pub const POSE: CommandSpec = CommandSpec {
+    name: "pose",
+    keyword: "POSE",
+    syntax: "POSE <angles>",
+    params: &[ANGLES],
+    response: "OK",
+    description: "Set the first servos at once, at full speed",
+    requires: None,
+};
+
+pub const MOVE: CommandSpec = CommandSpec {
+    name: "move",
+    keyword: "MOVE",
+    syntax: "MOVE <duration_ms> <angles>",
+    params: &[
+        ParamSpec {
+            name: "duration_ms",
+            kind: ParamKind::DurationMs,
+        },
+        ANGLES,
+    ],
+    response: "OK",
+    description: "Move the first servos together over a duration; replies once done",
+    requires: None,
+};
+
+pub const GET: CommandSpec = CommandSpec {
+    name: "get",
+    keyword: "GET",
+    syntax: "GET <channel>",
+    params: &[CHANNEL],
+    response: "SERVO <channel>: <angle> degrees",
+    description: "Read one servo's angle",
+    requires: Some(Requires::AngleQuery),
+};
+
+pub const BUSY: CommandSpec = CommandSpec {
+    name: "busy",
+    keyword: "BUSY",
+    syntax: "BUSY <channel>",
+    params: &[CHANNEL],
+    response: "BUSY <channel>: 0|1",
+    description: "Whether a servo is still moving",
+    requires: Some(Requires::BusyQuery),
+};
+
+pub const VOLT: CommandSpec = CommandSpec {
+    name: "volt",
+    keyword: "VOLT",
+    syntax: "VOLT",
+    params: &[],
+    response: "VOLT: <millivolts>",
+    description: "Read the supply voltage",
+    requires: Some(Requires::VoltageQuery),
+};
+
+pub const FAULTS: CommandSpec = CommandSpec {
+    name: "faults",
+    keyword: "FAULTS",
+    syntax: "FAULTS",
+    params: &[],
+    response: "FAULTS: <hex register>",
+    description: "Read the latched fault register",
+    requires: Some(Requires::FaultRegister),
+};
+
+pub const CLRFAULTS: CommandSpec = CommandSpec {
+    name: "clrfaults",
+    keyword: "CLRFAULTS",
+    syntax: "CLRFAULTS",
+    params: &[],
+    response: "OK",
+    description: "Clear the latched fault register",
+    requires: Some(Requires::FaultRegister),
+};
+
+pub const ADC: CommandSpec = CommandSpec {
+    name: "adc",
+    keyword: "ADC",
+    syntax: "ADC <channel>",
+    params: &[CHANNEL],
+    response: "ADC <channel>: <counts>",
+    description: "Read the raw counts of a servo's feedback potentiometer",
+    requires: Some(Requires::AdcQuery),
+};
+
+pub const SPEED: CommandSpec = CommandSpec {
+    name: "speed",
+    keyword: "SPEED",
+    syntax: "SPEED",
+    params: &[],
+    response: "SPEED: <degrees per second>",
+    description: "Read the firmware's slew-rate limit, 0 if there is none",
+    requires: Some(Requires::SpeedLimit),
+};
+
+pub const SETSPEED: CommandSpec = CommandSpec {
+    name: "set_speed",
+    keyword: "SETSPEED",
+    syntax: "SETSPEED <limit>",
+    params: &[ParamSpec {
+        name: "limit",
+        kind: ParamKind::DegreesPerSecond,
+    }],
+    response: "OK",
+    description: "Set the firmware's slew-rate limit for every servo, 0 for none",
+    requires: Some(Requires::SpeedLimit),
+};
+
+/// Names of the fault register bits, bit 0 first; other bits set are
+/// named `bit_<n>`
+pub const FAULT_BITS: [&str; 4] = ["overcurrent", "stall", "overtemperature", "undervoltage"];
+
+/// Every command the backend sends
+pub const COMMANDS: &[CommandSpec] = &[
+    START,
+    STOP,
+    SET_ANGLE,
+    SET_ANGLE_EXTENDED,
+    SET_PWM,
+    POSE,
+    MOVE,
+    GET,
+    BUSY,
+    VOLT,
+    FAULTS,
+    CLRFAULTS,
+    ADC,
+    SPEED,
+    SETSPEED,
+];
+
+/// The spec of an (unframed) command line, by its keyword; `S0:90`
+/// starts with `S` but `START` doesn't
+pub fn command_spec(cmd: &str) -> Option<&'static CommandSpec> {
+    let cmd = cmd.trim_start();
+    COMMANDS.iter().find(|spec| {
+        cmd.strip_prefix(spec.keyword)
+            .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_alphabetic()))
+    })
+}
+
+/// Wrap a newline-terminated command in the configured framing
+pub fn frame(cmd: &str, prefix: &str, suffix: &str) -> String {
+    let body = cmd.strip_suffix('\n').unwrap_or(cmd);
+    format!("{}{}{}\n", prefix, body, suffix)
+}
+
+/// Whether a response line matches an unsolicited line pattern, in which
+/// `*` matches any text
+pub fn matches_pattern(pattern: &str, line: &str) -> bool {
+    let pattern = pattern.trim();
+    let Some((first, rest)) = pattern.split_once('*') else {
+        return line == pattern;
+    };
+    let Some(mut remaining) = line.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match remaining.find(part) {
+            Some(at) => remaining = &remaining[at + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.ends_with(last)
+}
+
+/// Convert channel number to hex character (0-9, A-F)
+fn channel_to_hex(channel: u8) -> char {
+    if channel < 10 {
+        (b'0' + channel) as char
+    } else {
+        (b'A' + (channel - 10)) as char
+    }
+}
+
+fn format_angles(angles: &[Angle], extended: bool) -> String {
+    angles
+        .iter()
+        .map(|&a| {
+            if extended {
+                format!("{:03}", a.get())
+            } else {
+                a.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// [`START`]
+pub fn encode_start() -> String {
+    format!("{}\n", START.keyword)
+}
+
+/// [`STOP`]
+pub fn encode_stop() -> String {
+    format!("{}\n", STOP.keyword)
+}
+
+/// [`SET_ANGLE`], or [`SET_ANGLE_EXTENDED`] with the extended command set
+pub fn encode_set_angle(channel: Channel, angle: Angle, extended: bool) -> String {
+    if extended {
+        format!("{}{}:{:03}\n", SET_ANGLE_EXTENDED.keyword, channel, angle.get())
+    } else {
+        format!("{}{}:{}\n", SET_ANGLE.keyword, channel, angle)
+    }
+}
+
+/// [`SET_PWM`]
+pub fn encode_set_pwm(channel: Channel, pulse_us: u16) -> String {
+    format!("{}{}:{}\n", SET_PWM.keyword, channel, pulse_us)
+}
+
+/// [`GET`]
+pub fn encode_get_angle(channel: Channel) -> String {
+    format!("{} {}\n", GET.keyword, channel)
+}
+
+/// [`POSE`]; the extended command set uses three-digit angles
+pub fn encode_pose(angles: &[Angle], extended: bool) -> String {
+    format!("{} {}\n", POSE.keyword, format_angles(angles, extended))
+}
+
+/// [`MOVE`]; the extended command set uses three-digit angles
+pub fn encode_move(duration_ms: u16, angles: &[Angle], extended: bool) -> String {
+    format!(
+        "{} {} {}\n",
+        MOVE.keyword,
+        duration_ms,
+        format_angles(angles, extended)
+    )
+}
+
+/// [`BUSY`], for firmware that supports it
+pub fn encode_busy(channel: Channel) -> String {
+    format!("{} {}\n", BUSY.keyword, channel)
+}
+
+/// Parse the reply to [`encode_busy`]: `BUSY <n>: 0|1`
+pub fn parse_busy(response: &str) -> Option<bool> {
+    let rest = response.strip_prefix(BUSY.keyword)?.strip_prefix(' ')?;
+    match rest.split_once(':')?.1.trim() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+/// [`VOLT`], for firmware that supports it
+pub fn encode_voltage() -> String {
+    format!("{}\n", VOLT.keyword)
+}
+
+/// Parse the reply to [`encode_voltage`]: `VOLT: <millivolts>`
+pub fn parse_voltage(response: &str) -> Option<u32> {
+    response.strip_prefix(VOLT.keyword)?.split_once(':')?.1.trim().parse().ok()
+}
+
+/// [`ADC`], for firmware that supports it
+pub fn encode_adc(channel: Channel) -> String {
+    format!("{} {}\n", ADC.keyword, channel)
+}
+
+/// Parse the reply to [`encode_adc`]: `ADC <n>: <counts>`
+pub fn parse_adc(response: &str) -> Option<u32> {
+    let rest = response.strip_prefix(ADC.keyword)?.strip_prefix(' ')?;
+    rest.split_once(':')?.1.trim().parse().ok()
+}
+
+/// [`FAULTS`], for firmware that supports it
+pub fn encode_faults() -> String {
+    format!("{}\n", FAULTS.keyword)
+}
+
+/// [`CLRFAULTS`], for firmware that supports it
+pub fn encode_clear_faults() -> String {
+    format!("{}\n", CLRFAULTS.keyword)
+}
+
+/// Parse the reply to [`encode_faults`]: `FAULTS: <hex register>`
+pub fn parse_faults(response: &str) -> Option<u32> {
+    let value = response.strip_prefix(FAULTS.keyword)?.split_once(':')?.1.trim();
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    u32::from_str_radix(value, 16).ok()
+}
+
+/// [`SPEED`], for firmware that supports it
+pub fn encode_speed_limit() -> String {
+    format!("{}\n", SPEED.keyword)
+}
+
+/// [`SETSPEED`], for firmware that supports it
+pub fn encode_set_speed_limit(limit: u16) -> String {
+    format!("{} {}\n", SETSPEED.keyword, limit)
+}
+
+/// Parse the reply to [`encode_speed_limit`]: `SPEED: <degrees per second>`
+pub fn parse_speed_limit(response: &str) -> Option<u16> {
+    response.strip_prefix(SPEED.keyword)?.split_once(':')?.1.trim().parse().ok()
+}
+
+/// Names of the faults set in a fault register, see [`FAULT_BITS`]
+pub fn fault_names(register: u32) -> Vec<String> {
+    (0..u32::BITS)
+        .filter(|bit| register & (1 << bit) != 0)
+        .map(|bit| match FAULT_BITS.get(bit as usize) {
+            Some(name) => name.to_string(),
+            None => format!("bit_{}", bit),
+        })
+        .collect()
+}
+
+/// Unit words firmware builds put after the angle of a `GET` reply
+const ANGLE_UNITS: [&str; 4] = ["degrees", "degree", "deg", "°"];
+
+/// Parse the reply to [`encode_get_angle`] for `channel`
+///
+/// Accepts the variants of `SERVO 0: 90 degrees` seen in firmware builds:
+/// any spacing around the channel and colon (`SERVO0:90`), the channel in
+/// hex or decimal, and an optional unit. A reply for another channel is an
+/// error rather than that channel's angle, as is an angle beyond what the
+/// command set can carry. A value in `sentinels`, such as 255 or -1 from
+/// firmware that couldn't poll the servo, is no angle: `None`.
+pub fn parse_angle(
+    response: &str,
+    channel: Channel,
+    sentinels: &[i32],
+) -> Result<Option<Angle>, String> {
+    let invalid = || format!("Failed to parse servo angle from response: {}", response);
+    let text = response.trim();
+    let rest = text
+        .get(..5)
+        .filter(|keyword| keyword.eq_ignore_ascii_case("SERVO"))
+        .map(|_| text[5..].trim_start())
+        .ok_or_else(invalid)?;
+
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(rest.len());
+    let (echoed, rest) = rest.split_at(end);
+    if echoed.is_empty() {
+        return Err(invalid());
+    }
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix(':').unwrap_or(rest).trim_start();
+
+    let digits = rest.strip_prefix('-').unwrap_or(rest);
+    let end = rest.len() - digits.len()
+        + digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+    let (value, unit) = rest.split_at(end);
+    let value: i32 = value.parse().map_err(|_| invalid())?;
+    let unit = unit.trim();
+    if !unit.is_empty() && !ANGLE_UNITS.iter().any(|u| unit.eq_ignore_ascii_case(u)) {
+        return Err(invalid());
+    }
+
+    if !echoed.eq_ignore_ascii_case(&channel.to_string())
+        && echoed != channel.get().to_string()
+    {
+        return Err(format!(
+            "Reply for servo {} to a query for servo {}: {}",
+            echoed,
+            channel.get(),
+            response
+        ));
+    }
+    if sentinels.contains(&value) {
+        return Ok(None);
+    }
+    let angle = u16::try_from(value).map_err(|_| invalid())?;
+    Angle::try_from(angle).map(Some).map_err(|_| invalid())
+}
+
+/// Outcome of a handshake probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    /// A recognizable response was received
+    Clean,
+    /// Bytes were received but don't form a valid response (likely a
+    /// baud rate mismatch)
+    Garbled,
+    /// Nothing was received
+    Silent,
+}
+
+/// Classify the raw bytes received in reply to [`HANDSHAKE_PROBE`]
+pub fn classify_handshake(bytes: &[u8]) -> Handshake {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return Handshake::Garbled,
+    };
+    if text.trim().is_empty() {
+        return Handshake::Silent;
+    }
+    if text
+        .bytes()
+        .any(|b| !matches!(b, b' '..=b'~' | b'\r' | b'\n' | b'\t'))
+    {
+        return Handshake::Garbled;
+    }
+
+    let known = ["SERVO", "OK", "ERROR", "Type START"];
+    if text
+        .lines()
+        .any(|line| known.iter().any(|k| line.trim().starts_with(k)))
+    {
+        Handshake::Clean
+    } else {
+        Handshake::Garbled
+    }
+}
+
+/// A line produced by the [`LineAssembler`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    /// A complete, non-empty line without its terminator
+    Text(String),
+    /// A line exceeded the maximum length and was discarded
+    Overflow,
+}
+
+/// Streaming line framer for firmware responses
+///
+/// Bytes are fed in arbitrary chunks and complete lines come out. Carriage
+/// returns, NULs and other non-printable bytes are dropped, empty lines are
+/// skipped, and a line matching the last command sent (terminal echo) can
+/// be skipped. The internal buffer is reused between lines.
+pub struct LineAssembler {
+    buf: Vec<u8>,
+    max_len: usize,
+    overflowed: bool,
+    /// Echo to skip, empty if none; kept to reuse its allocation
+    echo: Vec<u8>,
+    garbage: usize,
+    /// The first dropped non-printable bytes, for a dump
+    dropped: Vec<u8>,
+}
+
+impl LineAssembler {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(max_len),
+            max_len,
+            overflowed: false,
+            echo: Vec::new(),
+            garbage: 0,
+            dropped: Vec::new(),
+        }
+    }
+
+    /// Discard any partial line and echo expectation
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.overflowed = false;
+        self.echo.clear();
+        self.garbage = 0;
+        self.dropped.clear();
+    }
+
+    /// Skip the next line if it is an echo of `cmd`
+    pub fn expect_echo(&mut self, cmd: &str) {
+        self.echo.clear();
+        self.echo.extend_from_slice(cmd.trim().as_bytes());
+    }
+
+    /// Number of non-printable bytes dropped since the last reset
+    pub fn garbage(&self) -> usize {
+        self.garbage
+    }
+
+    /// The first of those bytes, up to [`MAX_DUMP`]
+    pub fn dropped(&self) -> &[u8] {
+        &self.dropped
+    }
+
+    /// Text received so far for a line that has not been terminated yet
+    pub fn partial(&self) -> String {
+        String::from_utf8_lossy(&self.buf).into_owned()
+    }
+
+    /// Feed a chunk of bytes, yielding every line it completes
+    pub fn feed<'a>(&'a mut self, chunk: &'a [u8]) -> Lines<'a> {
+        Lines {
+            assembler: self,
+            chunk,
+            pos: 0,
+        }
+    }
+
+    /// Buffer a run of printable bytes, as far as the line has room
+    fn extend(&mut self, bytes: &[u8]) {
+        let room = self.max_len.saturating_sub(self.buf.len());
+        if bytes.len() > room {
+            self.overflowed = true;
+        }
+        self.buf.extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Line> {
+        match byte {
+            b'\n' => self.finish_line(),
+            b'\r' => None,
+            byte if is_printable(byte) => {
+                if self.buf.len() < self.max_len {
+                    self.buf.push(byte);
+                } else {
+                    self.overflowed = true;
+                }
+                None
+            }
+            _ => {
+                self.garbage += 1;
+                if self.dropped.len() < MAX_DUMP {
+                    self.dropped.push(byte);
+                }
+                None
+            }
+        }
+    }
+
+    fn finish_line(&mut self) -> Option<Line> {
+        if self.overflowed {
+            self.buf.clear();
+            self.overflowed = false;
+            return Some(Line::Overflow);
+        }
+
+        let start = self.buf.iter().position(|b| !b.is_ascii_whitespace());
+        let end = self.buf.iter().rposition(|b| !b.is_ascii_whitespace());
+        let line = match (start, end) {
+            (Some(start), Some(end)) => &self.buf[start..=end],
+            _ => &[][..],
+        };
+        if line.is_empty() {
+            self.buf.clear();
+            return None;
+        }
+        if !self.echo.is_empty() && self.echo == line {
+            self.echo.clear();
+            self.buf.clear();
+            return None;
+        }
+
+        // Only printable ASCII is ever buffered
+        let text = String::from_utf8_lossy(line).into_owned();
+        self.buf.clear();
+        Some(Line::Text(text))
+    }
+}
+
+/// Bytes a line keeps; the others end it or are dropped
+fn is_printable(byte: u8) -> bool {
+    matches!(byte, b' '..=b'~' | b'\t')
+}
+
+/// Iterator over the lines completed by one chunk
+pub struct Lines<'a> {
+    assembler: &'a mut LineAssembler,
+    chunk: &'a [u8],
+    pos: usize,
+}
+
+impl Lines<'_> {
+    /// The part of the chunk not fed yet
+    pub fn rest(&self) -> &[u8] {
+        &self.chunk[self.pos..]
+    }
+}
+
+impl Iterator for Lines<'_> {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Line> {
+        while self.pos < self.chunk.len() {
+            // Most of a line is printable, and buffered a run at a time
+            let rest = &self.chunk[self.pos..];
+            let run = rest.iter().position(|&b| !is_printable(b)).unwrap_or(rest.len());
+            if run > 0 {
+                self.assembler.extend(&rest[..run]);
+                self.pos += run;
+                continue;
+            }
+            let byte = rest[0];
+            self.pos += 1;
+            if let Some(line) = self.assembler.push(byte) {
+                return Some(line);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(assembler: &mut LineAssembler, chunk: &[u8]) -> Vec<Line> {
+        assembler.feed(chunk).collect()
+    }
+
+    fn text(line: &str) -> Line {
+        Line::Text(line.to_string())
+    }
+
+    // Line assembly
+
+    #[test]
+    fn line_split_across_chunks_comes_out_once_complete() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assert_eq!(lines(&mut assembler, b"SERVO 0"), []);
+        assert_eq!(assembler.partial(), "SERVO 0");
+        assert_eq!(lines(&mut assembler, b": 90 deg"), []);
+        assert_eq!(
+            lines(&mut assembler, b"rees\n"),
+            [text("SERVO 0: 90 degrees")]
+        );
+        assert_eq!(assembler.partial(), "");
+    }
+
+    #[test]
+    fn line_split_at_every_byte_is_the_same_line() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        let mut out = Vec::new();
+        for byte in b"OK\r\nERROR: Invalid angle\r\n" {
+            out.extend(lines(&mut assembler, std::slice::from_ref(byte)));
+        }
+        assert_eq!(out, [text("OK"), text("ERROR: Invalid angle")]);
+    }
+
+    #[test]
+    fn two_lines_in_one_chunk_both_come_out() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assert_eq!(
+            lines(&mut assembler, b"OK\nSERVO 1: 45 degrees\nSERV"),
+            [text("OK"), text("SERVO 1: 45 degrees")]
+        );
+        assert_eq!(assembler.partial(), "SERV");
+    }
+
+    #[test]
+    fn lines_stop_where_the_caller_stops_reading() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        let mut fed = assembler.feed(b"OK\nERROR: busy\n");
+        assert_eq!(fed.next(), Some(text("OK")));
+        assert_eq!(fed.rest(), b"ERROR: busy\n");
+    }
+
+    #[test]
+    fn oversized_line_is_reported_and_the_next_one_kept() {
+        let mut assembler = LineAssembler::new(8);
+        assert_eq!(lines(&mut assembler, b"0123456"), []);
+        assert_eq!(lines(&mut assembler, b"789"), []);
+        assert_eq!(
+            lines(&mut assembler, b"ABC\nOK\n"),
+            [Line::Overflow, text("OK")]
+        );
+    }
+
+    #[test]
+    fn line_of_exactly_the_maximum_is_kept() {
+        let mut assembler = LineAssembler::new(8);
+        assert_eq!(lines(&mut assembler, b"01234567\n"), [text("01234567")]);
+    }
+
+    #[test]
+    fn garbage_bytes_are_dropped_and_counted() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assert_eq!(lines(&mut assembler, b"O\x00K\xff\x1b\n"), [text("OK")]);
+        assert_eq!(assembler.garbage(), 3);
+        assert_eq!(assembler.dropped(), [0x00, 0xff, 0x1b]);
+
+        assembler.reset();
+        assert_eq!(assembler.garbage(), 0);
+        assert!(assembler.dropped().is_empty());
+    }
+
+    #[test]
+    fn crlf_split_across_chunks_ends_one_line() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assert_eq!(lines(&mut assembler, b"OK\r"), []);
+        assert_eq!(lines(&mut assembler, b"\nOK\r"), [text("OK")]);
+        assert_eq!(lines(&mut assembler, b"\n\r\n"), [text("OK")]);
+    }
+
+    #[test]
+    fn blank_lines_are_skipped_and_text_trimmed() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assert_eq!(lines(&mut assembler, b"\n  \n\t OK \n"), [text("OK")]);
+    }
+
+    #[test]
+    fn echo_is_skipped_once() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assembler.expect_echo("S0:90\n");
+        assert_eq!(lines(&mut assembler, b"S0:9"), []);
+        assert_eq!(
+            lines(&mut assembler, b"0\r\nOK\nS0:90\n"),
+            [text("OK"), text("S0:90")]
+        );
+    }
+
+    #[test]
+    fn reset_discards_the_partial_line() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assert_eq!(lines(&mut assembler, b"garbled"), []);
+        assembler.reset();
+        assert_eq!(lines(&mut assembler, b"OK\n"), [text("OK")]);
+    }
+
+    /// Lines, counts and leftovers of `stream` fed in chunks of `size`
+    fn assembled(stream: &[u8], size: usize, echo: &str) -> (Vec<Line>, usize, Vec<u8>, String) {
+        let mut assembler = LineAssembler::new(8);
+        assembler.expect_echo(echo);
+        let mut out = Vec::new();
+        for chunk in stream.chunks(size) {
+            out.extend(lines(&mut assembler, chunk));
+        }
+        let dropped = assembler.dropped().to_vec();
+        (out, assembler.garbage(), dropped, assembler.partial())
+    }
+
+    /// The same, one byte at a time through `push` alone, the way every
+    /// byte was buffered before runs were
+    fn assembled_bytewise(stream: &[u8], echo: &str) -> (Vec<Line>, usize, Vec<u8>, String) {
+        let mut assembler = LineAssembler::new(8);
+        assembler.expect_echo(echo);
+        let out = stream
+            .iter()
+            .filter_map(|&byte| assembler.push(byte))
+            .collect();
+        let dropped = assembler.dropped().to_vec();
+        (out, assembler.garbage(), dropped, assembler.partial())
+    }
+
+    #[test]
+    fn runs_are_assembled_like_single_bytes() {
+        let streams: [&[u8]; 6] = [
+            b"OK\r\nSERVO 1: 45\n",
+            b"0123456789\nOK\n01234567\n0123\x00456\x0178\n",
+            b"S0:90\r\nOK\nS0:90\n  \n\t OK \nPART",
+            b"\xff\xfe\x00OK\x1b[0m\r\n\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\n",
+            b"01234567\t\nABCDEFGH\r\r\r\n\n",
+            b"",
+        ];
+        for stream in streams {
+            for echo in ["", "S0:90\n"] {
+                let expected = assembled_bytewise(stream, echo);
+                for size in 1..=stream.len().max(1) {
+                    assert_eq!(
+                        assembled(stream, size, echo),
+                        expected,
+                        "{:?} in chunks of {}",
+                        String::from_utf8_lossy(stream),
+                        size
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn buffers_are_reused_across_lines() {
+        let mut assembler = LineAssembler::new(MAX_LINE_LEN);
+        assembler.expect_echo("POSE 90,90,90,90,90,90");
+        let (buf, echo) = (assembler.buf.capacity(), assembler.echo.capacity());
+        for _ in 0..100 {
+            assembler.expect_echo("S0:90");
+            assert_eq!(
+                lines(&mut assembler, b"S0:90\r\nSERVO 0: 90 degrees\r\n"),
+                [text("SERVO 0: 90 degrees")]
+            );
+        }
+        assert_eq!(assembler.buf.capacity(), buf);
+        assert_eq!(assembler.echo.capacity(), echo);
+    }
+
+    // Angle replies
+
+    #[test]
+    fn angle_reply_variants_are_parsed_or_rejected() {
+        const PARSE: &str = "Failed to parse servo angle";
+        const MISMATCH: &str = "to a query for servo";
+        // Reply, channel asked, sentinels, angle or error
+        type Case = (
+            &'static str,
+            u8,
+            &'static [i32],
+            Result<Option<u16>, &'static str>,
+        );
+        let cases: &[Case] = &[
+            ("SERVO 0: 90 degrees", 0, &[], Ok(Some(90))),
+            ("SERVO0:90", 0, &[], Ok(Some(90))),
+            ("servo 0 : 90 DEG", 0, &[], Ok(Some(90))),
+            ("  SERVO 1: 180 degrees\r", 1, &[], Ok(Some(180))),
+            ("SERVO 2: 0 degree", 2, &[], Ok(Some(0))),
+            ("SERVO 3: 45\u{b0}", 3, &[], Ok(Some(45))),
+            ("SERVO A: 120 degrees", 10, &[], Ok(Some(120))),
+            ("SERVO a: 120", 10, &[], Ok(Some(120))),
+            ("SERVO 10: 120", 10, &[], Ok(Some(120))),
+            ("SERVO 4: 255 degrees", 4, &[255, -1], Ok(None)),
+            ("SERVO 4: -1", 4, &[255, -1], Ok(None)),
+            ("SERVO 1: 90 degrees", 0, &[], Err(MISMATCH)),
+            ("SERVO B: 90 degrees", 10, &[], Err(MISMATCH)),
+            ("SERVO 4: -1", 4, &[], Err(PARSE)),
+            ("SERVO 0: 1000 degrees", 0, &[], Err(PARSE)),
+            ("SERVO 0:", 0, &[], Err(PARSE)),
+            ("SERVO 0: degrees", 0, &[], Err(PARSE)),
+            ("SERVO: 90", 0, &[], Err(PARSE)),
+            ("SERVO 0: 90 degrees!", 0, &[], Err(PARSE)),
+            ("SERVO 0: 90 rad", 0, &[], Err(PARSE)),
+            ("SERVO 0: 9x0", 0, &[], Err(PARSE)),
+            ("OK", 0, &[], Err(PARSE)),
+            ("", 0, &[], Err(PARSE)),
+        ];
+        for &(reply, channel, sentinels, expected) in cases {
+            let channel = Channel::try_from(channel).unwrap();
+            let parsed = parse_angle(reply, channel, sentinels);
+            match expected {
+                Ok(angle) => {
+                    assert_eq!(parsed.map(|a| a.map(Angle::get)), Ok(angle), "{:?}", reply)
+                }
+                Err(error) => {
+                    let message = parsed.expect_err(reply);
+                    assert!(message.contains(error), "{:?}: {}", reply, message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn adc_replies_are_parsed_or_rejected() {
+        let cases = [
+            ("ADC 0: 2048", Some(2048)),
+            ("ADC B: 0", Some(0)),
+            ("ADC 3:4095 ", Some(4095)),
+            ("ADC 3: -1", None),
+            ("ADC 3: 12.5", None),
+            ("ADC 3:", None),
+            ("ADC3: 100", None),
+            ("SERVO 3: 90", None),
+            ("OK", None),
+        ];
+        for (reply, counts) in cases {
+            assert_eq!(parse_adc(reply), counts, "{:?}", reply);
+        }
+        let channel = Channel::try_from(11).unwrap();
+        // Channels past 9 as hex digits, like the other commands
+        assert_eq!(encode_adc(channel), "ADC B\n");
+    }
+
+    // Command encoding
+
+    fn angles(angles: &[u16]) -> Vec<Angle> {
+        angles
+            .iter()
+            .map(|&a| Angle::try_from(a).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn basic_commands_carry_plain_angles() {
+        let channel = Channel::try_from(11).unwrap();
+        let angle = Angle::try_from(5).unwrap();
+        assert_eq!(encode_set_angle(channel, angle, false), "SB:5\n");
+        assert_eq!(
+            encode_pose(&angles(&[5, 90, 180]), false),
+            "POSE 5,90,180\n"
+        );
+        assert_eq!(
+            encode_move(500, &angles(&[0, 45]), false),
+            "MOVE 500 0,45\n"
+        );
+    }
+
+    #[test]
+    fn extended_commands_carry_three_digit_angles() {
+        let channel = Channel::try_from(2).unwrap();
+        for (angle, command) in [(5, "A2:005\n"), (270, "A2:270\n")] {
+            let angle = Angle::try_from(angle).unwrap();
+            assert_eq!(encode_set_angle(channel, angle, true), command);
+        }
+        assert_eq!(
+            encode_pose(&angles(&[5, 90, 270]), true),
+            "POSE 005,090,270\n"
+        );
+        assert_eq!(
+            encode_move(500, &angles(&[0, 200]), true),
+            "MOVE 500 000,200\n"
+        );
+        assert_eq!(
+            command_spec("A2:005").map(|spec| spec.name),
+            Some("set_angle_extended")
+        );
+    }
+
+    #[test]
+    fn angles_are_bounded_by_the_firmware_range() {
+        assert!(Angle::new(180, 180).is_ok());
+        assert_eq!(
+            Angle::new(181, 180),
+            Err("Invalid angle: 181 (must be 0-180)".to_string())
+        );
+        assert!(Angle::new(270, 270).is_ok());
+        // No firmware setting goes beyond three digits
+        assert!(Angle::new(1000, u16::MAX).is_err());
+    }
+
+    // Channels and angles
+
+    #[test]
+    fn channels_and_angles_serialize_as_plain_numbers() {
+        let channel = Channel::new(11, 16).unwrap();
+        let angle = Angle::new(270, 270).unwrap();
+        assert_eq!(
+            serde_json::to_value(channel).unwrap(),
+            serde_json::json!(11)
+        );
+        assert_eq!(serde_json::to_value(angle).unwrap(), serde_json::json!(270));
+        let back: (Channel, Angle) = serde_json::from_str("[11, 270]").unwrap();
+        assert_eq!(back, (channel, angle));
+        let angles: Vec<Angle> = serde_json::from_str("[0, 90, 999]").unwrap();
+        assert_eq!(serde_json::to_string(&angles).unwrap(), "[0,90,999]");
+        assert_eq!(
+            (channel.to_string(), angle.to_string()),
+            ("B".to_string(), "270".to_string())
+        );
+    }
+
+    #[test]
+    fn out_of_range_channels_and_angles_are_not_deserialized() {
+        assert!(serde_json::from_str::<Channel>("15").is_ok());
+        for json in ["16", "255", "-1", "1.5", "\"1\"", "256"] {
+            assert!(serde_json::from_str::<Channel>(json).is_err(), "{}", json);
+        }
+        let error = serde_json::from_str::<Channel>("16").unwrap_err();
+        assert!(error.to_string().contains("Invalid servo channel: 16"));
+        for json in ["1000", "65535", "-1", "90.5", "\"90\"", "65536"] {
+            assert!(serde_json::from_str::<Angle>(json).is_err(), "{}", json);
+        }
+        let error = serde_json::from_str::<Angle>("1000").unwrap_err();
+        assert!(error.to_string().contains("Invalid angle: 1000"));
+    }
+
+    #[test]
+    fn channels_are_bounded_by_the_count_fitted() {
+        assert_eq!(Channel::new(5, 6).unwrap().get(), 5);
+        assert_eq!(
+            Channel::new(6, 6),
+            Err("Invalid servo channel: 6".to_string())
+        );
+        // Never beyond one hex digit
+        assert!(Channel::new(16, 32).is_err());
+        assert_eq!(
+            Channel::all(3).map(Channel::get).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(Channel::all(40).count(), MAX_CHANNELS as usize);
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn};
+
+use crate::audit::{self, AuditEntry};
+use crate::config::{Config, Role};
+use crate::handlers::AppState;
+
+/// Audit kinds whose changes are replicated to the peer
+pub const REPLICATED_KINDS: [&str; 3] = ["pose", "sequence", "config"];
+
+/// Attempts to deliver one entry before giving up on it
+const PUSH_ATTEMPTS: u32 = 3;
+
+/// Time allowed for one push request
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of problems kept for the status report
+const MAX_PROBLEMS: usize = 100;
+
+/// Role and replication bookkeeping of this instance
+pub struct Replication {
+    role: Mutex<Role>,
+    outbox: Option<mpsc::UnboundedSender<AuditEntry>>,
+    status: Mutex<ReplicationStatus>,
+    /// Timestamp of the last write per object, for last-writer-wins
+    last_write: Mutex<HashMap<String, u64>>,
+}
+
+/// Counters and problems reported by `GET /api/replication`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplicationStatus {
+    /// Entries delivered to the peer
+    pub pushed: u64,
+    /// Entries that could not be delivered
+    pub push_failures: u64,
+    /// Entries received from the peer and applied
+    pub applied: u64,
+    /// Entries received from the peer but older than a local write
+    pub rejected: u64,
+    /// Most recent conflicts, divergences and delivery failures
+    pub problems: Vec<Problem>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    pub timestamp_ms: u64,
+    pub entry_id: u64,
+    pub message: String,
+}
+
+/// Object an audit entry applies to, e.g. "pose/wave"
+fn object_key(entry: &AuditEntry) -> String {
+    format!("{}/{}", entry.kind, entry.target.as_deref().unwrap_or(""))
+}
+
+impl Replication {
+    /// Set up replication for the config; the receiver is the queue of
+    /// entries to push if a peer is configured (see [`push_loop`])
+    pub fn new(config: &Config) -> (Self, Option<mpsc::UnboundedReceiver<AuditEntry>>) {
+        let (outbox, receiver) = match &config.replication.peer_url {
+            Some(_) => {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                (Some(sender), Some(receiver))
+            }
+            None => (None, None),
+        };
+        let replication = Self {
+            role: Mutex::new(config.role),
+            outbox,
+            status: Mutex::new(ReplicationStatus::default()),
+            last_write: Mutex::new(HashMap::new()),
+        };
+        (replication, receiver)
+    }
+
+    pub fn role(&self) -> Role {
+        *self.role.lock().unwrap()
+    }
+
+    /// Make this instance active, returning its previous role
+    pub fn promote(&self) -> Role {
+        std::mem::replace(&mut *self.role.lock().unwrap(), Role::Active)
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Note a change made through this instance's API and queue it for
+    /// the peer
+    pub fn local_write(&self, entry: &AuditEntry) {
+        self.last_write
+            .lock()
+            .unwrap()
+            .insert(object_key(entry), entry.timestamp_ms);
+        if let Some(outbox) = &self.outbox {
+            if REPLICATED_KINDS.contains(&entry.kind.as_str()) {
+                let _ = outbox.send(entry.clone());
+            }
+        }
+    }
+
+    /// Decide whether a replicated entry wins over local writes to the same
+    /// object (last writer wins by timestamp)
+    pub fn accept_remote(&self, entry: &AuditEntry) -> bool {
+        let mut last_write = self.last_write.lock().unwrap();
+        let key = object_key(entry);
+        match last_write.get(&key) {
+            Some(&local) if local > entry.timestamp_ms => {
+                drop(last_write);
+                self.status.lock().unwrap().rejected += 1;
+                self.report(
+                    entry.id,
+                    format!(
+                        "Conflict on {}: peer change at {} is older than local change at {}",
+                        key, entry.timestamp_ms, local
+                    ),
+                );
+                false
+            }
+            _ => {
+                last_write.insert(key, entry.timestamp_ms);
+                true
+            }
+        }
+    }
+
+    pub fn applied(&self, entry: &AuditEntry, divergences: Vec<String>) {
+        self.status.lock().unwrap().applied += 1;
+        for divergence in divergences {
+            self.report(entry.id, format!("Divergence: {}", divergence));
+        }
+    }
+
+    pub fn report(&self, entry_id: u64, message: String) {
+        warn!("Replication problem (entry {}): {}", entry_id, message);
+        let mut status = self.status.lock().unwrap();
+        status.problems.push(Problem {
+            timestamp_ms: audit::now_ms(),
+            entry_id,
+            message,
+        });
+        let excess = status.problems.len().saturating_sub(MAX_PROBLEMS);
+        status.problems.drain(..excess);
+    }
+}
+
+/// Deliver queued entries to the peer in order, retrying failed pushes
+pub async fn push_loop(state: Arc<AppState>, mut outbox: mpsc::UnboundedReceiver<AuditEntry>) {
+    while let Some(entry) = outbox.recv().await {
+        let config = state.config();
+        let peer = match &config.replication.peer_url {
+            Some(peer) => peer,
+            None => continue,
+        };
+        let body = match serde_json::to_string(&entry) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to serialize audit entry {}: {}", entry.id, e);
+                continue;
+            }
+        };
+
+        let mut delivered = false;
+        for attempt in 1..=PUSH_ATTEMPTS {
+            let token = config.replication.token.as_deref();
+            match post_json(peer, "/api/replication", token, &body).await {
+                Ok(status) if (200..300).contains(&status) => {
+                    debug!("Replicated audit entry {} to {}", entry.id, peer);
+                    delivered = true;
+                    break;
+                }
+                Ok(409) => {
+                    state.replication.report(
+                        entry.id,
+                        "Peer rejected change as older than its own".to_string(),
+                    );
+                    delivered = true;
+                    break;
+                }
+                // Other client errors won't go away by retrying
+                Ok(status) if (400..500).contains(&status) => {
+                    warn!("Peer refused entry {} with {}", entry.id, status);
+                    break;
+                }
+                Ok(status) => {
+                    warn!(
+                        "Peer answered {} to entry {} (attempt {})",
+                        status, entry.id, attempt
+                    )
+                }
+                Err(e) => warn!(
+                    "Failed to push entry {} (attempt {}): {:#}",
+                    entry.id, attempt, e
+                ),
+            }
+            tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+        }
+
+        let mut status = state.replication.status.lock().unwrap();
+        if delivered {
+            status.pushed += 1;
+        } else {
+            status.push_failures += 1;
+            drop(status);
+            state
+                .replication
+                .report(entry.id, format!("Could not deliver change to {}", peer));
+        }
+    }
+}
+
+/// Minimal HTTP/1.1 POST of a JSON body, returning the response status
+async fn post_json(base: &str, path: &str, token: Option<&str>, body: &str) -> Result<u16> {
+    let rest = base
+        .strip_prefix("http://")
+        .context("Peer URL must start with http://")?;
+    let (authority, prefix) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
+    // A port is present if the last ':' isn't inside an IPv6 literal
+    let has_port = matches!(authority.rsplit_once(':'), Some((_, port)) if !port.contains(']'));
+    let addr = if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut request = format!(
+        "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        prefix,
+        path,
+        authority,
+        body.len()
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(&addr)
+            .await
+            .with_context(|| format!("Failed to connect to {}", addr))?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, anyhow::Error>(response)
+    };
+    let response = tokio::time::timeout(PUSH_TIMEOUT, exchange)
+        .await
+        .context("Peer did not respond in time")??;
+
+    // Status line: HTTP/1.1 200 OK
+    let text = String::from_utf8_lossy(&response);
+    text.split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .context("Malformed HTTP response from peer")
+}
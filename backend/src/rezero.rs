@@ -0,0 +1,400 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, ServoConfig};
+use crate::library::{Library, Preconditions};
+
+/// Stores a re-zero can shift a channel's angles in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Store {
+    /// Saved poses, with their precondition ranges
+    Poses,
+    /// Every step of the saved sequences, with their precondition ranges
+    Sequences,
+    /// The configured home pose
+    Home,
+    /// The servo's soft limits
+    Limits,
+}
+
+/// An angle the offset would take outside the firmware's range
+#[derive(Debug, Clone, Serialize)]
+pub struct OutOfRange {
+    pub store: Store,
+    /// Pose or sequence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+    /// What the angle is, e.g. `angle`, `precondition.min` or `max`
+    pub field: &'static str,
+    pub angle: u16,
+    /// The angle plus the offset
+    pub shifted: i32,
+    /// What it is clamped to
+    pub clamped: u16,
+}
+
+/// The library and config with one channel's angles shifted
+pub struct Rezeroed {
+    pub library: Library,
+    pub config: Config,
+    /// Angles changed, clamped ones included
+    pub shifted: usize,
+    pub out_of_range: Vec<OutOfRange>,
+}
+
+/// Shifts one channel's angles, noting those that leave `0..=max`
+struct Shifter {
+    channel: u8,
+    offset: i32,
+    max: u16,
+    shifted: usize,
+    out_of_range: Vec<OutOfRange>,
+}
+
+impl Shifter {
+    fn shift(
+        &mut self,
+        angle: &mut u16,
+        store: Store,
+        name: Option<&str>,
+        step: Option<usize>,
+        field: &'static str,
+    ) {
+        let shifted = *angle as i32 + self.offset;
+        let clamped = shifted.clamp(0, self.max as i32) as u16;
+        if clamped as i32 != shifted {
+            self.out_of_range.push(OutOfRange {
+                store,
+                name: name.map(str::to_string),
+                step,
+                field,
+                angle: *angle,
+                shifted,
+                clamped,
+            });
+        }
+        if clamped != *angle {
+            self.shifted += 1;
+        }
+        *angle = clamped;
+    }
+
+    fn shift_preconditions(&mut self, preconditions: &mut Preconditions, store: Store, name: &str) {
+        if let Some(range) = preconditions.ranges.get_mut(&self.channel) {
+            self.shift(&mut range.min, store, Some(name), None, "precondition.min");
+            self.shift(&mut range.max, store, Some(name), None, "precondition.max");
+        }
+    }
+}
+
+/// Add `offset` degrees to every angle of `channel` in the selected
+/// stores, as after the servo's horn was refitted
+///
+/// Angles that would leave the firmware's range are clamped to it and
+/// listed; the caller decides whether to keep the result. Poses and steps
+/// that don't reach the channel are left alone.
+pub fn rezero(
+    library: &Library,
+    config: &Config,
+    channel: u8,
+    offset: i32,
+    stores: &[Store],
+) -> Rezeroed {
+    let mut library = library.clone();
+    let mut config = config.clone();
+    let mut shifter = Shifter {
+        channel,
+        offset,
+        max: config.protocol.max_angle,
+        shifted: 0,
+        out_of_range: Vec::new(),
+    };
+    let index = channel as usize;
+
+    if stores.contains(&Store::Poses) {
+        for (name, pose) in library.poses.iter_mut() {
+            if let Some(angle) = pose.angles.get_mut(index) {
+                shifter.shift(angle, Store::Poses, Some(name), None, "angle");
+            }
+            shifter.shift_preconditions(&mut pose.preconditions, Store::Poses, name);
+        }
+    }
+    if stores.contains(&Store::Sequences) {
+        for (name, sequence) in library.sequences.iter_mut() {
+            for (i, step) in sequence.steps.iter_mut().enumerate() {
+                if let Some(angle) = step.angles.get_mut(index) {
+                    shifter.shift(angle, Store::Sequences, Some(name), Some(i), "angle");
+                }
+            }
+            shifter.shift_preconditions(&mut sequence.preconditions, Store::Sequences, name);
+        }
+    }
+    if stores.contains(&Store::Home) {
+        if let Some(angle) = config.home.as_mut().and_then(|home| home.get_mut(index)) {
+            shifter.shift(angle, Store::Home, None, None, "angle");
+        }
+    }
+    if stores.contains(&Store::Limits) {
+        if !config.servos.iter().any(|servo| servo.channel == channel) {
+            config.servos.push(ServoConfig {
+                channel,
+                ..ServoConfig::default()
+            });
+        }
+        let servo = config
+            .servos
+            .iter_mut()
+            .find(|servo| servo.channel == channel)
+            .expect("just added");
+        shifter.shift(&mut servo.min, Store::Limits, None, None, "min");
+        shifter.shift(&mut servo.max, Store::Limits, None, None, "max");
+    }
+
+    Rezeroed {
+        library,
+        config,
+        shifted: shifter.shifted,
+        out_of_range: shifter.out_of_range,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_config, TestServer};
+    use reqwest::Method;
+    use serde_json::{json, Value};
+
+    const ALL: [Store; 4] = [Store::Poses, Store::Sequences, Store::Home, Store::Limits];
+
+    /// Poses and sequences reaching channel 2 or not, with preconditions
+    fn library() -> Library {
+        serde_json::from_value(json!({
+            "poses": {
+                "rest": { "angles": [90, 90, 10, 90] },
+                "reach": {
+                    "angles": [45, 60, 170],
+                    "preconditions": { "ranges": { "2": { "min": 100, "max": 175 } } }
+                },
+                "short": { "angles": [30, 40] }
+            },
+            "sequences": {
+                "wave": {
+                    "steps": [
+                        { "duration_ms": 500, "angles": [90, 90, 90] },
+                        { "duration_ms": 500, "angles": [90, 90, 3] },
+                        { "duration_ms": 500, "angles": [90] }
+                    ],
+                    "preconditions": { "ranges": { "1": { "min": 0, "max": 180 } } }
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn config() -> Config {
+        let mut config = test_config();
+        config.home = Some(vec![90, 90, 120, 90]);
+        config.servos = vec![ServoConfig {
+            channel: 2,
+            min: 20,
+            max: 160,
+            ..ServoConfig::default()
+        }];
+        config
+    }
+
+    fn angles(library: &Library, pose: &str) -> Vec<u16> {
+        library.poses[pose].angles.clone()
+    }
+
+    fn steps(library: &Library, sequence: &str) -> Vec<Vec<u16>> {
+        let steps = &library.sequences[sequence].steps;
+        steps.iter().map(|step| step.angles.clone()).collect()
+    }
+
+    #[test]
+    fn every_store_is_shifted() {
+        let rezeroed = rezero(&library(), &config(), 2, -2, &ALL);
+        assert!(rezeroed.out_of_range.is_empty());
+        assert_eq!(angles(&rezeroed.library, "rest"), [90, 90, 8, 90]);
+        assert_eq!(angles(&rezeroed.library, "reach"), [45, 60, 168]);
+        // Not reaching the channel
+        assert_eq!(angles(&rezeroed.library, "short"), [30, 40]);
+        let range = &rezeroed.library.poses["reach"].preconditions.ranges[&2];
+        assert_eq!((range.min, range.max), (98, 173));
+        assert_eq!(
+            steps(&rezeroed.library, "wave"),
+            [vec![90, 90, 88], vec![90, 90, 1], vec![90]]
+        );
+        // Another channel's range is left alone
+        let range = &rezeroed.library.sequences["wave"].preconditions.ranges[&1];
+        assert_eq!((range.min, range.max), (0, 180));
+        assert_eq!(rezeroed.config.home, Some(vec![90, 90, 118, 90]));
+        let servo = &rezeroed.config.servos[0];
+        assert_eq!((servo.min, servo.max), (18, 158));
+        // 2 pose angles, 2 precondition bounds, 2 steps, home and 2 limits
+        assert_eq!(rezeroed.shifted, 9);
+    }
+
+    #[test]
+    fn only_the_selected_stores_are_shifted() {
+        let (library, config) = (library(), config());
+        let rezeroed = rezero(&library, &config, 2, 5, &[Store::Sequences, Store::Home]);
+        assert_eq!(angles(&rezeroed.library, "rest"), angles(&library, "rest"));
+        let range = &rezeroed.library.poses["reach"].preconditions.ranges[&2];
+        assert_eq!((range.min, range.max), (100, 175));
+        assert_eq!(
+            steps(&rezeroed.library, "wave"),
+            [vec![90, 90, 95], vec![90, 90, 8], vec![90]]
+        );
+        assert_eq!(rezeroed.config.home, Some(vec![90, 90, 125, 90]));
+        assert_eq!(rezeroed.config.servos[0].min, 20);
+        assert_eq!(rezeroed.shifted, 3);
+    }
+
+    #[test]
+    fn angles_out_of_range_are_clamped_and_listed() {
+        let library = library();
+        let rezeroed = rezero(
+            &library,
+            &config(),
+            2,
+            -15,
+            &[Store::Poses, Store::Sequences],
+        );
+        let listed: Vec<Value> = rezeroed
+            .out_of_range
+            .iter()
+            .map(|entry| serde_json::to_value(entry).unwrap())
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                json!({
+                    "store": "poses", "name": "rest", "field": "angle",
+                    "angle": 10, "shifted": -5, "clamped": 0
+                }),
+                json!({
+                    "store": "sequences", "name": "wave", "step": 1, "field": "angle",
+                    "angle": 3, "shifted": -12, "clamped": 0
+                }),
+            ]
+        );
+        assert_eq!(angles(&rezeroed.library, "rest"), [90, 90, 0, 90]);
+        assert_eq!(steps(&rezeroed.library, "wave")[1], [90, 90, 0]);
+        // The library given is left as it was
+        assert_eq!(angles(&library, "rest"), [90, 90, 10, 90]);
+
+        let rezeroed = rezero(&library, &config(), 2, 11, &[Store::Poses]);
+        let fields: Vec<_> = rezeroed
+            .out_of_range
+            .iter()
+            .map(|entry| (entry.name.as_deref(), entry.field, entry.clamped))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (Some("reach"), "angle", 180),
+                (Some("reach"), "precondition.max", 180)
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_of_an_unconfigured_servo_are_added() {
+        let rezeroed = rezero(&library(), &config(), 3, 4, &[Store::Limits]);
+        let servo = rezeroed
+            .config
+            .servos
+            .iter()
+            .find(|s| s.channel == 3)
+            .unwrap();
+        assert_eq!((servo.min, servo.max), (4, 180));
+        assert_eq!(rezeroed.out_of_range.len(), 1);
+        assert_eq!(rezeroed.out_of_range[0].field, "max");
+        assert_eq!(rezeroed.out_of_range[0].shifted, 184);
+    }
+
+    /// Served with [`library`] and [`config`]
+    async fn populated() -> TestServer {
+        let server = TestServer::with_config(|c| *c = config()).await;
+        *server.server.state().library.lock().unwrap() = library();
+        server
+    }
+
+    /// The stores a re-zero touches, as the API reports them
+    fn stores(server: &TestServer) -> Value {
+        let state = server.server.state();
+        let config = state.base_config();
+        json!({
+            "library": *state.library.lock().unwrap(),
+            "home": config.home,
+            "limits": config.servos.iter().map(|s| (s.min, s.max)).collect::<Vec<_>>(),
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refused_rezero_changes_no_store() {
+        let server = populated().await;
+        let before = stores(&server);
+        let body = json!({ "channel": 2, "offset": -15, "apply_to": ALL });
+        let reply = server
+            .admin(Method::POST, "/api/maintenance/rezero", Some(body))
+            .await;
+        assert_eq!(reply.status, 422);
+        assert_eq!(reply.code(), "OUT_OF_RANGE");
+        assert_eq!(
+            reply.body["details"]["out_of_range"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(stores(&server), before);
+        assert!(server.get("/api/audit").await.body["entries"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clamped_rezero_is_undone_exactly() {
+        let server = populated().await;
+        let before = stores(&server);
+        let body = json!({ "channel": 2, "offset": -15, "apply_to": ALL, "clamp": true });
+        let reply = server
+            .admin(Method::POST, "/api/maintenance/rezero", Some(body))
+            .await;
+        assert_eq!(reply.status, 200, "{:?}", reply.body);
+        assert_eq!(reply.body["out_of_range"].as_array().unwrap().len(), 2);
+        let after = stores(&server);
+        assert_eq!(after["home"], json!([90, 90, 105, 90]));
+        assert_eq!(after["limits"], json!([[5, 145]]));
+        assert_eq!(
+            after["library"]["poses"]["rest"]["angles"],
+            json!([90, 90, 0, 90])
+        );
+
+        let entries = server.get("/api/audit").await.body["entries"].clone();
+        assert_eq!(entries[0]["kind"], "rezero");
+        assert_eq!(entries[0]["target"], "channel 2");
+        assert_eq!(entries[0]["id"], reply.body["audit_id"]);
+
+        let path = format!("/api/maintenance/rezero/undo/{}", reply.body["audit_id"]);
+        let undo = server.admin(Method::POST, &path, None).await;
+        assert_eq!(undo.status, 200, "{:?}", undo.body);
+        // The clamped angles come back as they were, not shifted back
+        assert_eq!(stores(&server), before);
+        assert_eq!(
+            server.get("/api/poses/rest").await.body["angles"],
+            json!([90, 90, 10, 90])
+        );
+
+        // Undoing again would overwrite the undo
+        let reply = server.admin(Method::POST, &path, None).await;
+        assert_eq!(reply.code(), "DIVERGED");
+    }
+}
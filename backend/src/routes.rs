@@ -0,0 +1,324 @@
+use axum::handler::Handler;
+use axum::routing::{self, MethodRouter};
+use axum::Router;
+use serde::Serialize;
+
+use crate::tokens::Scope;
+
+/// Credentials a route requires
+///
+/// Without `tokens` configured only the admin-only routes check a token;
+/// with them, every route but replication needs a token with its scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    /// Queries
+    Read,
+    /// Commands moving the arm
+    Motion,
+    /// Changes to the library and settings
+    Config,
+    /// The admin bearer token (`admin_token`), or a token with `admin`
+    Admin,
+    /// The replication token shared with the peer
+    Replication,
+}
+
+impl Auth {
+    /// Scope a token needs for the route; `None` for replication, which
+    /// checks its own token
+    pub fn scope(self) -> Option<Scope> {
+        match self {
+            Auth::Read => Some(Scope::Read),
+            Auth::Motion => Some(Scope::Motion),
+            Auth::Config => Some(Scope::Config),
+            Auth::Admin => Some(Scope::Admin),
+            Auth::Replication => None,
+        }
+    }
+}
+
+/// One registered route, as listed by `GET /api/routes`
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub auth: Auth,
+    /// Also served on the read-only address (`readonly_bind_addr`)
+    pub readonly: bool,
+    pub description: &'static str,
+}
+
+/// A `Router` that records each route added through it
+///
+/// Adding the same path again with another method extends the route, as
+/// `Router::route` does; the router built is the same as without the
+/// wrapper. GET routes are also collected into a second, read-only router.
+pub struct Routes<S> {
+    router: Router<S>,
+    readonly: Router<S>,
+    inventory: Vec<RouteInfo>,
+}
+
+impl<S: Clone + Send + Sync + 'static> Routes<S> {
+    pub fn new() -> Self {
+        Self {
+            router: Router::new(),
+            readonly: Router::new(),
+            inventory: Vec::new(),
+        }
+    }
+
+    pub fn get<H, T>(self, path: &'static str, handler: H, auth: Auth, desc: &'static str) -> Self
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        self.add("GET", path, routing::get(handler), auth, true, desc)
+    }
+
+    /// A GET route that controls the arm, e.g. a WebSocket taking
+    /// commands; left off the read-only router
+    pub fn get_control<H, T>(
+        self,
+        path: &'static str,
+        handler: H,
+        auth: Auth,
+        desc: &'static str,
+    ) -> Self
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        self.add("GET", path, routing::get(handler), auth, false, desc)
+    }
+
+    pub fn post<H, T>(self, path: &'static str, handler: H, auth: Auth, desc: &'static str) -> Self
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        self.add("POST", path, routing::post(handler), auth, false, desc)
+    }
+
+    pub fn put<H, T>(self, path: &'static str, handler: H, auth: Auth, desc: &'static str) -> Self
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        self.add("PUT", path, routing::put(handler), auth, false, desc)
+    }
+
+    pub fn delete<H, T>(
+        self,
+        path: &'static str,
+        handler: H,
+        auth: Auth,
+        desc: &'static str,
+    ) -> Self
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        self.add("DELETE", path, routing::delete(handler), auth, false, desc)
+    }
+
+    fn add(
+        mut self,
+        method: &'static str,
+        path: &'static str,
+        route: MethodRouter<S>,
+        auth: Auth,
+        readonly: bool,
+        description: &'static str,
+    ) -> Self {
+        if readonly {
+            self.readonly = self.readonly.route(path, route.clone());
+        }
+        self.router = self.router.route(path, route);
+        self.inventory.push(RouteInfo {
+            method,
+            path,
+            auth,
+            readonly,
+            description,
+        });
+        self
+    }
+
+    /// Apply a change to both routers, e.g. a layer, keeping the inventory
+    pub fn map(mut self, f: impl Fn(Router<S>) -> Router<S>) -> Self {
+        self.router = f(self.router);
+        self.readonly = f(self.readonly);
+        self
+    }
+
+    /// Combine with routes recorded separately
+    pub fn merge(mut self, other: Routes<S>) -> Self {
+        self.router = self.router.merge(other.router);
+        self.readonly = self.readonly.merge(other.readonly);
+        self.inventory.extend(other.inventory);
+        self
+    }
+
+    /// The full router, the read-only one and the inventory
+    pub fn into_parts(self) -> (Router<S>, Router<S>, Vec<RouteInfo>) {
+        (self.router, self.readonly, self.inventory)
+    }
+}
+
+/// One-line summary of the routes for the startup log
+pub fn summary(routes: &[RouteInfo]) -> String {
+    let count = |auth| routes.iter().filter(|route| route.auth == auth).count();
+    format!(
+        "{} routes ({} admin-only, {} for replication); GET /api/routes lists them",
+        routes.len(),
+        count(Auth::Admin),
+        count(Auth::Replication)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use reqwest::Method;
+    use tower::Service;
+
+    async fn status(router: &Router, method: &str, path: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().call(request).await.unwrap();
+        let allow = response
+            .headers()
+            .get("allow")
+            .map_or("", |allow| allow.to_str().unwrap())
+            .to_string();
+        (response.status(), allow)
+    }
+
+    /// `path` with every parameter filled in
+    fn concrete(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.chars().next() {
+                Some(':') => "1",
+                Some('*') => "x",
+                _ => segment,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[tokio::test]
+    async fn routes_are_recorded_as_added() {
+        let routes = Routes::new()
+            .get("/a", || async { "get" }, Auth::Read, "Read a")
+            .post("/a", || async { "post" }, Auth::Motion, "Move a")
+            .merge(Routes::new().get_control("/ws", || async {}, Auth::Motion, "Control"))
+            .map(|router| router.route("/unlisted", routing::get(|| async {})));
+        let (router, readonly, inventory) = routes.into_parts();
+
+        let listed: Vec<_> = inventory
+            .iter()
+            .map(|route| (route.method, route.path, route.auth, route.readonly))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("GET", "/a", Auth::Read, true),
+                ("POST", "/a", Auth::Motion, false),
+                ("GET", "/ws", Auth::Motion, false),
+            ]
+        );
+
+        assert_eq!(status(&router, "GET", "/a").await.0, StatusCode::OK);
+        assert_eq!(status(&router, "POST", "/a").await.0, StatusCode::OK);
+        assert_eq!(
+            status(&router, "PUT", "/a").await,
+            (StatusCode::METHOD_NOT_ALLOWED, "GET,HEAD,POST".to_string())
+        );
+        assert_eq!(status(&router, "GET", "/ws").await.0, StatusCode::OK);
+        assert_eq!(status(&router, "GET", "/unlisted").await.0, StatusCode::OK);
+
+        assert_eq!(status(&readonly, "GET", "/a").await.0, StatusCode::OK);
+        assert_eq!(
+            status(&readonly, "POST", "/a").await.0,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(&readonly, "GET", "/ws").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn summary_counts_the_restricted_routes() {
+        let route = |auth| RouteInfo {
+            method: "GET",
+            path: "/",
+            auth,
+            readonly: false,
+            description: "",
+        };
+        let routes = [
+            route(Auth::Read),
+            route(Auth::Admin),
+            route(Auth::Admin),
+            route(Auth::Replication),
+        ];
+        assert_eq!(
+            summary(&routes),
+            "4 routes (2 admin-only, 1 for replication); GET /api/routes lists them"
+        );
+        assert_eq!(Auth::Replication.scope(), None);
+        assert_eq!(Auth::Config.scope(), Some(Scope::Config));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_listed_route_resolves() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string();
+        let readonly_addr = addr.clone();
+        let server =
+            TestServer::with_config(|config| config.readonly_bind_addr = Some(readonly_addr)).await;
+        let inventory = server.server.state().routes.get().unwrap().clone();
+        assert!(inventory.len() > 50, "{}", inventory.len());
+        let client = reqwest::Client::new();
+
+        for route in &inventory {
+            let path = concrete(route.path);
+            // A method no route has, so the path is matched without
+            // running a handler (OPTIONS is answered by CORS)
+            let reply = server.admin(Method::PATCH, &path, None).await;
+            assert_eq!(reply.status, 405, "{} {}", route.method, route.path);
+            let allow = reply.headers["allow"].to_str().unwrap();
+            assert!(
+                allow.split(',').any(|method| method == route.method),
+                "{} {} allows {}",
+                route.method,
+                route.path,
+                allow
+            );
+
+            let response = client
+                .request(Method::PATCH, format!("http://{}{}", addr, path))
+                .bearer_auth(crate::testing::ADMIN_TOKEN)
+                .send()
+                .await
+                .unwrap();
+            let allow = response
+                .headers()
+                .get("allow")
+                .map_or("", |allow| allow.to_str().unwrap());
+            let served = allow.split(',').any(|method| method == route.method);
+            assert_eq!(served, route.readonly, "{} {}", route.method, route.path);
+        }
+    }
+}
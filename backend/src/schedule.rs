@@ -0,0 +1,224 @@
+use axum::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::handlers::{self, AppState};
+use crate::models::PoseAngles;
+
+/// Finished scheduled moves kept for `GET /api/schedule`
+const MAX_FINISHED: usize = 20;
+
+/// Most moves waiting at once
+pub const MAX_PENDING: usize = 100;
+
+/// Furthest a move can be scheduled ahead
+pub const MAX_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl ScheduleStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            ScheduleStatus::Pending => "pending",
+            ScheduleStatus::Running => "running",
+            ScheduleStatus::Completed => "completed",
+            ScheduleStatus::Failed => "failed",
+            ScheduleStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// A MOVE scheduled for a future time
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledMove {
+    pub id: u64,
+    pub status: ScheduleStatus,
+    /// Time the move is due, in ms since the epoch
+    pub at_ms: u64,
+    pub duration_ms: u16,
+    pub angles: PoseAngles,
+    pub scheduled_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_ms: Option<u64>,
+    /// Locked-out channels held at their position
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Moves waiting for their time and recently finished ones
+///
+/// Each pending move has a timer task. When it fires, the move is checked
+/// and run like `POST /api/move` at that moment: a standby or a link loss
+/// latch refuses it, and locked-out channels are held.
+#[derive(Default)]
+pub struct Schedule {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    moves: BTreeMap<u64, ScheduledMove>,
+    timers: BTreeMap<u64, CancellationToken>,
+}
+
+/// Why a scheduled move couldn't be cancelled
+pub enum CancelError {
+    NotFound,
+    /// Already running or finished
+    NotPending(ScheduleStatus),
+}
+
+impl Schedule {
+    /// Schedule a move (already validated) for `at_ms`; `None` if
+    /// [`MAX_PENDING`] moves are waiting
+    pub fn add(
+        &self,
+        state: Arc<AppState>,
+        at_ms: u64,
+        duration_ms: u16,
+        angles: PoseAngles,
+    ) -> Option<ScheduledMove> {
+        let now_ms = state.clock.now_ms();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.timers.len() >= MAX_PENDING {
+            return None;
+        }
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let record = ScheduledMove {
+            id,
+            status: ScheduleStatus::Pending,
+            at_ms,
+            duration_ms,
+            angles,
+            scheduled_ms: now_ms,
+            finished_ms: None,
+            skipped: Vec::new(),
+            error: None,
+        };
+        inner.moves.insert(id, record.clone());
+        let token = CancellationToken::new();
+        inner.timers.insert(id, token.clone());
+        drop(inner);
+
+        let delay = Duration::from_millis(at_ms.saturating_sub(now_ms));
+        info!("Scheduled move {} in {}ms", id, delay.as_millis());
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = state.clock.sleep(delay) => {}
+                _ = token.cancelled() => return,
+            }
+            let Some((duration_ms, angles)) = state.schedule.begin(id) else {
+                return;
+            };
+            let result = {
+                let state = state.clone();
+                tokio::task::spawn_blocking(move || {
+                    handlers::run_scheduled_move(&state, duration_ms, &angles)
+                })
+                .await
+            };
+            let result = match result {
+                Ok(result) => result.map_err(|(_, Json(e))| e.error),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = &result {
+                warn!("Scheduled move {} failed: {}", id, e);
+            }
+            state.schedule.finish(id, result, state.clock.now_ms());
+        });
+        Some(record)
+    }
+
+    /// Mark a due move running; `None` if it was cancelled meanwhile
+    fn begin(&self, id: u64) -> Option<(u16, PoseAngles)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.timers.remove(&id)?;
+        let record = inner.moves.get_mut(&id)?;
+        record.status = ScheduleStatus::Running;
+        info!("Running scheduled move {}", id);
+        Some((record.duration_ms, record.angles.clone()))
+    }
+
+    fn finish(&self, id: u64, result: Result<Vec<u8>, String>, now_ms: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(record) = inner.moves.get_mut(&id) {
+            record.finished_ms = Some(now_ms);
+            match result {
+                Ok(skipped) => {
+                    record.status = ScheduleStatus::Completed;
+                    record.skipped = skipped;
+                }
+                Err(e) => {
+                    record.status = ScheduleStatus::Failed;
+                    record.error = Some(e);
+                }
+            }
+        }
+        inner.prune();
+    }
+
+    /// Cancel a pending move
+    pub fn cancel(&self, id: u64, now_ms: u64) -> Result<ScheduledMove, CancelError> {
+        let mut inner = self.inner.lock().unwrap();
+        let status = inner.moves.get(&id).ok_or(CancelError::NotFound)?.status;
+        let Some(token) = inner.timers.remove(&id) else {
+            return Err(CancelError::NotPending(status));
+        };
+        token.cancel();
+        let record = inner.moves.get_mut(&id).ok_or(CancelError::NotFound)?;
+        record.status = ScheduleStatus::Cancelled;
+        record.finished_ms = Some(now_ms);
+        let record = record.clone();
+        inner.prune();
+        info!("Cancelled scheduled move {}", id);
+        Ok(record)
+    }
+
+    /// Pending and running moves by due time, then finished ones, most
+    /// recent first
+    pub fn list(&self) -> (Vec<ScheduledMove>, Vec<ScheduledMove>) {
+        let inner = self.inner.lock().unwrap();
+        let (mut pending, mut finished): (Vec<_>, Vec<_>) =
+            inner.moves.values().cloned().partition(|record| {
+                matches!(
+                    record.status,
+                    ScheduleStatus::Pending | ScheduleStatus::Running
+                )
+            });
+        pending.sort_by_key(|record| (record.at_ms, record.id));
+        finished.sort_by_key(|record| std::cmp::Reverse(record.finished_ms));
+        (pending, finished)
+    }
+}
+
+impl Inner {
+    /// Drop the oldest finished moves beyond [`MAX_FINISHED`]
+    fn prune(&mut self) {
+        let mut finished: Vec<(u64, u64)> = self
+            .moves
+            .values()
+            .filter_map(|record| record.finished_ms.map(|at| (at, record.id)))
+            .collect();
+        finished.sort_unstable();
+        let excess = finished.len().saturating_sub(MAX_FINISHED);
+        for (_, id) in &finished[..excess] {
+            self.moves.remove(id);
+        }
+    }
+}
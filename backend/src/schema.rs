@@ -0,0 +1,371 @@
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::serial::NUM_SERVOS;
+
+/// Largest pulse width the firmware accepts with `P<n>:<us>`, one full
+/// PWM period
+pub const MAX_PULSE_US: u32 = 20000;
+
+/// Highest trajectory sample rate; the firmware interpolates MOVEs in 20ms
+/// steps, so more frames don't make the motion smoother
+const MAX_SAMPLE_RATE_HZ: u32 = 50;
+
+/// A numeric command parameter with a checked range
+///
+/// Every range check on request values goes through [`Param::check`], and
+/// `GET /api/schema` describes the same ranges, so the two can't drift.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Param {
+    /// Servo angle of single-servo, POSE and MOVE commands
+    Angle,
+    /// PWM pulse width of `P<n>:<us>`
+    PulseWidth,
+    /// Duration of a MOVE
+    MoveDuration,
+    /// Time between two trajectory waypoints
+    WaypointDuration,
+    /// Frames per second of a planned trajectory
+    SampleRate,
+}
+
+impl Param {
+    pub const ALL: [Param; 5] = [
+        Param::Angle,
+        Param::PulseWidth,
+        Param::MoveDuration,
+        Param::WaypointDuration,
+        Param::SampleRate,
+    ];
+
+    /// Request field carrying the parameter
+    pub fn name(self) -> &'static str {
+        match self {
+            Param::Angle => "angle",
+            Param::PulseWidth => "pulse_us",
+            Param::MoveDuration => "duration_ms",
+            Param::WaypointDuration => "waypoint_duration_ms",
+            Param::SampleRate => "sample_rate_hz",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Param::Angle => "Angle",
+            Param::PulseWidth => "Pulse width",
+            Param::MoveDuration => "MOVE duration",
+            Param::WaypointDuration => "Waypoint duration",
+            Param::SampleRate => "Sample rate",
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Param::Angle => "deg",
+            Param::PulseWidth => "us",
+            Param::MoveDuration | Param::WaypointDuration => "ms",
+            Param::SampleRate => "Hz",
+        }
+    }
+
+    /// Range the firmware and protocol accept
+    pub fn range(self, config: &Config) -> Range {
+        match self {
+            Param::Angle => Range {
+                min: 0,
+                max: config.protocol.max_angle as u32,
+            },
+            Param::PulseWidth => Range {
+                min: 0,
+                max: MAX_PULSE_US,
+            },
+            Param::MoveDuration => Range {
+                min: 0,
+                max: config.max_move_duration_ms,
+            },
+            Param::WaypointDuration => Range {
+                min: 1,
+                max: u16::MAX as u32,
+            },
+            Param::SampleRate => Range {
+                min: 1,
+                max: MAX_SAMPLE_RATE_HZ,
+            },
+        }
+    }
+
+    /// Range for one servo, with its configured soft limits applied; the
+    /// same as [`range`](Self::range) for parameters without overrides
+    pub fn servo_range(self, config: &Config, channel: u8) -> Range {
+        let range = self.range(config);
+        match self {
+            Param::Angle => {
+                let servo = config.servo(channel);
+                Range {
+                    min: range.min.max(servo.min as u32),
+                    max: range.max.min(servo.max as u32),
+                }
+            }
+            _ => range,
+        }
+    }
+
+    fn has_servo_overrides(self) -> bool {
+        self == Param::Angle
+    }
+
+    /// Whether [`range`](Self::range) is set by the firmware rather than
+    /// by the backend; longer MOVEs than the firmware takes are split
+    fn firmware_limited(self) -> bool {
+        matches!(self, Param::Angle | Param::PulseWidth)
+    }
+
+    /// Check a value, for a specific servo if given
+    ///
+    /// Values outside [`range`](Self::range) fail with `FIRMWARE_RANGE`
+    /// (`OUT_OF_RANGE` for backend-only parameters); values outside a
+    /// servo's soft limits with `SOFT_LIMIT`.
+    pub fn check(self, config: &Config, channel: Option<u8>, value: u32) -> Result<(), RangeError> {
+        let servo = channel
+            .map(|c| format!(" for servo {}", c))
+            .unwrap_or_default();
+        let range = self.range(config);
+        if !range.contains(value) {
+            let (code, what) = if self.firmware_limited() {
+                ("FIRMWARE_RANGE", "beyond the firmware range")
+            } else {
+                ("OUT_OF_RANGE", "outside the allowed range")
+            };
+            return Err(RangeError {
+                code,
+                message: format!(
+                    "{} {}{} is {} {}-{}",
+                    self.label(),
+                    value,
+                    servo,
+                    what,
+                    range.min,
+                    range.max
+                ),
+            });
+        }
+
+        if let Some(channel) = channel.filter(|_| self.has_servo_overrides()) {
+            let limits = self.servo_range(config, channel);
+            if !limits.contains(value) {
+                return Err(RangeError {
+                    code: "SOFT_LIMIT",
+                    message: format!(
+                        "{} {}{} is outside its configured limits {}-{}",
+                        self.label(),
+                        value,
+                        servo,
+                        limits.min,
+                        limits.max
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe the parameter for `GET /api/schema`
+    pub fn describe(self, config: &Config) -> ParamSchema {
+        let range = self.range(config);
+        let servos = if self.has_servo_overrides() {
+            (0..NUM_SERVOS)
+                .filter(|&channel| config.kind(channel).is_servo())
+                .map(|channel| {
+                    let limits = self.servo_range(config, channel);
+                    ServoRange {
+                        channel,
+                        name: config.servo(channel).name,
+                        min: limits.min,
+                        max: limits.max,
+                    }
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        ParamSchema {
+            name: self.name(),
+            unit: self.unit(),
+            min: range.min,
+            max: range.max,
+            step: 1,
+            servos,
+            firmware: self.firmware_limited(),
+        }
+    }
+}
+
+/// Inclusive range of accepted values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Range {
+    pub fn contains(self, value: u32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+/// A value outside its range, with an error code for the API
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Description of one parameter
+#[derive(Debug, Serialize)]
+pub struct ParamSchema {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub min: u32,
+    pub max: u32,
+    pub step: u32,
+    /// The range is the firmware's, not a backend policy
+    pub firmware: bool,
+    /// Effective range per servo, for parameters with per-servo limits
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub servos: Vec<ServoRange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServoRange {
+    pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Describe every parameter
+pub fn describe(config: &Config) -> Vec<ParamSchema> {
+    Param::ALL
+        .iter()
+        .map(|param| param.describe(config))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ChannelKind, ServoConfig};
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.protocol.max_angle = 270;
+        config.max_move_duration_ms = 120_000;
+        config.servos = vec![
+            ServoConfig {
+                channel: 1,
+                min: 30,
+                max: 150,
+                ..ServoConfig::default()
+            },
+            // Limits wider than the firmware are cut to it
+            ServoConfig {
+                channel: 2,
+                min: 10,
+                max: 300,
+                ..ServoConfig::default()
+            },
+            ServoConfig {
+                channel: 5,
+                kind: ChannelKind::PwmOutput,
+                ..ServoConfig::default()
+            },
+        ];
+        config
+    }
+
+    /// Values around every bound the schema names
+    fn samples(schema: &ParamSchema) -> Vec<u32> {
+        let mut bounds = vec![schema.min, schema.max];
+        for servo in &schema.servos {
+            bounds.extend([servo.min, servo.max]);
+        }
+        bounds
+            .into_iter()
+            .flat_map(|bound| [bound.saturating_sub(1), bound, bound + 1])
+            .chain([0, u16::MAX as u32, u32::MAX])
+            .collect()
+    }
+
+    #[test]
+    fn validator_accepts_exactly_what_the_schema_describes() {
+        let config = config();
+        for schema in describe(&config) {
+            let param = *Param::ALL.iter().find(|p| p.name() == schema.name).unwrap();
+            for value in samples(&schema) {
+                let described = (schema.min..=schema.max).contains(&value);
+                assert_eq!(
+                    param.check(&config, None, value).is_ok(),
+                    described,
+                    "{} {}",
+                    schema.name,
+                    value
+                );
+                for servo in &schema.servos {
+                    let described = (servo.min..=servo.max).contains(&value);
+                    let checked = param.check(&config, Some(servo.channel), value);
+                    assert_eq!(
+                        checked.is_ok(),
+                        described,
+                        "{} {} for servo {}",
+                        schema.name,
+                        value,
+                        servo.channel
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn servo_limits_are_layered_on_the_firmware_range() {
+        let config = config();
+        let angle = Param::Angle.describe(&config);
+        assert_eq!((angle.min, angle.max), (0, 270));
+        let servos: Vec<_> = angle
+            .servos
+            .iter()
+            .map(|s| (s.channel, s.min, s.max))
+            .collect();
+        // Channel 5 is no servo
+        assert_eq!(
+            servos,
+            [
+                (0, 0, 180),
+                (1, 30, 150),
+                (2, 10, 270),
+                (3, 0, 180),
+                (4, 0, 180)
+            ]
+        );
+        assert!(Param::PulseWidth.describe(&config).servos.is_empty());
+    }
+
+    #[test]
+    fn errors_tell_the_firmware_from_the_limits() {
+        let config = config();
+        let code =
+            |param: Param, channel, value| param.check(&config, channel, value).unwrap_err().code;
+        assert_eq!(code(Param::Angle, Some(1), 271), "FIRMWARE_RANGE");
+        assert_eq!(code(Param::Angle, Some(1), 160), "SOFT_LIMIT");
+        assert_eq!(code(Param::PulseWidth, None, 20001), "FIRMWARE_RANGE");
+        assert_eq!(code(Param::SampleRate, None, 0), "OUT_OF_RANGE");
+        assert_eq!(
+            Param::Angle
+                .check(&config, Some(1), 160)
+                .unwrap_err()
+                .message,
+            "Angle 160 for servo 1 is outside its configured limits 30-150"
+        );
+    }
+}
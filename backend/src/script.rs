@@ -0,0 +1,1292 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Statements a script may execute, loop iterations included
+pub const MAX_STEPS: u32 = 10_000;
+
+/// Time a script may run, motion and sleeps included
+pub const MAX_RUNTIME: Duration = Duration::from_secs(300);
+
+/// Iterations of a single `repeat`
+const MAX_REPEAT: i64 = 1000;
+
+/// Longest single `sleep`
+const MAX_SLEEP_MS: i64 = 60_000;
+
+/// Nesting depth of blocks and expressions
+const MAX_DEPTH: usize = 32;
+
+/// A parse or runtime error, with the line it occurred on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn fail(line: usize, message: impl Into<String>) -> ScriptError {
+    ScriptError {
+        line,
+        message: message.into(),
+    }
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, ScriptError> {
+    Err(fail(line, message))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    Sym(&'static str),
+    /// End of a statement: a newline or `;`
+    End,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Int(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "\"{}\"", s),
+            Token::Ident(s) => write!(f, "{}", s),
+            Token::Sym(s) => write!(f, "'{}'", s),
+            Token::End => write!(f, "end of line"),
+            Token::Eof => write!(f, "end of script"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 19] = [
+    "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "=", "(", ")", "[", "]", "{", "}",
+    ",",
+];
+
+/// Split the source into tokens with their line numbers; newlines inside
+/// parentheses and brackets don't end a statement
+fn lex(source: &str) -> Result<Vec<(Token, usize)>, ScriptError> {
+    let mut tokens = Vec::new();
+    let mut depth = 0usize;
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut rest = text;
+        loop {
+            rest = rest.trim_start();
+            let c = match rest.chars().next() {
+                Some('#') | None => break,
+                Some(c) => c,
+            };
+            if c == ';' {
+                tokens.push((Token::End, line));
+                rest = &rest[1..];
+            } else if c.is_ascii_digit() {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let value = match rest[..end].parse() {
+                    Ok(value) => value,
+                    Err(_) => return error(line, format!("Number too large: {}", &rest[..end])),
+                };
+                tokens.push((Token::Int(value), line));
+                rest = &rest[end..];
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                tokens.push((Token::Ident(rest[..end].to_string()), line));
+                rest = &rest[end..];
+            } else if c == '"' {
+                let end = match rest[1..].find('"') {
+                    Some(end) => end + 1,
+                    None => return error(line, "Unterminated string"),
+                };
+                tokens.push((Token::Str(rest[1..end].to_string()), line));
+                rest = &rest[end + 1..];
+            } else {
+                let symbol = match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                    Some(symbol) => *symbol,
+                    None => return error(line, format!("Unexpected character '{}'", c)),
+                };
+                match symbol {
+                    "(" | "[" => depth += 1,
+                    ")" | "]" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                tokens.push((Token::Sym(symbol), line));
+                rest = &rest[symbol.len()..];
+            }
+        }
+        if depth == 0 {
+            tokens.push((Token::End, line));
+        }
+    }
+    let last = source.lines().count().max(1);
+    tokens.push((Token::Eof, last));
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Int(i64),
+    Bool(bool),
+    Var(String),
+    /// Cached position of a servo by index
+    Servo(Box<Expr>),
+    /// Cached position of a servo by name
+    NamedServo(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Stmt {
+    Let(String, Expr),
+    If(Expr, Vec<Line>, Vec<Line>),
+    Repeat(Expr, Vec<Line>),
+    While(Expr, Vec<Line>),
+    Pose(Vec<Expr>),
+    SavedPose(String),
+    Move(Expr, Vec<Expr>),
+    Sequence(String),
+    Sleep(Expr),
+    Home,
+}
+
+/// A statement and the line it starts on
+#[derive(Debug, Clone, PartialEq)]
+struct Line {
+    line: usize,
+    stmt: Stmt,
+}
+
+/// A parsed script
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    body: Vec<Line>,
+    source: Vec<String>,
+}
+
+/// Parse a script
+///
+/// ```text
+/// # Retract first if the elbow is raised
+/// if servo("elbow") > 90 {
+///     sequence "retract"
+/// }
+/// let n = 0
+/// repeat 3 {
+///     move 500 [90, 45 + n * 10, 120]
+///     sleep 200
+///     let n = n + 1
+/// }
+/// pose "rest"
+/// ```
+pub fn parse(source: &str) -> Result<Script, ScriptError> {
+    let mut parser = Parser {
+        tokens: lex(source)?,
+        pos: 0,
+        depth: 0,
+    };
+    let body = parser.block_body(false)?;
+    Ok(Script {
+        body,
+        source: source.lines().map(|l| l.trim().to_string()).collect(),
+    })
+}
+
+/// A single expression that must be true, e.g. `shoulder + elbow < 270`
+///
+/// It has no variables; names, like `servo("name")`, stand for the angle
+/// of the servo they name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    expr: Expr,
+}
+
+/// Parse a condition
+pub fn parse_condition(source: &str) -> Result<Condition, ScriptError> {
+    let mut parser = Parser {
+        tokens: lex(source)?,
+        pos: 0,
+        depth: 0,
+    };
+    parser.skip_ends();
+    let expr = parser.expr()?;
+    parser.skip_ends();
+    if *parser.peek() != Token::Eof {
+        return error(parser.line(), format!("Unexpected {}", parser.peek()));
+    }
+    let boolean = match &expr {
+        Expr::Bool(_) | Expr::Not(_) => true,
+        Expr::Binary(op, _, _) => !matches!(
+            op,
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem
+        ),
+        _ => false,
+    };
+    if !boolean {
+        return error(1, "Expected a condition, found a number");
+    }
+    Ok(Condition { expr })
+}
+
+impl Condition {
+    /// Whether the angles in `arm` meet the condition
+    pub fn holds(&self, arm: &dyn Positions) -> Result<bool, ScriptError> {
+        let evaluator = Evaluator { vars: None, arm };
+        evaluator.bool(1, &self.expr)
+    }
+
+    /// Servo names the condition reads
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        let mut pending = vec![&self.expr];
+        while let Some(expr) = pending.pop() {
+            match expr {
+                Expr::Var(name) | Expr::NamedServo(name) => names.push(name.as_str()),
+                Expr::Servo(operand) | Expr::Neg(operand) | Expr::Not(operand) => {
+                    pending.push(operand)
+                }
+                Expr::Binary(_, left, right) => pending.extend([&**left, &**right]),
+                Expr::Int(_) | Expr::Bool(_) => {}
+            }
+        }
+        names
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn is_sym(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Token::Sym(s) if *s == symbol)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(s) if s == keyword)
+    }
+
+    fn expect_sym(&mut self, symbol: &str) -> Result<(), ScriptError> {
+        if self.is_sym(symbol) {
+            self.next();
+            Ok(())
+        } else {
+            error(
+                self.line(),
+                format!("Expected '{}', found {}", symbol, self.peek()),
+            )
+        }
+    }
+
+    fn skip_ends(&mut self) {
+        while *self.peek() == Token::End {
+            self.next();
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), ScriptError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return error(self.line(), "Nesting too deep");
+        }
+        Ok(())
+    }
+
+    /// Statements up to the end of the script, or up to the closing brace
+    /// of a block
+    fn block_body(&mut self, braced: bool) -> Result<Vec<Line>, ScriptError> {
+        let mut body = Vec::new();
+        loop {
+            self.skip_ends();
+            match self.peek() {
+                Token::Eof if braced => return error(self.line(), "Missing '}'"),
+                Token::Eof => return Ok(body),
+                Token::Sym("}") if braced => {
+                    self.next();
+                    return Ok(body);
+                }
+                _ => body.push(self.statement()?),
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Line>, ScriptError> {
+        self.enter()?;
+        self.expect_sym("{")?;
+        let body = self.block_body(true)?;
+        self.depth -= 1;
+        Ok(body)
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, ScriptError> {
+        match self.next() {
+            Token::Str(s) => Ok(s),
+            other => error(
+                self.line(),
+                format!("Expected {} name in quotes, found {}", what, other),
+            ),
+        }
+    }
+
+    fn angles(&mut self) -> Result<Vec<Expr>, ScriptError> {
+        self.expect_sym("[")?;
+        let mut angles = vec![self.expr()?];
+        while self.is_sym(",") {
+            self.next();
+            angles.push(self.expr()?);
+        }
+        self.expect_sym("]")?;
+        Ok(angles)
+    }
+
+    fn statement(&mut self) -> Result<Line, ScriptError> {
+        let line = self.line();
+        let keyword = match self.next() {
+            Token::Ident(keyword) => keyword,
+            other => return error(line, format!("Expected a statement, found {}", other)),
+        };
+        let stmt = match keyword.as_str() {
+            "let" => {
+                let name = match self.next() {
+                    Token::Ident(name) if !is_reserved(&name) => name,
+                    other => {
+                        return error(line, format!("Expected a variable name, found {}", other))
+                    }
+                };
+                self.expect_sym("=")?;
+                Stmt::Let(name, self.expr()?)
+            }
+            "if" => self.if_statement()?,
+            "repeat" => Stmt::Repeat(self.expr()?, self.block()?),
+            "while" => Stmt::While(self.expr()?, self.block()?),
+            "pose" if self.is_sym("[") => Stmt::Pose(self.angles()?),
+            "pose" => Stmt::SavedPose(self.string("pose")?),
+            "move" => Stmt::Move(self.expr()?, self.angles()?),
+            "sequence" => Stmt::Sequence(self.string("sequence")?),
+            "sleep" => Stmt::Sleep(self.expr()?),
+            "home" => Stmt::Home,
+            _ => return error(line, format!("Unknown statement '{}'", keyword)),
+        };
+
+        // Blocks end at their brace; everything else at the end of the line
+        match self.peek() {
+            Token::End | Token::Eof | Token::Sym("}") => {}
+            other => return error(self.line(), format!("Unexpected {}", other)),
+        }
+        Ok(Line { line, stmt })
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, ScriptError> {
+        let cond = self.expr()?;
+        let then = self.block()?;
+        // `else` may follow on the next line
+        let after_then = self.pos;
+        self.skip_ends();
+        let otherwise = if self.is_keyword("else") {
+            self.next();
+            if self.is_keyword("if") {
+                let line = self.line();
+                self.next();
+                self.enter()?;
+                let stmt = self.if_statement()?;
+                self.depth -= 1;
+                vec![Line { line, stmt }]
+            } else {
+                self.block()?
+            }
+        } else {
+            self.pos = after_then;
+            Vec::new()
+        };
+        Ok(Stmt::If(cond, then, otherwise))
+    }
+
+    fn expr(&mut self) -> Result<Expr, ScriptError> {
+        self.enter()?;
+        let expr = self.or();
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<Expr, ScriptError> {
+        let mut left = self.and()?;
+        while self.is_keyword("or") {
+            self.next();
+            left = Expr::Binary(BinOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ScriptError> {
+        let mut left = self.not()?;
+        while self.is_keyword("and") {
+            self.next();
+            left = Expr::Binary(BinOp::And, Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ScriptError> {
+        if self.is_keyword("not") {
+            self.next();
+            self.enter()?;
+            let operand = self.not()?;
+            self.depth -= 1;
+            return Ok(Expr::Not(Box::new(operand)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ScriptError> {
+        let left = self.sum()?;
+        let op = match self.peek() {
+            Token::Sym("==") => BinOp::Eq,
+            Token::Sym("!=") => BinOp::Ne,
+            Token::Sym("<") => BinOp::Lt,
+            Token::Sym("<=") => BinOp::Le,
+            Token::Sym(">") => BinOp::Gt,
+            Token::Sym(">=") => BinOp::Ge,
+            _ => return Ok(left),
+        };
+        self.next();
+        Ok(Expr::Binary(op, Box::new(left), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, ScriptError> {
+        let mut left = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Token::Sym("+") => BinOp::Add,
+                Token::Sym("-") => BinOp::Sub,
+                _ => return Ok(left),
+            };
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, ScriptError> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Token::Sym("*") => BinOp::Mul,
+                Token::Sym("/") => BinOp::Div,
+                Token::Sym("%") => BinOp::Rem,
+                _ => return Ok(left),
+            };
+            self.next();
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ScriptError> {
+        if self.is_sym("-") {
+            self.next();
+            self.enter()?;
+            let operand = self.unary()?;
+            self.depth -= 1;
+            return Ok(Expr::Neg(Box::new(operand)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ScriptError> {
+        let line = self.line();
+        match self.next() {
+            Token::Int(n) => Ok(Expr::Int(n)),
+            Token::Sym("(") => {
+                let expr = self.expr()?;
+                self.expect_sym(")")?;
+                Ok(expr)
+            }
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Expr::Bool(true)),
+                "false" => Ok(Expr::Bool(false)),
+                "servo" => {
+                    self.expect_sym("(")?;
+                    let servo = match self.peek() {
+                        Token::Str(_) => Expr::NamedServo(self.string("servo")?),
+                        _ => Expr::Servo(Box::new(self.expr()?)),
+                    };
+                    self.expect_sym(")")?;
+                    Ok(servo)
+                }
+                _ if is_reserved(&name) => error(line, format!("Unexpected '{}'", name)),
+                _ => Ok(Expr::Var(name)),
+            },
+            other => error(line, format!("Expected a value, found {}", other)),
+        }
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    matches!(
+        name,
+        "let"
+            | "if"
+            | "else"
+            | "repeat"
+            | "while"
+            | "pose"
+            | "move"
+            | "sequence"
+            | "sleep"
+            | "home"
+            | "and"
+            | "or"
+            | "not"
+            | "true"
+            | "false"
+            | "servo"
+    )
+}
+
+/// Servo angles as an expression reads them
+pub trait Positions {
+    /// Angle of a servo, `None` if unknown
+    fn position(&self, channel: u8) -> Option<u16>;
+    fn channel_by_name(&self, name: &str) -> Option<u8>;
+}
+
+/// The arm as seen by a running script, whose expressions read the
+/// cached positions
+pub trait Host: Positions {
+    fn pose(&mut self, angles: &[u16]) -> Result<(), String>;
+    fn move_to(&mut self, duration_ms: u16, angles: &[u16]) -> Result<(), String>;
+    fn saved_pose(&mut self, name: &str) -> Result<(), String>;
+    fn sequence(&mut self, name: &str) -> Result<(), String>;
+    fn home(&mut self) -> Result<(), String>;
+    /// Wait, returning early with `false` if the script is cancelled
+    fn sleep(&mut self, duration: Duration) -> bool;
+    fn cancelled(&self) -> bool;
+    /// Record an executed statement
+    fn trace(&mut self, entry: TraceEntry);
+}
+
+/// An executed statement
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceEntry {
+    pub line: usize,
+    /// Source line of the statement
+    pub source: String,
+    /// Time since the script started
+    pub elapsed_ms: u64,
+    /// Outcome of a condition or the iteration of a loop
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// Why a script stopped before its end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Halt {
+    Cancelled { line: usize },
+    Failed(ScriptError),
+}
+
+impl From<ScriptError> for Halt {
+    fn from(error: ScriptError) -> Self {
+        Halt::Failed(error)
+    }
+}
+
+/// Bounds on a script run
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_steps: u32,
+    pub max_runtime: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_steps: MAX_STEPS,
+            max_runtime: MAX_RUNTIME,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Int(i64),
+    Bool(bool),
+}
+
+/// Run a script against the host
+pub fn run(script: &Script, host: &mut impl Host, limits: Limits) -> Result<(), Halt> {
+    let mut interpreter = Interpreter {
+        host,
+        source: &script.source,
+        vars: HashMap::new(),
+        steps: 0,
+        started: Instant::now(),
+        limits,
+    };
+    interpreter.block(&script.body)
+}
+
+struct Interpreter<'a, H: Host> {
+    host: &'a mut H,
+    source: &'a [String],
+    vars: HashMap<String, Value>,
+    steps: u32,
+    started: Instant,
+    limits: Limits,
+}
+
+impl<H: Host> Interpreter<'_, H> {
+    /// Count a step against the limits and trace it
+    fn step(&mut self, line: usize, note: Option<String>) -> Result<(), Halt> {
+        if self.host.cancelled() {
+            return Err(Halt::Cancelled { line });
+        }
+        self.steps += 1;
+        if self.steps > self.limits.max_steps {
+            let message = format!("Step limit of {} exceeded", self.limits.max_steps);
+            return Err(fail(line, message).into());
+        }
+        let elapsed = self.started.elapsed();
+        if elapsed > self.limits.max_runtime {
+            let message = format!(
+                "Runtime limit of {}s exceeded",
+                self.limits.max_runtime.as_secs()
+            );
+            return Err(fail(line, message).into());
+        }
+        self.host.trace(TraceEntry {
+            line,
+            source: self.source.get(line - 1).cloned().unwrap_or_default(),
+            elapsed_ms: elapsed.as_millis() as u64,
+            note,
+        });
+        Ok(())
+    }
+
+    fn block(&mut self, body: &[Line]) -> Result<(), Halt> {
+        for line in body {
+            self.statement(line)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, Line { line, stmt }: &Line) -> Result<(), Halt> {
+        let line = *line;
+        match stmt {
+            Stmt::If(cond, then, otherwise) => {
+                let taken = self.evaluator().bool(line, cond)?;
+                self.step(line, Some(taken.to_string()))?;
+                self.block(if taken { then } else { otherwise })
+            }
+            Stmt::Repeat(count, body) => {
+                let count = self.evaluator().int(line, count)?;
+                if !(0..=MAX_REPEAT).contains(&count) {
+                    let message = format!("Repeat count {} is outside 0-{}", count, MAX_REPEAT);
+                    return Err(fail(line, message).into());
+                }
+                for i in 1..=count {
+                    self.step(line, Some(format!("iteration {} of {}", i, count)))?;
+                    self.block(body)?;
+                }
+                Ok(())
+            }
+            Stmt::While(cond, body) => {
+                let mut iteration = 0;
+                while self.evaluator().bool(line, cond)? {
+                    iteration += 1;
+                    self.step(line, Some(format!("iteration {}", iteration)))?;
+                    self.block(body)?;
+                }
+                self.step(line, Some("done".to_string()))
+            }
+            Stmt::Let(name, expr) => {
+                let value = self.evaluator().eval(line, expr)?;
+                self.step(line, None)?;
+                self.vars.insert(name.clone(), value);
+                Ok(())
+            }
+            Stmt::Pose(angles) => {
+                let angles = self.angles(line, angles)?;
+                self.step(line, None)?;
+                self.host.pose(&angles).map_err(|e| fail(line, e))?;
+                Ok(())
+            }
+            Stmt::Move(duration, angles) => {
+                let duration = self.evaluator().int(line, duration)?;
+                let duration = match u16::try_from(duration) {
+                    Ok(duration) => duration,
+                    Err(_) => {
+                        return Err(fail(line, format!("Invalid MOVE duration {}", duration)).into())
+                    }
+                };
+                let angles = self.angles(line, angles)?;
+                self.step(line, None)?;
+                self.host
+                    .move_to(duration, &angles)
+                    .map_err(|e| fail(line, e))?;
+                Ok(())
+            }
+            Stmt::SavedPose(name) => {
+                self.step(line, None)?;
+                self.host.saved_pose(name).map_err(|e| fail(line, e))?;
+                Ok(())
+            }
+            Stmt::Sequence(name) => {
+                self.step(line, None)?;
+                self.host.sequence(name).map_err(|e| fail(line, e))?;
+                Ok(())
+            }
+            Stmt::Home => {
+                self.step(line, None)?;
+                self.host.home().map_err(|e| fail(line, e))?;
+                Ok(())
+            }
+            Stmt::Sleep(ms) => {
+                let ms = self.evaluator().int(line, ms)?;
+                if !(0..=MAX_SLEEP_MS).contains(&ms) {
+                    let message = format!("Sleep of {}ms is outside 0-{}", ms, MAX_SLEEP_MS);
+                    return Err(fail(line, message).into());
+                }
+                let duration = Duration::from_millis(ms as u64);
+                if self.started.elapsed() + duration > self.limits.max_runtime {
+                    return Err(fail(line, "Sleep would exceed the runtime limit").into());
+                }
+                self.step(line, None)?;
+                if !self.host.sleep(duration) {
+                    return Err(Halt::Cancelled { line });
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn angles(&self, line: usize, angles: &[Expr]) -> Result<Vec<u16>, ScriptError> {
+        angles
+            .iter()
+            .map(|angle| {
+                let angle = self.evaluator().int(line, angle)?;
+                u16::try_from(angle).or_else(|_| error(line, format!("Invalid angle {}", angle)))
+            })
+            .collect()
+    }
+
+    fn evaluator(&self) -> Evaluator<'_> {
+        Evaluator {
+            vars: Some(&self.vars),
+            arm: &*self.host,
+        }
+    }
+}
+
+/// Evaluates expressions against a script's variables and the arm
+struct Evaluator<'a> {
+    /// Variables of a script; without them, as in a [`Condition`], names
+    /// stand for the angles of the servos they name
+    vars: Option<&'a HashMap<String, Value>>,
+    arm: &'a dyn Positions,
+}
+
+impl Evaluator<'_> {
+    fn int(&self, line: usize, expr: &Expr) -> Result<i64, ScriptError> {
+        match self.eval(line, expr)? {
+            Value::Int(n) => Ok(n),
+            Value::Bool(_) => error(line, "Expected a number, found a boolean"),
+        }
+    }
+
+    fn bool(&self, line: usize, expr: &Expr) -> Result<bool, ScriptError> {
+        match self.eval(line, expr)? {
+            Value::Bool(b) => Ok(b),
+            Value::Int(_) => error(line, "Expected a condition, found a number"),
+        }
+    }
+
+    fn eval(&self, line: usize, expr: &Expr) -> Result<Value, ScriptError> {
+        let value = match expr {
+            Expr::Int(n) => Value::Int(*n),
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Var(name) => match self.vars {
+                Some(vars) => match vars.get(name) {
+                    Some(value) => *value,
+                    None => return error(line, format!("Unknown variable '{}'", name)),
+                },
+                None => match self.arm.channel_by_name(name) {
+                    Some(channel) => self.position(line, channel)?,
+                    None => return error(line, format!("Unknown servo name '{}'", name)),
+                },
+            },
+            Expr::Servo(channel) => {
+                let channel = self.int(line, channel)?;
+                let channel = u8::try_from(channel)
+                    .or_else(|_| error(line, format!("Invalid servo channel {}", channel)))?;
+                self.position(line, channel)?
+            }
+            Expr::NamedServo(name) => match self.arm.channel_by_name(name) {
+                Some(channel) => self.position(line, channel)?,
+                None => return error(line, format!("Unknown servo name '{}'", name)),
+            },
+            Expr::Neg(operand) => match self.int(line, operand)?.checked_neg() {
+                Some(n) => Value::Int(n),
+                None => return error(line, "Arithmetic overflow"),
+            },
+            Expr::Not(operand) => Value::Bool(!self.bool(line, operand)?),
+            // Short-circuit, so `servo(0) != 0 and ...` can guard the rest
+            Expr::Binary(BinOp::And, left, right) => {
+                Value::Bool(self.bool(line, left)? && self.bool(line, right)?)
+            }
+            Expr::Binary(BinOp::Or, left, right) => {
+                Value::Bool(self.bool(line, left)? || self.bool(line, right)?)
+            }
+            Expr::Binary(op @ (BinOp::Eq | BinOp::Ne), left, right) => {
+                let equal = match (self.eval(line, left)?, self.eval(line, right)?) {
+                    (Value::Int(a), Value::Int(b)) => a == b,
+                    (Value::Bool(a), Value::Bool(b)) => a == b,
+                    _ => return error(line, "Cannot compare a number with a boolean"),
+                };
+                Value::Bool(equal == (*op == BinOp::Eq))
+            }
+            Expr::Binary(op, left, right) => {
+                let a = self.int(line, left)?;
+                let b = self.int(line, right)?;
+                let result = match op {
+                    BinOp::Lt => return Ok(Value::Bool(a < b)),
+                    BinOp::Le => return Ok(Value::Bool(a <= b)),
+                    BinOp::Gt => return Ok(Value::Bool(a > b)),
+                    BinOp::Ge => return Ok(Value::Bool(a >= b)),
+                    BinOp::Add => a.checked_add(b),
+                    BinOp::Sub => a.checked_sub(b),
+                    BinOp::Mul => a.checked_mul(b),
+                    BinOp::Div | BinOp::Rem if b == 0 => {
+                        return error(line, "Division by zero");
+                    }
+                    BinOp::Div => a.checked_div(b),
+                    BinOp::Rem => a.checked_rem(b),
+                    BinOp::Eq | BinOp::Ne | BinOp::And | BinOp::Or => unreachable!(),
+                };
+                match result {
+                    Some(n) => Value::Int(n),
+                    None => return error(line, "Arithmetic overflow"),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    fn position(&self, line: usize, channel: u8) -> Result<Value, ScriptError> {
+        match self.arm.position(channel) {
+            Some(angle) => Ok(Value::Int(angle as i64)),
+            None => error(line, format!("Position of servo {} is unknown", channel)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A host that records the calls statements make
+    #[derive(Default)]
+    struct Recorder {
+        positions: HashMap<u8, u16>,
+        calls: Vec<String>,
+        /// Time `home` takes, to run a script past its runtime
+        home_takes: Duration,
+        /// Cancel once this many calls have been made
+        cancel_after: Option<usize>,
+    }
+
+    impl Positions for Recorder {
+        fn position(&self, channel: u8) -> Option<u16> {
+            self.positions.get(&channel).copied()
+        }
+
+        fn channel_by_name(&self, name: &str) -> Option<u8> {
+            match name {
+                "base" => Some(0),
+                "elbow" => Some(2),
+                _ => None,
+            }
+        }
+    }
+
+    impl Host for Recorder {
+        fn pose(&mut self, angles: &[u16]) -> Result<(), String> {
+            self.calls.push(format!("pose {:?}", angles));
+            Ok(())
+        }
+
+        fn move_to(&mut self, duration_ms: u16, angles: &[u16]) -> Result<(), String> {
+            self.calls
+                .push(format!("move {} {:?}", duration_ms, angles));
+            Ok(())
+        }
+
+        fn saved_pose(&mut self, name: &str) -> Result<(), String> {
+            if name == "missing" {
+                return Err("Pose 'missing' not found".to_string());
+            }
+            self.calls.push(format!("saved pose {}", name));
+            Ok(())
+        }
+
+        fn sequence(&mut self, name: &str) -> Result<(), String> {
+            self.calls.push(format!("sequence {}", name));
+            Ok(())
+        }
+
+        fn home(&mut self) -> Result<(), String> {
+            std::thread::sleep(self.home_takes);
+            self.calls.push("home".to_string());
+            Ok(())
+        }
+
+        fn sleep(&mut self, duration: Duration) -> bool {
+            self.calls.push(format!("sleep {}", duration.as_millis()));
+            !self.cancelled()
+        }
+
+        fn cancelled(&self) -> bool {
+            self.cancel_after
+                .is_some_and(|after| self.calls.len() >= after)
+        }
+
+        fn trace(&mut self, _entry: TraceEntry) {}
+    }
+
+    fn run_script(source: &str, host: &mut Recorder, limits: Limits) -> Result<(), Halt> {
+        run(&parse(source).unwrap(), host, limits)
+    }
+
+    fn failure(source: &str) -> ScriptError {
+        let mut host = Recorder::default();
+        match run_script(source, &mut host, Limits::default()) {
+            Err(Halt::Failed(error)) => error,
+            other => panic!("{} ran to {:?}", source, other),
+        }
+    }
+
+    fn nested(depth: usize) -> String {
+        "repeat 1 {\n".repeat(depth) + "home\n" + &"}\n".repeat(depth)
+    }
+
+    #[test]
+    fn expressions_bind_by_precedence() {
+        let script = parse("let x = 1 + 2 * -3").unwrap();
+        let expected = Expr::Binary(
+            BinOp::Add,
+            Box::new(Expr::Int(1)),
+            Box::new(Expr::Binary(
+                BinOp::Mul,
+                Box::new(Expr::Int(2)),
+                Box::new(Expr::Neg(Box::new(Expr::Int(3)))),
+            )),
+        );
+        assert_eq!(
+            script.body,
+            vec![Line {
+                line: 1,
+                stmt: Stmt::Let("x".to_string(), expected),
+            }]
+        );
+    }
+
+    #[test]
+    fn statements_keep_their_lines() {
+        let source =
+            "# comment\nhome; sleep 10\n\nif true {\n    pose \"rest\"\n}\nelse {\n    home\n}";
+        let script = parse(source).unwrap();
+        let lines: Vec<usize> = script.body.iter().map(|l| l.line).collect();
+        assert_eq!(lines, vec![2, 2, 4]);
+        match &script.body[2].stmt {
+            Stmt::If(_, then, otherwise) => {
+                assert_eq!(then[0].line, 5);
+                assert_eq!(otherwise[0].line, 8);
+            }
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn brackets_span_lines() {
+        let script = parse("pose [90,\n      45,\n      120]\nhome").unwrap();
+        assert_eq!(script.body.len(), 2);
+        assert_eq!(script.body[1].line, 4);
+    }
+
+    #[test]
+    fn syntax_errors_name_their_line() {
+        let cases = [
+            ("home\nwave", 2, "Unknown statement 'wave'"),
+            ("repeat 2 {\n  home\n", 2, "Missing '}'"),
+            ("home now", 1, "Unexpected now"),
+            ("let if = 1", 1, "Expected a variable name, found if"),
+            ("pose [90, 45", 1, "Expected ']', found end of script"),
+            (
+                "sequence wave",
+                1,
+                "Expected sequence name in quotes, found wave",
+            ),
+            ("pose \"rest", 1, "Unterminated string"),
+            (
+                "\nsleep 99999999999999999999",
+                2,
+                "Number too large: 99999999999999999999",
+            ),
+            ("sleep 1 & 2", 1, "Unexpected character '&'"),
+            ("let x = (1 + 2", 1, "Expected ')', found end of script"),
+            ("let x = not", 1, "Expected a value, found end of line"),
+        ];
+        for (source, line, message) in cases {
+            assert_eq!(parse(source), Err(fail(line, message)), "{}", source);
+        }
+    }
+
+    #[test]
+    fn nesting_is_bounded() {
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        let error = parse(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(error.message, "Nesting too deep");
+        assert_eq!(error.line, MAX_DEPTH + 1);
+
+        let parens = "(".repeat(MAX_DEPTH + 1) + "1" + &")".repeat(MAX_DEPTH + 1);
+        let error = parse(&format!("let x = {}", parens)).unwrap_err();
+        assert_eq!(error.message, "Nesting too deep");
+        let negations = format!("let x = {}1", "-".repeat(MAX_DEPTH + 1));
+        assert_eq!(parse(&negations).unwrap_err().message, "Nesting too deep");
+    }
+
+    #[test]
+    fn statements_map_to_host_calls() {
+        let source = "let n = 2\n\
+                      pose [90, 45 + n, 120]\n\
+                      move 500 [n * 10]\n\
+                      pose \"rest\"\n\
+                      sequence \"wave\"\n\
+                      sleep 250\n\
+                      home\n\
+                      if n > 1 { home } else { pose [0] }";
+        let mut host = Recorder::default();
+        run_script(source, &mut host, Limits::default()).unwrap();
+        assert_eq!(
+            host.calls,
+            [
+                "pose [90, 47, 120]",
+                "move 500 [20]",
+                "saved pose rest",
+                "sequence wave",
+                "sleep 250",
+                "home",
+                "home",
+            ]
+        );
+    }
+
+    #[test]
+    fn loops_repeat_their_body() {
+        let source = "let n = 0\nrepeat 3 { let n = n + 1; pose [n] }\nwhile n > 0 { let n = n - 1; move 10 [n] }";
+        let mut host = Recorder::default();
+        run_script(source, &mut host, Limits::default()).unwrap();
+        assert_eq!(
+            host.calls,
+            [
+                "pose [1]",
+                "pose [2]",
+                "pose [3]",
+                "move 10 [2]",
+                "move 10 [1]",
+                "move 10 [0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions_read_the_positions() {
+        let mut host = Recorder::default();
+        host.positions.insert(0, 30);
+        host.positions.insert(1, 0);
+        host.positions.insert(2, 100);
+        // Servo 9 is unknown, but never read
+        let source =
+            "pose [servo(0) + servo(\"elbow\")]\nif servo(1) != 0 and servo(9) == 0 { home }";
+        run_script(source, &mut host, Limits::default()).unwrap();
+        assert_eq!(host.calls, ["pose [130]"]);
+    }
+
+    #[test]
+    fn infinite_loop_stops_at_the_step_limit() {
+        let limits = Limits {
+            max_steps: 25,
+            max_runtime: MAX_RUNTIME,
+        };
+        let mut host = Recorder::default();
+        let halt = run_script("while true {\n  home\n}", &mut host, limits).unwrap_err();
+        assert_eq!(halt, Halt::Failed(fail(2, "Step limit of 25 exceeded")));
+        // Each iteration is a step for the loop and one for its body
+        assert_eq!(host.calls.len(), 12);
+    }
+
+    #[test]
+    fn long_script_stops_at_the_runtime_limit() {
+        let limits = Limits {
+            max_steps: MAX_STEPS,
+            max_runtime: Duration::from_millis(20),
+        };
+        let mut host = Recorder {
+            home_takes: Duration::from_millis(30),
+            ..Default::default()
+        };
+        let halt = run_script("home\nhome", &mut host, limits).unwrap_err();
+        assert_eq!(halt, Halt::Failed(fail(2, "Runtime limit of 0s exceeded")));
+        assert_eq!(host.calls, ["home"]);
+
+        // A sleep that would run past the limit is refused up front
+        let mut host = Recorder::default();
+        let halt = run_script("sleep 50", &mut host, limits).unwrap_err();
+        assert_eq!(
+            halt,
+            Halt::Failed(fail(1, "Sleep would exceed the runtime limit"))
+        );
+        assert!(host.calls.is_empty());
+    }
+
+    #[test]
+    fn cancellation_stops_at_the_next_statement() {
+        let mut host = Recorder {
+            cancel_after: Some(2),
+            ..Default::default()
+        };
+        let halt = run_script("home\nhome\nhome", &mut host, Limits::default()).unwrap_err();
+        assert_eq!(halt, Halt::Cancelled { line: 3 });
+        assert_eq!(host.calls.len(), 2);
+
+        let mut host = Recorder {
+            cancel_after: Some(1),
+            ..Default::default()
+        };
+        let halt = run_script("sleep 10\nhome", &mut host, Limits::default()).unwrap_err();
+        assert_eq!(halt, Halt::Cancelled { line: 1 });
+    }
+
+    #[test]
+    fn arithmetic_is_checked() {
+        let cases = [
+            ("let x = 9223372036854775807 + 1", "Arithmetic overflow"),
+            ("let x = -9223372036854775807 - 2", "Arithmetic overflow"),
+            ("let x = 4611686018427387904 * 2", "Arithmetic overflow"),
+            ("let x = -(-9223372036854775807 - 1)", "Arithmetic overflow"),
+            (
+                "let x = (-9223372036854775807 - 1) / -1",
+                "Arithmetic overflow",
+            ),
+            ("let x = 1 / 0", "Division by zero"),
+            ("let x = 1 % (2 - 2)", "Division by zero"),
+        ];
+        for (source, message) in cases {
+            assert_eq!(failure(source), fail(1, message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn runtime_errors_name_their_line() {
+        let cases = [
+            ("home\nlet x = y", 2, "Unknown variable 'y'"),
+            ("if 1 { home }", 1, "Expected a condition, found a number"),
+            ("let x = 1 + true", 1, "Expected a number, found a boolean"),
+            (
+                "let x = 1 == true",
+                1,
+                "Cannot compare a number with a boolean",
+            ),
+            ("pose [servo(5)]", 1, "Position of servo 5 is unknown"),
+            ("pose [servo(\"wrist\")]", 1, "Unknown servo name 'wrist'"),
+            ("pose [servo(300)]", 1, "Invalid servo channel 300"),
+            ("pose [-1]", 1, "Invalid angle -1"),
+            ("move 70000 [90]", 1, "Invalid MOVE duration 70000"),
+            (
+                "repeat 1001 { home }",
+                1,
+                "Repeat count 1001 is outside 0-1000",
+            ),
+            ("sleep 60001", 1, "Sleep of 60001ms is outside 0-60000"),
+            ("\n\npose \"missing\"", 3, "Pose 'missing' not found"),
+        ];
+        for (source, line, message) in cases {
+            assert_eq!(failure(source), fail(line, message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn conditions_read_servos_by_name() {
+        let condition = parse_condition("base + elbow < 270 and not servo(1) == 5").unwrap();
+        let mut names = condition.names();
+        names.sort();
+        assert_eq!(names, ["base", "elbow"]);
+
+        let mut arm = Recorder::default();
+        arm.positions.insert(0, 100);
+        arm.positions.insert(1, 0);
+        arm.positions.insert(2, 100);
+        assert_eq!(condition.holds(&arm), Ok(true));
+        arm.positions.insert(2, 170);
+        assert_eq!(condition.holds(&arm), Ok(false));
+        arm.positions.remove(&0);
+        assert_eq!(
+            condition.holds(&arm),
+            Err(fail(1, "Position of servo 0 is unknown"))
+        );
+
+        assert_eq!(
+            parse_condition("base + 10"),
+            Err(fail(1, "Expected a condition, found a number"))
+        );
+        assert_eq!(
+            parse_condition("base < 10 home"),
+            Err(fail(1, "Unexpected home"))
+        );
+    }
+}
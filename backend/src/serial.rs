@@ -1,29 +1,236 @@
 use anyhow::{Context, Result};
+use serde::Serialize;
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 use tokio_serial::SerialPort;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, trace, warn};
 
-const NUM_SERVOS: u8 = 6;
+use crate::clock::{Clock, SystemClock};
+use crate::command_queue::CommandQueue;
+use crate::config::{ProtocolConfig, SerialConfig, SimulationConfig, TimeoutConfig};
+use crate::connection::{ConnectionLog, ConnectionState};
+use crate::controller::ServoController;
+use crate::feedback::FeedbackCalibration;
+use crate::protocol::{
+    self, classify_handshake, encode_adc, encode_busy, encode_clear_faults, encode_faults,
+    encode_get_angle, encode_move, encode_pose, encode_set_angle, encode_set_pwm,
+    encode_set_speed_limit, encode_speed_limit, encode_start, encode_stop, encode_voltage, frame,
+    parse_adc, parse_angle, parse_busy, parse_faults, parse_speed_limit, parse_voltage, Angle,
+    Channel, Handshake, Line, LineAssembler, HANDSHAKE_PROBE, MAX_LINE_LEN,
+};
+use crate::simulator::SimulatedPort;
+use crate::violations::{hex_dump, Violation, ViolationKind, Violations};
+
+pub const NUM_SERVOS: u8 = 6;
+
+/// Read timeout while waiting for the handshake reply
+const HANDSHAKE_TIMEOUT_MS: u64 = 500;
+
+/// Assumed MOVE round trip until one was measured
+const DEFAULT_MOVE_LATENCY: Duration = Duration::from_millis(20);
+
+/// Longest round trip taken into the average, so one stalled reply
+/// doesn't make chained segments start early
+const MAX_MOVE_LATENCY: Duration = Duration::from_millis(250);
+
+/// Per-command overrides of the protocol settings
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommandOptions {
+    /// Clear the input buffer before sending (global default if `None`)
+    pub clear_input: Option<bool>,
+    /// Send a MOVE as a segment of a chain with
+    /// [`SerialManager::execute_move_chained`] if `move_lookahead` is
+    /// enabled
+    pub chain: bool,
+}
+
+/// A chained MOVE whose reply hasn't been read yet
+struct ChainedMove {
+    /// The command as sent, to skip its echo
+    cmd: String,
+    /// Estimated end of the MOVE
+    ends_at: Instant,
+    /// First segment of its chain, started right when it was written
+    first: bool,
+}
+
+/// Firmware mode, as last switched to by the backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialMode {
+    /// Accepting serial commands (after `START`)
+    Serial,
+    /// Driven by the buttons (after `STOP`)
+    Button,
+}
+
+/// Angles read by [`ServoController::get_all_servos`]
+pub struct ServoReadings {
+    /// `None` for a servo the firmware answered with an angle sentinel
+    pub servos: Vec<(Channel, Option<Angle>)>,
+    /// I/O failure that ended the reads early
+    pub failure: Option<anyhow::Error>,
+}
+
+/// Whether an error means the port itself failed rather than a single
+/// command, e.g. because the device was unplugged
+pub fn is_io_failure(error: &anyhow::Error) -> bool {
+    let error = error.to_string();
+    error.contains("Failed to clear input buffer")
+        || error.contains("Failed to clear output buffer")
+        || error.contains("Failed to write")
+        || error.contains("Failed to read")
+}
+
+/// Start of the firmware's reply to a command it doesn't know, which
+/// `START` is in serial mode
+const UNKNOWN_COMMAND: &str = "ERROR: Unknown command";
+
+/// Start of the error when the firmware doesn't take `START`
+const MODE_REFUSED: &str = "Firmware refused serial mode (button held?)";
+
+/// Whether an error means the firmware is reachable but didn't enter
+/// serial mode, e.g. because a button is held on the keypad
+pub fn is_mode_refused(error: &anyhow::Error) -> bool {
+    error.to_string().starts_with(MODE_REFUSED)
+}
+
+/// Start of the error when a motion command got no reply but a read did
+const IGNORED_IN_BUTTON_MODE: &str = "Firmware ignored a motion command but answers reads";
+
+/// Whether an error means the firmware silently ignored a motion command
+/// while still answering reads, as firmware outside serial mode does
+pub fn is_ignored_in_button_mode(error: &anyhow::Error) -> bool {
+    error.to_string().starts_with(IGNORED_IN_BUTTON_MODE)
+}
+
+/// A line the firmware sent on its own, matching `protocol.unsolicited`
+#[derive(Debug, Clone, Serialize)]
+pub struct UnsolicitedLine {
+    pub line: String,
+    /// The command whose response was being read, or about to be sent
+    pub command: String,
+    pub at_ms: u64,
+}
+
+/// Where connections report what happens on them, and the clock they
+/// time chained MOVEs on, kept across reconnects
+#[derive(Clone)]
+pub struct Observers {
+    pub connection: Arc<ConnectionLog>,
+    /// Unsolicited lines, for the serial monitor
+    pub unsolicited: broadcast::Sender<UnsolicitedLine>,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Start of the error of a command failed by a violation in strict mode
+const PROTOCOL_VIOLATION: &str = "Protocol violation";
+
+/// Start of the error refusing a command the firmware couldn't buffer
+const COMMAND_TOO_LONG: &str = "Command too long for the firmware";
+
+/// Whether an error means a command was refused as longer than
+/// `protocol.max_command_len`
+pub fn is_command_too_long(error: &anyhow::Error) -> bool {
+    error.to_string().starts_with(COMMAND_TOO_LONG)
+}
+
+/// Whether an error means strict mode failed a command over bytes no
+/// expected response explains
+pub fn is_protocol_violation(error: &anyhow::Error) -> bool {
+    error.to_string().starts_with(PROTOCOL_VIOLATION)
+}
+
+/// Commands firmware in button mode may ignore without a reply
+fn moves_arm(cmd: &str) -> bool {
+    protocol::command_spec(cmd).is_some_and(|spec| {
+        matches!(
+            spec.name,
+            "set_angle" | "set_angle_extended" | "set_pwm" | "pose" | "move"
+        )
+    })
+}
+
+/// Whether an error means a command was answered with the button-mode
+/// prompt, i.e. the firmware isn't in serial mode
+pub fn is_button_mode(error: &anyhow::Error) -> bool {
+    error.to_string().contains("Type START")
+}
 
 /// Serial port manager for robot arm communication
 pub struct SerialManager {
     port: Arc<Mutex<Box<dyn SerialPort>>>,
+    /// Commands waiting for the port
+    queue: CommandQueue,
+    assembler: Mutex<LineAssembler>,
+    response_delay_ms: AtomicU64,
+    clear_before_send: AtomicBool,
+    clear_output_after_read: AtomicBool,
+    drain_delay_ms: AtomicU64,
+    max_angle: AtomicU16,
+    extended_angles: AtomicBool,
+    angle_query: AtomicBool,
+    adc_query: AtomicBool,
+    busy_query: AtomicBool,
+    voltage_query: AtomicBool,
+    fault_register: AtomicBool,
+    speed_limit: AtomicBool,
+    max_command_len: AtomicUsize,
+    /// Command prefix and suffix of the firmware dialect
+    framing: Mutex<(String, String)>,
+    move_lookahead: AtomicBool,
+    move_queue: AtomicBool,
+    auto_start: AtomicBool,
+    strict: AtomicBool,
+    /// Patterns of `protocol.unsolicited`
+    unsolicited: Mutex<Arc<Vec<String>>>,
+    /// `protocol.angle_sentinels`
+    angle_sentinels: Mutex<Arc<Vec<i32>>>,
+    violations: Violations,
+    /// Chained MOVE in progress, see [`SerialManager::execute_move_chained`]
+    chained: Mutex<Option<ChainedMove>>,
+    /// Average time from a MOVE's end to its reply, in microseconds; 0
+    /// until one was measured
+    move_latency_us: AtomicU64,
+    /// Held across a mode switch, so concurrent `START`/`STOP`s apply in
+    /// turn and `mode` always matches the last one applied
+    mode_switch: Mutex<()>,
+    /// `None` until a switch succeeded, and after one failed
+    mode: Mutex<Option<SerialMode>>,
+    /// The last `START` was refused and no switch succeeded since
+    mode_refused: AtomicBool,
+    /// Button mode was last entered for being idle, so the next motion
+    /// command enters serial mode first
+    idle_stopped: AtomicBool,
+    /// Where the handshake, mode switches and unsolicited lines go
+    observers: Observers,
+    baud_rate: u32,
+    simulated: bool,
 }
 
 impl SerialManager {
     /// Open serial port and initialize connection
-    pub fn new(port_name: &str, baud_rate: u32) -> Result<Self> {
-        info!("Opening serial port {} at {} baud", port_name, baud_rate);
+    ///
+    /// If the handshake at the configured baud rate returns garbage and
+    /// autodetection is enabled, the other baud rates are tried in turn.
+    pub fn new(
+        serial: &SerialConfig,
+        timeouts: &TimeoutConfig,
+        protocol: &ProtocolConfig,
+        observers: Observers,
+    ) -> Result<Self> {
+        info!(port = %serial.port, baud = serial.baud, "Opening serial port");
 
-        let port = tokio_serial::new(port_name, baud_rate)
-            .timeout(Duration::from_secs(12))
+        let port = tokio_serial::new(&serial.port, serial.baud)
+            .timeout(Duration::from_millis(timeouts.command_ms))
             .open()
             .context("Failed to open serial port")?;
 
         // Wait for port to stabilize after opening
-        std::thread::sleep(Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(serial.init_delay_ms));
 
         // Flush input buffer to discard startup message and any stale data
         port.clear(tokio_serial::ClearBuffer::Input)
@@ -32,123 +239,828 @@ impl SerialManager {
         debug!("Input buffer cleared after opening port");
 
         // Additional flush: read and discard any remaining data
-        std::thread::sleep(Duration::from_millis(500));
+        std::thread::sleep(Duration::from_millis(serial.init_flush_delay_ms));
         port.clear(tokio_serial::ClearBuffer::Input)
             .context("Failed to clear input buffer (second flush)")?;
 
+        Self::init(port, serial, timeouts, protocol, observers, false)
+    }
+
+    /// Connect to an in-process simulated arm instead of a serial port
+    pub fn simulated(
+        serial: &SerialConfig,
+        timeouts: &TimeoutConfig,
+        protocol: &ProtocolConfig,
+        simulation: &SimulationConfig,
+        observers: Observers,
+    ) -> Result<Self> {
+        info!("Using simulated arm");
+        let port = SimulatedPort::new(serial.baud, protocol, simulation);
+        let port: Box<dyn SerialPort> = Box::new(port);
+        Self::init(port, serial, timeouts, protocol, observers, true)
+    }
+
+    /// Handshake with the firmware on a port opened elsewhere, e.g. a
+    /// custom transport
+    pub fn with_port(
+        port: Box<dyn SerialPort>,
+        serial: &SerialConfig,
+        timeouts: &TimeoutConfig,
+        protocol: &ProtocolConfig,
+        observers: Observers,
+    ) -> Result<Self> {
+        Self::init(port, serial, timeouts, protocol, observers, false)
+    }
+
+    /// Handshake with the firmware on an opened port
+    fn init(
+        mut port: Box<dyn SerialPort>,
+        serial: &SerialConfig,
+        timeouts: &TimeoutConfig,
+        protocol: &ProtocolConfig,
+        observers: Observers,
+        simulated: bool,
+    ) -> Result<Self> {
+        observers.connection.record(ConnectionState::Handshaking, None);
+        let mut baud_rate = serial.baud;
+        let probe = frame(
+            HANDSHAKE_PROBE,
+            &protocol.command_prefix,
+            &protocol.command_suffix,
+        );
+        if Self::handshake(&mut port, &probe)? == Handshake::Garbled {
+            warn!(
+                "Garbled handshake response at {} baud, check SERIAL_BAUD",
+                serial.baud
+            );
+            if let Some(detected) = Self::detect_baud(&mut port, serial, &probe)? {
+                baud_rate = detected;
+            } else {
+                port.set_baud_rate(serial.baud)
+                    .context("Failed to set baud rate")?;
+            }
+        }
+
+        port.set_timeout(Duration::from_millis(timeouts.command_ms))
+            .context("Failed to set serial timeout")?;
+
         debug!("Port initialization complete");
 
         Ok(Self {
             port: Arc::new(Mutex::new(port)),
+            queue: CommandQueue::default(),
+            assembler: Mutex::new(LineAssembler::new(MAX_LINE_LEN)),
+            response_delay_ms: AtomicU64::new(timeouts.response_delay_ms),
+            clear_before_send: AtomicBool::new(protocol.clear_before_send),
+            clear_output_after_read: AtomicBool::new(protocol.clear_output_after_read),
+            drain_delay_ms: AtomicU64::new(protocol.drain_delay_ms),
+            max_angle: AtomicU16::new(protocol.max_angle),
+            extended_angles: AtomicBool::new(protocol.extended_angles),
+            angle_query: AtomicBool::new(protocol.angle_query),
+            adc_query: AtomicBool::new(protocol.adc_query),
+            busy_query: AtomicBool::new(protocol.busy_query),
+            voltage_query: AtomicBool::new(protocol.voltage_query),
+            fault_register: AtomicBool::new(protocol.fault_register),
+            speed_limit: AtomicBool::new(protocol.speed_limit),
+            max_command_len: AtomicUsize::new(protocol.max_command_len),
+            framing: Mutex::new((
+                protocol.command_prefix.clone(),
+                protocol.command_suffix.clone(),
+            )),
+            move_lookahead: AtomicBool::new(protocol.move_lookahead),
+            move_queue: AtomicBool::new(protocol.move_queue),
+            auto_start: AtomicBool::new(protocol.auto_start),
+            strict: AtomicBool::new(protocol.strict),
+            unsolicited: Mutex::new(Arc::new(protocol.unsolicited.clone())),
+            angle_sentinels: Mutex::new(Arc::new(protocol.angle_sentinels.clone())),
+            violations: Violations::default(),
+            chained: Mutex::new(None),
+            move_latency_us: AtomicU64::new(0),
+            mode_switch: Mutex::new(()),
+            mode: Mutex::new(None),
+            mode_refused: AtomicBool::new(false),
+            idle_stopped: AtomicBool::new(false),
+            observers,
+            baud_rate,
+            simulated,
         })
     }
 
-    /// Send a command and read the response
-    fn send_command(&self, cmd: &str) -> Result<String> {
-        let mut port = self.port.lock().unwrap();
-
-        debug!("Sending command: {:?}", cmd.trim());
-        debug!("Sending bytes: {:?}", cmd.as_bytes());
+    /// Probe the firmware and classify the reply
+    ///
+    /// A garbled reply is retried once so that a single corrupted line
+    /// doesn't count as a mismatch.
+    fn handshake(port: &mut Box<dyn SerialPort>, probe: &str) -> Result<Handshake> {
+        port.set_timeout(Duration::from_millis(HANDSHAKE_TIMEOUT_MS))
+            .context("Failed to set serial timeout")?;
 
-        // Wrap in closure to catch errors
-        let result = (|| -> Result<String> {
-            // Clear any stale data in the buffer before sending
+        let mut result = Handshake::Silent;
+        for _ in 0..2 {
             port.clear(tokio_serial::ClearBuffer::Input)
-                .context("Failed to clear input buffer before sending")?;
-
-            // Send command
-            port.write_all(cmd.as_bytes())
+                .context("Failed to clear input buffer")?;
+            port.write_all(probe.as_bytes())
                 .context("Failed to write to serial port")?;
-            port.flush()
-                .context("Failed to flush serial port")?;
-
-            // Give the AVR time to process and respond
-            std::thread::sleep(std::time::Duration::from_millis(200));
-
-            // Read response - use the port directly, not a clone
-            let mut response = Vec::new();
-            let mut buf = [0u8; 1];
+            port.flush().context("Failed to flush serial port")?;
 
-            // Read until we get a newline
-            loop {
+            let mut reply = Vec::new();
+            let mut buf = [0u8; 64];
+            while reply.len() < MAX_LINE_LEN {
                 match port.read(&mut buf) {
                     Ok(n) if n > 0 => {
-                        response.push(buf[0]);
-                        if buf[0] == b'\n' {
-                            break;
-                        }
-                        // Safety: don't read forever
-                        if response.len() > 256 {
+                        reply.extend_from_slice(&buf[..n]);
+                        if reply.contains(&b'\n') {
                             break;
                         }
                     }
-                    Ok(_) => break, // EOF or no data
+                    Ok(_) => break,
                     Err(e) if e.kind() == std::io::ErrorKind::TimedOut => break,
                     Err(e) => return Err(e).context("Failed to read from serial port")?,
                 }
             }
 
-            let response = String::from_utf8_lossy(&response).to_string();
-            debug!("Read {} bytes: {:?}", response.len(), response.as_bytes());
-            debug!("Response string: {:?}", response);
-            debug!("Response trimmed: {:?}", response.trim());
+            result = classify_handshake(&reply);
+            debug!("Handshake reply {:?}: {:?}", reply, result);
+            if result != Handshake::Garbled {
+                break;
+            }
+        }
 
-            Ok(response)
-        })();
+        Ok(result)
+    }
 
-        // If error occurs, log it (caller will handle dropping SerialManager)
-        if let Err(ref e) = result {
-            error!("Serial communication error: {}", e);
+    /// Try the autodetect baud rates until one gives a clean handshake
+    fn detect_baud(
+        port: &mut Box<dyn SerialPort>,
+        serial: &SerialConfig,
+        probe: &str,
+    ) -> Result<Option<u32>> {
+        for &baud in &serial.baud_autodetect {
+            if baud == serial.baud {
+                continue;
+            }
+            debug!("Trying {} baud", baud);
+            port.set_baud_rate(baud).context("Failed to set baud rate")?;
+            if Self::handshake(port, probe)? == Handshake::Clean {
+                info!("Detected firmware baud rate: {}", baud);
+                return Ok(Some(baud));
+            }
         }
 
-        result
+        if !serial.baud_autodetect.is_empty() {
+            warn!("Baud rate autodetection failed, staying at {}", serial.baud);
+        }
+        Ok(None)
     }
 
-    /// Convert channel number to hex character (0-9, A-F)
-    fn channel_to_hex(channel: u8) -> char {
-        if channel < 10 {
-            (b'0' + channel) as char
-        } else {
-            (b'A' + (channel - 10)) as char
+    /// Angles are in the command set's range, but `max_angle` may be
+    /// lower
+    fn check_angles(&self, angles: &[Angle]) -> Result<()> {
+        let max_angle = self.max_angle();
+        if let Some(angle) = angles.iter().find(|a| a.get() > max_angle) {
+            anyhow::bail!("Invalid angle: {} (must be 0-{})", angle, max_angle);
         }
+        Ok(())
     }
 
-    /// Enter serial mode
-    pub fn start_serial_mode(&self) -> Result<()> {
-        info!("Entering serial mode");
-        let response = self.send_command("START\n")?;
+    /// Send a command and read the response
+    fn send_command(&self, cmd: &str) -> Result<String> {
+        self.send_command_with(cmd, CommandOptions::default())
+    }
 
-        if response.trim() == "OK" {
-            Ok(())
-        } else {
-            anyhow::bail!("Failed to enter serial mode: {}", response);
+    /// Send a command with per-command options and read the response
+    ///
+    /// The configured prefix and suffix are added here, so the command
+    /// builders only produce the bare command. A chained MOVE still in
+    /// progress is finished first. A motion command without a reply is
+    /// checked with [`unanswered`](Self::unanswered).
+    fn send_command_with(&self, cmd: &str, opts: CommandOptions) -> Result<String> {
+        let mut responses = self.send_lines(cmd, &[cmd.to_string()], opts)?;
+        Ok(responses.remove(0))
+    }
+
+    /// Send `lines` back to back in a single turn of the queue, where they
+    /// are listed as `cmd`, and read the response to each
+    ///
+    /// Every line is framed and measured before anything is sent, so a
+    /// line the firmware couldn't buffer fails the whole batch. After the
+    /// first line of several that isn't answered `OK` the rest are left
+    /// out.
+    fn send_lines(&self, cmd: &str, lines: &[String], opts: CommandOptions) -> Result<Vec<String>> {
+        let framed = lines
+            .iter()
+            .map(|line| self.framed(line))
+            .collect::<Result<Vec<String>>>()?;
+        let motion = moves_arm(cmd);
+        if motion && self.idle_stopped.load(Ordering::Relaxed) {
+            info!("Re-entering serial mode after idling");
+            self.switch_mode(SerialMode::Serial, false)?;
+        }
+        let _turn = self.queue.enter(cmd)?;
+        let mut port = self.port.lock().unwrap();
+        let clear_input = opts
+            .clear_input
+            .unwrap_or_else(|| self.clear_before_send.load(Ordering::Relaxed));
+        let mut responses = Vec::with_capacity(framed.len());
+
+        for (line, cmd) in lines.iter().zip(&framed) {
+            let command = protocol::command_spec(line).map_or("raw", |spec| spec.name);
+            let cmd = cmd.as_str();
+            debug!(command, line = cmd.trim(), "Sending command");
+            trace!(bytes = ?cmd.as_bytes(), "Sending bytes");
+            let sent = Instant::now();
+
+            // Wrap in closure to catch errors
+            let result = (|| -> Result<String> {
+                self.finish_chain(&mut port)?;
+
+                // Clear any stale data in the buffer before sending. Skipping
+                // this keeps unsolicited firmware output, but any such line is
+                // then read as this command's response. Data about to be
+                // cleared, or any in strict mode, is read first to be recorded.
+                if clear_input || self.strict.load(Ordering::Relaxed) {
+                    let stale = self.skip_unsolicited(cmd, &Self::pending(&mut port)?);
+                    if !stale.is_empty() {
+                        self.violation(ViolationKind::Unsolicited, cmd, &stale)?;
+                    }
+                }
+                if clear_input {
+                    port.clear(tokio_serial::ClearBuffer::Input)
+                        .context("Failed to clear input buffer before sending")?;
+                }
+
+                let response = self.exchange(&mut port, cmd)?;
+                if motion && response.trim().is_empty() {
+                    return self.unanswered(&mut port, cmd);
+                }
+                Ok(response)
+            })();
+
+            // If error occurs, log it (caller will handle dropping SerialManager)
+            let latency_ms = sent.elapsed().as_millis() as u64;
+            match &result {
+                Ok(response) => {
+                    debug!(command, latency_ms, response = response.trim(), "Command answered")
+                }
+                Err(e) => error!(command, latency_ms, error = %e, "Serial communication error"),
+            }
+            let response = result?;
+            let answered = response.trim() == "OK";
+            responses.push(response);
+            if !answered && lines.len() > 1 {
+                break;
+            }
         }
+        Ok(responses)
     }
 
-    /// Exit serial mode
-    pub fn stop_serial_mode(&self) -> Result<()> {
-        info!("Exiting serial mode");
-        let response = self.send_command("STOP\n")?;
+    /// Input already received and not read yet
+    fn pending(port: &mut Box<dyn SerialPort>) -> Result<Vec<u8>> {
+        let available = port.bytes_to_read().context("Failed to read from serial port")?;
+        let mut bytes = vec![0u8; available as usize];
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match port.read(&mut bytes[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e).context("Failed to read from serial port"),
+            }
+        }
+        bytes.truncate(filled);
+        Ok(bytes)
+    }
 
-        if response.trim() == "OK" {
-            Ok(())
+    /// Skip the complete lines among `bytes` that match an unsolicited line
+    /// pattern, read around the framed command `cmd`; returns the others
+    fn skip_unsolicited(&self, cmd: &str, bytes: &[u8]) -> Vec<u8> {
+        let patterns = self.unsolicited.lock().unwrap().clone();
+        if patterns.is_empty() {
+            return bytes.to_vec();
+        }
+        let mut kept = Vec::new();
+        for line in bytes.split_inclusive(|&b| b == b'\n') {
+            let text = String::from_utf8_lossy(line);
+            if !line.ends_with(b"\n") || !self.is_unsolicited(&patterns, cmd, text.trim()) {
+                kept.extend_from_slice(line);
+            }
+        }
+        kept
+    }
+
+    /// Whether a line matches an unsolicited line pattern; one that does
+    /// is passed on to the serial monitor
+    fn is_unsolicited(&self, patterns: &[String], cmd: &str, line: &str) -> bool {
+        if !patterns.iter().any(|pattern| protocol::matches_pattern(pattern, line)) {
+            return false;
+        }
+        debug!(line, "Skipped unsolicited line");
+        // Nobody listening is fine
+        let _ = self.observers.unsolicited.send(UnsolicitedLine {
+            line: line.to_string(),
+            command: cmd.trim().to_string(),
+            at_ms: SystemClock.now_ms(),
+        });
+        true
+    }
+
+    /// Record bytes no expected response explains around the framed
+    /// command `cmd`; fails in strict mode
+    fn violation(&self, kind: ViolationKind, cmd: &str, bytes: &[u8]) -> Result<()> {
+        let hex = hex_dump(bytes);
+        warn!(kind = kind.name(), line = cmd.trim(), hex, "Protocol violation");
+        self.violations.record(Violation {
+            kind,
+            command: cmd.trim().to_string(),
+            hex: hex.clone(),
+            bytes: bytes.len(),
+            at_ms: SystemClock.now_ms(),
+        });
+        if self.strict.load(Ordering::Relaxed) {
+            anyhow::bail!(
+                "{} ({} bytes around {:?}): {}",
+                PROTOCOL_VIOLATION,
+                kind.name(),
+                cmd.trim(),
+                hex
+            );
+        }
+        Ok(())
+    }
+
+    /// Write a framed command to the held port and read its response
+    fn exchange(&self, port: &mut Box<dyn SerialPort>, cmd: &str) -> Result<String> {
+        port.write_all(cmd.as_bytes())
+            .context("Failed to write to serial port")?;
+        port.flush()
+            .context("Failed to flush serial port")?;
+
+        // Give the AVR time to process and respond
+        let delay = self.response_delay_ms.load(Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(delay));
+
+        let response = self.read_response(port, cmd)?;
+        self.settle(port)?;
+        Ok(response)
+    }
+
+    /// Tell firmware that ignores motion commands outside serial mode
+    /// from a plain timeout after the framed motion command `cmd` got no
+    /// reply; returns the reply of a retry, or the empty one
+    ///
+    /// With `auto_start`, serial mode is entered and `cmd` sent once more.
+    /// The probe, `START` and the retry go to the held port directly, so
+    /// they never come back here.
+    fn unanswered(&self, port: &mut Box<dyn SerialPort>, cmd: &str) -> Result<String> {
+        if !self.probe_ignored(port)? {
+            return Ok(String::new());
+        }
+        if !self.auto_start.load(Ordering::Relaxed) {
+            anyhow::bail!("{}", IGNORED_IN_BUTTON_MODE);
+        }
+
+        info!(line = cmd.trim(), "Firmware ignored a motion command, entering serial mode");
+        let response = self.exchange(port, &self.framed(&encode_start())?)?;
+        let response = response.trim();
+        // Already in serial mode, START is rejected as malformed
+        if response != "OK" && !response.starts_with("ERROR") {
+            *self.mode.lock().unwrap() = None;
+            self.mode_refused.store(true, Ordering::Relaxed);
+            let response = if response.is_empty() { "no reply" } else { response };
+            anyhow::bail!("{}: {}", MODE_REFUSED, response);
+        }
+        *self.mode.lock().unwrap() = Some(SerialMode::Serial);
+        self.mode_refused.store(false, Ordering::Relaxed);
+        self.idle_stopped.store(false, Ordering::Relaxed);
+        self.exchange(port, cmd)
+    }
+
+    /// Whether the firmware answers a GET probe after a motion command went
+    /// unanswered, i.e. it is alive but ignored the command; noted as
+    /// button mode
+    fn probe_ignored(&self, port: &mut Box<dyn SerialPort>) -> Result<bool> {
+        let channel = Channel::new(0, NUM_SERVOS).expect("channel 0 exists");
+        let probe = self.exchange(port, &self.framed(&encode_get_angle(channel))?)?;
+        if probe.trim().is_empty() {
+            return Ok(false);
+        }
+        warn!(response = probe.trim(), "Motion command ignored, but GET answered");
+        *self.mode.lock().unwrap() = Some(SerialMode::Button);
+        Ok(true)
+    }
+
+    /// Fail a chained MOVE without a reply; segments aren't sent again, as
+    /// the next one was already written
+    fn check_chained_reply(&self, port: &mut Box<dyn SerialPort>, response: &str) -> Result<()> {
+        match response.trim() {
+            "OK" => Ok(()),
+            "" if self.probe_ignored(port)? => anyhow::bail!("{}", IGNORED_IN_BUTTON_MODE),
+            response => anyhow::bail!("Failed to execute MOVE: {}", response),
+        }
+    }
+
+    /// `cmd` in the configured framing, refused if the firmware couldn't
+    /// buffer it, framing and line ending included
+    fn framed(&self, cmd: &str) -> Result<String> {
+        let (prefix, suffix) = &*self.framing.lock().unwrap();
+        let framed = frame(cmd, prefix, suffix);
+        let max = self.max_command_len.load(Ordering::Relaxed);
+        if framed.len() > max {
+            anyhow::bail!(
+                "{}: {:?} is {} bytes with framing and line ending, the firmware buffers {} \
+                 (protocol.max_command_len)",
+                COMMAND_TOO_LONG,
+                framed.trim_end(),
+                framed.len(),
+                max
+            );
+        }
+        Ok(framed)
+    }
+
+    /// Whether the firmware can buffer `cmd`, see [`framed`](Self::framed)
+    fn fits(&self, cmd: &str) -> bool {
+        let (prefix, suffix) = &*self.framing.lock().unwrap();
+        frame(cmd, prefix, suffix).len() <= self.max_command_len.load(Ordering::Relaxed)
+    }
+
+    /// Read the response line to `cmd`, skipping its echo
+    fn read_response(&self, port: &mut Box<dyn SerialPort>, cmd: &str) -> Result<String> {
+        // Read response - use the port directly, not a clone
+        let mut assembler = self.assembler.lock().unwrap();
+        assembler.reset();
+        assembler.expect_echo(cmd);
+        let mut buf = [0u8; 64];
+        // Read along with the response line
+        let mut trailing = Vec::new();
+        let patterns = self.unsolicited.lock().unwrap().clone();
+
+        // Read until the assembler produces a complete line other than an
+        // unsolicited one
+        let response = 'read: loop {
+            match port.read(&mut buf) {
+                Ok(n) if n > 0 => {
+                    trace!(count = n, bytes = ?&buf[..n], "Read bytes");
+                    let mut lines = assembler.feed(&buf[..n]);
+                    while let Some(line) = lines.next() {
+                        match line {
+                            Line::Text(text) if self.is_unsolicited(&patterns, cmd, &text) => {}
+                            Line::Text(text) => {
+                                trailing = lines.rest().to_vec();
+                                break 'read text;
+                            }
+                            Line::Overflow => {
+                                anyhow::bail!("Response exceeded {} bytes", MAX_LINE_LEN)
+                            }
+                        }
+                    }
+                }
+                Ok(_) => break assembler.partial(), // EOF or no data
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    break assembler.partial()
+                }
+                Err(e) => return Err(e).context("Failed to read from serial port")?,
+            }
+        };
+
+        debug!(response = response.as_str(), "Response read");
+        if assembler.garbage() > 0 {
+            debug!("Dropped {} non-printable bytes", assembler.garbage());
+            let dropped = assembler.dropped().to_vec();
+            drop(assembler);
+            self.violation(ViolationKind::NonPrintable, cmd, &dropped)?;
+        }
+        // A line ending's second byte is part of the response
+        let trailing = trailing.strip_prefix(b"\r").unwrap_or(&trailing);
+        let trailing = self.skip_unsolicited(cmd, trailing);
+        if !trailing.is_empty() {
+            self.violation(ViolationKind::Trailing, cmd, &trailing)?;
+        }
+        Ok(response)
+    }
+
+    /// Optional output clear and drain delay after a response
+    fn settle(&self, port: &mut Box<dyn SerialPort>) -> Result<()> {
+        // Still holding the port, so the next command starts clean:
+        // nothing of this one left queued, and the line given time to
+        // settle before the next pre-clear
+        if self.clear_output_after_read.load(Ordering::Relaxed) {
+            port.clear(tokio_serial::ClearBuffer::Output)
+                .context("Failed to clear output buffer after reading")?;
+        }
+        let drain = self.drain_delay_ms.load(Ordering::Relaxed);
+        if drain > 0 {
+            std::thread::sleep(Duration::from_millis(drain));
+        }
+        Ok(())
+    }
+
+    /// How long before a chained MOVE ends the next one is written
+    ///
+    /// With a firmware that buffers a command during a MOVE, it is sent a
+    /// full round trip early so it is waiting when the MOVE ends;
+    /// otherwise half a round trip, to arrive just as it ends.
+    fn chain_lead(&self) -> Duration {
+        let round_trip = self.move_latency();
+        if self.move_queue.load(Ordering::Relaxed) {
+            round_trip
         } else {
-            anyhow::bail!("Failed to exit serial mode: {}", response);
+            round_trip / 2
+        }
+    }
+
+    /// Read the reply of the chained MOVE in progress, if any, waiting for
+    /// it to end
+    fn finish_chain(&self, port: &mut Box<dyn SerialPort>) -> Result<()> {
+        let Some(pending) = self.chained.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let response = self.read_response(port, &pending.cmd)?;
+        self.settle(port)?;
+        self.check_chained_reply(port, &response)
+    }
+
+    /// Send a MOVE as one segment of a chain, returning shortly before it
+    /// ends instead of once it is done
+    ///
+    /// The next segment is written while this one is still running, so the
+    /// firmware starts it without waiting for a round trip. The reply is
+    /// read when the next segment is sent, or before any other command, so
+    /// a caller that stops chaining between segments still stops at a
+    /// segment boundary. The first segment of a chain measures the round
+    /// trip for [`chain_lead`](Self::chain_lead).
+    pub fn execute_move_chained(&self, duration_ms: u16, angles: &[Angle]) -> Result<()> {
+        if angles.len() > NUM_SERVOS as usize {
+            anyhow::bail!("Too many servos: {} (max {})", angles.len(), NUM_SERVOS);
+        }
+        self.check_angles(angles)?;
+
+        let extended = self.extended_angles.load(Ordering::Relaxed);
+        let cmd = encode_move(duration_ms, angles, extended);
+        let framed = self.framed(&cmd)?;
+        let turn = self.queue.enter(&cmd)?;
+        let cmd = framed;
+        let duration = Duration::from_millis(duration_ms as u64);
+        let lead = self.chain_lead();
+        let clock = &self.observers.clock;
+        let mut port = self.port.lock().unwrap();
+        let previous = self.chained.lock().unwrap().take();
+        let first = previous.is_none();
+
+        let result = (|| -> Result<Instant> {
+            if let Some(previous) = &previous {
+                let write_at = previous.ends_at.checked_sub(lead).unwrap_or(previous.ends_at);
+                clock.sleep_blocking(write_at.saturating_duration_since(clock.now()));
+            }
+            debug!(command = "move", line = cmd.trim(), "Sending chained command");
+            port.write_all(cmd.as_bytes())
+                .context("Failed to write to serial port")?;
+            port.flush()
+                .context("Failed to flush serial port")?;
+            let written = clock.now();
+
+            let Some(previous) = previous else {
+                return Ok(written + duration);
+            };
+            let response = self.read_response(&mut port, &previous.cmd)?;
+            let replied = clock.now();
+            self.settle(&mut port)?;
+            self.check_chained_reply(&mut port, &response)?;
+            if previous.first {
+                let round_trip = replied.saturating_duration_since(previous.ends_at);
+                self.record_move_latency(round_trip.min(MAX_MOVE_LATENCY));
+            }
+            // This segment started once the previous one ended, half a
+            // round trip before its reply arrived
+            let round_trip = self.move_latency();
+            let started = replied.checked_sub(round_trip / 2).unwrap_or(replied).max(written);
+            Ok(started + duration)
+        })();
+
+        match result {
+            Ok(ends_at) => {
+                *self.chained.lock().unwrap() = Some(ChainedMove {
+                    cmd,
+                    ends_at,
+                    first,
+                });
+                drop(port);
+                drop(turn);
+                // Again, as the round trip may just have been measured
+                let lead = self.chain_lead();
+                let return_at = ends_at.checked_sub(lead).unwrap_or(ends_at);
+                clock.sleep_blocking(return_at.saturating_duration_since(clock.now()));
+                Ok(())
+            }
+            Err(e) => {
+                error!(command = "move", error = %e, "Serial communication error");
+                Err(e)
+            }
+        }
+    }
+
+    /// Fold a measured MOVE round trip into the running average, or start
+    /// it with the first one
+    fn record_move_latency(&self, round_trip: Duration) {
+        let sample = (round_trip.as_micros() as u64).max(1);
+        let updated = match self.move_latency_us.load(Ordering::Relaxed) {
+            0 => sample,
+            average => (average * 3 + sample) / 4,
+        };
+        self.move_latency_us.store(updated, Ordering::Relaxed);
+        debug!(
+            latency_ms = round_trip.as_millis() as u64,
+            average_us = updated,
+            "MOVE round trip"
+        );
+    }
+
+    /// Average round trip of a chained MOVE's reply
+    fn move_latency(&self) -> Duration {
+        match self.move_latency_us.load(Ordering::Relaxed) {
+            0 => DEFAULT_MOVE_LATENCY,
+            average => Duration::from_micros(average),
         }
     }
 
-    /// Set servo angle (0-180 degrees)
-    pub fn set_servo_angle(&self, channel: u8, angle: u8) -> Result<()> {
-        if channel >= NUM_SERVOS {
-            anyhow::bail!("Invalid servo channel: {}", channel);
+    /// Switch to `target`, for being `idle` if so; a failed switch keeps
+    /// the idle state, so a failed `START` after idling is tried again
+    fn switch_mode(&self, target: SerialMode, idle: bool) -> Result<()> {
+        let _switch = self.mode_switch.lock().unwrap();
+        // Firmware already in the target mode doesn't answer OK: in serial
+        // mode START is an unknown command, and in button mode every line
+        // but START gets the START prompt. Any other error, e.g. to a
+        // garbled START, leaves the mode unknown.
+        let (cmd, already, action) = match target {
+            SerialMode::Serial => (encode_start(), UNKNOWN_COMMAND, "enter"),
+            SerialMode::Button => (encode_stop(), "Type START", "exit"),
+        };
+        let result = self.send_command(&cmd).and_then(|response| {
+            let response = response.trim();
+            if response == "OK" {
+                Ok(())
+            } else if response.starts_with(already) {
+                debug!("Already in {:?} mode", target);
+                Ok(())
+            } else if target == SerialMode::Serial
+                && (response.is_empty() || response.starts_with("Type START"))
+            {
+                // While a button is held the keypad loop delays every UART
+                // poll, so the reply to START can miss the timeout
+                let response = if response.is_empty() { "no reply" } else { response };
+                anyhow::bail!("{}: {}", MODE_REFUSED, response)
+            } else {
+                anyhow::bail!("Failed to {} serial mode: {}", action, response)
+            }
+        });
+        *self.mode.lock().unwrap() = result.as_ref().ok().map(|_| target);
+        if result.is_ok() {
+            self.idle_stopped.store(idle, Ordering::Relaxed);
+            self.observers.connection.record(
+                match target {
+                    SerialMode::Serial => ConnectionState::SerialMode,
+                    SerialMode::Button => ConnectionState::Connected,
+                },
+                None,
+            );
+        }
+        if target == SerialMode::Serial || result.is_ok() {
+            let refused = matches!(&result, Err(e) if is_mode_refused(e));
+            self.mode_refused.store(refused, Ordering::Relaxed);
         }
-        if angle > 180 {
-            anyhow::bail!("Invalid angle: {} (must be 0-180)", angle);
+        result
+    }
+
+    /// Send a POSE too long for the firmware as one angle command per
+    /// channel, in a single turn of the queue so nothing comes between
+    /// them
+    fn execute_pose_split(&self, pose: &str, angles: &[Angle], opts: CommandOptions) -> Result<()> {
+        let extended = self.extended_angles.load(Ordering::Relaxed);
+        let lines: Vec<String> = Channel::all(NUM_SERVOS)
+            .zip(angles)
+            .map(|(channel, &angle)| encode_set_angle(channel, angle, extended))
+            .collect();
+        debug!(line = pose.trim(), parts = lines.len(), "Splitting a POSE too long to send");
+        let responses = self.send_lines(pose, &lines, opts)?;
+        match responses.iter().find(|response| response.trim() != "OK") {
+            None => Ok(()),
+            Some(response) => anyhow::bail!("Failed to execute POSE: {}", response),
+        }
+    }
+}
+
+impl ServoController for SerialManager {
+    fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
+
+    fn is_simulated(&self) -> bool {
+        self.simulated
+    }
+
+    fn queue(&self) -> &CommandQueue {
+        &self.queue
+    }
+
+    fn set_timeouts(&self, timeouts: &TimeoutConfig) -> Result<()> {
+        let mut port = self.port.lock().unwrap();
+        port.set_timeout(Duration::from_millis(timeouts.command_ms))
+            .context("Failed to set serial timeout")?;
+        self.response_delay_ms
+            .store(timeouts.response_delay_ms, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_protocol(&self, protocol: &ProtocolConfig) {
+        self.clear_before_send
+            .store(protocol.clear_before_send, Ordering::Relaxed);
+        self.clear_output_after_read
+            .store(protocol.clear_output_after_read, Ordering::Relaxed);
+        self.drain_delay_ms
+            .store(protocol.drain_delay_ms, Ordering::Relaxed);
+        self.max_angle.store(protocol.max_angle, Ordering::Relaxed);
+        self.extended_angles
+            .store(protocol.extended_angles, Ordering::Relaxed);
+        self.angle_query
+            .store(protocol.angle_query, Ordering::Relaxed);
+        self.adc_query.store(protocol.adc_query, Ordering::Relaxed);
+        self.busy_query.store(protocol.busy_query, Ordering::Relaxed);
+        self.voltage_query
+            .store(protocol.voltage_query, Ordering::Relaxed);
+        self.fault_register
+            .store(protocol.fault_register, Ordering::Relaxed);
+        self.speed_limit
+            .store(protocol.speed_limit, Ordering::Relaxed);
+        self.max_command_len
+            .store(protocol.max_command_len, Ordering::Relaxed);
+        *self.framing.lock().unwrap() = (
+            protocol.command_prefix.clone(),
+            protocol.command_suffix.clone(),
+        );
+        self.move_lookahead
+            .store(protocol.move_lookahead, Ordering::Relaxed);
+        self.move_queue.store(protocol.move_queue, Ordering::Relaxed);
+        self.auto_start.store(protocol.auto_start, Ordering::Relaxed);
+        self.strict.store(protocol.strict, Ordering::Relaxed);
+        *self.unsolicited.lock().unwrap() = Arc::new(protocol.unsolicited.clone());
+        *self.angle_sentinels.lock().unwrap() = Arc::new(protocol.angle_sentinels.clone());
+    }
+
+    fn violations(&self) -> &Violations {
+        &self.violations
+    }
+
+    fn max_angle(&self) -> u16 {
+        self.max_angle.load(Ordering::Relaxed)
+    }
+
+    fn end_chain(&self) -> Result<()> {
+        let _turn = self.queue.enter("MOVE (end of chain)")?;
+        let mut port = self.port.lock().unwrap();
+        let result = self.finish_chain(&mut port);
+        if let Err(ref e) = result {
+            error!(command = "move", error = %e, "Serial communication error");
         }
+        result
+    }
 
-        let hex_channel = Self::channel_to_hex(channel);
-        let cmd = format!("S{}:{}\n", hex_channel, angle);
-        let response = self.send_command(&cmd)?;
+    fn start_serial_mode(&self) -> Result<()> {
+        info!("Entering serial mode");
+        self.switch_mode(SerialMode::Serial, false)
+    }
+
+    fn stop_serial_mode(&self) -> Result<()> {
+        info!("Exiting serial mode");
+        self.switch_mode(SerialMode::Button, false)
+    }
+
+    fn stop_for_idle(&self) -> Result<()> {
+        info!("Idle, exiting serial mode");
+        self.switch_mode(SerialMode::Button, true)
+    }
+
+    fn idle_stopped(&self) -> bool {
+        self.idle_stopped.load(Ordering::Relaxed)
+    }
+
+    fn mode(&self) -> Option<SerialMode> {
+        *self.mode.lock().unwrap()
+    }
+
+    fn mode_refused(&self) -> bool {
+        self.mode_refused.load(Ordering::Relaxed)
+    }
+
+    fn set_servo_angle(
+        &self,
+        channel: Channel,
+        angle: Angle,
+        opts: CommandOptions,
+    ) -> Result<()> {
+        self.check_angles(&[angle])?;
+
+        let extended = self.extended_angles.load(Ordering::Relaxed);
+        let cmd = encode_set_angle(channel, angle, extended);
+        let response = self.send_command_with(&cmd, opts)?;
 
         if response.trim() == "OK" {
             Ok(())
@@ -157,18 +1069,18 @@ impl SerialManager {
         }
     }
 
-    /// Set servo PWM pulse width (0-20000 microseconds)
-    pub fn set_servo_pwm(&self, channel: u8, pulse_us: u16) -> Result<()> {
-        if channel >= NUM_SERVOS {
-            anyhow::bail!("Invalid servo channel: {}", channel);
-        }
+    fn set_servo_pwm(
+        &self,
+        channel: Channel,
+        pulse_us: u16,
+        opts: CommandOptions,
+    ) -> Result<()> {
         if pulse_us > 20000 {
             anyhow::bail!("Invalid pulse width: {} (must be 0-20000)", pulse_us);
         }
 
-        let hex_channel = Self::channel_to_hex(channel);
-        let cmd = format!("P{}:{}\n", hex_channel, pulse_us);
-        let response = self.send_command(&cmd)?;
+        let cmd = encode_set_pwm(channel, pulse_us);
+        let response = self.send_command_with(&cmd, opts)?;
 
         if response.trim() == "OK" {
             Ok(())
@@ -177,25 +1089,18 @@ impl SerialManager {
         }
     }
 
-    /// Execute POSE command (set multiple servos instantly)
-    pub fn execute_pose(&self, angles: &[u8]) -> Result<()> {
+    fn execute_pose(&self, angles: &[Angle], opts: CommandOptions) -> Result<()> {
         if angles.len() > NUM_SERVOS as usize {
             anyhow::bail!("Too many servos: {} (max {})", angles.len(), NUM_SERVOS);
         }
+        self.check_angles(angles)?;
 
-        for &angle in angles {
-            if angle > 180 {
-                anyhow::bail!("Invalid angle: {} (must be 0-180)", angle);
-            }
+        let extended = self.extended_angles.load(Ordering::Relaxed);
+        let cmd = encode_pose(angles, extended);
+        if !self.fits(&cmd) {
+            return self.execute_pose_split(&cmd, angles, opts);
         }
-
-        let angles_str = angles.iter()
-            .map(|a| a.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
-
-        let cmd = format!("POSE {}\n", angles_str);
-        let response = self.send_command(&cmd)?;
+        let response = self.send_command_with(&cmd, opts)?;
 
         if response.trim() == "OK" {
             Ok(())
@@ -204,25 +1109,23 @@ impl SerialManager {
         }
     }
 
-    /// Execute MOVE command (smooth interpolated movement)
-    pub fn execute_move(&self, duration_ms: u16, angles: &[u8]) -> Result<()> {
+    fn execute_move(
+        &self,
+        duration_ms: u16,
+        angles: &[Angle],
+        opts: CommandOptions,
+    ) -> Result<()> {
         if angles.len() > NUM_SERVOS as usize {
             anyhow::bail!("Too many servos: {} (max {})", angles.len(), NUM_SERVOS);
         }
+        self.check_angles(angles)?;
 
-        for &angle in angles {
-            if angle > 180 {
-                anyhow::bail!("Invalid angle: {} (must be 0-180)", angle);
-            }
+        if opts.chain && self.move_lookahead.load(Ordering::Relaxed) {
+            return self.execute_move_chained(duration_ms, angles);
         }
-
-        let angles_str = angles.iter()
-            .map(|a| a.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
-
-        let cmd = format!("MOVE {} {}\n", duration_ms, angles_str);
-        let response = self.send_command(&cmd)?;
+        let extended = self.extended_angles.load(Ordering::Relaxed);
+        let cmd = encode_move(duration_ms, angles, extended);
+        let response = self.send_command_with(&cmd, opts)?;
 
         if response.trim() == "OK" {
             Ok(())
@@ -231,41 +1134,943 @@ impl SerialManager {
         }
     }
 
-    /// Get servo angle
-    pub fn get_servo_angle(&self, channel: u8) -> Result<u8> {
-        if channel >= NUM_SERVOS {
-            anyhow::bail!("Invalid servo channel: {}", channel);
+    fn get_servo_angle(&self, channel: Channel) -> Result<Option<Angle>> {
+        let response = self.send_command(&encode_get_angle(channel))?;
+
+        // Parse response: "SERVO 0: 90 degrees", or a variant of it
+        let sentinels = self.angle_sentinels.lock().unwrap().clone();
+        parse_angle(&response, channel, &sentinels).map_err(anyhow::Error::msg)
+    }
+
+    fn get_servo_adc(&self, channel: Channel) -> Result<Option<u32>> {
+        if !self.adc_query.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let response = self.send_command(&encode_adc(channel))?;
+        match parse_adc(&response) {
+            Some(counts) => Ok(Some(counts)),
+            None => anyhow::bail!("Failed to parse ADC counts from response: {}", response),
+        }
+    }
+
+    fn get_measured_angle(
+        &self,
+        channel: Channel,
+        feedback: Option<&FeedbackCalibration>,
+    ) -> Result<Option<Angle>> {
+        if self.angle_query.load(Ordering::Relaxed) {
+            return self.get_servo_angle(channel);
         }
+        let Some(feedback) = feedback else {
+            anyhow::bail!(
+                "The firmware has no angle query and channel {} no feedback calibration",
+                channel.get()
+            );
+        };
+        let Some(counts) = self.get_servo_adc(channel)? else {
+            anyhow::bail!("The firmware has neither an angle nor an ADC query");
+        };
+        let max_angle = self.max_angle();
+        let angle = Angle::new(feedback.angle(counts, max_angle), max_angle);
+        angle.map(Some).map_err(anyhow::Error::msg)
+    }
 
-        let hex_channel = Self::channel_to_hex(channel);
-        let cmd = format!("GET {}\n", hex_channel);
-        let response = self.send_command(&cmd)?;
+    fn get_servo_busy(&self, channel: Channel) -> Result<Option<bool>> {
+        if !self.busy_query.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
 
-        // Parse response: "SERVO 0: 90 degrees"
-        let parts: Vec<&str> = response.split_whitespace().collect();
-        if parts.len() >= 3 {
-            if let Ok(angle) = parts[2].parse::<u8>() {
-                return Ok(angle);
-            }
+        let response = self.send_command(&encode_busy(channel))?;
+        match parse_busy(&response) {
+            Some(busy) => Ok(Some(busy)),
+            None => anyhow::bail!("Failed to parse busy state from response: {}", response),
+        }
+    }
+
+    fn get_supply_voltage(&self) -> Result<Option<u32>> {
+        if !self.voltage_query.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let response = self.send_command(&encode_voltage())?;
+        match parse_voltage(&response) {
+            Some(millivolts) => Ok(Some(millivolts)),
+            None => anyhow::bail!("Failed to parse supply voltage from response: {}", response),
+        }
+    }
+
+    fn get_faults(&self) -> Result<Option<u32>> {
+        if !self.fault_register.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let response = self.send_command(&encode_faults())?;
+        match parse_faults(&response) {
+            Some(register) => Ok(Some(register)),
+            None => anyhow::bail!("Failed to parse fault register from response: {}", response),
+        }
+    }
+
+    fn clear_faults(&self) -> Result<bool> {
+        if !self.fault_register.load(Ordering::Relaxed) {
+            return Ok(false);
         }
 
-        anyhow::bail!("Failed to parse servo angle from response: {}", response);
+        let response = self.send_command(&encode_clear_faults())?;
+        if response.trim() == "OK" {
+            Ok(true)
+        } else {
+            anyhow::bail!("Failed to clear fault register: {}", response);
+        }
+    }
+
+    fn get_speed_limit(&self) -> Result<Option<u16>> {
+        if !self.speed_limit.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let response = self.send_command(&encode_speed_limit())?;
+        match parse_speed_limit(&response) {
+            Some(limit) => Ok(Some(limit)),
+            None => anyhow::bail!("Failed to parse speed limit from response: {}", response),
+        }
+    }
+
+    fn set_speed_limit(&self, limit: u16) -> Result<bool> {
+        if !self.speed_limit.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+
+        let response = self.send_command(&encode_set_speed_limit(limit))?;
+        if response.trim() == "OK" {
+            Ok(true)
+        } else {
+            anyhow::bail!("Failed to set speed limit: {}", response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::config::Config;
+    use std::collections::VecDeque;
+    use tokio_serial::{ClearBuffer, DataBits, FlowControl, Parity, StopBits};
+
+    /// A command as the emulated firmware ran it
+    #[derive(Debug, Clone)]
+    struct Run {
+        line: String,
+        written: Instant,
+        start: Instant,
+        end: Instant,
+    }
+
+    /// What the emulated firmware replies to
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Answers {
+        All,
+        /// Everything but motion commands until `START`, like firmware in
+        /// button mode
+        Reads,
+        Nothing,
+    }
+
+    type Reply = Vec<(u64, &'static [u8])>;
+
+    /// Firmware that takes `one_way` to receive a line and again to send
+    /// its reply, running each command once the one before has ended
+    ///
+    /// Time passes on `clock` only while reading waits for a reply or the
+    /// manager sleeps on it, so every timing is exact.
+    struct LatentPort {
+        clock: Arc<MockClock>,
+        one_way: Duration,
+        answers: Answers,
+        /// Replies to the first of a line other than `OK` (or an angle of
+        /// 90 to a GET), as chunks each arriving a delay in ms after the
+        /// command ended
+        replies: Vec<(&'static str, Reply)>,
+        line: Vec<u8>,
+        output: Mutex<VecDeque<(Instant, u8)>>,
+        busy_until: Instant,
+        runs: Arc<Mutex<Vec<Run>>>,
+        timeout: Duration,
+    }
+
+    impl LatentPort {
+        fn receive(&mut self, line: String) {
+            let written = self.clock.now();
+            let start = (written + self.one_way).max(self.busy_until);
+            let duration = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["MOVE", ms, ..] => Duration::from_millis(ms.parse().unwrap()),
+                _ => Duration::ZERO,
+            };
+            let end = start + duration;
+            self.busy_until = end;
+            if line == "START" && self.answers == Answers::Reads {
+                self.answers = Answers::All;
+            }
+            let answered = match self.answers {
+                Answers::All => true,
+                Answers::Reads => !moves_arm(&line),
+                Answers::Nothing => false,
+            };
+            if answered {
+                let ready_at = end + self.one_way;
+                let reply: Vec<(u64, Vec<u8>)> =
+                    match self.replies.iter().position(|(to, _)| *to == line) {
+                        Some(position) => {
+                            let reply = self.replies.remove(position).1;
+                            reply.into_iter().map(|(at, b)| (at, b.to_vec())).collect()
+                        }
+                        None => match line.strip_prefix("GET ") {
+                            Some(channel) => vec![(0, format!("SERVO {}: 90\n", channel).into())],
+                            None => vec![(0, b"OK\n".to_vec())],
+                        },
+                    };
+                let output = self.output.get_mut().unwrap();
+                for (delay_ms, bytes) in reply {
+                    let at = ready_at + Duration::from_millis(delay_ms);
+                    output.extend(bytes.into_iter().map(|byte| (at, byte)));
+                }
+            }
+            self.runs.lock().unwrap().push(Run {
+                line,
+                written,
+                start,
+                end,
+            });
+        }
     }
 
-    /// Get all servo angles
-    pub fn get_all_servos(&self) -> Result<Vec<(u8, u8)>> {
-        let mut servos = Vec::new();
+    impl Read for LatentPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let timed_out =
+                || std::io::Error::new(std::io::ErrorKind::TimedOut, "Operation timed out");
+            let Some(&(ready_at, _)) = self.output.get_mut().unwrap().front() else {
+                self.clock.advance(self.timeout);
+                return Err(timed_out());
+            };
+            let wait = ready_at.saturating_duration_since(self.clock.now());
+            if wait > self.timeout {
+                self.clock.advance(self.timeout);
+                return Err(timed_out());
+            }
+            self.clock.advance(wait);
+            let now = self.clock.now();
+            let output = self.output.get_mut().unwrap();
+            let ready = output.iter().take_while(|&&(at, _)| at <= now).count();
+            let n = buf.len().min(ready);
+            for (slot, (_, byte)) in buf.iter_mut().zip(output.drain(..n)) {
+                *slot = byte;
+            }
+            Ok(n)
+        }
+    }
 
-        for channel in 0..NUM_SERVOS {
-            match self.get_servo_angle(channel) {
-                Ok(angle) => servos.push((channel, angle)),
-                Err(e) => {
-                    error!("Failed to get angle for servo {}: {}", channel, e);
-                    // Continue with other servos
+    impl Write for LatentPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            for &byte in buf {
+                if byte == b'\n' {
+                    let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                    self.line.clear();
+                    self.receive(line);
+                } else {
+                    self.line.push(byte);
                 }
             }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SerialPort for LatentPort {
+        fn name(&self) -> Option<String> {
+            Some("latent".to_string())
+        }
+
+        fn baud_rate(&self) -> tokio_serial::Result<u32> {
+            Ok(115200)
+        }
+
+        fn data_bits(&self) -> tokio_serial::Result<DataBits> {
+            Ok(DataBits::Eight)
+        }
+
+        fn flow_control(&self) -> tokio_serial::Result<FlowControl> {
+            Ok(FlowControl::None)
+        }
+
+        fn parity(&self) -> tokio_serial::Result<Parity> {
+            Ok(Parity::None)
+        }
+
+        fn stop_bits(&self) -> tokio_serial::Result<StopBits> {
+            Ok(StopBits::One)
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn set_baud_rate(&mut self, _: u32) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn set_data_bits(&mut self, _: DataBits) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn set_flow_control(&mut self, _: FlowControl) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn set_parity(&mut self, _: Parity) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn set_stop_bits(&mut self, _: StopBits) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> tokio_serial::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn write_request_to_send(&mut self, _: bool) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn write_data_terminal_ready(&mut self, _: bool) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn read_clear_to_send(&mut self) -> tokio_serial::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_data_set_ready(&mut self) -> tokio_serial::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_ring_indicator(&mut self) -> tokio_serial::Result<bool> {
+            Ok(false)
+        }
+
+        fn read_carrier_detect(&mut self) -> tokio_serial::Result<bool> {
+            Ok(true)
+        }
+
+        fn bytes_to_read(&self) -> tokio_serial::Result<u32> {
+            let now = self.clock.now();
+            let output = self.output.lock().unwrap();
+            Ok(output.iter().take_while(|&&(at, _)| at <= now).count() as u32)
+        }
+
+        fn bytes_to_write(&self) -> tokio_serial::Result<u32> {
+            Ok(0)
+        }
+
+        fn clear(&self, buffer: ClearBuffer) -> tokio_serial::Result<()> {
+            if buffer != ClearBuffer::Output {
+                let now = self.clock.now();
+                self.output.lock().unwrap().retain(|&(at, _)| at > now);
+            }
+            Ok(())
+        }
+
+        fn try_clone(&self) -> tokio_serial::Result<Box<dyn SerialPort>> {
+            Err(tokio_serial::Error::new(
+                tokio_serial::ErrorKind::Unknown,
+                "A latent port can't be cloned",
+            ))
+        }
+
+        fn set_break(&self) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+
+        fn clear_break(&self) -> tokio_serial::Result<()> {
+            Ok(())
+        }
+    }
+
+    type Runs = Arc<Mutex<Vec<Run>>>;
+
+    /// A manager on firmware with a round trip of twice `one_way`
+    fn connect(one_way_ms: u64, configure: impl FnOnce(&mut Config)) -> (SerialManager, Runs) {
+        connect_to(Answers::All, Vec::new(), one_way_ms, configure)
+    }
+
+    /// A manager on firmware that replies to `answers`, with `replies`
+    fn connect_to(
+        answers: Answers,
+        replies: Vec<(&'static str, Reply)>,
+        one_way_ms: u64,
+        configure: impl FnOnce(&mut Config),
+    ) -> (SerialManager, Runs) {
+        let mut config = Config::default();
+        config.timeouts.response_delay_ms = 0;
+        config.timeouts.command_ms = 1000;
+        config.protocol.move_lookahead = true;
+        configure(&mut config);
+        let runs = Runs::default();
+        let clock = Arc::new(MockClock::new());
+        let port = LatentPort {
+            clock: clock.clone(),
+            one_way: Duration::from_millis(one_way_ms),
+            answers,
+            replies,
+            line: Vec::new(),
+            output: Mutex::default(),
+            busy_until: clock.now(),
+            runs: runs.clone(),
+            timeout: Duration::from_millis(50),
+        };
+        let observers = Observers {
+            connection: Arc::new(ConnectionLog::new(None)),
+            unsolicited: broadcast::channel(16).0,
+            clock,
+        };
+        let serial = SerialManager::with_port(
+            Box::new(port),
+            &config.serial,
+            &config.timeouts,
+            &config.protocol,
+            observers,
+        )
+        .unwrap();
+        runs.lock().unwrap().clear();
+        (serial, runs)
+    }
+
+    const CHAINED: CommandOptions = CommandOptions {
+        clear_input: None,
+        chain: true,
+    };
+
+    /// Play `segments` MOVEs of `duration_ms`, returning the pause the
+    /// firmware made before each but the first
+    fn play(serial: &SerialManager, runs: &Runs, segments: u16, duration_ms: u16) -> Vec<Duration> {
+        for i in 0..segments {
+            let angles = [Angle::new(10 * i, 180).unwrap()];
+            serial.execute_move(duration_ms, &angles, CHAINED).unwrap();
+        }
+        serial.end_chain().unwrap();
+        let runs = runs.lock().unwrap();
+        assert_eq!(runs.len(), segments as usize);
+        runs.windows(2)
+            .map(|pair| pair[1].start.saturating_duration_since(pair[0].end))
+            .collect()
+    }
+
+    /// When each segment was written, after the first
+    fn written(runs: &Runs) -> Vec<Duration> {
+        let runs = runs.lock().unwrap();
+        runs.iter()
+            .map(|run| run.written - runs[0].written)
+            .collect()
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn chained_segments_follow_without_a_pause() {
+        let (serial, runs) = connect(20, |_| {});
+        let gaps = play(&serial, &runs, 6, 80);
+        assert_eq!(gaps, [Duration::ZERO; 5]);
+        // The second segment goes out half the assumed 20ms round trip
+        // before the first ends, the rest half the measured 40ms; each
+        // segment runs from 20ms after the first was written
+        assert_eq!(
+            written(&runs),
+            [ms(0), ms(70), ms(160), ms(240), ms(320), ms(400)]
+        );
+    }
+
+    #[test]
+    fn queued_segments_are_sent_a_round_trip_early() {
+        let (serial, runs) = connect(20, |config| config.protocol.move_queue = true);
+        let gaps = play(&serial, &runs, 6, 80);
+        assert_eq!(gaps, [Duration::ZERO; 5]);
+        // A whole round trip early, 20ms assumed then 40ms measured
+        assert_eq!(
+            written(&runs),
+            [ms(0), ms(60), ms(140), ms(220), ms(300), ms(380)]
+        );
+    }
+
+    #[test]
+    fn unchained_segments_pause_for_the_round_trip() {
+        let (serial, runs) = connect(20, |config| config.protocol.move_lookahead = false);
+        let gaps = play(&serial, &runs, 4, 80);
+        assert_eq!(gaps, [ms(40); 3]);
+    }
+
+    #[test]
+    fn lead_follows_the_measured_round_trip() {
+        let (serial, runs) = connect(40, |_| {});
+        assert_eq!(serial.move_latency(), DEFAULT_MOVE_LATENCY);
+        assert_eq!(serial.chain_lead(), DEFAULT_MOVE_LATENCY / 2);
+
+        // The first measurement replaces the assumption; only the first
+        // segment of a chain is measured
+        let gaps = play(&serial, &runs, 3, 30);
+        let measured = serial.move_latency();
+        assert_eq!(measured, ms(80));
+        // Segments shorter than the round trip can't hide it
+        assert_eq!(gaps, [ms(0), ms(50)]);
+        assert_eq!(serial.chain_lead(), ms(40));
+        serial.move_queue.store(true, Ordering::Relaxed);
+        assert_eq!(serial.chain_lead(), ms(80));
+
+        // Later ones are averaged in
+        serial.record_move_latency(ms(120));
+        assert_eq!(serial.move_latency(), ms(90));
+    }
+
+    #[test]
+    fn other_commands_wait_for_the_chained_segment() {
+        let (serial, runs) = connect(10, |_| {});
+        let angles = [Angle::new(20, 180).unwrap()];
+        serial.execute_move(100, &angles, CHAINED).unwrap();
+        // Returned a lead before the end, like a stopped chain would
+        serial
+            .execute_pose(&angles, CommandOptions::default())
+            .unwrap();
+
+        let runs = runs.lock().unwrap();
+        let [segment, pose] = &runs[..] else {
+            panic!("{:?}", runs);
+        };
+        assert!(segment.line.starts_with("MOVE 100"));
+        assert!(pose.line.starts_with("POSE"));
+        // The segment's reply was read, a one-way trip after it ended,
+        // before the next command was sent
+        assert_eq!(pose.written, segment.end + ms(10));
+        assert!(serial.chained.lock().unwrap().is_none());
+    }
+
+    fn lines(runs: &Runs) -> Vec<String> {
+        let runs = runs.lock().unwrap();
+        runs.iter().map(|run| run.line.clone()).collect()
+    }
+
+    /// Firmware in button mode, with short timeouts
+    fn in_button_mode(auto_start: bool) -> (SerialManager, Runs) {
+        connect_to(Answers::Reads, Vec::new(), 0, |config| {
+            config.timeouts.command_ms = 100;
+            config.protocol.auto_start = auto_start;
+        })
+    }
+
+    #[test]
+    fn ignored_motion_is_told_from_a_timeout() {
+        let (serial, runs) = in_button_mode(false);
+        let angles = [Angle::new(20, 180).unwrap(), Angle::new(30, 180).unwrap()];
+        let error = serial
+            .execute_pose(&angles, CommandOptions::default())
+            .unwrap_err();
+        assert!(is_ignored_in_button_mode(&error), "{:#}", error);
+        assert_eq!(serial.mode(), Some(SerialMode::Button));
+        // A single probe, which isn't probed in turn
+        assert_eq!(lines(&runs), ["POSE 20,30", "GET 0"]);
+
+        let channel = Channel::new(1, NUM_SERVOS).unwrap();
+        let error = serial
+            .set_servo_angle(channel, angles[0], CommandOptions::default())
+            .unwrap_err();
+        assert!(is_ignored_in_button_mode(&error), "{:#}", error);
+        // A chained segment's reply is checked when the chain ends
+        serial.execute_move(500, &angles, CHAINED).unwrap();
+        let error = serial.end_chain().unwrap_err();
+        assert!(is_ignored_in_button_mode(&error), "{:#}", error);
+        assert_eq!(
+            lines(&runs)[2..],
+            ["S1:20", "GET 0", "MOVE 500 20,30", "GET 0"]
+        );
+    }
+
+    #[test]
+    fn auto_start_enters_serial_mode_and_retries() {
+        let (serial, runs) = in_button_mode(true);
+        let angles = [Angle::new(20, 180).unwrap()];
+        serial
+            .execute_pose(&angles, CommandOptions::default())
+            .unwrap();
+        assert_eq!(serial.mode(), Some(SerialMode::Serial));
+        assert_eq!(lines(&runs), ["POSE 20", "GET 0", "START", "POSE 20"]);
+
+        // Serial mode from now on, without another probe
+        serial
+            .execute_pose(&angles, CommandOptions::default())
+            .unwrap();
+        assert_eq!(lines(&runs)[4..], ["POSE 20"]);
+    }
+
+    #[test]
+    fn only_an_unknown_start_means_serial_mode_already() {
+        let replies = vec![(
+            "START",
+            vec![(
+                0,
+                b"ERROR: Unknown command (type HELP for list)\n".as_slice(),
+            )],
+        )];
+        let (serial, _) = connect_to(Answers::All, replies, 0, |_| {});
+        serial.start_serial_mode().unwrap();
+        assert_eq!(serial.mode(), Some(SerialMode::Serial));
+
+        // A garbled START draws another error, which tells nothing
+        let replies = vec![(
+            "START",
+            vec![(0, b"ERROR: Invalid command format\n".as_slice())],
+        )];
+        let (serial, _) = connect_to(Answers::All, replies, 0, |_| {});
+        let error = serial.start_serial_mode().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to enter serial mode: ERROR: Invalid command format"
+        );
+        assert!(!is_mode_refused(&error));
+        assert_eq!(serial.mode(), None);
+    }
+
+    #[test]
+    fn silent_firmware_is_a_plain_timeout() {
+        let (serial, runs) = connect_to(Answers::Nothing, Vec::new(), 0, |config| {
+            config.timeouts.command_ms = 100;
+            config.protocol.auto_start = true;
+        });
+        let angles = [Angle::new(20, 180).unwrap()];
+        let error = serial
+            .execute_pose(&angles, CommandOptions::default())
+            .unwrap_err();
+        assert!(!is_ignored_in_button_mode(&error), "{:#}", error);
+        assert_ne!(serial.mode(), Some(SerialMode::Button));
+        assert_eq!(lines(&runs), ["POSE 20", "GET 0"]);
+
+        // Reads without a reply aren't probed
+        let channel = Channel::new(2, NUM_SERVOS).unwrap();
+        assert!(serial.get_servo_angle(channel).is_err());
+        assert_eq!(lines(&runs)[2..], ["GET 2"]);
+    }
+
+    fn servo(channel: u8) -> Channel {
+        Channel::new(channel, NUM_SERVOS).unwrap()
+    }
+
+    /// Firmware that sends stray bytes around reads of channels 1-3
+    fn stray(strict: bool) -> (SerialManager, Runs) {
+        let replies = vec![
+            ("GET 1", vec![(0, b"SERVO 1: 90\nEXTRA\n".as_slice())]),
+            ("GET 2", vec![(0, b"SERVO 2:\x00 9\xff0\r\n".as_slice())]),
+            (
+                "GET 3",
+                vec![(0, b"SERVO 3: 90\n".as_slice()), (20, b"BOOT\n".as_slice())],
+            ),
+        ];
+        connect_to(Answers::All, replies, 0, |config| {
+            config.protocol.strict = strict;
+            config.protocol.unsolicited = vec!["HEARTBEAT *".to_string()];
+        })
+    }
+
+    fn counts(serial: &SerialManager) -> Vec<(&'static str, u64)> {
+        serial.violations().snapshot().0.into_iter().collect()
+    }
+
+    #[test]
+    fn stray_bytes_are_recorded_but_tolerated() {
+        let (serial, _) = stray(false);
+        let angle = |channel| serial.get_servo_angle(servo(channel)).unwrap();
+        assert_eq!(angle(1).map(Angle::get), Some(90));
+        assert_eq!(angle(2).map(Angle::get), Some(90));
+        assert_eq!(angle(3).map(Angle::get), Some(90));
+        serial
+            .observers
+            .clock
+            .sleep_blocking(Duration::from_millis(40));
+        // The line that arrived meanwhile is cleared, not read as the reply
+        assert_eq!(angle(4).map(Angle::get), Some(90));
+
+        let (counts, recent) = serial.violations().snapshot();
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [("non_printable", 1), ("trailing", 1), ("unsolicited", 1)]
+        );
+        let dumps: Vec<(ViolationKind, &str, &str)> = recent
+            .iter()
+            .map(|v| (v.kind, v.command.as_str(), v.hex.as_str()))
+            .collect();
+        assert_eq!(
+            dumps,
+            [
+                (ViolationKind::Unsolicited, "GET 4", "42 4f 4f 54 0a"),
+                (ViolationKind::NonPrintable, "GET 2", "00 ff"),
+                (ViolationKind::Trailing, "GET 1", "45 58 54 52 41 0a"),
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_mode_fails_the_command() {
+        let (serial, _) = stray(true);
+        let read = |channel| serial.get_servo_angle(servo(channel)).unwrap_err();
+        for channel in [1, 2] {
+            let error = read(channel);
+            assert!(is_protocol_violation(&error), "{:#}", error);
         }
+        serial.get_servo_angle(servo(3)).unwrap();
+        serial
+            .observers
+            .clock
+            .sleep_blocking(Duration::from_millis(40));
+        let error = read(4);
+        assert!(is_protocol_violation(&error), "{:#}", error);
+        assert!(error.to_string().contains("unsolicited"), "{:#}", error);
+        // Read once reported, so the next command goes through
+        serial.get_servo_angle(servo(4)).unwrap();
+        assert_eq!(
+            counts(&serial),
+            [("non_printable", 1), ("trailing", 1), ("unsolicited", 1)]
+        );
+    }
+
+    #[test]
+    fn unsolicited_patterns_are_no_violation() {
+        let replies = vec![(
+            "GET 1",
+            vec![
+                (0, b"SERVO 1: 90\nHEARTBEAT 7\n".as_slice()),
+                (20, b"HEARTBEAT 8\n".as_slice()),
+            ],
+        )];
+        let (serial, _) = connect_to(Answers::All, replies, 0, |config| {
+            config.protocol.strict = true;
+            config.protocol.unsolicited = vec!["HEARTBEAT *".to_string()];
+        });
+        serial.get_servo_angle(servo(1)).unwrap();
+        serial
+            .observers
+            .clock
+            .sleep_blocking(Duration::from_millis(40));
+        serial.get_servo_angle(servo(2)).unwrap();
+        assert!(counts(&serial).is_empty());
+    }
+
+    /// Firmware answering `ADC 1` with `reply`, with `configure`d queries
+    fn adc(reply: &'static [u8], configure: impl FnOnce(&mut Config)) -> (SerialManager, Runs) {
+        let replies = vec![("ADC 1", vec![(0, reply)])];
+        connect_to(Answers::All, replies, 0, configure)
+    }
+
+    /// 1000 counts at 0 degrees, 3000 at 180
+    const FEEDBACK: FeedbackCalibration = FeedbackCalibration {
+        raw_a: 1000,
+        angle_a: 0,
+        raw_b: 3000,
+        angle_b: 180,
+    };
+
+    fn without_get(config: &mut Config) {
+        config.protocol.angle_query = false;
+        config.protocol.adc_query = true;
+    }
+
+    #[test]
+    fn angle_query_is_preferred_to_the_adc() {
+        let (serial, runs) = adc(b"ADC 1: 2000\n", |config| config.protocol.adc_query = true);
+        let angle = serial
+            .get_measured_angle(servo(1), Some(&FEEDBACK))
+            .unwrap();
+        assert_eq!(angle.map(Angle::get), Some(90));
+        assert_eq!(lines(&runs), ["GET 1"]);
+    }
+
+    #[test]
+    fn firmware_without_get_is_read_through_the_adc() {
+        let (serial, runs) = adc(b"ADC 1: 2500\n", without_get);
+        let angle = serial
+            .get_measured_angle(servo(1), Some(&FEEDBACK))
+            .unwrap();
+        assert_eq!(angle.map(Angle::get), Some(135));
+        assert_eq!(lines(&runs), ["ADC 1"]);
+    }
+
+    #[test]
+    fn adc_reads_need_a_calibration_and_the_query() {
+        let (serial, runs) = adc(b"ADC 1: 2000\n", without_get);
+        let error = serial.get_measured_angle(servo(1), None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The firmware has no angle query and channel 1 no feedback calibration"
+        );
+        assert!(lines(&runs).is_empty());
+
+        let (serial, runs) = adc(b"ADC 1: 2000\n", |config| {
+            config.protocol.angle_query = false;
+        });
+        assert_eq!(serial.get_servo_adc(servo(1)).unwrap(), None);
+        let error = serial
+            .get_measured_angle(servo(1), Some(&FEEDBACK))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The firmware has neither an angle nor an ADC query"
+        );
+        assert!(lines(&runs).is_empty());
+    }
+
+    #[test]
+    fn adc_counts_are_saturated_or_rejected() {
+        let (serial, _) = adc(b"ADC 1: 4000\n", without_get);
+        let angle = serial
+            .get_measured_angle(servo(1), Some(&FEEDBACK))
+            .unwrap();
+        assert_eq!(angle.map(Angle::get), Some(180));
+
+        let (serial, _) = adc(b"ADC 1: lots\n", without_get);
+        let error = serial.get_servo_adc(servo(1)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to parse ADC counts from response: ADC 1: lots"
+        );
+    }
+
+    fn angles(degrees: &[u16]) -> Vec<Angle> {
+        degrees
+            .iter()
+            .map(|&d| Angle::new(d, 180).unwrap())
+            .collect()
+    }
+
+    const SIX: [u16; 6] = [100, 110, 120, 130, 140, 150];
+
+    #[test]
+    fn commands_are_measured_with_the_line_ending() {
+        let (serial, runs) = connect(0, |_| {});
+        // "MOVE 10 100,110,120,130,140,150\n" fills the 32 bytes exactly
+        serial
+            .execute_move(10, &angles(&SIX), CommandOptions::default())
+            .unwrap();
+        assert_eq!(lines(&runs), ["MOVE 10 100,110,120,130,140,150"]);
+
+        let error = serial
+            .execute_move(100, &angles(&SIX), CommandOptions::default())
+            .unwrap_err();
+        assert!(is_command_too_long(&error), "{:#}", error);
+        assert_eq!(
+            error.to_string(),
+            "Command too long for the firmware: \"MOVE 100 100,110,120,130,140,150\" is 33 bytes \
+             with framing and line ending, the firmware buffers 32 (protocol.max_command_len)"
+        );
+        // Refused before anything was sent
+        assert_eq!(lines(&runs).len(), 1);
+        let error = serial.execute_move_chained(100, &angles(&SIX)).unwrap_err();
+        assert!(is_command_too_long(&error), "{:#}", error);
+        assert_eq!(lines(&runs).len(), 1);
+    }
+
+    #[test]
+    fn framing_counts_towards_the_length() {
+        let (serial, runs) = connect(0, |config| {
+            config.protocol.command_prefix = "#".to_string();
+            config.protocol.command_suffix = "$".to_string();
+            config.protocol.max_command_len = 33;
+        });
+        // 32 bytes unframed, 34 framed
+        let error = serial
+            .execute_move(10, &angles(&SIX), CommandOptions::default())
+            .unwrap_err();
+        assert!(is_command_too_long(&error), "{:#}", error);
+        assert!(error.to_string().contains("is 34 bytes"), "{:#}", error);
+        serial
+            .execute_move(10, &angles(&SIX[..5]), CommandOptions::default())
+            .unwrap();
+        assert_eq!(lines(&runs), ["#MOVE 10 100,110,120,130,140$"]);
+    }
+
+    #[test]
+    fn long_pose_is_split_into_angle_commands() {
+        // "POSE 100,110,120\n" is 17 bytes
+        let (serial, runs) = connect(0, |config| config.protocol.max_command_len = 16);
+        serial
+            .execute_pose(&angles(&[10, 20]), CommandOptions::default())
+            .unwrap();
+        serial
+            .execute_pose(&angles(&SIX[..3]), CommandOptions::default())
+            .unwrap();
+        assert_eq!(lines(&runs), ["POSE 10,20", "S0:100", "S1:110", "S2:120"]);
+
+        let (serial, runs) = connect(0, |config| {
+            config.protocol.command_prefix = "#".to_string();
+            config.protocol.max_command_len = 16;
+        });
+        serial
+            .execute_pose(&angles(&[100, 110]), CommandOptions::default())
+            .unwrap();
+        // "#POSE 100,110\n" fits, "#POSE 100,110,120\n" doesn't
+        serial
+            .execute_pose(&angles(&SIX[..3]), CommandOptions::default())
+            .unwrap();
+        assert_eq!(
+            lines(&runs),
+            ["#POSE 100,110", "#S0:100", "#S1:110", "#S2:120"]
+        );
+    }
+
+    #[test]
+    fn split_pose_stops_at_the_first_refusal() {
+        let replies = vec![("S1:110", vec![(0, b"ERROR: busy\n".as_slice())])];
+        let (serial, runs) = connect_to(Answers::All, replies, 0, |config| {
+            config.protocol.max_command_len = 16;
+        });
+        let error = serial
+            .execute_pose(&angles(&SIX), CommandOptions::default())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Failed to execute POSE: ERROR: busy");
+        assert_eq!(lines(&runs), ["S0:100", "S1:110"]);
+
+        // An angle command that doesn't fit either refuses the whole POSE
+        let (serial, runs) = connect(0, |config| config.protocol.max_command_len = 6);
+        let error = serial
+            .execute_pose(&angles(&[100, 10]), CommandOptions::default())
+            .unwrap_err();
+        assert!(is_command_too_long(&error), "{:#}", error);
+        assert!(lines(&runs).is_empty());
+    }
 
-        Ok(servos)
+    #[tokio::test(flavor = "multi_thread")]
+    async fn command_too_long_is_refused_with_the_alternative() {
+        let server = crate::testing::TestServer::start().await;
+        server.mock.fail_next(&format!(
+            "{}: \"MOVE 100 ..\" is 33 bytes",
+            COMMAND_TOO_LONG
+        ));
+        let reply = server
+            .post(
+                "/api/move",
+                serde_json::json!({ "duration_ms": 100, "angles": SIX }),
+            )
+            .await;
+        assert_eq!(reply.status, 422);
+        assert_eq!(reply.code(), "COMMAND_TOO_LONG");
+        assert!(
+            reply.body["error"].as_str().unwrap().ends_with(
+                "send fewer channels per MOVE, or a POSE, which is split into angle commands"
+            ),
+            "{}",
+            reply.body
+        );
     }
 }
@@ -0,0 +1,1056 @@
+use anyhow::{Context, Result};
+use axum::{middleware, Router};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_serial::SerialPort;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::{Any, CorsLayer};
+use tracing::{error, info, warn};
+
+use crate::audit::{self, AuditLog};
+use crate::clock::{Clock, SystemClock};
+use crate::compensation::Compensation;
+use crate::config::{Config, SerialConfig};
+use crate::connection::{ConnectionLog, ConnectionState};
+use crate::controller::ServoController;
+use crate::demo::{self, MotionActivity};
+use crate::handlers::{self, AppState};
+use crate::library::Library;
+use crate::lockout::ChannelLockout;
+use crate::persist::{self, Persister};
+use crate::poller::{self, Poller};
+use crate::replication::{self, Replication};
+use crate::routes::{self, Auth, RouteInfo, Routes};
+use crate::serial::{Observers, SerialManager};
+use crate::wear::{self, Wear};
+use crate::{attract, backup, idle_exit, last_pose, library_watch, uds};
+
+/// Unsolicited lines kept for a serial monitor that falls behind
+const UNSOLICITED_BACKLOG: usize = 64;
+
+/// Opens the link to the firmware in place of the serial port, e.g. a
+/// bridge over the network; called again for every reconnect
+pub type Transport = Arc<dyn Fn() -> Result<Box<dyn SerialPort>> + Send + Sync>;
+
+/// Connects to the arm with the current config, at startup and for every
+/// reconnect
+pub(crate) type Connect =
+    Arc<dyn Fn(&Config, &Observers) -> Result<Arc<dyn ServoController>> + Send + Sync>;
+
+/// Open the serial port, the simulated arm in simulation mode, or a
+/// custom transport
+fn connect(
+    serial: &SerialConfig,
+    simulate: bool,
+    config: &Config,
+    observers: &Observers,
+    transport: Option<&Transport>,
+) -> Result<SerialManager> {
+    let observers = observers.clone();
+    if let Some(transport) = transport {
+        let port = transport().context("Failed to open the transport")?;
+        SerialManager::with_port(port, serial, &config.timeouts, &config.protocol, observers)
+    } else if simulate {
+        SerialManager::simulated(
+            serial,
+            &config.timeouts,
+            &config.protocol,
+            &config.simulation,
+            observers,
+        )
+    } else {
+        SerialManager::new(serial, &config.timeouts, &config.protocol, observers)
+    }
+}
+
+/// The middleware every served router gets, around the routes' own layers
+fn with_layers(router: Router<Arc<AppState>>, state: &Arc<AppState>) -> Router {
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any);
+
+    router
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            handlers::sign_response,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            handlers::account_usage,
+        ))
+        .layer(middleware::from_fn(handlers::tag_requester))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            handlers::apply_override,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            handlers::check_scope,
+        ))
+        // Outside the requester tag, as the task the handler runs in
+        // doesn't inherit it
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            handlers::request_timeout,
+        ))
+        .layer(cors)
+        .with_state(state.clone())
+}
+
+/// The API: the router for `bind_addr`, the one for `readonly_bind_addr`
+/// and the inventory for `GET /api/routes`
+fn api_routes(
+    state: &Arc<AppState>,
+) -> (Router<Arc<AppState>>, Router<Arc<AppState>>, Vec<RouteInfo>) {
+    // Write commands answering with a plain success status, which may be
+    // sent as 204 No Content instead
+    let commands = Routes::new()
+        // Serial mode control
+        .post("/api/serial/start", handlers::start_serial_mode, Auth::Motion, "Enter serial mode")
+        .post("/api/serial/stop", handlers::stop_serial_mode, Auth::Motion, "Return to button mode")
+        // Single servo control
+        .post(
+            "/api/servo/:id/angle",
+            handlers::set_servo_angle,
+            Auth::Motion,
+            "Set a servo's angle",
+        )
+        .post("/api/servo/:id/pwm", handlers::set_servo_pwm, Auth::Motion, "Set a raw pulse width")
+        .post(
+            "/api/servos/batch",
+            handlers::set_servos,
+            Auth::Motion,
+            "Set several servo angles, reporting each channel",
+        )
+        .post(
+            "/api/servo/:id/enable",
+            handlers::enable_channel,
+            Auth::Admin,
+            "Lift a channel's lockout",
+        )
+        .post(
+            "/api/servo/:id/disable",
+            handlers::disable_channel,
+            Auth::Admin,
+            "Lock a channel out",
+        )
+        .post("/api/output/:id", handlers::set_output, Auth::Motion, "Drive a PWM output channel")
+        // Multi-servo commands
+        .post("/api/pose", handlers::execute_pose, Auth::Motion, "Set several servos at once")
+        .post(
+            "/api/pose/named",
+            handlers::execute_named_pose,
+            Auth::Motion,
+            "Set servos by joint name",
+        )
+        .post("/api/move", handlers::execute_move, Auth::Motion, "Move servos over a duration")
+        .post("/api/home", handlers::go_home, Auth::Motion, "Move to the home pose")
+        // Saved poses and sequences
+        .get("/api/poses/:name", handlers::get_pose, Auth::Read, "A saved pose")
+        .put("/api/poses/:name", handlers::save_pose, Auth::Config, "Save a pose")
+        .delete("/api/poses/:name", handlers::delete_pose, Auth::Config, "Delete a saved pose")
+        .post(
+            "/api/poses/:name/execute",
+            handlers::execute_saved_pose,
+            Auth::Motion,
+            "Move to a saved pose",
+        )
+        .post(
+            "/api/poses/:name/adjusted",
+            handlers::execute_adjusted_pose,
+            Auth::Motion,
+            "Move to a saved pose with offsets on some channels",
+        )
+        .get("/api/sequences/:name", handlers::get_sequence, Auth::Read, "A saved sequence")
+        .put("/api/sequences/:name", handlers::save_sequence, Auth::Config, "Save a sequence")
+        .delete(
+            "/api/sequences/:name",
+            handlers::delete_sequence,
+            Auth::Config,
+            "Delete a saved sequence",
+        )
+        .post(
+            "/api/sequences/:name/execute",
+            handlers::execute_sequence,
+            Auth::Motion,
+            "Run a saved sequence",
+        )
+        .post(
+            "/api/sequence/stream",
+            handlers::stream_sequence,
+            Auth::Motion,
+            "Play newline-delimited JSON steps as they arrive",
+        )
+        .map(|router| {
+            router.route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                handlers::minimal_response,
+            ))
+        });
+
+    commands
+        // Health check
+        .merge(
+            Routes::new()
+                .get("/api/health", handlers::health_check, Auth::Read, "Overall status")
+                .get(
+                    "/api/capabilities",
+                    handlers::get_capabilities,
+                    Auth::Read,
+                    "Enabled features and firmware details",
+                )
+                .get(
+                    "/api/schema",
+                    handlers::get_schema,
+                    Auth::Read,
+                    "Ranges and units of command parameters",
+                )
+                .get(
+                    "/api/protocol",
+                    handlers::get_protocol,
+                    Auth::Read,
+                    "The firmware command set",
+                )
+                .get(
+                    "/api/protocol/violations",
+                    handlers::get_protocol_violations,
+                    Auth::Read,
+                    "Unexplained bytes from the firmware",
+                )
+                .post(
+                    "/api/serial/benchmark",
+                    handlers::run_benchmark,
+                    Auth::Admin,
+                    "Flood the link with one command and measure the rate it sustains",
+                )
+                .get("/api/routes", handlers::get_routes, Auth::Read, "This list of routes")
+                // Queries
+                .get("/api/servo/:id", handlers::get_servo_position, Auth::Read, "Read a servo")
+                .get(
+                    "/api/servo/:id/busy",
+                    handlers::get_servo_busy,
+                    Auth::Read,
+                    "Whether a servo is still moving",
+                )
+                .get(
+                    "/api/servo/:id/state",
+                    handlers::get_servo_state,
+                    Auth::Read,
+                    "Everything known about one servo",
+                )
+                .get(
+                    "/api/servo/:id/feedback",
+                    handlers::get_servo_feedback,
+                    Auth::Read,
+                    "Raw feedback ADC counts of a servo and the angle they map to",
+                )
+                .post(
+                    "/api/servo/:id/feedback/calibrate",
+                    handlers::calibrate_feedback,
+                    Auth::Motion,
+                    "Calibrate a servo's feedback ADC at two angles",
+                )
+                .get(
+                    "/api/move/:id/progress",
+                    handlers::get_move_progress,
+                    Auth::Read,
+                    "Progress of a tracked move",
+                )
+                .post(
+                    "/api/move/:id/cancel",
+                    handlers::cancel_move,
+                    Auth::Motion,
+                    "Stop a tracked move",
+                )
+                .post(
+                    "/api/move/schedule",
+                    handlers::schedule_move,
+                    Auth::Motion,
+                    "Schedule a move for a future time",
+                )
+                .get("/api/schedule", handlers::get_schedule, Auth::Read, "Scheduled moves")
+                .delete(
+                    "/api/schedule/:id",
+                    handlers::cancel_scheduled_move,
+                    Auth::Motion,
+                    "Cancel a scheduled move",
+                )
+                .post("/api/pattern", handlers::start_pattern, Auth::Motion, "Trace a pattern")
+                .get("/api/pattern", handlers::get_pattern, Auth::Read, "The last pattern run")
+                .delete(
+                    "/api/pattern",
+                    handlers::cancel_pattern,
+                    Auth::Motion,
+                    "Stop the running pattern",
+                )
+                .post("/api/script", handlers::run_script, Auth::Motion, "Start a script job")
+                .get("/api/script/:id", handlers::get_script, Auth::Read, "A script job")
+                .post(
+                    "/api/script/:id/cancel",
+                    handlers::cancel_script,
+                    Auth::Motion,
+                    "Stop a script job",
+                )
+                .get("/api/servos", handlers::get_all_servos, Auth::Read, "Read every servo")
+                .get(
+                    "/api/servos/error",
+                    handlers::get_tracking_error,
+                    Auth::Read,
+                    "Commanded versus measured angles",
+                )
+                .get(
+                    "/api/servos/stats",
+                    handlers::get_servo_stats,
+                    Auth::Read,
+                    "How often each channel is commanded",
+                )
+                .get(
+                    "/api/wear",
+                    handlers::get_wear,
+                    Auth::Read,
+                    "Range use, travel and duty of each servo",
+                )
+                .get(
+                    "/api/usage",
+                    handlers::get_usage,
+                    Auth::Read,
+                    "Use of the API by the requesting client this day and hour",
+                )
+                .get(
+                    "/api/usage/all",
+                    handlers::get_usage_all,
+                    Auth::Admin,
+                    "Use of the API by every client",
+                )
+                .get("/api/busy", handlers::get_busy, Auth::Read, "Motion estimates of the arm")
+                .get("/api/power", handlers::get_power, Auth::Read, "Supply voltage")
+                .get("/api/faults", handlers::get_faults, Auth::Read, "Latched firmware faults")
+                .post(
+                    "/api/faults/clear",
+                    handlers::clear_faults,
+                    Auth::Motion,
+                    "Clear the firmware fault register",
+                )
+                .get(
+                    "/api/firmware/speed_limit",
+                    handlers::get_speed_limit,
+                    Auth::Read,
+                    "Firmware slew-rate limit next to the software velocity limits",
+                )
+                .post(
+                    "/api/firmware/speed_limit",
+                    handlers::set_speed_limit,
+                    Auth::Admin,
+                    "Set the firmware slew-rate limit",
+                )
+                .get("/api/queue", handlers::get_queue, Auth::Read, "Commands waiting for the port")
+                .delete(
+                    "/api/queue",
+                    handlers::flush_queue,
+                    Auth::Admin,
+                    "Cancel every queued command that doesn't move the arm",
+                )
+                .delete(
+                    "/api/queue/:id",
+                    handlers::cancel_queue_entry,
+                    Auth::Admin,
+                    "Cancel a queued command",
+                )
+                .get("/api/poses", handlers::list_poses, Auth::Read, "Saved poses")
+                .get("/api/sequences", handlers::list_sequences, Auth::Read, "Saved sequences")
+                // Checks and exports, answered with their body even when
+                // commands get minimal responses
+                .post(
+                    "/api/pose/estimate_load",
+                    handlers::estimate_pose_load,
+                    Auth::Read,
+                    "Estimated current draw of a pose",
+                )
+                .post(
+                    "/api/validate",
+                    handlers::validate_poses,
+                    Auth::Read,
+                    "Check a batch of candidate poses without moving",
+                )
+                .get(
+                    "/api/validate-library",
+                    handlers::validate_library,
+                    Auth::Read,
+                    "Check stored entries against the current config",
+                )
+                .get(
+                    "/api/export/jointstates",
+                    handlers::export_joint_states,
+                    Auth::Read,
+                    "Angle history as ROS-style joint states",
+                )
+                .post(
+                    "/api/sequences/:name/import",
+                    handlers::import_sequence,
+                    Auth::Config,
+                    "Import a sequence from a file",
+                )
+                .get("/api/imports/:id", handlers::get_import, Auth::Read, "A sequence import")
+                .post(
+                    "/api/poses/import-url",
+                    handlers::import_poses_url,
+                    Auth::Config,
+                    "Import a pose pack from a URL",
+                )
+                .post(
+                    "/api/sequences/import-url",
+                    handlers::import_sequences_url,
+                    Auth::Config,
+                    "Import a sequence pack from a URL",
+                )
+                .get(
+                    "/api/url-imports/:id",
+                    handlers::get_url_import,
+                    Auth::Read,
+                    "An import from a URL",
+                )
+                .get(
+                    "/api/library/watch",
+                    handlers::get_library_watch,
+                    Auth::Read,
+                    "Files loaded from the watched directory and their problems",
+                )
+                .get(
+                    "/api/persistence",
+                    handlers::get_persistence,
+                    Auth::Read,
+                    "Files waiting to be written and problems keeping them",
+                )
+                // Motion planning and streaming
+                .post(
+                    "/api/trajectory/plan",
+                    handlers::plan_trajectory,
+                    Auth::Motion,
+                    "Plan and optionally run a trajectory",
+                )
+                .get_control(
+                    "/api/ws/stream",
+                    handlers::stream_poses,
+                    Auth::Motion,
+                    "Stream poses (WebSocket)",
+                )
+                .get(
+                    "/api/ws/positions",
+                    handlers::position_feed,
+                    Auth::Read,
+                    "Known positions, optionally delta-encoded (WebSocket)",
+                )
+                .get(
+                    "/api/serial/monitor",
+                    handlers::serial_monitor,
+                    Auth::Read,
+                    "Unsolicited firmware lines (WebSocket)",
+                )
+                // Configuration
+                .post(
+                    "/api/config/reload",
+                    handlers::reload_config,
+                    Auth::Config,
+                    "Reload the config file",
+                )
+                .get("/api/profiles", handlers::list_profiles, Auth::Read, "Config profiles")
+                .post(
+                    "/api/profile/:name/activate",
+                    handlers::activate_profile,
+                    Auth::Config,
+                    "Switch to a config profile",
+                )
+                .get(
+                    "/api/motion-scale",
+                    handlers::get_motion_scale,
+                    Auth::Read,
+                    "The motion scale",
+                )
+                .put(
+                    "/api/motion-scale",
+                    handlers::set_motion_scale,
+                    Auth::Config,
+                    "Change the motion scale",
+                )
+                .get(
+                    "/api/compensation",
+                    handlers::get_compensation,
+                    Auth::Read,
+                    "Sag compensation models by channel",
+                )
+                .put(
+                    "/api/compensation/:id",
+                    handlers::set_compensation,
+                    Auth::Config,
+                    "Set a channel's sag compensation model",
+                )
+                .delete(
+                    "/api/compensation/:id",
+                    handlers::delete_compensation,
+                    Auth::Config,
+                    "Remove a channel's sag compensation model",
+                )
+                .post(
+                    "/api/compensation/:id/calibrate",
+                    handlers::calibrate_compensation,
+                    Auth::Config,
+                    "Fit a channel's sag compensation model to measured samples",
+                )
+                .get(
+                    "/api/link-loss",
+                    handlers::get_link_loss,
+                    Auth::Read,
+                    "Recovery after the link dropped during a MOVE",
+                )
+                .post(
+                    "/api/link-loss/ack",
+                    handlers::ack_link_loss,
+                    Auth::Motion,
+                    "Lift the motion latch after a link loss",
+                )
+                .get(
+                    "/api/connection/history",
+                    handlers::get_connection_history,
+                    Auth::Read,
+                    "Lifecycle transitions of the serial connection",
+                )
+                .get("/api/audit", handlers::get_audit, Auth::Read, "Audit trail")
+                .get(
+                    "/api/overrides",
+                    handlers::get_override,
+                    Auth::Read,
+                    "The active override session",
+                )
+                .post(
+                    "/api/overrides",
+                    handlers::create_override,
+                    Auth::Admin,
+                    "Relax limits for supervised maintenance",
+                )
+                .delete(
+                    "/api/overrides/:id",
+                    handlers::revoke_override,
+                    Auth::Admin,
+                    "End an override session",
+                )
+                .post(
+                    "/api/maintenance/rezero",
+                    handlers::rezero,
+                    Auth::Admin,
+                    "Shift one channel's stored angles by an offset",
+                )
+                .post(
+                    "/api/maintenance/rezero/undo/:audit_id",
+                    handlers::undo_rezero,
+                    Auth::Admin,
+                    "Revert a re-zero recorded in the audit trail",
+                )
+                .get(
+                    "/api/state-at",
+                    handlers::get_state_at,
+                    Auth::Read,
+                    "Arm state at a past time",
+                )
+                .get(
+                    "/api/snapshot",
+                    handlers::get_snapshot,
+                    Auth::Read,
+                    "Config, library and positions",
+                )
+                .get(
+                    "/api/backup/status",
+                    handlers::get_backup_status,
+                    Auth::Read,
+                    "Last attempt, success and error of the snapshot backups",
+                )
+                .post(
+                    "/api/backup/now",
+                    handlers::backup_now,
+                    Auth::Config,
+                    "Back up the snapshot now, even if unchanged",
+                )
+                .get(
+                    "/api/support-bundle",
+                    handlers::get_support_bundle,
+                    Auth::Admin,
+                    "Diagnostics archive",
+                )
+                // Warm-spare replication
+                .get(
+                    "/api/replication",
+                    handlers::get_replication,
+                    Auth::Read,
+                    "Replication status",
+                )
+                .post(
+                    "/api/replication",
+                    handlers::receive_replication,
+                    Auth::Replication,
+                    "Apply a change from the peer",
+                )
+                .post(
+                    "/api/admin/promote",
+                    handlers::promote,
+                    Auth::Admin,
+                    "Promote a standby to active",
+                ),
+        )
+        .into_parts()
+}
+
+/// The whole backend, arm connection, background tasks and API, run by
+/// the binary or embedded in another program
+///
+/// ```text
+/// let server = RobotArmServer::builder()
+///     .config(config)
+///     .router(Router::new().route("/kiosk", get(kiosk_page)))
+///     .start()
+///     .await?;
+/// control::pose(server.state(), vec![90, 45, 120]).await?;
+/// server.shutdown().await?;
+/// ```
+pub struct RobotArmServer;
+
+impl RobotArmServer {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+/// Settings of a [`RobotArmServer`] to start
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    config_path: Option<PathBuf>,
+    transport: Option<Transport>,
+    router: Option<Router>,
+    /// Clock of the background tasks ([`SystemClock`] if not given)
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl ServerBuilder {
+    /// Settings to run with ([`Config::default`] if not given)
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// File the config came from, read again by `POST /api/config/reload`
+    pub fn config_path(mut self, path: PathBuf) -> Self {
+        self.config_path = Some(path);
+        self
+    }
+
+    /// Reach the firmware through `transport` instead of the serial port
+    /// or the simulator
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Routes of the embedding program, served next to the API; the API's
+    /// middleware (auth, quotas, signing) doesn't apply to them
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    /// Run the timing features on `clock`, e.g. a [`MockClock`]
+    ///
+    /// [`MockClock`]: crate::clock::MockClock
+    #[cfg(test)]
+    pub(crate) fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Connect to the arm, load the stored state, start the background
+    /// tasks and serve the API on `bind_addr` (or `bind_uds`)
+    ///
+    /// The arm needn't be reachable yet; the connection is retried in the
+    /// background. Stored state that can't be loaded is an error.
+    pub async fn start(self) -> Result<ServerHandle> {
+        let serial = self.config.serial.clone();
+        let simulate = self.config.simulate;
+        let transport = self.transport.clone();
+        let connect: Connect = Arc::new(move |config, observers| {
+            let manager = connect(&serial, simulate, config, observers, transport.as_ref())?;
+            Ok(Arc::new(manager))
+        });
+        self.start_with(connect).await
+    }
+
+    /// [`start`](Self::start), connecting to the arm with `connect`
+    pub(crate) async fn start_with(self, connect: Connect) -> Result<ServerHandle> {
+        let config = self.config;
+        let config_path = self.config_path;
+        let transport = self.transport;
+        let serial_config = config.serial.clone();
+        let bind_addr = config.bind_addr.clone();
+        let bind_uds = config.bind_uds.clone();
+        let readonly_bind_addr = config.readonly_bind_addr.clone();
+        let simulate = config.simulate;
+        let demo = config.demo.enabled;
+        let mut tasks = Vec::new();
+
+        info!("Starting robot arm backend");
+        if let Some(path) = &config_path {
+            info!("Config file: {}", path.display());
+        }
+        if config.motion_scale < 1.0 {
+            warn!(
+                "Motion scaled to {} of the commanded range (motion_scale)",
+                config.motion_scale
+            );
+        }
+        if transport.is_some() {
+            info!("Custom transport: the serial port isn't used");
+        } else if simulate {
+            info!("Simulation mode: no hardware is used");
+        } else {
+            info!(
+                "Serial port: {} @ {} baud",
+                serial_config.port, serial_config.baud
+            );
+        }
+
+        // Bound before anything is started, so a taken address fails early
+        let listener = match bind_uds {
+            Some(path) => Listener::Unix(path),
+            None => Listener::Tcp(
+                tokio::net::TcpListener::bind(&bind_addr)
+                    .await
+                    .context("Failed to bind to address")?,
+            ),
+        };
+        let local_addr = match &listener {
+            Listener::Tcp(listener) => Some(listener.local_addr()?),
+            Listener::Unix(_) => None,
+        };
+        let readonly_listener = match &readonly_bind_addr {
+            Some(addr) => Some(
+                tokio::net::TcpListener::bind(addr)
+                    .await
+                    .context("Failed to bind to the read-only address")?,
+            ),
+            None => None,
+        };
+
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let observers = Observers {
+            connection: Arc::new(ConnectionLog::new(config.connection_log_file.clone())),
+            unsolicited: tokio::sync::broadcast::channel(UNSOLICITED_BACKLOG).0,
+            clock: clock.clone(),
+        };
+        observers.connection.record(ConnectionState::Connecting, None);
+
+        // Try initial connection (non-blocking)
+        let initial_serial = match connect(&config, &observers) {
+            Ok(manager) => {
+                info!("Serial connection established at {} baud", manager.baud_rate());
+                observers.connection.record(ConnectionState::Connected, None);
+                Some(manager)
+            }
+            Err(e) => {
+                let error = Some(format!("{:#}", e));
+                observers.connection.record(ConnectionState::Disconnected, error);
+                tracing::warn!("Serial device not available at startup: {}", e);
+                tracing::warn!("Will retry connection in background");
+                None
+            }
+        };
+
+        // Recoveries from backups at load are reported through it
+        let persister = Persister::default();
+
+        let library = match &config.library_file {
+            Some(path) => match Library::load(path, &persister) {
+                Ok(library) => {
+                    info!(
+                        "Loaded {} poses and {} sequences from {}",
+                        library.poses.len(),
+                        library.sequences.len(),
+                        path.display()
+                    );
+                    library
+                }
+                Err(e) => return Err(e.context("Invalid library")),
+            },
+            None => Library::default(),
+        };
+
+        let lockout = match ChannelLockout::load(config.lockout_file.as_deref(), &persister) {
+            Ok(lockout) => {
+                let disabled = lockout.disabled();
+                if !disabled.is_empty() {
+                    warn!("Channels locked out until re-enabled: {:?}", disabled);
+                }
+                lockout
+            }
+            Err(e) => return Err(e.context("Invalid lockout")),
+        };
+
+        let compensation = Compensation::load(config.compensation_file.as_deref(), &persister);
+        let compensation = match compensation {
+            Ok(compensation) => {
+                let channels: Vec<u8> = compensation.all().into_keys().collect();
+                if !channels.is_empty() {
+                    info!("Sag compensation on channels {:?}", channels);
+                }
+                compensation
+            }
+            Err(e) => return Err(e.context("Invalid compensation")),
+        };
+
+        let audit = match AuditLog::load(config.audit_file.as_deref(), config.audit_max_entries) {
+            Ok(audit) => audit,
+            Err(e) => return Err(e.context("Invalid audit log")),
+        };
+
+        let wear = match Wear::load(config.wear_file.as_deref(), &persister, audit::now_ms()) {
+            Ok(wear) => wear,
+            Err(e) => return Err(e.context("Invalid wear totals")),
+        };
+
+        let (replication, outbox) = Replication::new(&config);
+
+        // Create shared state
+        let state = Arc::new(AppState {
+            serial: Arc::new(std::sync::Mutex::new(initial_serial)),
+            config: std::sync::Mutex::new(Arc::new(config)),
+            config_path,
+            positions: std::sync::Mutex::new(Default::default()),
+            restored: std::sync::Mutex::new(Default::default()),
+            positions_unknown: Default::default(),
+            commanded: std::sync::Mutex::new(Default::default()),
+            moving_until: std::sync::Mutex::new(Default::default()),
+            last_command: std::sync::Mutex::new(clock.now()),
+            library: std::sync::Mutex::new(library),
+            audit: std::sync::Mutex::new(audit),
+            motion: MotionActivity::new(clock.clone()),
+            connected: Default::default(),
+            replication,
+            moves: Default::default(),
+            schedule: Default::default(),
+            patterns: Default::default(),
+            has_connected: Default::default(),
+            scripts: Default::default(),
+            imports: Default::default(),
+            supply_mv: std::sync::Mutex::new(None),
+            faults: std::sync::Mutex::new(None),
+            firmware_speed_limit: std::sync::Mutex::new(None),
+            history: Default::default(),
+            lockout,
+            overrides: Default::default(),
+            compensation,
+            link_loss: Default::default(),
+            observers,
+            command_stats: Default::default(),
+            usage: Default::default(),
+            wear,
+            persister,
+            url_imports: Default::default(),
+            backups: Default::default(),
+            library_watch: Default::default(),
+            poller: Poller::new(clock.clone()),
+            clock,
+            routes: Default::default(),
+        });
+
+        if let Some(path) = state.config().last_pose_file.clone() {
+            match last_pose::load(&path, &state.persister) {
+                Ok(Some(positions)) => {
+                    info!("Restored positions {:?} until confirmed", positions);
+                    state.restore_positions(&positions);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Not restoring positions: {:#}", e),
+            }
+        }
+
+        // Known before the API is served
+        if let Some(serial) = state.serial.lock().unwrap().clone() {
+            handlers::home_on_connect(&state, &*serial);
+            handlers::seed_positions(&state, &*serial);
+        }
+
+        if let Some(outbox) = outbox {
+            tasks.push(tokio::spawn(replication::push_loop(state.clone(), outbox)));
+            if let Some(peer) = &state.config().replication.peer_url {
+                info!("Replicating changes to {}", peer);
+            }
+        }
+
+        // Background task for automatic reconnection
+        let reconnect_state = state.clone();
+
+        tasks.push(tokio::spawn(async move {
+            use std::time::Duration;
+            use tracing::debug;
+
+            loop {
+                reconnect_state.clock.sleep(Duration::from_secs(5)).await;
+
+                // Check if we need to reconnect
+                let needs_connection = {
+                    let serial = reconnect_state.serial.lock().unwrap();
+                    serial.is_none()
+                };
+
+                if needs_connection {
+                    debug!("Attempting to reconnect to serial device...");
+                    let config = reconnect_state.config();
+                    let observers = &reconnect_state.observers;
+                    observers.connection.record(ConnectionState::Reconnecting, None);
+                    match connect(&config, observers) {
+                        Ok(manager) => {
+                            let baud = manager.baud_rate();
+                            info!("Serial connection re-established at {} baud", baud);
+                            observers.connection.record(ConnectionState::Connected, None);
+                            // Opening the port resets the board, so cached positions are stale
+                            reconnect_state.clear_positions();
+                            handlers::recover_link_loss(&reconnect_state, &*manager);
+                            handlers::home_on_connect(&reconnect_state, &*manager);
+                            handlers::seed_positions(&reconnect_state, &*manager);
+                            *reconnect_state.serial.lock().unwrap() = Some(manager);
+                            // Wake requests waiting for the connection
+                            reconnect_state.connected.notify_waiters();
+                        }
+                        Err(e) => {
+                            debug!("Reconnection failed: {}", e);
+                            // Stays reconnecting; recorded again only after
+                            // getting as far as the handshake
+                            observers
+                                .connection
+                                .record(ConnectionState::Reconnecting, Some(format!("{:#}", e)));
+                        }
+                    }
+                }
+            }
+        }));
+
+        info!("Background reconnection task started (checks every 5 seconds)");
+
+        if demo {
+            tasks.push(tokio::spawn(demo::run(state.clone())));
+            info!("Demo motion started");
+        }
+        // Idles until enabled in the config, so the section can be hot-reloaded
+        tasks.push(tokio::spawn(attract::run(state.clone())));
+        tasks.push(tokio::spawn(backup::run(state.clone())));
+        tasks.push(tokio::spawn(library_watch::run(state.clone())));
+        tasks.push(tokio::spawn(poller::run(state.clone())));
+        tasks.push(tokio::spawn(last_pose::run(state.clone())));
+        tasks.push(tokio::spawn(idle_exit::run(state.clone())));
+        tasks.push(tokio::spawn(wear::run(state.clone())));
+        tasks.push(tokio::spawn(persist::run(state.clone())));
+
+        let (app, readonly_app, inventory) = api_routes(&state);
+
+        info!("{}", routes::summary(&inventory));
+        let _ = state.routes.set(inventory);
+
+        let mut app = with_layers(app, &state);
+        if let Some(router) = self.router {
+            app = app.merge(router);
+        }
+        let shutdown = CancellationToken::new();
+
+        if let (Some(listener), Some(addr)) = (readonly_listener, &readonly_bind_addr) {
+            info!("Read-only routes listening on {}", addr);
+            let readonly_app = with_layers(readonly_app, &state);
+            let stop = shutdown.clone();
+            tasks.push(tokio::spawn(async move {
+                let serve = axum::serve(listener, readonly_app)
+                    .with_graceful_shutdown(stop.cancelled_owned());
+                if let Err(e) = serve.await {
+                    error!("Read-only server failed: {}", e);
+                }
+            }));
+        }
+
+        let stop = shutdown.clone();
+        let server = match listener {
+            Listener::Tcp(listener) => {
+                info!("Server listening on {}", listener.local_addr()?);
+                tokio::spawn(async move {
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(stop.cancelled_owned())
+                        .await
+                        .context("Failed to start server")
+                })
+            }
+            Listener::Unix(path) => tokio::spawn(async move {
+                uds::serve(&path, app, stop)
+                    .await
+                    .with_context(|| format!("Failed to serve on {}", path.display()))
+            }),
+        };
+
+        Ok(ServerHandle {
+            state,
+            local_addr,
+            server,
+            shutdown,
+            tasks,
+        })
+    }
+}
+
+/// Where the API is served
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    /// Bound once serving starts, see [`uds::serve`]
+    Unix(PathBuf),
+}
+
+/// A started [`RobotArmServer`]
+pub struct ServerHandle {
+    state: Arc<AppState>,
+    local_addr: Option<SocketAddr>,
+    server: JoinHandle<Result<()>>,
+    shutdown: CancellationToken,
+    /// Background tasks, stopped with the server
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Address the API is served on; `None` on a Unix domain socket
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// State shared with the API, for commanding the arm with
+    /// [`control`](crate::control)
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    /// Wait until the server stops: after [`shutdown`](Self::shutdown),
+    /// on a Unix domain socket at SIGINT or SIGTERM, or when it fails
+    pub async fn wait(mut self) -> Result<()> {
+        let served = (&mut self.server).await.context("Server task failed");
+        for task in &self.tasks {
+            task.abort();
+        }
+        // Changes still waiting out the debounce
+        let persister = self.state.persister.clone();
+        let written = tokio::task::spawn_blocking(move || persister.write_pending()).await;
+        if !matches!(written, Ok(true)) {
+            warn!("Not every changed file could be written at shutdown");
+        }
+        served?
+    }
+
+    /// Stop serving, letting requests in progress finish, stop the
+    /// background tasks and write changed files
+    pub async fn shutdown(self) -> Result<()> {
+        info!("Shutting down");
+        self.shutdown.cancel();
+        self.wait().await
+    }
+}
@@ -0,0 +1,287 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::fmt::Write;
+
+use crate::audit;
+
+/// Header carrying the signature, `v1=<hex HMAC-SHA256>`
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Header carrying the signing time in ms since the epoch
+pub const TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+
+/// Request header asking for an unsigned response (`X-No-Signature: 1`)
+pub const OPT_OUT_HEADER: &str = "x-no-signature";
+
+/// HMAC-SHA256 signer for responses and stream frames
+///
+/// The signed message is
+///
+/// ```text
+/// v1\n<timestamp>\n<method> <path and query>\n<canonical JSON body>
+/// ```
+///
+/// with the body in canonical form: no whitespace, object keys sorted by
+/// their UTF-8 bytes, strings and numbers as serde_json writes them. The
+/// body is sent in that form, so clients verify over the bytes received.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+/// A signature and the time it was made
+pub struct Signature {
+    pub timestamp_ms: u64,
+    /// `v1=<hex>`
+    pub value: String,
+}
+
+impl Signer {
+    pub fn new(key: &str) -> Self {
+        Self {
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    /// Sign a canonical body sent for `method` on `target`
+    pub fn sign(&self, method: &str, target: &str, body: &str) -> Signature {
+        let timestamp_ms = audit::now_ms();
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(format!("v1\n{}\n{} {}\n", timestamp_ms, method, target).as_bytes());
+        mac.update(body.as_bytes());
+        let mut value = String::from("v1=");
+        for byte in mac.finalize().into_bytes() {
+            let _ = write!(value, "{:02x}", byte);
+        }
+        Signature {
+            timestamp_ms,
+            value,
+        }
+    }
+
+    /// Wrap a stream frame in a signed envelope
+    ///
+    /// The frame is canonicalized and carried as a string in `payload`,
+    /// signed with the method `WS` and the stream's path.
+    pub fn sign_frame(&self, path: &str, frame: &Value) -> String {
+        let payload = canonical(frame);
+        let signature = self.sign("WS", path, &payload);
+        let envelope = SignedFrame {
+            payload,
+            timestamp_ms: signature.timestamp_ms,
+            signature: signature.value,
+        };
+        serde_json::to_string(&envelope).unwrap_or_default()
+    }
+}
+
+#[derive(Serialize)]
+struct SignedFrame {
+    payload: String,
+    timestamp_ms: u64,
+    signature: String,
+}
+
+/// Canonical JSON text of a value, as described on [`Signer`]
+pub fn canonical(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(item, out);
+            }
+            out.push('}');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+    use futures_util::StreamExt;
+    use serde_json::json;
+    use sha2::Digest;
+    use tokio_tungstenite::connect_async;
+    use tokio_tungstenite::tungstenite::Message;
+
+    /// HMAC-SHA256 as RFC 2104 defines it, written out over SHA-256 alone
+    /// so the signatures are checked without the `hmac` crate
+    fn reference_hmac(key: &[u8], message: &[u8]) -> String {
+        let mut block = [0u8; 64];
+        if key.len() > block.len() {
+            block[..32].copy_from_slice(&Sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+        let inner = Sha256::new()
+            .chain_update(pad(0x36))
+            .chain_update(message)
+            .finalize();
+        let outer = Sha256::new()
+            .chain_update(pad(0x5c))
+            .chain_update(inner)
+            .finalize();
+        outer.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Check `signature` as a client would, from the documented message
+    fn verify(key: &str, timestamp_ms: u64, method: &str, target: &str, body: &str) -> String {
+        let message = format!("v1\n{}\n{} {}\n{}", timestamp_ms, method, target, body);
+        format!("v1={}", reference_hmac(key.as_bytes(), message.as_bytes()))
+    }
+
+    #[test]
+    fn reference_matches_rfc_4231() {
+        assert_eq!(
+            reference_hmac(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // A key longer than the block is hashed first
+        assert_eq!(
+            reference_hmac(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn signature_matches_the_reference() {
+        for key in ["secret", "", &"k".repeat(100)] {
+            let signer = Signer::new(key);
+            let body = r#"{"a":1,"b":[true,null]}"#;
+            let signature = signer.sign("GET", "/api/health?x=1", body);
+            assert_eq!(
+                signature.value,
+                verify(key, signature.timestamp_ms, "GET", "/api/health?x=1", body)
+            );
+            // Every part of the message is covered
+            let other = verify(key, signature.timestamp_ms, "GET", "/api/health", body);
+            assert_ne!(signature.value, other);
+            let other = verify(
+                key,
+                signature.timestamp_ms + 1,
+                "GET",
+                "/api/health?x=1",
+                body,
+            );
+            assert_ne!(signature.value, other);
+        }
+    }
+
+    #[test]
+    fn canonical_form_sorts_keys_without_whitespace() {
+        let value = json!({
+            "b": { "z": [1, 2.5, -3], "a": null },
+            "a": "line\n\"quoted\"",
+            "B": true,
+            "é": "ünï",
+        });
+        assert_eq!(
+            canonical(&value),
+            r#"{"B":true,"a":"line\n\"quoted\"","b":{"a":null,"z":[1,2.5,-3]},"é":"ünï"}"#
+        );
+        let reparsed: Value = serde_json::from_str(&canonical(&value)).unwrap();
+        assert_eq!(reparsed, value);
+        assert_eq!(canonical(&json!([])), "[]");
+        assert_eq!(canonical(&json!({})), "{}");
+    }
+
+    #[test]
+    fn frame_envelope_verifies() {
+        let signer = Signer::new("secret");
+        let envelope = signer.sign_frame("/api/ws/stream", &json!({ "seq": 1, "ok": true }));
+        let envelope: Value = serde_json::from_str(&envelope).unwrap();
+        let payload = envelope["payload"].as_str().unwrap();
+        assert_eq!(payload, r#"{"ok":true,"seq":1}"#);
+        let timestamp_ms = envelope["timestamp_ms"].as_u64().unwrap();
+        assert_eq!(
+            envelope["signature"],
+            verify("secret", timestamp_ms, "WS", "/api/ws/stream", payload)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn responses_are_signed_over_the_bytes_sent() {
+        let server =
+            TestServer::with_config(|config| config.response_signing_key = Some("kiosk".into()))
+                .await;
+        let client = reqwest::Client::new();
+        let response = client
+            .get(server.url("/api/servos?x=1"))
+            .send()
+            .await
+            .unwrap();
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap();
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+        let timestamp_ms: u64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert_eq!(
+            signature,
+            verify("kiosk", timestamp_ms, "GET", "/api/servos?x=1", &body)
+        );
+
+        let response = client
+            .get(server.url("/api/servos"))
+            .header(OPT_OUT_HEADER, "1")
+            .send()
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(SIGNATURE_HEADER));
+        // Only reads are signed
+        let reply = server.post("/api/serial/start", json!({})).await;
+        assert!(!reply.headers.contains_key(SIGNATURE_HEADER));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_frames_are_signed_one_by_one() {
+        let server =
+            TestServer::with_config(|config| config.response_signing_key = Some("kiosk".into()))
+                .await;
+        let (mut socket, _) = connect_async(server.ws_url("/api/ws/positions?hz=50"))
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            let Some(Ok(Message::Text(text))) = socket.next().await else {
+                panic!("no frame");
+            };
+            let envelope: Value = serde_json::from_str(&text).unwrap();
+            let payload = envelope["payload"].as_str().unwrap();
+            let timestamp_ms = envelope["timestamp_ms"].as_u64().unwrap();
+            assert_eq!(
+                envelope["signature"],
+                verify("kiosk", timestamp_ms, "WS", "/api/ws/positions", payload)
+            );
+        }
+    }
+}
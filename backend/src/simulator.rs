@@ -0,0 +1,442 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio_serial::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::config::{ProtocolConfig, ServoConfig, SimulationConfig};
+use crate::serial::NUM_SERVOS;
+
+/// Feedback ADC counts of the emulated potentiometers at 0 degrees, and
+/// per degree
+const ADC_ZERO: u32 = 200;
+const ADC_PER_DEGREE: u32 = 10;
+
+/// Interval between interpolation steps of a MOVE
+const MOVE_STEP_MS: u64 = 20;
+
+/// State of the emulated firmware
+struct Firmware {
+    serial_mode: bool,
+    angles: [u16; NUM_SERVOS as usize],
+    /// Largest accepted angle; above 180 the extended `A` command is
+    /// understood as well
+    max_angle: u16,
+    /// Answer `GET <n>`; firmware without it answers with an error
+    angle_query: bool,
+    /// Answer `ADC <n>` like firmware with feedback potentiometers
+    adc_query: bool,
+    /// Answer `BUSY <n>` like firmware with the motion state query
+    busy_query: bool,
+    /// Answer `VOLT` like firmware with a supply voltage reading
+    voltage_query: bool,
+    supply_mv: u32,
+    /// Answer `FAULTS` and `CLRFAULTS` like firmware with a fault
+    /// register; the simulated arm never faults by itself
+    fault_register: bool,
+    faults: u32,
+    /// Answer `SPEED` and `SETSPEED` like firmware with a slew-rate
+    /// limit; the simulated arm keeps it but moves as fast as before
+    speed_limit: bool,
+    speed_dps: u16,
+    /// Framing expected around every command
+    prefix: String,
+    suffix: String,
+}
+
+/// In-process emulation of the arm firmware behind the `SerialPort` trait
+///
+/// Commands are parsed as the firmware parses them and answered with the
+/// same strings. A MOVE holds back its reply for the move duration, like
+/// the blocking firmware loop does.
+pub struct SimulatedPort {
+    firmware: Firmware,
+    line: Vec<u8>,
+    /// Firmware command buffer size (`CMD_BUFFER_SIZE`), including the
+    /// terminator, from `protocol.max_command_len`; the rest of a longer
+    /// line is dropped like the firmware drops it
+    buffer_size: usize,
+    /// Reply bytes with the time each becomes readable
+    output: Mutex<VecDeque<(Instant, u8)>>,
+    /// Time until which the firmware is busy with the commands received
+    ready_at: Instant,
+    timeout: Duration,
+    baud_rate: u32,
+}
+
+impl SimulatedPort {
+    /// Emulate firmware matching the protocol settings, reporting the
+    /// telemetry of `simulation`
+    pub fn new(baud_rate: u32, protocol: &ProtocolConfig, simulation: &SimulationConfig) -> Self {
+        Self {
+            firmware: Firmware {
+                serial_mode: false,
+                angles: [90; NUM_SERVOS as usize],
+                max_angle: protocol.max_angle,
+                angle_query: protocol.angle_query,
+                adc_query: protocol.adc_query,
+                busy_query: protocol.busy_query,
+                voltage_query: protocol.voltage_query,
+                supply_mv: simulation.supply_mv,
+                fault_register: protocol.fault_register,
+                faults: simulation.faults,
+                speed_limit: protocol.speed_limit,
+                speed_dps: 0,
+                prefix: protocol.command_prefix.clone(),
+                suffix: protocol.command_suffix.clone(),
+            },
+            line: Vec::with_capacity(protocol.max_command_len),
+            buffer_size: protocol.max_command_len,
+            output: Mutex::new(VecDeque::new()),
+            ready_at: Instant::now(),
+            timeout: Duration::from_millis(0),
+            baud_rate,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match byte {
+            b'\n' | b'\r' if !self.line.is_empty() => {
+                let line = String::from_utf8_lossy(&self.line).into_owned();
+                self.line.clear();
+                let (reply, busy) = self.firmware.process(&line);
+                // Commands queue up behind a MOVE still in progress
+                self.ready_at = self.ready_at.max(Instant::now()) + busy;
+                let ready_at = self.ready_at;
+                self.output
+                    .get_mut()
+                    .unwrap()
+                    .extend(reply.bytes().map(|byte| (ready_at, byte)));
+            }
+            b'\x08' | 127 => {
+                self.line.pop();
+            }
+            b' '..=b'~' if self.line.len() < self.buffer_size - 1 => self.line.push(byte),
+            _ => {}
+        }
+    }
+}
+
+impl Firmware {
+    /// Handle one command line, returning the reply and how long the
+    /// firmware is busy before sending it
+    fn process(&mut self, line: &str) -> (String, Duration) {
+        let idle = Duration::from_millis(0);
+
+        let Some(cmd) = line
+            .strip_prefix(self.prefix.as_str())
+            .and_then(|cmd| cmd.strip_suffix(self.suffix.as_str()))
+        else {
+            return ("ERROR: Unknown command (type HELP for list)\n".to_string(), idle);
+        };
+
+        if !self.serial_mode {
+            if cmd == "START" || cmd == "start" {
+                self.serial_mode = true;
+                return ("OK\n".to_string(), idle);
+            }
+            return ("Type START to enter serial mode\n".to_string(), idle);
+        }
+
+        let upper = cmd.to_ascii_uppercase();
+        if upper == "STOP" {
+            self.serial_mode = false;
+            return ("OK\n".to_string(), idle);
+        }
+        if let Some(arg) = upper.strip_prefix("GET ").filter(|_| self.angle_query) {
+            return match parse_channel(arg) {
+                Some(channel) => (
+                    format!("SERVO {:X}: {} degrees\n", channel, self.angles[channel]),
+                    idle,
+                ),
+                None => ("ERROR: Invalid GET command\n".to_string(), idle),
+            };
+        }
+        if let Some(arg) = upper.strip_prefix("ADC ").filter(|_| self.adc_query) {
+            return match parse_channel(arg) {
+                Some(channel) => {
+                    let counts = ADC_ZERO + self.angles[channel] as u32 * ADC_PER_DEGREE;
+                    (format!("ADC {:X}: {}\n", channel, counts), idle)
+                }
+                None => ("ERROR: Invalid ADC command\n".to_string(), idle),
+            };
+        }
+        if upper == "VOLT" && self.voltage_query {
+            return (format!("VOLT: {}\n", self.supply_mv), idle);
+        }
+        if upper == "FAULTS" && self.fault_register {
+            return (format!("FAULTS: {:X}\n", self.faults), idle);
+        }
+        if upper == "CLRFAULTS" && self.fault_register {
+            self.faults = 0;
+            return ("OK\n".to_string(), idle);
+        }
+        if upper == "SPEED" && self.speed_limit {
+            return (format!("SPEED: {}\n", self.speed_dps), idle);
+        }
+        if let Some(arg) = upper.strip_prefix("SETSPEED ").filter(|_| self.speed_limit) {
+            return match arg.trim().parse() {
+                Ok(limit) => {
+                    self.speed_dps = limit;
+                    ("OK\n".to_string(), idle)
+                }
+                Err(_) => ("ERROR: Invalid SETSPEED command\n".to_string(), idle),
+            };
+        }
+        if let Some(arg) = upper.strip_prefix("BUSY ").filter(|_| self.busy_query) {
+            // A MOVE blocks the firmware until it is done, so by the time a
+            // query is read nothing is moving
+            return match parse_channel(arg) {
+                Some(channel) => (format!("BUSY {:X}: 0\n", channel), idle),
+                None => ("ERROR: Invalid BUSY command\n".to_string(), idle),
+            };
+        }
+        if let Some(arg) = upper.strip_prefix("POSE ") {
+            return match parse_angle_list(arg, self.max_angle) {
+                Some(angles) => {
+                    self.angles[..angles.len()].copy_from_slice(&angles);
+                    ("OK\n".to_string(), idle)
+                }
+                None => ("ERROR: Invalid POSE format\n".to_string(), idle),
+            };
+        }
+        if let Some(arg) = upper.strip_prefix("MOVE ") {
+            let arg = arg.trim_start();
+            let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+            let duration = arg[..split].parse::<u16>().ok();
+            return match (duration, parse_angle_list(&arg[split..], self.max_angle)) {
+                (Some(duration_ms), Some(angles)) => {
+                    self.angles[..angles.len()].copy_from_slice(&angles);
+                    let steps = (duration_ms as u64 / MOVE_STEP_MS).max(1);
+                    ("OK\n".to_string(), Duration::from_millis(steps * MOVE_STEP_MS))
+                }
+                _ => ("ERROR: Invalid MOVE format\n".to_string(), idle),
+            };
+        }
+        if let Some(arg) = upper.strip_prefix('S') {
+            return self.set_angle(arg, 180);
+        }
+        if let Some(arg) = upper.strip_prefix('A').filter(|_| self.max_angle > 180) {
+            return self.set_angle(arg, self.max_angle);
+        }
+        if let Some(arg) = upper.strip_prefix('P') {
+            return match parse_assignment(arg) {
+                Some((_, pulse)) if pulse <= 20000 => ("OK\n".to_string(), idle),
+                Some(_) => (
+                    "ERROR: Invalid pulse width (must be 0-20000us)\n".to_string(),
+                    idle,
+                ),
+                None => ("ERROR: Invalid command format\n".to_string(), idle),
+            };
+        }
+
+        ("ERROR: Unknown command (type HELP for list)\n".to_string(), idle)
+    }
+
+    fn set_angle(&mut self, arg: &str, max_angle: u16) -> (String, Duration) {
+        let idle = Duration::from_millis(0);
+        match parse_assignment(arg) {
+            Some((channel, angle)) if angle <= max_angle => {
+                self.angles[channel] = angle;
+                ("OK\n".to_string(), idle)
+            }
+            Some(_) => (
+                format!("ERROR: Invalid angle (must be 0-{})\n", max_angle),
+                idle,
+            ),
+            None => ("ERROR: Invalid command format\n".to_string(), idle),
+        }
+    }
+}
+
+fn parse_channel(text: &str) -> Option<usize> {
+    let mut chars = text.chars();
+    let channel = chars.next()?.to_digit(16)? as usize;
+    (channel < NUM_SERVOS as usize).then_some(channel)
+}
+
+/// Parse `<hex>:<value>` as sent with S and P commands
+fn parse_assignment(text: &str) -> Option<(usize, u16)> {
+    let (channel, value) = text.split_once(':')?;
+    let channel = match channel.len() {
+        1 => parse_channel(channel)?,
+        _ => return None,
+    };
+    Some((channel, value.trim().parse().ok()?))
+}
+
+fn parse_angle_list(text: &str, max_angle: u16) -> Option<Vec<u16>> {
+    let angles = text
+        .split(',')
+        .map(|a| a.trim().parse::<u16>().ok().filter(|&a| a <= max_angle))
+        .collect::<Option<Vec<_>>>()?;
+    (!angles.is_empty() && angles.len() <= NUM_SERVOS as usize).then_some(angles)
+}
+
+/// Synthetic draw of a simulated servo: its holding current, and halfway
+/// from there to its stall current while it moves
+pub fn servo_load_ma(servo: &ServoConfig, moving: bool) -> u32 {
+    if moving {
+        (servo.holding_ma + servo.stall_ma) / 2
+    } else {
+        servo.holding_ma
+    }
+}
+
+/// Synthetic temperature of a simulated servo drawing `load_ma`
+pub fn servo_temperature_c(simulation: &SimulationConfig, load_ma: u32) -> f64 {
+    simulation.ambient_c + simulation.c_per_amp * load_ma as f64 / 1000.0
+}
+
+impl Read for SimulatedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(&(ready_at, _)) = self.output.get_mut().unwrap().front() else {
+            std::thread::sleep(self.timeout);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out"));
+        };
+
+        let wait = ready_at.saturating_duration_since(Instant::now());
+        if wait > self.timeout {
+            std::thread::sleep(self.timeout);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out"));
+        }
+        std::thread::sleep(wait);
+
+        // Only this reply; one queued behind it is sent later
+        let output = self.output.get_mut().unwrap();
+        let now = Instant::now();
+        let ready = output.iter().take_while(|&&(at, _)| at <= now).count();
+        let n = buf.len().min(ready);
+        for (slot, (_, byte)) in buf.iter_mut().zip(output.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimulatedPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.receive(byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for SimulatedPort {
+    fn name(&self) -> Option<String> {
+        Some("simulator".to_string())
+    }
+
+    fn baud_rate(&self) -> tokio_serial::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> tokio_serial::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> tokio_serial::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> tokio_serial::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> tokio_serial::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> tokio_serial::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> tokio_serial::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> tokio_serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> tokio_serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> tokio_serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> tokio_serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> tokio_serial::Result<u32> {
+        let now = Instant::now();
+        let output = self.output.lock().unwrap();
+        Ok(output.iter().take_while(|&&(at, _)| at <= now).count() as u32)
+    }
+
+    fn bytes_to_write(&self) -> tokio_serial::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer: ClearBuffer) -> tokio_serial::Result<()> {
+        // A reply the firmware is still busy producing hasn't been sent yet
+        if buffer != ClearBuffer::Output {
+            let now = Instant::now();
+            self.output.lock().unwrap().retain(|&(at, _)| at > now);
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> tokio_serial::Result<Box<dyn SerialPort>> {
+        Err(tokio_serial::Error::new(
+            tokio_serial::ErrorKind::Unknown,
+            "The simulated port cannot be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+}
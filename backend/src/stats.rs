@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::serial::NUM_SERVOS;
+
+/// Period the rolling command rate is measured over
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How often each channel is commanded, for spotting a client hammering a
+/// joint
+///
+/// A channel counts once per angle command and once per POSE or MOVE it
+/// is part of, when the firmware accepted the command.
+#[derive(Default)]
+pub struct CommandStats {
+    channels: Mutex<[ChannelStats; NUM_SERVOS as usize]>,
+}
+
+#[derive(Default)]
+struct ChannelStats {
+    count: u64,
+    /// Times of the commands within the rate window
+    recent: VecDeque<Instant>,
+    /// Last commanded angle and its time in ms since the epoch
+    last: Option<(u16, u64)>,
+}
+
+/// Statistics of one channel, see [`CommandStats::snapshot`]
+pub struct ChannelSnapshot {
+    pub channel: u8,
+    pub count: u64,
+    /// Commands per second over the rate window
+    pub rate: f64,
+    pub last: Option<(u16, u64)>,
+}
+
+impl ChannelStats {
+    fn prune(&mut self, now: Instant) {
+        while matches!(self.recent.front(), Some(&at) if now.duration_since(at) > RATE_WINDOW) {
+            self.recent.pop_front();
+        }
+    }
+}
+
+impl CommandStats {
+    /// Count a command to each of the `(channel, angle)` pairs
+    pub fn record(&self, commands: impl IntoIterator<Item = (u8, u16)>, now: Instant, now_ms: u64) {
+        let mut channels = self.channels.lock().unwrap();
+        for (channel, angle) in commands {
+            let Some(stats) = channels.get_mut(channel as usize) else {
+                continue;
+            };
+            stats.count += 1;
+            stats.prune(now);
+            stats.recent.push_back(now);
+            stats.last = Some((angle, now_ms));
+        }
+    }
+
+    /// Statistics of every channel commanded since startup
+    pub fn snapshot(&self, now: Instant) -> Vec<ChannelSnapshot> {
+        let mut channels = self.channels.lock().unwrap();
+        channels
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, stats)| stats.count > 0)
+            .map(|(channel, stats)| {
+                stats.prune(now);
+                ChannelSnapshot {
+                    channel: channel as u8,
+                    count: stats.count,
+                    rate: stats.recent.len() as f64 / RATE_WINDOW.as_secs_f64(),
+                    last: stats.last,
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,555 @@
+use axum::extract::ws::{Message, WebSocket};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+use crate::config::Violation;
+use crate::handlers::{self, ApiError, AppState};
+use crate::models::{ErrorResponse, PoseRequest};
+use crate::serial::{CommandOptions, NUM_SERVOS};
+use crate::signing::Signer;
+
+/// Route of the pose stream, part of every signed frame
+const STREAM_PATH: &str = "/api/ws/stream";
+
+/// Route of the position feed, part of every signed frame
+const FEED_PATH: &str = "/api/ws/positions";
+
+/// Fastest and default rate of the position feed
+pub const MAX_FEED_HZ: u32 = 50;
+pub const DEFAULT_FEED_HZ: u32 = 10;
+
+/// Longest and default keyframe interval of a compact feed, in frames
+pub const MAX_KEYFRAME_EVERY: u32 = 1000;
+pub const DEFAULT_KEYFRAME_EVERY: u32 = 20;
+
+type Positions = [Option<u16>; NUM_SERVOS as usize];
+
+/// Limits how far streamed targets may move from the previous output
+///
+/// A target further away than the channel's velocity allows over the frame
+/// interval is either clamped to the largest allowed step or dropped, so a
+/// glitch in the stream results in a small step instead of a jump.
+pub struct SafetyClamp {
+    /// Last angle sent per channel; `None` until known
+    last: [Option<u16>; NUM_SERVOS as usize],
+}
+
+/// Result of passing a target through the [`SafetyClamp`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Clamped {
+    /// Angles to send; `limited` lists the channels held back
+    Send { angles: Vec<u16>, limited: Vec<u8> },
+    /// The frame exceeded the limit on `limited` and is not sent
+    Drop { limited: Vec<u8> },
+}
+
+impl SafetyClamp {
+    /// Start from the given known positions
+    pub fn new(last: [Option<u16>; NUM_SERVOS as usize]) -> Self {
+        Self { last }
+    }
+
+    /// Limit `target` given the time since the previous output
+    ///
+    /// `max_velocity` holds degrees per second per channel. Channels
+    /// without a known previous angle are passed through. At least one
+    /// degree per frame is always allowed so slow streams still converge.
+    pub fn apply(
+        &mut self,
+        target: &[u16],
+        interval: Duration,
+        max_velocity: &[u16],
+        on_violation: Violation,
+    ) -> Clamped {
+        let mut angles = Vec::with_capacity(target.len());
+        let mut limited = Vec::new();
+        for (channel, &angle) in target.iter().enumerate() {
+            let last = match self.last[channel] {
+                Some(last) => last,
+                None => {
+                    angles.push(angle);
+                    continue;
+                }
+            };
+            let max_step = (max_velocity[channel] as f64 * interval.as_secs_f64())
+                .floor()
+                .max(1.0) as u16;
+            if angle.abs_diff(last) <= max_step {
+                angles.push(angle);
+            } else if angle > last {
+                angles.push(last + max_step);
+                limited.push(channel as u8);
+            } else {
+                angles.push(last - max_step);
+                limited.push(channel as u8);
+            }
+        }
+
+        if !limited.is_empty() && on_violation == Violation::Drop {
+            return Clamped::Drop { limited };
+        }
+        for (slot, &angle) in self.last.iter_mut().zip(&angles) {
+            *slot = Some(angle);
+        }
+        Clamped::Send { angles, limited }
+    }
+}
+
+/// One frame of the position feed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum FeedFrame {
+    /// Every channel's position, `null` where unknown
+    Key { positions: Positions, seq: u64 },
+    /// The channels that changed since the previous frame, by index
+    Delta { d: BTreeMap<String, Option<u16>>, seq: u64 },
+}
+
+/// Turns a subscriber's position samples into frames
+///
+/// Every frame carries the next sequence number, so a gap tells the
+/// client it missed one. A keyframe goes out first, then every
+/// `keyframe_every` frames and after a [`resync`](Self::resync); the
+/// frames between carry only the channels that changed, or none. With
+/// `keyframe_every` 1 every frame is a keyframe.
+pub struct DeltaEncoder {
+    keyframe_every: u32,
+    /// Positions as of the previous frame; `None` until the first frame
+    /// and after a resync, so the next one is a keyframe
+    sent: Option<Positions>,
+    since_keyframe: u32,
+    seq: u64,
+}
+
+impl DeltaEncoder {
+    pub fn new(keyframe_every: u32) -> Self {
+        Self {
+            keyframe_every: keyframe_every.max(1),
+            sent: None,
+            since_keyframe: 0,
+            seq: 0,
+        }
+    }
+
+    /// The frame for `positions`
+    pub fn frame(&mut self, positions: &Positions) -> FeedFrame {
+        self.seq += 1;
+        let seq = self.seq;
+        let previous = self.sent.replace(*positions);
+        match previous {
+            Some(previous) if self.since_keyframe < self.keyframe_every - 1 => {
+                self.since_keyframe += 1;
+                let d = previous
+                    .iter()
+                    .zip(positions)
+                    .enumerate()
+                    .filter(|(_, (before, now))| before != now)
+                    .map(|(channel, (_, &now))| (channel.to_string(), now))
+                    .collect();
+                FeedFrame::Delta { d, seq }
+            }
+            _ => {
+                self.since_keyframe = 0;
+                FeedFrame::Key {
+                    positions: *positions,
+                    seq,
+                }
+            }
+        }
+    }
+
+    /// Make the next frame a keyframe, e.g. after the client lost one
+    pub fn resync(&mut self) {
+        self.sent = None;
+    }
+}
+
+/// Options of a position feed subscription, from the query
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct FeedOptions {
+    /// Frames per second
+    pub hz: u32,
+    /// Send deltas between keyframes instead of every position each frame
+    pub compact: bool,
+    pub keyframe_every: u32,
+}
+
+impl Default for FeedOptions {
+    fn default() -> Self {
+        Self {
+            hz: DEFAULT_FEED_HZ,
+            compact: false,
+            keyframe_every: DEFAULT_KEYFRAME_EVERY,
+        }
+    }
+}
+
+/// Message a feed client sends to ask for a keyframe
+#[derive(Debug, Deserialize)]
+struct FeedRequest {
+    resync: bool,
+}
+
+/// Serve one WebSocket connection with the known positions, `hz` times a
+/// second, in signed envelopes if a signer is given
+///
+/// Text messages `{"resync": true}` are answered with a keyframe right
+/// away; anything else the client sends is ignored.
+pub async fn feed_session(
+    state: Arc<AppState>,
+    mut socket: WebSocket,
+    options: FeedOptions,
+    signer: Option<Signer>,
+) {
+    info!("Position feed connected ({} Hz, compact: {})", options.hz, options.compact);
+    let keyframe_every = if options.compact { options.keyframe_every } else { 1 };
+    let mut encoder = DeltaEncoder::new(keyframe_every);
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / options.hz);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<FeedRequest>(&text) {
+                        Ok(FeedRequest { resync: true }) => encoder.resync(),
+                        _ => continue,
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        }
+        let positions = *state.positions.lock().unwrap();
+        let frame = serde_json::to_value(encoder.frame(&positions)).unwrap_or_default();
+        let text = match &signer {
+            Some(signer) => signer.sign_frame(FEED_PATH, &frame),
+            None => frame.to_string(),
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+    info!("Position feed disconnected");
+}
+
+/// Report sent back to the client for a frame that wasn't sent as-is
+#[derive(Debug, Serialize)]
+struct FrameReport {
+    status: &'static str,
+    channels: Vec<u8>,
+}
+
+/// Serve one WebSocket connection streaming target poses
+///
+/// Every text message is a pose request (`{"angles": ...}`). Frames that
+/// arrive while the previous one is still being sent are coalesced: only
+/// the newest is applied. Nothing is sent back for a frame applied as
+/// given; clamped or dropped frames and errors are reported, in signed
+/// envelopes if a signer is given.
+pub async fn session(state: Arc<AppState>, mut socket: WebSocket, signer: Option<Signer>) {
+    info!("Pose stream connected");
+    let mut clamp = SafetyClamp::new(*state.positions.lock().unwrap());
+    let mut last_output: Option<Instant> = None;
+
+    while let Some(Ok(message)) = socket.recv().await {
+        let mut latest = message;
+        // Skip to the newest frame already received
+        while let Ok(Some(Ok(message))) = tokio::time::timeout(Duration::ZERO, socket.recv()).await
+        {
+            if !matches!(message, Message::Text(_) | Message::Close(_)) {
+                continue;
+            }
+            latest = message;
+        }
+        let text = match latest {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        let reply = match apply_frame(&state, &mut clamp, &mut last_output, &text) {
+            Ok(None) => continue,
+            Ok(Some(report)) => serde_json::to_value(&report),
+            Err(error) => serde_json::to_value(&error),
+        };
+        let reply = reply.unwrap_or_default();
+        let reply = match &signer {
+            Some(signer) => signer.sign_frame(STREAM_PATH, &reply),
+            None => reply.to_string(),
+        };
+        if socket.send(Message::Text(reply)).await.is_err() {
+            break;
+        }
+    }
+    info!("Pose stream disconnected");
+}
+
+fn error_body((_, Json(error)): ApiError) -> ErrorResponse {
+    error
+}
+
+/// Clamp and send one frame, returning a report if it wasn't sent as given
+fn apply_frame(
+    state: &AppState,
+    clamp: &mut SafetyClamp,
+    last_output: &mut Option<Instant>,
+    text: &str,
+) -> Result<Option<FrameReport>, ErrorResponse> {
+    let req: PoseRequest = serde_json::from_str(text)
+        .map_err(|e| ErrorResponse::with_code("INVALID_FRAME", e.to_string()))?;
+    let serial = state.require_serial().map_err(error_body)?;
+    let _motion = state.begin_motion().map_err(error_body)?;
+    let target = handlers::resolve_angles(state, &*serial, &req.angles).map_err(error_body)?;
+
+    let config = state.config();
+    let max_interval = Duration::from_millis(config.streaming.max_interval_ms);
+    let interval = last_output.map_or(max_interval, |last| last.elapsed().min(max_interval));
+    let max_velocity: Vec<u16> = (0..NUM_SERVOS).map(|c| config.max_velocity(c)).collect();
+
+    match clamp.apply(
+        &target,
+        interval,
+        &max_velocity,
+        config.streaming.on_violation,
+    ) {
+        Clamped::Send { angles, limited } => {
+            // The arm starts moving when the command is sent, not when the
+            // reply arrives
+            let sent = Instant::now();
+            handlers::run_pose(state, &*serial, &angles, CommandOptions::default())
+                .map_err(error_body)?;
+            *last_output = Some(sent);
+            if limited.is_empty() {
+                return Ok(None);
+            }
+            debug!("Streamed frame clamped on channels {:?}", limited);
+            Ok(Some(FrameReport {
+                status: "clamped",
+                channels: limited,
+            }))
+        }
+        Clamped::Drop { limited } => {
+            debug!("Streamed frame dropped, too fast on channels {:?}", limited);
+            Ok(Some(FrameReport {
+                status: "dropped",
+                channels: limited,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(50);
+    /// 100 degrees per second on every channel: 5 degrees per frame
+    const VELOCITY: [u16; NUM_SERVOS as usize] = [100; NUM_SERVOS as usize];
+
+    fn known(angle: u16) -> SafetyClamp {
+        SafetyClamp::new([Some(angle); NUM_SERVOS as usize])
+    }
+
+    fn sent(clamped: Clamped) -> Vec<u16> {
+        match clamped {
+            Clamped::Send { angles, .. } => angles,
+            Clamped::Drop { limited } => panic!("dropped on {:?}", limited),
+        }
+    }
+
+    /// Feed channel 0 a stream one frame apart, returning what was sent
+    fn stream(clamp: &mut SafetyClamp, targets: &[u16], mode: Violation) -> Vec<Option<u16>> {
+        targets
+            .iter()
+            .map(
+                |&target| match clamp.apply(&[target], FRAME, &VELOCITY, mode) {
+                    Clamped::Send { angles, .. } => Some(angles[0]),
+                    Clamped::Drop { .. } => None,
+                },
+            )
+            .collect()
+    }
+
+    fn assert_steps_within(start: u16, outputs: &[Option<u16>], max_step: u16) {
+        let mut last = start;
+        for &angle in outputs.iter().flatten() {
+            assert!(angle.abs_diff(last) <= max_step, "{} after {}", angle, last);
+            last = angle;
+        }
+    }
+
+    #[test]
+    fn spike_is_clamped_to_one_step() {
+        let mut clamp = known(90);
+        let outputs = stream(&mut clamp, &[91, 150, 92, 0, 93], Violation::Clamp);
+        assert_eq!(outputs, [Some(91), Some(96), Some(92), Some(87), Some(92)]);
+        assert_steps_within(90, &outputs, 5);
+    }
+
+    #[test]
+    fn spike_is_dropped_and_the_stream_carries_on() {
+        let mut clamp = known(90);
+        let outputs = stream(&mut clamp, &[91, 150, 92, 0, 93], Violation::Drop);
+        assert_eq!(outputs, [Some(91), None, Some(92), None, Some(93)]);
+        assert_steps_within(90, &outputs, 5);
+    }
+
+    #[test]
+    fn jump_is_approached_a_step_at_a_time() {
+        let mut clamp = known(0);
+        let outputs = stream(&mut clamp, &[180; 40], Violation::Clamp);
+        assert_steps_within(0, &outputs, 5);
+        assert_eq!(outputs[35], Some(180));
+        assert_eq!(outputs[34], Some(175));
+
+        // Dropping never gets there on its own
+        let mut clamp = known(0);
+        let outputs = stream(&mut clamp, &[180; 40], Violation::Drop);
+        assert!(outputs.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn step_follows_the_interval_and_each_channel() {
+        let velocity = [100, 20, 100, 100, 100, 100];
+        let mut clamp = known(90);
+        // A stale frame a second late may move a full second's worth
+        let clamped = clamp.apply(
+            &[180, 180],
+            Duration::from_secs(1),
+            &velocity,
+            Violation::Clamp,
+        );
+        assert_eq!(
+            clamped,
+            Clamped::Send {
+                angles: vec![180, 110],
+                limited: vec![1],
+            }
+        );
+        // Frames with no time between them still move a degree
+        let clamped = clamp.apply(&[0, 0], Duration::ZERO, &velocity, Violation::Clamp);
+        assert_eq!(sent(clamped), [179, 109]);
+    }
+
+    #[test]
+    fn unknown_channels_pass_through_once() {
+        let mut clamp = SafetyClamp::new([None; NUM_SERVOS as usize]);
+        let first = clamp.apply(&[10, 170], FRAME, &VELOCITY, Violation::Clamp);
+        assert_eq!(sent(first), [10, 170]);
+        let next = clamp.apply(&[170, 10], FRAME, &VELOCITY, Violation::Clamp);
+        assert_eq!(
+            next,
+            Clamped::Send {
+                angles: vec![15, 165],
+                limited: vec![0, 1],
+            }
+        );
+    }
+
+    #[test]
+    fn dropped_frame_leaves_every_channel_where_it_was() {
+        let mut clamp = known(90);
+        // Channel 1 is fine, but the frame goes as a whole
+        let dropped = clamp.apply(&[150, 92], FRAME, &VELOCITY, Violation::Drop);
+        assert_eq!(dropped, Clamped::Drop { limited: vec![0] });
+        let next = clamp.apply(&[94, 94], FRAME, &VELOCITY, Violation::Drop);
+        assert_eq!(sent(next), [94, 94]);
+    }
+
+    fn at(angles: [u16; 2]) -> Positions {
+        let mut positions = [Some(90); NUM_SERVOS as usize];
+        positions[0] = Some(angles[0]);
+        positions[1] = Some(angles[1]);
+        positions
+    }
+
+    fn key(angles: [u16; 2], seq: u64) -> FeedFrame {
+        FeedFrame::Key {
+            positions: at(angles),
+            seq,
+        }
+    }
+
+    fn delta(changed: &[(usize, Option<u16>)], seq: u64) -> FeedFrame {
+        let d = changed
+            .iter()
+            .map(|&(channel, angle)| (channel.to_string(), angle))
+            .collect();
+        FeedFrame::Delta { d, seq }
+    }
+
+    #[test]
+    fn keyframes_come_first_and_every_so_many_frames() {
+        let mut encoder = DeltaEncoder::new(3);
+        let frames: Vec<FeedFrame> = (0..7).map(|_| encoder.frame(&at([90, 90]))).collect();
+        assert_eq!(
+            frames,
+            [
+                key([90, 90], 1),
+                delta(&[], 2),
+                delta(&[], 3),
+                key([90, 90], 4),
+                delta(&[], 5),
+                delta(&[], 6),
+                key([90, 90], 7),
+            ]
+        );
+
+        // Every frame a keyframe, 0 taken as 1
+        for every in [0, 1] {
+            let mut encoder = DeltaEncoder::new(every);
+            assert_eq!(encoder.frame(&at([10, 20])), key([10, 20], 1));
+            assert_eq!(encoder.frame(&at([10, 20])), key([10, 20], 2));
+        }
+    }
+
+    #[test]
+    fn deltas_carry_only_the_changed_channels() {
+        let mut encoder = DeltaEncoder::new(100);
+        encoder.frame(&at([90, 90]));
+        assert_eq!(encoder.frame(&at([45, 90])), delta(&[(0, Some(45))], 2));
+        assert_eq!(encoder.frame(&at([45, 90])), delta(&[], 3));
+        assert_eq!(
+            encoder.frame(&at([50, 100])),
+            delta(&[(0, Some(50)), (1, Some(100))], 4)
+        );
+        // A channel becoming unknown is a change too
+        let mut unknown = at([50, 100]);
+        unknown[5] = None;
+        assert_eq!(encoder.frame(&unknown), delta(&[(5, None)], 5));
+        // Back to where it was compares against the previous frame
+        assert_eq!(encoder.frame(&at([50, 100])), delta(&[(5, Some(90))], 6));
+
+        let json = serde_json::to_value(delta(&[(0, Some(45)), (5, None)], 7)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "d": { "0": 45, "5": null }, "seq": 7 })
+        );
+    }
+
+    #[test]
+    fn resync_sends_a_keyframe_and_restarts_the_cadence() {
+        let mut encoder = DeltaEncoder::new(3);
+        encoder.frame(&at([90, 90]));
+        encoder.frame(&at([45, 90]));
+        encoder.resync();
+        // The sequence carries on, so the gap stays visible
+        assert_eq!(encoder.frame(&at([45, 90])), key([45, 90], 3));
+        assert_eq!(encoder.frame(&at([45, 80])), delta(&[(1, Some(80))], 4));
+        assert_eq!(encoder.frame(&at([45, 80])), delta(&[], 5));
+        assert_eq!(encoder.frame(&at([45, 80])), key([45, 80], 6));
+        // Resyncing twice before a frame is still one keyframe
+        encoder.resync();
+        encoder.resync();
+        assert_eq!(encoder.frame(&at([45, 80])), key([45, 80], 7));
+        assert_eq!(encoder.frame(&at([45, 80])), delta(&[], 8));
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// Contents of a support bundle, one JSON file each
+#[derive(Default)]
+pub struct Bundle {
+    entries: Vec<(&'static str, serde_json::Value)>,
+}
+
+impl Bundle {
+    pub fn add(&mut self, name: &'static str, value: impl Serialize) {
+        let value = serde_json::to_value(value).unwrap_or_default();
+        self.entries.push((name, value));
+    }
+
+    /// Write the bundle as a zip archive to a non-seekable stream
+    ///
+    /// Each entry is compressed straight into `out`; the archive is never
+    /// assembled in memory.
+    pub fn write_zip(self, out: impl Write) -> Result<()> {
+        let mut zip = ZipWriter::new_stream(out);
+        let options = SimpleFileOptions::default();
+        for (name, value) in self.entries {
+            zip.start_file(name, options)?;
+            serde_json::to_writer_pretty(&mut zip, &value)?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Read;
+
+    #[test]
+    fn bundle_is_a_zip_of_json_files() {
+        let mut bundle = Bundle::default();
+        bundle.add("health.json", json!({ "status": "ok" }));
+        bundle.add("positions.json", [Some(90), None::<u16>]);
+        let mut out = Vec::new();
+        bundle.write_zip(&mut out).unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(out)).unwrap();
+        let mut read = |name: &str| {
+            let mut text = String::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+        assert_eq!(read("health.json"), json!({ "status": "ok" }));
+        assert_eq!(read("positions.json"), json!([90, null]));
+        assert_eq!(archive.len(), 2);
+    }
+}
@@ -0,0 +1,580 @@
+//! A mock of the firmware link and a server to test the API against
+
+use anyhow::Result;
+use reqwest::Method;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::clock::{Clock, MockClock};
+use crate::command_queue::CommandQueue;
+use crate::config::{Config, ProtocolConfig, TimeoutConfig};
+use crate::controller::ServoController;
+use crate::feedback::FeedbackCalibration;
+use crate::protocol::{self, Angle, Channel};
+use crate::serial::{CommandOptions, SerialMode, NUM_SERVOS};
+use crate::server::{Connect, RobotArmServer, ServerBuilder, ServerHandle};
+use crate::violations::Violations;
+
+/// Error of a command whose port failed, which drops the connection
+pub const IO_FAILURE: &str = "Failed to write to serial port";
+
+/// Error of a `START` the firmware refused
+pub const MODE_REFUSED: &str = "Firmware refused serial mode (button held?)";
+
+/// Error of a motion command the firmware ignored in button mode
+pub const IGNORED: &str = "Firmware ignored a motion command but answers reads";
+
+/// Error of a command the firmware answered with its button mode prompt
+pub const BUTTON_MODE: &str = "Unexpected response: Type START to begin";
+
+/// Error of a command longer than the firmware's line buffer
+pub const TOO_LONG: &str = "Command too long for the firmware";
+
+/// Error of a command failed in strict mode over stray bytes
+pub const VIOLATION: &str = "Protocol violation: stray bytes";
+
+/// `admin_token` of a [`TestServer`]
+pub const ADMIN_TOKEN: &str = "test-admin";
+
+/// Header sending [`ADMIN_TOKEN`]
+pub const ADMIN: (&str, &str) = ("authorization", "Bearer test-admin");
+
+/// What a [`MockController`] answers
+pub struct Script {
+    /// Angle reported per channel, moved by the motion commands; `None`
+    /// is answered as an angle sentinel
+    pub angles: [Option<u16>; NUM_SERVOS as usize],
+    /// Errors the next commands fail with, in turn
+    pub failures: VecDeque<String>,
+    /// Feedback ADC counts, `None` for firmware without the query
+    pub adc: Option<u32>,
+    /// Busy state, `None` for firmware without the query
+    pub busy: Option<bool>,
+    pub supply_mv: Option<u32>,
+    pub faults: Option<u32>,
+    pub speed_limit: Option<u16>,
+    pub max_angle: u16,
+    /// Whether angles are sent with the extended command set
+    pub extended_angles: bool,
+    /// Whether angles are read with GET, or else from the feedback ADC
+    pub angle_query: bool,
+    pub simulated: bool,
+    /// Whether connecting fails, so the server stays disconnected once
+    /// the link drops
+    pub offline: bool,
+    mode: Option<SerialMode>,
+    idle_stopped: bool,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            angles: [Some(90); NUM_SERVOS as usize],
+            failures: VecDeque::new(),
+            adc: None,
+            busy: None,
+            supply_mv: None,
+            faults: None,
+            speed_limit: None,
+            max_angle: 180,
+            extended_angles: false,
+            angle_query: true,
+            simulated: false,
+            offline: false,
+            mode: None,
+            idle_stopped: false,
+        }
+    }
+}
+
+/// A firmware link that records every command as the line the firmware
+/// would get and answers from a [`Script`]
+#[derive(Default)]
+pub struct MockController {
+    commands: Mutex<Vec<String>>,
+    /// Signalled whenever a command is recorded
+    recorded: Notify,
+    script: Mutex<Script>,
+    queue: CommandQueue,
+    violations: Violations,
+    /// What MOVEs take their time on, the wall clock if `None`
+    clock: Option<Arc<MockClock>>,
+}
+
+impl MockController {
+    /// A mock whose MOVEs take their time on `clock`, to be advanced by
+    /// the test
+    pub fn on_clock(clock: Arc<MockClock>) -> Self {
+        Self {
+            clock: Some(clock),
+            ..Self::default()
+        }
+    }
+
+    /// The script, to change what the next commands get
+    pub fn script(&self) -> MutexGuard<'_, Script> {
+        self.script.lock().unwrap()
+    }
+
+    /// Make the next command fail with `error`
+    pub fn fail_next(&self, error: &str) {
+        self.script().failures.push_back(error.to_string());
+    }
+
+    /// Commands given since the last call, oldest first
+    pub fn take_commands(&self) -> Vec<String> {
+        std::mem::take(&mut *self.commands.lock().unwrap())
+    }
+
+    /// Wait until the commands given since the last
+    /// [`take_commands`](Self::take_commands) are `done`
+    pub async fn recorded(&self, done: impl Fn(&[String]) -> bool) {
+        loop {
+            let recorded = self.recorded.notified();
+            tokio::pin!(recorded);
+            recorded.as_mut().enable();
+            if done(&self.commands.lock().unwrap()) {
+                return;
+            }
+            recorded.await;
+        }
+    }
+
+    /// Record `line` and fail it if the script says so; returns the
+    /// script to answer it from
+    fn send(&self, line: String) -> Result<MutexGuard<'_, Script>> {
+        self.commands
+            .lock()
+            .unwrap()
+            .push(line.trim_end().to_string());
+        self.recorded.notify_waiters();
+        let mut script = self.script();
+        match script.failures.pop_front() {
+            Some(error) => anyhow::bail!("{}", error),
+            None => Ok(script),
+        }
+    }
+
+    fn check_angles(&self, angles: &[Angle]) -> Result<()> {
+        if angles.len() > NUM_SERVOS as usize {
+            anyhow::bail!("Too many servos: {} (max {})", angles.len(), NUM_SERVOS);
+        }
+        let max_angle = self.max_angle();
+        if let Some(angle) = angles.iter().find(|a| a.get() > max_angle) {
+            anyhow::bail!("Invalid angle: {} (must be 0-{})", angle, max_angle);
+        }
+        Ok(())
+    }
+
+    fn switch_mode(&self, line: String, mode: SerialMode, idle: bool) -> Result<()> {
+        let mut script = self.send(line)?;
+        script.mode = Some(mode);
+        script.idle_stopped = idle;
+        Ok(())
+    }
+}
+
+impl ServoController for MockController {
+    fn baud_rate(&self) -> u32 {
+        115200
+    }
+
+    fn is_simulated(&self) -> bool {
+        self.script().simulated
+    }
+
+    fn queue(&self) -> &CommandQueue {
+        &self.queue
+    }
+
+    fn set_timeouts(&self, _: &TimeoutConfig) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_protocol(&self, protocol: &ProtocolConfig) {
+        let mut script = self.script();
+        script.max_angle = protocol.max_angle;
+        script.extended_angles = protocol.extended_angles;
+        script.angle_query = protocol.angle_query;
+    }
+
+    fn violations(&self) -> &Violations {
+        &self.violations
+    }
+
+    fn max_angle(&self) -> u16 {
+        self.script().max_angle
+    }
+
+    fn end_chain(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn start_serial_mode(&self) -> Result<()> {
+        self.switch_mode(protocol::encode_start(), SerialMode::Serial, false)
+    }
+
+    fn stop_serial_mode(&self) -> Result<()> {
+        self.switch_mode(protocol::encode_stop(), SerialMode::Button, false)
+    }
+
+    fn stop_for_idle(&self) -> Result<()> {
+        self.switch_mode(protocol::encode_stop(), SerialMode::Button, true)
+    }
+
+    fn idle_stopped(&self) -> bool {
+        self.script().idle_stopped
+    }
+
+    fn mode(&self) -> Option<SerialMode> {
+        self.script().mode
+    }
+
+    fn mode_refused(&self) -> bool {
+        false
+    }
+
+    fn set_servo_angle(&self, channel: Channel, angle: Angle, _: CommandOptions) -> Result<()> {
+        self.check_angles(&[angle])?;
+        let extended = self.script().extended_angles;
+        let mut script = self.send(protocol::encode_set_angle(channel, angle, extended))?;
+        script.angles[channel.index()] = Some(angle.get());
+        Ok(())
+    }
+
+    fn set_servo_pwm(&self, channel: Channel, pulse_us: u16, _: CommandOptions) -> Result<()> {
+        if pulse_us > 20000 {
+            anyhow::bail!("Invalid pulse width: {} (must be 0-20000)", pulse_us);
+        }
+        self.send(protocol::encode_set_pwm(channel, pulse_us))
+            .map(drop)
+    }
+
+    fn execute_pose(&self, angles: &[Angle], _: CommandOptions) -> Result<()> {
+        self.check_angles(angles)?;
+        let extended = self.script().extended_angles;
+        let mut script = self.send(protocol::encode_pose(angles, extended))?;
+        for (slot, angle) in script.angles.iter_mut().zip(angles) {
+            *slot = Some(angle.get());
+        }
+        Ok(())
+    }
+
+    /// Answers once the move is done, as the firmware does
+    fn execute_move(&self, duration_ms: u16, angles: &[Angle], _: CommandOptions) -> Result<()> {
+        self.check_angles(angles)?;
+        let duration = Duration::from_millis(duration_ms.into());
+        // From when it was sent, which a test may see and advance past
+        let done = self.clock.as_ref().map(|clock| clock.now() + duration);
+        let extended = self.script().extended_angles;
+        let mut script = self.send(protocol::encode_move(duration_ms, angles, extended))?;
+        for (slot, angle) in script.angles.iter_mut().zip(angles) {
+            *slot = Some(angle.get());
+        }
+        drop(script);
+        match self.clock.as_ref().zip(done) {
+            Some((clock, done)) => clock.wait_until(done),
+            None => std::thread::sleep(duration),
+        }
+        Ok(())
+    }
+
+    fn get_servo_angle(&self, channel: Channel) -> Result<Option<Angle>> {
+        let script = self.send(protocol::encode_get_angle(channel))?;
+        let max_angle = script.max_angle;
+        match script.angles[channel.index()] {
+            Some(angle) => Angle::new(angle, max_angle)
+                .map(Some)
+                .map_err(anyhow::Error::msg),
+            None => Ok(None),
+        }
+    }
+
+    fn get_servo_adc(&self, channel: Channel) -> Result<Option<u32>> {
+        if self.script().adc.is_none() {
+            return Ok(None);
+        }
+        Ok(self.send(protocol::encode_adc(channel))?.adc)
+    }
+
+    fn get_measured_angle(
+        &self,
+        channel: Channel,
+        feedback: Option<&FeedbackCalibration>,
+    ) -> Result<Option<Angle>> {
+        if self.script().angle_query {
+            return self.get_servo_angle(channel);
+        }
+        let Some(feedback) = feedback else {
+            anyhow::bail!("Channel {} has no feedback calibration", channel.get());
+        };
+        let Some(counts) = self.get_servo_adc(channel)? else {
+            anyhow::bail!("The firmware has neither an angle nor an ADC query");
+        };
+        let max_angle = self.max_angle();
+        let angle = Angle::new(feedback.angle(counts, max_angle), max_angle);
+        angle.map(Some).map_err(anyhow::Error::msg)
+    }
+
+    fn get_servo_busy(&self, channel: Channel) -> Result<Option<bool>> {
+        if self.script().busy.is_none() {
+            return Ok(None);
+        }
+        Ok(self.send(protocol::encode_busy(channel))?.busy)
+    }
+
+    fn get_supply_voltage(&self) -> Result<Option<u32>> {
+        if self.script().supply_mv.is_none() {
+            return Ok(None);
+        }
+        Ok(self.send(protocol::encode_voltage())?.supply_mv)
+    }
+
+    fn get_faults(&self) -> Result<Option<u32>> {
+        if self.script().faults.is_none() {
+            return Ok(None);
+        }
+        Ok(self.send(protocol::encode_faults())?.faults)
+    }
+
+    fn clear_faults(&self) -> Result<bool> {
+        if self.script().faults.is_none() {
+            return Ok(false);
+        }
+        self.send(protocol::encode_clear_faults())?.faults = Some(0);
+        Ok(true)
+    }
+
+    fn get_speed_limit(&self) -> Result<Option<u16>> {
+        if self.script().speed_limit.is_none() {
+            return Ok(None);
+        }
+        Ok(self.send(protocol::encode_speed_limit())?.speed_limit)
+    }
+
+    fn set_speed_limit(&self, limit: u16) -> Result<bool> {
+        if self.script().speed_limit.is_none() {
+            return Ok(false);
+        }
+        self.send(protocol::encode_set_speed_limit(limit))?
+            .speed_limit = Some(limit);
+        Ok(true)
+    }
+}
+
+/// A response, with the body parsed as JSON (`null` if empty, a string if
+/// not JSON)
+pub struct Reply {
+    pub status: u16,
+    pub headers: reqwest::header::HeaderMap,
+    pub body: Value,
+}
+
+impl Reply {
+    async fn read(response: reqwest::Response) -> Self {
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let text = response.text().await.expect("body is read");
+        let body = if text.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(&text).unwrap_or(Value::String(text))
+        };
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// The error code of an error body
+    pub fn code(&self) -> &str {
+        self.body["code"].as_str().unwrap_or_default()
+    }
+}
+
+/// A directory of its own under the system's temporary directory,
+/// removed with everything in it when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "robotarm-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).expect("temporary directory is created");
+        Self(path)
+    }
+
+    /// Path of `name` in the directory
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The default config, served on a free local port with [`ADMIN_TOKEN`]
+pub fn test_config() -> Config {
+    Config {
+        bind_addr: "127.0.0.1:0".to_string(),
+        admin_token: Some(ADMIN_TOKEN.to_string()),
+        ..Config::default()
+    }
+}
+
+/// The API served on a free local port, connected to a [`MockController`]
+pub struct TestServer {
+    pub mock: Arc<MockController>,
+    pub server: ServerHandle,
+    client: reqwest::Client,
+    url: String,
+}
+
+impl TestServer {
+    /// Served with the default config, with [`ADMIN_TOKEN`]
+    pub async fn start() -> Self {
+        Self::with_config(|_| {}).await
+    }
+
+    /// Served with the default config as changed by `configure`
+    pub async fn with_config(configure: impl FnOnce(&mut Config)) -> Self {
+        let mut config = test_config();
+        configure(&mut config);
+        let builder = RobotArmServer::builder().config(config);
+        Self::serve(builder, Arc::new(MockController::default())).await
+    }
+
+    /// Served like [`with_config`](Self::with_config) on `clock`, which
+    /// MOVEs take their time on too
+    pub async fn on_clock(clock: Arc<MockClock>, configure: impl FnOnce(&mut Config)) -> Self {
+        let mut config = test_config();
+        configure(&mut config);
+        let builder = RobotArmServer::builder()
+            .config(config)
+            .clock(clock.clone());
+        Self::serve(builder, Arc::new(MockController::on_clock(clock))).await
+    }
+
+    /// Served as `builder` sets up, connected to `mock`
+    pub async fn serve(builder: ServerBuilder, mock: Arc<MockController>) -> Self {
+        let controller = mock.clone();
+        let connect: Connect = Arc::new(move |config, _| {
+            if controller.script().offline {
+                anyhow::bail!("Failed to open serial port");
+            }
+            // As a port opened with the config would be
+            controller.set_protocol(&config.protocol);
+            Ok(controller.clone())
+        });
+        let server = builder.start_with(connect).await.expect("server starts");
+        let url = format!("http://{}", server.local_addr().expect("served over TCP"));
+        // Reads at startup aren't what a test looks at
+        mock.take_commands();
+        Self {
+            mock,
+            server,
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    /// Send a request with `headers`, and a JSON `body` if not `None`
+    pub async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> Reply {
+        let mut request = self.client.request(method, format!("{}{}", self.url, path));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        if let Some(body) = body {
+            request = request
+                .header("content-type", "application/json")
+                .body(body.to_string());
+        }
+        Reply::read(request.send().await.expect("request is answered")).await
+    }
+
+    /// Drop the link for good: connecting fails from now on, and a command
+    /// failing with [`IO_FAILURE`] drops the connection
+    pub async fn disconnect(&self) {
+        self.mock.script().offline = true;
+        self.mock.fail_next(IO_FAILURE);
+        assert_eq!(
+            self.post("/api/serial/start", Value::Null).await.status,
+            503
+        );
+    }
+
+    /// Serve `body` at a URL of its own, as a remote host would
+    pub async fn host(body: &str) -> String {
+        let body = body.to_string();
+        let app = axum::Router::new().route("/file", axum::routing::get(|| async { body }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("binds");
+        let addr = listener.local_addr().expect("bound");
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/file", addr)
+    }
+
+    /// URL of a route
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    /// URL of a WebSocket route
+    pub fn ws_url(&self, path: &str) -> String {
+        self.url(path).replacen("http", "ws", 1)
+    }
+
+    /// `POST` a body that isn't JSON
+    pub async fn post_text(&self, path: &str, body: &str) -> Reply {
+        let response = self
+            .client
+            .post(format!("{}{}", self.url, path))
+            .body(body.to_string())
+            .send()
+            .await
+            .expect("request is answered");
+        Reply::read(response).await
+    }
+
+    pub async fn get(&self, path: &str) -> Reply {
+        self.send(Method::GET, path, &[], None).await
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> Reply {
+        self.send(Method::POST, path, &[], Some(body)).await
+    }
+
+    pub async fn put(&self, path: &str, body: Value) -> Reply {
+        self.send(Method::PUT, path, &[], Some(body)).await
+    }
+
+    pub async fn delete(&self, path: &str) -> Reply {
+        self.send(Method::DELETE, path, &[], None).await
+    }
+
+    /// Send a request with the admin token
+    pub async fn admin(&self, method: Method, path: &str, body: Option<Value>) -> Reply {
+        self.send(method, path, &[ADMIN], body).await
+    }
+}
@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+use crate::config::Config;
+
+/// Part of the API a token may use; each route needs one, see
+/// [`Auth::scope`](crate::routes::Auth::scope)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Queries, nothing that changes state
+    Read,
+    /// Commands that move the arm or switch the firmware's mode
+    Motion,
+    /// Changes to the library, the settings and the compensation models
+    Config,
+    /// Everything, including the admin-only routes
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [Scope::Read, Scope::Motion, Scope::Config, Scope::Admin];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Motion => "motion",
+            Scope::Config => "config",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Scope> {
+        Scope::ALL.into_iter().find(|scope| scope.name() == name)
+    }
+
+    /// Whether holding this scope allows a route needing `required`;
+    /// `admin` covers every scope and every scope covers `read`
+    pub fn covers(self, required: Scope) -> bool {
+        self == required || self == Scope::Admin || required == Scope::Read
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A named bearer token and its scopes, written `name:token:scope,...`
+/// in the config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ApiToken {
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl TryFrom<String> for ApiToken {
+    type Error = String;
+
+    fn try_from(entry: String) -> Result<Self, String> {
+        let mut parts = entry.splitn(3, ':');
+        let (Some(name), Some(token), Some(scopes)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err("A token must be written name:token:scope".to_string());
+        };
+        if name.is_empty() || token.is_empty() {
+            return Err("A token needs a name and a value".to_string());
+        }
+        let scopes = scopes
+            .split(',')
+            .map(|scope| {
+                Scope::parse(scope.trim()).ok_or_else(|| {
+                    format!(
+                        "Unknown scope {:?} of token {}, expected read, motion, config or admin",
+                        scope, name
+                    )
+                })
+            })
+            .collect::<Result<Vec<Scope>, String>>()?;
+        Ok(Self {
+            name: name.to_string(),
+            token: token.to_string(),
+            scopes,
+        })
+    }
+}
+
+/// Who sent a request, by its bearer token
+#[derive(Debug, Clone, Serialize)]
+pub struct Caller {
+    /// Name of the token, `admin` for `admin_token`
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl Caller {
+    pub fn grants(&self, required: Scope) -> bool {
+        self.scopes.iter().any(|scope| scope.covers(required))
+    }
+}
+
+/// Whether `presented` is `secret`, taking the same time wherever they
+/// differ, so a token can't be guessed byte by byte from response times
+pub fn same_token(presented: &str, secret: &str) -> bool {
+    presented.as_bytes().ct_eq(secret.as_bytes()).into()
+}
+
+/// The caller presenting `bearer`, if it is one of `tokens` or the
+/// `admin_token`
+///
+/// Every token is compared, so the time taken doesn't tell which one
+/// matched either.
+pub fn caller(config: &Config, bearer: &str) -> Option<Caller> {
+    let admin = config
+        .admin_token
+        .as_deref()
+        .is_some_and(|token| same_token(bearer, token));
+    let named = config
+        .tokens
+        .iter()
+        .fold(None, |found, token| {
+            if same_token(bearer, &token.token) {
+                Some(token)
+            } else {
+                found
+            }
+        });
+    if admin {
+        return Some(Caller {
+            name: "admin".to_string(),
+            scopes: vec![Scope::Admin],
+        });
+    }
+    named.map(|token| Caller {
+        name: token.name.clone(),
+        scopes: token.scopes.clone(),
+    })
+}
+
+/// Whether any configured token, `admin_token` included, grants `admin`
+pub fn admin_configured(config: &Config) -> bool {
+    config.admin_token.is_some()
+        || config.tokens.iter().any(|token| token.scopes.contains(&Scope::Admin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(entry: &str) -> Result<ApiToken, String> {
+        serde_json::from_value(serde_json::Value::from(entry)).map_err(|e| e.to_string())
+    }
+
+    fn config(admin_token: Option<&str>, tokens: &[&str]) -> Config {
+        Config {
+            admin_token: admin_token.map(str::to_string),
+            tokens: tokens.iter().map(|entry| token(entry).unwrap()).collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn tokens_are_parsed_with_their_scopes() {
+        assert_eq!(
+            token("kiosk:token2:read"),
+            Ok(ApiToken {
+                name: "kiosk".to_string(),
+                token: "token2".to_string(),
+                scopes: vec![Scope::Read],
+            })
+        );
+        assert_eq!(
+            token("ops:t:motion, config").unwrap().scopes,
+            [Scope::Motion, Scope::Config]
+        );
+        let refused = |entry: &str, error: &str| {
+            let message = token(entry).unwrap_err();
+            assert!(message.starts_with(error), "{}: {}", entry, message);
+        };
+        refused("kiosk:token2", "A token must be written name:token:scope");
+        refused("kiosk", "A token must be written name:token:scope");
+        refused(":token2:read", "A token needs a name and a value");
+        refused("kiosk::read", "A token needs a name and a value");
+        refused(
+            "kiosk:token2:write",
+            "Unknown scope \"write\" of token kiosk, expected read, motion, config or admin",
+        );
+        refused("kiosk:token2:", "Unknown scope \"\"");
+        // A colon can't be part of the token
+        refused("kiosk:to:ken:read", "Unknown scope \"ken:read\"");
+    }
+
+    #[test]
+    fn admin_covers_every_scope_and_every_scope_covers_read() {
+        let covered = |held: Scope| -> Vec<Scope> {
+            Scope::ALL
+                .into_iter()
+                .filter(|&required| held.covers(required))
+                .collect()
+        };
+        assert_eq!(covered(Scope::Read), [Scope::Read]);
+        assert_eq!(covered(Scope::Motion), [Scope::Read, Scope::Motion]);
+        assert_eq!(covered(Scope::Config), [Scope::Read, Scope::Config]);
+        assert_eq!(covered(Scope::Admin), Scope::ALL);
+
+        let caller = Caller {
+            name: "ops".to_string(),
+            scopes: vec![Scope::Motion, Scope::Config],
+        };
+        assert!(caller.grants(Scope::Motion) && caller.grants(Scope::Config));
+        assert!(!caller.grants(Scope::Admin));
+        let nobody = Caller {
+            name: "nobody".to_string(),
+            scopes: Vec::new(),
+        };
+        assert!(!nobody.grants(Scope::Read));
+    }
+
+    #[test]
+    fn callers_are_found_by_their_token() {
+        let config = config(Some("root"), &["alice:token1:admin", "kiosk:token2:read"]);
+        let caller = caller(&config, "token2").unwrap();
+        assert_eq!(caller.name, "kiosk");
+        assert_eq!(caller.scopes, [Scope::Read]);
+        let caller_of = |bearer| super::caller(&config, bearer).map(|caller| caller.name);
+        assert_eq!(caller_of("token1").as_deref(), Some("alice"));
+        assert_eq!(caller_of("root").as_deref(), Some("admin"));
+        assert_eq!(caller_of("token"), None);
+        assert_eq!(caller_of("token22"), None);
+        assert_eq!(caller_of(""), None);
+
+        // The admin token wins over a named one with the same value
+        let config = self::config(Some("shared"), &["kiosk:shared:read"]);
+        let caller = super::caller(&config, "shared").unwrap();
+        assert_eq!(
+            (caller.name.as_str(), caller.scopes),
+            ("admin", vec![Scope::Admin])
+        );
+
+        assert!(same_token("token1", "token1"));
+        assert!(!same_token("token1", "token2"));
+        assert!(!same_token("token", "token1"));
+    }
+
+    #[test]
+    fn admin_is_configured_by_either_kind_of_token() {
+        assert!(!admin_configured(&config(None, &[])));
+        assert!(!admin_configured(&config(
+            None,
+            &["kiosk:token2:read,motion,config"]
+        )));
+        assert!(admin_configured(&config(Some("root"), &[])));
+        assert!(admin_configured(&config(
+            None,
+            &["alice:token1:read,admin"]
+        )));
+    }
+}
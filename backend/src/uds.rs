@@ -0,0 +1,72 @@
+use anyhow::{Context, Result};
+use axum::extract::Request;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tower::Service;
+use tracing::{info, warn};
+
+/// Serve `app` on the Unix domain socket at `path` until SIGINT, SIGTERM
+/// or `shutdown`, then remove the socket file
+///
+/// A socket file left behind by a backend that didn't shut down cleanly is
+/// replaced; one another process still listens on is refused.
+pub async fn serve(path: &Path, app: Router, shutdown: CancellationToken) -> Result<()> {
+    remove_stale(path)?;
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind to {}", path.display()))?;
+    info!("Server listening on {}", path.display());
+
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to watch SIGTERM")?;
+    loop {
+        let socket = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+            _ = shutdown.cancelled() => break,
+        };
+        let service = app.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                service.clone().call(request)
+            });
+            // With upgrades, so the WebSocket endpoints work too
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(socket), service)
+                .await
+            {
+                warn!("Failed to serve a connection: {}", e);
+            }
+        });
+    }
+
+    info!("Shutting down, removing {}", path.display());
+    std::fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+/// Remove a leftover socket file at `path`
+fn remove_stale(path: &Path) -> Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{} exists and isn't a socket", path.display());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        anyhow::bail!("{} is in use by another process", path.display());
+    }
+    warn!("Removing stale socket {}", path.display());
+    std::fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
@@ -0,0 +1,765 @@
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::audit;
+use crate::config::{redact_url, UrlImportConfig};
+use crate::handlers::{self, ApiError, AppState};
+use crate::imports::{self, ImportStatus};
+use crate::library::{Pose, Sequence, StepError};
+use crate::models::ErrorResponse;
+
+/// Time the request waits for an import before answering 202
+const SYNC_WAIT: Duration = Duration::from_secs(2);
+
+/// Finished imports kept for `GET /api/url-imports/:id`
+const MAX_FINISHED_IMPORTS: usize = 20;
+
+/// Content types accepted for a pack; raw gists are served as text
+const CONTENT_TYPES: [&str; 2] = ["application/json", "text/plain"];
+
+/// What a pack contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackKind {
+    Poses,
+    Sequences,
+}
+
+impl PackKind {
+    fn endpoint(self) -> &'static str {
+        match self {
+            PackKind::Poses => "POST /api/poses/import-url",
+            PackKind::Sequences => "POST /api/sequences/import-url",
+        }
+    }
+}
+
+/// Outcome of one pose or sequence of a pack
+#[derive(Debug, Clone, Serialize)]
+pub struct ItemResult {
+    /// Name saved under, including the prefix
+    pub name: String,
+    pub imported: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub step_errors: Vec<StepError>,
+}
+
+/// State and outcome of an import from a URL
+#[derive(Debug, Clone, Serialize)]
+pub struct UrlImportRecord {
+    pub id: u64,
+    pub kind: PackKind,
+    pub url: String,
+    pub status: ImportStatus,
+    pub started_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_ms: Option<u64>,
+    pub bytes: usize,
+    /// Why the pack as a whole couldn't be imported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub imported: usize,
+    pub failed: usize,
+    pub items: Vec<ItemResult>,
+}
+
+/// Imports from URLs, running and recently finished
+#[derive(Default)]
+pub struct UrlImports {
+    inner: Mutex<Imports>,
+}
+
+#[derive(Default)]
+struct Imports {
+    next_id: u64,
+    records: BTreeMap<u64, UrlImportRecord>,
+}
+
+impl UrlImports {
+    pub fn get(&self, id: u64) -> Option<UrlImportRecord> {
+        self.inner.lock().unwrap().records.get(&id).cloned()
+    }
+
+    fn next_id(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        inner.next_id
+    }
+
+    /// Keep an import's record, dropping the oldest finished ones
+    fn update(&self, record: UrlImportRecord) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.records.insert(record.id, record);
+        let finished: Vec<u64> = inner
+            .records
+            .values()
+            .filter(|record| record.status != ImportStatus::Running)
+            .map(|record| record.id)
+            .collect();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_IMPORTS);
+        for id in &finished[..excess] {
+            inner.records.remove(id);
+        }
+    }
+}
+
+/// Where a pack is fetched from, checked against `[url_import]`
+struct Target {
+    url: Url,
+    /// Host name and the addresses it was checked at, to connect to the
+    /// same ones
+    resolved: Option<(String, Vec<SocketAddr>)>,
+}
+
+/// Import the poses or sequences of the pack at `url`, with `prefix`
+/// added to their names
+///
+/// The URL is checked before anything is fetched. The download runs in
+/// the background: if it finishes within [`SYNC_WAIT`] the request gets
+/// the result, otherwise 202 with the record to follow at
+/// `GET /api/url-imports/:id`.
+pub async fn start(
+    state: Arc<AppState>,
+    kind: PackKind,
+    url: &str,
+    prefix: String,
+    headers: HeaderMap,
+) -> Result<(StatusCode, UrlImportRecord), ApiError> {
+    let config = state.config().url_import.clone();
+    let target = check_url(&config, url).await?;
+
+    let record = UrlImportRecord {
+        id: state.url_imports.next_id(),
+        kind,
+        url: redact_url(url),
+        status: ImportStatus::Running,
+        started_ms: audit::now_ms(),
+        finished_ms: None,
+        bytes: 0,
+        error: None,
+        imported: 0,
+        failed: 0,
+        items: Vec::new(),
+    };
+    state.url_imports.update(record.clone());
+    info!(
+        "Importing {:?} from {} (import {})",
+        kind, record.url, record.id
+    );
+
+    let mut task = tokio::spawn(run(state.clone(), record.clone(), target, prefix, headers));
+    tokio::select! {
+        result = &mut task => match result {
+            Ok(record) if record.status == ImportStatus::Failed => Err(failed(record)),
+            Ok(record) => Ok((StatusCode::OK, record)),
+            Err(e) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse::new(format!("Import task failed: {}", e))),
+            )),
+        },
+        _ = state.clock.sleep(SYNC_WAIT) => Ok((StatusCode::ACCEPTED, record)),
+    }
+}
+
+/// Fetch and apply a pack, keeping the final record
+async fn run(
+    state: Arc<AppState>,
+    mut record: UrlImportRecord,
+    target: Target,
+    prefix: String,
+    headers: HeaderMap,
+) -> UrlImportRecord {
+    let config = state.config().url_import.clone();
+    match fetch(&config, target).await {
+        Ok(body) => {
+            record.bytes = body.len();
+            let running = record.clone();
+            let state = state.clone();
+            // Saving the library writes files
+            let applied = tokio::task::spawn_blocking(move || {
+                apply(&state, &headers, &mut record, &prefix, &body);
+                record
+            });
+            record = applied.await.unwrap_or_else(|e| UrlImportRecord {
+                status: ImportStatus::Failed,
+                error: Some(format!("Import task failed: {}", e)),
+                ..running
+            });
+        }
+        Err(e) => {
+            warn!("Import {} failed: {}", record.id, e);
+            record.status = ImportStatus::Failed;
+            record.error = Some(e);
+        }
+    }
+    record.finished_ms = Some(audit::now_ms());
+    state.url_imports.update(record.clone());
+    record
+}
+
+/// Check a URL against `[url_import]`, resolving its host to make sure
+/// it isn't a loopback, link-local or private address
+async fn check_url(config: &UrlImportConfig, url: &str) -> Result<Target, ApiError> {
+    let url = Url::parse(url).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse::new(format!("Invalid URL: {}", e))),
+        )
+    })?;
+    match url.scheme() {
+        "https" => {}
+        "http" if config.allow_http => {}
+        "http" => return Err(not_allowed("Only https:// URLs are allowed".to_string())),
+        scheme => return Err(not_allowed(format!("Unsupported URL scheme {}", scheme))),
+    }
+    let host = url.host_str().unwrap_or_default().to_string();
+    if host.is_empty() {
+        return Err(not_allowed("The URL has no host".to_string()));
+    }
+    if !config.allowed_hosts.is_empty()
+        && !config
+            .allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+    {
+        return Err(not_allowed(format!(
+            "Host {} is not in url_import.allowed_hosts",
+            host
+        )));
+    }
+
+    let port = url.port_or_known_default().unwrap_or(443);
+    let literal = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>();
+    let (addrs, resolved) = match literal {
+        Ok(ip) => (vec![SocketAddr::new(ip, port)], false),
+        Err(_) => {
+            let timeout = Duration::from_millis(config.timeout_ms);
+            let lookup =
+                tokio::time::timeout(timeout, tokio::net::lookup_host((host.as_str(), port)));
+            let addrs: Vec<SocketAddr> = match lookup.await {
+                Ok(Ok(addrs)) => addrs.collect(),
+                Ok(Err(e)) => {
+                    return Err(fetch_failed(format!("Failed to resolve {}: {}", host, e)))
+                }
+                Err(_) => return Err(fetch_failed(format!("Resolving {} timed out", host))),
+            };
+            (addrs, true)
+        }
+    };
+    if addrs.is_empty() {
+        return Err(fetch_failed(format!("{} has no addresses", host)));
+    }
+    if !config.allow_private {
+        if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+            return Err(not_allowed(format!(
+                "{} resolves to {}, which is not a public address",
+                host,
+                addr.ip()
+            )));
+        }
+    }
+
+    Ok(Target {
+        url,
+        resolved: resolved.then_some((host, addrs)),
+    })
+}
+
+/// Whether an address is reachable on the internet, as opposed to
+/// loopback, link-local, private and other special ranges
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space (carrier-grade NAT)
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local and link-local
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Download a pack, connecting only to the addresses checked
+async fn fetch(config: &UrlImportConfig, target: Target) -> Result<Vec<u8>, String> {
+    let mut client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(config.timeout_ms))
+        // A redirect could lead anywhere, past the checks
+        .redirect(Policy::none())
+        .no_proxy();
+    if let Some((host, addrs)) = &target.resolved {
+        client = client.resolve_to_addrs(host, addrs);
+    }
+    let client = client
+        .build()
+        .map_err(|e| format!("Failed to create client: {}", e))?;
+
+    let mut response = client
+        .get(target.url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch: {}", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(format!("The server answered {}", status));
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if !CONTENT_TYPES
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(essence))
+    {
+        return Err(format!("Unsupported content type {:?}", content_type));
+    }
+    let too_large = || format!("The document is larger than {} bytes", config.max_bytes);
+    if response
+        .content_length()
+        .is_some_and(|len| len > config.max_bytes as u64)
+    {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| format!("Failed to read: {}", e))?
+    {
+        if body.len() + chunk.len() > config.max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Validate and save each item of a pack, recording its outcome
+///
+/// A pack is an object of names to poses or sequences, as listed by
+/// `GET /api/poses` and `GET /api/sequences`. Items are independent: an
+/// invalid one, or one whose name is already taken, is reported and the
+/// rest are still saved.
+fn apply(
+    state: &AppState,
+    headers: &HeaderMap,
+    record: &mut UrlImportRecord,
+    prefix: &str,
+    body: &[u8],
+) {
+    let items: BTreeMap<String, serde_json::Value> = match serde_json::from_slice(body) {
+        Ok(items) => items,
+        Err(e) => {
+            warn!("Import {} failed: invalid pack: {}", record.id, e);
+            record.status = ImportStatus::Failed;
+            record.error = Some(format!("Invalid pack: {}", e));
+            return;
+        }
+    };
+
+    let config = state.config();
+    let endpoint = record.kind.endpoint();
+    for (name, value) in items {
+        let name = format!("{}{}", prefix, name);
+        let mut result = ItemResult {
+            name: name.clone(),
+            imported: false,
+            error: None,
+            step_errors: Vec::new(),
+        };
+        let exists = {
+            let library = state.library.lock().unwrap();
+            match record.kind {
+                PackKind::Poses => library.poses.contains_key(&name),
+                PackKind::Sequences => library.sequences.contains_key(&name),
+            }
+        };
+        let outcome = if exists {
+            Err(format!("{} already exists", name))
+        } else {
+            match record.kind {
+                PackKind::Poses => serde_json::from_value::<Pose>(value)
+                    .map_err(|e| format!("Invalid pose: {}", e))
+                    .and_then(|pose| {
+                        pose.validate(&config).map_err(|e| format!("{:#}", e))?;
+                        handlers::store_pose(state, headers, endpoint, &name, pose)
+                            .map_err(|(_, e)| e.0.error)
+                    }),
+                PackKind::Sequences => serde_json::from_value::<Sequence>(value)
+                    .map_err(|e| format!("Invalid sequence: {}", e))
+                    .and_then(|sequence| {
+                        if let Err(errors) = imports::check_sequence(&config, &sequence) {
+                            result.step_errors = errors.step_errors;
+                            return Err(errors.error);
+                        }
+                        handlers::store_sequence(state, headers, endpoint, &name, sequence)
+                            .map_err(|(_, e)| e.0.error)
+                    }),
+            }
+        };
+        match outcome {
+            Ok(()) => {
+                result.imported = true;
+                record.imported += 1;
+            }
+            Err(e) => {
+                result.error = Some(e);
+                record.failed += 1;
+            }
+        }
+        record.items.push(result);
+    }
+
+    info!(
+        "Import {} completed: {} imported, {} failed",
+        record.id, record.imported, record.failed
+    );
+    record.status = ImportStatus::Completed;
+}
+
+fn not_allowed(error: String) -> ApiError {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse::with_code("URL_NOT_ALLOWED", error)),
+    )
+}
+
+fn fetch_failed(error: String) -> ApiError {
+    (
+        StatusCode::BAD_GATEWAY,
+        Json(ErrorResponse::with_code("FETCH_FAILED", error)),
+    )
+}
+
+/// 502 `FETCH_FAILED` for an import that failed while the request waited,
+/// with the record as details
+fn failed(record: UrlImportRecord) -> ApiError {
+    let error = record.error.clone().unwrap_or_default();
+    (
+        StatusCode::BAD_GATEWAY,
+        Json(ErrorResponse {
+            details: serde_json::to_value(&record).ok(),
+            ..ErrorResponse::with_code("FETCH_FAILED", error)
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestServer;
+    use serde_json::json;
+
+    fn config() -> UrlImportConfig {
+        UrlImportConfig::default()
+    }
+
+    /// Serve `response` at `/pack` of a local host, returning its URL
+    async fn host<R>(response: R) -> String
+    where
+        R: axum::response::IntoResponse + Clone + Send + Sync + 'static,
+    {
+        let app = axum::Router::new().route(
+            "/pack",
+            axum::routing::get(move || {
+                let response = response.clone();
+                async move { response }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}/pack", addr)
+    }
+
+    async fn refusal(config: &UrlImportConfig, url: &str) -> (StatusCode, String, String) {
+        let Err((status, Json(error))) = check_url(config, url).await else {
+            panic!("{} was allowed", url);
+        };
+        (status, error.code.unwrap_or_default(), error.error)
+    }
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        let refused = [
+            "127.0.0.1",
+            "127.255.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "192.0.2.1",
+            "100.64.0.1",
+            "100.127.255.255",
+            "::1",
+            "::",
+            "fe80::1",
+            "febf::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "::ffff:10.0.0.1",
+        ];
+        for ip in refused {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        let allowed = [
+            "1.1.1.1",
+            "8.8.8.8",
+            "172.32.0.1",
+            "100.128.0.1",
+            "169.255.0.1",
+            "2606:4700::1111",
+            "fec0::1",
+            "::ffff:8.8.8.8",
+        ];
+        for ip in allowed {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn urls_are_checked_before_fetching() {
+        let config = config();
+        for url in [
+            "https://127.0.0.1/",
+            "https://169.254.169.254/latest/meta-data",
+            "https://[::1]:8443/pack",
+            "https://[::ffff:127.0.0.1]/",
+            "https://0.0.0.0/",
+            "https://10.0.0.8/pack.json",
+            "https://localhost/",
+        ] {
+            let (status, code, error) = refusal(&config, url).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", url);
+            assert_eq!(code, "URL_NOT_ALLOWED", "{}", url);
+            assert!(
+                error.ends_with("which is not a public address"),
+                "{}",
+                error
+            );
+        }
+
+        let (_, _, error) = refusal(&config, "http://1.1.1.1/").await;
+        assert_eq!(error, "Only https:// URLs are allowed");
+        let (_, _, error) = refusal(&config, "file:///etc/passwd").await;
+        assert_eq!(error, "Unsupported URL scheme file");
+        let (status, _, _) = refusal(&config, "not a url").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let config = UrlImportConfig {
+            allowed_hosts: vec!["Gist.GitHubUserContent.com".to_string()],
+            ..UrlImportConfig::default()
+        };
+        let (_, _, error) = refusal(&config, "https://1.1.1.1/").await;
+        assert_eq!(error, "Host 1.1.1.1 is not in url_import.allowed_hosts");
+    }
+
+    #[tokio::test]
+    async fn private_addresses_can_be_allowed() {
+        let config = UrlImportConfig {
+            allow_private: true,
+            ..UrlImportConfig::default()
+        };
+        let target = check_url(&config, "https://127.0.0.1:8443/pack")
+            .await
+            .unwrap();
+        assert!(target.resolved.is_none());
+        // A name is pinned to the addresses it was checked at
+        let target = check_url(&config, "https://localhost/").await.unwrap();
+        let (host, addrs) = target.resolved.unwrap();
+        assert_eq!(host, "localhost");
+        assert!(addrs
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 443));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn loopback_and_metadata_urls_are_refused_by_default() {
+        let server = TestServer::start().await;
+        for url in ["https://127.0.0.1/", "https://169.254.169.254/"] {
+            for path in ["/api/poses/import-url", "/api/sequences/import-url"] {
+                let reply = server.post(path, json!({ "url": url })).await;
+                assert_eq!(reply.status, 422, "{} {}", path, url);
+                assert_eq!(reply.code(), "URL_NOT_ALLOWED", "{} {}", path, url);
+            }
+        }
+        // Nothing was started
+        assert_eq!(server.get("/api/url-imports/1").await.status, 404);
+    }
+
+    /// A server importing from local test hosts
+    async fn importing() -> TestServer {
+        TestServer::with_config(|config| {
+            config.url_import.allow_http = true;
+            config.url_import.allow_private = true;
+        })
+        .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn each_item_is_reported_with_its_prefixed_name() {
+        let server = importing().await;
+        server
+            .put("/api/poses/community_wave", json!({ "angles": [90] }))
+            .await;
+        let pack = json!({
+            "rest": { "angles": [10, 20] },
+            "wave": { "angles": [30] },
+            "wild": { "angles": [200] },
+            "broken": { "angle": 10 },
+        });
+        let url = host(pack.to_string()).await;
+        let reply = server
+            .post(
+                "/api/poses/import-url",
+                json!({ "url": url, "prefix": "community_" }),
+            )
+            .await;
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body["status"], "completed");
+        assert_eq!(
+            (reply.body["imported"].clone(), reply.body["failed"].clone()),
+            (json!(1), json!(3))
+        );
+        let items: BTreeMap<&str, &serde_json::Value> = reply.body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| (item["name"].as_str().unwrap(), item))
+            .collect();
+        assert_eq!(
+            items.keys().copied().collect::<Vec<_>>(),
+            [
+                "community_broken",
+                "community_rest",
+                "community_wave",
+                "community_wild"
+            ]
+        );
+        assert_eq!(items["community_rest"]["imported"], true);
+        assert_eq!(
+            items["community_wave"]["error"],
+            "community_wave already exists"
+        );
+        assert!(items["community_broken"]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid pose"));
+        assert_eq!(items["community_wild"]["imported"], false);
+
+        assert_eq!(
+            server.get("/api/poses/community_rest").await.body["angles"],
+            json!([10, 20])
+        );
+        // The existing pose is left as it was
+        assert_eq!(
+            server.get("/api/poses/community_wave").await.body["angles"],
+            json!([90])
+        );
+        assert_eq!(server.get("/api/poses/community_wild").await.status, 404);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invalid_sequence_steps_are_listed() {
+        let server = importing().await;
+        let pack = json!({
+            "good": { "steps": [{ "duration_ms": 10, "angles": [10] }] },
+            "bad": { "steps": [
+                { "duration_ms": 10, "angles": [10] },
+                { "duration_ms": 10, "angles": [200] },
+            ] },
+        });
+        let url = host(pack.to_string()).await;
+        let reply = server
+            .post("/api/sequences/import-url", json!({ "url": url }))
+            .await;
+        assert_eq!(reply.body["imported"], 1);
+        let bad = &reply.body["items"][0];
+        assert_eq!(bad["name"], "bad");
+        assert_eq!(bad["step_errors"][0]["step"], 1);
+        assert_eq!(server.get("/api/sequences/good").await.status, 200);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn documents_are_limited_in_type_and_size() {
+        let server = TestServer::with_config(|config| {
+            config.url_import.allow_http = true;
+            config.url_import.allow_private = true;
+            config.url_import.max_bytes = 64;
+        })
+        .await;
+        let import = |url: String| {
+            let server = &server;
+            async move {
+                server
+                    .post("/api/poses/import-url", json!({ "url": url }))
+                    .await
+            }
+        };
+
+        let url = host(([("content-type", "text/html")], "{}")).await;
+        let reply = import(url).await;
+        assert_eq!(reply.status, 502);
+        assert_eq!(reply.code(), "FETCH_FAILED");
+        assert_eq!(
+            reply.body["error"],
+            "Unsupported content type \"text/html\""
+        );
+        assert_eq!(reply.body["details"]["status"], "failed");
+
+        let url = host(format!("{{\"a\": {{\"angles\": [{}]}}}}", "90,".repeat(30))).await;
+        let reply = import(url).await;
+        assert_eq!(reply.body["error"], "The document is larger than 64 bytes");
+
+        // A redirect could lead past the checks
+        let url = host((StatusCode::FOUND, [("location", "http://169.254.169.254/")])).await;
+        let reply = import(url).await;
+        assert_eq!(reply.body["error"], "The server answered 302 Found");
+
+        let url = host("not json").await;
+        let reply = import(url).await;
+        assert!(reply.body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid pack"));
+    }
+}
@@ -0,0 +1,437 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+const HOUR_MS: u64 = 3_600_000;
+const DAY_MS: u64 = 24 * HOUR_MS;
+
+tokio::task_local! {
+    /// Who a request is accounted to, from `X-Actor`, set around every
+    /// request
+    pub static PRINCIPAL: String;
+}
+
+/// Who the current task's request is accounted to; `None` outside one,
+/// e.g. for the schedule and the attract loop
+pub fn principal() -> Option<String> {
+    PRINCIPAL.try_with(|principal| principal.clone()).ok()
+}
+
+/// Caps on one client's use per clock hour (UTC)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quota {
+    pub requests_per_hour: Option<u64>,
+    pub motion_per_hour: Option<u64>,
+    /// Milliseconds of MOVE duration commanded
+    pub move_ms_per_hour: Option<u64>,
+}
+
+/// Kind of request, by method
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestClass {
+    /// `GET` and `HEAD`
+    Read,
+    Write,
+}
+
+/// Use counted over a day or an hour
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Counters {
+    pub reads: u64,
+    pub writes: u64,
+    /// Motion commands started, each pose, move, sequence and so on
+    pub motion_commands: u64,
+    /// Sum of the MOVE durations commanded
+    pub move_ms: u64,
+}
+
+/// A quota that ran out
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaExceeded {
+    /// Which limit, e.g. `motion_per_hour`
+    pub quota: &'static str,
+    pub limit: u64,
+    /// When the hour is over and the quota available again, in ms since
+    /// the epoch
+    pub reset_ms: u64,
+}
+
+/// Use of one client
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub principal: String,
+    /// Start of the current UTC day, in ms since the epoch
+    pub day_start_ms: u64,
+    pub today: Counters,
+    /// Start of the current clock hour, in ms since the epoch
+    pub hour_start_ms: u64,
+    pub this_hour: Counters,
+}
+
+/// Counters of one client, for the day and the hour they were last
+/// touched in
+#[derive(Default)]
+struct Account {
+    day: u64,
+    today: Counters,
+    hour: u64,
+    this_hour: Counters,
+}
+
+impl Account {
+    /// Start over for a day or hour that has begun since the last use
+    fn roll_over(&mut self, now_ms: u64) {
+        if self.day != now_ms / DAY_MS {
+            self.day = now_ms / DAY_MS;
+            self.today = Counters::default();
+        }
+        if self.hour != now_ms / HOUR_MS {
+            self.hour = now_ms / HOUR_MS;
+            self.this_hour = Counters::default();
+        }
+    }
+
+    fn report(&self, principal: &str) -> UsageReport {
+        UsageReport {
+            principal: principal.to_string(),
+            day_start_ms: self.day * DAY_MS,
+            today: self.today.clone(),
+            hour_start_ms: self.hour * HOUR_MS,
+            this_hour: self.this_hour.clone(),
+        }
+    }
+
+    fn exceeded(&self, quota: &'static str, limit: u64) -> QuotaExceeded {
+        QuotaExceeded {
+            quota,
+            limit,
+            reset_ms: (self.hour + 1) * HOUR_MS,
+        }
+    }
+}
+
+/// Use of the API by each client, as named by `X-Actor`, since startup
+///
+/// Counters roll over at the start of each UTC day, and for the quotas,
+/// each clock hour. Times come from the caller, so the clock can be
+/// replaced.
+#[derive(Default)]
+pub struct Usage {
+    accounts: Mutex<BTreeMap<String, Account>>,
+}
+
+impl Usage {
+    /// Count a request, unless `quota` caps the client's requests and the
+    /// cap was reached this hour
+    pub fn request(
+        &self,
+        principal: &str,
+        class: RequestClass,
+        quota: Option<&Quota>,
+        now_ms: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(principal.to_string()).or_default();
+        account.roll_over(now_ms);
+        if let Some(limit) = quota.and_then(|quota| quota.requests_per_hour) {
+            let hour = &account.this_hour;
+            if hour.reads + hour.writes >= limit {
+                return Err(account.exceeded("requests_per_hour", limit));
+            }
+        }
+        for counters in [&mut account.today, &mut account.this_hour] {
+            match class {
+                RequestClass::Read => counters.reads += 1,
+                RequestClass::Write => counters.writes += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a motion command, unless `quota` caps the client's motion
+    /// commands or MOVE time and the cap was reached this hour
+    pub fn motion(
+        &self,
+        principal: &str,
+        quota: Option<&Quota>,
+        now_ms: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(principal.to_string()).or_default();
+        account.roll_over(now_ms);
+        let hour = &account.this_hour;
+        if let Some(limit) = quota.and_then(|quota| quota.motion_per_hour) {
+            if hour.motion_commands >= limit {
+                return Err(account.exceeded("motion_per_hour", limit));
+            }
+        }
+        if let Some(limit) = quota.and_then(|quota| quota.move_ms_per_hour) {
+            if hour.move_ms >= limit {
+                return Err(account.exceeded("move_ms_per_hour", limit));
+            }
+        }
+        account.today.motion_commands += 1;
+        account.this_hour.motion_commands += 1;
+        Ok(())
+    }
+
+    /// Count `duration_ms` of MOVE commanded
+    ///
+    /// The MOVE was already allowed by [`motion`](Self::motion), so it is
+    /// counted whole even if that takes the client past its cap.
+    pub fn move_ms(&self, principal: &str, duration_ms: u64, now_ms: u64) {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(principal.to_string()).or_default();
+        account.roll_over(now_ms);
+        account.today.move_ms += duration_ms;
+        account.this_hour.move_ms += duration_ms;
+    }
+
+    /// Use of one client as of `now_ms`
+    pub fn report(&self, principal: &str, now_ms: u64) -> UsageReport {
+        let mut accounts = self.accounts.lock().unwrap();
+        let mut unseen = Account::default();
+        let account = accounts.get_mut(principal).unwrap_or(&mut unseen);
+        account.roll_over(now_ms);
+        account.report(principal)
+    }
+
+    /// Use of every client seen as of `now_ms`
+    pub fn all(&self, now_ms: u64) -> Vec<UsageReport> {
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts
+            .iter_mut()
+            .map(|(principal, account)| {
+                account.roll_over(now_ms);
+                account.report(principal)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::server::RobotArmServer;
+    use crate::testing::{test_config, MockController, Reply, TestServer};
+    use reqwest::Method;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// 2024-03-10, 23:00 UTC
+    const LATE: u64 = 1_710_111_600_000;
+
+    fn quota(configure: impl FnOnce(&mut Quota)) -> Quota {
+        let mut quota = Quota::default();
+        configure(&mut quota);
+        quota
+    }
+
+    #[test]
+    fn counters_roll_over_with_the_hour_and_the_day() {
+        let usage = Usage::default();
+        usage
+            .request("ann", RequestClass::Read, None, LATE)
+            .unwrap();
+        usage
+            .request("ann", RequestClass::Write, None, LATE + HOUR_MS - 1)
+            .unwrap();
+        usage.motion("ann", None, LATE + HOUR_MS - 1).unwrap();
+        usage.move_ms("ann", 500, LATE + HOUR_MS - 1);
+        let report = usage.report("ann", LATE + HOUR_MS - 1);
+        assert_eq!(report.hour_start_ms, LATE);
+        assert_eq!(report.day_start_ms, LATE - 23 * HOUR_MS);
+        assert_eq!((report.this_hour.reads, report.this_hour.writes), (1, 1));
+        assert_eq!(report.today.motion_commands, 1);
+        assert_eq!(report.today.move_ms, 500);
+
+        // Midnight: both start over
+        let report = usage.report("ann", LATE + HOUR_MS);
+        assert_eq!(report.day_start_ms, LATE + HOUR_MS);
+        assert_eq!(report.hour_start_ms, LATE + HOUR_MS);
+        assert_eq!(report.today.reads + report.today.writes, 0);
+        assert_eq!(report.this_hour.move_ms, 0);
+
+        // An hour later only the hour does
+        usage
+            .request("ann", RequestClass::Read, None, LATE + HOUR_MS)
+            .unwrap();
+        let report = usage.report("ann", LATE + 2 * HOUR_MS + 1);
+        assert_eq!(report.today.reads, 1);
+        assert_eq!(report.this_hour.reads, 0);
+        assert_eq!(report.hour_start_ms, LATE + 2 * HOUR_MS);
+    }
+
+    #[test]
+    fn clients_are_counted_apart() {
+        let usage = Usage::default();
+        usage
+            .request("ann", RequestClass::Read, None, LATE)
+            .unwrap();
+        usage
+            .request("bob", RequestClass::Write, None, LATE)
+            .unwrap();
+        usage
+            .request("bob", RequestClass::Write, None, LATE)
+            .unwrap();
+        let all = usage.all(LATE + DAY_MS);
+        let principals: Vec<_> = all.iter().map(|r| r.principal.as_str()).collect();
+        assert_eq!(principals, ["ann", "bob"]);
+        assert!(all.iter().all(|r| r.today.reads + r.today.writes == 0));
+        let all = usage.all(LATE);
+        assert_eq!(all[1].today.writes, 0);
+
+        // Unseen clients have nothing counted, and aren't remembered
+        let report = usage.report("eve", LATE);
+        assert_eq!(report.today.reads, 0);
+        assert_eq!(usage.all(LATE).len(), 2);
+    }
+
+    #[test]
+    fn request_quota_runs_out_until_the_next_hour() {
+        let usage = Usage::default();
+        let quota = quota(|q| q.requests_per_hour = Some(2));
+        let request = |now_ms| usage.request("ann", RequestClass::Read, Some(&quota), now_ms);
+        request(LATE + 10).unwrap();
+        request(LATE + 20).unwrap();
+        let exceeded = request(LATE + HOUR_MS - 1).unwrap_err();
+        assert_eq!(exceeded.quota, "requests_per_hour");
+        assert_eq!(exceeded.limit, 2);
+        assert_eq!(exceeded.reset_ms, LATE + HOUR_MS);
+        // Refused requests aren't counted
+        assert_eq!(usage.report("ann", LATE + 30).this_hour.reads, 2);
+        request(exceeded.reset_ms).unwrap();
+        // Without a quota there is no cap
+        for _ in 0..10 {
+            usage
+                .request("bob", RequestClass::Read, None, LATE)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn motion_quotas_cap_commands_and_move_time() {
+        let usage = Usage::default();
+        let commands = quota(|q| q.motion_per_hour = Some(1));
+        usage.motion("ann", Some(&commands), LATE).unwrap();
+        let exceeded = usage.motion("ann", Some(&commands), LATE).unwrap_err();
+        assert_eq!((exceeded.quota, exceeded.limit), ("motion_per_hour", 1));
+
+        let move_time = quota(|q| q.move_ms_per_hour = Some(1000));
+        usage.motion("bob", Some(&move_time), LATE).unwrap();
+        usage.move_ms("bob", 800, LATE);
+        usage.motion("bob", Some(&move_time), LATE).unwrap();
+        // Counted whole though past the cap
+        usage.move_ms("bob", 800, LATE);
+        assert_eq!(usage.report("bob", LATE).this_hour.move_ms, 1600);
+        let exceeded = usage.motion("bob", Some(&move_time), LATE).unwrap_err();
+        assert_eq!((exceeded.quota, exceeded.limit), ("move_ms_per_hour", 1000));
+        assert_eq!(exceeded.reset_ms, LATE + HOUR_MS);
+        usage
+            .motion("bob", Some(&move_time), LATE + HOUR_MS)
+            .unwrap();
+        assert_eq!(usage.report("bob", LATE + HOUR_MS).today.motion_commands, 1);
+    }
+
+    /// Served on a mock clock with `quotas`
+    async fn capped(quotas: &[(&str, Quota)]) -> (TestServer, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new());
+        let mut config = test_config();
+        for (principal, quota) in quotas {
+            config.quotas.insert(principal.to_string(), quota.clone());
+        }
+        let builder = RobotArmServer::builder()
+            .config(config)
+            .clock(clock.clone());
+        let server = TestServer::serve(builder, Arc::new(MockController::default())).await;
+        (server, clock)
+    }
+
+    /// Time until the next clock hour
+    fn to_next_hour(clock: &MockClock) -> Duration {
+        use crate::clock::Clock;
+        Duration::from_millis(HOUR_MS - clock.now_ms() % HOUR_MS)
+    }
+
+    async fn pose(server: &TestServer, actor: &str) -> Reply {
+        let body = Some(json!({ "angles": [10] }));
+        server
+            .send(Method::POST, "/api/pose", &[("x-actor", actor)], body)
+            .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exhausted_motion_quota_is_refused_until_it_resets() {
+        let motion = quota(|q| q.motion_per_hour = Some(2));
+        let (server, clock) = capped(&[("robot", motion)]).await;
+        assert_eq!(pose(&server, "robot").await.status, 200);
+        assert_eq!(pose(&server, "robot").await.status, 200);
+        server.mock.take_commands();
+
+        let reply = pose(&server, "robot").await;
+        assert_eq!(reply.status, 429);
+        assert_eq!(reply.code(), "QUOTA_EXCEEDED");
+        let reset_ms =
+            crate::clock::Clock::now_ms(&*clock) + to_next_hour(&clock).as_millis() as u64;
+        assert_eq!(
+            reply.body["details"],
+            json!({ "quota": "motion_per_hour", "limit": 2, "reset_ms": reset_ms })
+        );
+        assert!(server.mock.take_commands().is_empty());
+        // Others aren't held to it
+        assert_eq!(pose(&server, "painter").await.status, 200);
+
+        clock.advance(to_next_hour(&clock));
+        assert_eq!(pose(&server, "robot").await.status, 200);
+        let reply = server
+            .send(Method::GET, "/api/usage", &[("x-actor", "robot")], None)
+            .await;
+        assert_eq!(reply.body["this_hour"]["motion_commands"], 1);
+        assert_eq!(reply.body["hour_start_ms"], reset_ms);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exhausted_request_quota_refuses_every_request() {
+        let requests = quota(|q| q.requests_per_hour = Some(3));
+        let (server, clock) = capped(&[("*", requests)]).await;
+        let actor = [("x-actor", "robot")];
+        for _ in 0..3 {
+            let reply = server.send(Method::GET, "/api/health", &actor, None).await;
+            assert_eq!(reply.status, 200);
+        }
+        let reply = server.send(Method::GET, "/api/health", &actor, None).await;
+        assert_eq!(reply.status, 429);
+        assert_eq!(reply.body["details"]["quota"], "requests_per_hour");
+        let body = Some(json!({ "angles": [10] }));
+        let reply = server.send(Method::POST, "/api/pose", &actor, body).await;
+        assert_eq!(reply.status, 429);
+        assert!(server.mock.take_commands().is_empty());
+
+        clock.advance(to_next_hour(&clock));
+        let reply = server.send(Method::GET, "/api/health", &actor, None).await;
+        assert_eq!(reply.status, 200);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn move_time_is_accounted_to_the_client() {
+        let move_time = quota(|q| q.move_ms_per_hour = Some(300));
+        let (server, _clock) = capped(&[("robot", move_time)]).await;
+        let actor = [("x-actor", "robot")];
+        let body = Some(json!({ "duration_ms": 200, "angles": [10] }));
+        for _ in 0..2 {
+            let reply = server
+                .send(Method::POST, "/api/move", &actor, body.clone())
+                .await;
+            assert_eq!(reply.status, 200, "{:?}", reply.body);
+        }
+        let reply = server.send(Method::POST, "/api/move", &actor, body).await;
+        assert_eq!(reply.body["details"]["quota"], "move_ms_per_hour");
+        let reply = server.send(Method::GET, "/api/usage", &actor, None).await;
+        assert_eq!(reply.body["today"]["move_ms"], 400);
+        assert_eq!(reply.body["today"]["motion_commands"], 2);
+        assert_eq!(reply.body["today"]["writes"], 3);
+    }
+}
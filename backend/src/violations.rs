@@ -0,0 +1,129 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+/// Violations kept for `GET /api/protocol/violations`
+const MAX_RECENT: usize = 50;
+
+/// Most bytes of a violation kept in its dump
+pub const MAX_DUMP: usize = 64;
+
+/// Bytes no expected response explains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKind {
+    /// Bytes other than printable ASCII and line endings within a response
+    NonPrintable,
+    /// Input waiting before a command was sent
+    Unsolicited,
+    /// Data after the response line, in the same read
+    Trailing,
+}
+
+impl ViolationKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ViolationKind::NonPrintable => "non_printable",
+            ViolationKind::Unsolicited => "unsolicited",
+            ViolationKind::Trailing => "trailing",
+        }
+    }
+}
+
+/// One recorded violation
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    /// The command being sent, or whose response it was
+    pub command: String,
+    /// The bytes as space-separated hex, at most [`MAX_DUMP`] of them
+    pub hex: String,
+    /// Number of bytes, which may be more than the dump shows
+    pub bytes: usize,
+    pub at_ms: u64,
+}
+
+/// Protocol violations since the connection was opened
+#[derive(Default)]
+pub struct Violations {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    counts: BTreeMap<ViolationKind, u64>,
+    recent: VecDeque<Violation>,
+}
+
+impl Violations {
+    pub fn record(&self, violation: Violation) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.counts.entry(violation.kind).or_default() += 1;
+        if inner.recent.len() >= MAX_RECENT {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(violation);
+    }
+
+    /// Counts by kind, and the recent violations, most recent first
+    pub fn snapshot(&self) -> (BTreeMap<&'static str, u64>, Vec<Violation>) {
+        let inner = self.inner.lock().unwrap();
+        let counts = inner
+            .counts
+            .iter()
+            .map(|(kind, &count)| (kind.name(), count))
+            .collect();
+        (counts, inner.recent.iter().rev().cloned().collect())
+    }
+}
+
+/// Bytes as space-separated hex, the first [`MAX_DUMP`] of them
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let shown = &bytes[..bytes.len().min(MAX_DUMP)];
+    let hex: Vec<String> = shown.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violation(kind: ViolationKind, command: &str) -> Violation {
+        Violation {
+            kind,
+            command: command.to_string(),
+            hex: String::new(),
+            bytes: 0,
+            at_ms: 0,
+        }
+    }
+
+    #[test]
+    fn dumps_are_hex_and_capped() {
+        assert_eq!(hex_dump(b""), "");
+        assert_eq!(hex_dump(b"OK\r\n\x00\xff"), "4f 4b 0d 0a 00 ff");
+        let dump = hex_dump(&[0xab; 100]);
+        assert_eq!(dump.split(' ').count(), MAX_DUMP);
+        assert!(dump.split(' ').all(|byte| byte == "ab"));
+    }
+
+    #[test]
+    fn counts_outlast_the_recent_ones() {
+        let violations = Violations::default();
+        assert_eq!(violations.snapshot().0.len(), 0);
+        for i in 0..MAX_RECENT + 10 {
+            violations.record(violation(ViolationKind::Trailing, &format!("GET {}", i)));
+        }
+        violations.record(violation(ViolationKind::NonPrintable, "POSE 90"));
+
+        let (counts, recent) = violations.snapshot();
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [("non_printable", 1), ("trailing", MAX_RECENT as u64 + 10)]
+        );
+        assert_eq!(recent.len(), MAX_RECENT);
+        assert_eq!(recent[0].command, "POSE 90");
+        assert_eq!(recent[1].command, format!("GET {}", MAX_RECENT + 9));
+        assert_eq!(recent[MAX_RECENT - 1].command, "GET 11");
+    }
+}
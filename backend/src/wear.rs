@@ -0,0 +1,289 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::handlers::AppState;
+use crate::persist::{self, Persister};
+
+/// Width of a histogram bucket in degrees
+pub const BUCKET_DEGREES: u16 = 5;
+
+/// How often the totals are written, if they changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lifetime use of one servo
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChannelWear {
+    /// Commands ending in each bucket; bucket `i` covers the angles from
+    /// `i * BUCKET_DEGREES` up to the next bucket
+    pub buckets: Vec<u64>,
+    /// Sum of the degrees moved between commanded angles
+    pub travel_deg: u64,
+    /// Estimated time spent moving, in ms
+    pub moving_ms: u64,
+}
+
+/// Contents of the wear file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WearFile {
+    /// When the totals were started, in ms since the epoch
+    since_ms: u64,
+    /// Time the backend ran while counting, in ms
+    tracked_ms: u64,
+    channels: BTreeMap<u8, ChannelWear>,
+}
+
+/// Which parts of each servo's range are used and how much it moves,
+/// kept in `wear_file` (if set) across restarts
+///
+/// Counted as commands are sent: every commanded angle lands in its
+/// bucket, and the distance from the previous commanded angle adds to
+/// the travel.
+pub struct Wear {
+    totals: Mutex<WearFile>,
+    /// Start of this run, in ms since the epoch
+    started_ms: u64,
+    path: Option<PathBuf>,
+    persister: Persister,
+}
+
+/// Totals of every channel, see [`Wear::snapshot`]
+pub struct WearSnapshot {
+    pub since_ms: u64,
+    pub tracked_ms: u64,
+    pub channels: BTreeMap<u8, ChannelWear>,
+}
+
+impl Wear {
+    /// Load the totals from `path`, or its backup if the file is corrupt,
+    /// and go on counting from them; a missing file starts from zero
+    pub fn load(path: Option<&Path>, persister: &Persister, now_ms: u64) -> Result<Self> {
+        let totals = match path {
+            Some(path) => persist::load(path, persister, |text| {
+                serde_json::from_str(text)
+                    .with_context(|| format!("Failed to parse wear {}", path.display()))
+            })?,
+            None => None,
+        };
+        Ok(Self {
+            totals: Mutex::new(totals.unwrap_or(WearFile {
+                since_ms: now_ms,
+                ..WearFile::default()
+            })),
+            started_ms: now_ms,
+            path: path.map(Path::to_path_buf),
+            persister: persister.clone(),
+        })
+    }
+
+    /// Count a command moving `channel` from `from` (if known) to `to`,
+    /// taking `moving_ms`
+    pub fn record(&self, channel: u8, from: Option<u16>, to: u16, moving_ms: u64) {
+        let mut totals = self.totals.lock().unwrap();
+        let wear = totals.channels.entry(channel).or_default();
+        let bucket = (to / BUCKET_DEGREES) as usize;
+        if wear.buckets.len() <= bucket {
+            wear.buckets.resize(bucket + 1, 0);
+        }
+        wear.buckets[bucket] += 1;
+        wear.travel_deg += from.map_or(0, |from| from.abs_diff(to)) as u64;
+        wear.moving_ms += moving_ms;
+    }
+
+    /// The totals as of `now_ms`
+    pub fn snapshot(&self, now_ms: u64) -> WearSnapshot {
+        let totals = self.totals.lock().unwrap();
+        WearSnapshot {
+            since_ms: totals.since_ms,
+            tracked_ms: totals.tracked_ms + now_ms.saturating_sub(self.started_ms),
+            channels: totals.channels.clone(),
+        }
+    }
+
+    /// Commands counted over every channel
+    fn commands(&self) -> u64 {
+        let totals = self.totals.lock().unwrap();
+        totals
+            .channels
+            .values()
+            .flat_map(|wear| &wear.buckets)
+            .sum()
+    }
+
+    /// Queue the totals as of `now_ms` to be written to disk
+    fn save(&self, now_ms: u64) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let snapshot = self.snapshot(now_ms);
+        let file = WearFile {
+            since_ms: snapshot.since_ms,
+            tracked_ms: snapshot.tracked_ms,
+            channels: snapshot.channels,
+        };
+        self.persister
+            .submit(path, serde_json::to_vec_pretty(&file)?);
+        Ok(())
+    }
+}
+
+/// Keep `wear_file` up to date with the totals
+///
+/// The file is only written after commands, so the time tracked while
+/// idle is saved with the next one.
+pub async fn run(state: Arc<AppState>) {
+    let mut saved = None;
+    loop {
+        state.clock.sleep(SAVE_INTERVAL).await;
+        let counted = state.wear.commands();
+        if saved == Some(counted) {
+            continue;
+        }
+        match state.wear.save(state.clock.now_ms()) {
+            Ok(()) => saved = Some(counted),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TempDir, TestServer};
+    use serde_json::json;
+
+    /// `(buckets, travel_deg, moving_ms)` of a channel
+    fn totals(snapshot: &WearSnapshot, channel: u8) -> (Vec<u64>, u64, u64) {
+        let wear = &snapshot.channels[&channel];
+        (wear.buckets.clone(), wear.travel_deg, wear.moving_ms)
+    }
+
+    /// A histogram with `counts` as `(bucket, count)`
+    fn histogram(len: usize, counts: &[(usize, u64)]) -> Vec<u64> {
+        let mut buckets = vec![0; len];
+        for &(bucket, count) in counts {
+            buckets[bucket] = count;
+        }
+        buckets
+    }
+
+    #[test]
+    fn commands_are_bucketed_and_their_travel_summed() {
+        let wear = Wear::load(None, &Persister::default(), 1000).unwrap();
+        // Unknown start, then back and forth, then held
+        wear.record(0, None, 90, 0);
+        wear.record(0, Some(90), 4, 500);
+        wear.record(0, Some(4), 5, 20);
+        wear.record(0, Some(5), 180, 1000);
+        wear.record(0, Some(180), 180, 0);
+        wear.record(3, Some(90), 45, 250);
+
+        let snapshot = wear.snapshot(1000);
+        assert_eq!(
+            totals(&snapshot, 0),
+            (
+                histogram(37, &[(0, 1), (1, 1), (18, 1), (36, 2)]),
+                86 + 1 + 175,
+                1520
+            )
+        );
+        assert_eq!(totals(&snapshot, 3), (histogram(10, &[(9, 1)]), 45, 250));
+        assert!(!snapshot.channels.contains_key(&1));
+        assert_eq!(wear.commands(), 6);
+    }
+
+    #[test]
+    fn tracked_time_runs_from_the_start() {
+        let wear = Wear::load(None, &Persister::default(), 1000).unwrap();
+        let snapshot = wear.snapshot(4000);
+        assert_eq!((snapshot.since_ms, snapshot.tracked_ms), (1000, 3000));
+        // A clock stepped back doesn't count negative time
+        assert_eq!(wear.snapshot(500).tracked_ms, 0);
+    }
+
+    #[test]
+    fn saved_totals_are_counted_on_from_after_a_restart() {
+        let dir = TempDir::new("wear-restart");
+        let path = dir.join("wear.json");
+        let persister = Persister::default();
+        let wear = Wear::load(Some(&path), &persister, 1000).unwrap();
+        wear.record(1, None, 90, 0);
+        wear.record(1, Some(90), 100, 200);
+        wear.save(6000).unwrap();
+        assert!(persister.write_pending());
+
+        // Restarted an hour later
+        let restarted = Wear::load(Some(&path), &persister, 3_606_000).unwrap();
+        let snapshot = restarted.snapshot(3_606_000);
+        assert_eq!((snapshot.since_ms, snapshot.tracked_ms), (1000, 5000));
+        assert_eq!(
+            totals(&snapshot, 1),
+            (histogram(21, &[(18, 1), (20, 1)]), 10, 200)
+        );
+
+        restarted.record(1, Some(100), 40, 300);
+        let snapshot = restarted.snapshot(3_608_000);
+        assert_eq!(snapshot.tracked_ms, 7000);
+        assert_eq!(
+            totals(&snapshot, 1),
+            (histogram(21, &[(8, 1), (18, 1), (20, 1)]), 70, 500)
+        );
+        assert_eq!(restarted.commands(), 3);
+    }
+
+    #[test]
+    fn without_a_file_nothing_is_saved() {
+        let persister = Persister::default();
+        let wear = Wear::load(None, &persister, 0).unwrap();
+        wear.record(0, None, 90, 0);
+        wear.save(1000).unwrap();
+        assert!(persister.pending().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_kind_of_motion_is_counted() {
+        // Read as 90 on connecting, moving at 180 degrees a second
+        let server = TestServer::start().await;
+        let sequence = json!({ "steps": [
+            { "duration_ms": 100, "angles": [110, 60] },
+            { "duration_ms": 100, "angles": [100, 60] }
+        ] });
+        server.put("/api/sequences/nod", sequence).await;
+        let commands = [
+            ("/api/servo/0/angle", json!({ "angle": 100 })),
+            ("/api/pose", json!({ "angles": [120, 60] })),
+            ("/api/move", json!({ "duration_ms": 200, "angles": [100] })),
+            ("/api/sequences/nod/execute", json!({})),
+        ];
+        for (path, body) in commands {
+            let reply = server.post(path, body).await;
+            assert!(reply.status < 300, "{}: {:?}", path, reply.body);
+        }
+
+        let state = server.server.state();
+        let snapshot = state.wear.snapshot(state.clock.now_ms());
+        // 10 degrees in 56 ms, 20 in 112, 20 in the MOVE's 200 and 10 in
+        // each step's 100
+        assert_eq!(
+            totals(&snapshot, 0),
+            (histogram(25, &[(20, 3), (22, 1), (24, 1)]), 70, 568)
+        );
+        // 30 degrees in 167 ms, then held through the sequence
+        assert_eq!(totals(&snapshot, 1), (histogram(13, &[(12, 3)]), 30, 167));
+        assert_eq!(snapshot.channels.len(), 2);
+
+        let reply = server.get("/api/wear").await;
+        assert_eq!(reply.body["bucket_degrees"], 5);
+        let servos = reply.body["servos"].as_array().unwrap();
+        assert_eq!(servos[0]["channel"], 0);
+        assert_eq!(servos[0]["commands"], 5);
+        assert_eq!(servos[0]["travel_deg"], 70);
+        assert_eq!(servos[0]["moving_ms"], 568);
+        let duty = servos[0]["duty"].as_f64().unwrap();
+        assert!(duty > 0.0 && duty <= 1.0, "{}", duty);
+    }
+}
@@ -0,0 +1,106 @@
+//! The backend embedded in another program: its own routes next to the
+//! API, the arm commanded directly through `control`
+
+use axum::routing::get;
+use axum::Router;
+use robotarm_backend::{control, Config, RobotArmServer};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+async fn get_json(client: &reqwest::Client, url: String) -> Value {
+    let response = client
+        .get(url)
+        .header("authorization", "Bearer kiosk-admin")
+        .send()
+        .await
+        .expect("request is answered");
+    serde_json::from_str(&response.text().await.unwrap()).unwrap()
+}
+
+/// Requesters of the commands in the queue, once there are `count`
+async fn requesters(client: &reqwest::Client, url: &str, count: usize) -> Vec<String> {
+    for _ in 0..200 {
+        let queue = get_json(client, format!("{}/api/queue", url)).await;
+        let entries = queue["entries"].as_array().unwrap();
+        if entries.len() >= count {
+            return entries
+                .iter()
+                .map(|entry| entry["requester"].as_str().unwrap().to_string())
+                .collect();
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    panic!("never {} commands in the queue", count);
+}
+
+// Commands block their worker thread while the port is busy
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn kiosk_embeds_the_backend() {
+    let config = Config {
+        bind_addr: "127.0.0.1:0".to_string(),
+        simulate: true,
+        admin_token: Some("kiosk-admin".to_string()),
+        ..Config::default()
+    };
+    let kiosk = Router::new().route("/kiosk", get(|| async { "Kiosk" }));
+    let server = RobotArmServer::builder()
+        .config(config)
+        .router(kiosk)
+        .start()
+        .await
+        .expect("server starts");
+    let url = format!("http://{}", server.local_addr().unwrap());
+    let client = reqwest::Client::new();
+
+    // The program's route is served next to the API
+    let page = client.get(format!("{}/kiosk", url)).send().await.unwrap();
+    assert_eq!(page.status(), 200);
+    assert_eq!(page.text().await.unwrap(), "Kiosk");
+
+    // A pose sent directly is seen over HTTP
+    control::pose(server.state(), vec![30, 60]).await.unwrap();
+    let servo = get_json(&client, format!("{}/api/servo/1", url)).await;
+    assert_eq!(servo["angle"], 60);
+
+    // ...and checked like a request
+    let (status, error) = control::pose(server.state(), vec![30, 200])
+        .await
+        .unwrap_err();
+    assert_eq!(status, 422);
+    assert!(error.error.contains("200"), "{}", error.error);
+
+    // ...and accounted to its own actor
+    let usage = get_json(&client, format!("{}/api/usage/all", url)).await;
+    let embedded = usage
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|report| report["principal"] == control::ACTOR)
+        .expect("the embedding program is accounted");
+    assert_eq!(embedded["today"]["motion_commands"], 2);
+
+    // ...and waits its turn in the same queue
+    let body = json!({ "duration_ms": 1000, "angles": [90, 90] });
+    let moving = client
+        .post(format!("{}/api/move", url))
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .send();
+    let moving = tokio::spawn(moving);
+    let queued = |count| requesters(&client, &url, count);
+    assert_eq!(queued(1).await, ["POST /api/move (anonymous)"]);
+    let state = server.state().clone();
+    let posing = tokio::spawn(async move { control::pose(&state, vec![45]).await });
+    assert_eq!(
+        queued(2).await,
+        ["POST /api/move (anonymous)", "POST /api/pose (embedded)"]
+    );
+    assert_eq!(moving.await.unwrap().unwrap().status(), 200);
+    posing.await.unwrap().unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), server.shutdown())
+        .await
+        .expect("shuts down promptly")
+        .unwrap();
+    assert!(client.get(format!("{}/kiosk", url)).send().await.is_err());
+}
@@ -0,0 +1,320 @@
+//! The API over a scripted `SerialPort`, reached through
+//! `ServerBuilder::transport` like a program embedding the backend would
+
+use robotarm_backend::{Config, RobotArmServer, ServerHandle, Transport};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_serial::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+type Answer = dyn Fn(&str) -> String + Send + Sync;
+
+/// A port that answers every line written to it with `answer` and
+/// records the lines
+struct ScriptedPort {
+    answer: Arc<Answer>,
+    lines: Arc<Mutex<Vec<String>>>,
+    input: Vec<u8>,
+    output: Arc<Mutex<VecDeque<u8>>>,
+    timeout: Duration,
+}
+
+impl Read for ScriptedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut output = self.output.lock().unwrap();
+        if output.is_empty() {
+            drop(output);
+            std::thread::sleep(self.timeout);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
+        let n = buf.len().min(output.len());
+        for (slot, byte) in buf.iter_mut().zip(output.drain(..n)) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for ScriptedPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte != b'\n' {
+                self.input.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.input).trim().to_string();
+            self.input.clear();
+            let reply = (self.answer)(&line);
+            self.lines.lock().unwrap().push(line);
+            self.output.lock().unwrap().extend(reply.bytes());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for ScriptedPort {
+    fn name(&self) -> Option<String> {
+        Some("scripted".to_string())
+    }
+
+    fn baud_rate(&self) -> tokio_serial::Result<u32> {
+        Ok(115200)
+    }
+
+    fn data_bits(&self) -> tokio_serial::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> tokio_serial::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> tokio_serial::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> tokio_serial::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _: u32) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _: DataBits) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _: FlowControl) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _: Parity) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _: StopBits) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> tokio_serial::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _: bool) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _: bool) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> tokio_serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> tokio_serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> tokio_serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> tokio_serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> tokio_serial::Result<u32> {
+        Ok(self.output.lock().unwrap().len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> tokio_serial::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer: ClearBuffer) -> tokio_serial::Result<()> {
+        if buffer != ClearBuffer::Output {
+            self.output.lock().unwrap().clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> tokio_serial::Result<Box<dyn SerialPort>> {
+        Err(tokio_serial::Error::new(
+            tokio_serial::ErrorKind::Unknown,
+            "A scripted port can't be cloned",
+        ))
+    }
+
+    fn set_break(&self) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> tokio_serial::Result<()> {
+        Ok(())
+    }
+}
+
+/// Replies of the firmware in serial mode, with servo 0 at 42 degrees
+fn firmware(line: &str) -> String {
+    match line {
+        "GET 0" => "SERVO 0: 42 degrees\n".to_string(),
+        "S3:10" => "ERROR: Invalid command format\n".to_string(),
+        "S2:10" => "Type START to enter serial mode\n".to_string(),
+        _ if line.starts_with("GET ") => format!("SERVO {}: 90 degrees\n", &line[4..]),
+        _ => "OK\n".to_string(),
+    }
+}
+
+struct Served {
+    handle: ServerHandle,
+    lines: Arc<Mutex<Vec<String>>>,
+    url: String,
+}
+
+async fn serve(answer: Arc<Answer>) -> Served {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let recorded = lines.clone();
+    let transport: Transport = Arc::new(move || {
+        let port = ScriptedPort {
+            answer: answer.clone(),
+            lines: recorded.clone(),
+            input: Vec::new(),
+            output: Arc::default(),
+            timeout: Duration::from_millis(50),
+        };
+        Ok(Box::new(port) as Box<dyn SerialPort>)
+    });
+    let config = Config {
+        bind_addr: "127.0.0.1:0".to_string(),
+        ..Config::default()
+    };
+    let handle = RobotArmServer::builder()
+        .config(config)
+        .transport(transport)
+        .start()
+        .await
+        .expect("server starts");
+    let url = format!("http://{}", handle.local_addr().unwrap());
+    lines.lock().unwrap().clear();
+    Served { handle, lines, url }
+}
+
+async fn send(request: reqwest::RequestBuilder) -> (u16, Value) {
+    let response = request.send().await.expect("request is answered");
+    let status = response.status().as_u16();
+    let text = response.text().await.unwrap();
+    (status, serde_json::from_str(&text).unwrap_or(Value::Null))
+}
+
+fn post(served: &Served, path: &str, body: Value) -> reqwest::RequestBuilder {
+    reqwest::Client::new()
+        .post(format!("{}{}", served.url, path))
+        .header("content-type", "application/json")
+        .body(body.to_string())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_reach_the_port() {
+    let served = serve(Arc::new(firmware)).await;
+    let get = reqwest::Client::new().get(format!("{}/api/servo/0", served.url));
+    let (status, body) = send(get).await;
+    assert_eq!(status, 200);
+    assert_eq!(body["angle"], 42);
+
+    let request = post(&served, "/api/servo/1/angle", json!({ "angle": 30 }));
+    assert_eq!(send(request).await.0, 200);
+    let lines = served.lines.lock().unwrap().clone();
+    assert!(lines.contains(&"GET 0".to_string()), "{:?}", lines);
+    assert!(lines.contains(&"S1:30".to_string()), "{:?}", lines);
+    served.handle.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn firmware_errors_are_mapped() {
+    let served = serve(Arc::new(firmware)).await;
+    // The soft limits are checked before anything is sent
+    let request = post(&served, "/api/servo/1/angle", json!({ "angle": 200 }));
+    let (status, body) = send(request).await;
+    assert_eq!(status, 422);
+    assert_eq!(body["code"], "FIRMWARE_RANGE");
+
+    let request = post(&served, "/api/servo/2/angle", json!({ "angle": 10 }));
+    let (status, body) = send(request).await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "NOT_IN_SERIAL_MODE");
+
+    let request = post(&served, "/api/servo/3/angle", json!({ "angle": 10 }));
+    let (status, body) = send(request).await;
+    assert_eq!(status, 400);
+    assert!(body["error"]
+        .as_str()
+        .unwrap()
+        .contains("Invalid command format"));
+    served.handle.shutdown().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_mode_toggles_leave_the_last_mode() {
+    // Firmware that answers as the real one does in each mode, slowly
+    // enough for the requests to overlap
+    let serial_mode = Arc::new(Mutex::new(false));
+    let mode = serial_mode.clone();
+    let answer = move |line: &str| {
+        std::thread::sleep(Duration::from_millis(5));
+        let mut serial = mode.lock().unwrap();
+        match (line, *serial) {
+            ("START", false) | ("STOP", true) => {
+                *serial = line == "START";
+                "OK\n".to_string()
+            }
+            ("START", true) => "ERROR: Unknown command (type HELP for list)\n".to_string(),
+            (_, false) => "Type START to enter serial mode\n".to_string(),
+            _ => firmware(line),
+        }
+    };
+    let served = serve(Arc::new(answer)).await;
+
+    let requests = (0..20).map(|i| {
+        let path = if i % 2 == 0 {
+            "/api/serial/start"
+        } else {
+            "/api/serial/stop"
+        };
+        send(post(&served, path, json!({})))
+    });
+    for (status, _) in futures_util::future::join_all(requests).await {
+        assert_eq!(status, 200);
+    }
+
+    let last = served
+        .lines
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|line| *line == "START" || *line == "STOP")
+        .cloned()
+        .unwrap();
+    let expected = if last == "START" { "serial" } else { "button" };
+    let health = reqwest::Client::new().get(format!("{}/api/health", served.url));
+    let (_, body) = send(health).await;
+    assert_eq!(body["mode"], expected);
+    assert_eq!(*serial_mode.lock().unwrap(), last == "START");
+    served.handle.shutdown().await.unwrap();
+}